@@ -1,7 +1,8 @@
-use cgmath::EuclideanSpace;
-use gilrs::{Axis, Button, Gilrs};
-use std::{f64::consts::PI, path::PathBuf};
+use gilrs::{Axis, Button, EventType, GamepadId, Gilrs};
+use serde::Deserialize;
+use std::path::PathBuf;
 use structopt::StructOpt;
+use terra::controller::{CameraController, GamepadAxis, InputEvent, Key};
 use winit::{
     event,
     event_loop::{ControlFlow, EventLoop},
@@ -17,19 +18,110 @@ struct Opt {
     elevation: f64,
     #[structopt(long)]
     generate: Option<PathBuf>,
+    /// JSON file overriding the default gamepad bindings.
+    #[structopt(long)]
+    gamepad: Option<PathBuf>,
+    /// How frames are presented: fifo (vsync), mailbox or immediate.
+    #[structopt(long, default_value = "fifo", parse(try_from_str = parse_present_mode))]
+    present_mode: wgpu::PresentMode,
+    /// Upper limit on frames rendered per second.
+    #[structopt(long)]
+    max_fps: Option<f64>,
+    /// Only render while the camera is moving or terrain is still loading.
+    #[structopt(long)]
+    low_power: bool,
 }
 
-fn compute_projection_matrix(width: f32, height: f32) -> cgmath::Matrix4<f32> {
-    let aspect = width / height;
-    let f = 1.0 / (45.0f32.to_radians() / aspect).tan();
-    let near = 0.1;
-
-    #[cfg_attr(rustfmt, rustfmt_skip)]
-    cgmath::Matrix4::new(
-        f/aspect,  0.0,  0.0,   0.0,
-        0.0,       f,    0.0,   0.0,
-        0.0,       0.0,  0.0,  -1.0,
-        0.0,       0.0,  near,  0.0)
+fn parse_present_mode(s: &str) -> Result<wgpu::PresentMode, String> {
+    match s {
+        "fifo" => Ok(wgpu::PresentMode::Fifo),
+        "mailbox" => Ok(wgpu::PresentMode::Mailbox),
+        "immediate" => Ok(wgpu::PresentMode::Immediate),
+        _ => Err(format!("unknown present mode '{}'", s)),
+    }
+}
+
+/// Which gamepad controls move the camera, and how stick input is shaped.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+struct GamepadBindings {
+    forward: Axis,
+    strafe: Axis,
+    turn: Axis,
+    climb: Button,
+    descend: Button,
+    invert_forward: bool,
+    /// Stick deflection below which input is ignored, so that worn sticks don't drift.
+    dead_zone: f32,
+    /// Exponent applied to deflection past the dead zone. Values above one give finer control
+    /// near the center of the stick.
+    curve: f32,
+}
+impl Default for GamepadBindings {
+    fn default() -> Self {
+        Self {
+            forward: Axis::LeftStickY,
+            strafe: Axis::LeftStickX,
+            turn: Axis::RightStickX,
+            climb: Button::DPadUp,
+            descend: Button::DPadDown,
+            invert_forward: false,
+            dead_zone: 0.15,
+            curve: 2.0,
+        }
+    }
+}
+impl GamepadBindings {
+    fn axis(&self, gamepad: &gilrs::Gamepad, axis: Axis) -> f32 {
+        let value = gamepad.value(axis);
+        let deflection = ((value.abs() - self.dead_zone) / (1.0 - self.dead_zone)).max(0.0);
+        deflection.powf(self.curve) * value.signum()
+    }
+
+    /// Report the current state of `gamepad` to `controller`.
+    fn apply(&self, gamepad: &gilrs::Gamepad, controller: &mut CameraController) {
+        let mut forward = self.axis(gamepad, self.forward);
+        if self.invert_forward {
+            forward = -forward;
+        }
+        let climb = gamepad.is_pressed(self.climb) as i32 - gamepad.is_pressed(self.descend) as i32;
+        for &(axis, value) in &[
+            (GamepadAxis::Forward, forward),
+            (GamepadAxis::Strafe, self.axis(gamepad, self.strafe)),
+            (GamepadAxis::Turn, self.axis(gamepad, self.turn)),
+            (GamepadAxis::Climb, climb as f32),
+        ] {
+            controller.handle(InputEvent::GamepadAxis { axis, value });
+        }
+    }
+}
+
+/// Map keyboard keys to camera controller actions.
+fn key_binding(keycode: event::VirtualKeyCode) -> Option<Key> {
+    use event::VirtualKeyCode::*;
+    Some(match keycode {
+        Up | W => Key::Forward,
+        Down | S => Key::Back,
+        Left | A => Key::Left,
+        Right | D => Key::Right,
+        Space => Key::Up,
+        Z | Semicolon => Key::Down,
+        Q => Key::TurnLeft,
+        E => Key::TurnRight,
+        _ => return None,
+    })
+}
+
+/// Track the gamepad in use as controllers are plugged in and removed. The most recently
+/// connected or used gamepad takes over, and when it is unplugged another connected one does.
+fn update_gamepad(gilrs: &Gilrs, current: &mut Option<GamepadId>, id: GamepadId, event: EventType) {
+    match event {
+        EventType::Disconnected if *current == Some(id) => {
+            *current = gilrs.gamepads().map(|(other, _)| other).find(|&other| other != id);
+        }
+        EventType::Disconnected => {}
+        _ => *current = Some(id),
+    }
 }
 
 fn make_swapchain(
@@ -37,6 +129,7 @@ fn make_swapchain(
     surface: &wgpu::Surface,
     width: u32,
     height: u32,
+    present_mode: wgpu::PresentMode,
 ) -> wgpu::SwapChain {
     device.create_swap_chain(
         &surface,
@@ -45,7 +138,7 @@ fn make_swapchain(
             format: wgpu::TextureFormat::Bgra8UnormSrgb,
             width,
             height,
-            present_mode: wgpu::PresentMode::Fifo, // disable vsync by switching to Mailbox,
+            present_mode,
         },
     )
 }
@@ -94,16 +187,13 @@ fn main() {
         }))
         .expect("Unable to create compatible wgpu adapter");
 
-    // Terra requires support for BC texture compression.
-    assert!(adapter.features().contains(wgpu::Features::TEXTURE_COMPRESSION_BC));
-
-    let features = if !adapter.features().contains(wgpu::Features::SHADER_FLOAT64)
-        || cfg!(feature = "soft-float64")
+    // Terra uses BC texture compression and 64-bit floats in shaders when they are available.
+    let mut features = adapter.features() & wgpu::Features::TEXTURE_COMPRESSION_BC;
+    if adapter.features().contains(wgpu::Features::SHADER_FLOAT64)
+        && !cfg!(feature = "soft-float64")
     {
-        wgpu::Features::TEXTURE_COMPRESSION_BC
-    } else {
-        wgpu::Features::TEXTURE_COMPRESSION_BC | wgpu::Features::SHADER_FLOAT64
-    };
+        features |= wgpu::Features::SHADER_FLOAT64;
+    }
 
     let (device, queue) = runtime
         .block_on(adapter.request_device(
@@ -133,16 +223,23 @@ fn main() {
     }
 
     let opt = Opt::from_args();
+    let bindings: GamepadBindings = match opt.gamepad {
+        Some(ref path) => serde_json::from_slice(&std::fs::read(path).unwrap())
+            .expect("Failed to parse gamepad bindings"),
+        None => Default::default(),
+    };
     let plus_center =
         open_location_code::decode(&opt.plus).expect("Failed to parse plus code").center;
 
-    let planet_radius = 6371000.0;
-    let mut angle = opt.heading.to_radians();
-    let mut lat = plus_center.y().to_radians();
-    let mut long = plus_center.x().to_radians();
-    let mut altitude = opt.elevation;
+    let mut controller = CameraController::new(
+        plus_center.y().to_radians(),
+        plus_center.x().to_radians(),
+        opt.heading.to_radians(),
+        opt.elevation,
+    );
 /*
-    let mut terrain = terra::Terrain::new(&device, &queue).unwrap();
+    let options = terra::TerrainOptions::for_adapter(&adapter.get_info(), None);
+    let mut terrain = terra::Terrain::with_options(&device, &queue, options).unwrap();
 
     if let Some(dataset_directory) = opt.generate {
         let pb = indicatif::ProgressBar::new(100);
@@ -178,18 +275,12 @@ fn main() {
         runtime.block_on(terrain.generate_roughness(&mut progress_callback)).unwrap();
     }
 
-    {
-        let r = altitude + planet_radius;
-        let eye = cgmath::Point3::new(
-            r * lat.cos() * long.cos(),
-            r * lat.cos() * long.sin(),
-            r * lat.sin(),
-        );
-        while terrain.poll_loading_status(&device, &queue, eye.into()) {
-            std::thread::sleep(std::time::Duration::from_millis(10));
-        }
+    while terrain.poll_loading_status(&device, &queue, controller.camera(0.0).position) {
+        std::thread::sleep(std::time::Duration::from_millis(10));
     }
 
+    let mut last_frame = std::time::Instant::now();
+
     let mut set_visible = false;
     event_loop.run(move |event, _, control_flow| {
         *control_flow = if cfg!(feature = "metal-auto-capture") {
@@ -203,37 +294,22 @@ fn main() {
                     *control_flow = ControlFlow::Exit;
                 }
                 event::WindowEvent::KeyboardInput {
-                    input:
-                        event::KeyboardInput {
-                            virtual_keycode: Some(keycode),
-                            state: event::ElementState::Pressed,
-                            ..
-                        },
+                    input: event::KeyboardInput { virtual_keycode: Some(keycode), state, .. },
                     ..
                 } => match keycode {
                     event::VirtualKeyCode::Escape => *control_flow = ControlFlow::Exit,
-                    event::VirtualKeyCode::Space => altitude += 0.1 * altitude,
-                    event::VirtualKeyCode::Z | event::VirtualKeyCode::Semicolon => {
-                        altitude -= 0.1 * altitude
-                    }
-                    event::VirtualKeyCode::Left => {
-                        lat += -angle.sin() * -(0.0000001 * altitude).min(0.01);
-                        long += angle.cos() * -(0.0000001 * altitude).min(0.01);
-                    }
-                    event::VirtualKeyCode::Right => {
-                        lat += -angle.sin() * (0.0000001 * altitude).min(0.01);
-                        long += angle.cos() * (0.0000001 * altitude).min(0.01);
-                    }
-                    event::VirtualKeyCode::Up => {
-                        lat += angle.cos() * (0.0000001 * altitude).min(0.01);
-                        long += -angle.sin() * (0.0000001 * altitude).min(0.01);
+                    _ => if let Some(key) = key_binding(keycode) {
+                        let pressed = state == event::ElementState::Pressed;
+                        controller.handle(InputEvent::Key { key, pressed });
                     }
-                    event::VirtualKeyCode::Down => {
-                        lat += angle.cos() * -(0.0000001 * altitude).min(0.01);
-                        long += -angle.sin() * -(0.0000001 * altitude).min(0.01);
-                    }
-                    _ => {}
                 },
+                event::WindowEvent::MouseWheel { delta, .. } => {
+                    let lines = match delta {
+                        event::MouseScrollDelta::LineDelta(_, y) => y as f64,
+                        event::MouseScrollDelta::PixelDelta(p) => p.y / 20.0,
+                    };
+                    controller.handle(InputEvent::Scroll(lines));
+                }
                 event::WindowEvent::Resized(new_size) => {
                     size = new_size;
                     swap_chain = None;
@@ -245,8 +321,39 @@ fn main() {
                 _ => {}
             },
             event::Event::MainEventsCleared => {
+                let now = std::time::Instant::now();
+                if let Some(max_fps) = opt.max_fps {
+                    let next_frame = last_frame + std::time::Duration::from_secs_f64(1.0 / max_fps);
+                    if now < next_frame {
+                        *control_flow = ControlFlow::WaitUntil(next_frame);
+                        return;
+                    }
+                }
+                // Gamepads don't wake the event loop, so keep checking on them while idle.
+                if opt.low_power && set_visible && controller.is_idle() && !terrain.needs_redraw() {
+                    while let Some(gilrs::Event { id, event, time: _ }) = gilrs.next_event() {
+                        update_gamepad(&gilrs, &mut current_gamepad, id, event);
+                    }
+                    if let Some(gamepad) = current_gamepad.and_then(|id| gilrs.connected_gamepad(id)) {
+                        bindings.apply(&gamepad, &mut controller);
+                    }
+                    if controller.is_idle() {
+                        *control_flow = ControlFlow::WaitUntil(
+                            now + std::time::Duration::from_millis(100),
+                        );
+                        last_frame = now;
+                        return;
+                    }
+                }
+
                 if swap_chain.is_none() {
-                    swap_chain = Some(make_swapchain(&device, &surface, size.width, size.height));
+                    swap_chain = Some(make_swapchain(
+                        &device,
+                        &surface,
+                        size.width,
+                        size.height,
+                        opt.present_mode,
+                    ));
                 }
                 if depth_buffer.is_none() {
                     depth_buffer = Some(make_depth_buffer(&device, size.width, size.height));
@@ -261,76 +368,26 @@ fn main() {
                 #[cfg(feature = "smaa")]
                 let frame = smaa_target.start_frame(&device, &queue, frame);
 
-                while let Some(gilrs::Event { id, event: _event, time: _ }) = gilrs.next_event() {
-                    current_gamepad = Some(id);
+                while let Some(gilrs::Event { id, event, time: _ }) = gilrs.next_event() {
+                    update_gamepad(&gilrs, &mut current_gamepad, id, event);
                 }
-                if let Some(gamepad) = current_gamepad.map(|id| gilrs.gamepad(id)) {
-                    lat += angle.cos() * gamepad.value(Axis::LeftStickY) as f64 * 0.01
-                        - angle.sin() * gamepad.value(Axis::LeftStickX) as f64 * 0.01;
-
-                    long += -angle.sin() * gamepad.value(Axis::LeftStickY) as f64 * 0.01
-                        + angle.cos() * gamepad.value(Axis::LeftStickX) as f64 * 0.01;
-
-                    angle -= gamepad.value(Axis::RightZ) as f64 * 0.01;
-
-                    if gamepad.is_pressed(Button::DPadUp) {
-                        altitude += 0.01 * altitude;
-                    }
-                    if gamepad.is_pressed(Button::DPadDown) {
-                        altitude -= 0.01 * altitude;
-                    }
+                if let Some(gamepad) = current_gamepad.and_then(|id| gilrs.connected_gamepad(id)) {
+                    bindings.apply(&gamepad, &mut controller);
                 }
 
-                    lat = lat.max(-PI).min(PI);
-                    if long < -PI {
-                        long += PI * 2.0;
-                    }
-                    if long > PI {
-                        long -= PI * 2.0;
-                    }
-
-                    let surface_height = terrain.get_height(lat, long) as f64;
-                    let r = altitude + planet_radius + surface_height + 2.0;
-                    let eye = cgmath::Point3::new(
-                        r * lat.cos() * long.cos(),
-                        r * lat.cos() * long.sin(),
-                        r * lat.sin(),
-                    );
-
-                    let dt = (planet_radius / (planet_radius + altitude)).acos() * 0.3;
-                    let latc = lat + angle.cos() * dt;
-                    let longc = long - angle.sin() * dt;
-
-                    let center = cgmath::Point3::new(
-                        planet_radius * latc.cos() * longc.cos() - eye.x,
-                        planet_radius * latc.cos() * longc.sin() - eye.y,
-                        planet_radius * latc.sin() - eye.z,
-                    );
-                    let up = cgmath::Vector3::new(eye.x as f32, eye.y as f32, eye.z as f32);
-
-                    let view = cgmath::Matrix4::look_at_rh(
-                        cgmath::Point3::origin(),
-                        cgmath::Point3::new(center.x as f32, center.y as f32, center.z as f32),
-                        up,
-                    );
-
-                    let proj = compute_projection_matrix(size.width as f32, size.height as f32);
-                    let view_proj = proj * view;
-                    let view_proj = mint::ColumnMatrix4 {
-                        x: view_proj.x.into(),
-                        y: view_proj.y.into(),
-                        z: view_proj.z.into(),
-                        w: view_proj.w.into(),
-                    };
+                let dt = (now - last_frame).as_secs_f64();
+                last_frame = now;
+                let ground_height =
+                    terrain.get_height(controller.latitude(), controller.longitude());
+                let camera = controller.update(dt, ground_height);
 
-                terrain.render(
+                terrain.render_camera(
                     &device,
                     &queue,
                     &*frame,
                     depth_buffer.as_ref().unwrap(),
                     (size.width, size.height),
-                    view_proj,
-                    eye.into(),
+                    &camera,
                 );
 
                 if !set_visible {
@@ -6,9 +6,17 @@ use spirv_headers::ImageFormat;
 use std::collections::HashMap;
 use std::collections::{btree_map::Entry, BTreeMap};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{self, Receiver};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
+
+/// Most recompiled shader sets swapped in per frame. Each swap makes the owner rebuild its
+/// pipelines, so when a shared header changes they are spread over several frames instead.
+const SWAPS_PER_FRAME: usize = 4;
+/// Length of the window that `SWAPS_PER_FRAME` applies to.
+const FRAME_DURATION: Duration = Duration::from_millis(16);
+
+#[derive(Clone, Debug)]
 pub enum ShaderSource {
     Inline {
         name: &'static str,
@@ -73,6 +81,38 @@ impl ShaderSource {
     }
 }
 
+type CompileJob = Box<dyn FnOnce() + Send>;
+
+/// Start of the current swap window and the number of swaps left in it.
+struct SwapBudget {
+    window_start: Instant,
+    remaining: usize,
+}
+impl SwapBudget {
+    fn available(&mut self) -> bool {
+        if self.window_start.elapsed() >= FRAME_DURATION {
+            self.window_start = Instant::now();
+            self.remaining = SWAPS_PER_FRAME;
+        }
+        self.remaining > 0
+    }
+}
+
+/// Start the thread that recompiles changed shaders, so that the render loop never waits on the
+/// compiler. Jobs are run one at a time in the order they were queued.
+fn spawn_compiler() -> Mutex<Sender<CompileJob>> {
+    let (tx, rx) = mpsc::channel::<CompileJob>();
+    std::thread::Builder::new()
+        .name("rshader-compiler".to_owned())
+        .spawn(move || {
+            for job in rx {
+                job();
+            }
+        })
+        .unwrap();
+    Mutex::new(tx)
+}
+
 pub(crate) struct ShaderSetInner {
     pub vertex: Option<Vec<u32>>,
     pub fragment: Option<Vec<u32>>,
@@ -155,6 +195,8 @@ pub struct ShaderSet {
     fragment_source: Option<ShaderSource>,
     compute_source: Option<ShaderSource>,
     last_update: Instant,
+    /// Recompilation queued on the compiler thread, if any.
+    pending: Option<Receiver<Result<ShaderSetInner, anyhow::Error>>>,
 }
 impl ShaderSet {
     pub fn simple(
@@ -167,6 +209,7 @@ impl ShaderSet {
             fragment_source: Some(fragment_source),
             compute_source: None,
             last_update: Instant::now(),
+            pending: None,
         })
     }
     pub fn compute_only(compute_source: ShaderSource) -> Result<Self, anyhow::Error> {
@@ -176,11 +219,35 @@ impl ShaderSet {
             fragment_source: None,
             compute_source: Some(compute_source),
             last_update: Instant::now(),
+            pending: None,
         })
     }
 
     /// Refreshes the shader if necessary. Returns whether a refresh happened.
+    ///
+    /// Changed shaders are recompiled on a background thread, and swapped in by a later call once
+    /// they are ready. Only a few shader sets are swapped in per frame.
     pub fn refresh(&mut self) -> bool {
+        if let Some(ref pending) = self.pending {
+            let mut budget = SWAP_BUDGET.lock().unwrap();
+            if !budget.available() {
+                return false;
+            }
+            return match pending.try_recv() {
+                Ok(Ok(inner)) => {
+                    budget.remaining -= 1;
+                    self.inner = inner;
+                    self.pending = None;
+                    true
+                }
+                Ok(Err(_)) | Err(TryRecvError::Disconnected) => {
+                    self.pending = None;
+                    false
+                }
+                Err(TryRecvError::Empty) => false,
+            };
+        }
+
         if !self.vertex_source.as_ref().map(|s| s.needs_update(self.last_update)).unwrap_or(false)
             && !self
                 .fragment_source
@@ -196,19 +263,25 @@ impl ShaderSet {
             return false;
         }
 
-        let r =
-            || -> Result<(), anyhow::Error> {
-                Ok(self.inner =
-                    match (&self.vertex_source, &self.fragment_source, &self.compute_source) {
-                        (Some(ref vs), Some(ref fs), None) => {
-                            ShaderSetInner::simple(vs.load()?, fs.load()?)
-                        }
-                        (None, None, Some(ref cs)) => ShaderSetInner::compute_only(cs.load()?),
-                        _ => unreachable!(),
-                    }?)
-            }();
+        let sources =
+            (self.vertex_source.clone(), self.fragment_source.clone(), self.compute_source.clone());
+        let (tx, rx) = mpsc::channel();
+        let job = move || {
+            let r = || -> Result<ShaderSetInner, anyhow::Error> {
+                match sources {
+                    (Some(ref vs), Some(ref fs), None) => {
+                        ShaderSetInner::simple(vs.load()?, fs.load()?)
+                    }
+                    (None, None, Some(ref cs)) => ShaderSetInner::compute_only(cs.load()?),
+                    _ => unreachable!(),
+                }
+            };
+            let _ = tx.send(r());
+        };
+        COMPILER.lock().unwrap().send(Box::new(job)).unwrap();
+        self.pending = Some(rx);
         self.last_update = Instant::now();
-        r.is_ok()
+        false
     }
 
     pub fn layout_descriptor(&self) -> wgpu::BindGroupLayoutDescriptor {
@@ -237,6 +310,9 @@ impl ShaderSet {
 
 lazy_static::lazy_static! {
     static ref DIRECTORY_WATCHER: Mutex<DirectoryWatcher> = Mutex::new(DirectoryWatcher::new());
+    static ref COMPILER: Mutex<Sender<CompileJob>> = spawn_compiler();
+    static ref SWAP_BUDGET: Mutex<SwapBudget> =
+        Mutex::new(SwapBudget { window_start: Instant::now(), remaining: SWAPS_PER_FRAME });
 }
 
 #[macro_export]
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+/// Settings for adjusting terrain detail to hold a target frame time. See
+/// `Terrain::set_adaptive_quality`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AdaptiveQuality {
+    /// GPU frame time to aim for.
+    pub target_frame_time: Duration,
+    /// Screen-space error used when there is plenty of headroom. This is the most detail the
+    /// controller will ever draw.
+    pub min_screen_space_error: f32,
+    /// Screen-space error used when the frame budget is badly exceeded.
+    pub max_screen_space_error: f32,
+    /// Fraction of the way from the minimum to the maximum error past which grass is no longer
+    /// drawn. One means grass is never turned off.
+    pub disable_grass_above: f32,
+}
+impl Default for AdaptiveQuality {
+    /// Target 60 frames per second, with screen-space error between 1 and 8 pixels.
+    fn default() -> Self {
+        Self {
+            target_frame_time: Duration::from_micros(16_600),
+            min_screen_space_error: 1.0,
+            max_screen_space_error: 8.0,
+            disable_grass_above: 0.5,
+        }
+    }
+}
+
+/// Snapshot of the decisions made by the adaptive quality controller.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AdaptiveQualityState {
+    /// Smoothed GPU frame time the controller is responding to.
+    pub frame_time: Duration,
+    /// Screen-space error currently used to select terrain detail.
+    pub screen_space_error: f32,
+    /// How far quality has been reduced, from zero at the minimum error to one at the maximum.
+    pub level: f32,
+    /// Whether grass is currently allowed.
+    pub grass: bool,
+}
+
+pub(crate) struct AdaptiveQualityController {
+    settings: AdaptiveQuality,
+    /// Exponentially weighted average of reported frame times, in seconds.
+    frame_time: Option<f64>,
+    level: f32,
+}
+impl AdaptiveQualityController {
+    /// Weight given to each new frame time sample.
+    const SMOOTHING: f64 = 0.1;
+    /// Frame times within this fraction below the target are left alone, so the controller does
+    /// not oscillate around the target.
+    const HEADROOM: f64 = 0.15;
+    /// Largest change in level made for a single frame.
+    const MAX_STEP: f32 = 0.02;
+
+    pub fn new(settings: AdaptiveQuality) -> Self {
+        Self { settings, frame_time: None, level: 0.0 }
+    }
+
+    pub fn settings(&self) -> &AdaptiveQuality {
+        &self.settings
+    }
+
+    pub fn report_frame_time(&mut self, frame_time: Duration) {
+        let sample = frame_time.as_secs_f64();
+        let smoothed = match self.frame_time {
+            Some(t) => t + (sample - t) * Self::SMOOTHING,
+            None => sample,
+        };
+        self.frame_time = Some(smoothed);
+
+        let target = self.settings.target_frame_time.as_secs_f64().max(1e-6);
+        let ratio = smoothed / target;
+        let step = if ratio > 1.0 {
+            ((ratio - 1.0) as f32 * 0.1).min(Self::MAX_STEP)
+        } else if ratio < 1.0 - Self::HEADROOM {
+            // Regain quality more slowly than it is shed, since overshooting causes a visible
+            // stutter while undershooting only costs a little detail.
+            -((1.0 - Self::HEADROOM - ratio) as f32 * 0.05).min(Self::MAX_STEP * 0.5)
+        } else {
+            0.0
+        };
+        self.level = (self.level + step).max(0.0).min(1.0);
+    }
+
+    pub fn state(&self) -> AdaptiveQualityState {
+        let min = self.settings.min_screen_space_error.max(0.01);
+        let max = self.settings.max_screen_space_error.max(min);
+        AdaptiveQualityState {
+            frame_time: Duration::from_secs_f64(self.frame_time.unwrap_or(0.0)),
+            // Interpolate geometrically, since each halving of the error roughly quadruples the
+            // number of triangles drawn.
+            screen_space_error: min * (max / min).powf(self.level),
+            level: self.level,
+            grass: self.level <= self.settings.disable_grass_above,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converges_toward_target() {
+        let mut controller = AdaptiveQualityController::new(AdaptiveQuality::default());
+        for _ in 0..200 {
+            controller.report_frame_time(Duration::from_millis(30));
+        }
+        let state = controller.state();
+        assert_eq!(state.level, 1.0);
+        assert_eq!(state.screen_space_error, 8.0);
+        assert!(!state.grass);
+
+        for _ in 0..1000 {
+            controller.report_frame_time(Duration::from_millis(8));
+        }
+        let state = controller.state();
+        assert_eq!(state.level, 0.0);
+        assert_eq!(state.screen_space_error, 1.0);
+        assert!(state.grass);
+
+        let level = controller.level;
+        controller.report_frame_time(Duration::from_millis(16));
+        assert_eq!(controller.level, level);
+    }
+}
@@ -0,0 +1,363 @@
+use crate::coordinates::{self, PLANET_RADIUS};
+use crate::generate::{HeightmapPass, TileData, TileNode};
+use crate::geodesy;
+use crate::mapfile::MapFile;
+use crate::spatial::QuadSpatialIndex;
+use crate::terrain::quadtree::node::VNode;
+use anyhow::{ensure, format_err, Error};
+use cgmath::{InnerSpace, Vector3};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Meters per foot, which OurAirports uses for lengths and elevations.
+const FEET: f32 = 0.3048;
+/// Width in meters assumed for runways that don't record one.
+const DEFAULT_WIDTH: f32 = 45.0;
+/// Distance in meters beyond the edges of each runway over which flattened ground blends back
+/// into the surrounding terrain.
+const SHOULDER: f64 = 60.0;
+
+/// A runway from the [OurAirports](https://ourairports.com/data/) database.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Runway {
+    /// Identifier of the airport, usually its ICAO code.
+    pub airport: String,
+    /// Designators of the two ends of the runway, like "09L" and "27R".
+    pub ends: [String; 2],
+    /// Latitude and longitude in radians of the threshold at each end.
+    pub thresholds: [(f64, f64); 2],
+    /// Elevation in meters of each threshold.
+    pub elevations: [f32; 2],
+    /// True heading in radians, clockwise from north, of the runway when traveling from the first
+    /// threshold towards the second.
+    pub heading: f64,
+    /// Length in meters.
+    pub length: f32,
+    /// Width in meters.
+    pub width: f32,
+    /// Surface material as recorded by OurAirports, like "ASP" or "GRASS".
+    pub surface: String,
+}
+impl Runway {
+    /// Designator of the whole runway, like "09L/27R".
+    pub fn ident(&self) -> String {
+        format!("{}/{}", self.ends[0], self.ends[1])
+    }
+
+    /// The same runway described from its other end.
+    pub fn reversed(&self) -> Self {
+        Self {
+            airport: self.airport.clone(),
+            ends: [self.ends[1].clone(), self.ends[0].clone()],
+            thresholds: [self.thresholds[1], self.thresholds[0]],
+            elevations: [self.elevations[1], self.elevations[0]],
+            heading: (self.heading + PI) % (2.0 * PI),
+            length: self.length,
+            width: self.width,
+            surface: self.surface.clone(),
+        }
+    }
+
+    fn midpoint(&self) -> (f64, f64) {
+        let a = coordinates::polar_to_ecef(Vector3::new(
+            self.thresholds[0].0,
+            self.thresholds[0].1,
+            0.0,
+        ));
+        let b = coordinates::polar_to_ecef(Vector3::new(
+            self.thresholds[1].0,
+            self.thresholds[1].1,
+            0.0,
+        ));
+        let m = coordinates::ecef_to_polar(a + b);
+        (m.x, m.y)
+    }
+
+    /// Elevation that the ground at `(latitude, longitude)` should be flattened towards, along
+    /// with how strongly, from zero outside the footprint to one on the runway itself.
+    fn flatten(&self, center: (f64, f64), latitude: f64, longitude: f64) -> Option<(f32, f32)> {
+        // Within a few kilometers the ground is close enough to a plane to work in meters east and
+        // north of the midpoint.
+        let east = coordinates::normalize_longitude(longitude - center.1) * center.0.cos();
+        let north = latitude - center.0;
+        let (east, north) = (east * PLANET_RADIUS, north * PLANET_RADIUS);
+        let (sin, cos) = self.heading.sin_cos();
+        let along = east * sin + north * cos;
+        let across = (north * sin - east * cos).abs();
+
+        let half_length = 0.5 * self.length as f64;
+        let half_width = 0.5 * self.width as f64;
+        let outside = (along.abs() - half_length).max(0.0).max(across - half_width);
+        if outside >= SHOULDER {
+            return None;
+        }
+
+        let t = (along.max(-half_length).min(half_length) / self.length as f64 + 0.5) as f32;
+        let elevation = self.elevations[0] + (self.elevations[1] - self.elevations[0]) * t;
+        let x = (outside / SHOULDER) as f32;
+        Some((elevation, 1.0 - x * x * (3.0 - 2.0 * x)))
+    }
+}
+
+/// Every runway loaded from OurAirports, indexed by the position of its midpoint.
+pub(crate) struct Runways {
+    runways: Vec<Runway>,
+    /// Latitude and longitude of the midpoint of each runway.
+    midpoints: Vec<(f64, f64)>,
+    index: QuadSpatialIndex<usize>,
+    /// Length of the longest runway, which bounds how far from its midpoint a runway reaches.
+    max_length: f64,
+}
+impl Runways {
+    pub fn new(runways: Vec<Runway>) -> Self {
+        let midpoints: Vec<_> = runways.iter().map(Runway::midpoint).collect();
+        let mut index = QuadSpatialIndex::new(VNode::LEVEL_CELL_76M);
+        for (i, &(latitude, longitude)) in midpoints.iter().enumerate() {
+            let p = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+            index.insert(mint::Point3 { x: p.x, y: p.y, z: p.z }, i);
+        }
+        let max_length = runways.iter().map(|r| r.length as f64).fold(0.0, f64::max);
+        Self { runways, midpoints, index, max_length }
+    }
+
+    /// Runways previously stored in the map file by `ingest`.
+    pub fn load(mapfile: &MapFile) -> Result<Arc<Self>, Error> {
+        Ok(Arc::new(Self::new(mapfile.runways()?)))
+    }
+
+    /// Find a runway by airport and either its full designator or that of one of its ends. When
+    /// matched by the designator of an end, the runway is described from that end.
+    pub fn find(&self, airport: &str, ident: &str) -> Option<Runway> {
+        let at_airport = || self.runways.iter().filter(|r| r.airport.eq_ignore_ascii_case(airport));
+        if let Some(runway) = at_airport().find(|r| r.ident().eq_ignore_ascii_case(ident)) {
+            return Some(runway.clone());
+        }
+        at_airport().find_map(|r| {
+            if r.ends[0].eq_ignore_ascii_case(ident) {
+                Some(r.clone())
+            } else if r.ends[1].eq_ignore_ascii_case(ident) {
+                Some(r.reversed())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Indices of the runways whose midpoints are within `radius` meters of a point, along with
+    /// their distances.
+    fn within_radius(&self, latitude: f64, longitude: f64, radius: f64) -> Vec<(usize, f64)> {
+        let center = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        self.index
+            .within_radius(mint::Point3 { x: center.x, y: center.y, z: center.z }, radius)
+            .into_iter()
+            .map(|(_, &i)| {
+                let (latitude, longitude) = self.midpoints[i];
+                let p = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+                (i, (p - center).magnitude())
+            })
+            .collect()
+    }
+
+    /// Runways whose midpoints are within `radius` meters of a point, closest first.
+    pub fn near(&self, latitude: f64, longitude: f64, radius: f64) -> Vec<&Runway> {
+        let mut found = self.within_radius(latitude, longitude, radius);
+        found.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        found.into_iter().map(|(i, _)| &self.runways[i]).collect()
+    }
+}
+
+/// Heightmap pass that levels the ground under each runway, blending into the surrounding terrain
+/// over a short shoulder so approaches stay free of artificial cliffs.
+///
+/// Tiles too coarse to resolve the shoulder are left alone, since a runway would only move a
+/// handful of their samples.
+pub(crate) fn flatten_runways(runways: &Runways, tile: &mut TileData, node: TileNode) {
+    if node.sample_spacing() as f64 > SHOULDER {
+        return;
+    }
+
+    let (latitude, longitude) = node.sample_position(tile.resolution / 2, tile.resolution / 2);
+    let tile_radius = node.sample_spacing() as f64 * tile.resolution as f64;
+    let reach = tile_radius + runways.max_length + SHOULDER;
+    let nearby: Vec<_> = runways
+        .within_radius(latitude, longitude, reach)
+        .into_iter()
+        .map(|(i, _)| (&runways.runways[i], runways.midpoints[i]))
+        .collect();
+    if nearby.is_empty() {
+        return;
+    }
+
+    for y in 0..tile.resolution {
+        for x in 0..tile.resolution {
+            let (latitude, longitude) = node.sample_position(x, y);
+            let height = &mut tile.heights[y * tile.resolution + x];
+            for (runway, midpoint) in &nearby {
+                if let Some((elevation, weight)) = runway.flatten(*midpoint, latitude, longitude) {
+                    let h = *height as f32;
+                    *height = (h + (elevation - h) * weight).round() as i16;
+                }
+            }
+        }
+    }
+}
+
+/// Read runways from the OurAirports `airports.csv` and `runways.csv` files, store them in the map
+/// file, and replace any previous runway pass in `passes` with one that flattens them.
+pub(crate) fn ingest(
+    mapfile: &MapFile,
+    passes: &mut Vec<HeightmapPass>,
+    airports_csv: &Path,
+    runways_csv: &Path,
+) -> Result<Arc<Runways>, Error> {
+    let runways = read_runways(airports_csv, runways_csv)?;
+    mapfile.set_runways(&runways)?;
+
+    let runways = Arc::new(Runways::new(runways));
+    let pass_runways = Arc::clone(&runways);
+    passes.retain(|p| p.name != "runways");
+    passes.push(HeightmapPass {
+        name: "runways".to_owned(),
+        pass: Arc::new(move |tile: &mut TileData, node: TileNode| {
+            flatten_runways(&pass_runways, tile, node)
+        }),
+    });
+    Ok(runways)
+}
+
+/// Split a line of CSV into fields, handling quoted fields that contain commas or quotes.
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Parse a CSV file with a header row, returning the rows as maps from column name to value.
+fn read_csv(reader: impl BufRead) -> Result<Vec<HashMap<String, String>>, Error> {
+    let mut lines = reader.lines();
+    let header = split_csv(&lines.next().ok_or_else(|| format_err!("missing CSV header"))??);
+    let mut rows = Vec::new();
+    for line in lines {
+        let line = line?;
+        if !line.is_empty() {
+            rows.push(header.iter().cloned().zip(split_csv(&line)).collect());
+        }
+    }
+    Ok(rows)
+}
+
+fn parse_runways(airports: impl BufRead, runways: impl BufRead) -> Result<Vec<Runway>, Error> {
+    let airport_elevations: HashMap<String, f32> = read_csv(airports)?
+        .into_iter()
+        .filter_map(|mut row| {
+            let elevation = row.get("elevation_ft")?.parse::<f32>().ok()?;
+            Some((row.remove("ident")?, elevation * FEET))
+        })
+        .collect();
+
+    let rows = read_csv(runways)?;
+    ensure!(
+        rows.first().map_or(true, |row| row.contains_key("le_latitude_deg")),
+        "Not an OurAirports runways file"
+    );
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let field = |name: &str| row.get(name).map(String::as_str).unwrap_or("");
+            let number = |name: &str| field(name).parse::<f64>().ok();
+            if field("closed") == "1" {
+                return None;
+            }
+
+            let airport = field("airport_ident").to_owned();
+            let thresholds = [
+                (number("le_latitude_deg")?.to_radians(), number("le_longitude_deg")?.to_radians()),
+                (number("he_latitude_deg")?.to_radians(), number("he_longitude_deg")?.to_radians()),
+            ];
+            let airport_elevation = airport_elevations.get(&airport).copied();
+            let elevation = |end: &str| {
+                number(&format!("{}_elevation_ft", end))
+                    .map(|e| e as f32 * FEET)
+                    .or(airport_elevation)
+            };
+            let geodesic = geodesy::inverse(thresholds[0], thresholds[1]);
+            if geodesic.distance <= 0.0 {
+                return None;
+            }
+
+            Some(Runway {
+                ends: [field("le_ident").to_owned(), field("he_ident").to_owned()],
+                thresholds,
+                elevations: [elevation("le")?, elevation("he")?],
+                heading: geodesic.initial_bearing.rem_euclid(2.0 * PI),
+                length: number("length_ft").map_or(geodesic.distance as f32, |l| l as f32 * FEET),
+                width: number("width_ft").map_or(DEFAULT_WIDTH, |w| w as f32 * FEET),
+                surface: field("surface").to_owned(),
+                airport,
+            })
+        })
+        .collect())
+}
+
+/// Read every open runway with known threshold positions from the `airports.csv` and
+/// `runways.csv` files published by OurAirports. Runways without recorded threshold elevations
+/// take the elevation of their airport.
+fn read_runways(airports: &Path, runways: &Path) -> Result<Vec<Runway>, Error> {
+    parse_runways(BufReader::new(File::open(airports)?), BufReader::new(File::open(runways)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ourairports_runways() {
+        let airports = "\
+\"id\",\"ident\",\"type\",\"name\",\"latitude_deg\",\"longitude_deg\",\"elevation_ft\"
+3622,\"KJFK\",\"large_airport\",\"John F Kennedy International Airport\",40.639447,-73.779317,13
+2434,\"EGLL\",\"large_airport\",\"London Heathrow Airport\",51.4706,-0.461941,83";
+        let runways = "\
+\"id\",\"airport_ref\",\"airport_ident\",\"length_ft\",\"width_ft\",\"surface\",\"lighted\",\"closed\",\"le_ident\",\"le_latitude_deg\",\"le_longitude_deg\",\"le_elevation_ft\",\"le_heading_degT\",\"le_displaced_threshold_ft\",\"he_ident\",\"he_latitude_deg\",\"he_longitude_deg\",\"he_elevation_ft\",\"he_heading_degT\",\"he_displaced_threshold_ft\"
+240418,2434,\"EGLL\",12799,164,\"ASP\",1,0,\"09L\",51.4775,-0.484892,79,89.6,1013,\"27R\",51.4777,-0.433273,78,269.7,
+255155,3622,\"KJFK\",14511,150,\"ASP\",1,0,\"04L\",40.6227,-73.7856,,31,,\"22R\",40.6451,-73.7633,,211,
+255156,3622,\"KJFK\",8400,200,\"ASP\",1,1,\"04R\",40.6254,-73.7700,,31,,\"22L\",40.6421,-73.7535,,211,";
+        let runways = parse_runways(airports.as_bytes(), runways.as_bytes()).unwrap();
+        assert_eq!(runways.len(), 2);
+
+        let heathrow = &runways[0];
+        assert_eq!(heathrow.ident(), "09L/27R");
+        assert!((heathrow.heading.to_degrees() - 89.6).abs() < 0.5);
+        assert!((heathrow.length - 3901.2).abs() < 1.0);
+        assert!((heathrow.elevations[0] - 79.0 * FEET).abs() < 1e-3);
+
+        // JFK 04L has no threshold elevations, so it takes the elevation of the airport.
+        assert_eq!(runways[1].elevations, [13.0 * FEET; 2]);
+
+        let runways = Runways::new(runways);
+        let reversed = runways.find("egll", "27R").unwrap();
+        assert_eq!(reversed.ident(), "27R/09L");
+        assert!((reversed.heading.to_degrees() - 269.6).abs() < 0.5);
+        assert!(runways.find("KJFK", "04R").is_none());
+
+        let near = runways.near(51.47f64.to_radians(), (-0.45f64).to_radians(), 10000.0);
+        assert_eq!(near.len(), 1);
+        assert_eq!(near[0].airport, "EGLL");
+    }
+}
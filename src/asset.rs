@@ -1,23 +1,27 @@
-use anyhow::Error;
-use bincode;
 use dirs;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use memmap::MmapMut;
-use num::ToPrimitive;
-use serde::de::DeserializeOwned;
-use serde::Serialize;
-use std::io::{BufWriter, Cursor, Read, Write};
-use std::ops::Drop;
 use std::path::PathBuf;
-use std::time::{Duration, Instant};
-use std::{
-    fs::{self, File, OpenOptions},
-    sync::Arc,
+#[cfg(feature = "render")]
+use {
+    anyhow::Error,
+    bincode,
+    indicatif::{MultiProgress, ProgressBar, ProgressStyle},
+    memmap::MmapMut,
+    num::ToPrimitive,
+    serde::de::DeserializeOwned,
+    serde::Serialize,
+    std::fs::{self, File, OpenOptions},
+    std::io::{BufWriter, Cursor, Read, Write},
+    std::ops::Drop,
+    std::sync::Arc,
+    std::time::{Duration, Instant},
 };
 
 lazy_static! {
     pub(crate) static ref TERRA_DIRECTORY: PathBuf =
         dirs::cache_dir().unwrap_or(PathBuf::from(".")).join("terra");
+}
+#[cfg(feature = "render")]
+lazy_static! {
     static ref PROGRESS_BAR_STYLE: ProgressStyle = ProgressStyle::default_bar()
         .template("{msg} {pos}/{len} [{wide_bar}] {percent}% {per_sec} {eta}")
         .progress_chars("=> ");
@@ -26,9 +30,11 @@ lazy_static! {
         .progress_chars("=> ");
 }
 
+#[cfg(feature = "render")]
 pub(crate) struct AssetLoadContextBuf {
     bars: Arc<MultiProgress>,
 }
+#[cfg(feature = "render")]
 impl AssetLoadContextBuf {
     pub fn new() -> Self {
         Self { bars: Arc::new(MultiProgress::new()) }
@@ -46,10 +52,12 @@ impl AssetLoadContextBuf {
     }
 }
 
+#[cfg(feature = "render")]
 pub(crate) struct AssetLoadContext<'a> {
     inner: &'a mut AssetLoadContextBuf,
     bar: ProgressBar,
 }
+#[cfg(feature = "render")]
 impl<'a> AssetLoadContext<'a> {
     pub fn set_progress<N: ToPrimitive>(&mut self, value: N) {
         self.bar.set_position(value.to_u64().unwrap());
@@ -96,12 +104,14 @@ impl<'a> AssetLoadContext<'a> {
         AssetLoadContext { inner: self.inner, bar }
     }
 }
+#[cfg(feature = "render")]
 impl<'a> Drop for AssetLoadContext<'a> {
     fn drop(&mut self) {
         self.bar.finish_and_clear();
     }
 }
 
+#[cfg(feature = "render")]
 fn read_file(context: &mut AssetLoadContext, mut file: File) -> Result<Vec<u8>, Error> {
     context.bytes_display_enabled(true);
     let ret = (|| {
@@ -128,6 +138,7 @@ fn read_file(context: &mut AssetLoadContext, mut file: File) -> Result<Vec<u8>,
     ret
 }
 
+#[cfg(feature = "render")]
 pub(crate) enum CompressionType {
     None,
     #[allow(unused)]
@@ -136,6 +147,7 @@ pub(crate) enum CompressionType {
     Lz4,
 }
 
+#[cfg(feature = "render")]
 pub(crate) trait WebAsset {
     type Type;
 
@@ -236,6 +248,7 @@ pub(crate) trait WebAsset {
     }
 }
 
+#[cfg(feature = "render")]
 pub(crate) trait GeneratedAsset {
     type Type: Serialize + DeserializeOwned;
 
@@ -266,6 +279,7 @@ pub(crate) trait GeneratedAsset {
     }
 }
 
+#[cfg(feature = "render")]
 pub(crate) trait MMappedAsset {
     type Header: Serialize + DeserializeOwned;
 
@@ -317,6 +331,7 @@ pub(crate) trait MMappedAsset {
     }
 }
 
+#[cfg(feature = "render")]
 impl<H: Serialize + DeserializeOwned, A: WebAsset<Type = (H, Vec<u8>)>> MMappedAsset for A {
     type Header = H;
 
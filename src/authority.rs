@@ -0,0 +1,352 @@
+use crate::cache::Priority;
+use crate::coordinates;
+use crate::holes::TerrainHoleId;
+use crate::terrain::quadtree::VNode;
+use anyhow::{ensure, Error};
+use cgmath::Vector3;
+use fnv::{FnvHashMap, FnvHashSet};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// Deepest level that the shared quadtree is split to. Matches the most detailed heightmap tiles
+/// that `Terrain::get_height` reads from.
+const MAX_LEVEL: u8 = VNode::LEVEL_CELL_1M;
+/// Priority that nodes of the shared quadtree are streamed with, so that they stay loaded even
+/// where nothing is being rendered.
+const STREAMING_PRIORITY: f32 = 4.0;
+
+/// Role of a `Terrain` in server-authoritative streaming. The server decides how a quadtree shared
+/// with every client is split, and the heights used for collision are read from the tiles of its
+/// leaves, so all players collide against identical geometry.
+///
+/// Tiles are only identical if every instance uses the same map file and registers the same
+/// heightmap passes and runways, which is what `map_version` guards against.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StreamingAuthority {
+    /// Split the shared quadtree around the streaming viewpoints, and produce updates for clients
+    /// with `Terrain::authoritative_update`.
+    Server { map_version: u64 },
+    /// Follow updates from a server passed to `Terrain::apply_authoritative_update`.
+    Client { map_version: u64 },
+}
+
+/// A single change to the state shared between a server and its clients.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) enum TerrainDelta {
+    /// Refine a leaf into its four children.
+    Split(VNode),
+    /// Collapse a node whose children are all leaves back into a leaf.
+    Merge(VNode),
+    /// Add a hole, identified by the id it was given on the server.
+    AddHole(TerrainHoleId, Vec<(f64, f64)>),
+    RemoveHole(TerrainHoleId),
+}
+
+#[derive(Serialize, Deserialize)]
+struct AuthoritativeUpdate {
+    map_version: u64,
+    /// Index of the update, so that clients can detect ones that were lost or reordered.
+    sequence: u64,
+    deltas: Vec<TerrainDelta>,
+}
+
+/// Quadtree whose splits are decided by a server and replayed in order on every client.
+pub(crate) struct SharedQuadTree {
+    authority: StreamingAuthority,
+    /// Nodes that have been split. Any node that is a root or whose parent is split is part of
+    /// the tree, and is a leaf if it isn't split itself.
+    split: FnvHashSet<VNode>,
+    /// Sequence number of the next update to produce or apply.
+    sequence: u64,
+    /// Hole changes made on the server since the last update.
+    pending_holes: Vec<TerrainDelta>,
+    /// Local ids of holes added by the server, keyed by the ids the server gave them.
+    holes: FnvHashMap<TerrainHoleId, TerrainHoleId>,
+}
+impl SharedQuadTree {
+    pub fn new(authority: StreamingAuthority) -> Self {
+        Self {
+            authority,
+            split: FnvHashSet::default(),
+            sequence: 0,
+            pending_holes: Vec::new(),
+            holes: FnvHashMap::default(),
+        }
+    }
+
+    pub fn is_server(&self) -> bool {
+        matches!(self.authority, StreamingAuthority::Server { .. })
+    }
+
+    fn map_version(&self) -> u64 {
+        match self.authority {
+            StreamingAuthority::Server { map_version } => map_version,
+            StreamingAuthority::Client { map_version } => map_version,
+        }
+    }
+
+    /// Record a hole change on the server to be sent with the next update.
+    pub fn record_hole(&mut self, delta: TerrainDelta) {
+        if self.is_server() {
+            self.pending_holes.push(delta);
+        }
+    }
+
+    /// Split the tree so that every node within its own `min_distance` of a viewpoint is refined,
+    /// and return the compressed update that brings clients to the new state.
+    pub fn update(&mut self, viewpoints: &[mint::Point3<f64>]) -> Result<Vec<u8>, Error> {
+        ensure!(self.is_server(), "Only servers can produce authoritative updates");
+
+        let viewpoints: Vec<_> = viewpoints.iter().map(|v| Vector3::new(v.x, v.y, v.z)).collect();
+        let mut split = FnvHashSet::default();
+        VNode::breadth_first(|node| {
+            let min_distance2 = node.min_distance() * node.min_distance();
+            let refine = node.level() < MAX_LEVEL
+                && viewpoints
+                    .iter()
+                    .any(|&v| node.distance2(v, VNode::DEFAULT_ELEVATION_RANGE) < min_distance2);
+            if refine {
+                split.insert(node);
+            }
+            refine
+        });
+
+        // Parents are split before their children, and children merged before their parents.
+        let mut splits: Vec<_> = split.difference(&self.split).copied().collect();
+        let mut merges: Vec<_> = self.split.difference(&split).copied().collect();
+        splits.sort_by_key(|n| (n.level(), *n));
+        merges.sort_by_key(|n| (std::cmp::Reverse(n.level()), *n));
+
+        let mut deltas: Vec<_> = merges.into_iter().map(TerrainDelta::Merge).collect();
+        deltas.extend(splits.into_iter().map(TerrainDelta::Split));
+        deltas.append(&mut self.pending_holes);
+        self.split = split;
+
+        let update = AuthoritativeUpdate {
+            map_version: self.map_version(),
+            sequence: self.sequence,
+            deltas,
+        };
+        self.sequence += 1;
+
+        let mut encoder = lz4::EncoderBuilder::new().build(Vec::new())?;
+        bincode::serialize_into(&mut encoder, &update)?;
+        let (bytes, result) = encoder.finish();
+        result?;
+        Ok(bytes)
+    }
+
+    /// Apply an update produced by `update` on the server. Holes it contains are passed to
+    /// `add_hole` and `remove_hole`.
+    pub fn apply<A, R>(
+        &mut self,
+        bytes: &[u8],
+        mut add_hole: A,
+        mut remove_hole: R,
+    ) -> Result<(), Error>
+    where
+        A: FnMut(&[(f64, f64)]) -> Result<TerrainHoleId, Error>,
+        R: FnMut(TerrainHoleId),
+    {
+        ensure!(!self.is_server(), "Servers cannot apply authoritative updates");
+
+        let mut contents = Vec::new();
+        lz4::Decoder::new(bytes)?.read_to_end(&mut contents)?;
+        let update: AuthoritativeUpdate = bincode::deserialize(&contents)?;
+        ensure!(
+            update.map_version == self.map_version(),
+            "Update is for map version {} but this client has version {}",
+            update.map_version,
+            self.map_version()
+        );
+        ensure!(
+            update.sequence == self.sequence,
+            "Expected authoritative update {} but got {}",
+            self.sequence,
+            update.sequence
+        );
+
+        // Check the whole update against copies of the tree and the hole ids before changing
+        // anything, so that a rejected update leaves the client as it was.
+        let mut split = self.split.clone();
+        let mut hole_ids: FnvHashSet<_> = self.holes.keys().copied().collect();
+        for delta in &update.deltas {
+            match *delta {
+                TerrainDelta::Split(node) => {
+                    ensure!(Self::is_leaf(&split, node), "Split of {:?} which is not a leaf", node);
+                    split.insert(node);
+                }
+                TerrainDelta::Merge(node) => {
+                    ensure!(
+                        split.contains(&node) && node.children().iter().all(|c| !split.contains(c)),
+                        "Merge of {:?} whose children are not all leaves",
+                        node
+                    );
+                    split.remove(&node);
+                }
+                TerrainDelta::AddHole(id, _) => {
+                    ensure!(hole_ids.insert(id), "Addition of existing hole {:?}", id)
+                }
+                TerrainDelta::RemoveHole(id) => {
+                    ensure!(hole_ids.remove(&id), "Removal of unknown hole {:?}", id)
+                }
+            }
+        }
+
+        // Adding a hole is the only step that can still fail, so add them all before removing
+        // any, and take back the ones already added if one fails. Server ids are never reused, so
+        // this has the same result as following the order of the update.
+        let mut added = Vec::new();
+        for delta in &update.deltas {
+            if let TerrainDelta::AddHole(id, ref polygon) = *delta {
+                match add_hole(polygon) {
+                    Ok(local) => added.push((id, local)),
+                    Err(e) => {
+                        for (_, local) in added {
+                            remove_hole(local);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        self.holes.extend(added);
+        for delta in &update.deltas {
+            if let TerrainDelta::RemoveHole(id) = *delta {
+                if let Some(local) = self.holes.remove(&id) {
+                    remove_hole(local);
+                }
+            }
+        }
+
+        self.split = split;
+        self.sequence += 1;
+        Ok(())
+    }
+
+    fn in_tree(split: &FnvHashSet<VNode>, node: VNode) -> bool {
+        node.parent().map(|(p, _)| split.contains(&p)).unwrap_or(true)
+    }
+
+    fn is_leaf(split: &FnvHashSet<VNode>, node: VNode) -> bool {
+        Self::in_tree(split, node) && !split.contains(&node)
+    }
+
+    /// Every leaf of the tree.
+    pub fn leaves(&self) -> Vec<VNode> {
+        let mut leaves = Vec::new();
+        VNode::breadth_first(|node| {
+            if !self.split.contains(&node) {
+                leaves.push(node);
+                return false;
+            }
+            true
+        });
+        leaves
+    }
+
+    /// The leaf containing the given point.
+    pub fn leaf(&self, latitude: f64, longitude: f64) -> VNode {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+        let mut level = 0;
+        loop {
+            let node = VNode::from_cspace(cspace, level).0;
+            if !self.split.contains(&node) {
+                return node;
+            }
+            level += 1;
+        }
+    }
+
+    /// Priority that `node` should be streamed with so that the tiles of the tree are loaded.
+    pub fn priority(&self, node: VNode) -> Priority {
+        if Self::in_tree(&self.split, node) {
+            Priority::from_f32(STREAMING_PRIORITY)
+        } else {
+            Priority::none()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_follow_server() {
+        let surface = mint::Point3 { x: 6_371_000.0, y: 0.0, z: 0.0 };
+        let map_version = 3;
+        let mut server = SharedQuadTree::new(StreamingAuthority::Server { map_version });
+        let mut client = SharedQuadTree::new(StreamingAuthority::Client { map_version });
+        let no_holes = |_: &[(f64, f64)]| -> Result<TerrainHoleId, Error> { unreachable!() };
+
+        let first = server.update(&[surface]).unwrap();
+        client.apply(&first, no_holes, |_| unreachable!()).unwrap();
+        assert_eq!(client.split, server.split);
+        assert_eq!(client.leaf(0.0, 0.0).level(), MAX_LEVEL);
+
+        // Moving the viewpoint away merges the nodes around the old position.
+        let second = server.update(&[]).unwrap();
+        client.apply(&second, no_holes, |_| unreachable!()).unwrap();
+        assert!(client.split.is_empty());
+        assert_eq!(client.leaves().len(), 6);
+
+        // Updates can't be applied twice or out of order.
+        assert!(client.apply(&first, no_holes, |_| unreachable!()).is_err());
+
+        let mut other = SharedQuadTree::new(StreamingAuthority::Client { map_version: 4 });
+        assert!(other.apply(&first, no_holes, |_| unreachable!()).is_err());
+    }
+
+    #[test]
+    fn rejected_updates_change_nothing() {
+        let map_version = 3;
+        let mut client = SharedQuadTree::new(StreamingAuthority::Client { map_version });
+        let encode = |deltas| {
+            let update = AuthoritativeUpdate { map_version, sequence: 0, deltas };
+            let mut encoder = lz4::EncoderBuilder::new().build(Vec::new()).unwrap();
+            bincode::serialize_into(&mut encoder, &update).unwrap();
+            let (bytes, result) = encoder.finish();
+            result.unwrap();
+            bytes
+        };
+        let hole_id = |i: u64| -> TerrainHoleId {
+            bincode::deserialize(&bincode::serialize(&i).unwrap()).unwrap()
+        };
+        let root = VNode::roots()[0];
+        let polygon = vec![(0.0, 0.0), (0.0, 0.001), (0.001, 0.0)];
+
+        // Nothing is applied if a later delta is invalid, not even the holes before it.
+        let invalid = encode(vec![
+            TerrainDelta::Split(root),
+            TerrainDelta::AddHole(hole_id(1), polygon.clone()),
+            TerrainDelta::Split(root),
+        ]);
+        let no_holes = |_: &[(f64, f64)]| -> Result<TerrainHoleId, Error> { unreachable!() };
+        assert!(client.apply(&invalid, no_holes, |_| unreachable!()).is_err());
+        assert!(client.split.is_empty());
+
+        // Holes already added are removed again if adding a later one fails.
+        let failing = encode(vec![
+            TerrainDelta::Split(root),
+            TerrainDelta::AddHole(hole_id(1), polygon.clone()),
+            TerrainDelta::AddHole(hole_id(2), polygon),
+        ]);
+        let mut added = 0;
+        let add_hole = |_: &[(f64, f64)]| {
+            added += 1;
+            ensure!(added == 1, "too many holes");
+            Ok(hole_id(10))
+        };
+        let mut removed = Vec::new();
+        assert!(client.apply(&failing, add_hole, |id| removed.push(id)).is_err());
+        assert_eq!(removed, vec![hole_id(10)]);
+        assert!(client.split.is_empty() && client.holes.is_empty());
+
+        // The sequence number wasn't consumed, so a valid update with it still applies.
+        let valid = encode(vec![TerrainDelta::Split(root)]);
+        client.apply(&valid, no_holes, |_| unreachable!()).unwrap();
+        assert_eq!(client.leaves().len(), 9);
+    }
+}
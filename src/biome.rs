@@ -0,0 +1,80 @@
+use crate::cache::{LayerDesc, LayerGenerator, TextureFormat};
+
+/// Name of the layer that biomes are generated into. Reserved, so applications can't declare a
+/// custom layer with the same name.
+pub(crate) const BIOME_LAYER: &str = "biomes";
+
+/// Broad classes of ecosystem, derived from land cover, elevation and latitude when tiles are
+/// generated. Returned by `Terrain::biome_at` and used to restrict where a `GroundMaterial`
+/// appears.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Biome {
+    Ocean = 0,
+    IceSheet = 1,
+    Tundra = 2,
+    BorealForest = 3,
+    TemperateForest = 4,
+    Grassland = 5,
+    Shrubland = 6,
+    Desert = 7,
+    Savanna = 8,
+    TropicalForest = 9,
+    Alpine = 10,
+}
+impl Biome {
+    /// Every biome, in order of index. Indices must match the `BIOME_*` defines in
+    /// declarations.glsl.
+    pub const ALL: [Biome; 11] = [
+        Biome::Ocean,
+        Biome::IceSheet,
+        Biome::Tundra,
+        Biome::BorealForest,
+        Biome::TemperateForest,
+        Biome::Grassland,
+        Biome::Shrubland,
+        Biome::Desert,
+        Biome::Savanna,
+        Biome::TropicalForest,
+        Biome::Alpine,
+    ];
+
+    pub(crate) fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(index as usize).copied()
+    }
+
+    /// Bitmask with only this biome set, as used by the material uniforms.
+    pub(crate) fn bit_mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+/// Layer holding the biome index of each sample in its red channel.
+pub(crate) fn layer_desc() -> LayerDesc {
+    LayerDesc {
+        name: BIOME_LAYER.to_owned(),
+        texture_format: TextureFormat::RGBA8,
+        texture_resolution: 65,
+        texture_border_size: 0,
+        mipmaps: false,
+        generator: LayerGenerator::new(
+            rshader::shader_source!("shaders", "gen-biomes.comp", "declarations.glsl"),
+            9,
+        )
+        .with_peer_inputs(&["heightmaps", "albedo"]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn biome_indices() {
+        for (i, biome) in Biome::ALL.iter().enumerate() {
+            assert_eq!(*biome as usize, i);
+            assert_eq!(Biome::from_index(i as u8), Some(*biome));
+        }
+        assert_eq!(Biome::from_index(Biome::ALL.len() as u8), None);
+        assert_eq!(Biome::Desert.bit_mask(), 0x80);
+    }
+}
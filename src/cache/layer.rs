@@ -0,0 +1,346 @@
+use super::LayerType;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum TextureFormat {
+    R8,
+    RG8,
+    RGBA8,
+    RGBA16F,
+    R32F,
+    RG32F,
+    RGBA32F,
+    SRGBA,
+    BC4,
+    BC5,
+}
+impl TextureFormat {
+    /// Returns the number of bytes in a single texel of the format. Actually reports bytes per
+    /// block for compressed formats.
+    pub fn bytes_per_block(&self) -> usize {
+        match *self {
+            TextureFormat::R8 => 1,
+            TextureFormat::RG8 => 2,
+            TextureFormat::RGBA8 => 4,
+            TextureFormat::RGBA16F => 8,
+            TextureFormat::R32F => 4,
+            TextureFormat::RG32F => 8,
+            TextureFormat::RGBA32F => 16,
+            TextureFormat::SRGBA => 4,
+            TextureFormat::BC4 => 8,
+            TextureFormat::BC5 => 16,
+        }
+    }
+    #[cfg(feature = "render")]
+    pub fn to_wgpu(&self) -> wgpu::TextureFormat {
+        match *self {
+            TextureFormat::R8 => wgpu::TextureFormat::R8Unorm,
+            TextureFormat::RG8 => wgpu::TextureFormat::Rg8Unorm,
+            TextureFormat::RGBA8 => wgpu::TextureFormat::Rgba8Unorm,
+            TextureFormat::RGBA16F => wgpu::TextureFormat::Rgba16Float,
+            TextureFormat::R32F => wgpu::TextureFormat::R32Float,
+            TextureFormat::RG32F => wgpu::TextureFormat::Rg32Float,
+            TextureFormat::RGBA32F => wgpu::TextureFormat::Rgba32Float,
+            TextureFormat::SRGBA => wgpu::TextureFormat::Rgba8UnormSrgb,
+            TextureFormat::BC4 => wgpu::TextureFormat::Bc4RUnorm,
+            TextureFormat::BC5 => wgpu::TextureFormat::Bc5RgUnorm,
+        }
+    }
+    pub fn block_size(&self) -> u32 {
+        match *self {
+            TextureFormat::BC4 | TextureFormat::BC5 => 4,
+            TextureFormat::R8
+            | TextureFormat::RG8
+            | TextureFormat::RGBA8
+            | TextureFormat::RGBA16F
+            | TextureFormat::R32F
+            | TextureFormat::RG32F
+            | TextureFormat::RGBA32F
+            | TextureFormat::SRGBA => 1,
+        }
+    }
+    pub fn is_compressed(&self) -> bool {
+        match *self {
+            TextureFormat::BC4 | TextureFormat::BC5 => true,
+            TextureFormat::R8
+            | TextureFormat::RG8
+            | TextureFormat::RGBA8
+            | TextureFormat::RGBA16F
+            | TextureFormat::R32F
+            | TextureFormat::RG32F
+            | TextureFormat::RGBA32F
+            | TextureFormat::SRGBA => false,
+        }
+    }
+
+    /// Format used in place of this one on devices that can't sample compressed textures.
+    /// Compressed formats fall back to RGBA8 rather than R8 or RG8, since generated normals are
+    /// then written directly by compute shaders and neither of those is a storage format.
+    pub fn decompressed(&self) -> Self {
+        match *self {
+            TextureFormat::BC4 | TextureFormat::BC5 => TextureFormat::RGBA8,
+            format => format,
+        }
+    }
+
+    /// Decode tightly packed blocks of this format into RGBA8 texels, for an image `width` texels
+    /// wide. Channels not present in the format are zero, except for alpha which is opaque.
+    pub fn decompress(&self, data: &[u8], width: usize) -> Vec<u8> {
+        let channels = match *self {
+            TextureFormat::BC4 => 1,
+            TextureFormat::BC5 => 2,
+            _ => unreachable!("{:?} is not compressed", self),
+        };
+        let width_blocks = width / 4;
+        let height_blocks = data.len() / self.bytes_per_block() / width_blocks;
+
+        let mut texels = vec![0; width * height_blocks * 4 * 4];
+        for (i, block) in data.chunks_exact(self.bytes_per_block()).enumerate() {
+            let (bx, by) = (i % width_blocks, i / width_blocks);
+            for channel in 0..channels {
+                let values = decode_bc4_block(&block[channel * 8..][..8]);
+                for (j, &value) in values.iter().enumerate() {
+                    let (x, y) = (bx * 4 + j % 4, by * 4 + j / 4);
+                    texels[(x + y * width) * 4 + channel] = value;
+                }
+            }
+        }
+        for texel in texels.chunks_exact_mut(4) {
+            texel[3] = 255;
+        }
+        texels
+    }
+
+    /// Halve the resolution of a square image of this format by averaging each 2x2 group of
+    /// texels. Only supported for formats with 8-bit channels.
+    pub fn downsample(&self, data: &[u8], resolution: usize) -> Vec<u8> {
+        let channels = match *self {
+            TextureFormat::R8
+            | TextureFormat::RG8
+            | TextureFormat::RGBA8
+            | TextureFormat::SRGBA => self.bytes_per_block(),
+            _ => unreachable!("{:?} does not have 8-bit channels", self),
+        };
+        let half = resolution / 2;
+
+        let mut output = vec![0; half * half * channels];
+        for y in 0..half {
+            for x in 0..half {
+                for c in 0..channels {
+                    let texel = |dx, dy| {
+                        data[((x * 2 + dx) + (y * 2 + dy) * resolution) * channels + c] as u32
+                    };
+                    let sum = texel(0, 0) + texel(1, 0) + texel(0, 1) + texel(1, 1);
+                    output[(x + y * half) * channels + c] = ((sum + 2) / 4) as u8;
+                }
+            }
+        }
+        output
+    }
+}
+
+/// Decode a single BC4 block into its 16 values, in row major order.
+fn decode_bc4_block(block: &[u8]) -> [u8; 16] {
+    let (r0, r1) = (block[0] as u32, block[1] as u32);
+    let palette: [u32; 8] = if r0 > r1 {
+        let lerp = |i: u32| ((7 - i) * r0 + i * r1) / 7;
+        [r0, r1, lerp(1), lerp(2), lerp(3), lerp(4), lerp(5), lerp(6)]
+    } else {
+        let lerp = |i: u32| ((5 - i) * r0 + i * r1) / 5;
+        [r0, r1, lerp(1), lerp(2), lerp(3), lerp(4), 0, 255]
+    };
+
+    let mut indices = 0u64;
+    for (i, &byte) in block[2..8].iter().enumerate() {
+        indices |= (byte as u64) << (8 * i);
+    }
+    let mut values = [0; 16];
+    for (i, value) in values.iter_mut().enumerate() {
+        *value = palette[(indices >> (3 * i)) as usize & 7] as u8;
+    }
+    values
+}
+
+#[cfg(feature = "render")]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ByteRange {
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// Compute shader that fills in the tiles of a custom layer.
+///
+/// Terra runs the shader for a node once all of its inputs are resident in the tile cache, and
+/// takes care of the bind groups. The shader is given a uniform block named `ubo` holding the
+/// slot, parent slot, level, face and position of the node being generated, writes the tile
+/// through an image named `<name>_out` and may read the parent tile of any layer as `<layer>_in`.
+/// The full cache of any layer can be bound by its name, which is how peer inputs are read. Tiles
+/// are regenerated whenever the shader is reloaded.
+#[derive(Clone, Debug)]
+pub struct LayerGenerator {
+    /// Source of the compute shader. Sources created with `rshader::shader_source!` are watched
+    /// and reloaded whenever their files change.
+    #[cfg(feature = "render")]
+    pub shader: rshader::ShaderSource,
+    /// Number of workgroups dispatched along each axis for every tile.
+    pub dimensions: u32,
+    /// Names of layers that must already be present for the node being generated.
+    pub peer_inputs: Vec<String>,
+    /// Names of layers that must already be present for its parent. Root nodes are generated
+    /// without them.
+    pub parent_inputs: Vec<String>,
+}
+impl LayerGenerator {
+    /// Generator running `shader`, with no inputs.
+    #[cfg(feature = "render")]
+    pub fn new(shader: rshader::ShaderSource, dimensions: u32) -> Self {
+        Self { shader, dimensions, peer_inputs: Vec::new(), parent_inputs: Vec::new() }
+    }
+
+    /// Generator running GLSL source held in memory, which may include the "declarations.glsl"
+    /// and "hash.glsl" headers used by the built in generators.
+    #[cfg(feature = "render")]
+    pub fn glsl(contents: String, dimensions: u32) -> Self {
+        let mut headers = std::collections::HashMap::new();
+        headers.insert("declarations.glsl", include_str!("../shaders/declarations.glsl").into());
+        headers.insert("hash.glsl", include_str!("../shaders/hash.glsl").into());
+        Self::new(
+            rshader::ShaderSource::Inline {
+                name: "gen-custom-layer.comp",
+                contents,
+                headers,
+                defines: Vec::new(),
+            },
+            dimensions,
+        )
+    }
+
+    pub fn with_peer_inputs(mut self, layers: &[&str]) -> Self {
+        self.peer_inputs = layers.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn with_parent_inputs(mut self, layers: &[&str]) -> Self {
+        self.parent_inputs = layers.iter().map(|s| s.to_string()).collect();
+        self
+    }
+}
+
+/// A tile layer declared by the application, in addition to the built in heightmaps,
+/// displacements, albedo, roughness and normals. Pass to `Terrain::with_layers`.
+///
+/// Custom layers are never streamed from disk. Their tiles are produced on the GPU by `generator`
+/// whenever the tile cache needs them, and are evicted along with the other layers of a node.
+#[derive(Clone, Debug)]
+pub struct LayerDesc {
+    /// Unique name of the layer, which also names its shader bindings.
+    pub name: String,
+    pub texture_format: TextureFormat,
+    /// Number of samples in each dimension, per tile.
+    pub texture_resolution: u32,
+    /// Number of samples outside the tile on each side.
+    pub texture_border_size: u32,
+    /// Whether to generate a mip chain for each tile.
+    pub mipmaps: bool,
+    pub generator: LayerGenerator,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct LayerParams {
+    /// Index of this layer in the map file. There can be at most one of each layer type in a file.
+    pub layer_type: LayerType,
+    /// Name used for the layer's shader bindings and resource labels.
+    pub name: String,
+    /// Number of samples in each dimension, per tile.
+    pub texture_resolution: u32,
+    /// Number of samples outside the tile on each side.
+    pub texture_border_size: u32,
+    /// Format used by this layer.
+    pub texture_format: TextureFormat,
+    /// Maximum number of tiles for this layer to generate in a single frame.
+    pub tiles_generated_per_frame: usize,
+    /// Whether to generate a mip chain for each tile of this layer.
+    pub mipmaps: bool,
+    /// How tiles of a custom layer are produced. Built in layers have dedicated generators.
+    #[cfg(feature = "render")]
+    #[serde(skip)]
+    pub generator: Option<LayerGenerator>,
+}
+impl LayerParams {
+    #[cfg(feature = "render")]
+    pub fn custom(layer_type: LayerType, desc: LayerDesc) -> Self {
+        Self {
+            layer_type,
+            name: desc.name,
+            texture_resolution: desc.texture_resolution,
+            texture_border_size: desc.texture_border_size,
+            texture_format: desc.texture_format,
+            tiles_generated_per_frame: 16,
+            mipmaps: desc.mipmaps,
+            generator: Some(desc.generator),
+        }
+    }
+
+    /// Number of mip levels stored for each tile. Levels are only added while the resolution
+    /// divides evenly so that the tile border stays aligned at every level.
+    #[cfg(feature = "render")]
+    pub fn mip_level_count(&self) -> u32 {
+        if !self.mipmaps {
+            return 1;
+        }
+
+        let mut levels = 1;
+        let mut resolution = self.texture_resolution;
+        while resolution % 2 == 0 && resolution / 2 >= self.texture_format.block_size() {
+            resolution /= 2;
+            levels += 1;
+        }
+        levels
+    }
+
+    /// Bytes taken by one tile of this layer in the cache, counting its whole mip chain.
+    #[cfg(feature = "render")]
+    pub fn slot_bytes(&self) -> u64 {
+        let block_size = self.texture_format.block_size();
+        (0..self.mip_level_count())
+            .map(|level| {
+                let blocks = ((self.texture_resolution >> level) + block_size - 1) / block_size;
+                blocks as u64 * blocks as u64 * self.texture_format.bytes_per_block() as u64
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompress() {
+        // Ends at 255 and 0, with the first texel at the start of the ramp, the second at the
+        // end, the third one seventh of the way along and the rest at the start.
+        let red = [255, 0, 0b10_001_000, 0, 0, 0, 0, 0];
+        let texels = TextureFormat::BC4.decompress(&red, 4);
+        assert_eq!(texels.len(), 4 * 4 * 4);
+        assert_eq!(&texels[..12], &[255, 0, 0, 255, 0, 0, 0, 255, 218, 0, 0, 255]);
+        assert!(texels[12..].chunks(4).all(|t| t == [255, 0, 0, 255]));
+
+        // In the six value mode, indices six and seven are always zero and 255.
+        let green = [10, 20, 0b111_110, 0, 0, 0, 0, 0];
+        let bc5: Vec<u8> = [red, green, red, green].concat();
+        let texels = TextureFormat::BC5.decompress(&bc5, 8);
+        assert_eq!(texels.len(), 8 * 4 * 4);
+        assert_eq!(&texels[..8], &[255, 0, 0, 255, 0, 255, 0, 255]);
+        assert_eq!(&texels[8..12], &[218, 10, 0, 255]);
+        assert_eq!(&texels[32..36], &[255, 10, 0, 255]);
+        assert_eq!(TextureFormat::BC5.decompressed(), TextureFormat::RGBA8);
+        assert_eq!(TextureFormat::R32F.decompressed(), TextureFormat::R32F);
+    }
+
+    #[test]
+    fn downsample() {
+        let image = [0, 10, 20, 20, 0, 10, 20, 21, 5, 5, 255, 255, 5, 5, 255, 254];
+        assert_eq!(TextureFormat::R8.downsample(&image, 4), [5, 20, 5, 255]);
+        assert_eq!(TextureFormat::RG8.downsample(&image[..8], 2), [10, 15]);
+    }
+}
@@ -1,70 +1,102 @@
+mod layer;
+#[cfg(feature = "render")]
 mod mesh;
+#[cfg(feature = "render")]
+mod replay;
+#[cfg(feature = "render")]
 mod texture;
+#[cfg(feature = "render")]
 mod tile;
+#[cfg(feature = "render")]
+mod upload;
 
+#[cfg(feature = "render")]
 use cgmath::Vector2;
+pub(crate) use layer::LayerParams;
+pub use layer::{LayerDesc, LayerGenerator, TextureFormat};
+#[cfg(feature = "render")]
 pub(crate) use mesh::{MeshCache, MeshCacheDesc};
+#[cfg(feature = "render")]
 pub(crate) use texture::{SingularLayerCache, SingularLayerDesc};
-pub(crate) use tile::{LayerParams, TextureFormat, TileCache};
-
-use crate::{generate::GenerateTile, gpu_state::{GpuMeshLayer, GpuState}, mapfile::MapFile, terrain::quadtree::{QuadTree, VNode}};
+#[cfg(feature = "render")]
+pub use tile::NodeStatistics;
+#[cfg(feature = "render")]
+pub(crate) use tile::TileCache;
+
+#[cfg(feature = "render")]
+use crate::{
+    generate::GenerateTile,
+    gpu_state::{GpuMeshLayer, GpuState},
+    mapfile::MapFile,
+    terrain::quadtree::{QuadTree, VNode},
+};
 use serde::{Deserialize, Serialize};
+use std::cmp::{Eq, Ord, PartialOrd};
+#[cfg(any(feature = "render", test))]
+use std::collections::HashMap;
+#[cfg(any(feature = "render", test))]
 use std::hash::Hash;
+#[cfg(feature = "render")]
+use std::num::NonZeroU32;
 use std::ops::{Index, IndexMut};
-use std::{
-    cmp::{Eq, Ord, PartialOrd},
-    sync::Arc,
-};
-use std::{collections::HashMap, num::NonZeroU32};
+#[cfg(feature = "render")]
+use std::sync::Arc;
 use vec_map::VecMap;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) enum LayerType {
-    Displacements = 0,
-    Albedo = 1,
-    Roughness = 2,
-    Normals = 3,
-    Heightmaps = 4,
-}
+/// Index of a tile layer in the map file's layer registry.
+///
+/// The built in layers have fixed indices and are named by the associated constants below. Layers
+/// declared by the application with `LayerDesc` are numbered after them, and are only known
+/// through their `LayerParams`. Serializes exactly like the enum this used to be, so that tile
+/// metadata written by earlier versions can still be found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub(crate) struct LayerType(u32);
+#[allow(non_upper_case_globals)]
 impl LayerType {
+    pub const Displacements: Self = Self(0);
+    pub const Albedo: Self = Self(1);
+    pub const Roughness: Self = Self(2);
+    pub const Normals: Self = Self(3);
+    pub const Heightmaps: Self = Self(4);
+    /// Application data attached to nodes. Unlike the other layers, this is never uploaded to the
+    /// GPU and has no `LayerParams`.
+    pub const Vector: Self = Self(5);
+    /// Place names from a gazetteer. Like `Vector`, this is never uploaded to the GPU.
+    pub const Places: Self = Self(6);
+
+    /// Index of the first layer declared by the application.
+    #[cfg(feature = "render")]
+    pub const FIRST_CUSTOM: usize = 7;
+    /// Maximum number of layers, limited by the bits reserved for them in `LayerMask`.
+    #[cfg(feature = "render")]
+    pub const MAX_LAYERS: usize = 16;
+
     pub fn index(&self) -> usize {
-        *self as usize
+        self.0 as usize
     }
+    #[cfg(feature = "render")]
     pub fn from_index(i: usize) -> Self {
-        match i {
-            0 => LayerType::Displacements,
-            1 => LayerType::Albedo,
-            2 => LayerType::Roughness,
-            3 => LayerType::Normals,
-            4 => LayerType::Heightmaps,
-            _ => unreachable!(),
-        }
+        assert!(i < Self::MAX_LAYERS);
+        Self(i as u32)
     }
+    #[cfg(feature = "render")]
     pub fn bit_mask(&self) -> LayerMask {
         (*self).into()
     }
-    pub fn name(&self) -> &'static str {
-        match *self {
-            LayerType::Displacements => "displacements",
-            LayerType::Albedo => "albedo",
-            LayerType::Roughness => "roughness",
-            LayerType::Normals => "normals",
-            LayerType::Heightmaps => "heightmaps",
-        }
-    }
+    #[cfg(feature = "render")]
     fn iter() -> impl Iterator<Item = Self> {
-        (0..=4).map(Self::from_index)
+        (0..Self::MAX_LAYERS).map(Self::from_index)
     }
 }
 impl<T> Index<LayerType> for VecMap<T> {
     type Output = T;
     fn index(&self, i: LayerType) -> &Self::Output {
-        &self[i as usize]
+        &self[i.index()]
     }
 }
 impl<T> IndexMut<LayerType> for VecMap<T> {
     fn index_mut(&mut self, i: LayerType) -> &mut Self::Output {
-        &mut self[i as usize]
+        &mut self[i.index()]
     }
 }
 
@@ -73,17 +105,20 @@ pub(crate) enum MeshType {
     Grass = 0,
 }
 impl MeshType {
+    #[cfg(feature = "render")]
     pub fn name(&self) -> &'static str {
         match *self {
             MeshType::Grass => "grass",
         }
     }
+    #[cfg(feature = "render")]
     fn from_index(i: usize) -> Self {
         match i {
             0 => MeshType::Grass,
             _ => unreachable!(),
         }
     }
+    #[cfg(feature = "render")]
     fn iter() -> impl Iterator<Item = Self> {
         (0..=0).map(Self::from_index)
     }
@@ -105,17 +140,20 @@ pub(crate) enum SingularLayerType {
     GrassCanopy = 0,
 }
 impl SingularLayerType {
+    #[cfg(feature = "render")]
     pub fn name(&self) -> &'static str {
         match *self {
             SingularLayerType::GrassCanopy => "grass_canopy",
         }
     }
+    #[cfg(feature = "render")]
     fn from_index(i: usize) -> Self {
         match i {
             0 => SingularLayerType::GrassCanopy,
             _ => unreachable!(),
         }
     }
+    #[cfg(feature = "render")]
     fn iter() -> impl Iterator<Item = Self> {
         (0..=0).map(Self::from_index)
     }
@@ -132,8 +170,10 @@ impl<T> IndexMut<SingularLayerType> for VecMap<T> {
     }
 }
 
+#[cfg(feature = "render")]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub(crate) struct LayerMask(NonZeroU32);
+#[cfg(feature = "render")]
 impl LayerMask {
     const VALID: u32 = 0x80000000;
 
@@ -144,50 +184,58 @@ impl LayerMask {
         self.0.get() & other.0.get() != Self::VALID
     }
     pub fn contains_layer(&self, t: LayerType) -> bool {
-        assert!((t as usize) < 8);
-        self.0.get() & (1 << (t as usize)) != 0
+        assert!(t.index() < LayerType::MAX_LAYERS);
+        self.0.get() & (1 << t.index()) != 0
     }
 }
+#[cfg(feature = "render")]
 impl From<LayerType> for LayerMask {
     fn from(t: LayerType) -> Self {
-        assert!((t as usize) < 8);
-        Self(NonZeroU32::new(Self::VALID | (1 << (t as usize))).unwrap())
+        assert!(t.index() < LayerType::MAX_LAYERS);
+        Self(NonZeroU32::new(Self::VALID | (1 << t.index())).unwrap())
     }
 }
+#[cfg(feature = "render")]
 impl From<MeshType> for LayerMask {
     fn from(t: MeshType) -> Self {
         assert!((t as usize) < 8);
-        Self(NonZeroU32::new(Self::VALID | (1 << (t as usize + 8))).unwrap())
+        Self(NonZeroU32::new(Self::VALID | (1 << (t as usize + 16))).unwrap())
     }
 }
+#[cfg(feature = "render")]
 impl From<SingularLayerType> for LayerMask {
     fn from(t: SingularLayerType) -> Self {
-        assert!((t as usize) < 8);
-        Self(NonZeroU32::new(Self::VALID | (1 << (t as usize + 16))).unwrap())
+        assert!((t as usize) < 7);
+        Self(NonZeroU32::new(Self::VALID | (1 << (t as usize + 24))).unwrap())
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::BitOr for LayerMask {
     type Output = Self;
     fn bitor(self, rhs: Self) -> Self {
         Self(self.0 | rhs.0)
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::BitOrAssign for LayerMask {
     fn bitor_assign(&mut self, rhs: Self) {
         self.0 |= rhs.0;
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::BitAnd for LayerMask {
     type Output = Self;
     fn bitand(self, rhs: Self) -> Self {
         Self(NonZeroU32::new(Self::VALID | (self.0.get() & rhs.0.get())).unwrap())
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::BitAndAssign for LayerMask {
     fn bitand_assign(&mut self, rhs: Self) {
         self.0 = NonZeroU32::new(Self::VALID | (self.0.get() & rhs.0.get())).unwrap();
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::Not for LayerMask {
     type Output = Self;
     fn not(self) -> Self {
@@ -195,8 +243,10 @@ impl std::ops::Not for LayerMask {
     }
 }
 
+#[cfg(feature = "render")]
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub(crate) struct GeneratorMask(NonZeroU32);
+#[cfg(feature = "render")]
 impl GeneratorMask {
     const VALID: u32 = 0x80000000;
 
@@ -211,28 +261,33 @@ impl GeneratorMask {
         self.0.get() & other.0.get() != Self::VALID
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::BitOr for GeneratorMask {
     type Output = Self;
     fn bitor(self, rhs: Self) -> Self {
         Self(self.0 | rhs.0)
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::BitOrAssign for GeneratorMask {
     fn bitor_assign(&mut self, rhs: Self) {
         self.0 |= rhs.0;
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::BitAnd for GeneratorMask {
     type Output = Self;
     fn bitand(self, rhs: Self) -> Self {
         Self(NonZeroU32::new(Self::VALID | (self.0.get() & rhs.0.get())).unwrap())
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::BitAndAssign for GeneratorMask {
     fn bitand_assign(&mut self, rhs: Self) {
         self.0 = NonZeroU32::new(Self::VALID | (self.0.get() & rhs.0.get())).unwrap();
     }
 }
+#[cfg(feature = "render")]
 impl std::ops::Not for GeneratorMask {
     type Output = Self;
     fn not(self) -> Self {
@@ -240,6 +295,7 @@ impl std::ops::Not for GeneratorMask {
     }
 }
 
+#[cfg(feature = "render")]
 pub(crate) struct CacheLookup {
     pub slot: usize,
     pub offset: Vector2<u32>,
@@ -267,6 +323,7 @@ impl Ord for Priority {
     }
 }
 
+#[cfg(any(feature = "render", test))]
 pub trait PriorityCacheEntry {
     type Key: Hash + Eq;
 
@@ -274,12 +331,14 @@ pub trait PriorityCacheEntry {
     fn key(&self) -> Self::Key;
 }
 
+#[cfg(any(feature = "render", test))]
 #[derive(Default)]
 pub struct PriorityCache<T: PriorityCacheEntry> {
     size: usize,
     slots: Vec<T>,
     reverse: HashMap<T::Key, usize>,
 }
+#[cfg(any(feature = "render", test))]
 impl<T: PriorityCacheEntry> PriorityCache<T> {
     pub fn new(size: usize) -> Self {
         Self { size, slots: Vec::new(), reverse: HashMap::new() }
@@ -329,26 +388,32 @@ impl<T: PriorityCacheEntry> PriorityCache<T> {
         }
     }
 
+    #[cfg(feature = "render")]
     pub fn size(&self) -> usize {
         self.size
     }
 
+    #[cfg(feature = "render")]
     pub fn is_full(&self) -> bool {
         self.slots.len() == self.size
     }
+    #[cfg(feature = "render")]
     pub fn contains(&self, key: &T::Key) -> bool {
         self.reverse.contains_key(key)
     }
 
+    #[cfg(feature = "render")]
     pub fn slots(&self) -> &[T] {
         &*self.slots
     }
+    #[cfg(feature = "render")]
     pub fn slots_mut(&mut self) -> &mut [T] {
         &mut *self.slots
     }
     pub fn entry(&self, key: &T::Key) -> Option<&T> {
         Some(&self.slots[*self.reverse.get(key)?])
     }
+    #[cfg(feature = "render")]
     pub fn entry_mut(&mut self, key: &T::Key) -> Option<&mut T> {
         Some(&mut self.slots[*self.reverse.get(key)?])
     }
@@ -356,25 +421,69 @@ impl<T: PriorityCacheEntry> PriorityCache<T> {
     pub fn index_of(&self, key: &T::Key) -> Option<usize> {
         self.reverse.get(key).copied()
     }
+
+    /// Move entries towards the order given by `key`, exchanging at most `max_swaps` pairs of
+    /// slots. Returns the pairs exchanged, so that whatever is held elsewhere for each slot can be
+    /// moved to match.
+    pub fn compact<K: Ord>(
+        &mut self,
+        key: impl Fn(&T) -> K,
+        max_swaps: usize,
+    ) -> Vec<(usize, usize)> {
+        let mut order: Vec<usize> = (0..self.slots.len()).collect();
+        order.sort_by_key(|&i| key(&self.slots[i]));
+
+        // Current slot of the entry that started out in each slot, and the reverse.
+        let mut position: Vec<usize> = (0..self.slots.len()).collect();
+        let mut occupant = position.clone();
+        let mut swaps = Vec::new();
+        for (target, original) in order.into_iter().enumerate() {
+            if swaps.len() == max_swaps {
+                break;
+            }
+            let current = position[original];
+            if current != target {
+                let displaced = occupant[target];
+                self.slots.swap(target, current);
+                occupant.swap(target, current);
+                position[original] = target;
+                position[displaced] = current;
+                swaps.push((target, current));
+            }
+        }
+        for &(a, b) in &swaps {
+            self.reverse.insert(self.slots[a].key(), a);
+            self.reverse.insert(self.slots[b].key(), b);
+        }
+        swaps
+    }
+
+    #[cfg(feature = "render")]
+    pub fn into_entries(self) -> Vec<T> {
+        self.slots
+    }
 }
 
+#[cfg(feature = "render")]
 pub(crate) struct UnifiedPriorityCache {
     pub tiles: TileCache,
     meshes: VecMap<MeshCache>,
     textures: VecMap<SingularLayerCache>,
 }
 
+#[cfg(feature = "render")]
 impl UnifiedPriorityCache {
     pub fn new(
         device: &wgpu::Device,
         mapfile: Arc<MapFile>,
+        layers: VecMap<LayerParams>,
         size: usize,
         generators: Vec<Box<dyn GenerateTile>>,
         mesh_layers: Vec<MeshCacheDesc>,
         texture_layers: Vec<SingularLayerDesc>,
     ) -> Self {
         Self {
-            tiles: TileCache::new(mapfile, generators, size),
+            tiles: TileCache::new(mapfile, layers, generators, size),
             meshes: mesh_layers
                 .into_iter()
                 .map(|desc| (desc.ty as usize, MeshCache::new(device, desc)))
@@ -396,6 +505,11 @@ impl UnifiedPriorityCache {
     ) {
         for (i, gen) in self.tiles.generators.iter_mut().enumerate() {
             if gen.needs_refresh() {
+                // Tiles saved to disk were made by the previous version of the generator.
+                for &layer in TileCache::PERSISTED_LAYERS.iter() {
+                    let _ = mapfile.clear_generated(layer);
+                }
+
                 assert!(i < 32);
                 let mask = GeneratorMask::from_index(i);
                 for slot in self.tiles.inner.slots_mut() {
@@ -444,9 +558,11 @@ impl UnifiedPriorityCache {
         SingularLayerCache::generate_all(self, device, queue, gpu_state);
 
         self.tiles.update(quadtree);
-        self.tiles.upload_tiles(queue, &gpu_state.tile_cache);
+        self.tiles.request_paged_tiles(mapfile);
+        self.tiles.upload_tiles(device, queue, &gpu_state.tile_cache);
         TileCache::generate_tiles(self, mapfile, device, &queue, gpu_state);
         self.tiles.download_tiles();
+        self.tiles.compact(device, queue, &gpu_state.tile_cache);
 
         for m in self.meshes.values_mut() {
             m.update(quadtree);
@@ -505,3 +621,34 @@ impl UnifiedPriorityCache {
         cache.inner.index_of(&n).map(|slot| CacheLookup { slot, levels, offset })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Entry(u32, f32);
+    impl PriorityCacheEntry for Entry {
+        type Key = u32;
+        fn priority(&self) -> Priority {
+            Priority::from_f32(self.1)
+        }
+        fn key(&self) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn compact_slots() {
+        let mut cache = PriorityCache::new(5);
+        cache.insert((0..5).map(|i| Entry(i * 3 % 5, i as f32)).collect());
+
+        let swaps = cache.compact(|e| e.0, 1);
+        assert_eq!(swaps.len(), 1);
+        cache.compact(|e| e.0, 10);
+        assert!(cache.compact(|e| e.0, 10).is_empty());
+        for key in 0..5 {
+            assert_eq!(cache.index_of(&key), Some(key as usize));
+            assert_eq!(cache.entry(&key).unwrap().0, key);
+        }
+    }
+}
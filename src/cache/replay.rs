@@ -0,0 +1,141 @@
+use crate::terrain::quadtree::VNode;
+use anyhow::Error;
+use fnv::FnvHashSet;
+use serde::{Deserialize, Serialize};
+#[cfg(test)]
+use std::io::Read;
+
+/// Tiles that started or stopped being resident in the tile cache during a single frame.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StreamingFrame {
+    /// Index of the frame, counting from when recording started.
+    pub frame: u32,
+    /// Nodes that became resident this frame, sorted.
+    pub loaded: Vec<VNode>,
+    /// Nodes that were evicted this frame, sorted.
+    pub evicted: Vec<VNode>,
+}
+
+/// A record of which tiles the streaming system loaded and evicted on each frame.
+///
+/// Frames where nothing changed aren't stored, and the log is LZ4 compressed when serialized, so
+/// long camera paths produce small logs. Because each frame only depends on the ones before it, a
+/// log captured on one machine can be replayed on another to reproduce the exact contents of the
+/// tile cache at any point.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StreamingLog {
+    frames: Vec<StreamingFrame>,
+    num_frames: u32,
+    #[serde(skip)]
+    resident: FnvHashSet<VNode>,
+}
+impl StreamingLog {
+    /// Record the set of nodes resident after this frame's streaming decisions were made.
+    pub fn record(&mut self, resident: impl Iterator<Item = VNode>) {
+        let resident: FnvHashSet<VNode> = resident.collect();
+
+        let mut loaded: Vec<_> = resident.difference(&self.resident).copied().collect();
+        let mut evicted: Vec<_> = self.resident.difference(&resident).copied().collect();
+        if !loaded.is_empty() || !evicted.is_empty() {
+            loaded.sort();
+            evicted.sort();
+            self.frames.push(StreamingFrame { frame: self.num_frames, loaded, evicted });
+        }
+
+        self.resident = resident;
+        self.num_frames += 1;
+    }
+
+    /// Frames on which at least one tile was loaded or evicted.
+    #[cfg(test)]
+    pub fn frames(&self) -> &[StreamingFrame] {
+        &self.frames
+    }
+
+    /// Total number of frames recorded, including ones where nothing changed.
+    #[cfg(test)]
+    pub fn num_frames(&self) -> u32 {
+        self.num_frames
+    }
+
+    /// Reconstruct the set of resident nodes after each recorded frame.
+    #[cfg(test)]
+    pub fn replay(&self) -> Vec<FnvHashSet<VNode>> {
+        let mut resident = FnvHashSet::default();
+        let mut frames = self.frames.iter().peekable();
+        (0..self.num_frames)
+            .map(|i| {
+                if let Some(frame) = frames.next_if(|f| f.frame == i) {
+                    for node in &frame.evicted {
+                        resident.remove(node);
+                    }
+                    resident.extend(frame.loaded.iter().copied());
+                }
+                resident.clone()
+            })
+            .collect()
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut encoder = lz4::EncoderBuilder::new().build(Vec::new())?;
+        bincode::serialize_into(&mut encoder, self)?;
+        let (bytes, result) = encoder.finish();
+        result?;
+        Ok(bytes)
+    }
+
+    #[cfg(test)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut contents = Vec::new();
+        lz4::Decoder::new(bytes)?.read_to_end(&mut contents)?;
+        let mut log: Self = bincode::deserialize(&contents)?;
+        log.resident = log.replay().pop().unwrap_or_default();
+        Ok(log)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{Priority, PriorityCache, PriorityCacheEntry, TileCache};
+    use crate::terrain::quadtree::QuadTree;
+
+    fn run(frames: u32) -> (StreamingLog, Vec<FnvHashSet<VNode>>) {
+        let mut quadtree = QuadTree::new(64, VNode::DEFAULT_ELEVATION_RANGE);
+        let mut cache = PriorityCache::new(128);
+        let mut log = StreamingLog::default();
+        let mut expected = Vec::new();
+        for i in 0..frames {
+            let camera = mint::Point3 { x: 6_372_000.0, y: 200.0 * i as f64, z: 10.0 * i as f64 };
+            quadtree.update_visibility(
+                camera,
+                |_| None,
+                |_| None,
+                |_| VNode::LEVEL_CELL_2CM,
+                &[],
+                |_| Priority::none(),
+            );
+            TileCache::select_tiles(&mut cache, &quadtree);
+            let resident = cache.slots().iter().map(|e| e.key()).collect::<FnvHashSet<_>>();
+            log.record(resident.iter().copied());
+            expected.push(resident);
+        }
+        (log, expected)
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let (log, expected) = run(40);
+        assert_eq!(log.num_frames(), 40);
+
+        let decoded = StreamingLog::from_bytes(&log.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.replay(), expected);
+    }
+
+    #[test]
+    fn recording_is_deterministic() {
+        let (a, _) = run(20);
+        let (b, _) = run(20);
+        assert_eq!(a.frames(), b.frames());
+    }
+}
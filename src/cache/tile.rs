@@ -1,121 +1,122 @@
 use crate::{cache::{self, Priority, PriorityCacheEntry}, terrain::quadtree::{QuadTree, VNode}};
 use crate::{
     coordinates,
+    deformation::HeightDelta,
     stream::{TileResult, TileStreamerEndpoint},
+    vector::{VectorTileCallback, VectorTileId},
 };
 use crate::{
-    generate::GenerateTile,
+    generate::{heightmap, GenerateTile, MipmapGen},
     gpu_state::GpuState,
-    mapfile::{MapFile, TileState},
+    mapfile::{AlbedoBlend, MapFile, TileProvenance, TileState},
 };
+use anyhow::{ensure, format_err, Error};
 use cache::{LayerType, PriorityCache};
 use cgmath::Vector3;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::future::BoxFuture;
 use futures::future::FutureExt;
 use futures::stream::futures_unordered::FuturesUnordered;
 use futures::StreamExt;
-use serde::{Deserialize, Serialize};
-use std::{num::NonZeroU32, sync::Arc};
+use std::{num::NonZeroU32, sync::Arc, time::SystemTime};
 use vec_map::VecMap;
 
-use super::{GeneratorMask, LayerMask, UnifiedPriorityCache};
-
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
-pub enum TextureFormat {
-    R8,
-    RG8,
-    RGBA8,
-    RGBA16F,
-    R32F,
-    RG32F,
-    RGBA32F,
-    SRGBA,
-    BC4,
-    BC5,
-}
-impl TextureFormat {
-    /// Returns the number of bytes in a single texel of the format. Actually reports bytes per
-    /// block for compressed formats.
-    pub fn bytes_per_block(&self) -> usize {
-        match *self {
-            TextureFormat::R8 => 1,
-            TextureFormat::RG8 => 2,
-            TextureFormat::RGBA8 => 4,
-            TextureFormat::RGBA16F => 8,
-            TextureFormat::R32F => 4,
-            TextureFormat::RG32F => 8,
-            TextureFormat::RGBA32F => 16,
-            TextureFormat::SRGBA => 4,
-            TextureFormat::BC4 => 8,
-            TextureFormat::BC5 => 16,
-        }
-    }
-    pub fn to_wgpu(&self) -> wgpu::TextureFormat {
-        match *self {
-            TextureFormat::R8 => wgpu::TextureFormat::R8Unorm,
-            TextureFormat::RG8 => wgpu::TextureFormat::Rg8Unorm,
-            TextureFormat::RGBA8 => wgpu::TextureFormat::Rgba8Unorm,
-            TextureFormat::RGBA16F => wgpu::TextureFormat::Rgba16Float,
-            TextureFormat::R32F => wgpu::TextureFormat::R32Float,
-            TextureFormat::RG32F => wgpu::TextureFormat::Rg32Float,
-            TextureFormat::RGBA32F => wgpu::TextureFormat::Rgba32Float,
-            TextureFormat::SRGBA => wgpu::TextureFormat::Rgba8UnormSrgb,
-            TextureFormat::BC4 => wgpu::TextureFormat::Bc4RUnorm,
-            TextureFormat::BC5 => wgpu::TextureFormat::Bc5RgUnorm,
-        }
-    }
-    pub fn block_size(&self) -> u32 {
-        match *self {
-            TextureFormat::BC4 | TextureFormat::BC5 => 4,
-            TextureFormat::R8
-            | TextureFormat::RG8
-            | TextureFormat::RGBA8
-            | TextureFormat::RGBA16F
-            | TextureFormat::R32F
-            | TextureFormat::RG32F
-            | TextureFormat::RGBA32F
-            | TextureFormat::SRGBA => 1,
-        }
-    }
-    pub fn is_compressed(&self) -> bool {
-        match *self {
-            TextureFormat::BC4 | TextureFormat::BC5 => true,
-            TextureFormat::R8
-            | TextureFormat::RG8
-            | TextureFormat::RGBA8
-            | TextureFormat::RGBA16F
-            | TextureFormat::R32F
-            | TextureFormat::RG32F
-            | TextureFormat::RGBA32F
-            | TextureFormat::SRGBA => false,
-        }
-    }
-}
-
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
-pub(crate) struct ByteRange {
-    pub offset: usize,
-    pub length: usize,
-}
+use super::{
+    replay::StreamingLog, upload::UploadBelt, GeneratorMask, LayerMask, LayerParams,
+    UnifiedPriorityCache,
+};
+use crate::biome::{Biome, BIOME_LAYER};
+use crate::places::Place;
+use crate::planet::PlanetConfig;
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub(crate) struct LayerParams {
-    /// What kind of layer this is. There can be at most one of each layer type in a file.
-    pub layer_type: LayerType,
-    /// Number of samples in each dimension, per tile.
-    pub texture_resolution: u32,
-    /// Number of samples outside the tile on each side.
-    pub texture_border_size: u32,
-    /// Format used by this layer.
-    pub texture_format: TextureFormat,
-    /// Maximum number of tiles for this layer to generate in a single frame.
-    pub tiles_generated_per_frame: usize,
+/// Summary of the terrain within a node, returned by `Terrain::node_statistics`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NodeStatistics {
+    /// Lowest elevation within the node, in meters.
+    pub min_elevation: f32,
+    /// Highest elevation within the node, in meters.
+    pub max_elevation: f32,
+    /// Average elevation across the node, in meters.
+    pub mean_elevation: f32,
+    /// Average slope of the terrain as rise over run, so zero for flat ground and one for 45
+    /// degree slopes.
+    pub ruggedness: f32,
 }
 
 enum CpuHeightmap {
     I16(Arc<Vec<i16>>),
     F32(Arc<Vec<f32>>),
 }
+impl CpuHeightmap {
+    /// Add `delta` to the heights, or subtract it if `sign` is negative.
+    fn add_delta(&mut self, delta: &HeightDelta, resolution: usize, sign: i32) {
+        match self {
+            CpuHeightmap::I16(h) => delta.add_to(Arc::make_mut(h), resolution, sign),
+            CpuHeightmap::F32(h) => {
+                let h = Arc::make_mut(h);
+                for (i, d) in delta.offsets(resolution) {
+                    h[i] += (d * sign.signum()) as f32;
+                }
+            }
+        }
+    }
+
+    fn get(&self, index: usize) -> f32 {
+        match self {
+            CpuHeightmap::I16(h) => h[index] as f32,
+            CpuHeightmap::F32(h) => h[index],
+        }
+    }
+
+    fn elevation_range(&self) -> (f32, f32) {
+        match self {
+            CpuHeightmap::I16(h) => h.iter().fold((f32::MAX, f32::MIN), |(min, max), &h| {
+                (min.min(h as f32), max.max(h as f32))
+            }),
+            CpuHeightmap::F32(h) => {
+                h.iter().fold((f32::MAX, f32::MIN), |(min, max), &h| (min.min(h), max.max(h)))
+            }
+        }
+    }
+
+    /// Elevation and slope statistics over the samples inside the node, excluding the border
+    /// shared with its neighbors.
+    fn statistics(&self, layers: &VecMap<LayerParams>, node: VNode) -> NodeStatistics {
+        let resolution = layers[LayerType::Heightmaps].texture_resolution as usize;
+        let border = layers[LayerType::Heightmaps].texture_border_size as usize;
+        let spacing = node.aprox_side_length() / (resolution - 2 * border - 1) as f32;
+        let height = |x: usize, y: usize| self.get(x + y * resolution);
+
+        let (mut min, mut max) = (f32::MAX, f32::MIN);
+        let (mut elevation_sum, mut slope_sum) = (0.0f64, 0.0f64);
+        for y in border..(resolution - border) {
+            for x in border..(resolution - border) {
+                let h = height(x, y);
+                min = min.min(h);
+                max = max.max(h);
+                elevation_sum += h as f64;
+
+                let dx = height(x + 1, y) - height(x - 1, y);
+                let dy = height(x, y + 1) - height(x, y - 1);
+                slope_sum += ((dx * dx + dy * dy).sqrt() / (2.0 * spacing)) as f64;
+            }
+        }
+
+        let samples = ((resolution - 2 * border) * (resolution - 2 * border)) as f64;
+        NodeStatistics {
+            min_elevation: min,
+            max_elevation: max,
+            mean_elevation: (elevation_sum / samples) as f32,
+            ruggedness: (slope_sum / samples) as f32,
+        }
+    }
+
+    /// Estimate the maximum vertical error, in meters, from rendering a node's mesh with only
+    /// every other vertex. This is roughly the error of drawing this node instead of its children.
+    fn geometric_error(&self, layers: &VecMap<LayerParams>, planet: &PlanetConfig) -> f32 {
+        heightmap::geometric_error(layers, planet, |i| self.get(i))
+    }
+}
 
 pub(super) struct Entry {
     /// How imporant this entry is for the current frame.
@@ -130,6 +131,18 @@ pub(super) struct Entry {
     streaming: LayerMask,
     /// A CPU copy of the heightmap tile, useful for collision detection and such.
     heightmap: Option<CpuHeightmap>,
+    /// Maximum height error from rendering this node rather than its children, if known.
+    geometric_error: Option<f32>,
+    /// Minimum and maximum elevation of the heightmap tile, if known.
+    elevation_range: Option<(f32, f32)>,
+    /// Summary of the heightmap tile, if known.
+    statistics: Option<NodeStatistics>,
+    /// A CPU copy of the biome index of each sample in the biome tile, if it has been read back.
+    biomes: Option<Vec<u8>>,
+    /// Places stored in the place tile of this node, if it has been loaded.
+    places: Vec<Place>,
+    /// Where the heightmap tile came from and when it was produced, once that has been decided.
+    provenance: Option<(TileProvenance, SystemTime)>,
     /// Map from layer to the generators that were used (perhaps indirectly) to produce it.
     pub(super) generators: VecMap<GeneratorMask>,
 }
@@ -142,10 +155,31 @@ impl Entry {
             generated: LayerMask::empty(),
             streaming: LayerMask::empty(),
             heightmap: None,
+            geometric_error: None,
+            elevation_range: None,
+            statistics: None,
+            biomes: None,
+            places: Vec::new(),
+            provenance: None,
             generators: VecMap::new(),
         }
     }
 }
+impl Entry {
+    /// Mark every layer held on the GPU as missing, except that heightmaps with a CPU copy are
+    /// uploaded again by `upload_tiles` if the node is in `pending_restores`. The other layers are
+    /// then regenerated from them.
+    fn invalidate_gpu_layers(&mut self) {
+        let cpu_layers = LayerType::Vector.bit_mask() | LayerType::Places.bit_mask();
+        let heightmap_generators = self.generators.remove(LayerType::Heightmaps.index());
+        self.generators = VecMap::new();
+        if let Some(generators) = heightmap_generators {
+            self.generators.insert(LayerType::Heightmaps.index(), generators);
+        }
+        self.valid = self.valid & cpu_layers;
+        self.generated = self.generated & LayerType::Heightmaps.bit_mask();
+    }
+}
 impl PriorityCacheEntry for Entry {
     type Key = VNode;
     fn priority(&self) -> Priority {
@@ -160,29 +194,276 @@ pub(crate) struct TileCache {
     pub(super) inner: PriorityCache<Entry>,
     pub(super) layers: VecMap<LayerParams>,
     pub(super) generators: Vec<Box<dyn GenerateTile>>,
+    /// Parameters of layers as stored in the map file, for those whose tiles are held at a lower
+    /// resolution or decompressed in the cache.
+    stored_layers: VecMap<LayerParams>,
+    /// Heights are raised to sea level and clamped to the elevation range of this planet.
+    planet: PlanetConfig,
+    /// Layer that biomes are generated into, whose tiles are read back for `get_biome`.
+    biome_layer: Option<LayerType>,
+
+    /// Used to fill in the mip chains of layers that have them.
+    mipmap_generators: VecMap<MipmapGen>,
+    /// Tiles whose top mip level changed since their mip chain was last generated.
+    pending_mipmaps: Vec<(VNode, LayerType)>,
 
     streamer: TileStreamerEndpoint,
+    /// Nodes whose albedo tile was being streamed when the albedo blend changed.
+    stale_albedo: FnvHashSet<VNode>,
     pending_heightmap_downloads:
         FuturesUnordered<BoxFuture<'static, Result<(VNode, wgpu::Buffer), ()>>>,
+
+    /// Whether generated tiles of `PERSISTED_LAYERS` are read back and saved to disk.
+    persist_generated: bool,
+    pending_write_backs:
+        FuturesUnordered<BoxFuture<'static, Result<(VNode, LayerType, wgpu::Buffer), ()>>>,
+
+    /// Staging buffers that streamed and restored tiles are copied to the GPU through.
+    upload_belt: UploadBelt,
+    /// Two tile textures of each layer that `compact` swaps slots through.
+    compaction_scratch: VecMap<wgpu::Texture>,
+
+    /// Record of streaming decisions, if one is being captured.
+    streaming_log: Option<StreamingLog>,
+
+    /// Nodes whose vector tiles have been passed to `vector_tile_callback` and not yet unloaded.
+    loaded_vector_tiles: FnvHashSet<VNode>,
+    vector_tile_callback: Option<VectorTileCallback>,
+    /// Whether place tiles are loaded for resident nodes.
+    load_places: bool,
+    /// Nodes whose heightmaps were carried over by `restore_from` or edited, and still have to be
+    /// uploaded.
+    pending_restores: Vec<VNode>,
+    /// Height deltas applied at runtime, by the node they change, which are added to its heightmap
+    /// whenever it is loaded.
+    height_deltas: FnvHashMap<VNode, Vec<HeightDelta>>,
+    /// Whether the geometric error or elevation range of any node became known or changed since the
+    /// last call to `take_bounds_changed`.
+    bounds_changed: bool,
 }
 impl TileCache {
-    pub fn new(mapfile: Arc<MapFile>, generators: Vec<Box<dyn GenerateTile>>, size: usize) -> Self {
+    /// Layers whose generated tiles can be saved to disk. Heightmaps are excluded because stored
+    /// heightmap tiles are encoded relative to their parents, and albedo and roughness because
+    /// they depend on the albedo blend.
+    pub(crate) const PERSISTED_LAYERS: [LayerType; 1] = [LayerType::Normals];
+
+    /// Most pairs of slots exchanged by `compact` per frame.
+    const COMPACTION_SWAPS_PER_FRAME: usize = 4;
+
+    /// Layers whose tiles are stored at half resolution in compact mode.
+    const COMPACT_LAYERS: [LayerType; 3] =
+        [LayerType::Albedo, LayerType::Roughness, LayerType::Normals];
+
+    /// Create a cache holding `size` tiles of each of `layers`, as returned by `cache_layers`.
+    pub fn new(
+        mapfile: Arc<MapFile>,
+        layers: VecMap<LayerParams>,
+        generators: Vec<Box<dyn GenerateTile>>,
+        size: usize,
+    ) -> Self {
         Self {
             inner: PriorityCache::new(size),
-            layers: mapfile.layers().clone(),
+            stored_layers: mapfile
+                .layers()
+                .iter()
+                .filter(|(i, layer)| {
+                    layer.texture_format != layers[*i].texture_format
+                        || layer.texture_resolution != layers[*i].texture_resolution
+                })
+                .map(|(i, layer)| (i, layer.clone()))
+                .collect(),
+            planet: *mapfile.planet(),
+            biome_layer: mapfile.layer_by_name(BIOME_LAYER),
+            mipmap_generators: layers
+                .iter()
+                .filter(|(_, layer)| layer.mipmaps)
+                .map(|(i, layer)| (i, MipmapGen::new(layer.clone())))
+                .collect(),
+            layers,
+            pending_mipmaps: Vec::new(),
             streamer: TileStreamerEndpoint::new(mapfile).unwrap(),
+            stale_albedo: FnvHashSet::default(),
             generators,
             pending_heightmap_downloads: FuturesUnordered::new(),
+            persist_generated: false,
+            pending_write_backs: FuturesUnordered::new(),
+            upload_belt: UploadBelt::new(),
+            compaction_scratch: VecMap::new(),
+            streaming_log: None,
+            loaded_vector_tiles: FnvHashSet::default(),
+            vector_tile_callback: None,
+            load_places: false,
+            pending_restores: Vec::new(),
+            bounds_changed: false,
+            height_deltas: FnvHashMap::default(),
         }
     }
 
+    /// Parameters of the layers of `mapfile` as held in the cache. In `compact` mode, texture
+    /// layers have half the resolution and border. Compressed layers are replaced with their
+    /// `TextureFormat::decompressed` equivalents without `compressed_textures`, or if halving
+    /// their resolution leaves partial blocks.
+    pub(crate) fn cache_layers(
+        mapfile: &MapFile,
+        compressed_textures: bool,
+        compact: bool,
+    ) -> VecMap<LayerParams> {
+        let mut layers = mapfile.layers().clone();
+        for layer in layers.values_mut() {
+            if compact && Self::COMPACT_LAYERS.contains(&layer.layer_type) {
+                assert_eq!(layer.texture_resolution % 2, 0);
+                assert_eq!(layer.texture_border_size % 2, 0);
+                layer.texture_resolution /= 2;
+                layer.texture_border_size /= 2;
+            }
+            if !compressed_textures
+                || layer.texture_resolution % layer.texture_format.block_size() != 0
+            {
+                layer.texture_format = layer.texture_format.decompressed();
+            }
+        }
+        layers
+    }
+
+    /// Whether tiles of compressed layers are held compressed on the GPU.
+    pub(crate) fn compressed_textures(&self) -> bool {
+        self.stored_layers.iter().all(|(i, stored)| {
+            !stored.texture_format.is_compressed() || self.layers[i].texture_format.is_compressed()
+        })
+    }
+
+    /// Convert a streamed tile from the format and resolution it is stored in to those of the
+    /// cache.
+    fn convert_tile(stored: &LayerParams, layer: &LayerParams, data: &[u8]) -> Vec<u8> {
+        let mut data = if stored.texture_format != layer.texture_format {
+            stored.texture_format.decompress(data, stored.texture_resolution as usize)
+        } else {
+            data.to_vec()
+        };
+        let mut resolution = stored.texture_resolution;
+        while resolution > layer.texture_resolution {
+            data = layer.texture_format.downsample(&data, resolution as usize);
+            resolution /= 2;
+        }
+        data
+    }
+
+    /// Take over everything held on the CPU by `old`, a cache whose textures were lost along with
+    /// the device they were created on. Heightmap tiles with CPU copies are uploaded again by the
+    /// next `upload_tiles`, so that the other layers can be regenerated from them without the
+    /// heightmaps having to be streamed or generated first. Vector and place tiles stay loaded.
+    pub(crate) fn restore_from(&mut self, old: TileCache) {
+        let mut entries = Vec::new();
+        for mut entry in old.inner.into_entries() {
+            if entry.heightmap.is_some() {
+                self.pending_restores.push(entry.node);
+            }
+            entry.invalidate_gpu_layers();
+            entry.streaming = LayerMask::empty();
+            entries.push(entry);
+        }
+        self.inner.insert(entries);
+
+        self.vector_tile_callback = old.vector_tile_callback;
+        self.loaded_vector_tiles = old.loaded_vector_tiles;
+        self.load_places = old.load_places;
+        self.streaming_log = old.streaming_log;
+    }
+
     pub(super) fn update(&mut self, quadtree: &QuadTree) {
-        // Update priorities
+        Self::select_tiles(&mut self.inner, quadtree);
+        if let Some(ref mut log) = self.streaming_log {
+            log.record(self.inner.slots().iter().map(|entry| entry.node));
+        }
+
+        // Report vector tiles whose nodes were evicted.
+        if let Some(ref mut callback) = self.vector_tile_callback {
+            let inner = &self.inner;
+            self.loaded_vector_tiles.retain(|node| {
+                let resident = inner.contains(node);
+                if !resident {
+                    callback(VectorTileId(*node), None);
+                }
+                resident
+            });
+        }
+    }
+
+    /// Replace the callback that vector tiles are passed to. Tiles loaded with the previous
+    /// callback are reported to it as unloaded, and every resident tile is loaded again for the
+    /// new one.
+    pub fn set_vector_tile_callback(&mut self, callback: Option<VectorTileCallback>) {
+        if let Some(ref mut old) = self.vector_tile_callback {
+            for node in self.loaded_vector_tiles.drain() {
+                old(VectorTileId(node), None);
+            }
+        }
+        self.loaded_vector_tiles.clear();
         for entry in self.inner.slots_mut() {
+            entry.valid &= !LayerType::Vector.bit_mask();
+        }
+        self.vector_tile_callback = callback;
+    }
+
+    /// Set whether place tiles are loaded for resident nodes. Disabling it drops every loaded
+    /// place.
+    pub fn set_load_places(&mut self, enabled: bool) {
+        if !enabled {
+            for entry in self.inner.slots_mut() {
+                entry.valid &= !LayerType::Places.bit_mask();
+                entry.places = Vec::new();
+            }
+        }
+        self.load_places = enabled;
+    }
+
+    /// Places in the place tiles of every resident node.
+    pub fn resident_places(&self) -> impl Iterator<Item = &Place> {
+        self.inner.slots().iter().flat_map(|entry| entry.places.iter())
+    }
+
+    /// Start streaming the vector and place tiles of any newly resident nodes. Vector tiles are
+    /// only loaded while there is a callback to pass them to, and place tiles while they are
+    /// enabled with `set_load_places`.
+    pub(super) fn request_paged_tiles(&mut self, mapfile: &MapFile) {
+        let mut layers = Vec::new();
+        if self.vector_tile_callback.is_some() {
+            layers.push(LayerType::Vector);
+        }
+        if self.load_places {
+            layers.push(LayerType::Places);
+        }
+
+        for entry in self.inner.slots_mut() {
+            for &layer in &layers {
+                let mask = layer.bit_mask();
+                if (entry.valid | entry.streaming).intersects(mask) {
+                    continue;
+                }
+                match mapfile.tile_state(layer, entry.node).unwrap() {
+                    TileState::Base | TileState::Generated => {
+                        if self.streamer.num_inflight() < 128 {
+                            entry.streaming |= mask;
+                            self.streamer.request_tile(entry.node, layer);
+                        }
+                    }
+                    // Nothing is stored for this node, so there is nothing to load.
+                    _ => entry.valid |= mask,
+                }
+            }
+        }
+    }
+
+    /// Decide which tiles should be resident based on the priorities computed by `quadtree`.
+    /// This only depends on the previous contents of the cache and the quadtree, so it is fully
+    /// deterministic.
+    pub(super) fn select_tiles(inner: &mut PriorityCache<Entry>, quadtree: &QuadTree) {
+        // Update priorities
+        for entry in inner.slots_mut() {
             entry.priority = quadtree.node_priority(entry.node);
         }
         let min_priority =
-            self.inner.slots().iter().map(|s| s.priority).min().unwrap_or(Priority::none());
+            inner.slots().iter().map(|s| s.priority).min().unwrap_or(Priority::none());
 
         // Find any tiles that may need to be added.
         let mut missing = Vec::new();
@@ -191,13 +472,148 @@ impl TileCache {
             if priority < Priority::cutoff() {
                 return false;
             }
-            if !self.inner.contains(&node) && (priority > min_priority || !self.inner.is_full()) {
+            if !inner.contains(&node) && (priority > min_priority || !inner.is_full()) {
                 missing.push(Entry::new(node, priority));
             }
 
             node.level() < VNode::LEVEL_CELL_2CM
         });
-        self.inner.insert(missing);
+        inner.insert(missing);
+    }
+
+    /// Start recording which tiles are loaded and evicted each frame, discarding any previous
+    /// recording.
+    pub fn start_recording(&mut self) {
+        self.streaming_log = Some(StreamingLog::default());
+    }
+
+    /// Stop recording and return the log, if recording was in progress.
+    pub fn finish_recording(&mut self) -> Option<StreamingLog> {
+        self.streaming_log.take()
+    }
+
+    /// Add `delta` to the heightmap of its node, or remove a delta added before if `revert` is
+    /// set. If the heightmap is loaded it is uploaded again and the node's other layers are
+    /// regenerated from it.
+    pub fn apply_height_delta(&mut self, delta: &HeightDelta, revert: bool) -> Result<(), Error> {
+        let resolution = self.layers[LayerType::Heightmaps].texture_resolution as usize;
+        ensure!(delta.fits(resolution), "Height delta extends past the edge of its tile");
+
+        let node = delta.vnode();
+        let deltas = self.height_deltas.entry(node).or_default();
+        if revert {
+            let index = deltas
+                .iter()
+                .position(|d| d == delta)
+                .ok_or_else(|| format_err!("Height delta to revert was never applied"))?;
+            deltas.remove(index);
+            if deltas.is_empty() {
+                self.height_deltas.remove(&node);
+            }
+        } else {
+            deltas.push(delta.clone());
+        }
+
+        if let Some(entry) = self.inner.entry_mut(&node) {
+            if let Some(ref mut heightmap) = entry.heightmap {
+                heightmap.add_delta(delta, resolution, if revert { -1 } else { 1 });
+                let geometric_error = Some(heightmap.geometric_error(&self.layers, &self.planet));
+                self.bounds_changed |= entry.geometric_error != geometric_error;
+                entry.geometric_error = geometric_error;
+                let elevation_range = Some(heightmap.elevation_range());
+                self.bounds_changed |= entry.elevation_range != elevation_range;
+                entry.elevation_range = elevation_range;
+                entry.statistics = Some(heightmap.statistics(&self.layers, node));
+                entry.invalidate_gpu_layers();
+                self.pending_restores.push(node);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn height_deltas(&self) -> &FnvHashMap<VNode, Vec<HeightDelta>> {
+        &self.height_deltas
+    }
+
+    pub fn set_height_deltas(&mut self, deltas: FnvHashMap<VNode, Vec<HeightDelta>>) {
+        self.height_deltas = deltas;
+    }
+
+    pub fn albedo_blend(&self) -> Option<&AlbedoBlend> {
+        self.streamer.albedo_blend()
+    }
+
+    /// Change which albedo epochs are crossfaded. All albedo tiles are reloaded if the blend
+    /// differs from the current one.
+    pub fn set_albedo_blend(&mut self, blend: Option<AlbedoBlend>) {
+        if blend.as_ref() == self.streamer.albedo_blend() {
+            return;
+        }
+        self.streamer.set_albedo_blend(blend);
+
+        for entry in self.inner.slots_mut() {
+            if entry.streaming.contains_layer(LayerType::Albedo) {
+                self.stale_albedo.insert(entry.node);
+            }
+            entry.valid &= !LayerType::Albedo.bit_mask();
+        }
+    }
+
+    pub fn persist_generated(&self) -> bool {
+        self.persist_generated
+    }
+
+    /// Start or stop saving newly generated tiles to disk. Saved tiles are streamed in the future
+    /// rather than being generated again.
+    pub fn set_persist_generated(&mut self, enabled: bool) {
+        self.persist_generated = enabled;
+    }
+
+    /// Size in bytes of each row of a tile as stored, of each row when copied into a buffer, and
+    /// the number of rows.
+    fn readback_layout(layer: &LayerParams) -> (usize, usize, usize) {
+        let rows = (layer.texture_resolution / layer.texture_format.block_size()) as usize;
+        let row_bytes = rows * layer.texture_format.bytes_per_block();
+        (row_bytes, (row_bytes + 255) & !255, rows)
+    }
+
+    /// Record a copy of the top mip level of the tile in `slot` into a new buffer that can be
+    /// mapped for reading.
+    fn copy_tile_to_buffer(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        layer: &LayerParams,
+        slot: usize,
+    ) -> wgpu::Buffer {
+        let (_, row_pitch, rows) = Self::readback_layout(layer);
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: (row_pitch * rows) as u64,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            label: Some(&format!("buffer.tiles.write_back.{}", layer.name)),
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &gpu_state.tile_cache[layer.layer_type],
+                mip_level: 0,
+                origin: wgpu::Origin3d { x: 0, y: 0, z: slot as u32 },
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(NonZeroU32::new(row_pitch as u32).unwrap()),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: layer.texture_resolution,
+                height: layer.texture_resolution,
+                depth_or_array_layers: 1,
+            },
+        );
+        buffer
     }
 
     pub(super) fn generate_tiles(
@@ -208,6 +624,7 @@ impl TileCache {
         gpu_state: &GpuState,
     ) {
         let mut planned_heightmap_downloads = Vec::new();
+        let mut planned_write_backs = Vec::new();
         let mut pending_generate = VecMap::new();
 
         for layer in cache.tiles.layers.values() {
@@ -245,6 +662,12 @@ impl TileCache {
                         pending_generate.push(entry.node);
                     }
                 }
+
+                // Tiles generated on the fly are stamped with the time they were requested.
+                if ty == LayerType::Heightmaps && entry.provenance.is_none() {
+                    let (provenance, modified) = mapfile.tile_provenance(ty, entry.node);
+                    entry.provenance = Some((provenance, modified.unwrap_or_else(SystemTime::now)));
+                }
             }
 
             pending_generate.sort_by_key(|n| n.level());
@@ -296,6 +719,13 @@ impl TileCache {
                         };
 
                         let output_mask = !entry.valid & generator.outputs(n.level());
+                        log::trace!(
+                            target: crate::generate::LOG_TARGET,
+                            "generating {:?} tile {:?} with generator {}",
+                            layer,
+                            n,
+                            generator_index
+                        );
                         generator.generate(
                             device,
                             &mut encoder,
@@ -321,6 +751,9 @@ impl TileCache {
                             LayerType::iter().filter(|&layer| output_mask.contains_layer(layer))
                         {
                             entry.generators.insert(layer.index(), input_generators);
+                            if cache.tiles.layers[layer].mipmaps {
+                                cache.tiles.pending_mipmaps.push((*n, layer));
+                            }
                         }
 
                         if output_mask.contains_layer(LayerType::Heightmaps)
@@ -367,11 +800,57 @@ impl TileCache {
                             planned_heightmap_downloads.push((*n, buffer));
                         }
 
+                        if cache.tiles.persist_generated {
+                            for &persisted in Self::PERSISTED_LAYERS.iter() {
+                                // Tiles held at a lower resolution or decompressed can't be
+                                // saved in the format of the map file.
+                                if output_mask.contains_layer(persisted)
+                                    && !cache.tiles.stored_layers.contains_key(persisted.index())
+                                {
+                                    let buffer = Self::copy_tile_to_buffer(
+                                        device,
+                                        &mut encoder,
+                                        gpu_state,
+                                        &cache.tiles.layers[persisted],
+                                        slot,
+                                    );
+                                    planned_write_backs.push((*n, persisted, buffer));
+                                }
+                            }
+                        }
+                        if let Some(biomes) = cache.tiles.biome_layer {
+                            if output_mask.contains_layer(biomes) {
+                                let buffer = Self::copy_tile_to_buffer(
+                                    device,
+                                    &mut encoder,
+                                    gpu_state,
+                                    &cache.tiles.layers[biomes],
+                                    slot,
+                                );
+                                planned_write_backs.push((*n, biomes, buffer));
+                            }
+                        }
+
                         break;
                     }
                 }
             }
         }
+
+        for generator in cache.tiles.mipmap_generators.values_mut() {
+            generator.refresh();
+        }
+        for (n, layer) in cache.tiles.pending_mipmaps.drain(..) {
+            if let Some(slot) = cache.tiles.inner.index_of(&n) {
+                cache.tiles.mipmap_generators[layer].generate(
+                    device,
+                    &mut encoder,
+                    gpu_state,
+                    slot,
+                );
+            }
+        }
+
         queue.submit(Some(encoder.finish()));
 
         for (n, buffer) in planned_heightmap_downloads.drain(..) {
@@ -388,12 +867,95 @@ impl TileCache {
                     .boxed(),
             );
         }
+        for (n, layer, buffer) in planned_write_backs {
+            cache.tiles.pending_write_backs.push(
+                buffer
+                    .slice(..)
+                    .map_async(wgpu::MapMode::Read)
+                    .then(move |result| {
+                        futures::future::ready(match result {
+                            Ok(()) => Ok((n, layer, buffer)),
+                            Err(_) => Err(()),
+                        })
+                    })
+                    .boxed(),
+            );
+        }
     }
 
-    pub(super) fn upload_tiles(&mut self, queue: &wgpu::Queue, textures: &VecMap<wgpu::Texture>) {
-        while let Some(mut tile) = self.streamer.try_complete() {
+    pub(super) fn upload_tiles(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        textures: &VecMap<wgpu::Texture>,
+    ) {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.tiles.upload"),
+        });
+
+        let resolution = self.resolution(LayerType::Heightmaps);
+        let row_bytes = self.resolution_blocks(LayerType::Heightmaps) as usize
+            * self.layers[LayerType::Heightmaps].texture_format.bytes_per_block();
+        for node in std::mem::take(&mut self.pending_restores) {
+            let index = match self.inner.index_of(&node) {
+                Some(index) => index,
+                None => continue,
+            };
+            let entry = &mut self.inner.slots_mut()[index];
+            if entry.valid.contains_layer(LayerType::Heightmaps) {
+                continue;
+            }
+            let heights: Vec<f32> = match entry.heightmap {
+                Some(CpuHeightmap::I16(ref h)) => h.iter().map(|&h| h as f32).collect(),
+                Some(CpuHeightmap::F32(ref h)) => h.to_vec(),
+                None => continue,
+            };
+            entry.valid |= LayerType::Heightmaps.bit_mask();
+
+            self.upload_belt.copy_to_texture(
+                device,
+                &mut encoder,
+                bytemuck::cast_slice(&heights),
+                row_bytes,
+                wgpu::ImageCopyTexture {
+                    texture: &textures[LayerType::Heightmaps],
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x: 0, y: 0, z: index as u32 },
+                },
+                wgpu::Extent3d { width: resolution, height: resolution, depth_or_array_layers: 1 },
+            );
+            if self.layers[LayerType::Heightmaps].mipmaps {
+                self.pending_mipmaps.push((node, LayerType::Heightmaps));
+            }
+        }
+
+        while let Some(tile) = self.streamer.try_complete() {
+            if let TileResult::Vector(node, ref data) = tile {
+                if let Some(entry) = self.inner.entry_mut(&node) {
+                    entry.valid |= LayerType::Vector.bit_mask();
+                    entry.streaming &= !LayerType::Vector.bit_mask();
+                    if let Some(ref mut callback) = self.vector_tile_callback {
+                        callback(VectorTileId(node), Some(data));
+                        self.loaded_vector_tiles.insert(node);
+                    }
+                }
+                continue;
+            }
+            if let TileResult::Places(node, places) = tile {
+                if let Some(entry) = self.inner.entry_mut(&node) {
+                    entry.valid |= LayerType::Places.bit_mask();
+                    entry.streaming &= !LayerType::Places.bit_mask();
+                    entry.places = places;
+                }
+                continue;
+            }
+
+            // Stale albedo tiles are still uploaded, but will be requested again next frame.
+            let stale = tile.layer() == LayerType::Albedo && self.stale_albedo.remove(&tile.node());
             if let Some(entry) = self.inner.entry_mut(&tile.node()) {
-                entry.valid |= tile.layer().bit_mask();
+                if !stale {
+                    entry.valid |= tile.layer().bit_mask();
+                }
                 entry.streaming &= !tile.layer().bit_mask();
 
                 let index = self.inner.index_of(&tile.node()).unwrap();
@@ -404,24 +966,56 @@ impl TileCache {
                 let bytes_per_block = self.layers[tile.layer()].texture_format.bytes_per_block();
                 let row_bytes = resolution_blocks * bytes_per_block;
 
-                let data;
-                let mut height_data;
+                let mut data: &[u8];
+                let height_data: Vec<u8>;
+                let converted;
+                let traced;
                 match tile {
-                    TileResult::Heightmaps(node, ref heights) => {
+                    TileResult::Heightmaps(node, ref heights, stored_error) => {
+                        let (heights, stored_error) = match self.height_deltas.get(&node) {
+                            Some(deltas) => {
+                                let mut heights = heights.to_vec();
+                                for delta in deltas {
+                                    delta.add_to(&mut heights, resolution, 1);
+                                }
+                                (Arc::new(heights), None)
+                            }
+                            None => (Arc::clone(heights), stored_error),
+                        };
+                        let heightmap = CpuHeightmap::I16(Arc::clone(&heights));
+                        // Tiles that were downloaded rather than generated have no recorded error.
+                        let geometric_error = stored_error.unwrap_or_else(|| {
+                            heightmap.geometric_error(&self.layers, &self.planet)
+                        });
+                        let elevation_range = heightmap.elevation_range();
+                        let statistics = heightmap.statistics(&self.layers, node);
                         if let Some(entry) = self.inner.entry_mut(&node) {
-                            entry.heightmap = Some(CpuHeightmap::I16(Arc::clone(&heights)));
+                            entry.heightmap = Some(heightmap);
+                            self.bounds_changed |= entry.geometric_error != Some(geometric_error);
+                            entry.geometric_error = Some(geometric_error);
+                            self.bounds_changed |= entry.elevation_range != Some(elevation_range);
+                            entry.elevation_range = Some(elevation_range);
+                            entry.statistics = Some(statistics);
                         }
                         let heights: Vec<_> = heights.iter().map(|&h| h as f32).collect();
-                        height_data = vec![0; heights.len() * 4];
-                        height_data.copy_from_slice(bytemuck::cast_slice(&heights));
-                        data = &mut height_data;
+                        height_data = bytemuck::cast_slice(&heights).to_vec();
+                        data = &height_data;
                     }
-                    TileResult::Albedo(_, ref mut d) | TileResult::Roughness(_, ref mut d) => {
-                        data = &mut *d
+                    TileResult::Albedo(_, ref d) | TileResult::Roughness(_, ref d) => data = d,
+                    // Memory mapped normal tiles are read straight from the mapping as they are
+                    // copied to the GPU, so they never have to be loaded into memory as a whole.
+                    TileResult::Normals(_, ref d) => data = d,
+                    TileResult::Vector(..) | TileResult::Places(..) => unreachable!(),
+                }
+                if layer != LayerType::Heightmaps {
+                    if let Some(stored) = self.stored_layers.get(layer.index()) {
+                        converted = Self::convert_tile(stored, &self.layers[layer], data);
+                        data = &converted;
                     }
                 }
 
                 if cfg!(feature = "small-trace") {
+                    let mut copy = data.to_vec();
                     for y in 0..resolution_blocks {
                         for x in 0..resolution_blocks {
                             if x % 16 == 0 && y % 16 == 0 {
@@ -429,31 +1023,39 @@ impl TileCache {
                             }
                             let src = ((x & !15) + (y & !15) * resolution_blocks) * bytes_per_block;
                             let dst = (x + y * resolution_blocks) * bytes_per_block;
-                            data.copy_within(src..src + bytes_per_block, dst);
+                            copy.copy_within(src..src + bytes_per_block, dst);
                         }
                     }
+                    traced = copy;
+                    data = &traced;
                 }
 
-                queue.write_texture(
+                self.upload_belt.copy_to_texture(
+                    device,
+                    &mut encoder,
+                    data,
+                    row_bytes,
                     wgpu::ImageCopyTexture {
                         texture: &textures[layer],
                         mip_level: 0,
                         origin: wgpu::Origin3d { x: 0, y: 0, z: index as u32 },
                     },
-                    &*data,
-                    wgpu::ImageDataLayout {
-                        offset: 0,
-                        bytes_per_row: Some(NonZeroU32::new(row_bytes as u32).unwrap()),
-                        rows_per_image: None,
-                    },
                     wgpu::Extent3d {
                         width: resolution as u32,
                         height: resolution as u32,
                         depth_or_array_layers: 1,
                     },
                 );
+
+                if self.layers[layer].mipmaps {
+                    self.pending_mipmaps.push((tile.node(), layer));
+                }
             }
         }
+
+        self.upload_belt.finish();
+        queue.submit(Some(encoder.finish()));
+        self.upload_belt.recall();
     }
 
     pub(super) fn download_tiles(&mut self) {
@@ -479,7 +1081,44 @@ impl TileCache {
                             }
                             buffer.unmap();
 
-                            entry.heightmap = Some(CpuHeightmap::F32(Arc::new(heights)));
+                            let mut heightmap = CpuHeightmap::F32(Arc::new(heights));
+                            if let Some(deltas) = self.height_deltas.get(&node) {
+                                // The tile was generated without the deltas, so upload it again
+                                // with them and regenerate everything derived from it.
+                                for delta in deltas {
+                                    heightmap.add_delta(delta, resolution, 1);
+                                }
+                                entry.invalidate_gpu_layers();
+                                self.pending_restores.push(node);
+                            }
+                            let geometric_error = Some(heightmap.geometric_error(&self.layers, &self.planet));
+                            self.bounds_changed |= entry.geometric_error != geometric_error;
+                            entry.geometric_error = geometric_error;
+                            let elevation_range = Some(heightmap.elevation_range());
+                            self.bounds_changed |= entry.elevation_range != elevation_range;
+                            entry.elevation_range = elevation_range;
+                            entry.statistics = Some(heightmap.statistics(&self.layers, node));
+                            entry.heightmap = Some(heightmap);
+                        }
+                    }
+                }
+                w = self.pending_write_backs.select_next_some() => {
+                    if let Ok((node, layer, buffer)) = w {
+                        let (row_bytes, row_pitch, rows) = Self::readback_layout(&self.layers[layer]);
+                        let mut data = Vec::with_capacity(row_bytes * rows);
+                        {
+                            let mapped_buffer = buffer.slice(..).get_mapped_range();
+                            for row in mapped_buffer.chunks_exact(row_pitch) {
+                                data.extend_from_slice(&row[..row_bytes]);
+                            }
+                        }
+                        buffer.unmap();
+                        if Some(layer) == self.biome_layer {
+                            if let Some(entry) = self.inner.entry_mut(&node) {
+                                entry.biomes = Some(data.chunks_exact(4).map(|texel| texel[0]).collect());
+                            }
+                        } else {
+                            self.streamer.write_back_tile(node, layer, data);
                         }
                     }
                 }
@@ -489,6 +1128,86 @@ impl TileCache {
         }
     }
 
+    /// While nothing is being streamed, move a few tiles per frame so that the slots holding tiles
+    /// end up contiguous, followed by the ones with nothing loaded. The key doesn't depend on the
+    /// camera, so slots stop moving once they are packed rather than being shuffled every time the
+    /// view changes. Slot contents are exchanged on the GPU in the same frame as the slot lookup
+    /// is updated, so every later draw and generator sees a consistent cache.
+    pub(super) fn compact(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        textures: &VecMap<wgpu::Texture>,
+    ) {
+        if !self.is_idle() {
+            return;
+        }
+        let swaps = self
+            .inner
+            .compact(|entry| entry.valid == LayerMask::empty(), Self::COMPACTION_SWAPS_PER_FRAME);
+        if swaps.is_empty() {
+            return;
+        }
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.tiles.compact"),
+        });
+        for (ty, layer) in &self.layers {
+            let scratch = &*self.compaction_scratch.entry(ty).or_insert_with(|| {
+                device.create_texture(&wgpu::TextureDescriptor {
+                    size: wgpu::Extent3d {
+                        width: layer.texture_resolution,
+                        height: layer.texture_resolution,
+                        depth_or_array_layers: 2,
+                    },
+                    format: layer.texture_format.to_wgpu(),
+                    mip_level_count: layer.mip_level_count(),
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    usage: wgpu::TextureUsage::COPY_SRC | wgpu::TextureUsage::COPY_DST,
+                    label: Some(&format!("texture.tiles.compact.{}", layer.name)),
+                })
+            });
+            let texture = &textures[ty];
+            for &(a, b) in &swaps {
+                Self::copy_tile(&mut encoder, layer, (texture, a), (scratch, 0));
+                Self::copy_tile(&mut encoder, layer, (texture, b), (scratch, 1));
+                Self::copy_tile(&mut encoder, layer, (scratch, 0), (texture, b));
+                Self::copy_tile(&mut encoder, layer, (scratch, 1), (texture, a));
+            }
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Record a copy of every mip level of the tile in layer `src.1` of `src.0` into layer `dst.1`
+    /// of `dst.0`.
+    fn copy_tile(
+        encoder: &mut wgpu::CommandEncoder,
+        layer: &LayerParams,
+        src: (&wgpu::Texture, usize),
+        dst: (&wgpu::Texture, usize),
+    ) {
+        let block_size = layer.texture_format.block_size();
+        for mip in 0..layer.mip_level_count() {
+            // Copies of compressed textures cover whole blocks, even past the edge of small mips.
+            let size = (layer.texture_resolution >> mip).max(1);
+            let size = (size + block_size - 1) / block_size * block_size;
+            encoder.copy_texture_to_texture(
+                wgpu::ImageCopyTexture {
+                    texture: src.0,
+                    mip_level: mip,
+                    origin: wgpu::Origin3d { x: 0, y: 0, z: src.1 as u32 },
+                },
+                wgpu::ImageCopyTexture {
+                    texture: dst.0,
+                    mip_level: mip,
+                    origin: wgpu::Origin3d { x: 0, y: 0, z: dst.1 as u32 },
+                },
+                wgpu::Extent3d { width: size, height: size, depth_or_array_layers: 1 },
+            );
+        }
+    }
+
     pub(super) fn make_cache_textures(&self, device: &wgpu::Device) -> VecMap<wgpu::Texture> {
         self.layers
             .iter()
@@ -502,7 +1221,7 @@ impl TileCache {
                             depth_or_array_layers: self.inner.size() as u32,
                         },
                         format: layer.texture_format.to_wgpu(),
-                        mip_level_count: 1,
+                        mip_level_count: layer.mip_level_count(),
                         sample_count: 1,
                         dimension: wgpu::TextureDimension::D2,
                         usage: wgpu::TextureUsage::COPY_SRC
@@ -513,7 +1232,7 @@ impl TileCache {
                             } else {
                                 wgpu::TextureUsage::empty()
                             },
-                        label: Some(&format!("texture.tiles.{}", LayerType::from_index(ty).name())),
+                        label: Some(&format!("texture.tiles.{}", layer.name)),
                     }),
                 )
             })
@@ -530,6 +1249,71 @@ impl TileCache {
             .unwrap_or(false)
     }
 
+    /// Whether no tiles are currently being streamed, downloaded or waiting on mipmaps.
+    pub fn is_idle(&self) -> bool {
+        self.streamer.num_inflight() == 0
+            && self.pending_heightmap_downloads.is_empty()
+            && self.pending_mipmaps.is_empty()
+    }
+    /// Every resident tile whose `ty` layer is valid, along with the slot it occupies.
+    #[cfg(feature = "render")]
+    pub fn resident_slots(&self, ty: LayerType) -> Vec<(VNode, usize)> {
+        self.inner
+            .slots()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.valid.contains_layer(ty))
+            .map(|(slot, entry)| (entry.node, slot))
+            .collect()
+    }
+
+    /// Snapshot of the valid layers of every resident tile. Comparing snapshots taken after
+    /// successive updates shows whether the cache is still making progress.
+    pub fn valid_layers(&self) -> Vec<(VNode, LayerMask)> {
+        self.inner.slots().iter().map(|entry| (entry.node, entry.valid)).collect()
+    }
+
+    pub fn geometric_error(&self, node: VNode) -> Option<f32> {
+        self.inner.entry(&node)?.geometric_error
+    }
+
+    /// Whether the bounds of any node changed since the last call, in which case nodes have to be
+    /// reselected even if the camera hasn't moved.
+    pub fn take_bounds_changed(&mut self) -> bool {
+        std::mem::take(&mut self.bounds_changed)
+    }
+
+    pub fn elevation_range(&self, node: VNode) -> Option<(f32, f32)> {
+        self.inner.entry(&node)?.elevation_range
+    }
+
+    pub fn statistics(&self, node: VNode) -> Option<NodeStatistics> {
+        self.inner.entry(&node)?.statistics
+    }
+
+    /// Where the heightmap tile of `node` came from and when it was produced, if it is resident.
+    pub fn provenance(&self, node: VNode) -> Option<(TileProvenance, SystemTime)> {
+        self.inner.entry(&node)?.provenance
+    }
+
+    /// Biome at the sample nearest to the given point within the biome tile of the node at
+    /// `level` covering it, if that tile has been generated and read back.
+    pub fn get_biome(&self, latitude: f64, longitude: f64, level: u8) -> Option<Biome> {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+
+        let (node, x, y) = VNode::from_cspace(cspace, level);
+
+        let layer = &self.layers[self.biome_layer?];
+        let border = layer.texture_border_size as usize;
+        let resolution = layer.texture_resolution as usize;
+        let x = (x * (resolution - 2 * border - 1) as f32).round() as usize + border;
+        let y = (y * (resolution - 2 * border - 1) as f32).round() as usize + border;
+
+        let biomes = self.inner.entry(&node)?.biomes.as_ref()?;
+        Biome::from_index(biomes[x + y * resolution])
+    }
+
     pub fn get_slot(&self, node: VNode) -> Option<usize> {
         self.inner.index_of(&node)
     }
@@ -565,15 +1349,44 @@ impl TileCache {
         let i01 = x.floor() as usize + y.ceil() as usize * resolution;
         let i11 = x.ceil() as usize + y.ceil() as usize * resolution;
 
-        self.inner.entry(&node).and_then(|entry| Some(entry.heightmap.as_ref()?)).map(|h| match h {
-            CpuHeightmap::I16(h) => (h[i00] as f32 * w00
-                + h[i10] as f32 * w10
-                + h[i01] as f32 * w01
-                + h[i11] as f32 * w11)
-                .max(0.0),
-            CpuHeightmap::F32(h) => {
-                (h[i00] * w00 + h[i10] * w10 + h[i01] * w01 + h[i11] * w11).max(0.0)
-            }
+        self.inner.entry(&node).and_then(|entry| Some(entry.heightmap.as_ref()?)).map(|h| {
+            self.planet.clamp(match h {
+                CpuHeightmap::I16(h) => {
+                    h[i00] as f32 * w00
+                        + h[i10] as f32 * w10
+                        + h[i01] as f32 * w01
+                        + h[i11] as f32 * w11
+                }
+                CpuHeightmap::F32(h) => h[i00] * w00 + h[i10] * w10 + h[i01] * w01 + h[i11] * w11,
+            })
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::SyntheticPlanet;
+
+    #[test]
+    fn heightmap_statistics() {
+        let layers = SyntheticPlanet::layers();
+        let resolution = layers[LayerType::Heightmaps].texture_resolution as usize;
+        let border = layers[LayerType::Heightmaps].texture_border_size as usize;
+        let node = VNode::roots()[0].children()[0];
+        let spacing = node.aprox_side_length() / (resolution - 2 * border - 1) as f32;
+
+        // A plane rising by half a meter per meter along x, centered on 100 meters.
+        let center = (resolution / 2) as f32;
+        let heights = (0..resolution * resolution)
+            .map(|i| 100.0 + 0.5 * spacing * ((i % resolution) as f32 - center))
+            .collect();
+        let statistics = CpuHeightmap::F32(Arc::new(heights)).statistics(&layers, node);
+
+        let half_width = 0.5 * spacing * (center - border as f32);
+        assert!((statistics.min_elevation - (100.0 - half_width)).abs() < 1.0);
+        assert!((statistics.max_elevation - (100.0 + half_width)).abs() < 1.0);
+        assert!((statistics.mean_elevation - 100.0).abs() < 1.0);
+        assert!((statistics.ruggedness - 0.5).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,136 @@
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::futures_unordered::FuturesUnordered;
+use futures::StreamExt;
+use std::num::NonZeroU32;
+
+/// Size in bytes of each staging buffer. Uploads that don't fit get a buffer of their own size.
+const CHUNK_SIZE: u64 = 8 << 20;
+/// Alignment of both the start and the rows of every upload within a staging buffer.
+const ALIGNMENT: u64 = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64;
+
+struct Chunk {
+    buffer: wgpu::Buffer,
+    size: u64,
+    /// Bytes of the buffer already written this frame.
+    offset: u64,
+}
+
+/// Ring of staging buffers that tile contents are written into before being copied to the tile
+/// cache textures. All the copies of a frame are recorded into the same command encoder, and
+/// buffers the GPU is done with are mapped again and reused rather than allocated every time.
+pub(super) struct UploadBelt {
+    /// Mapped buffers being written this frame.
+    active: Vec<Chunk>,
+    /// Unmapped buffers holding data for copies that haven't been submitted yet.
+    closed: Vec<Chunk>,
+    /// Mapped buffers that are ready to be written again.
+    free: Vec<Chunk>,
+    recalled: FuturesUnordered<BoxFuture<'static, Result<Chunk, ()>>>,
+}
+impl UploadBelt {
+    pub fn new() -> Self {
+        Self {
+            active: Vec::new(),
+            closed: Vec::new(),
+            free: Vec::new(),
+            recalled: FuturesUnordered::new(),
+        }
+    }
+
+    /// Record a copy of `data`, made up of rows of `row_bytes` bytes each, into `texture`.
+    pub fn copy_to_texture(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        data: &[u8],
+        row_bytes: usize,
+        texture: wgpu::ImageCopyTexture,
+        size: wgpu::Extent3d,
+    ) {
+        let rows = (data.len() / row_bytes) as u64;
+        let row_pitch = (row_bytes as u64 + ALIGNMENT - 1) & !(ALIGNMENT - 1);
+        let chunk = self.allocate(device, row_pitch * rows);
+        let offset = chunk.offset;
+        chunk.offset += row_pitch * rows;
+
+        {
+            let mut mapped = chunk.buffer.slice(offset..chunk.offset).get_mapped_range_mut();
+            for (dst, src) in
+                mapped.chunks_exact_mut(row_pitch as usize).zip(data.chunks_exact(row_bytes))
+            {
+                dst[..row_bytes].copy_from_slice(src);
+            }
+        }
+
+        encoder.copy_buffer_to_texture(
+            wgpu::ImageCopyBuffer {
+                buffer: &chunk.buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset,
+                    bytes_per_row: Some(NonZeroU32::new(row_pitch as u32).unwrap()),
+                    rows_per_image: None,
+                },
+            },
+            texture,
+            size,
+        );
+    }
+
+    /// Find room for `size` bytes in a mapped buffer, reusing recalled buffers when possible.
+    fn allocate(&mut self, device: &wgpu::Device, size: u64) -> &mut Chunk {
+        while let Some(Some(Ok(chunk))) = self.recalled.next().now_or_never() {
+            self.free.push(Chunk { offset: 0, ..chunk });
+        }
+
+        let index = match self.active.iter().position(|c| c.offset + size <= c.size) {
+            Some(index) => index,
+            None => {
+                let chunk = match self.free.iter().position(|c| size <= c.size) {
+                    Some(i) => self.free.swap_remove(i),
+                    None => Chunk {
+                        buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                            size: size.max(CHUNK_SIZE),
+                            usage: wgpu::BufferUsage::MAP_WRITE | wgpu::BufferUsage::COPY_SRC,
+                            label: Some("buffer.tiles.upload"),
+                            mapped_at_creation: true,
+                        }),
+                        size: size.max(CHUNK_SIZE),
+                        offset: 0,
+                    },
+                };
+                self.active.push(chunk);
+                self.active.len() - 1
+            }
+        };
+        &mut self.active[index]
+    }
+
+    /// Unmap the buffers written this frame. Must be called before the encoder holding the copies
+    /// is submitted.
+    pub fn finish(&mut self) {
+        for chunk in self.active.drain(..) {
+            chunk.buffer.unmap();
+            self.closed.push(chunk);
+        }
+    }
+
+    /// Map the buffers of submitted copies again, so that they can be reused once the GPU is done
+    /// with them. Must be called after the encoder holding the copies is submitted.
+    pub fn recall(&mut self) {
+        for chunk in self.closed.drain(..) {
+            self.recalled.push(
+                chunk
+                    .buffer
+                    .slice(..)
+                    .map_async(wgpu::MapMode::Write)
+                    .then(move |result| {
+                        futures::future::ready(match result {
+                            Ok(()) => Ok(chunk),
+                            Err(_) => Err(()),
+                        })
+                    })
+                    .boxed(),
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use cgmath::{Matrix4, Quaternion};
+
+/// Perspective projection with an infinite far plane and reversed depth, matching the depth
+/// convention used by terra's render pipelines.
+pub(crate) fn infinite_reversed_perspective(fov_y: f64, aspect: f64, near: f64) -> Matrix4<f64> {
+    let f = 1.0 / (fov_y * 0.5).tan();
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    Matrix4::new(
+        f/aspect,  0.0,  0.0,   0.0,
+        0.0,       f,    0.0,   0.0,
+        0.0,       0.0,  0.0,  -1.0,
+        0.0,       0.0,  near,  0.0)
+}
+
+/// Perspective projection with reversed depth that maps distances from `near` to `far` onto the
+/// full depth range.
+#[cfg(any(feature = "render", test))]
+pub(crate) fn reversed_perspective(fov_y: f64, aspect: f64, near: f64, far: f64) -> Matrix4<f64> {
+    let f = 1.0 / (fov_y * 0.5).tan();
+    let a = near / (far - near);
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    Matrix4::new(
+        f/aspect,  0.0,  0.0,      0.0,
+        0.0,       f,    0.0,      0.0,
+        0.0,       0.0,  a,       -1.0,
+        0.0,       0.0,  a * far,  0.0)
+}
+
+/// A camera described entirely in double precision.
+///
+/// Terra renders with positions relative to the camera, so as long as the camera itself is given
+/// in double precision there is no need for applications to build camera-relative matrices
+/// themselves.
+#[derive(Copy, Clone, Debug)]
+pub struct Camera {
+    /// Position in world space: earth-centered, earth-fixed coordinates in meters.
+    pub position: mint::Point3<f64>,
+    /// Rotation from camera space, in which the camera looks down the -Z axis with +Y up, to
+    /// world space.
+    pub orientation: mint::Quaternion<f64>,
+    /// Vertical field of view in radians.
+    pub fov_y: f64,
+    /// Distance to the near clipping plane in meters. There is no far plane.
+    pub near: f64,
+}
+impl Camera {
+    /// Combined view and projection matrix for a frame with the given aspect ratio. Positions are
+    /// taken relative to the camera, as expected by `Terrain::render`.
+    pub fn view_proj(&self, aspect: f64) -> mint::ColumnMatrix4<f32> {
+        let rotation = Quaternion::from(self.orientation);
+        let view = Matrix4::from(rotation.conjugate());
+        let proj = infinite_reversed_perspective(self.fov_y, aspect, self.near);
+        (proj * view).cast::<f32>().unwrap().into()
+    }
+
+    /// Same as `view_proj`, but with a far clipping plane `far` meters from the camera.
+    #[cfg(feature = "render")]
+    pub(crate) fn view_proj_with_far(&self, aspect: f64, far: f64) -> mint::ColumnMatrix4<f32> {
+        let rotation = Quaternion::from(self.orientation);
+        let view = Matrix4::from(rotation.conjugate());
+        let proj = reversed_perspective(self.fov_y, aspect, self.near, far);
+        (proj * view).cast::<f32>().unwrap().into()
+    }
+
+    /// Build a camera from `glam` types.
+    #[cfg(feature = "glam")]
+    pub fn from_glam(
+        position: glam::DVec3,
+        orientation: glam::DQuat,
+        fov_y: f64,
+        near: f64,
+    ) -> Self {
+        Self {
+            position: mint::Point3 { x: position.x, y: position.y, z: position.z },
+            orientation: mint::Quaternion {
+                v: mint::Vector3 { x: orientation.x, y: orientation.y, z: orientation.z },
+                s: orientation.w,
+            },
+            fov_y,
+            near,
+        }
+    }
+
+    /// Build a camera from `nalgebra` types.
+    #[cfg(feature = "nalgebra")]
+    pub fn from_nalgebra(
+        position: nalgebra::Point3<f64>,
+        orientation: nalgebra::UnitQuaternion<f64>,
+        fov_y: f64,
+        near: f64,
+    ) -> Self {
+        let q = &orientation.quaternion().coords;
+        Self {
+            position: mint::Point3 { x: position.x, y: position.y, z: position.z },
+            orientation: mint::Quaternion { v: mint::Vector3 { x: q.x, y: q.y, z: q.z }, s: q.w },
+            fov_y,
+            near,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Vector4;
+
+    #[test]
+    fn reversed_depth_range() {
+        let proj = reversed_perspective(1.0, 1.5, 10.0, 1000.0);
+        let depth = |distance: f64| {
+            let p = proj * Vector4::new(0.0, 0.0, -distance, 1.0);
+            p.z / p.w
+        };
+        assert!((depth(10.0) - 1.0).abs() < 1e-9);
+        assert!(depth(1000.0).abs() < 1e-9);
+        assert!(depth(100.0) > depth(500.0));
+    }
+}
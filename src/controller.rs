@@ -0,0 +1,385 @@
+//! Planetary camera navigation that doesn't depend on any particular windowing library.
+//!
+//! Applications translate their own keyboard, mouse, gamepad and touch events into `InputEvent`s,
+//! feed them to a `CameraController`, and call `CameraController::update` once per frame to get
+//! the `Camera` to render with. Movement speed scales with altitude, so the same controls work
+//! from orbit down to ground level.
+
+use crate::camera::Camera;
+use crate::coordinates::{self, PLANET_RADIUS};
+use cgmath::{InnerSpace, Matrix3, Quaternion, Vector3};
+use fnv::FnvHashMap;
+use std::f64::consts::PI;
+
+/// Movement actions that are bound to keys. Which physical keys map to which action is up to the
+/// application.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Forward,
+    Back,
+    Left,
+    Right,
+    Up,
+    Down,
+    TurnLeft,
+    TurnRight,
+}
+
+/// Analog gamepad controls, each ranging from -1 to 1. Dead zones and response curves should
+/// already have been applied.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum GamepadAxis {
+    Forward,
+    Strafe,
+    Turn,
+    Climb,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TouchPhase {
+    Started,
+    Moved,
+    Ended,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InputEvent {
+    Key {
+        key: Key,
+        pressed: bool,
+    },
+    /// Mouse movement in pixels while the look button is held.
+    MouseDrag {
+        dx: f64,
+        dy: f64,
+    },
+    /// Scroll wheel movement in lines. Positive values move towards the ground.
+    Scroll(f64),
+    GamepadAxis {
+        axis: GamepadAxis,
+        value: f32,
+    },
+    /// A touch point in pixels. One finger pans, and two fingers pinch to change altitude and
+    /// twist to turn.
+    Touch {
+        id: u64,
+        phase: TouchPhase,
+        x: f64,
+        y: f64,
+    },
+}
+
+/// Flies a camera around the planet in response to input events.
+#[derive(Clone, Debug)]
+pub struct CameraController {
+    latitude: f64,
+    longitude: f64,
+    /// Radians clockwise from north.
+    heading: f64,
+    /// Radians above the horizon.
+    pitch: f64,
+    /// Height above the ground in meters.
+    altitude: f64,
+
+    keys: FnvHashMap<Key, bool>,
+    axes: FnvHashMap<GamepadAxis, f32>,
+    touches: FnvHashMap<u64, (f64, f64)>,
+    /// Forward and rightward motion from touch input, in units of altitude, not yet applied.
+    pan: (f64, f64),
+    /// Factor to multiply the altitude by from scrolling and pinching, not yet applied.
+    zoom: f64,
+    /// Speed of the last one finger pan, in units of altitude per second, which continues after
+    /// the finger lifts.
+    pan_velocity: (f64, f64),
+    inertia: f64,
+
+    look_sensitivity: f64,
+    fov_y: f64,
+    near: f64,
+}
+impl CameraController {
+    const MIN_ALTITUDE: f64 = 2.0;
+    const MAX_ALTITUDE: f64 = 4.0 * PLANET_RADIUS;
+
+    /// Start at the given latitude and longitude, in radians, facing `heading` radians clockwise
+    /// from north and `altitude` meters above the ground.
+    pub fn new(latitude: f64, longitude: f64, heading: f64, altitude: f64) -> Self {
+        let (latitude, longitude) = coordinates::normalize_lat_long(latitude, longitude);
+        Self {
+            latitude,
+            longitude,
+            heading,
+            pitch: -0.3,
+            altitude: altitude.clamp(Self::MIN_ALTITUDE, Self::MAX_ALTITUDE),
+            keys: FnvHashMap::default(),
+            axes: FnvHashMap::default(),
+            touches: FnvHashMap::default(),
+            pan: (0.0, 0.0),
+            zoom: 1.0,
+            pan_velocity: (0.0, 0.0),
+            inertia: 0.3,
+            look_sensitivity: 0.002,
+            fov_y: 45f64.to_radians(),
+            near: 0.1,
+        }
+    }
+
+    /// Radians of rotation per pixel of mouse movement. Defaults to 0.002.
+    pub fn with_look_sensitivity(mut self, radians_per_pixel: f64) -> Self {
+        self.look_sensitivity = radians_per_pixel;
+        self
+    }
+
+    /// Time constant in seconds over which panning slows to a stop after a finger lifts, or zero
+    /// to stop immediately. Defaults to 0.3.
+    pub fn with_inertia(mut self, seconds: f64) -> Self {
+        self.inertia = seconds.max(0.0);
+        self
+    }
+
+    /// Field of view and near plane of the cameras produced.
+    pub fn with_projection(mut self, fov_y: f64, near: f64) -> Self {
+        self.fov_y = fov_y;
+        self.near = near;
+        self
+    }
+
+    pub fn latitude(&self) -> f64 {
+        self.latitude
+    }
+    pub fn longitude(&self) -> f64 {
+        self.longitude
+    }
+    pub fn heading(&self) -> f64 {
+        self.heading
+    }
+    /// Height above the ground in meters.
+    pub fn altitude(&self) -> f64 {
+        self.altitude
+    }
+
+    pub fn handle(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Key { key, pressed } => {
+                self.keys.insert(key, pressed);
+            }
+            InputEvent::MouseDrag { dx, dy } => {
+                self.heading += dx * self.look_sensitivity;
+                self.pitch -= dy * self.look_sensitivity;
+            }
+            InputEvent::Scroll(lines) => self.zoom *= 0.9f64.powf(lines),
+            InputEvent::GamepadAxis { axis, value } => {
+                self.axes.insert(axis, value.clamp(-1.0, 1.0));
+            }
+            InputEvent::Touch { id, phase: TouchPhase::Started, x, y } => {
+                // Touching the screen catches the globe if it is still drifting.
+                self.pan_velocity = (0.0, 0.0);
+                self.touches.insert(id, (x, y));
+            }
+            InputEvent::Touch { id, phase: TouchPhase::Ended, .. } => {
+                self.touches.remove(&id);
+            }
+            InputEvent::Touch { id, phase: TouchPhase::Moved, x, y } => {
+                let previous = match self.touches.get(&id) {
+                    Some(&p) => p,
+                    None => return,
+                };
+                match self.touches.len() {
+                    1 => {
+                        // Drag the ground along with the finger, roughly assuming a 1000 pixel
+                        // tall view.
+                        self.pan.0 += (y - previous.1) * 0.001;
+                        self.pan.1 -= (x - previous.0) * 0.001;
+                    }
+                    2 => {
+                        let other = self.touches.iter().find(|t| *t.0 != id).unwrap().1;
+                        let before = (previous.0 - other.0).hypot(previous.1 - other.1);
+                        let after = (x - other.0).hypot(y - other.1);
+                        if before > 0.0 && after > 0.0 {
+                            self.zoom *= before / after;
+                        }
+
+                        // Turn so that the ground rotates along with the fingers.
+                        let twist = (y - other.1).atan2(x - other.0)
+                            - (previous.1 - other.1).atan2(previous.0 - other.0);
+                        self.heading -= (twist + PI).rem_euclid(2.0 * PI) - PI;
+                    }
+                    _ => {}
+                }
+                self.touches.insert(id, (x, y));
+            }
+        }
+    }
+
+    /// Apply the input received for `dt` seconds and return the new camera. `ground_height` is the
+    /// height of the terrain in meters below the camera, for instance from `Terrain::get_height`.
+    pub fn update(&mut self, dt: f64, ground_height: f32) -> Camera {
+        let key = |k| if self.keys.get(&k).copied().unwrap_or(false) { 1.0 } else { 0.0 };
+        let axis = |a| self.axes.get(&a).copied().unwrap_or(0.0) as f64;
+
+        let forward = key(Key::Forward) - key(Key::Back) + axis(GamepadAxis::Forward);
+        let right = key(Key::Right) - key(Key::Left) + axis(GamepadAxis::Strafe);
+        let climb = key(Key::Up) - key(Key::Down) + axis(GamepadAxis::Climb);
+        let turn = key(Key::TurnRight) - key(Key::TurnLeft) + axis(GamepadAxis::Turn);
+
+        if self.touches.len() == 1 {
+            if dt > 0.0 {
+                self.pan_velocity = (self.pan.0 / dt, self.pan.1 / dt);
+            }
+        } else if self.touches.is_empty() && self.inertia > 0.0 {
+            self.pan.0 += self.pan_velocity.0 * dt;
+            self.pan.1 += self.pan_velocity.1 * dt;
+            let decay = (-dt / self.inertia).exp();
+            self.pan_velocity = (self.pan_velocity.0 * decay, self.pan_velocity.1 * decay);
+            if self.pan_velocity.0.hypot(self.pan_velocity.1) < 1e-3 {
+                self.pan_velocity = (0.0, 0.0);
+            }
+        } else {
+            self.pan_velocity = (0.0, 0.0);
+        }
+
+        self.heading = (self.heading + turn * dt).rem_euclid(2.0 * PI);
+        self.pitch = self.pitch.clamp(-PI / 2.0 + 0.01, PI / 2.0 - 0.01);
+
+        // Cover about one altitude worth of ground per second, but never more than a few degrees.
+        let speed = self.altitude.min(500_000.0);
+        let north = (forward * self.heading.cos() - right * self.heading.sin()) * speed * dt
+            + (self.pan.0 * self.heading.cos() - self.pan.1 * self.heading.sin()) * self.altitude;
+        let east = (forward * self.heading.sin() + right * self.heading.cos()) * speed * dt
+            + (self.pan.0 * self.heading.sin() + self.pan.1 * self.heading.cos()) * self.altitude;
+        let latitude = self.latitude + north / PLANET_RADIUS;
+        let longitude = self.longitude + east / (PLANET_RADIUS * self.latitude.cos().max(0.01));
+        // Flying over a pole continues down the other side, facing the opposite way in terms of
+        // compass heading.
+        if latitude.abs() > PI / 2.0 {
+            self.heading = (self.heading + PI).rem_euclid(2.0 * PI);
+        }
+        let (latitude, longitude) = coordinates::normalize_lat_long(latitude, longitude);
+        self.latitude = latitude;
+        self.longitude = longitude;
+
+        self.altitude *= (climb * dt).exp2() * self.zoom;
+        self.altitude = self.altitude.clamp(Self::MIN_ALTITUDE, Self::MAX_ALTITUDE);
+        self.pan = (0.0, 0.0);
+        self.zoom = 1.0;
+
+        self.camera(ground_height)
+    }
+
+    /// Whether the camera would stay where it is if `update` were called, because no controls are
+    /// held and no panning is still slowing down.
+    pub fn is_idle(&self) -> bool {
+        self.keys.values().all(|&pressed| !pressed)
+            && self.axes.values().all(|&value| value == 0.0)
+            && self.touches.is_empty()
+            && self.pan == (0.0, 0.0)
+            && self.pan_velocity == (0.0, 0.0)
+            && self.zoom == 1.0
+    }
+
+    /// The camera for the current position without applying any input.
+    pub fn camera(&self, ground_height: f32) -> Camera {
+        let (latitude, longitude) = (self.latitude, self.longitude);
+        let position = coordinates::polar_to_ecef(Vector3::new(
+            latitude,
+            longitude,
+            ground_height.max(0.0) as f64 + self.altitude,
+        ));
+
+        let up = position.normalize();
+        let east = Vector3::new(-longitude.sin(), longitude.cos(), 0.0);
+        let north = up.cross(east);
+        let level = north * self.heading.cos() + east * self.heading.sin();
+        let forward = level * self.pitch.cos() + up * self.pitch.sin();
+        let camera_up = up * self.pitch.cos() - level * self.pitch.sin();
+        let rotation =
+            Quaternion::from(Matrix3::from_cols(forward.cross(camera_up), camera_up, -forward));
+
+        Camera {
+            position: mint::Point3 { x: position.x, y: position.y, z: position.z },
+            orientation: rotation.into(),
+            fov_y: self.fov_y,
+            near: self.near,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controller_input() {
+        let mut controller = CameraController::new(0.0, 0.0, 0.0, 1000.0);
+        controller.handle(InputEvent::Key { key: Key::Forward, pressed: true });
+        controller.update(0.5, 0.0);
+        assert!(controller.latitude() > 0.0);
+        assert!(controller.longitude().abs() < 1e-12);
+
+        controller.handle(InputEvent::Key { key: Key::Forward, pressed: false });
+        controller.handle(InputEvent::Scroll(1.0));
+        controller.update(0.5, 0.0);
+        assert!((controller.altitude() - 900.0).abs() < 1e-6);
+
+        // Spreading two fingers apart to twice their distance halves the altitude.
+        controller.handle(InputEvent::Touch { id: 0, phase: TouchPhase::Started, x: 0.0, y: 0.0 });
+        controller.handle(InputEvent::Touch {
+            id: 1,
+            phase: TouchPhase::Started,
+            x: 100.0,
+            y: 0.0,
+        });
+        controller.handle(InputEvent::Touch { id: 1, phase: TouchPhase::Moved, x: 200.0, y: 0.0 });
+        let camera = controller.update(0.5, 100.0);
+        assert!((controller.altitude() - 450.0).abs() < 1e-6);
+
+        let position = Vector3::new(camera.position.x, camera.position.y, camera.position.z);
+        assert!((position.magnitude() - PLANET_RADIUS - 550.0).abs() < 1e-3);
+        let forward = Quaternion::from(camera.orientation) * -Vector3::unit_z();
+        assert!(forward.z > 0.0 && forward.dot(position.normalize()) < 0.0);
+    }
+
+    #[test]
+    fn fly_over_pole() {
+        let mut controller = CameraController::new(1.57, 3.0, 0.0, 100_000.0);
+        controller.handle(InputEvent::Key { key: Key::Forward, pressed: true });
+        controller.update(0.2, 0.0);
+        assert!(controller.latitude() < 1.57);
+        assert!((controller.longitude() - (3.0 - PI)).abs() < 1e-9);
+        assert!((controller.heading() - PI).abs() < 1e-9);
+    }
+
+    #[test]
+    fn touch_gestures() {
+        let touch = |id, phase, x, y| InputEvent::Touch { id, phase, x, y };
+        let mut controller = CameraController::new(0.0, 0.0, 0.0, 1000.0);
+
+        // Twisting two fingers a quarter turn clockwise on screen turns the camera left.
+        controller.handle(touch(0, TouchPhase::Started, 0.0, 0.0));
+        controller.handle(touch(1, TouchPhase::Started, 100.0, 0.0));
+        controller.handle(touch(1, TouchPhase::Moved, 0.0, 100.0));
+        controller.handle(touch(0, TouchPhase::Ended, 0.0, 0.0));
+        controller.handle(touch(1, TouchPhase::Ended, 0.0, 100.0));
+        controller.update(0.1, 0.0);
+        assert!((controller.heading() - 1.5 * PI).abs() < 1e-9);
+        assert!((controller.altitude() - 1000.0).abs() < 1e-6);
+
+        // A flick keeps panning after the finger lifts, slowing down over time.
+        controller.handle(touch(2, TouchPhase::Started, 0.0, 0.0));
+        controller.handle(touch(2, TouchPhase::Moved, -100.0, 0.0));
+        controller.update(0.1, 0.0);
+        controller.handle(touch(2, TouchPhase::Ended, -100.0, 0.0));
+        let mut steps = Vec::new();
+        for _ in 0..3 {
+            let start = controller.latitude();
+            controller.update(0.1, 0.0);
+            steps.push(controller.latitude() - start);
+        }
+        assert!(steps[0] > steps[1] && steps[1] > steps[2] && steps[2] > 0.0);
+
+        controller.handle(touch(3, TouchPhase::Started, 0.0, 0.0));
+        let start = controller.latitude();
+        controller.update(0.1, 0.0);
+        assert_eq!(controller.latitude(), start);
+    }
+}
@@ -15,11 +15,20 @@
 //! considerably faster.
 //!
 //! *cspace* - Restricted to points on the unit cube, projected from polar.
+//!
+//! Positions can also be converted to and from the UTM, MGRS and Web Mercator grids in which GIS
+//! tools usually exchange data.
+//!
+//! Longitudes returned by public functions are normalized to [-π, π), and any longitude is accepted
+//! as input. Areas that straddle the antimeridian or contain a pole are described with
+//! `LatLongBounds`.
 
+use anyhow::{ensure, Error};
 use cgmath::{InnerSpace, Vector3};
+use std::f64::consts::{FRAC_PI_2, PI};
 
-const WGS84_INV_FLATTENING: f64 = 298.257223563;
-const WGS84_SEMI_MAJOR_AXIS_METERS: f64 = 6378137.0;
+pub(crate) const WGS84_INV_FLATTENING: f64 = 298.257223563;
+pub(crate) const WGS84_SEMI_MAJOR_AXIS_METERS: f64 = 6378137.0;
 const WSG84_SEMI_MINOR_AXIS_METERS: f64 =
     WGS84_SEMI_MAJOR_AXIS_METERS * (1.0 - 1.0 / WGS84_INV_FLATTENING);
 
@@ -61,32 +70,632 @@ pub fn warped_to_ecef(warped: Vector3<f64>) -> Vector3<f64> {
     )
 }
 
-#[allow(unused)]
-pub fn sun_direction() -> Vector3<f64> {
-    use astro::{coords, sun};
+pub fn cspace_to_polar(position: Vector3<f64>) -> Vector3<f64> {
+    let p = Vector3::new(position.x, position.y, position.z).normalize();
+    let latitude = f64::asin(p.z);
+    let longitude = f64::atan2(p.y, p.x);
+    Vector3::new(latitude, longitude, 0.0)
+}
 
-    let (ecl, distance_au) = sun::geocent_ecl_pos(180.0);
-    let distance = distance_au * 149597870700.0;
+/// Wrap a longitude in radians into [-π, π).
+pub fn normalize_longitude(longitude: f64) -> f64 {
+    (longitude + PI).rem_euclid(2.0 * PI) - PI
+}
 
-    let e = 0.40905;
-    let declination = coords::dec_frm_ecl(ecl.long, ecl.lat, e);
-    let right_ascension = coords::asc_frm_ecl(ecl.long, ecl.lat, e);
+/// Bring a latitude and longitude in radians into range, so that going past a pole continues down
+/// the other side of the planet and the longitude is wrapped into [-π, π).
+pub fn normalize_lat_long(latitude: f64, longitude: f64) -> (f64, f64) {
+    let latitude = normalize_longitude(latitude);
+    if latitude > FRAC_PI_2 {
+        (PI - latitude, normalize_longitude(longitude + PI))
+    } else if latitude < -FRAC_PI_2 {
+        (-PI - latitude, normalize_longitude(longitude + PI))
+    } else {
+        (latitude, normalize_longitude(longitude))
+    }
+}
 
-    let eq_rect = Vector3::new(
-        distance * declination.cos() * right_ascension.cos(),
-        distance * declination.cos() * right_ascension.sin(),
-        distance * declination.sin(),
-    );
+/// The horizon as seen from a camera position, treating the planet as a sphere of radius
+/// `PLANET_RADIUS` like terra's renderer does. Applications can use this to cull their own distant
+/// objects consistently with the terrain.
+#[derive(Copy, Clone, Debug)]
+pub struct Horizon {
+    camera: Vector3<f64>,
+    /// Distance from the camera to the sea level horizon, in meters. Zero if the camera is below
+    /// sea level.
+    pub distance: f64,
+    /// Plane containing the horizon circle, given as a unit normal pointing towards the camera in
+    /// `xyz` and an offset in `w`. Points at sea level are visible exactly when
+    /// `dot(normal, point) + w >= 0`.
+    pub plane: mint::Vector4<f64>,
+}
+impl Horizon {
+    /// Compute the horizon for a camera at `camera`, given in the same coordinates that are passed
+    /// to `Terrain::render`.
+    pub fn new(camera: mint::Point3<f64>) -> Self {
+        let camera = Vector3::new(camera.x, camera.y, camera.z);
+        let radius = camera.magnitude().max(PLANET_RADIUS);
+        let normal = camera.normalize();
+        Self {
+            camera,
+            distance: Self::distance_from_altitude(radius - PLANET_RADIUS),
+            plane: mint::Vector4 {
+                x: normal.x,
+                y: normal.y,
+                z: normal.z,
+                w: -PLANET_RADIUS * PLANET_RADIUS / radius,
+            },
+        }
+    }
+
+    /// Distance to the sea level horizon from `altitude` meters above sea level.
+    pub fn distance_from_altitude(altitude: f64) -> f64 {
+        let altitude = altitude.max(0.0);
+        (altitude * (2.0 * PLANET_RADIUS + altitude)).sqrt()
+    }
 
-    // TODO: Is this conversion from equatorial coordinates to ECEF actually valid?
-    let ecef = Vector3::new(eq_rect.x, -eq_rect.y, eq_rect.z);
+    /// Furthest distance at which an object `height` meters above sea level can be seen over the
+    /// horizon. Suitable for a far clipping distance.
+    pub fn visible_distance(&self, height: f64) -> f64 {
+        self.distance + Self::distance_from_altitude(height)
+    }
 
-    ecef.normalize()
+    /// Returns whether the planet completely hides `point`, meaning that the line of sight to it
+    /// passes below sea level.
+    pub fn is_occluded(&self, point: mint::Point3<f64>) -> bool {
+        let point = Vector3::new(point.x, point.y, point.z);
+        let v = point - self.camera;
+        let t = (-self.camera.dot(v) / v.magnitude2()).max(0.0).min(1.0);
+        (self.camera + v * t).magnitude2() < PLANET_RADIUS * PLANET_RADIUS
+    }
 }
 
-pub fn cspace_to_polar(position: Vector3<f64>) -> Vector3<f64> {
-    let p = Vector3::new(position.x, position.y, position.z).normalize();
-    let latitude = f64::asin(p.z);
-    let longitude = f64::atan2(p.y, p.x);
-    Vector3::new(latitude, longitude, 0.0)
+/// Scale factor along the central meridian of each UTM zone.
+const UTM_SCALE: f64 = 0.9996;
+/// Latitude bands used by MGRS, each spanning 8 degrees starting at 80°S. Band X is 12 degrees
+/// tall.
+const MGRS_BANDS: &[u8] = b"CDEFGHJKLMNPQRSTUVWX";
+/// Letters identifying the 100 km squares within each UTM zone.
+const MGRS_COLUMNS: [&[u8]; 3] = [b"ABCDEFGH", b"JKLMNPQR", b"STUVWXYZ"];
+const MGRS_ROWS: &[u8] = b"ABCDEFGHJKLMNPQRSTUV";
+
+/// Coefficients of the Krüger series for the transverse Mercator projection on the WGS84
+/// ellipsoid, to sixth order in the third flattening. Returns the rectifying radius followed by
+/// the coefficients of the forward and reverse series.
+fn kruger_coefficients() -> (f64, [f64; 6], [f64; 6]) {
+    let n = 1.0 / (2.0 * WGS84_INV_FLATTENING - 1.0);
+    let (n2, n3, n4, n5, n6) = (n * n, n.powi(3), n.powi(4), n.powi(5), n.powi(6));
+    let radius =
+        WGS84_SEMI_MAJOR_AXIS_METERS / (1.0 + n) * (1.0 + n2 / 4.0 + n4 / 64.0 + n6 / 256.0);
+    let alpha = [
+        n / 2.0 - 2.0 / 3.0 * n2 + 5.0 / 16.0 * n3 + 41.0 / 180.0 * n4 - 127.0 / 288.0 * n5
+            + 7891.0 / 37800.0 * n6,
+        13.0 / 48.0 * n2 - 3.0 / 5.0 * n3 + 557.0 / 1440.0 * n4 + 281.0 / 630.0 * n5
+            - 1983433.0 / 1935360.0 * n6,
+        61.0 / 240.0 * n3 - 103.0 / 140.0 * n4 + 15061.0 / 26880.0 * n5 + 167603.0 / 181440.0 * n6,
+        49561.0 / 161280.0 * n4 - 179.0 / 168.0 * n5 + 6601661.0 / 7257600.0 * n6,
+        34729.0 / 80640.0 * n5 - 3418889.0 / 1995840.0 * n6,
+        212378941.0 / 319334400.0 * n6,
+    ];
+    let beta = [
+        n / 2.0 - 2.0 / 3.0 * n2 + 37.0 / 96.0 * n3 - 1.0 / 360.0 * n4 - 81.0 / 512.0 * n5
+            + 96199.0 / 604800.0 * n6,
+        1.0 / 48.0 * n2 + 1.0 / 15.0 * n3 - 437.0 / 1440.0 * n4 + 46.0 / 105.0 * n5
+            - 1118711.0 / 3870720.0 * n6,
+        17.0 / 480.0 * n3 - 37.0 / 840.0 * n4 - 209.0 / 4480.0 * n5 + 5569.0 / 90720.0 * n6,
+        4397.0 / 161280.0 * n4 - 11.0 / 504.0 * n5 - 830251.0 / 7257600.0 * n6,
+        4583.0 / 161280.0 * n5 - 108847.0 / 3991680.0 * n6,
+        20648693.0 / 638668800.0 * n6,
+    ];
+    (radius, alpha, beta)
+}
+
+/// A position in the Universal Transverse Mercator system on the WGS84 ellipsoid.
+///
+/// UTM covers latitudes from 80°S up to 84°N. The polar regions, which use the separate UPS
+/// system, are not supported.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct UtmCoordinate {
+    /// Zone number, from 1 to 60.
+    pub zone: u8,
+    /// Whether the position is in the northern hemisphere, which determines the false northing.
+    pub northern: bool,
+    /// Meters east, with the central meridian of the zone at 500 km.
+    pub easting: f64,
+    /// Meters north of the equator, or of a point 10,000 km south of it in the southern
+    /// hemisphere.
+    pub northing: f64,
+}
+impl UtmCoordinate {
+    /// Convert a latitude and longitude in radians, picking the standard zone for the position
+    /// including the exceptions around Norway and Svalbard.
+    pub fn from_lat_long(latitude: f64, longitude: f64) -> Result<Self, Error> {
+        let (lat, long) = (latitude.to_degrees(), longitude.to_degrees());
+        ensure!((-80.0..84.0).contains(&lat), "latitude {} is outside the UTM grid", lat);
+        let long = (long + 180.0).rem_euclid(360.0) - 180.0;
+
+        let mut zone = ((long + 180.0) / 6.0).floor().min(59.0) as u8 + 1;
+        if (56.0..64.0).contains(&lat) && (3.0..12.0).contains(&long) {
+            zone = 32;
+        } else if lat >= 72.0 && (0.0..42.0).contains(&long) {
+            zone = match long {
+                l if l < 9.0 => 31,
+                l if l < 21.0 => 33,
+                l if l < 33.0 => 35,
+                _ => 37,
+            };
+        }
+        Ok(Self::in_zone(latitude, longitude, zone))
+    }
+
+    /// Convert a latitude and longitude in radians to the given zone, which need not be the one
+    /// containing the position. Accuracy degrades slowly with distance from the zone.
+    pub fn in_zone(latitude: f64, longitude: f64, zone: u8) -> Self {
+        let (radius, alpha, _) = kruger_coefficients();
+        let e = (1.0 - (1.0 - 1.0 / WGS84_INV_FLATTENING).powi(2)).sqrt();
+
+        let lambda = longitude - Self::central_meridian(zone);
+        let t = (latitude.sin().atanh() - e * (e * latitude.sin()).atanh()).sinh();
+        let xi = t.atan2(lambda.cos());
+        let eta = (lambda.sin() / (1.0 + t * t).sqrt()).atanh();
+
+        let (mut x, mut y) = (eta, xi);
+        for (j, a) in alpha.iter().enumerate() {
+            let k = 2.0 * (j + 1) as f64;
+            x += a * (k * xi).cos() * (k * eta).sinh();
+            y += a * (k * xi).sin() * (k * eta).cosh();
+        }
+
+        let northern = latitude >= 0.0;
+        Self {
+            zone,
+            northern,
+            easting: 500_000.0 + UTM_SCALE * radius * x,
+            northing: if northern { 0.0 } else { 10_000_000.0 } + UTM_SCALE * radius * y,
+        }
+    }
+
+    /// Latitude and longitude in radians.
+    pub fn to_lat_long(self) -> (f64, f64) {
+        let (radius, _, beta) = kruger_coefficients();
+        let e2 = 1.0 - (1.0 - 1.0 / WGS84_INV_FLATTENING).powi(2);
+        let e = e2.sqrt();
+
+        let false_northing = if self.northern { 0.0 } else { 10_000_000.0 };
+        let xi = (self.northing - false_northing) / (UTM_SCALE * radius);
+        let eta = (self.easting - 500_000.0) / (UTM_SCALE * radius);
+        let (mut xi1, mut eta1) = (xi, eta);
+        for (j, b) in beta.iter().enumerate() {
+            let k = 2.0 * (j + 1) as f64;
+            xi1 -= b * (k * xi).sin() * (k * eta).cosh();
+            eta1 -= b * (k * xi).cos() * (k * eta).sinh();
+        }
+
+        // Recover the geodetic latitude from the conformal latitude with Newton's method.
+        let tau1 = xi1.sin() / (eta1.sinh().hypot(xi1.cos()));
+        let mut tau = tau1;
+        for _ in 0..5 {
+            let sigma = (e * (e * tau / (1.0 + tau * tau).sqrt()).atanh()).sinh();
+            let tau_i = tau * (1.0 + sigma * sigma).sqrt() - sigma * (1.0 + tau * tau).sqrt();
+            let dtau = (tau1 - tau_i) / (1.0 + tau_i * tau_i).sqrt()
+                * (1.0 + (1.0 - e2) * tau * tau)
+                / ((1.0 - e2) * (1.0 + tau * tau).sqrt());
+            tau += dtau;
+        }
+
+        let longitude = Self::central_meridian(self.zone) + eta1.sinh().atan2(xi1.cos());
+        (tau.atan(), normalize_longitude(longitude))
+    }
+
+    /// Parse a Military Grid Reference System string such as "33UXP0500444997". Spaces are
+    /// ignored, and any even number of digits from 0 to 10 is accepted. The result is the
+    /// south-west corner of the referenced grid square.
+    pub fn from_mgrs(mgrs: &str) -> Result<Self, Error> {
+        let s: Vec<u8> = mgrs.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        let zone_digits = s.iter().take_while(|b| b.is_ascii_digit()).count();
+        ensure!(zone_digits == 1 || zone_digits == 2, "invalid MGRS zone in {:?}", mgrs);
+        ensure!(s.len() >= zone_digits + 3, "MGRS reference {:?} is too short", mgrs);
+        let zone: u8 = std::str::from_utf8(&s[..zone_digits])?.parse()?;
+        ensure!((1..=60).contains(&zone), "invalid MGRS zone in {:?}", mgrs);
+
+        let letter = |i: usize| s[zone_digits + i].to_ascii_uppercase();
+        let band = MGRS_BANDS.iter().position(|&b| b == letter(0));
+        let column = MGRS_COLUMNS[(zone as usize - 1) % 3].iter().position(|&c| c == letter(1));
+        let row = MGRS_ROWS.iter().position(|&r| r == letter(2));
+        let (band, column, row) = match (band, column, row) {
+            (Some(b), Some(c), Some(r)) => (b, c, r),
+            _ => anyhow::bail!("invalid MGRS letters in {:?}", mgrs),
+        };
+
+        let digits = &s[zone_digits + 3..];
+        ensure!(
+            digits.len() & 1 == 0 && digits.len() <= 10 && digits.iter().all(u8::is_ascii_digit),
+            "invalid MGRS digits in {:?}",
+            mgrs
+        );
+        let precision = digits.len() / 2;
+        let parse = |d: &[u8]| -> Result<f64, Error> {
+            if d.is_empty() {
+                return Ok(0.0);
+            }
+            let value: f64 = std::str::from_utf8(d)?.parse()?;
+            Ok(value * 10f64.powi(5 - precision as i32))
+        };
+        let easting = (column + 1) as f64 * 100_000.0 + parse(&digits[..precision])?;
+        let row = (row + 20 - if zone & 1 == 0 { 5 } else { 0 }) % 20;
+        let mut northing = row as f64 * 100_000.0 + parse(&digits[precision..])?;
+
+        // The row letters repeat every 2000 km, so pick the repetition that falls within the
+        // latitude band. Bands are less than 1000 km tall, and their southern edge is furthest
+        // south on the central meridian.
+        let northern = band >= 10;
+        let band_south = (-80.0 + 8.0 * band as f64).to_radians();
+        let min_northing = Self::in_zone(band_south, Self::central_meridian(zone), zone).northing;
+        while northing + 100_000.0 <= min_northing {
+            northing += 2_000_000.0;
+        }
+
+        Ok(Self { zone, northern, easting, northing })
+    }
+
+    /// Format as a Military Grid Reference System string with `digits` digits each of easting
+    /// and northing, from 0 for a 100 km square up to 5 for a 1 m square.
+    pub fn to_mgrs(self, digits: u8) -> Result<String, Error> {
+        ensure!(digits <= 5, "MGRS references have at most 5 digits per coordinate");
+        let latitude = self.to_lat_long().0.to_degrees();
+        ensure!(
+            (-80.0..84.0).contains(&latitude),
+            "latitude {} is outside the MGRS grid",
+            latitude
+        );
+        let band = MGRS_BANDS[(((latitude + 80.0) / 8.0).floor() as usize).min(19)];
+
+        let column = (self.easting / 100_000.0).floor() as i64 - 1;
+        ensure!((0..8).contains(&column), "easting {} is outside the MGRS grid", self.easting);
+        let column = MGRS_COLUMNS[(self.zone as usize - 1) % 3][column as usize];
+        let row =
+            (self.northing / 100_000.0).floor() as i64 + if self.zone & 1 == 0 { 5 } else { 0 };
+        let row = MGRS_ROWS[row.rem_euclid(20) as usize];
+
+        let scale = 10f64.powi(5 - digits as i32);
+        let e = (self.easting.rem_euclid(100_000.0) / scale).floor() as u32;
+        let n = (self.northing.rem_euclid(100_000.0) / scale).floor() as u32;
+        let mut mgrs = format!("{}{}{}{}", self.zone, band as char, column as char, row as char);
+        if digits > 0 {
+            mgrs += &format!("{:0w$}{:0w$}", e, n, w = digits as usize);
+        }
+        Ok(mgrs)
+    }
+
+    fn central_meridian(zone: u8) -> f64 {
+        (zone as f64 * 6.0 - 183.0).to_radians()
+    }
+}
+
+/// A position in the spherical Web Mercator projection (EPSG:3857) used by most web maps, in
+/// meters.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WebMercator {
+    pub x: f64,
+    pub y: f64,
+}
+impl WebMercator {
+    /// Convert a latitude and longitude in radians. Latitudes beyond about 85.05° fall outside the
+    /// square extent of the projection but are still converted.
+    pub fn from_lat_long(latitude: f64, longitude: f64) -> Self {
+        Self {
+            x: WGS84_SEMI_MAJOR_AXIS_METERS * normalize_longitude(longitude),
+            y: WGS84_SEMI_MAJOR_AXIS_METERS
+                * (std::f64::consts::FRAC_PI_4 + 0.5 * latitude).tan().ln(),
+        }
+    }
+
+    /// Latitude and longitude in radians.
+    pub fn to_lat_long(self) -> (f64, f64) {
+        let latitude = 2.0 * (self.y / WGS84_SEMI_MAJOR_AXIS_METERS).exp().atan()
+            - std::f64::consts::FRAC_PI_2;
+        (latitude, normalize_longitude(self.x / WGS84_SEMI_MAJOR_AXIS_METERS))
+    }
+}
+
+/// A flat Web Mercator map of the planet that the globe can be blended into with
+/// `Terrain::set_map_projection`. The map is tangent to the globe at its center, so that terrain
+/// there stays in place while the rest of the planet unrolls around it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MapProjection {
+    /// Latitude of the center of the map in radians.
+    pub latitude: f64,
+    /// Longitude of the center of the map in radians.
+    pub longitude: f64,
+    /// How far the globe has been flattened, from 0 for the globe to 1 for the map.
+    pub blend: f32,
+}
+impl MapProjection {
+    /// Latitude beyond which the map is clamped, so that it forms a square.
+    pub(crate) const MAX_LATITUDE: f64 = 1.4844222297453324;
+
+    /// The projection `progress` of the way through a transition from the globe to the map, eased
+    /// so that the motion starts and ends gently. To animate the transition, call this each frame
+    /// with `progress` going from 0 to 1, or back from 1 to 0 to return to the globe.
+    pub fn transition(latitude: f64, longitude: f64, progress: f64) -> Self {
+        let t = progress.clamp(0.0, 1.0);
+        Self { latitude, longitude, blend: (t * t * (3.0 - 2.0 * t)) as f32 }
+    }
+
+    /// Where a point given in earth-centered, earth-fixed coordinates ends up on the fully
+    /// flattened map. Useful for placing the camera and other objects over the map.
+    pub fn map_position(&self, ecef: mint::Point3<f64>) -> mint::Point3<f64> {
+        let ecef = Vector3::new(ecef.x, ecef.y, ecef.z);
+        let r = ecef.magnitude();
+        let latitude = (ecef.z / r).asin().clamp(-Self::MAX_LATITUDE, Self::MAX_LATITUDE);
+        let longitude = ecef.y.atan2(ecef.x);
+
+        let (up, east, north) = self.frame();
+        let x = normalize_longitude(longitude - self.longitude);
+        let y = Self::mercator_y(latitude) - Self::mercator_y(self.latitude);
+        let p = up * r + east * (x * PLANET_RADIUS) + north * (y * PLANET_RADIUS);
+        mint::Point3 { x: p.x, y: p.y, z: p.z }
+    }
+
+    /// Vertical Web Mercator coordinate on the unit sphere.
+    pub(crate) fn mercator_y(latitude: f64) -> f64 {
+        (std::f64::consts::FRAC_PI_4 + 0.5 * latitude).tan().ln()
+    }
+
+    /// Up, east and north directions at the center of the map.
+    fn frame(&self) -> (Vector3<f64>, Vector3<f64>, Vector3<f64>) {
+        let up = polar_to_ecef(Vector3::new(self.latitude, self.longitude, 0.0)).normalize();
+        let east = Vector3::new(-self.longitude.sin(), self.longitude.cos(), 0.0);
+        (up, east, up.cross(east))
+    }
+}
+
+/// A range of latitudes and longitudes in radians. The longitude range runs east from `west` to
+/// `east`, so it crosses the antimeridian when `west` is greater than `east`. Bounds that reach a
+/// pole span every longitude.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LatLongBounds {
+    south: f64,
+    north: f64,
+    west: f64,
+    east: f64,
+}
+impl LatLongBounds {
+    /// Every point on the planet.
+    pub const ALL: Self = Self { south: -FRAC_PI_2, north: FRAC_PI_2, west: -PI, east: PI };
+
+    /// Bounds running east from `west` to `east`. Longitudes may be given in any range, so a
+    /// region from 170°E to 170°W can be passed as either (170°, -170°) or (170°, 190°). Spans of
+    /// a full turn or more cover every longitude.
+    pub fn new(south: f64, north: f64, west: f64, east: f64) -> Self {
+        let (south, north) = (south.max(-FRAC_PI_2), north.min(FRAC_PI_2));
+        assert!(south <= north, "south edge must not be north of the north edge");
+        if east - west >= 2.0 * PI {
+            return Self { south, north, ..Self::ALL };
+        }
+        Self { south, north, west: normalize_longitude(west), east: normalize_longitude(east) }
+    }
+
+    /// The smallest bounds containing every point within `radius` meters of the given latitude
+    /// and longitude.
+    pub fn around(latitude: f64, longitude: f64, radius: f64) -> Self {
+        let (latitude, longitude) = normalize_lat_long(latitude, longitude);
+        let angle = radius / PLANET_RADIUS;
+        let (south, north) = (latitude - angle, latitude + angle);
+        if south <= -FRAC_PI_2 || north >= FRAC_PI_2 || angle >= PI {
+            return Self::new(south, north, -PI, PI);
+        }
+        let spread = (angle.sin() / latitude.cos()).min(1.0).asin();
+        Self::new(south, north, longitude - spread, longitude + spread)
+    }
+
+    /// The smallest bounds containing all of the given (latitude, longitude) points. The
+    /// longitude range is chosen to leave out the widest gap between points, so points on
+    /// either side of the antimeridian produce bounds that cross it.
+    pub fn from_points(points: &[(f64, f64)]) -> Self {
+        assert!(!points.is_empty());
+        let points: Vec<_> =
+            points.iter().map(|&(lat, long)| normalize_lat_long(lat, long)).collect();
+        let south = points.iter().map(|p| p.0).fold(FRAC_PI_2, f64::min);
+        let north = points.iter().map(|p| p.0).fold(-FRAC_PI_2, f64::max);
+
+        let mut longitudes: Vec<_> = points.iter().map(|p| p.1).collect();
+        longitudes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let last = *longitudes.last().unwrap();
+        let (mut west, mut east, mut gap) = (longitudes[0], last, longitudes[0] + 2.0 * PI - last);
+        for pair in longitudes.windows(2) {
+            if pair[1] - pair[0] > gap {
+                gap = pair[1] - pair[0];
+                west = pair[1];
+                east = pair[0];
+            }
+        }
+        Self { south, north, west, east }
+    }
+
+    pub fn south(&self) -> f64 {
+        self.south
+    }
+    pub fn north(&self) -> f64 {
+        self.north
+    }
+    pub fn west(&self) -> f64 {
+        self.west
+    }
+    pub fn east(&self) -> f64 {
+        self.east
+    }
+
+    /// Whether the longitude range crosses ±180°.
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.west > self.east
+    }
+
+    /// Width of the longitude range in radians, up to 2π for bounds covering every longitude.
+    pub fn longitude_span(&self) -> f64 {
+        if self.crosses_antimeridian() {
+            self.east - self.west + 2.0 * PI
+        } else {
+            self.east - self.west
+        }
+    }
+
+    /// Latitude and longitude at the middle of the bounds.
+    pub fn center(&self) -> (f64, f64) {
+        (
+            0.5 * (self.south + self.north),
+            normalize_longitude(self.west + 0.5 * self.longitude_span()),
+        )
+    }
+
+    fn contains_longitude(&self, longitude: f64) -> bool {
+        (longitude - self.west).rem_euclid(2.0 * PI) <= self.longitude_span()
+    }
+
+    /// Whether the given latitude and longitude lie within the bounds.
+    pub fn contains(&self, latitude: f64, longitude: f64) -> bool {
+        let (latitude, longitude) = normalize_lat_long(latitude, longitude);
+        latitude >= self.south && latitude <= self.north && self.contains_longitude(longitude)
+    }
+
+    /// Whether any point lies within both bounds.
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.south <= other.north
+            && other.south <= self.north
+            && (self.contains_longitude(other.west) || other.contains_longitude(self.west))
+    }
+
+    /// A latitude, longitude and radius in meters of a circle that encloses the bounds, found by
+    /// measuring the distance from the center to points spaced along the edges. Bounds containing
+    /// a pole are centered on it.
+    pub fn bounding_circle(&self) -> (f64, f64, f64) {
+        let full_turn = self.longitude_span() >= 2.0 * PI;
+        let (latitude, longitude) = if full_turn && self.north >= FRAC_PI_2 {
+            (FRAC_PI_2, 0.0)
+        } else if full_turn && self.south <= -FRAC_PI_2 {
+            (-FRAC_PI_2, 0.0)
+        } else {
+            self.center()
+        };
+        if self.contains(-latitude, longitude + PI) {
+            return (latitude, longitude, PI * PLANET_RADIUS);
+        }
+        let center = polar_to_ecef(Vector3::new(latitude, longitude, 0.0)).normalize();
+        let mut radius: f64 = 0.0;
+        for i in 0..=16 {
+            let t = i as f64 / 16.0;
+            let long = self.west + t * self.longitude_span();
+            let lat = self.south + t * (self.north - self.south);
+            for &(lat, long) in
+                &[(self.south, long), (self.north, long), (lat, self.west), (lat, self.east)]
+            {
+                let p = polar_to_ecef(Vector3::new(lat, long, 0.0)).normalize();
+                radius = radius.max(center.cross(p).magnitude().atan2(center.dot(p)));
+            }
+        }
+        (latitude, longitude, radius * PLANET_RADIUS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_conversions() {
+        let (latitude, longitude) = (40f64.to_radians(), -74f64.to_radians());
+        let utm = UtmCoordinate::from_lat_long(latitude, longitude).unwrap();
+        assert_eq!((utm.zone, utm.northern), (18, true));
+        assert!(
+            (utm.easting - 585360.462).abs() < 0.01 && (utm.northing - 4428236.065).abs() < 0.01
+        );
+        let (lat, long) = utm.to_lat_long();
+        assert!((lat - latitude).abs() < 1e-12 && (long - longitude).abs() < 1e-12);
+
+        assert_eq!(utm.to_mgrs(5).unwrap(), "18TWK8536028236");
+        let square = UtmCoordinate::from_mgrs("18T WK 853 282").unwrap();
+        assert_eq!((square.easting, square.northing), (585300.0, 4428200.0));
+        let south = UtmCoordinate::from_lat_long(-33.8568f64.to_radians(), 2.6f64).unwrap();
+        let parsed = UtmCoordinate::from_mgrs(&south.to_mgrs(5).unwrap()).unwrap();
+        assert!((parsed.northing - south.northing).abs() < 1.0);
+        assert!(UtmCoordinate::from_lat_long(85f64.to_radians(), 0.0).is_err());
+
+        let mercator = WebMercator::from_lat_long(latitude, longitude);
+        let (lat, long) = mercator.to_lat_long();
+        assert!((lat - latitude).abs() < 1e-12 && (long - longitude).abs() < 1e-12);
+    }
+
+    #[test]
+    fn antimeridian_and_poles() {
+        let deg = |d: f64| d.to_radians();
+        assert!((normalize_longitude(deg(190.0)) - deg(-170.0)).abs() < 1e-12);
+        assert_eq!(normalize_longitude(PI), -PI);
+        let (lat, long) = normalize_lat_long(deg(95.0), deg(10.0));
+        assert!((lat - deg(85.0)).abs() < 1e-12 && (long - deg(-170.0)).abs() < 1e-12);
+        let (lat, long) = normalize_lat_long(deg(-100.0), deg(-175.0));
+        assert!((lat - deg(-80.0)).abs() < 1e-12 && (long - deg(5.0)).abs() < 1e-12);
+
+        // A region from 170°E to 170°W, given either way, crosses the antimeridian.
+        let fiji = LatLongBounds::new(deg(-20.0), deg(-10.0), deg(170.0), deg(-170.0));
+        let unwrapped = LatLongBounds::new(deg(-20.0), deg(-10.0), deg(170.0), deg(190.0));
+        assert!((unwrapped.east() - fiji.east()).abs() < 1e-12);
+        assert!(fiji.crosses_antimeridian());
+        assert!((fiji.longitude_span() - deg(20.0)).abs() < 1e-12);
+        assert!((fiji.center().1.abs() - PI).abs() < 1e-12);
+        assert!(fiji.contains(deg(-15.0), deg(179.0)) && fiji.contains(deg(-15.0), deg(-179.0)));
+        assert!(fiji.contains(deg(-15.0), deg(540.0)));
+        assert!(!fiji.contains(deg(-15.0), 0.0));
+        assert!(fiji.intersects(&LatLongBounds::new(deg(-12.0), 0.0, deg(-175.0), deg(-160.0))));
+        assert!(!fiji.intersects(&LatLongBounds::new(deg(-12.0), 0.0, deg(150.0), deg(160.0))));
+
+        let points =
+            [(deg(-16.0), deg(178.0)), (deg(-18.0), deg(-179.0)), (deg(-17.0), deg(179.5))];
+        let bounds = LatLongBounds::from_points(&points);
+        assert!((bounds.west() - deg(178.0)).abs() < 1e-12);
+        assert!((bounds.east() - deg(-179.0)).abs() < 1e-12);
+        assert!(points.iter().all(|&(lat, long)| bounds.contains(lat, long)));
+
+        // Circles around the antimeridian and the poles.
+        let circle = LatLongBounds::around(0.0, PI, 100_000.0);
+        assert!(circle.crosses_antimeridian() && circle.contains(0.0, deg(-179.5)));
+        let polar = LatLongBounds::around(deg(89.5), deg(30.0), 100_000.0);
+        assert_eq!((polar.north(), polar.longitude_span()), (FRAC_PI_2, 2.0 * PI));
+        assert!(polar.contains(deg(89.5), deg(-150.0)));
+        let (lat, _, radius) = polar.bounding_circle();
+        assert!(lat > deg(89.0) && radius < 200_000.0);
+
+        // Conversions accept any longitude and return normalized ones.
+        let mercator = WebMercator::from_lat_long(0.1, deg(200.0));
+        assert!((mercator.to_lat_long().1 - deg(-160.0)).abs() < 1e-12);
+        let utm = UtmCoordinate::from_lat_long(deg(-17.0), deg(179.9)).unwrap();
+        assert_eq!(utm.zone, 60);
+        let east_of_zone = UtmCoordinate::in_zone(deg(-17.0), deg(-179.9), 60);
+        assert!((east_of_zone.to_lat_long().1 - deg(-179.9)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn map_projection() {
+        let map = MapProjection::transition(0.5, 0.2, 1.0);
+        assert_eq!(map.blend, 1.0);
+        assert_eq!(MapProjection::transition(0.5, 0.2, 0.5).blend, 0.5);
+
+        let to_map = |latitude: f64, longitude: f64| {
+            let p = polar_to_ecef(Vector3::new(latitude, longitude, 1000.0));
+            let p = map.map_position(mint::Point3 { x: p.x, y: p.y, z: p.z });
+            Vector3::new(p.x, p.y, p.z)
+        };
+        let center = to_map(0.5, 0.2);
+        let expected = polar_to_ecef(Vector3::new(0.5, 0.2, 1000.0));
+        assert!((center - expected).magnitude() < 1e-6);
+
+        // Points along the parallel through the center lie on a straight line to the east.
+        let (_, east, north) = map.frame();
+        let d = to_map(0.5, 0.3) - center;
+        assert!((d.dot(east) - 0.1 * PLANET_RADIUS).abs() < 1e-3);
+        assert!(d.dot(north).abs() < 1e-3);
+
+        let d = to_map(0.6, 0.2) - center;
+        let expected = MapProjection::mercator_y(0.6) - MapProjection::mercator_y(0.5);
+        assert!((d.dot(north) - expected * PLANET_RADIUS).abs() < 1e-3);
+    }
 }
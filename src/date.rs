@@ -0,0 +1,34 @@
+use astro::time::{self, CalType};
+
+/// A date and time of day in the Gregorian calendar, in UTC.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Date {
+    pub year: i16,
+    /// Month of the year, from 1 to 12.
+    pub month: u8,
+    /// Day of the month starting from 1. The fractional part gives the time of day.
+    pub day: f64,
+}
+impl Date {
+    pub fn new(year: i16, month: u8, day: f64) -> Self {
+        assert!(month >= 1 && month <= 12);
+        Self { year, month, day }
+    }
+
+    /// Number of days since noon on January 1, 4713 BC. This is the time scale used by
+    /// astronomical algorithms.
+    pub(crate) fn julian_day(&self) -> f64 {
+        time::julian_day(&time::Date {
+            year: self.year,
+            month: self.month,
+            decimal_day: self.day,
+            cal_type: CalType::Gregorian,
+        })
+    }
+
+    /// Days elapsed since the start of the year, including the fractional time of day.
+    #[cfg(feature = "render")]
+    pub(crate) fn day_of_year(&self) -> f64 {
+        self.julian_day() - Date::new(self.year, 1, 1.0).julian_day()
+    }
+}
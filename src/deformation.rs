@@ -0,0 +1,165 @@
+use crate::terrain::quadtree::VNode;
+use crate::vector::VectorTileId;
+use anyhow::{ensure, Error};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+
+/// A change to the elevations of a rectangle of samples in one heightmap tile, such as an
+/// explosion crater or a trench dug by a player. Deltas are small once serialized with `to_bytes`,
+/// so multiplayer games can send one for every edit, and applying one and later reverting it
+/// leaves the terrain exactly as it was. The exception is a change that pushes heights past what a
+/// heightmap tile can store, about 32 kilometers from sea level, since those heights are clamped
+/// and the clamped part isn't restored.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HeightDelta {
+    node: VNode,
+    /// Column and row of the first sample changed, counting the border of the tile.
+    origin: (u16, u16),
+    /// Number of columns and rows of samples changed.
+    size: (u16, u16),
+    /// Meters that each unit of `deltas` stands for.
+    step: u16,
+    /// Change of each sample in units of `step`, in row-major order.
+    deltas: Vec<i8>,
+}
+impl HeightDelta {
+    /// Change the heights of the `size.0` by `size.1` samples of the heightmap tile of `node`
+    /// starting at column `origin.0` and row `origin.1` by `deltas` meters, given in row-major
+    /// order. Each change is stored in a single byte, so if any is larger than 127 meters they are
+    /// all rounded to a multiple of the smallest step that fits.
+    pub fn new(
+        node: VectorTileId,
+        origin: (u16, u16),
+        size: (u16, u16),
+        deltas: &[i32],
+    ) -> Result<Self, Error> {
+        ensure!(size.0 > 0 && size.1 > 0, "Height delta of size {:?} changes no samples", size);
+        ensure!(
+            deltas.len() == size.0 as usize * size.1 as usize,
+            "Expected {} height deltas but got {}",
+            size.0 as usize * size.1 as usize,
+            deltas.len()
+        );
+        let largest = deltas.iter().map(|d| d.abs()).max().unwrap_or(0);
+        let step = ((largest + 126) / 127).max(1);
+        ensure!(step <= u16::MAX as i32, "Height delta of {} meters is too large", largest);
+
+        let deltas = deltas
+            .iter()
+            .map(|&d| (d as f32 / step as f32).round().max(-127.0).min(127.0) as i8)
+            .collect();
+        Ok(Self { node: node.0, origin, size, step: step as u16, deltas })
+    }
+
+    /// The node whose heightmap tile is changed.
+    pub fn node(&self) -> VectorTileId {
+        VectorTileId(self.node)
+    }
+
+    /// Column and row of the first sample changed.
+    pub fn origin(&self) -> (u16, u16) {
+        self.origin
+    }
+
+    /// Number of columns and rows of samples changed.
+    pub fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut encoder = lz4::EncoderBuilder::new().build(Vec::new())?;
+        bincode::serialize_into(&mut encoder, self)?;
+        let (bytes, result) = encoder.finish();
+        result?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut contents = Vec::new();
+        lz4::Decoder::new(bytes)?.read_to_end(&mut contents)?;
+        let delta: Self = bincode::deserialize(&contents)?;
+        let (width, height) = (delta.size.0 as usize, delta.size.1 as usize);
+        ensure!(
+            width > 0 && height > 0,
+            "Height delta of size {:?} changes no samples",
+            delta.size
+        );
+        ensure!(
+            delta.deltas.len() == width * height,
+            "Expected {} height deltas but got {}",
+            width * height,
+            delta.deltas.len()
+        );
+        ensure!(delta.step > 0, "Height delta has a step of zero meters");
+        Ok(delta)
+    }
+
+    pub(crate) fn vnode(&self) -> VNode {
+        self.node
+    }
+
+    /// Whether the changed samples all lie within a tile `resolution` samples across.
+    pub(crate) fn fits(&self, resolution: usize) -> bool {
+        self.origin.0 as usize + self.size.0 as usize <= resolution
+            && self.origin.1 as usize + self.size.1 as usize <= resolution
+    }
+
+    /// Index into a tile `resolution` samples across, and change in meters, of each sample.
+    pub(crate) fn offsets(&self, resolution: usize) -> impl Iterator<Item = (usize, i32)> + '_ {
+        let (x0, y0) = (self.origin.0 as usize, self.origin.1 as usize);
+        let width = self.size.0 as usize;
+        self.deltas.iter().enumerate().map(move |(i, &d)| {
+            ((y0 + i / width) * resolution + x0 + i % width, d as i32 * self.step as i32)
+        })
+    }
+
+    /// Add the change to `heights`, or subtract it if `sign` is negative.
+    pub(crate) fn add_to(&self, heights: &mut [i16], resolution: usize, sign: i32) {
+        for (i, d) in self.offsets(resolution) {
+            let h = heights[i] as i32 + d * sign.signum();
+            heights[i] = h.max(i16::MIN as i32).min(i16::MAX as i32) as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_and_revert() {
+        let node = VectorTileId::containing(0.3, 0.4, 12);
+        let crater = [-3, -5, -3, -5, -9, -5];
+        let delta = HeightDelta::new(node, (1, 2), (3, 2), &crater).unwrap();
+        assert_eq!(HeightDelta::from_bytes(&delta.to_bytes().unwrap()).unwrap(), delta);
+
+        let original: Vec<i16> = (0..25).collect();
+        let mut heights = original.clone();
+        delta.add_to(&mut heights, 5, 1);
+        assert_eq!(heights[2 * 5 + 1], 11 - 3);
+        assert_eq!(heights[3 * 5 + 2], 17 - 9);
+        assert_eq!(heights[0], 0);
+        delta.add_to(&mut heights, 5, -1);
+        assert_eq!(heights, original);
+
+        // Large changes are quantized.
+        let deep = HeightDelta::new(node, (0, 0), (2, 1), &[-300, 1]).unwrap();
+        let offsets: Vec<_> = deep.offsets(5).collect();
+        assert_eq!(offsets, vec![(0, -300), (1, 0)]);
+        assert!(!deep.fits(1));
+        assert!(HeightDelta::new(node, (0, 0), (2, 2), &[0; 3]).is_err());
+        assert!(HeightDelta::new(node, (0, 0), (0, 2), &[]).is_err());
+    }
+
+    #[test]
+    fn reject_malformed_bytes() {
+        let node = VectorTileId::containing(0.3, 0.4, 12).0;
+        let delta = |size, step, deltas| HeightDelta { node, origin: (0, 0), size, step, deltas };
+        let decode = |delta: HeightDelta| HeightDelta::from_bytes(&delta.to_bytes().unwrap());
+
+        assert!(decode(delta((2, 1), 1, vec![1, 2])).is_ok());
+        assert!(decode(delta((2, 2), 1, vec![1, 2])).is_err());
+        assert!(decode(delta((0, 1), 1, vec![])).is_err());
+        assert!(decode(delta((2, 1), 0, vec![1, 2])).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::coordinates::{self, PLANET_RADIUS};
+use cgmath::{InnerSpace, Vector3};
+use std::num::NonZeroU32;
+
+/// Number of voxels along each horizontal side of the distance field.
+pub(crate) const RESOLUTION: usize = 64;
+/// Number of voxels spanning the elevations of the terrain in the region.
+pub(crate) const LAYERS: usize = 32;
+/// Width in meters of the square region around the camera that the distance field covers.
+const REGION_SIZE: f64 = 32000.0;
+/// Distances are stored as a fraction of this, and saturate beyond it.
+const MAX_DISTANCE: f32 = 4000.0;
+/// Space in meters kept above the highest and below the lowest terrain in the region, so rays
+/// towards the sun don't immediately leave the field.
+const HEADROOM: f32 = 2000.0;
+const FOOTROOM: f32 = 100.0;
+/// Beyond this altitude the terrain in the region is too small on screen to be worth shadowing.
+const MAX_ALTITUDE: f64 = 16000.0;
+
+/// Squared distance transform along one axis, after Felzenszwalb and Huttenlocher: sets
+/// `output[i]` to the minimum over all `j` of `f[j] + ((i - j) * spacing)^2` in linear time.
+fn distance_transform(f: &[f64], spacing: f64, output: &mut [f64]) {
+    let n = f.len();
+    let s = spacing * spacing;
+    let intersect = |q: usize, p: usize| {
+        ((f[q] + s * (q * q) as f64) - (f[p] + s * (p * p) as f64)) / (2.0 * s * (q - p) as f64)
+    };
+
+    // Parabolas making up the lower envelope, and the ranges over which each is lowest.
+    let mut v = vec![0; n];
+    let mut z = vec![0.0; n + 1];
+    let mut k = 0;
+    z[0] = f64::NEG_INFINITY;
+    z[1] = f64::INFINITY;
+    for q in 1..n {
+        let mut x = intersect(q, v[k]);
+        while x <= z[k] {
+            k -= 1;
+            x = intersect(q, v[k]);
+        }
+        k += 1;
+        v[k] = q;
+        z[k] = x;
+        z[k + 1] = f64::INFINITY;
+    }
+
+    k = 0;
+    for (q, output) in output.iter_mut().enumerate() {
+        while z[k + 1] < q as f64 {
+            k += 1;
+        }
+        let d = q as f64 - v[k] as f64;
+        *output = s * d * d + f[v[k]];
+    }
+}
+
+/// Signed distances in meters from the center of every voxel to the terrain described by
+/// `heights`, a `resolution` x `resolution` grid of elevations `spacing` meters apart. Layer `k`
+/// is centered at elevation `base + (k + 0.5) * layer_height`, and the result is ordered by layer,
+/// then row, then column.
+///
+/// Terrain is treated as a set of columns rising to the height of each sample. Above the surface
+/// the distance is to the nearest column, while below it only the vertical depth is used.
+fn compute(
+    heights: &[f32],
+    resolution: usize,
+    layers: usize,
+    spacing: f32,
+    base: f32,
+    layer_height: f32,
+) -> Vec<f32> {
+    assert_eq!(heights.len(), resolution * resolution);
+    let mut distances = vec![0.0; resolution * resolution * layers];
+    let mut squared = vec![0.0; resolution * resolution];
+    let mut line = vec![0.0; resolution];
+    let mut transformed = vec![0.0; resolution];
+    for (k, layer) in distances.chunks_exact_mut(resolution * resolution).enumerate() {
+        let elevation = base + (k as f32 + 0.5) * layer_height;
+        for (s, &h) in squared.iter_mut().zip(heights) {
+            *s = ((elevation - h).max(0.0) as f64).powi(2);
+        }
+        for row in squared.chunks_exact_mut(resolution) {
+            line.copy_from_slice(row);
+            distance_transform(&line, spacing as f64, row);
+        }
+        for x in 0..resolution {
+            for (y, l) in line.iter_mut().enumerate() {
+                *l = squared[y * resolution + x];
+            }
+            distance_transform(&line, spacing as f64, &mut transformed);
+            for (y, &t) in transformed.iter().enumerate() {
+                squared[y * resolution + x] = t;
+            }
+        }
+        for ((d, &s), &h) in layer.iter_mut().zip(&squared).zip(heights) {
+            *d = if elevation < h { elevation - h } else { s.sqrt() as f32 };
+        }
+    }
+    distances
+}
+
+/// Pack a signed distance into a byte, with the surface at the middle of the range.
+fn encode(distance: f32) -> u8 {
+    let d = (distance / MAX_DISTANCE).max(-1.0).min(1.0);
+    ((0.5 + 0.5 * d) * 255.0).round() as u8
+}
+
+/// Where the distance field currently sits on the planet.
+struct Region {
+    /// Point at zero elevation under the middle of the region.
+    center: Vector3<f64>,
+    east: Vector3<f64>,
+    north: Vector3<f64>,
+    /// Elevation of the bottom of the lowest layer.
+    base: f32,
+    /// Elevation range spanned by all layers.
+    span: f32,
+}
+
+/// Coarse signed distance field of the terrain around the camera, used for soft sun shadows and
+/// sky occlusion beyond the range of shadow maps. It is rebuilt on the CPU from the resident
+/// heightmaps whenever the camera moves far enough or the tiles change.
+pub(crate) struct DistanceField {
+    region: Option<Region>,
+}
+impl DistanceField {
+    pub fn new() -> Self {
+        Self { region: None }
+    }
+
+    /// Forget the current region, so the next call to `update` rebuilds the field.
+    pub fn reset(&mut self) {
+        self.region = None;
+    }
+
+    /// Rebuild the field into `texture` if the camera has moved more than a quarter of the region
+    /// away from its center or `tiles_changed` is set. `height` gives the elevation of the
+    /// terrain at a latitude and longitude.
+    pub fn update<F: Fn(f64, f64) -> f32>(
+        &mut self,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        camera: Vector3<f64>,
+        tiles_changed: bool,
+        height: F,
+    ) {
+        if coordinates::ecef_to_polar(camera).z > MAX_ALTITUDE {
+            self.region = None;
+            return;
+        }
+        let up = camera.normalize();
+        if let Some(ref region) = self.region {
+            let offset = camera - region.center;
+            let moved = offset.dot(region.east).abs().max(offset.dot(region.north).abs());
+            if !tiles_changed && moved < 0.25 * REGION_SIZE {
+                return;
+            }
+        }
+
+        let center = up * PLANET_RADIUS;
+        let east = Vector3::unit_z().cross(up);
+        let east = if east.magnitude2() > 1e-12 { east.normalize() } else { Vector3::unit_x() };
+        let north = up.cross(east);
+
+        let spacing = REGION_SIZE / RESOLUTION as f64;
+        let mut heights = Vec::with_capacity(RESOLUTION * RESOLUTION);
+        for y in 0..RESOLUTION {
+            for x in 0..RESOLUTION {
+                let e = (x as f64 + 0.5) * spacing - 0.5 * REGION_SIZE;
+                let n = (y as f64 + 0.5) * spacing - 0.5 * REGION_SIZE;
+                let polar = coordinates::ecef_to_polar(center + east * e + north * n);
+                heights.push(height(polar.x, polar.y));
+            }
+        }
+
+        let min = heights.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = heights.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let base = min - FOOTROOM;
+        let span = max + HEADROOM - base;
+        let distances =
+            compute(&heights, RESOLUTION, LAYERS, spacing as f32, base, span / LAYERS as f32);
+        let encoded: Vec<u8> = distances.into_iter().map(encode).collect();
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture { texture, mip_level: 0, origin: wgpu::Origin3d::ZERO },
+            &encoded,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(NonZeroU32::new(RESOLUTION as u32).unwrap()),
+                rows_per_image: Some(NonZeroU32::new(RESOLUTION as u32).unwrap()),
+            },
+            wgpu::Extent3d {
+                width: RESOLUTION as u32,
+                height: RESOLUTION as u32,
+                depth_or_array_layers: LAYERS as u32,
+            },
+        );
+        self.region = Some(Region { center, east, north, base, span });
+    }
+
+    /// Packed form passed to shaders: the center of the region relative to `camera` and its
+    /// width, then the east and north directions along with the elevation of the bottom of the
+    /// field and the range it spans. All zero while there is no field.
+    pub fn uniforms(&self, camera: Vector3<f64>) -> [[f32; 4]; 3] {
+        match self.region {
+            Some(ref r) => {
+                let c = r.center - camera;
+                [
+                    [c.x as f32, c.y as f32, c.z as f32, REGION_SIZE as f32],
+                    [r.east.x as f32, r.east.y as f32, r.east.z as f32, r.base],
+                    [r.north.x as f32, r.north.y as f32, r.north.z as f32, r.span],
+                ]
+            }
+            None => [[0.0; 4]; 3],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance_to_column() {
+        // A single 100 meter column in the middle of flat ground at zero elevation.
+        let resolution = 9;
+        let mut heights = vec![0.0; resolution * resolution];
+        heights[4 * resolution + 4] = 100.0;
+        let distances = compute(&heights, resolution, 4, 10.0, 0.0, 50.0);
+        let at = |x: usize, y: usize, k: usize| distances[(k * resolution + y) * resolution + x];
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-3;
+
+        // Layer 0 is at 25 meters: inside the column, and above flat ground elsewhere.
+        assert!(close(at(4, 4, 0), -75.0));
+        assert!(close(at(0, 0, 0), 25.0));
+        assert!(close(at(6, 4, 0), 20.0));
+        assert!(close(at(5, 5, 0), 200f32.sqrt()));
+
+        // Layer 2 is at 125 meters, so the top of the column is 25 meters below and closer than
+        // the ground everywhere in the grid.
+        assert!(close(at(4, 4, 2), 25.0));
+        assert!(close(at(7, 4, 2), (30f32 * 30.0 + 25.0 * 25.0).sqrt()));
+        assert!(close(at(8, 8, 2), (2.0 * 40f32 * 40.0 + 25.0 * 25.0).sqrt()));
+
+        assert_eq!(encode(0.0), 128);
+        assert_eq!(encode(-2.0 * MAX_DISTANCE), 0);
+        assert_eq!(encode(MAX_DISTANCE), 255);
+    }
+}
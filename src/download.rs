@@ -0,0 +1,186 @@
+use crate::terrain::quadtree::node::VNode;
+use std::time::{Duration, Instant};
+
+/// Restrictions on downloading tiles, for users on slow or capped connections.
+pub(crate) struct DownloadLimits {
+    /// Average number of bytes per second that may be downloaded, or None for no limit.
+    rate_limit: Option<u32>,
+    /// No download may start before this time, so that the ones already made average out to
+    /// `rate_limit`.
+    next_download: Instant,
+    /// Whether only coarse levels are downloaded.
+    metered: bool,
+    /// Finest level that is downloaded on a metered connection.
+    permitted_level: u8,
+}
+impl Default for DownloadLimits {
+    fn default() -> Self {
+        Self {
+            rate_limit: None,
+            next_download: Instant::now(),
+            metered: false,
+            permitted_level: Self::METERED_MAX_LEVEL,
+        }
+    }
+}
+impl DownloadLimits {
+    /// Finest level downloaded on a metered connection unless more are permitted. Covering the
+    /// whole planet down to this level takes a few hundred megabytes.
+    pub const METERED_MAX_LEVEL: u8 = VNode::LEVEL_CELL_1KM;
+
+    #[cfg(any(feature = "render", test))]
+    pub fn set_rate_limit(&mut self, bytes_per_second: Option<u32>) {
+        self.rate_limit = bytes_per_second;
+    }
+
+    #[cfg(any(feature = "render", test))]
+    pub fn set_metered(&mut self, metered: bool) {
+        self.metered = metered;
+    }
+
+    #[cfg(any(feature = "render", test))]
+    pub fn set_permitted_level(&mut self, level: u8) {
+        self.permitted_level = level;
+    }
+
+    pub fn may_download(&self, node: VNode) -> bool {
+        !self.metered || node.level() <= self.permitted_level
+    }
+
+    /// How long to wait before starting another download.
+    pub fn delay(&self, now: Instant) -> Duration {
+        self.next_download.saturating_duration_since(now)
+    }
+
+    /// Account for a download of `bytes` that just finished.
+    pub fn record(&mut self, bytes: usize, now: Instant) {
+        if let Some(rate) = self.rate_limit {
+            let duration = Duration::from_secs_f64(bytes as f64 / rate.max(1) as f64);
+            self.next_download = self.next_download.max(now) + duration;
+        }
+    }
+}
+
+/// A tile server that tiles can be downloaded from.
+struct Mirror {
+    base_url: String,
+    /// Downloads currently in progress from this server.
+    inflight: usize,
+    /// Number of downloads in a row that have failed.
+    failures: u32,
+    /// The server is only tried as a last resort before this time.
+    retry_after: Instant,
+}
+
+/// Tile servers holding identical copies of the tiles, which downloads are spread across.
+pub(crate) struct Mirrors {
+    mirrors: Vec<Mirror>,
+}
+impl Mirrors {
+    /// Longest time that a failing server is avoided for.
+    const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+    pub fn new(base_urls: &[String]) -> Self {
+        let mut mirrors = Self { mirrors: Vec::new() };
+        mirrors.set_base_urls(base_urls);
+        mirrors
+    }
+
+    /// Replace the list of servers, keeping the health of any that were already known.
+    pub fn set_base_urls(&mut self, base_urls: &[String]) {
+        let mut old = std::mem::take(&mut self.mirrors);
+        for url in base_urls {
+            let base_url = if url.ends_with('/') { url.clone() } else { format!("{}/", url) };
+            let mirror = match old.iter().position(|m| m.base_url == base_url) {
+                Some(i) => old.swap_remove(i),
+                None => Mirror { base_url, inflight: 0, failures: 0, retry_after: Instant::now() },
+            };
+            self.mirrors.push(mirror);
+        }
+    }
+
+    /// Order in which servers should be tried for the next download: healthy servers with the
+    /// fewest downloads in progress first, followed by the ones that failed recently.
+    pub fn candidates(&self, now: Instant) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.mirrors.len()).collect();
+        order.sort_by_key(|&i| {
+            let m = &self.mirrors[i];
+            (m.retry_after > now, m.inflight, m.failures)
+        });
+        order
+    }
+
+    pub fn url(&self, index: usize, name: &str) -> String {
+        format!("{}{}", self.mirrors[index].base_url, name)
+    }
+
+    pub fn start(&mut self, index: usize) {
+        self.mirrors[index].inflight += 1;
+    }
+
+    /// Record the outcome of a download. Servers that fail are avoided for exponentially
+    /// increasing amounts of time.
+    pub fn finish(&mut self, index: usize, success: bool, now: Instant) {
+        let mirror = &mut self.mirrors[index];
+        mirror.inflight -= 1;
+        if success {
+            mirror.failures = 0;
+            mirror.retry_after = now;
+        } else {
+            mirror.failures += 1;
+            let backoff = Duration::from_secs(1 << mirror.failures.min(16));
+            mirror.retry_after = now + backoff.min(Self::MAX_BACKOFF);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_limits() {
+        let mut limits = DownloadLimits::default();
+        let now = Instant::now();
+        limits.record(1000, now);
+        assert_eq!(limits.delay(now), Duration::from_secs(0));
+
+        limits.set_rate_limit(Some(500));
+        limits.record(1000, now);
+        limits.record(500, now);
+        assert_eq!(limits.delay(now), Duration::from_secs(3));
+        assert_eq!(limits.delay(now + Duration::from_secs(5)), Duration::from_secs(0));
+
+        let mut fine = VNode::roots()[0];
+        while fine.level() < VNode::LEVEL_CELL_625M {
+            fine = fine.children()[0];
+        }
+        assert!(limits.may_download(fine));
+        limits.set_metered(true);
+        assert!(!limits.may_download(fine));
+        assert!(limits.may_download(fine.parent().unwrap().0));
+        limits.set_permitted_level(VNode::LEVEL_CELL_625M);
+        assert!(limits.may_download(fine));
+    }
+
+    #[test]
+    fn mirror_failover() {
+        let mut mirrors =
+            Mirrors::new(&["https://a.example".to_owned(), "https://b.example/".into()]);
+        let now = Instant::now();
+        assert_eq!(mirrors.url(0, "x.raw"), "https://a.example/x.raw");
+        assert_eq!(mirrors.candidates(now), vec![0, 1]);
+
+        mirrors.start(0);
+        assert_eq!(mirrors.candidates(now), vec![1, 0]);
+        mirrors.finish(0, false, now);
+        mirrors.start(1);
+        assert_eq!(mirrors.candidates(now), vec![1, 0]);
+        mirrors.finish(1, true, now);
+        assert_eq!(mirrors.candidates(now + Duration::from_secs(3)), vec![1, 0]);
+
+        mirrors.set_base_urls(&["https://b.example".to_owned(), "https://a.example".to_owned()]);
+        assert_eq!(mirrors.candidates(now), vec![0, 1]);
+        assert_eq!(mirrors.url(1, "x.raw"), "https://a.example/x.raw");
+    }
+}
@@ -0,0 +1,68 @@
+//! Positions of the sun and moon as seen from the planet.
+
+use crate::date::Date;
+use astro::{coords, ecliptic, lunar, sun, time};
+use cgmath::Vector3;
+
+/// Mean radius of the moon in kilometers.
+const MOON_RADIUS_KM: f64 = 1737.4;
+
+/// Convert a direction given in geocentric ecliptic coordinates into a unit vector in ECEF
+/// coordinates.
+fn ecliptic_to_ecef(julian_day: f64, longitude: f64, latitude: f64) -> Vector3<f64> {
+    let obliquity = ecliptic::mn_oblq_IAU(julian_day);
+    let right_ascension = coords::asc_frm_ecl(longitude, latitude, obliquity);
+    let declination = coords::dec_frm_ecl(longitude, latitude, obliquity);
+
+    // Sidereal time at Greenwich gives how far the planet has rotated relative to the stars.
+    let hour_angle = right_ascension - time::mn_sidr(julian_day);
+    Vector3::new(
+        declination.cos() * hour_angle.cos(),
+        declination.cos() * hour_angle.sin(),
+        declination.sin(),
+    )
+}
+
+/// Where the sun and moon are in the sky at a particular moment.
+pub(crate) struct Ephemeris {
+    /// Unit vector from the center of the planet towards the sun, in ECEF coordinates.
+    pub sun_direction: Vector3<f64>,
+    /// Unit vector from the center of the planet towards the moon, in ECEF coordinates.
+    pub moon_direction: Vector3<f64>,
+    /// Angular radius of the moon's disk in radians.
+    pub moon_angular_radius: f64,
+}
+impl Ephemeris {
+    pub fn at(date: Date) -> Self {
+        let julian_day = date.julian_day();
+        let (sun, _) = sun::geocent_ecl_pos(julian_day);
+        let (moon, moon_distance_km) = lunar::geocent_ecl_pos(julian_day);
+        Self {
+            sun_direction: ecliptic_to_ecef(julian_day, sun.long, sun.lat),
+            moon_direction: ecliptic_to_ecef(julian_day, moon.long, moon.lat),
+            moon_angular_radius: (MOON_RADIUS_KM / moon_distance_km).asin(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+
+    #[test]
+    fn solstice_and_lunar_phases() {
+        // Noon UTC on the June solstice, so the sun is close to the prime meridian.
+        let sun = Ephemeris::at(Date::new(2021, 6, 21.5)).sun_direction;
+        assert!((sun.z.asin().to_degrees() - 23.44).abs() < 0.1);
+        assert!(sun.y.atan2(sun.x).to_degrees().abs() < 2.0);
+
+        // Fraction of the moon's disk that is lit.
+        let illumination = |date| {
+            let e = Ephemeris::at(date);
+            0.5 - 0.5 * e.sun_direction.dot(e.moon_direction)
+        };
+        assert!(illumination(Date::new(2021, 6, 10.45)) < 0.03);
+        assert!(illumination(Date::new(2021, 6, 24.78)) > 0.97);
+    }
+}
@@ -0,0 +1,45 @@
+use crate::coordinates::PLANET_RADIUS;
+use cgmath::{InnerSpace, Vector3};
+
+/// Pick an exposure for a camera at `camera` with the sun in `sun_direction`, both in
+/// earth-centered, earth-fixed coordinates, clamped to `range`.
+///
+/// Exposure stays at the top of the range whenever sunlit ground can be seen, which from high
+/// altitude includes ground well beyond the terminator since the horizon drops away. Only once
+/// the camera is on the night side with nothing lit in view does it fall to the bottom of the
+/// range, so that night scenes at ground level stay readable.
+pub(crate) fn auto_ev100(
+    camera: Vector3<f64>,
+    sun_direction: Vector3<f64>,
+    range: (f32, f32),
+) -> f32 {
+    let distance = camera.magnitude().max(PLANET_RADIUS);
+    let horizon_dip = (PLANET_RADIUS / distance).acos();
+    let sun_elevation = camera.normalize().dot(sun_direction.normalize()).asin();
+
+    // Fade through civil twilight, which ends when the sun is six degrees below the horizon.
+    let x = ((sun_elevation + horizon_dip + 0.1) / 0.15).clamp(0.0, 1.0);
+    let daylight = (x * x * (3.0 - 2.0 * x)) as f32;
+    range.0 + (range.1 - range.0) * daylight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn night_exposure() {
+        let range = (4.0, 15.0);
+        let sun = Vector3::new(1.0, 0.0, 0.0);
+        let ground = |x: f64, y: f64| Vector3::new(x, y, 0.0).normalize() * PLANET_RADIUS;
+
+        assert_eq!(auto_ev100(ground(1.0, 0.0), sun, range), 15.0);
+        assert_eq!(auto_ev100(ground(-1.0, 0.0), sun, range), 4.0);
+        let dusk = auto_ev100(ground(0.03, 1.0), -sun, range);
+        assert!(dusk > 4.0 && dusk < 15.0);
+
+        // From orbit above the terminator, part of the dayside is in view.
+        let orbit = Vector3::new(-0.2, 1.0, 0.0).normalize() * PLANET_RADIUS * 1.5;
+        assert_eq!(auto_ev100(orbit, sun, range), 15.0);
+    }
+}
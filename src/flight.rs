@@ -0,0 +1,352 @@
+use crate::camera::Camera;
+use crate::coordinates::{self, PLANET_RADIUS};
+use cgmath::{InnerSpace, Matrix3, Quaternion, Rad, Rotation3, Vector3};
+
+/// A point that a `FlightPath` passes through.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Waypoint {
+    /// Latitude in radians.
+    pub latitude: f64,
+    /// Longitude in radians.
+    pub longitude: f64,
+    /// Altitude above sea level in meters.
+    pub altitude: f64,
+}
+
+/// A camera path that follows great circles between waypoints.
+///
+/// Altitude is eased between waypoints so that climbs and descents start and end gently, and the
+/// camera looks along a direction averaged over `lookahead` meters of path so that it turns
+/// smoothly through each waypoint, banking into the turn. Sample it once per frame with
+/// `camera` and pass the result to `Terrain::render_camera`.
+#[derive(Clone, Debug)]
+pub struct FlightPath {
+    waypoints: Vec<Waypoint>,
+    /// Unit vectors from the planet center towards each waypoint.
+    directions: Vec<Vector3<f64>>,
+    /// Distance along the surface from the first waypoint to each waypoint.
+    distances: Vec<f64>,
+    lookahead: f64,
+    max_bank: f64,
+    fov_y: f64,
+    near: f64,
+}
+impl FlightPath {
+    /// Radians of roll per radian of turn within the lookahead window.
+    const BANK_PER_TURN: f64 = 4.0;
+
+    /// Create a path through the given waypoints, which must contain at least two entries.
+    pub fn new(waypoints: Vec<Waypoint>) -> Self {
+        assert!(waypoints.len() >= 2);
+        let directions: Vec<_> = waypoints
+            .iter()
+            .map(|w| {
+                coordinates::polar_to_ecef(Vector3::new(w.latitude, w.longitude, 0.0)).normalize()
+            })
+            .collect();
+        let mut distances = vec![0.0];
+        for d in directions.windows(2) {
+            let angle = d[0].cross(d[1]).magnitude().atan2(d[0].dot(d[1]));
+            distances.push(distances.last().unwrap() + angle * PLANET_RADIUS);
+        }
+
+        Self {
+            waypoints,
+            directions,
+            distances,
+            lookahead: 500.0,
+            max_bank: 30f64.to_radians(),
+            fov_y: 60f64.to_radians(),
+            near: 0.1,
+        }
+    }
+
+    /// Distance in meters over which the view direction is averaged. Larger values give wider,
+    /// smoother turns. Defaults to 500.
+    pub fn with_lookahead(mut self, meters: f64) -> Self {
+        self.lookahead = meters.max(1.0);
+        self
+    }
+
+    /// Maximum roll in radians when turning. Zero disables banking. Defaults to 30 degrees.
+    pub fn with_max_bank(mut self, radians: f64) -> Self {
+        self.max_bank = radians.abs();
+        self
+    }
+
+    /// Field of view and near plane of the cameras produced.
+    pub fn with_projection(mut self, fov_y: f64, near: f64) -> Self {
+        self.fov_y = fov_y;
+        self.near = near;
+        self
+    }
+
+    /// Length of the path in meters, measured along the planet surface.
+    pub fn length(&self) -> f64 {
+        *self.distances.last().unwrap()
+    }
+
+    /// The camera at `distance` meters along the path. Distances outside the path are clamped to
+    /// its ends.
+    pub fn camera(&self, distance: f64) -> Camera {
+        let length = self.length();
+        let distance = distance.max(0.0).min(length);
+        let behind = self.position(distance - self.lookahead);
+        let position = self.position(distance);
+        let ahead = self.position(distance + self.lookahead);
+
+        let up = position.normalize();
+        let mut forward = ahead - behind;
+        if forward.magnitude2() == 0.0 {
+            forward = self.directions[1] - self.directions[0];
+        }
+        let forward = forward.normalize();
+        let up = (up - forward * forward.dot(up)).normalize();
+        let right = forward.cross(up);
+
+        let (d0, d1) = (position - behind, ahead - position);
+        let turn = if d0.magnitude2() > 0.0 && d1.magnitude2() > 0.0 {
+            d0.cross(d1).dot(up).atan2(d0.dot(d1))
+        } else {
+            0.0
+        };
+        let roll = (turn * Self::BANK_PER_TURN).max(-self.max_bank).min(self.max_bank);
+
+        let rotation = Quaternion::from(Matrix3::from_cols(right, up, -forward))
+            * Quaternion::from_angle_z(Rad(roll));
+        Camera {
+            position: mint::Point3 { x: position.x, y: position.y, z: position.z },
+            orientation: rotation.into(),
+            fov_y: self.fov_y,
+            near: self.near,
+        }
+    }
+
+    /// World space position at `distance` meters along the path.
+    fn position(&self, distance: f64) -> Vector3<f64> {
+        let distance = distance.max(0.0).min(self.length());
+        let i = match self.distances[1..].iter().position(|&d| distance <= d) {
+            Some(i) => i,
+            None => self.distances.len() - 2,
+        };
+
+        let (a, b) = (self.directions[i], self.directions[i + 1]);
+        let segment = self.distances[i + 1] - self.distances[i];
+        let (dir, u) = if segment > 0.0 {
+            let axis = a.cross(b).normalize();
+            let t = (distance - self.distances[i]) / PLANET_RADIUS;
+            (a * t.cos() + axis.cross(a) * t.sin(), (distance - self.distances[i]) / segment)
+        } else {
+            (a, 0.0)
+        };
+
+        let ease = u * u * (3.0 - 2.0 * u);
+        let (a0, a1) = (self.waypoints[i].altitude, self.waypoints[i + 1].altitude);
+        dir * (PLANET_RADIUS + a0 + (a1 - a0) * ease)
+    }
+}
+
+/// Flies a camera along a great circle at a fixed height above the ground.
+///
+/// Each step samples the ground ahead with a height query such as `Terrain::get_height`. The
+/// camera starts climbing early enough to clear the highest point within `lookahead` meters, and
+/// descends more slowly than it climbs, so that it sweeps over ridgelines instead of tracing every
+/// bump in the terrain. Call `advance` once per frame and pass the result to
+/// `Terrain::render_camera`.
+#[derive(Clone, Debug)]
+pub struct TerrainFollower {
+    /// Unit vector from the planet center towards the camera.
+    direction: Vector3<f64>,
+    /// Unit vector along the surface in the direction of travel.
+    forward: Vector3<f64>,
+    /// Current altitude above sea level.
+    altitude: f64,
+    /// Vertical speed as a fraction of the horizontal speed, used to pitch the camera.
+    climb: f64,
+    height_above_ground: f64,
+    speed: f64,
+    lookahead: f64,
+    fov_y: f64,
+    near: f64,
+}
+impl TerrainFollower {
+    /// Steepest climb, as a fraction of the distance traveled, that the camera plans for when
+    /// approaching high ground.
+    const MAX_CLIMB: f64 = 0.3;
+    /// Number of ground samples taken across the lookahead distance.
+    const SAMPLES: usize = 8;
+    /// Time constants in seconds for approaching the target altitude from below and above.
+    const CLIMB_TIME: f64 = 1.0;
+    const DESCENT_TIME: f64 = 4.0;
+
+    /// Start at the given latitude and longitude, in radians, heading `heading` radians clockwise
+    /// from north at `speed` meters per second and `height_above_ground` meters above the
+    /// terrain.
+    pub fn new(
+        latitude: f64,
+        longitude: f64,
+        heading: f64,
+        height_above_ground: f64,
+        speed: f64,
+    ) -> Self {
+        let direction =
+            coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0)).normalize();
+        let east = Vector3::new(-longitude.sin(), longitude.cos(), 0.0);
+        let north = direction.cross(east);
+        Self {
+            direction,
+            forward: north * heading.cos() + east * heading.sin(),
+            altitude: f64::NAN,
+            climb: 0.0,
+            height_above_ground,
+            speed,
+            lookahead: 2000.0,
+            fov_y: 60f64.to_radians(),
+            near: 0.1,
+        }
+    }
+
+    /// Distance in meters ahead of the camera that the ground is sampled over. Larger values
+    /// smooth out more of the terrain. Defaults to 2000.
+    pub fn with_lookahead(mut self, meters: f64) -> Self {
+        self.lookahead = meters.max(1.0);
+        self
+    }
+
+    /// Field of view and near plane of the cameras produced.
+    pub fn with_projection(mut self, fov_y: f64, near: f64) -> Self {
+        self.fov_y = fov_y;
+        self.near = near;
+        self
+    }
+
+    pub fn set_height_above_ground(&mut self, meters: f64) {
+        self.height_above_ground = meters;
+    }
+
+    pub fn set_speed(&mut self, meters_per_second: f64) {
+        self.speed = meters_per_second;
+    }
+
+    /// Turn by `radians`, clockwise when seen from above.
+    pub fn turn(&mut self, radians: f64) {
+        let right = self.forward.cross(self.direction);
+        self.forward = self.forward * radians.cos() + right * radians.sin();
+    }
+
+    /// Altitude above sea level in meters.
+    pub fn altitude(&self) -> f64 {
+        self.altitude
+    }
+
+    /// Move forward for `dt` seconds and return the new camera. `get_height` returns the height of
+    /// the ground in meters at a latitude and longitude in radians.
+    pub fn advance<F: FnMut(f64, f64) -> f32>(&mut self, dt: f64, mut get_height: F) -> Camera {
+        let step = self.speed * dt;
+        let previous_altitude = self.altitude;
+        let (direction, forward) = self.along(step);
+        self.direction = direction;
+        self.forward = forward;
+
+        let mut target = f64::MIN;
+        for i in 0..=Self::SAMPLES {
+            let distance = self.lookahead * i as f64 / Self::SAMPLES as f64;
+            let polar = coordinates::ecef_to_polar(self.along(distance).0 * PLANET_RADIUS);
+            let ground = get_height(polar.x, polar.y) as f64;
+            target = target.max(ground + self.height_above_ground - distance * Self::MAX_CLIMB);
+        }
+
+        if self.altitude.is_nan() {
+            self.altitude = target;
+        } else {
+            let time = if target > self.altitude { Self::CLIMB_TIME } else { Self::DESCENT_TIME };
+            self.altitude += (target - self.altitude) * (1.0 - (-dt / time).exp());
+        }
+        self.climb = if step > 0.0 && !previous_altitude.is_nan() {
+            (self.altitude - previous_altitude) / step
+        } else {
+            0.0
+        };
+
+        let position = self.direction * (PLANET_RADIUS + self.altitude);
+        let forward = (self.forward + self.direction * self.climb).normalize();
+        let up = (self.direction - forward * forward.dot(self.direction)).normalize();
+        let rotation = Quaternion::from(Matrix3::from_cols(forward.cross(up), up, -forward));
+        Camera {
+            position: mint::Point3 { x: position.x, y: position.y, z: position.z },
+            orientation: rotation.into(),
+            fov_y: self.fov_y,
+            near: self.near,
+        }
+    }
+
+    /// Unit vectors towards the point `distance` meters ahead along the great circle, and along
+    /// the direction of travel at that point.
+    fn along(&self, distance: f64) -> (Vector3<f64>, Vector3<f64>) {
+        let angle = distance / PLANET_RADIUS;
+        let direction = self.direction * angle.cos() + self.forward * angle.sin();
+        let forward = self.forward * angle.cos() - self.direction * angle.sin();
+        (direction.normalize(), forward.normalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{EuclideanSpace, Point3};
+    use std::f64::consts::PI;
+
+    #[test]
+    fn follows_waypoints() {
+        let waypoints = vec![
+            Waypoint { latitude: 0.0, longitude: 0.0, altitude: 1000.0 },
+            Waypoint { latitude: 0.0, longitude: 0.01, altitude: 3000.0 },
+            Waypoint { latitude: 0.01, longitude: 0.01, altitude: 3000.0 },
+        ];
+        let path = FlightPath::new(waypoints);
+        assert!((path.length() - 0.02 * PLANET_RADIUS).abs() < 1.0);
+
+        let middle = Point3::from(path.camera(0.01 * PLANET_RADIUS).position).to_vec();
+        let expected = coordinates::polar_to_ecef(Vector3::new(0.0, 0.01, 3000.0));
+        assert!((middle - expected).magnitude() < 1e-3);
+
+        // Heading east at the start, looking down the -Z axis of the camera.
+        let camera = path.camera(0.0);
+        let forward = Quaternion::from(camera.orientation) * -Vector3::unit_z();
+        assert!(forward.dot(Vector3::unit_y()) > 0.99);
+
+        // Turning left towards the north, so the camera rolls left.
+        let camera = path.camera(0.01 * PLANET_RADIUS);
+        let up = Quaternion::from(camera.orientation) * Vector3::unit_y();
+        assert!(up.dot(Vector3::unit_z()) > 0.0);
+    }
+
+    #[test]
+    fn follows_terrain() {
+        // Heading east along the equator towards a 500 meter plateau starting 3 km away.
+        let ridge = 3000.0 / PLANET_RADIUS;
+        let height = |_: f64, longitude: f64| if longitude > ridge { 500.0 } else { 0.0 };
+        let mut follower = TerrainFollower::new(0.0, 0.0, PI / 2.0, 100.0, 100.0);
+
+        let camera = follower.advance(0.1, height);
+        assert!((follower.altitude() - 100.0).abs() < 1e-6);
+        let forward = Quaternion::from(camera.orientation) * -Vector3::unit_z();
+        assert!(forward.dot(Vector3::unit_y()) > 0.99);
+
+        // Climbing starts before the plateau is reached, and the camera pitches up to match.
+        let mut camera = camera;
+        for _ in 0..280 {
+            camera = follower.advance(0.1, height);
+        }
+        let position = Point3::from(camera.position).to_vec();
+        assert!(position.y / PLANET_RADIUS < ridge);
+        assert!(follower.altitude() > 400.0);
+        let forward = Quaternion::from(camera.orientation) * -Vector3::unit_z();
+        assert!(forward.dot(position.normalize()) > 0.0);
+
+        for _ in 0..420 {
+            follower.advance(0.1, height);
+        }
+        assert!((follower.altitude() - 600.0).abs() < 1.0);
+    }
+}
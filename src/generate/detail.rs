@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize};
+
+/// Shape of the fractal detail synthesized below the resolution of the source elevation data.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FractalDetail {
+    /// Height of the detail on flat ground, relative to the spacing between heightmap samples.
+    pub amplitude: f32,
+    /// Height of the detail on steep slopes, relative to the spacing between heightmap samples.
+    pub steep_amplitude: f32,
+    /// From zero for smooth, rolling detail up to one for jagged detail that varies from one
+    /// sample to the next.
+    pub roughness: f32,
+}
+
+/// Fractal detail to use for each class of land cover. Land cover is estimated from the albedo of
+/// the enclosing tile, and the parameters of neighboring classes are blended so there are no
+/// visible boundaries between them.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FractalDetailTable {
+    /// Grassland and farmland.
+    pub plains: FractalDetail,
+    /// Densely vegetated areas.
+    pub forest: FractalDetail,
+    /// Sandy areas, where smooth detail gives the appearance of dunes.
+    pub desert: FractalDetail,
+    /// Bare rock, where rough detail resembles karst and scree.
+    pub rock: FractalDetail,
+    /// Snow and ice sheets.
+    pub ice: FractalDetail,
+}
+impl FractalDetailTable {
+    /// Packed form passed to the heightmap generation shader. The order must match
+    /// `fractal_detail` in gen-heightmaps.comp.
+    #[cfg(feature = "render")]
+    pub(crate) fn uniform(&self) -> [[f32; 4]; 5] {
+        let pack = |d: &FractalDetail| {
+            [d.amplitude, d.steep_amplitude, d.roughness.max(0.0).min(1.0), 0.0]
+        };
+        [
+            pack(&self.plains),
+            pack(&self.forest),
+            pack(&self.desert),
+            pack(&self.rock),
+            pack(&self.ice),
+        ]
+    }
+}
+impl Default for FractalDetailTable {
+    fn default() -> Self {
+        Self {
+            plains: FractalDetail { amplitude: 0.1, steep_amplitude: 0.4, roughness: 1.0 },
+            forest: FractalDetail { amplitude: 0.15, steep_amplitude: 0.4, roughness: 0.8 },
+            desert: FractalDetail { amplitude: 0.3, steep_amplitude: 0.3, roughness: 0.1 },
+            rock: FractalDetail { amplitude: 0.25, steep_amplitude: 0.6, roughness: 1.0 },
+            ice: FractalDetail { amplitude: 0.05, steep_amplitude: 0.3, roughness: 0.3 },
+        }
+    }
+}
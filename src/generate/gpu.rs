@@ -13,6 +13,8 @@ pub(crate) struct GenHeightmapsUniforms {
     pub out_slot: i32,
     pub level_resolution: i32,
     pub face: u32,
+    pub padding: [u32; 3],
+    pub fractal_detail: [[f32; 4]; 5],
 }
 unsafe impl bytemuck::Zeroable for GenHeightmapsUniforms {}
 unsafe impl bytemuck::Pod for GenHeightmapsUniforms {}
@@ -29,6 +31,10 @@ pub(crate) struct GenDisplacementsUniforms {
     pub displacements_slot: i32,
     pub face: i32,
     pub level_resolution: u32,
+    /// Terrain is clamped to lie between these elevations. See `PlanetConfig::drawn_range`.
+    pub min_elevation: f32,
+    pub max_elevation: f32,
+    pub padding1: f32,
 }
 unsafe impl bytemuck::Zeroable for GenDisplacementsUniforms {}
 unsafe impl bytemuck::Pod for GenDisplacementsUniforms {}
@@ -55,11 +61,29 @@ pub(crate) struct GenMaterialsUniforms {
     pub albedo_slot: i32,
     pub parent_slot: i32,
     pub spacing: f32,
-    pub padding: i32,
+    /// Whether albedo is upsampled from the parent tile guided by the heightmap.
+    pub guided_upsampling: u32,
 }
 unsafe impl bytemuck::Zeroable for GenMaterialsUniforms {}
 unsafe impl bytemuck::Pod for GenMaterialsUniforms {}
 
+/// Uniforms passed to the generators of custom layers. See `LayerGenerator`.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct GenCustomLayerUniforms {
+    pub position: [u32; 2],
+    pub slot: i32,
+    /// Slot of the parent tile, or -1 for root nodes.
+    pub parent_slot: i32,
+    pub level: u32,
+    pub face: u32,
+    /// Which quadrant of the parent the node covers.
+    pub parent_index: u32,
+    pub padding: u32,
+}
+unsafe impl bytemuck::Zeroable for GenCustomLayerUniforms {}
+unsafe impl bytemuck::Pod for GenCustomLayerUniforms {}
+
 pub(crate) struct ComputeShader<U> {
     shader: rshader::ShaderSet,
     bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::ComputePipeline)>,
@@ -120,7 +144,7 @@ impl<U: bytemuck::Pod> ComputeShader<U> {
                 bind_group,
                 device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
                     layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                        bind_group_layouts: [&bind_group_layout][..].into(),
+                        bind_group_layouts: [&*bind_group_layout][..].into(),
                         push_constant_ranges: &[],
                         label: Some(&format!("pipeline.{}.layout", self.name)),
                     })),
@@ -1,8 +1,10 @@
 use crate::cache::{LayerParams, LayerType};
 use crate::coordinates;
+use crate::generate::{HeightmapPass, TileData, TileNode};
 use crate::mapfile::MapFile;
+use crate::planet::PlanetConfig;
 use crate::terrain::quadtree::node::VNode;
-use crate::terrain::raster::{GlobalRaster, RasterCache};
+use crate::terrain::raster::{GlobalRaster, MMappedValues, RasterCache};
 use anyhow::Error;
 use cgmath::Vector2;
 use crossbeam::channel::{self, Receiver, Sender};
@@ -18,7 +20,45 @@ use std::io::{Cursor, Read, Write};
 use std::sync::{Arc, Weak};
 use vec_map::VecMap;
 
-fn compress_heightmap_tile(
+/// Estimate the maximum vertical error, in meters, from rendering a node's mesh with only every
+/// other vertex, given the height of each sample of its heightmap tile. This is roughly the error
+/// of drawing the node instead of its children.
+pub(crate) fn geometric_error(
+    layers: &VecMap<LayerParams>,
+    planet: &PlanetConfig,
+    height: impl Fn(usize) -> f32,
+) -> f32 {
+    let resolution = layers[LayerType::Heightmaps].texture_resolution as usize;
+    let border = layers[LayerType::Heightmaps].texture_border_size as usize;
+    let mesh_resolution = layers[LayerType::Displacements].texture_resolution as usize - 1;
+    let stride = (resolution - 2 * border - 1) / mesh_resolution;
+    assert_eq!(mesh_resolution % 2, 0);
+
+    let height = |x: usize, y: usize| {
+        planet.clamp(height((border + x * stride) + (border + y * stride) * resolution))
+    };
+
+    let mut error = 0.0f32;
+    for y in 0..=mesh_resolution {
+        for x in 0..=mesh_resolution {
+            let coarse = match (x % 2, y % 2) {
+                (0, 0) => continue,
+                (1, 0) => 0.5 * (height(x - 1, y) + height(x + 1, y)),
+                (0, 1) => 0.5 * (height(x, y - 1) + height(x, y + 1)),
+                _ => {
+                    0.25 * (height(x - 1, y - 1)
+                        + height(x + 1, y - 1)
+                        + height(x - 1, y + 1)
+                        + height(x + 1, y + 1))
+                }
+            };
+            error = error.max((height(x, y) - coarse).abs());
+        }
+    }
+    error
+}
+
+pub(crate) fn compress_heightmap_tile(
     resolution: usize,
     skirt: usize,
     log2_scale_factor: i8,
@@ -296,6 +336,15 @@ impl HeightmapCache {
         Self { layer, tiles: Cache::new(capacity) }
     }
 
+    /// Returns the decoded tile for `node` if it is already in memory.
+    pub(crate) fn cached(&mut self, node: VNode) -> Option<Arc<Vec<i16>>> {
+        self.tiles.get(node)
+    }
+
+    pub(crate) fn insert(&mut self, node: VNode, heights: Arc<Vec<i16>>) {
+        self.tiles.insert(node, heights);
+    }
+
     pub(crate) fn get_tile<'a>(
         &mut self,
         mapfile: &'a MapFile,
@@ -349,8 +398,9 @@ impl HeightmapCache {
 
 pub(crate) struct HeightmapGen {
     pub tile_cache: HeightmapCache,
-    pub dems: RasterCache<f32, Vec<f32>>,
+    pub dems: RasterCache<f32, MMappedValues<f32>>,
     pub global_dem: Arc<GlobalRaster<i16>>,
+    pub passes: Arc<Vec<HeightmapPass>>,
 }
 impl HeightmapGen {
     pub(crate) async fn generate_heightmaps<'a>(
@@ -396,7 +446,9 @@ impl HeightmapGen {
             }
         }
 
+        let windows = self.dems.windows();
         let global_dem = self.global_dem.clone();
+        let passes = Arc::clone(&self.passes);
         let resolution = self.tile_cache.layer.texture_resolution as usize;
         let border_size = self.tile_cache.layer.texture_border_size as usize;
         Ok(async move {
@@ -410,8 +462,27 @@ impl HeightmapGen {
                 );
             } else {
                 let rasters = futures::future::try_join_all(rasters).await?;
-                let rasters: fnv::FnvHashMap<(i16, i16), Arc<_>> =
-                    rasters.into_iter().filter_map(|v| Some((v.0, v.1?))).collect();
+                let requested = rasters.len();
+                // Only copy the windows of each raster that this tile touches, so generating many
+                // tiles at once doesn't page in every cell of every raster.
+                let rasters: fnv::FnvHashMap<(i16, i16), _> = rasters
+                    .into_iter()
+                    .filter_map(|(tile, raster)| {
+                        let points = coordinates.iter().copied().filter(|(lat, long)| {
+                            (lat.floor() as i16, long.floor() as i16) == tile
+                        });
+                        Some((tile, windows.view(tile, &*raster?, points)))
+                    })
+                    .collect();
+                if rasters.len() < requested {
+                    log::debug!(
+                        target: super::LOG_TARGET,
+                        "heightmap tile {:?} has no DEM data for {} of {} cells, using ETOPO1",
+                        node,
+                        requested - rasters.len(),
+                        requested
+                    );
+                }
 
                 heightmap.par_iter_mut().zip(coordinates.into_par_iter()).for_each(
                     |(h, (lat, long))| {
@@ -425,6 +496,13 @@ impl HeightmapGen {
 
             let (tx, rx) = tokio::sync::oneshot::channel();
             rayon::spawn(move || {
+                let mut data = TileData { resolution, border: border_size, heights: heightmap };
+                for pass in &*passes {
+                    (pass.pass)(&mut data, TileNode::new(node, resolution, border_size));
+                }
+                assert_eq!(data.heights.len(), resolution * resolution);
+                let heightmap = data.heights;
+
                 let tile = compress_heightmap_tile(
                     resolution,
                     border_size,
@@ -433,7 +511,17 @@ impl HeightmapGen {
                     parent.as_ref().map(|&(i, ref a)| (i, &***a)),
                 );
 
-                tx.send(mapfile.write_tile(LayerType::Heightmaps, node, &tile, true)).unwrap();
+                let elevation_range =
+                    heightmap.iter().fold((f32::MAX, f32::MIN), |(min, max), &h| {
+                        (min.min(h as f32), max.max(h as f32))
+                    });
+                let geometric_error =
+                    geometric_error(mapfile.layers(), mapfile.planet(), |i| heightmap[i] as f32);
+
+                tx.send(mapfile.write_tile(LayerType::Heightmaps, node, &tile, true).and_then(
+                    |()| mapfile.set_heightmap_bounds(node, elevation_range, geometric_error),
+                ))
+                .unwrap();
             });
             rx.map(|r| Ok(r??)).await
         }
@@ -0,0 +1,191 @@
+#[cfg(any(feature = "jpeg", feature = "webp"))]
+use crate::asset::TERRA_DIRECTORY;
+use anyhow::{format_err, Error};
+#[cfg(any(feature = "jpeg", feature = "webp"))]
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use image::{png::PngDecoder, ColorType, ImageDecoder};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufReader, Read};
+#[cfg(any(feature = "jpeg", feature = "webp"))]
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+#[cfg(any(feature = "jpeg", feature = "webp"))]
+use std::time::UNIX_EPOCH;
+
+/// Number of rows read from each image before synchronizing with the other readers.
+pub(crate) const SLAB_ROWS: usize = 108;
+/// Most images decoded at once by `open_strip_readers`. Each JPEG or WebP decode holds the whole
+/// image in memory, which for a 21600x21600 Blue Marble image is about 1.4 GB.
+const MAX_PARALLEL_DECODES: usize = 2;
+
+/// Sequential reader that produces an image one slab of RGB8 rows at a time.
+pub(crate) trait StripReader: Send {
+    fn dimensions(&self) -> (u32, u32);
+    /// Fill each of `rows` with the next row of the image.
+    fn read_rows(&mut self, rows: &mut [&mut [u8]]) -> Result<(), Error>;
+}
+
+/// Streams rows out of a PNG, or out of the raw file that an image in another format was decoded
+/// to, without ever holding the full image in memory.
+struct StreamingStripReader {
+    dimensions: (u32, u32),
+    reader: Box<dyn Read + Send>,
+}
+impl StripReader for StreamingStripReader {
+    fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+    fn read_rows(&mut self, rows: &mut [&mut [u8]]) -> Result<(), Error> {
+        for row in rows {
+            self.reader.read_exact(row)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the dimensions of a decoded image followed by its RGB8 pixels, in the layout that
+/// `open_raw` reads back.
+#[cfg(any(feature = "jpeg", feature = "webp"))]
+fn write_raw(out: &mut dyn Write, (width, height): (u32, u32), pixels: &[u8]) -> Result<(), Error> {
+    out.write_u32::<LittleEndian>(width)?;
+    out.write_u32::<LittleEndian>(height)?;
+    out.write_all(pixels)?;
+    Ok(())
+}
+
+/// Open a raw file written by `open_decoded`, or return None if it is missing or doesn't hold as
+/// many pixels as its dimensions say.
+#[cfg(any(feature = "jpeg", feature = "webp"))]
+fn open_raw(filename: &Path) -> Result<Option<StreamingStripReader>, Error> {
+    let mut file = match File::open(filename) {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+    let len = file.metadata()?.len();
+    if len < 8 {
+        return Ok(None);
+    }
+    let width = file.read_u32::<LittleEndian>()?;
+    let height = file.read_u32::<LittleEndian>()?;
+    if len != 8 + width as u64 * height as u64 * 3 {
+        return Ok(None);
+    }
+    Ok(Some(StreamingStripReader {
+        dimensions: (width, height),
+        reader: Box::new(BufReader::new(file)),
+    }))
+}
+
+/// JPEG and WebP can't be decoded a slab at a time, so the first time one of them is opened it is
+/// decoded in full and written out as raw RGB8 rows, which are then streamed like a PNG. While
+/// that happens the compressed and decoded image are both in memory. The raw file is named after
+/// the size and modification time of the source, so later runs reuse it until the source is
+/// replaced, at which point raw files of earlier versions are removed.
+#[cfg(any(feature = "jpeg", feature = "webp"))]
+fn open_decoded(
+    path: &Path,
+    decode: fn(&[u8], &mut dyn Write) -> Result<(), Error>,
+) -> Result<StreamingStripReader, Error> {
+    let source = path.file_name().unwrap().to_string_lossy();
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+    let directory = TERRA_DIRECTORY.join("imagery");
+    let filename = directory.join(format!("{}.{}-{}.rgb", source, metadata.len(), modified));
+    if let Some(reader) = open_raw(&filename)? {
+        return Ok(reader);
+    }
+
+    std::fs::create_dir_all(&directory)?;
+    for entry in std::fs::read_dir(&directory)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&format!("{}.", source)) && name.ends_with(".rgb") {
+            std::fs::remove_file(directory.join(name))?;
+        }
+    }
+
+    let data = std::fs::read(path)?;
+    let temporary = filename.with_extension("rgb.tmp");
+    let mut out = BufWriter::new(File::create(&temporary)?);
+    decode(&data, &mut out).map_err(|e| format_err!("{}: {}", path.display(), e))?;
+    out.flush()?;
+    drop(out);
+    std::fs::rename(&temporary, &filename)?;
+
+    open_raw(&filename)?
+        .ok_or_else(|| format_err!("{}: decoded image has the wrong size", path.display()))
+}
+
+#[cfg(feature = "jpeg")]
+fn decode_jpeg(data: &[u8], out: &mut dyn Write) -> Result<(), Error> {
+    // Blue Marble images are wider and taller than zune-jpeg allows by default.
+    let options = zune_jpeg::zune_core::options::DecoderOptions::default()
+        .set_max_width(1 << 16)
+        .set_max_height(1 << 16);
+    let mut decoder = zune_jpeg::JpegDecoder::new_with_options(data, options);
+    let pixels = decoder.decode().map_err(|e| format_err!("{:?}", e))?;
+    let (width, height) = decoder.dimensions().ok_or_else(|| format_err!("missing dimensions"))?;
+    write_raw(out, (width as u32, height as u32), &pixels)
+}
+
+#[cfg(feature = "webp")]
+fn decode_webp(data: &[u8], out: &mut dyn Write) -> Result<(), Error> {
+    let image =
+        webp::Decoder::new(data).decode().ok_or_else(|| format_err!("not a still WebP image"))?;
+    let dimensions = (image.width(), image.height());
+    if image.is_alpha() {
+        let pixels: Vec<u8> = image.chunks_exact(4).flat_map(|p| &p[..3]).copied().collect();
+        write_raw(out, dimensions, &pixels)
+    } else {
+        write_raw(out, dimensions, &image)
+    }
+}
+
+/// Extensions that `open_strip_reader` can handle, in order of preference.
+fn supported_extensions() -> Vec<&'static str> {
+    let mut extensions = vec!["png"];
+    if cfg!(feature = "jpeg") {
+        extensions.push("jpg");
+    }
+    if cfg!(feature = "webp") {
+        extensions.push("webp");
+    }
+    extensions
+}
+
+/// Open a reader for each of `stems` within `directory`, decoding up to `MAX_PARALLEL_DECODES`
+/// images at once.
+pub(crate) fn open_strip_readers(
+    directory: &Path,
+    stems: &[String],
+) -> Result<Vec<Box<dyn StripReader>>, Error> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(MAX_PARALLEL_DECODES).build()?;
+    pool.install(|| stems.par_iter().map(|stem| open_strip_reader(directory, stem)).collect())
+}
+
+/// Open `directory/stem.<ext>` with whichever supported extension is present.
+fn open_strip_reader(directory: &Path, stem: &str) -> Result<Box<dyn StripReader>, Error> {
+    let path: PathBuf = supported_extensions()
+        .into_iter()
+        .map(|ext| directory.join(format!("{}.{}", stem, ext)))
+        .find(|p| p.exists())
+        .ok_or_else(|| format_err!("No supported image found for {}", stem))?;
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("png") => {
+            let decoder = PngDecoder::new(BufReader::new(File::open(&path)?))?;
+            if decoder.color_type() != ColorType::Rgb8 {
+                return Err(format_err!("{}: expected RGB8 image", path.display()));
+            }
+            Ok(Box::new(StreamingStripReader {
+                dimensions: decoder.dimensions(),
+                reader: Box::new(decoder.into_reader()?),
+            }))
+        }
+        #[cfg(feature = "jpeg")]
+        Some("jpg") => Ok(Box::new(open_decoded(&path, decode_jpeg)?)),
+        #[cfg(feature = "webp")]
+        Some("webp") => Ok(Box::new(open_decoded(&path, decode_webp)?)),
+        _ => unreachable!(),
+    }
+}
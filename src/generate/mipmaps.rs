@@ -0,0 +1,160 @@
+use crate::cache::{LayerParams, LayerType};
+use crate::gpu_state::GpuState;
+use std::{borrow::Cow, collections::HashMap, num::NonZeroU32};
+
+/// Fills in the mip chain of a single tile from its top level.
+pub(crate) struct MipmapGen {
+    shader: rshader::ShaderSet,
+    pipeline: Option<wgpu::ComputePipeline>,
+    layer: LayerParams,
+}
+impl MipmapGen {
+    pub fn new(layer: LayerParams) -> Self {
+        let shader = if layer.texture_format.is_compressed() {
+            rshader::shader_source!("../shaders", "gen-mipmaps.comp", "declarations.glsl"; "BC5" = "1")
+        } else {
+            rshader::shader_source!("../shaders", "gen-mipmaps.comp", "declarations.glsl"; "BC5" = "0")
+        };
+        Self { shader: rshader::ShaderSet::compute_only(shader).unwrap(), pipeline: None, layer }
+    }
+
+    pub fn refresh(&mut self) {
+        if self.shader.refresh() {
+            self.pipeline = None;
+        }
+    }
+
+    pub fn generate(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        state: &GpuState,
+        slot: usize,
+    ) {
+        let ty = self.layer.layer_type;
+        let texture = &state.tile_cache[ty];
+        let compressed = self.layer.texture_format.is_compressed();
+        assert!(!compressed || ty == LayerType::Normals);
+
+        for level in 1..self.layer.mip_level_count() {
+            let resolution = self.layer.texture_resolution >> level;
+
+            let mut image_views: HashMap<Cow<str>, _> = HashMap::new();
+            image_views.insert(
+                "mip_in".into(),
+                texture.create_view(&wgpu::TextureViewDescriptor {
+                    label: Some(&format!("view.{}[{}].mip{}", self.layer.name, slot, level - 1)),
+                    dimension: Some(wgpu::TextureViewDimension::D2),
+                    base_mip_level: level - 1,
+                    mip_level_count: Some(NonZeroU32::new(1).unwrap()),
+                    base_array_layer: slot as u32,
+                    array_layer_count: Some(NonZeroU32::new(1).unwrap()),
+                    ..Default::default()
+                }),
+            );
+            if !compressed {
+                image_views.insert(
+                    "mip_out".into(),
+                    texture.create_view(&wgpu::TextureViewDescriptor {
+                        label: Some(&format!("view.{}[{}].mip{}", self.layer.name, slot, level)),
+                        dimension: Some(wgpu::TextureViewDimension::D2),
+                        base_mip_level: level,
+                        mip_level_count: Some(NonZeroU32::new(1).unwrap()),
+                        base_array_layer: slot as u32,
+                        array_layer_count: Some(NonZeroU32::new(1).unwrap()),
+                        ..Default::default()
+                    }),
+                );
+            }
+
+            let (bind_group, bind_group_layout) = state.bind_group_for_shader(
+                device,
+                &self.shader,
+                HashMap::new(),
+                image_views,
+                &format!("generate.mipmaps.{}", self.layer.name),
+            );
+
+            if self.pipeline.is_none() {
+                self.pipeline =
+                    Some(device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                        layout: Some(&device.create_pipeline_layout(
+                            &wgpu::PipelineLayoutDescriptor {
+                                bind_group_layouts: [&*bind_group_layout][..].into(),
+                                push_constant_ranges: &[],
+                                label: None,
+                            },
+                        )),
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some(&format!("shader.generate.mipmaps.{}", self.layer.name)),
+                            source: wgpu::ShaderSource::SpirV(self.shader.compute().into()),
+                            flags: wgpu::ShaderFlags::empty(),
+                        }),
+                        entry_point: "main",
+                        label: Some(&format!("pipeline.generate.mipmaps.{}", self.layer.name)),
+                    }));
+            }
+
+            {
+                let mut cpass =
+                    encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+                cpass.set_pipeline(&self.pipeline.as_ref().unwrap());
+                cpass.set_bind_group(0, &bind_group, &[]);
+                cpass.dispatch((resolution + 3) / 4, (resolution + 3) / 4, 1);
+            }
+
+            if compressed {
+                let resolution_blocks = (resolution + 3) / 4;
+                let row_pitch = (resolution_blocks * 16 + 255) & !255;
+                let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    size: row_pitch as u64 * resolution_blocks as u64,
+                    usage: wgpu::BufferUsage::COPY_SRC | wgpu::BufferUsage::COPY_DST,
+                    mapped_at_creation: false,
+                    label: Some("buffer.blit.bc5.mipmaps"),
+                });
+                encoder.copy_texture_to_buffer(
+                    wgpu::ImageCopyTexture {
+                        texture: &state.bc5_staging,
+                        mip_level: 0,
+                        origin: wgpu::Origin3d::default(),
+                    },
+                    wgpu::ImageCopyBuffer {
+                        buffer: &buffer,
+                        layout: wgpu::ImageDataLayout {
+                            bytes_per_row: Some(NonZeroU32::new(row_pitch).unwrap()),
+                            rows_per_image: None,
+                            offset: 0,
+                        },
+                    },
+                    wgpu::Extent3d {
+                        width: resolution_blocks,
+                        height: resolution_blocks,
+                        depth_or_array_layers: 1,
+                    },
+                );
+                // Partial blocks are copied in full, since copies of compressed textures must
+                // cover the physical size of the mip level.
+                encoder.copy_buffer_to_texture(
+                    wgpu::ImageCopyBuffer {
+                        buffer: &buffer,
+                        layout: wgpu::ImageDataLayout {
+                            bytes_per_row: Some(NonZeroU32::new(row_pitch).unwrap()),
+                            rows_per_image: None,
+                            offset: 0,
+                        },
+                    },
+                    wgpu::ImageCopyTexture {
+                        texture,
+                        mip_level: level,
+                        origin: wgpu::Origin3d { x: 0, y: 0, z: slot as u32 },
+                    },
+                    wgpu::Extent3d {
+                        width: resolution_blocks * 4,
+                        height: resolution_blocks * 4,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+    }
+}
@@ -1,39 +1,60 @@
+#[cfg(feature = "render")]
+use crate::asset::{AssetLoadContext, AssetLoadContextBuf, WebAsset};
 use crate::cache::{LayerParams, LayerType, TextureFormat};
-use crate::gpu_state::GpuState;
-use crate::mapfile::{MapFile, TextureDescriptor};
-use crate::srgb::SRGB_TO_LINEAR;
+use crate::coordinates;
+use crate::date::Date;
+use crate::mapfile::{AlbedoEpoch, MapFile, TileStore};
+use crate::places;
+use crate::planet::PlanetConfig;
 use crate::terrain::dem::DemSource;
 use crate::terrain::quadtree::VNode;
-use crate::terrain::raster::GlobalRaster;
-use crate::terrain::raster::RasterCache;
+use crate::terrain::raster::{BandEncoding, GlobalRaster};
+use crate::terrain::raster::{MMappedRasterSource, RasterCache, RasterCacheStats};
+#[cfg(feature = "render")]
 use crate::{
-    asset::{AssetLoadContext, AssetLoadContextBuf, WebAsset},
-    cache::LayerMask,
+    cache::{LayerDesc, LayerMask},
+    gpu_state::GpuState,
+    mapfile::TextureDescriptor,
 };
-use crate::{coordinates, Terrain};
 use anyhow::Error;
+#[cfg(feature = "render")]
 use bytemuck::Pod;
+#[cfg(feature = "render")]
 use cgmath::Vector2;
 use futures::StreamExt;
-use image::{png::PngDecoder, ColorType, ImageDecoder};
 use itertools::Itertools;
+use log::info;
 use maplit::hashmap;
 use rayon::prelude::*;
+#[cfg(feature = "render")]
+use std::{borrow::Cow, collections::HashMap, mem, num::NonZeroU32};
+use std::{f64::consts::PI, path::PathBuf};
 use std::{
-    borrow::Cow, collections::HashMap, f64::consts::PI, fs::File, mem, num::NonZeroU32,
-    path::PathBuf,
-};
-use std::{
-    io::{Read, Write},
+    io::Write,
     path::Path,
     sync::{Arc, Mutex},
 };
 use vec_map::VecMap;
 
+mod detail;
+#[cfg(feature = "render")]
 mod gpu;
 pub mod heightmap;
+mod imagery;
+#[cfg(feature = "render")]
+mod mipmaps;
+mod passes;
 
+pub use detail::{FractalDetail, FractalDetailTable};
+#[cfg(feature = "render")]
 pub(crate) use gpu::*;
+#[cfg(feature = "render")]
+pub(crate) use mipmaps::MipmapGen;
+pub(crate) use passes::HeightmapPass;
+pub use passes::{TileData, TileNode};
+
+/// Log target for tile generation. See the crate documentation.
+pub(crate) const LOG_TARGET: &str = "terra::gen";
 
 /// The radius of the earth in meters.
 pub(crate) const EARTH_RADIUS: f64 = 6371000.0;
@@ -50,6 +71,7 @@ pub const BLUE_MARBLE_URLS: [&str; 8] = [
     "https://eoimages.gsfc.nasa.gov/images/imagerecords/76000/76487/world.200406.3x21600x21600.D2.png",
 ];
 
+#[cfg(feature = "render")]
 pub(crate) trait GenerateTile: Send {
     /// Layers generated by this object. Zero means generate cannot operate for nodes of this level.
     fn outputs(&self, level: u8) -> LayerMask;
@@ -73,6 +95,7 @@ pub(crate) trait GenerateTile: Send {
     );
 }
 
+#[cfg(feature = "render")]
 struct ShaderGen<T, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T> {
     shader: rshader::ShaderSet,
     shader_validation: bool,
@@ -89,6 +112,7 @@ struct ShaderGen<T, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMas
     name: String,
     f: F,
 }
+#[cfg(feature = "render")]
 impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T> GenerateTile
     for ShaderGen<T, F>
 {
@@ -149,9 +173,9 @@ impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T
         if let Some(parent_slot) = parent_slot {
             for layer in layers.values() {
                 image_views.insert(
-                    format!("{}_in", layer.layer_type.name()).into(),
+                    format!("{}_in", layer.name).into(),
                     state.tile_cache[layer.layer_type].create_view(&wgpu::TextureViewDescriptor {
-                        label: Some(&format!("view.{}[{}]", layer.layer_type.name(), parent_slot)),
+                        label: Some(&format!("view.{}[{}]", layer.name, parent_slot)),
                         base_array_layer: parent_slot as u32,
                         array_layer_count: Some(NonZeroU32::new(1).unwrap()),
                         ..Default::default()
@@ -164,9 +188,11 @@ impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T
             layers.values().filter(|l| self.outputs(node.level()).contains_layer(l.layer_type))
         {
             image_views.insert(
-                format!("{}_out", layer.layer_type.name()).into(),
+                format!("{}_out", layer.name).into(),
                 state.tile_cache[layer.layer_type].create_view(&wgpu::TextureViewDescriptor {
-                    label: Some(&format!("view.{}[{}]", layer.layer_type.name(), slot)),
+                    label: Some(&format!("view.{}[{}]", layer.name, slot)),
+                    base_mip_level: 0,
+                    mip_level_count: Some(NonZeroU32::new(1).unwrap()),
                     base_array_layer: slot as u32,
                     array_layer_count: Some(NonZeroU32::new(1).unwrap()),
                     ..Default::default()
@@ -190,7 +216,7 @@ impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T
             self.pipeline =
                 Some(device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
                     layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                        bind_group_layouts: [&bind_group_layout][..].into(),
+                        bind_group_layouts: [&*bind_group_layout][..].into(),
                         push_constant_ranges: &[],
                         label: None,
                     })),
@@ -216,7 +242,9 @@ impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T
             cpass.dispatch(self.dimensions, self.dimensions, 1);
         }
 
-        if let Some(layer) = self.blit_from_bc5_staging {
+        if let Some(layer) =
+            self.blit_from_bc5_staging.filter(|&l| layers[l].texture_format.is_compressed())
+        {
             let resolution = layers[layer].texture_resolution;
             let resolution_blocks = (resolution + 3) / 4;
             let row_pitch = (resolution_blocks * 16 + 255) & !255;
@@ -267,6 +295,7 @@ impl<T: Pod, F: 'static + Send + Fn(VNode, usize, Option<usize>, LayerMask) -> T
     }
 }
 
+#[cfg(feature = "render")]
 struct ShaderGenBuilder {
     name: String,
     dimensions: u32,
@@ -279,6 +308,7 @@ struct ShaderGenBuilder {
     blit_from_bc5_staging: Option<LayerType>,
     shader_validation: bool,
 }
+#[cfg(feature = "render")]
 impl ShaderGenBuilder {
     fn new(name: String, shader: rshader::ShaderSource) -> Self {
         Self {
@@ -330,7 +360,7 @@ impl ShaderGenBuilder {
         self,
         f: F,
     ) -> Box<dyn GenerateTile> {
-        println!(">>>>>>>> {}", self.name);
+        log::debug!(target: LOG_TARGET, "building generator '{}'", self.name);
         Box::new(ShaderGen {
             name: self.name,
             shader_validation: self.shader_validation,
@@ -354,24 +384,35 @@ impl ShaderGenBuilder {
     }
 }
 
+#[cfg(feature = "render")]
 pub(crate) fn generators(
     layers: &VecMap<LayerParams>,
     soft_float64: bool,
+    fractal_detail: FractalDetailTable,
+    planet: PlanetConfig,
+    guided_upsampling: bool,
 ) -> Vec<Box<dyn GenerateTile>> {
     let heightmaps_resolution = layers[LayerType::Heightmaps].texture_resolution;
     let heightmaps_border = layers[LayerType::Heightmaps].texture_border_size;
     let displacements_resolution = layers[LayerType::Displacements].texture_resolution;
     let normals_resolution = layers[LayerType::Normals].texture_resolution;
     let normals_border = layers[LayerType::Normals].texture_border_size;
+    let compressed_normals = layers[LayerType::Normals].texture_format.is_compressed();
 
-    vec![
+    let mut generators: Vec<Box<dyn GenerateTile>> = vec![
         ShaderGenBuilder::new(
             "heightmaps".into(),
-            rshader::shader_source!("../shaders", "gen-heightmaps.comp", "declarations.glsl", "hash.glsl"),
+            rshader::shader_source!(
+                "../shaders",
+                "gen-heightmaps.comp",
+                "declarations.glsl",
+                "hash.glsl",
+                "erosion.glsl"
+            ),
         )
         .outputs(LayerType::Heightmaps.bit_mask())
         .dimensions((heightmaps_resolution + 7) / 8)
-        .parent_inputs(LayerType::Heightmaps.bit_mask())
+        .parent_inputs(LayerType::Heightmaps.bit_mask() | LayerType::Albedo.bit_mask())
         .no_validate() // validation doesn't support barrier() yet.
         .build(
             move |node: VNode,
@@ -404,6 +445,8 @@ pub(crate) fn generators(
                     out_slot: slot as i32,
                     level_resolution: level_resolution as i32,
                     face: node.face() as u32,
+                    padding: [0; 3],
+                    fractal_detail: fractal_detail.uniform(),
                 }
             },
         ),
@@ -462,12 +505,19 @@ pub(crate) fn generators(
                     face: node.face() as i32,
                     level_resolution,
                     padding0: 0.0,
+                    min_elevation: planet.drawn_range().0,
+                    max_elevation: planet.drawn_range().1,
+                    padding1: 0.0,
                 }
             },
         ),
         ShaderGenBuilder::new(
             "root-normals".into(),
-            rshader::shader_source!("../shaders", "gen-root-normals.comp", "declarations.glsl", "hash.glsl"),
+            if compressed_normals {
+                rshader::shader_source!("../shaders", "gen-root-normals.comp", "declarations.glsl", "hash.glsl"; "BC5" = "1")
+            } else {
+                rshader::shader_source!("../shaders", "gen-root-normals.comp", "declarations.glsl", "hash.glsl"; "BC5" = "0")
+            },
         )
         .root_outputs(LayerType::Normals.bit_mask())
         .dimensions((normals_resolution + 3) / 4)
@@ -491,7 +541,11 @@ pub(crate) fn generators(
         }),
         ShaderGenBuilder::new(
             "materials".into(),
-            rshader::shader_source!("../shaders", "gen-materials.comp", "declarations.glsl", "hash.glsl"),
+            if compressed_normals {
+                rshader::shader_source!("../shaders", "gen-materials.comp", "declarations.glsl", "hash.glsl"; "BC5" = "1")
+            } else {
+                rshader::shader_source!("../shaders", "gen-materials.comp", "declarations.glsl", "hash.glsl"; "BC5" = "0")
+            },
         )
         .outputs(LayerType::Normals.bit_mask() | LayerType::Albedo.bit_mask())
         .dimensions((normals_resolution + 3) / 4)
@@ -535,59 +589,119 @@ pub(crate) fn generators(
                             (normals_resolution - normals_border) / 2
                         },
                     ],
-                    padding: 0,
+                    guided_upsampling: guided_upsampling as u32,
                 }
             },
         ),
-    ]
+    ];
+
+    // Layers declared by the application each get a generator built from their own shader.
+    for layer in layers.values() {
+        let generator = match layer.generator {
+            Some(ref generator) => generator,
+            None => continue,
+        };
+        let mask = |names: &[String]| {
+            names.iter().fold(LayerMask::empty(), |mask, name| {
+                mask | layers.values().find(|l| l.name == *name).unwrap().layer_type.bit_mask()
+            })
+        };
+
+        generators.push(
+            ShaderGenBuilder::new(layer.name.clone(), generator.shader.clone())
+                .outputs(layer.layer_type.bit_mask())
+                .root_outputs(layer.layer_type.bit_mask())
+                .dimensions(generator.dimensions)
+                .peer_inputs(mask(&generator.peer_inputs))
+                .parent_inputs(mask(&generator.parent_inputs))
+                .build(
+                    |node: VNode,
+                     slot: usize,
+                     parent_slot: Option<usize>,
+                     _: LayerMask|
+                     -> GenCustomLayerUniforms {
+                        GenCustomLayerUniforms {
+                            position: [node.x(), node.y()],
+                            slot: slot as i32,
+                            parent_slot: parent_slot.map(|s| s as i32).unwrap_or(-1),
+                            level: node.level() as u32,
+                            face: node.face() as u32,
+                            parent_index: node.parent().map(|p| p.1 as u32).unwrap_or(0),
+                            padding: 0,
+                        }
+                    },
+                ),
+        );
+    }
+    generators
 }
 
 pub(crate) struct MapFileBuilder(MapFile);
 impl MapFileBuilder {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(store: TileStore) -> Self {
         let layers: VecMap<LayerParams> = hashmap![
             LayerType::Heightmaps.index() => LayerParams {
                     layer_type: LayerType::Heightmaps,
+                    name: "heightmaps".into(),
                     texture_resolution: 521,
                     texture_border_size: 4,
                     texture_format: TextureFormat::R32F,
                     tiles_generated_per_frame: 16,
+                    mipmaps: false,
+                    #[cfg(feature = "render")]
+                    generator: None,
                     // peer_dependency_mask: 0,
                     // parent_dependency_mask: LayerType::Heightmaps.bit_mask(),
                 },
             LayerType::Displacements.index() => LayerParams {
                     layer_type: LayerType::Displacements,
+                    name: "displacements".into(),
                     texture_resolution: 65,
                     texture_border_size: 0,
                     texture_format: TextureFormat::RGBA32F,
                     tiles_generated_per_frame: 128,
+                    mipmaps: false,
+                    #[cfg(feature = "render")]
+                    generator: None,
                     // peer_dependency_mask: 0,
                     // parent_dependency_mask: LayerType::Heightmaps.bit_mask(),
                 },
             LayerType::Albedo.index() => LayerParams {
                     layer_type: LayerType::Albedo,
+                    name: "albedo".into(),
                     texture_resolution: 516,
                     texture_border_size: 2,
                     texture_format: TextureFormat::RGBA8,
                     tiles_generated_per_frame: 16,
+                    mipmaps: true,
+                    #[cfg(feature = "render")]
+                    generator: None,
                     // peer_dependency_mask: 0,
                     // parent_dependency_mask: LayerType::Albedo.bit_mask(),
                 },
             LayerType::Roughness.index() => LayerParams {
                     layer_type: LayerType::Roughness,
+                    name: "roughness".into(),
                     texture_resolution: 516,
                     texture_border_size: 2,
                     texture_format: TextureFormat::BC4,
                     tiles_generated_per_frame: 16,
+                    mipmaps: false,
+                    #[cfg(feature = "render")]
+                    generator: None,
                     // peer_dependency_mask: 0,
                     // parent_dependency_mask: LayerType::Roughness.bit_mask(),
                 },
             LayerType::Normals.index() => LayerParams {
                     layer_type: LayerType::Normals,
+                    name: "normals".into(),
                     texture_resolution: 516,
                     texture_border_size: 2,
                     texture_format: TextureFormat::BC5,
                     tiles_generated_per_frame: 16,
+                    mipmaps: true,
+                    #[cfg(feature = "render")]
+                    generator: None,
                     // peer_dependency_mask: LayerType::Heightmaps.bit_mask(),
                     // parent_dependency_mask: LayerType::Albedo.bit_mask(),
                 },
@@ -595,7 +709,7 @@ impl MapFileBuilder {
         .into_iter()
         .collect();
 
-        let mapfile = MapFile::new(layers);
+        let mapfile = MapFile::new(layers, store);
         VNode::breadth_first(|n| {
             mapfile.reload_tile_state(LayerType::Heightmaps, n, true).unwrap();
             n.level() < VNode::LEVEL_CELL_153M
@@ -612,6 +726,37 @@ impl MapFileBuilder {
         Self(mapfile)
     }
 
+    /// Register custom layers after the built in ones, in the order given.
+    #[cfg(feature = "render")]
+    pub(crate) fn with_layers(mut self, layers: Vec<LayerDesc>) -> Result<Self, Error> {
+        for layer in layers {
+            self.0.register_layer(layer)?;
+        }
+        Ok(self)
+    }
+
+    /// Read tiles missing from the tile store out of the zip archive at `path`, if any. See
+    /// `MapFile::open_archive`.
+    pub(crate) fn with_archive(mut self, path: Option<&Path>) -> Result<Self, Error> {
+        if let Some(path) = path {
+            self.0.open_archive(path)?;
+        }
+        Ok(self)
+    }
+
+    /// Describe the body that the map file holds. See `PlanetConfig`.
+    pub(crate) fn with_planet(mut self, planet: PlanetConfig) -> Result<Self, Error> {
+        planet.validate()?;
+        self.0.set_planet(planet);
+        Ok(self)
+    }
+
+    /// Return the map file without generating any of the assets that are only needed for
+    /// rendering.
+    pub(crate) fn build_headless(self) -> MapFile {
+        self.0
+    }
+
     /// Actually construct the `QuadTree`.
     ///
     /// This function will (the first time it is called) download many gigabytes of raw data,
@@ -623,6 +768,7 @@ impl MapFileBuilder {
     /// of CPU resources. You can expect it to run at full load continiously for several full
     /// minutes, even in release builds (you *really* don't want to wait for generation in debug
     /// mode...).
+    #[cfg(feature = "render")]
     pub(crate) async fn build(mut self) -> Result<MapFile, Error> {
         let mut context = AssetLoadContextBuf::new();
         let mut context = context.context("Building Terrain...", 1);
@@ -636,19 +782,49 @@ impl MapFileBuilder {
     }
 }
 
-impl Terrain {
-    /// Generate heightmap tiles.
-    ///
-    /// `etopo1_file` is the location of [ETOPO1_Ice_c_geotiff.zip](https://www.ngdc.noaa.gov/mgg/global/relief/ETOPO1/data/ice_surface/cell_registered/georeferenced_tiff/ETOPO1_Ice_c_geotiff.zip).
+/// Offline generation of base tiles from raw datasets. Only needs the map file, so it is shared by
+/// `Terrain` and `HeadlessTerrain`.
+pub(crate) struct TileGenerator {
+    pub mapfile: Arc<MapFile>,
+    /// Passes applied to each heightmap tile before it is written.
+    pub heightmap_passes: Vec<HeightmapPass>,
+    /// Number of samples taken along each axis of every albedo texel.
+    pub albedo_supersampling: u32,
+}
+impl TileGenerator {
     pub async fn generate_heightmaps<'a, F: FnMut(&str, usize, usize) + Send>(
         &mut self,
         etopo1_file: impl AsRef<Path>,
         srtm3_directory: PathBuf,
         mut progress_callback: F,
-    ) -> Result<(), Error> {
+    ) -> Result<RasterCacheStats, Error> {
         let (missing, total_tiles) = self.mapfile.get_missing_base(LayerType::Heightmaps)?;
         if missing.is_empty() {
-            return Ok(());
+            return Ok(RasterCacheStats::default());
+        }
+        info!(
+            target: LOG_TARGET,
+            "generating {} of {} heightmap tiles",
+            missing.len(),
+            total_tiles
+        );
+
+        // Tiles are encoded relative to their parents, so every tile has to be generated with the
+        // same passes.
+        let pass_names: Vec<String> =
+            self.heightmap_passes.iter().map(|p| p.name.clone()).collect();
+        if missing.len() < total_tiles {
+            let existing = self.mapfile.heightmap_passes()?;
+            if existing != pass_names {
+                anyhow::bail!(
+                    "heightmap tiles were already generated with passes {:?}, but {:?} are \
+                     registered. Delete the existing heightmap tiles to regenerate them.",
+                    existing,
+                    pass_names
+                );
+            }
+        } else {
+            self.mapfile.set_heightmap_passes(&pass_names)?;
         }
 
         let mut gen = heightmap::HeightmapGen {
@@ -656,11 +832,19 @@ impl Terrain {
                 self.mapfile.layers()[LayerType::Heightmaps].clone(),
                 32,
             ),
-            dems: RasterCache::new(Arc::new(DemSource::Srtm90m(srtm3_directory)), 256),
+            dems: RasterCache::new(
+                Arc::new(MMappedRasterSource::new(
+                    "srtm3",
+                    Arc::new(DemSource::Srtm90m(srtm3_directory)),
+                )),
+                256,
+                1024,
+            ),
             global_dem: Arc::new(crate::terrain::dem::parse_etopo1(
                 etopo1_file,
                 &mut progress_callback,
             )?),
+            passes: Arc::new(self.heightmap_passes.clone()),
         };
 
         let total_missing = missing.len();
@@ -700,63 +884,123 @@ impl Terrain {
             }
         }
 
-        Ok(())
+        info!(target: LOG_TARGET, "generated {} heightmap tiles", tiles_processed);
+        Ok(gen.dems.stats())
     }
 
-    /// Generate albedo tiles.
-    ///
-    /// `blue_marble_directory` must contain the 8 files from NASA's Blue Marble: Next Generation
-    /// indicated in [`BLUE_MARBLE_URLS`](constant.BLUE_MARBLE_URLS.html).
     pub async fn generate_albedos<F: FnMut(&str, usize, usize) + Send>(
         &mut self,
         blue_marble_directory: impl AsRef<Path>,
-        mut progress_callback: F,
+        progress_callback: F,
     ) -> Result<(), Error> {
         let (missing, total_tiles) = self.mapfile.get_missing_base(LayerType::Albedo)?;
+        let mapfile = &*self.mapfile;
+        self.generate_albedo_tiles(
+            blue_marble_directory.as_ref(),
+            "200406",
+            missing,
+            total_tiles,
+            progress_callback,
+            |n, data| mapfile.write_tile(LayerType::Albedo, n, data, true),
+        )
+    }
+
+    pub async fn generate_albedo_epoch<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        year: i16,
+        month: u8,
+        blue_marble_directory: impl AsRef<Path>,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        let epoch = AlbedoEpoch {
+            name: format!("{:04}{:02}", year, month),
+            julian_day: Date::new(year, month, 15.0).julian_day(),
+        };
+        let (missing, total_tiles) = self.mapfile.missing_albedo_epoch_tiles(&epoch)?;
+        let mapfile = &*self.mapfile;
+        self.generate_albedo_tiles(
+            blue_marble_directory.as_ref(),
+            &epoch.name,
+            missing,
+            total_tiles,
+            progress_callback,
+            |n, data| mapfile.write_albedo_epoch_tile(&epoch, n, data),
+        )?;
+        self.mapfile.add_albedo_epoch(epoch)
+    }
+
+    /// Generate the albedo tiles for `missing` from the Blue Marble images for `month`, passing
+    /// the encoded tiles to `write_tile`.
+    fn generate_albedo_tiles<F, W>(
+        &self,
+        blue_marble_directory: &Path,
+        month: &str,
+        missing: Vec<VNode>,
+        total_tiles: usize,
+        mut progress_callback: F,
+        write_tile: W,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&str, usize, usize) + Send,
+        W: Fn(VNode, &[u8]) -> Result<(), Error> + Sync,
+    {
         if missing.is_empty() {
             return Ok(());
         }
+        info!(
+            target: LOG_TARGET,
+            "generating {} of {} albedo tiles for {}",
+            missing.len(),
+            total_tiles,
+            month
+        );
 
         let layer = self.mapfile.layers()[LayerType::Albedo].clone();
         assert!(layer.texture_border_size >= 2);
+        let supersampling = self.albedo_supersampling.max(1);
+        let samples = (supersampling * supersampling) as f64;
 
         let bm_dimensions = 21600;
         let mut values = vec![0u8; bm_dimensions * bm_dimensions * 8 * 3];
 
         let (north, south) = values.split_at_mut(bm_dimensions * bm_dimensions * 12);
-        let mut slices: Vec<&mut [u8]> = north
+        let slices = north
             .chunks_exact_mut(bm_dimensions * 3)
-            .interleave(south.chunks_exact_mut(bm_dimensions * 3))
-            .collect();
+            .interleave(south.chunks_exact_mut(bm_dimensions * 3));
 
-        let mut decoders = Vec::new();
+        let mut stems = Vec::new();
         for x in 0..4 {
             for y in 0..2 {
-                let decoder =
-                    PngDecoder::new(File::open(blue_marble_directory.as_ref().join(format!(
-                        "world.200406.3x21600x21600.{}{}.png",
-                        "ABCD".chars().nth(x).unwrap(),
-                        "12".chars().nth(y).unwrap()
-                    )))?)?;
-                assert_eq!(decoder.dimensions(), (bm_dimensions as u32, bm_dimensions as u32));
-                assert_eq!(decoder.color_type(), ColorType::Rgb8);
-                decoders.push(decoder.into_reader()?);
+                stems.push(format!(
+                    "world.{}.3x21600x21600.{}{}",
+                    month,
+                    "ABCD".chars().nth(x).unwrap(),
+                    "12".chars().nth(y).unwrap()
+                ));
             }
         }
+        let mut readers = Vec::new();
+        for reader in imagery::open_strip_readers(blue_marble_directory, &stems)? {
+            assert_eq!(reader.dimensions(), (bm_dimensions as u32, bm_dimensions as u32));
+            readers.push((reader, Vec::new()));
+        }
 
-        let total = slices.len() / 8;
-        for (i, chunk) in slices.chunks_mut(8).enumerate() {
-            if i % 108 == 0 {
-                progress_callback("Loading blue marble images... ", i / 108, total / 108);
-            }
+        // Distribute the destination rows to the image each one comes from.
+        for (i, slice) in slices.enumerate() {
+            readers[i % 8].1.push(slice);
+        }
 
-            decoders.par_iter_mut().zip(chunk).try_for_each(|(d, s)| d.read_exact(s))?;
+        let total = bm_dimensions / imagery::SLAB_ROWS;
+        for i in 0..total {
+            progress_callback("Loading blue marble images... ", i, total);
+            readers.par_iter_mut().try_for_each(|(reader, rows)| {
+                reader.read_rows(&mut rows[i * imagery::SLAB_ROWS..][..imagery::SLAB_ROWS])
+            })?;
         }
 
         let bluemarble =
             GlobalRaster { width: bm_dimensions * 4, height: bm_dimensions * 2, bands: 3, values };
 
-        let mapfile = &self.mapfile;
         let progress = &Mutex::new((total_tiles - missing.len(), progress_callback));
 
         missing.into_par_iter().try_for_each(|n| -> Result<(), Error> {
@@ -767,32 +1011,38 @@ impl Terrain {
                 progress.0 += 1;
             }
 
-            let mut colormap = Vec::with_capacity(
-                layer.texture_resolution as usize * layer.texture_resolution as usize,
-            );
-
-            let coordinates: Vec<_> = (0..(layer.texture_resolution * layer.texture_resolution))
+            // Average a grid of samples within each texel, which subdivides the texel grid with
+            // `supersampling` times the resolution and border.
+            let encodings = [BandEncoding::Srgb { max: 255.0 }; 3];
+            let colormap: Vec<[u8; 4]> = (0..(layer.texture_resolution * layer.texture_resolution))
                 .into_par_iter()
                 .map(|i| {
-                    let cspace = n.cell_position_cspace(
-                        (i % layer.texture_resolution) as i32,
-                        (i / layer.texture_resolution) as i32,
-                        layer.texture_border_size as u16,
-                        layer.texture_resolution as u16,
-                    );
-                    let polar = coordinates::cspace_to_polar(cspace);
-                    (polar.x.to_degrees(), polar.y.to_degrees())
+                    let (x, y) = (i % layer.texture_resolution, i / layer.texture_resolution);
+                    let mut sum = [0.0; 3];
+                    let mut rgb = [0.0; 3];
+                    for j in 0..(supersampling * supersampling) {
+                        let cspace = n.cell_position_cspace(
+                            (x * supersampling + j % supersampling) as i32,
+                            (y * supersampling + j / supersampling) as i32,
+                            (layer.texture_border_size * supersampling) as u16,
+                            (layer.texture_resolution * supersampling) as u16,
+                        );
+                        let polar = coordinates::cspace_to_polar(cspace);
+                        bluemarble.interpolate_bands(
+                            polar.x.to_degrees(),
+                            polar.y.to_degrees(),
+                            &encodings,
+                            &mut rgb,
+                        );
+                        for (s, v) in sum.iter_mut().zip(&rgb) {
+                            *s += v;
+                        }
+                    }
+                    let encode = |v: f64| (v * 255.0 / samples).round() as u8;
+                    [encode(sum[0]), encode(sum[1]), encode(sum[2]), 255]
                 })
                 .collect();
-
-            for (lat, long) in coordinates {
-                colormap.extend_from_slice(&[
-                    SRGB_TO_LINEAR[bluemarble.interpolate(lat, long, 0) as u8],
-                    SRGB_TO_LINEAR[bluemarble.interpolate(lat, long, 1) as u8],
-                    SRGB_TO_LINEAR[bluemarble.interpolate(lat, long, 2) as u8],
-                    255,
-                ]);
-            }
+            let colormap = colormap.concat();
 
             let mut data = Vec::new();
             let encoder = image::codecs::png::PngEncoder::new(&mut data);
@@ -802,7 +1052,7 @@ impl Terrain {
                 layer.texture_resolution as u32,
                 image::ColorType::Rgba8,
             )?;
-            mapfile.write_tile(LayerType::Albedo, n, &data, true)
+            write_tile(n, &data)
         })
     }
 
@@ -814,6 +1064,12 @@ impl Terrain {
         if missing.is_empty() {
             return Ok(());
         }
+        info!(
+            target: LOG_TARGET,
+            "generating {} of {} roughness tiles",
+            missing.len(),
+            total_tiles
+        );
 
         let layer = self.mapfile.layers()[LayerType::Roughness].clone();
         assert!(layer.texture_border_size >= 2);
@@ -844,8 +1100,28 @@ impl Terrain {
 
         Ok(())
     }
+
+    pub fn generate_place_names<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        geonames_file: impl AsRef<Path>,
+        mut progress_callback: F,
+    ) -> Result<(), Error> {
+        progress_callback("Reading place names... ", 0, 1);
+        let pages = places::page_places(places::read_geonames(geonames_file.as_ref())?);
+
+        let total = pages.len();
+        info!(target: LOG_TARGET, "writing {} place tiles", total);
+        for (i, (node, places)) in pages.into_iter().enumerate() {
+            progress_callback("Writing place names... ", i, total);
+            let data = bincode::serialize(&places)?;
+            self.mapfile.write_tile(LayerType::Places, node, &data, true)?;
+        }
+
+        Ok(())
+    }
 }
 
+#[cfg(feature = "render")]
 fn generate_noise(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result<(), Error> {
     if !mapfile.reload_texture("noise") {
         // wavelength = 1.0 / 256.0;
@@ -878,6 +1154,7 @@ fn generate_noise(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Resu
     Ok(())
 }
 
+#[cfg(feature = "render")]
 fn generate_sky(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result<(), Error> {
     if !mapfile.reload_texture("sky") {
         context.reset("Generating sky texture... ", 1);
@@ -888,8 +1165,27 @@ fn generate_sky(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result
         .load(context)?;
         mapfile.write_texture("sky", sky.0, &sky.1)?;
     }
-    if !mapfile.reload_texture("transmittance") || !mapfile.reload_texture("inscattering") {
-        let atmosphere = crate::sky::Atmosphere::new(context)?;
+    Ok(())
+}
+
+/// Whether the atmosphere lookup tables are missing or were computed for a different atmosphere
+/// than the map file's planet has.
+#[cfg(feature = "render")]
+pub(crate) fn atmosphere_outdated(mapfile: &MapFile) -> Result<bool, Error> {
+    Ok(!mapfile.reload_texture("transmittance")
+        || !mapfile.reload_texture("inscattering")
+        || mapfile.sky_atmosphere_height()? != Some(mapfile.planet().atmosphere_height))
+}
+
+/// Compute the atmosphere lookup tables if they are outdated. This takes a while, so `Terrain`
+/// runs it in the background and draws the sky without the tables until it finishes.
+#[cfg(feature = "render")]
+pub(crate) fn generate_atmosphere(mapfile: &MapFile) -> Result<(), Error> {
+    if atmosphere_outdated(mapfile)? {
+        let atmosphere_height = mapfile.planet().atmosphere_height;
+        let mut context = AssetLoadContextBuf::new();
+        let mut context = context.context("Precomputing atmosphere...", 1);
+        let atmosphere = crate::sky::Atmosphere::new(&mut context, atmosphere_height)?;
         mapfile.write_texture(
             "transmittance",
             TextureDescriptor {
@@ -912,14 +1208,17 @@ fn generate_sky(mapfile: &mut MapFile, context: &mut AssetLoadContext) -> Result
             },
             bytemuck::cast_slice(&atmosphere.inscattering.data),
         )?;
+        mapfile.set_sky_atmosphere_height(atmosphere_height)?;
     }
     Ok(())
 }
 
+#[cfg(feature = "render")]
 struct WebTextureAsset {
     url: String,
     filename: String,
 }
+#[cfg(feature = "render")]
 impl WebAsset for WebTextureAsset {
     type Type = (TextureDescriptor, Vec<u8>);
 
@@ -0,0 +1,66 @@
+use crate::coordinates;
+use crate::terrain::quadtree::node::VNode;
+use std::fmt;
+use std::sync::Arc;
+
+/// Heights of a single heightmap tile, handed to heightmap passes to modify in place.
+pub struct TileData {
+    /// Number of samples along each side of the tile, including the border.
+    pub resolution: usize,
+    /// Number of samples along each edge that overlap the neighboring tiles.
+    pub border: usize,
+    /// Elevations in meters, in row-major order.
+    pub heights: Vec<i16>,
+}
+
+/// Location of the tile being processed by a heightmap pass.
+#[derive(Copy, Clone, Debug)]
+pub struct TileNode {
+    node: VNode,
+    resolution: u16,
+    border: u16,
+}
+impl TileNode {
+    pub(crate) fn new(node: VNode, resolution: usize, border: usize) -> Self {
+        Self { node, resolution: resolution as u16, border: border as u16 }
+    }
+
+    /// Level of the tile in the quadtree. Each level has twice the resolution of the one above.
+    pub fn level(&self) -> u8 {
+        self.node.level()
+    }
+
+    /// Which of the six faces of the cube map the tile lies on.
+    pub fn face(&self) -> u8 {
+        self.node.face()
+    }
+
+    /// Approximate distance in meters between adjacent samples.
+    pub fn sample_spacing(&self) -> f32 {
+        self.node.aprox_side_length() / (self.resolution - 1 - 2 * self.border) as f32
+    }
+
+    /// Latitude and longitude in radians of the sample at column `x` and row `y`.
+    pub fn sample_position(&self, x: usize, y: usize) -> (f64, f64) {
+        let cspace =
+            self.node.grid_position_cspace(x as i32, y as i32, self.border, self.resolution);
+        let polar = coordinates::cspace_to_polar(cspace);
+        (polar.x, polar.y)
+    }
+}
+
+/// A named post-processing step applied to every heightmap tile as it is generated, before the
+/// tile is written to the map file.
+///
+/// Samples in the border of each tile are shared with its neighbors, so passes should compute
+/// heights purely from sample positions and existing heights to avoid visible seams.
+#[derive(Clone)]
+pub(crate) struct HeightmapPass {
+    pub name: String,
+    pub pass: Arc<dyn Fn(&mut TileData, TileNode) + Send + Sync>,
+}
+impl fmt::Debug for HeightmapPass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HeightmapPass").field("name", &self.name).finish()
+    }
+}
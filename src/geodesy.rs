@@ -0,0 +1,946 @@
+//! Geodesic calculations on the WGS84 ellipsoid.
+//!
+//! These follow Karney, "Algorithms for geodesics" (J. Geodesy, 2013), using the same sixth order
+//! series as GeographicLib, so distances are accurate to a few nanometers anywhere on the planet
+//! including between nearly antipodal points. Latitudes, longitudes and bearings are given in
+//! radians, with bearings measured clockwise from north.
+
+use crate::coordinates::{WGS84_INV_FLATTENING, WGS84_SEMI_MAJOR_AXIS_METERS};
+use std::f64::consts::PI;
+
+/// The shortest path between two points, as computed by `inverse`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GeodesicSolution {
+    /// Length of the path in meters.
+    pub distance: f64,
+    /// Direction of travel at the first point.
+    pub initial_bearing: f64,
+    /// Direction of travel on arrival at the second point.
+    pub final_bearing: f64,
+}
+
+/// The end of a path computed by `destination`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Destination {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Direction of travel on arrival.
+    pub final_bearing: f64,
+}
+
+/// Length in meters of the shortest path between two (latitude, longitude) pairs.
+pub fn distance(from: (f64, f64), to: (f64, f64)) -> f64 {
+    inverse(from, to).distance
+}
+
+/// Solve the inverse geodesic problem: find the shortest path between two (latitude, longitude)
+/// pairs along with the bearings at either end.
+pub fn inverse(from: (f64, f64), to: (f64, f64)) -> GeodesicSolution {
+    let inverse = Ellipsoid::wgs84().inverse(from.0, from.1, to.0, to.1);
+    GeodesicSolution {
+        distance: inverse.s12,
+        initial_bearing: inverse.salp1.atan2(inverse.calp1),
+        final_bearing: inverse.salp2.atan2(inverse.calp2),
+    }
+}
+
+/// Solve the direct geodesic problem: find where a path starting at `from` with the given bearing
+/// ends after `distance` meters.
+pub fn destination(from: (f64, f64), bearing: f64, distance: f64) -> Destination {
+    Ellipsoid::wgs84().direct(from.0, from.1, bearing, distance)
+}
+
+/// Area in square meters enclosed by a polygon of (latitude, longitude) pairs whose edges are
+/// geodesics. The area is positive if the vertices are ordered counter-clockwise when viewed from
+/// above and negative otherwise. Polygons may enclose a pole.
+pub fn polygon_area(polygon: &[(f64, f64)]) -> f64 {
+    if polygon.len() < 3 {
+        return 0.0;
+    }
+
+    let ellipsoid = Ellipsoid::wgs84();
+    let mut area = 0.0;
+    let mut crossings = 0;
+    for (i, &(lat1, lon1)) in polygon.iter().enumerate() {
+        let (lat2, lon2) = polygon[(i + 1) % polygon.len()];
+        area += ellipsoid.inverse(lat1, lon1, lat2, lon2).s12_area;
+        crossings += transit(lon1, lon2);
+    }
+
+    let total = 4.0 * PI * ellipsoid.c2;
+    let mut area = remainder(area, total);
+    if crossings % 2 != 0 {
+        area += if area < 0.0 { 0.5 * total } else { -0.5 * total };
+    }
+    area = -area;
+    if area > 0.5 * total {
+        area -= total;
+    } else if area <= -0.5 * total {
+        area += total;
+    }
+    area
+}
+
+/// Returns 1 or -1 if the edge from `lon1` to `lon2` crosses the prime meridian heading east or
+/// west, and 0 otherwise.
+fn transit(lon1: f64, lon2: f64) -> i32 {
+    let lon12 = ang_diff(lon1, lon2).0;
+    let lon1 = remainder(lon1, 2.0 * PI);
+    let lon2 = remainder(lon2, 2.0 * PI);
+    if lon12 > 0.0 && ((lon1 < 0.0 && lon2 >= 0.0) || (lon1 > 0.0 && lon2 == 0.0)) {
+        1
+    } else if lon12 < 0.0 && lon1 >= 0.0 && lon2 < 0.0 {
+        -1
+    } else {
+        0
+    }
+}
+
+/// `x - y * round(x / y)`, in the range [-y/2, y/2].
+fn remainder(x: f64, y: f64) -> f64 {
+    let z = x % y;
+    if z < -0.5 * y {
+        z + y
+    } else if z >= 0.5 * y {
+        z - y
+    } else {
+        z
+    }
+}
+
+/// Difference `y - x` reduced to (-pi, pi], along with `pi - |y - x|` computed without losing
+/// precision when the difference is close to pi.
+fn ang_diff(x: f64, y: f64) -> (f64, f64) {
+    let mut d = remainder(y - x, 2.0 * PI);
+    if d == -PI {
+        d = PI;
+    }
+    (d, PI - d.abs())
+}
+
+fn norm(x: &mut f64, y: &mut f64) {
+    let r = x.hypot(*y);
+    *x /= r;
+    *y /= r;
+}
+
+/// Evaluate a polynomial with coefficients listed from the highest order term down.
+fn polyval(order: usize, coeffs: &[f64], x: f64) -> f64 {
+    coeffs[..=order].iter().fold(0.0, |y, &c| y * x + c)
+}
+
+/// Evaluate `sum(c[i] * sin(2 * i * x))` for i in 1..=n if `sin`, or `sum(c[i] * cos((2 * i + 1)
+/// * x))` for i in 0..n otherwise, using Clenshaw summation.
+fn sin_cos_series(sin: bool, sinx: f64, cosx: f64, c: &[f64], n: usize) -> f64 {
+    let mut k = n + sin as usize;
+    let ar = 2.0 * (cosx - sinx) * (cosx + sinx);
+    let mut y0 = if n & 1 == 1 {
+        k -= 1;
+        c[k]
+    } else {
+        0.0
+    };
+    let mut y1 = 0.0;
+    for _ in 0..n / 2 {
+        k -= 1;
+        y1 = ar * y0 - y1 + c[k];
+        k -= 1;
+        y0 = ar * y1 - y0 + c[k];
+    }
+    if sin {
+        2.0 * sinx * cosx * y0
+    } else {
+        cosx * (y0 - y1)
+    }
+}
+
+/// Order of the series expansions.
+const ORDER: usize = 6;
+
+const TOL0: f64 = f64::EPSILON;
+const TOL1: f64 = 200.0 * TOL0;
+const MAX_ITERATIONS_NEWTON: usize = 20;
+const MAX_ITERATIONS: usize = MAX_ITERATIONS_NEWTON + 53 + 10;
+
+fn tol2() -> f64 {
+    TOL0.sqrt()
+}
+fn tolb() -> f64 {
+    TOL0 * tol2()
+}
+fn tiny() -> f64 {
+    f64::MIN_POSITIVE.sqrt()
+}
+
+fn a1m1f(eps: f64) -> f64 {
+    const COEFF: [f64; 5] = [1.0, 4.0, 64.0, 0.0, 256.0];
+    let t = polyval(ORDER / 2, &COEFF, eps * eps) / COEFF[ORDER / 2 + 1];
+    (t + eps) / (1.0 - eps)
+}
+
+fn a2m1f(eps: f64) -> f64 {
+    const COEFF: [f64; 5] = [-11.0, -28.0, -192.0, 0.0, 256.0];
+    let t = polyval(ORDER / 2, &COEFF, eps * eps) / COEFF[ORDER / 2 + 1];
+    (t - eps) / (1.0 + eps)
+}
+
+/// Fill `c[1..=ORDER]` with the coefficients of a series in `eps`, given as polynomials in
+/// `eps^2` each followed by its divisor.
+fn series_in_eps2(coeff: &[f64], eps: f64, c: &mut [f64]) {
+    let eps2 = eps * eps;
+    let mut d = eps;
+    let mut o = 0;
+    for (l, c) in c[1..=ORDER].iter_mut().enumerate().map(|(i, c)| (i + 1, c)) {
+        let m = (ORDER - l) / 2;
+        *c = d * polyval(m, &coeff[o..], eps2) / coeff[o + m + 1];
+        o += m + 2;
+        d *= eps;
+    }
+}
+
+fn c1f(eps: f64, c: &mut [f64]) {
+    #[rustfmt::skip]
+    const COEFF: [f64; 18] = [
+        -1.0, 6.0, -16.0, 32.0,
+        -9.0, 64.0, -128.0, 2048.0,
+        9.0, -16.0, 768.0,
+        3.0, -5.0, 512.0,
+        -7.0, 1280.0,
+        -7.0, 2048.0,
+    ];
+    series_in_eps2(&COEFF, eps, c)
+}
+
+fn c1pf(eps: f64, c: &mut [f64]) {
+    #[rustfmt::skip]
+    const COEFF: [f64; 18] = [
+        205.0, -432.0, 768.0, 1536.0,
+        4005.0, -4736.0, 3840.0, 12288.0,
+        -225.0, 116.0, 384.0,
+        -7173.0, 2695.0, 7680.0,
+        3467.0, 7680.0,
+        38081.0, 61440.0,
+    ];
+    series_in_eps2(&COEFF, eps, c)
+}
+
+fn c2f(eps: f64, c: &mut [f64]) {
+    #[rustfmt::skip]
+    const COEFF: [f64; 18] = [
+        1.0, 2.0, 16.0, 32.0,
+        35.0, 64.0, 384.0, 2048.0,
+        15.0, 80.0, 768.0,
+        7.0, 35.0, 512.0,
+        63.0, 1280.0,
+        77.0, 2048.0,
+    ];
+    series_in_eps2(&COEFF, eps, c)
+}
+
+struct Inverse {
+    s12: f64,
+    salp1: f64,
+    calp1: f64,
+    salp2: f64,
+    calp2: f64,
+    /// Area between the geodesic and the equator.
+    s12_area: f64,
+}
+
+struct Ellipsoid {
+    a: f64,
+    f: f64,
+    f1: f64,
+    e2: f64,
+    ep2: f64,
+    n: f64,
+    b: f64,
+    /// Square of the authalic radius.
+    c2: f64,
+    etol2: f64,
+    a3x: [f64; ORDER],
+    c3x: [f64; ORDER * (ORDER - 1) / 2],
+    c4x: [f64; ORDER * (ORDER + 1) / 2],
+}
+impl Ellipsoid {
+    fn wgs84() -> Self {
+        let a = WGS84_SEMI_MAJOR_AXIS_METERS;
+        let f = 1.0 / WGS84_INV_FLATTENING;
+        let f1 = 1.0 - f;
+        let e2 = f * (2.0 - f);
+        let ep2 = e2 / (f1 * f1);
+        let n = f / (2.0 - f);
+        let b = a * f1;
+        let c2 = (a * a + b * b * e2.sqrt().atanh() / e2.sqrt()) / 2.0;
+        let etol2 = 0.1 * tol2() / (f.abs().max(0.001) * (1.0 - f / 2.0).min(1.0) / 2.0).sqrt();
+
+        let mut ellipsoid = Self {
+            a,
+            f,
+            f1,
+            e2,
+            ep2,
+            n,
+            b,
+            c2,
+            etol2,
+            a3x: [0.0; ORDER],
+            c3x: [0.0; ORDER * (ORDER - 1) / 2],
+            c4x: [0.0; ORDER * (ORDER + 1) / 2],
+        };
+        ellipsoid.a3coeff();
+        ellipsoid.c3coeff();
+        ellipsoid.c4coeff();
+        ellipsoid
+    }
+
+    fn a3coeff(&mut self) {
+        #[rustfmt::skip]
+        const COEFF: [f64; 18] = [
+            -3.0, 128.0,
+            -2.0, -3.0, 64.0,
+            -1.0, -3.0, -1.0, 16.0,
+            3.0, -1.0, -2.0, 8.0,
+            1.0, -1.0, 2.0,
+            1.0, 1.0,
+        ];
+        let (mut o, mut k) = (0, 0);
+        for j in (0..ORDER).rev() {
+            let m = (ORDER - j - 1).min(j);
+            self.a3x[k] = polyval(m, &COEFF[o..], self.n) / COEFF[o + m + 1];
+            k += 1;
+            o += m + 2;
+        }
+    }
+
+    fn c3coeff(&mut self) {
+        #[rustfmt::skip]
+        const COEFF: [f64; 45] = [
+            3.0, 128.0,
+            2.0, 5.0, 128.0,
+            -1.0, 3.0, 3.0, 64.0,
+            -1.0, 0.0, 1.0, 8.0,
+            -1.0, 1.0, 4.0,
+            5.0, 256.0,
+            1.0, 3.0, 128.0,
+            -3.0, -2.0, 3.0, 64.0,
+            1.0, -3.0, 2.0, 32.0,
+            7.0, 512.0,
+            -10.0, 9.0, 384.0,
+            5.0, -9.0, 5.0, 192.0,
+            7.0, 512.0,
+            -14.0, 7.0, 512.0,
+            21.0, 2560.0,
+        ];
+        let (mut o, mut k) = (0, 0);
+        for l in 1..ORDER {
+            for j in (l..ORDER).rev() {
+                let m = (ORDER - j - 1).min(j);
+                self.c3x[k] = polyval(m, &COEFF[o..], self.n) / COEFF[o + m + 1];
+                k += 1;
+                o += m + 2;
+            }
+        }
+    }
+
+    fn c4coeff(&mut self) {
+        #[rustfmt::skip]
+        const COEFF: [f64; 77] = [
+            97.0, 15015.0,
+            1088.0, 156.0, 45045.0,
+            -224.0, -4784.0, 1573.0, 45045.0,
+            -10656.0, 14144.0, -4576.0, -858.0, 45045.0,
+            64.0, 624.0, -4576.0, 6864.0, -3003.0, 15015.0,
+            100.0, 208.0, 572.0, 3432.0, -12012.0, 30030.0, 45045.0,
+            1.0, 9009.0,
+            -2944.0, 468.0, 135135.0,
+            5792.0, 1040.0, -1287.0, 135135.0,
+            5952.0, -11648.0, 9152.0, -2574.0, 135135.0,
+            -64.0, -624.0, 4576.0, -6864.0, 3003.0, 135135.0,
+            8.0, 10725.0,
+            1856.0, -936.0, 225225.0,
+            -8448.0, 4992.0, -1144.0, 225225.0,
+            -1440.0, 4160.0, -4576.0, 1716.0, 225225.0,
+            -136.0, 63063.0,
+            1024.0, -208.0, 105105.0,
+            3584.0, -3328.0, 1144.0, 315315.0,
+            -128.0, 135135.0,
+            -2560.0, 832.0, 405405.0,
+            128.0, 99099.0,
+        ];
+        let (mut o, mut k) = (0, 0);
+        for l in 0..ORDER {
+            for j in (l..ORDER).rev() {
+                let m = ORDER - j - 1;
+                self.c4x[k] = polyval(m, &COEFF[o..], self.n) / COEFF[o + m + 1];
+                k += 1;
+                o += m + 2;
+            }
+        }
+    }
+
+    fn a3f(&self, eps: f64) -> f64 {
+        polyval(ORDER - 1, &self.a3x, eps)
+    }
+
+    fn c3f(&self, eps: f64, c: &mut [f64]) {
+        let mut mult = 1.0;
+        let mut o = 0;
+        for (l, c) in c[1..ORDER].iter_mut().enumerate().map(|(i, c)| (i + 1, c)) {
+            let m = ORDER - l - 1;
+            mult *= eps;
+            *c = mult * polyval(m, &self.c3x[o..], eps);
+            o += m + 1;
+        }
+    }
+
+    fn c4f(&self, eps: f64, c: &mut [f64]) {
+        let mut mult = 1.0;
+        let mut o = 0;
+        for (l, c) in c[..ORDER].iter_mut().enumerate() {
+            let m = ORDER - l - 1;
+            *c = mult * polyval(m, &self.c4x[o..], eps);
+            o += m + 1;
+            mult *= eps;
+        }
+    }
+
+    /// Distance and reduced length, scaled by `b`, of the geodesic between two points on the
+    /// auxiliary sphere. Also returns `m0`, the coefficient of secular growth of the reduced
+    /// length.
+    fn lengths(
+        &self,
+        eps: f64,
+        sig12: f64,
+        (ssig1, csig1, dn1): (f64, f64, f64),
+        (ssig2, csig2, dn2): (f64, f64, f64),
+    ) -> (f64, f64, f64) {
+        let mut ca = [0.0; ORDER + 1];
+        let mut cb = [0.0; ORDER + 1];
+        let a1 = a1m1f(eps);
+        c1f(eps, &mut ca);
+        let a2 = a2m1f(eps);
+        c2f(eps, &mut cb);
+        let m0 = a1 - a2;
+        let (a1, a2) = (1.0 + a1, 1.0 + a2);
+
+        let b1 = sin_cos_series(true, ssig2, csig2, &ca, ORDER)
+            - sin_cos_series(true, ssig1, csig1, &ca, ORDER);
+        let b2 = sin_cos_series(true, ssig2, csig2, &cb, ORDER)
+            - sin_cos_series(true, ssig1, csig1, &cb, ORDER);
+        let s12 = a1 * (sig12 + b1);
+        let j12 = m0 * sig12 + (a1 * b1 - a2 * b2);
+        let m12 = dn2 * (csig1 * ssig2) - dn1 * (ssig1 * csig2) - csig1 * csig2 * j12;
+        (s12, m12, m0)
+    }
+
+    /// Solve the astroid equation that gives the starting point for nearly antipodal points.
+    fn astroid(x: f64, y: f64) -> f64 {
+        let (p, q) = (x * x, y * y);
+        let r = (p + q - 1.0) / 6.0;
+        if q == 0.0 && r <= 0.0 {
+            return 0.0;
+        }
+
+        let s = p * q / 4.0;
+        let r2 = r * r;
+        let r3 = r * r2;
+        let disc = s * (s + 2.0 * r3);
+        let mut u = r;
+        if disc >= 0.0 {
+            let mut t3 = s + r3;
+            t3 += if t3 < 0.0 { -disc.sqrt() } else { disc.sqrt() };
+            let t = t3.cbrt();
+            u += t + if t != 0.0 { r2 / t } else { 0.0 };
+        } else {
+            let ang = (-disc).sqrt().atan2(-(s + r3));
+            u += 2.0 * r * (ang / 3.0).cos();
+        }
+        let v = (u * u + q).sqrt();
+        let uv = if u < 0.0 { q / (v - u) } else { u + v };
+        let w = (uv - q) / (2.0 * v);
+        uv / ((uv + w * w).sqrt() + w)
+    }
+
+    /// Starting guess for the azimuth at the first point. Returns the arc length if the points
+    /// are close enough that the guess is already exact, or a negative value otherwise.
+    #[allow(clippy::too_many_arguments)]
+    fn inverse_start(
+        &self,
+        (sbet1, cbet1): (f64, f64),
+        (sbet2, cbet2): (f64, f64),
+        lam12: f64,
+        (slam12, clam12): (f64, f64),
+        alp1: &mut (f64, f64),
+        alp2: &mut (f64, f64),
+        dnm: &mut f64,
+    ) -> f64 {
+        let mut sig12 = -1.0;
+        let sbet12 = sbet2 * cbet1 - cbet2 * sbet1;
+        let cbet12 = cbet2 * cbet1 + sbet2 * sbet1;
+        let sbet12a = sbet2 * cbet1 + cbet2 * sbet1;
+        let shortline = cbet12 >= 0.0 && sbet12 < 0.5 && cbet2 * lam12 < 0.5;
+        let (mut somg12, mut comg12) = if shortline {
+            let mut sbetm2 = (sbet1 + sbet2) * (sbet1 + sbet2);
+            sbetm2 /= sbetm2 + (cbet1 + cbet2) * (cbet1 + cbet2);
+            *dnm = (1.0 + self.ep2 * sbetm2).sqrt();
+            let omg12 = lam12 / (self.f1 * *dnm);
+            (omg12.sin(), omg12.cos())
+        } else {
+            (slam12, clam12)
+        };
+
+        let (salp1, calp1) = alp1;
+        *salp1 = cbet2 * somg12;
+        *calp1 = if comg12 >= 0.0 {
+            sbet12 + cbet2 * sbet1 * somg12 * somg12 / (1.0 + comg12)
+        } else {
+            sbet12a - cbet2 * sbet1 * somg12 * somg12 / (1.0 - comg12)
+        };
+        let ssig12 = salp1.hypot(*calp1);
+        let csig12 = sbet1 * sbet2 + cbet1 * cbet2 * comg12;
+
+        if shortline && ssig12 < self.etol2 {
+            let (salp2, calp2) = alp2;
+            *salp2 = cbet1 * somg12;
+            *calp2 = sbet12
+                - cbet1
+                    * sbet2
+                    * if comg12 >= 0.0 { somg12 * somg12 / (1.0 + comg12) } else { 1.0 - comg12 };
+            norm(salp2, calp2);
+            sig12 = ssig12.atan2(csig12);
+        } else if self.n.abs() > 0.1
+            || csig12 >= 0.0
+            || ssig12 >= 6.0 * self.n.abs() * PI * cbet1 * cbet1
+        {
+            // The zeroth order spherical approximation is good enough.
+        } else {
+            // Nearly antipodal points. Scale to the astroid problem.
+            let lam12x = (-slam12).atan2(-clam12);
+            let k2 = sbet1 * sbet1 * self.ep2;
+            let eps = k2 / (2.0 * (1.0 + (1.0 + k2).sqrt()) + k2);
+            let lamscale = self.f * cbet1 * self.a3f(eps) * PI;
+            let betscale = lamscale * cbet1;
+            let x = lam12x / lamscale;
+            let y = sbet12a / betscale;
+
+            if y > -TOL1 && x > -1.0 - 1000.0 * tol2() {
+                *salp1 = (-x).min(1.0);
+                *calp1 = -(1.0 - *salp1 * *salp1).sqrt();
+            } else {
+                let k = Self::astroid(x, y);
+                let omg12a = lamscale * (-x * k / (1.0 + k));
+                somg12 = omg12a.sin();
+                comg12 = -omg12a.cos();
+                *salp1 = cbet2 * somg12;
+                *calp1 = sbet12a - cbet2 * sbet1 * somg12 * somg12 / (1.0 - comg12);
+            }
+        }
+
+        if *salp1 > 0.0 {
+            norm(salp1, calp1);
+        } else {
+            *salp1 = 1.0;
+            *calp1 = 0.0;
+        }
+        sig12
+    }
+
+    /// Longitude difference reached by a geodesic leaving the first point with azimuth `alp1`,
+    /// minus the target `lam12`. Also returns its derivative with respect to `alp1`, and the
+    /// position on the auxiliary sphere of both points.
+    fn lambda12(
+        &self,
+        (sbet1, cbet1, dn1): (f64, f64, f64),
+        (sbet2, cbet2, dn2): (f64, f64, f64),
+        (salp1, calp1): (f64, f64),
+        (slam120, clam120): (f64, f64),
+    ) -> Lambda12 {
+        let calp1 = if sbet1 == 0.0 && calp1 == 0.0 { -tiny() } else { calp1 };
+        let salp0 = salp1 * cbet1;
+        let calp0 = calp1.hypot(salp1 * sbet1);
+
+        let mut ssig1 = sbet1;
+        let somg1 = salp0 * sbet1;
+        let mut csig1 = calp1 * cbet1;
+        let comg1 = csig1;
+        norm(&mut ssig1, &mut csig1);
+
+        let salp2 = if cbet2 != cbet1 { salp0 / cbet2 } else { salp1 };
+        let calp2 = if cbet2 != cbet1 || sbet2.abs() != -sbet1 {
+            ((calp1 * cbet1) * (calp1 * cbet1)
+                + if cbet1 < -sbet1 {
+                    (cbet2 - cbet1) * (cbet1 + cbet2)
+                } else {
+                    (sbet1 - sbet2) * (sbet1 + sbet2)
+                })
+            .sqrt()
+                / cbet2
+        } else {
+            calp1.abs()
+        };
+
+        let mut ssig2 = sbet2;
+        let somg2 = salp0 * sbet2;
+        let mut csig2 = calp2 * cbet2;
+        let comg2 = csig2;
+        norm(&mut ssig2, &mut csig2);
+
+        let sig12 = (csig1 * ssig2 - ssig1 * csig2).max(0.0).atan2(csig1 * csig2 + ssig1 * ssig2);
+        let somg12 = (comg1 * somg2 - somg1 * comg2).max(0.0);
+        let comg12 = comg1 * comg2 + somg1 * somg2;
+        let eta = (somg12 * clam120 - comg12 * slam120).atan2(comg12 * clam120 + somg12 * slam120);
+
+        let k2 = calp0 * calp0 * self.ep2;
+        let eps = k2 / (2.0 * (1.0 + (1.0 + k2).sqrt()) + k2);
+        let mut c3 = [0.0; ORDER];
+        self.c3f(eps, &mut c3);
+        let b312 = sin_cos_series(true, ssig2, csig2, &c3, ORDER - 1)
+            - sin_cos_series(true, ssig1, csig1, &c3, ORDER - 1);
+        let domg12 = -self.f * self.a3f(eps) * salp0 * (sig12 + b312);
+
+        let dlam12 = if calp2 == 0.0 {
+            -2.0 * self.f1 * dn1 / sbet1
+        } else {
+            let (_, m12, _) = self.lengths(eps, sig12, (ssig1, csig1, dn1), (ssig2, csig2, dn2));
+            m12 * self.f1 / (calp2 * cbet2)
+        };
+
+        Lambda12 {
+            value: eta + domg12,
+            derivative: dlam12,
+            alp2: (salp2, calp2),
+            sig12,
+            sig1: (ssig1, csig1),
+            sig2: (ssig2, csig2),
+            eps,
+            domg12,
+        }
+    }
+
+    fn inverse(&self, lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> Inverse {
+        let tiny = tiny();
+        let (lon12, lon12s) = ang_diff(lon1, lon2);
+        let mut lonsign = if lon12.is_sign_negative() { -1.0 } else { 1.0 };
+        let lon12 = lonsign * lon12;
+        let lam12 = lon12;
+        let (slam12, clam12) =
+            if lon12 > 0.5 * PI { (lon12s.sin(), -lon12s.cos()) } else { lon12.sin_cos() };
+
+        let (mut lat1, mut lat2) =
+            (lat1.clamp(-0.5 * PI, 0.5 * PI), lat2.clamp(-0.5 * PI, 0.5 * PI));
+        let swapp = if lat1.abs() < lat2.abs() { -1.0 } else { 1.0 };
+        if swapp < 0.0 {
+            lonsign *= -1.0;
+            std::mem::swap(&mut lat1, &mut lat2);
+        }
+        let latsign = if lat1.is_sign_negative() { 1.0 } else { -1.0 };
+        lat1 *= latsign;
+        lat2 *= latsign;
+
+        let (mut sbet1, mut cbet1) = lat1.sin_cos();
+        sbet1 *= self.f1;
+        norm(&mut sbet1, &mut cbet1);
+        cbet1 = cbet1.max(tiny);
+        let (mut sbet2, mut cbet2) = lat2.sin_cos();
+        sbet2 *= self.f1;
+        norm(&mut sbet2, &mut cbet2);
+        cbet2 = cbet2.max(tiny);
+
+        if cbet1 < -sbet1 {
+            if cbet2 == cbet1 {
+                sbet2 = sbet1.copysign(sbet2);
+            }
+        } else if sbet2.abs() == -sbet1 {
+            cbet2 = cbet1;
+        }
+
+        let dn1 = (1.0 + self.ep2 * sbet1 * sbet1).sqrt();
+        let dn2 = (1.0 + self.ep2 * sbet2 * sbet2).sqrt();
+
+        let (mut salp1, mut calp1, mut salp2, mut calp2) = (0.0, 0.0, 0.0, 0.0);
+        let mut s12x = 0.0;
+        let mut omg12 = 0.0;
+        let (mut somg12, mut comg12) = (2.0, 0.0);
+
+        let mut meridian = lat1 == -0.5 * PI || slam12 == 0.0;
+        if meridian {
+            calp1 = clam12;
+            salp1 = slam12;
+            calp2 = 1.0;
+            salp2 = 0.0;
+            let (ssig1, csig1) = (sbet1, calp1 * cbet1);
+            let (ssig2, csig2) = (sbet2, calp2 * cbet2);
+            let sig12 =
+                (csig1 * ssig2 - ssig1 * csig2).max(0.0).atan2(csig1 * csig2 + ssig1 * ssig2);
+            let (s, m12x, _) =
+                self.lengths(self.n, sig12, (ssig1, csig1, dn1), (ssig2, csig2, dn2));
+            s12x = s;
+            if sig12 < 1.0 || m12x >= 0.0 {
+                if sig12 < 3.0 * tiny || (sig12 < TOL0 && (s12x < 0.0 || m12x < 0.0)) {
+                    s12x = 0.0;
+                }
+                s12x *= self.b;
+            } else {
+                meridian = false;
+            }
+        }
+
+        if !meridian && sbet1 == 0.0 && lon12s >= self.f * PI {
+            // Along the equator.
+            calp1 = 0.0;
+            calp2 = 0.0;
+            salp1 = 1.0;
+            salp2 = 1.0;
+            s12x = self.a * lam12;
+            omg12 = lam12 / self.f1;
+        } else if !meridian {
+            let mut dnm = 0.0;
+            let mut alp1 = (0.0, 0.0);
+            let mut alp2 = (0.0, 0.0);
+            let sig12 = self.inverse_start(
+                (sbet1, cbet1),
+                (sbet2, cbet2),
+                lam12,
+                (slam12, clam12),
+                &mut alp1,
+                &mut alp2,
+                &mut dnm,
+            );
+            salp1 = alp1.0;
+            calp1 = alp1.1;
+
+            if sig12 >= 0.0 {
+                salp2 = alp2.0;
+                calp2 = alp2.1;
+                s12x = sig12 * self.b * dnm;
+                omg12 = lam12 / (self.f1 * dnm);
+            } else {
+                // Find the azimuth at the first point with Newton's method, falling back to
+                // bisection when it fails to converge.
+                let (mut salp1a, mut calp1a) = (tiny, 1.0);
+                let (mut salp1b, mut calp1b) = (tiny, -1.0);
+                let mut tripn = false;
+                let mut tripb = false;
+                let mut numit = 0;
+                let mut result;
+                loop {
+                    result = self.lambda12(
+                        (sbet1, cbet1, dn1),
+                        (sbet2, cbet2, dn2),
+                        (salp1, calp1),
+                        (slam12, clam12),
+                    );
+                    let v = result.value;
+                    if tripb
+                        || v.is_nan()
+                        || v.abs() < if tripn { 8.0 } else { 1.0 } * TOL0
+                        || numit == MAX_ITERATIONS
+                    {
+                        break;
+                    }
+                    if v > 0.0 && (numit > MAX_ITERATIONS_NEWTON || calp1 / salp1 > calp1b / salp1b)
+                    {
+                        salp1b = salp1;
+                        calp1b = calp1;
+                    } else if v < 0.0
+                        && (numit > MAX_ITERATIONS_NEWTON || calp1 / salp1 < calp1a / salp1a)
+                    {
+                        salp1a = salp1;
+                        calp1a = calp1;
+                    }
+                    numit += 1;
+
+                    if numit <= MAX_ITERATIONS_NEWTON && result.derivative > 0.0 {
+                        let dalp1 = -v / result.derivative;
+                        if dalp1.abs() < PI {
+                            let (sdalp1, cdalp1) = dalp1.sin_cos();
+                            let nsalp1 = salp1 * cdalp1 + calp1 * sdalp1;
+                            if nsalp1 > 0.0 {
+                                calp1 = calp1 * cdalp1 - salp1 * sdalp1;
+                                salp1 = nsalp1;
+                                norm(&mut salp1, &mut calp1);
+                                tripn = v.abs() <= 16.0 * TOL0;
+                                continue;
+                            }
+                        }
+                    }
+
+                    salp1 = (salp1a + salp1b) / 2.0;
+                    calp1 = (calp1a + calp1b) / 2.0;
+                    norm(&mut salp1, &mut calp1);
+                    tripn = false;
+                    tripb = (salp1a - salp1).abs() + (calp1a - calp1) < tolb()
+                        || (salp1 - salp1b).abs() + (calp1 - calp1b) < tolb();
+                }
+
+                let (s, _, _) = self.lengths(
+                    result.eps,
+                    result.sig12,
+                    (result.sig1.0, result.sig1.1, dn1),
+                    (result.sig2.0, result.sig2.1, dn2),
+                );
+                s12x = s * self.b;
+                salp2 = result.alp2.0;
+                calp2 = result.alp2.1;
+                let (sdomg12, cdomg12) = result.domg12.sin_cos();
+                somg12 = slam12 * cdomg12 - clam12 * sdomg12;
+                comg12 = clam12 * cdomg12 + slam12 * sdomg12;
+            }
+        }
+
+        // Area between the geodesic and the equator.
+        let salp0 = salp1 * cbet1;
+        let calp0 = calp1.hypot(salp1 * sbet1);
+        let mut s12_area = if calp0 != 0.0 && salp0 != 0.0 {
+            let (mut ssig1, mut csig1) = (sbet1, calp1 * cbet1);
+            let (mut ssig2, mut csig2) = (sbet2, calp2 * cbet2);
+            let k2 = calp0 * calp0 * self.ep2;
+            let eps = k2 / (2.0 * (1.0 + (1.0 + k2).sqrt()) + k2);
+            let a4 = self.a * self.a * calp0 * salp0 * self.e2;
+            norm(&mut ssig1, &mut csig1);
+            norm(&mut ssig2, &mut csig2);
+            let mut c4 = [0.0; ORDER];
+            self.c4f(eps, &mut c4);
+            let b41 = sin_cos_series(false, ssig1, csig1, &c4, ORDER);
+            let b42 = sin_cos_series(false, ssig2, csig2, &c4, ORDER);
+            a4 * (b42 - b41)
+        } else {
+            0.0
+        };
+        if !meridian && somg12 == 2.0 {
+            somg12 = omg12.sin();
+            comg12 = omg12.cos();
+        }
+        let alp12 =
+            if !meridian && comg12 > -std::f64::consts::FRAC_1_SQRT_2 && sbet2 - sbet1 < 1.75 {
+                let domg12 = 1.0 + comg12;
+                let dbet1 = 1.0 + cbet1;
+                let dbet2 = 1.0 + cbet2;
+                2.0 * (somg12 * (sbet1 * dbet2 + sbet2 * dbet1))
+                    .atan2(domg12 * (sbet1 * sbet2 + dbet1 * dbet2))
+            } else {
+                let mut salp12 = salp2 * calp1 - calp2 * salp1;
+                let mut calp12 = calp2 * calp1 + salp2 * salp1;
+                if salp12 == 0.0 && calp12 < 0.0 {
+                    salp12 = tiny * calp1;
+                    calp12 = -1.0;
+                }
+                salp12.atan2(calp12)
+            };
+        s12_area += self.c2 * alp12;
+        s12_area *= swapp * lonsign * latsign;
+
+        if swapp < 0.0 {
+            std::mem::swap(&mut salp1, &mut salp2);
+            std::mem::swap(&mut calp1, &mut calp2);
+        }
+        Inverse {
+            s12: s12x,
+            salp1: salp1 * swapp * lonsign,
+            calp1: calp1 * swapp * latsign,
+            salp2: salp2 * swapp * lonsign,
+            calp2: calp2 * swapp * latsign,
+            s12_area,
+        }
+    }
+
+    fn direct(&self, lat1: f64, lon1: f64, azi1: f64, s12: f64) -> Destination {
+        let tiny = tiny();
+        let (salp1, calp1) = azi1.sin_cos();
+        let (mut sbet1, mut cbet1) = lat1.clamp(-0.5 * PI, 0.5 * PI).sin_cos();
+        sbet1 *= self.f1;
+        norm(&mut sbet1, &mut cbet1);
+        cbet1 = cbet1.max(tiny);
+
+        let salp0 = salp1 * cbet1;
+        let calp0 = calp1.hypot(salp1 * sbet1);
+        let mut ssig1 = sbet1;
+        let somg1 = salp0 * sbet1;
+        let mut csig1 = if sbet1 != 0.0 || calp1 != 0.0 { cbet1 * calp1 } else { 1.0 };
+        let comg1 = csig1;
+        norm(&mut ssig1, &mut csig1);
+
+        let k2 = calp0 * calp0 * self.ep2;
+        let eps = k2 / (2.0 * (1.0 + (1.0 + k2).sqrt()) + k2);
+        let a1m1 = a1m1f(eps);
+        let mut c1a = [0.0; ORDER + 1];
+        c1f(eps, &mut c1a);
+        let b11 = sin_cos_series(true, ssig1, csig1, &c1a, ORDER);
+        let (s, c) = b11.sin_cos();
+        let stau1 = ssig1 * c + csig1 * s;
+        let ctau1 = csig1 * c - ssig1 * s;
+        let mut c1pa = [0.0; ORDER + 1];
+        c1pf(eps, &mut c1pa);
+        let a3c = -self.f * salp0 * self.a3f(eps);
+        let mut c3a = [0.0; ORDER];
+        self.c3f(eps, &mut c3a);
+        let b31 = sin_cos_series(true, ssig1, csig1, &c3a, ORDER - 1);
+
+        let tau12 = s12 / (self.b * (1.0 + a1m1));
+        let (s, c) = tau12.sin_cos();
+        let b12 = -sin_cos_series(true, stau1 * c + ctau1 * s, ctau1 * c - stau1 * s, &c1pa, ORDER);
+        let sig12 = tau12 - (b12 - b11);
+        let (ssig12, csig12) = sig12.sin_cos();
+
+        let ssig2 = ssig1 * csig12 + csig1 * ssig12;
+        let mut csig2 = csig1 * csig12 - ssig1 * ssig12;
+        let sbet2 = calp0 * ssig2;
+        let mut cbet2 = salp0.hypot(calp0 * csig2);
+        if cbet2 == 0.0 {
+            cbet2 = tiny;
+            csig2 = tiny;
+        }
+        let salp2 = salp0;
+        let calp2 = calp0 * csig2;
+
+        let somg2 = salp0 * ssig2;
+        let comg2 = csig2;
+        let omg12 = (somg2 * comg1 - comg2 * somg1).atan2(comg2 * comg1 + somg2 * somg1);
+        let lam12 =
+            omg12 + a3c * (sig12 + (sin_cos_series(true, ssig2, csig2, &c3a, ORDER - 1) - b31));
+
+        Destination {
+            latitude: sbet2.atan2(self.f1 * cbet2),
+            longitude: remainder(lon1 + lam12, 2.0 * PI),
+            final_bearing: salp2.atan2(calp2),
+        }
+    }
+}
+
+struct Lambda12 {
+    value: f64,
+    derivative: f64,
+    alp2: (f64, f64),
+    sig12: f64,
+    sig1: (f64, f64),
+    sig2: (f64, f64),
+    eps: f64,
+    domg12: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_geographiclib() {
+        let jfk = (40.6f64.to_radians(), -73.8f64.to_radians());
+        let lhr = (51.6f64.to_radians(), -0.5f64.to_radians());
+        let path = inverse(jfk, lhr);
+        assert!((path.distance - 5551759.400319).abs() < 1e-5);
+        assert!((path.initial_bearing.to_degrees() - 51.198882845579).abs() < 1e-9);
+        assert!((path.final_bearing.to_degrees() - 107.821776735514).abs() < 1e-9);
+
+        let end = destination(jfk, path.initial_bearing, path.distance);
+        assert!((end.latitude - lhr.0).abs() < 1e-12 && (end.longitude - lhr.1).abs() < 1e-12);
+
+        // Nearly antipodal points, from Karney's paper.
+        let path =
+            inverse((-30f64.to_radians(), 0.0), (29.9f64.to_radians(), 179.8f64.to_radians()));
+        assert!((path.distance - 19989832.827610).abs() < 1e-5);
+
+        let octant = [(0.0, 0.0), (0.0, 90f64.to_radians()), (90f64.to_radians(), 0.0)];
+        assert!((polygon_area(&octant) - 63758202715511.0).abs() < 1.0);
+    }
+}
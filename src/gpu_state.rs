@@ -1,11 +1,17 @@
+use std::sync::{Arc, Mutex};
 use std::{borrow::Cow, collections::HashMap};
 
 use crate::{
     cache::{LayerType, MeshType, SingularLayerType, UnifiedPriorityCache},
+    distance_field,
     mapfile::MapFile,
+    skyline,
+    terrain::material::{GpuMaterials, PARALLAX_DEPTH, PARALLAX_DISTANCE},
     terrain::quadtree::NodeState,
 };
+use std::num::NonZeroU8;
 use vec_map::VecMap;
+use wgpu::util::DeviceExt;
 
 #[repr(C)]
 pub(crate) struct DrawIndexedIndirect {
@@ -28,12 +34,195 @@ pub(crate) struct GlobalUniformBlock {
     pub view_proj_inverse: mint::ColumnMatrix4<f32>,
     pub camera: [f32; 4],
     pub sun_direction: [f32; 4],
+    pub prev_view_proj: mint::ColumnMatrix4<f32>,
+    pub jitter: [f32; 2],
+    /// Exposure used when tonemapping, as EV100.
+    pub ev100: f32,
+    /// Nonzero to color terrain by where its tiles came from. See
+    /// `Terrain::set_provenance_overlay`.
+    pub debug_overlay: f32,
+    pub shadow_view_proj: mint::ColumnMatrix4<f32>,
+    /// x: whether the shadow map is enabled, y: depth bias.
+    pub shadow_params: [f32; 4],
+    /// See `Season::uniform`.
+    pub season: [f32; 4],
+    /// xyz: direction towards the moon, w: angular radius of the moon or zero to hide it.
+    pub moon: [f32; 4],
+    /// See `Weather::uniform`.
+    pub weather: [f32; 4],
+    /// xyz: camera position modulo the period of the ground materials.
+    pub ground_camera: [f32; 4],
+    /// x: blend towards the flat map, y: latitude and z: longitude of the center of the map, w:
+    /// Web Mercator y coordinate of the center.
+    pub map_projection: [f32; 4],
+    /// See `PlanetConfig::uniform`.
+    pub planet: [f32; 4],
+    /// See `Weather::clouds_uniform`.
+    pub clouds: [f32; 4],
+    /// See `DistanceField::uniforms`.
+    pub distance_field: [[f32; 4]; 3],
+    /// x: southern and y: western edge of the region covered by skylines, z: its latitude and w:
+    /// its longitude span, all in radians. Zero if there are no skylines.
+    pub skylines: [f32; 4],
 }
 unsafe impl bytemuck::Pod for GlobalUniformBlock {}
 unsafe impl bytemuck::Zeroable for GlobalUniformBlock {}
 
+/// How terrain textures are filtered when sampled.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TextureFiltering {
+    /// Only sample from the most detailed mip level.
+    Bilinear,
+    /// Blend between mip levels.
+    Trilinear,
+    /// Trilinear filtering with up to the given number of anisotropic samples (1, 2, 4, 8 or 16).
+    /// Other counts are rounded down to the nearest of those.
+    Anisotropic(u8),
+}
+impl Default for TextureFiltering {
+    fn default() -> Self {
+        TextureFiltering::Anisotropic(16)
+    }
+}
+impl TextureFiltering {
+    /// The sampler's anisotropy clamp, which wgpu only accepts as a power of two up to 16.
+    fn anisotropy_clamp(self) -> Option<NonZeroU8> {
+        match self {
+            TextureFiltering::Anisotropic(n) if n > 1 => {
+                NonZeroU8::new(1 << (7 - n.min(16).leading_zeros()))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn create_filtered_sampler(
+    device: &wgpu::Device,
+    filtering: TextureFiltering,
+    address_mode: wgpu::AddressMode,
+    label: &str,
+) -> wgpu::Sampler {
+    device.create_sampler(&wgpu::SamplerDescriptor {
+        address_mode_u: address_mode,
+        address_mode_v: address_mode,
+        address_mode_w: address_mode,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: match filtering {
+            TextureFiltering::Bilinear => wgpu::FilterMode::Nearest,
+            TextureFiltering::Trilinear | TextureFiltering::Anisotropic(_) => {
+                wgpu::FilterMode::Linear
+            }
+        },
+        lod_max_clamp: match filtering {
+            TextureFiltering::Bilinear => 0.0,
+            TextureFiltering::Trilinear | TextureFiltering::Anisotropic(_) => std::f32::MAX,
+        },
+        anisotropy_clamp: filtering.anisotropy_clamp(),
+        label: Some(label),
+        ..Default::default()
+    })
+}
+
+/// Create the depth texture that external shadow casters are rendered into. When no shadow map is
+/// in use, a 1x1 placeholder is bound instead.
+fn create_shadow_map(device: &wgpu::Device, resolution: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d { width: resolution, height: resolution, depth_or_array_layers: 1 },
+        format: wgpu::TextureFormat::Depth32Float,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+        label: Some("texture.shadow_map"),
+    })
+}
+
+/// Create the 3D texture holding the distance field of the terrain around the camera. When
+/// distance field shadows are disabled, a 1x1x1 placeholder is bound instead.
+fn create_distance_field(device: &wgpu::Device, enabled: bool) -> wgpu::Texture {
+    let (resolution, layers) = if enabled {
+        (distance_field::RESOLUTION as u32, distance_field::LAYERS as u32)
+    } else {
+        (1, 1)
+    };
+    device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+            width: resolution,
+            height: resolution,
+            depth_or_array_layers: layers,
+        },
+        format: wgpu::TextureFormat::R8Unorm,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D3,
+        usage: wgpu::TextureUsage::COPY_DST | wgpu::TextureUsage::SAMPLED,
+        label: Some("texture.distance_field"),
+    })
+}
+
+/// Load one of the atmosphere lookup tables from the map file. Until they have been generated a
+/// 1x1 placeholder is used instead, which shaders detect to fall back to computing transmittance
+/// directly.
+fn load_atmosphere_table(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mapfile: &MapFile,
+    name: &str,
+    dimension: wgpu::TextureDimension,
+) -> Result<wgpu::Texture, anyhow::Error> {
+    if mapfile.reload_texture(name) {
+        return mapfile.read_texture(device, queue, name);
+    }
+    Ok(device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+        format: wgpu::TextureFormat::Rgba32Float,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension,
+        usage: wgpu::TextureUsage::COPY_DST | wgpu::TextureUsage::SAMPLED,
+        label: Some(&format!("texture.{}", name)),
+    }))
+}
+
+/// Load the skylines of distant mountains from the map file, or create a 1x1x1 placeholder if
+/// none have been generated.
+fn load_skylines(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mapfile: &MapFile,
+) -> Result<wgpu::Texture, anyhow::Error> {
+    if mapfile.reload_texture(skyline::TEXTURE_NAME) {
+        return mapfile.read_texture(device, queue, skyline::TEXTURE_NAME);
+    }
+    Ok(device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+        format: wgpu::TextureFormat::Rg32Float,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D3,
+        usage: wgpu::TextureUsage::COPY_DST | wgpu::TextureUsage::SAMPLED,
+        label: Some("texture.skylines"),
+    }))
+}
+
+/// Create one of the cube map arrays holding the planet imposter, with a layer for each face.
+fn create_imposter_texture(device: &wgpu::Device, resolution: u32, name: &str) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d { width: resolution, height: resolution, depth_or_array_layers: 6 },
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        usage: wgpu::TextureUsage::STORAGE | wgpu::TextureUsage::SAMPLED,
+        label: Some(&format!("texture.{}", name)),
+    })
+}
+
 pub(crate) struct GpuState {
     pub tile_cache: VecMap<wgpu::Texture>,
+    /// Layer of `tile_cache` bound to shader images with each name.
+    tile_layers: HashMap<String, LayerType>,
     pub mesh_cache: VecMap<GpuMeshLayer>,
     pub texture_cache: VecMap<wgpu::Texture>,
 
@@ -48,10 +237,28 @@ pub(crate) struct GpuState {
     transmittance: wgpu::Texture,
     inscattering: wgpu::Texture,
     aerial_perspective: wgpu::Texture,
+    pub shadow_map: wgpu::Texture,
+    pub distance_field: wgpu::Texture,
+    skylines: wgpu::Texture,
+    pub ground_materials: GpuMaterials,
+    materials: wgpu::Buffer,
+    imposter_albedo: wgpu::Texture,
+    imposter_normals: wgpu::Texture,
 
     nearest: wgpu::Sampler,
     linear: wgpu::Sampler,
     linear_wrap: wgpu::Sampler,
+    filtered: wgpu::Sampler,
+    ground: wgpu::Sampler,
+    shadow: wgpu::Sampler,
+
+    /// Bind group layouts made by `bind_group_for_shader`, keyed by their entries. Shaders with
+    /// the same bindings share a layout, and reloading a shader only creates a new one if its
+    /// bindings changed.
+    layouts: Mutex<HashMap<Vec<wgpu::BindGroupLayoutEntry>, Arc<wgpu::BindGroupLayout>>>,
+    /// Views of the textures above, made the first time each is bound. Cleared whenever one of
+    /// the textures is replaced.
+    views: Mutex<HashMap<String, Arc<wgpu::TextureView>>>,
 }
 impl GpuState {
     pub(crate) fn new(
@@ -59,12 +266,26 @@ impl GpuState {
         queue: &wgpu::Queue,
         mapfile: &MapFile,
         cache: &UnifiedPriorityCache,
+        filtering: TextureFiltering,
     ) -> Result<Self, anyhow::Error> {
+        let ground_materials = GpuMaterials::empty(device);
         Ok(GpuState {
             noise: mapfile.read_texture(device, queue, "noise")?,
             sky: mapfile.read_texture(device, queue, "sky")?,
-            transmittance: mapfile.read_texture(device, queue, "transmittance")?,
-            inscattering: mapfile.read_texture(device, queue, "inscattering")?,
+            transmittance: load_atmosphere_table(
+                device,
+                queue,
+                mapfile,
+                "transmittance",
+                wgpu::TextureDimension::D2,
+            )?,
+            inscattering: load_atmosphere_table(
+                device,
+                queue,
+                mapfile,
+                "inscattering",
+                wgpu::TextureDimension::D3,
+            )?,
             aerial_perspective: device.create_texture(&wgpu::TextureDescriptor {
                 size: wgpu::Extent3d { width: 17, height: 17, depth_or_array_layers: 1024 },
                 format: wgpu::TextureFormat::Rgba16Float,
@@ -102,6 +323,11 @@ impl GpuState {
                 label: Some("texture.staging.bc5"),
             }),
             tile_cache: cache.make_gpu_tile_cache(device),
+            tile_layers: mapfile
+                .layers()
+                .values()
+                .map(|layer| (layer.name.clone(), layer.layer_type))
+                .collect(),
             mesh_cache: cache.make_gpu_mesh_cache(device),
             texture_cache: cache.make_gpu_texture_cache(device),
             globals: device.create_buffer(&wgpu::BufferDescriptor {
@@ -148,6 +374,181 @@ impl GpuState {
                 label: Some("sampler.linear_wrap"),
                 ..Default::default()
             }),
+            filtered: create_filtered_sampler(
+                device,
+                filtering,
+                wgpu::AddressMode::ClampToEdge,
+                "sampler.filtered",
+            ),
+            ground: create_filtered_sampler(
+                device,
+                filtering,
+                wgpu::AddressMode::Repeat,
+                "sampler.ground",
+            ),
+            materials: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                contents: bytemuck::bytes_of(&ground_materials.uniforms),
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::UNIFORM,
+                label: Some("buffer.materials"),
+            }),
+            ground_materials,
+            shadow_map: create_shadow_map(device, 1),
+            distance_field: create_distance_field(device, false),
+            skylines: load_skylines(device, queue, mapfile)?,
+            imposter_albedo: create_imposter_texture(device, 1, "imposter_albedo"),
+            imposter_normals: create_imposter_texture(device, 1, "imposter_normals"),
+            shadow: device.create_sampler(&wgpu::SamplerDescriptor {
+                address_mode_u: wgpu::AddressMode::ClampToEdge,
+                address_mode_v: wgpu::AddressMode::ClampToEdge,
+                address_mode_w: wgpu::AddressMode::ClampToEdge,
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                mipmap_filter: wgpu::FilterMode::Nearest,
+                compare: Some(wgpu::CompareFunction::LessEqual),
+                label: Some("sampler.shadow"),
+                ..Default::default()
+            }),
+            layouts: Mutex::new(HashMap::new()),
+            views: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Replace the sampler used for mipmapped tile layers. Bind groups referencing the old sampler
+    /// must be recreated.
+    pub(crate) fn set_texture_filtering(
+        &mut self,
+        device: &wgpu::Device,
+        filtering: TextureFiltering,
+    ) {
+        self.filtered = create_filtered_sampler(
+            device,
+            filtering,
+            wgpu::AddressMode::ClampToEdge,
+            "sampler.filtered",
+        );
+        self.ground =
+            create_filtered_sampler(device, filtering, wgpu::AddressMode::Repeat, "sampler.ground");
+    }
+
+    /// Replace the ground material textures and parameters. Bind groups referencing the old
+    /// textures must be recreated.
+    pub(crate) fn set_materials(&mut self, queue: &wgpu::Queue, materials: GpuMaterials) {
+        // Keep the rendering options, which are set separately from the materials.
+        let mut materials = materials;
+        materials.uniforms.settings[2] = self.ground_materials.uniforms.settings[2];
+        materials.uniforms.parallax = self.ground_materials.uniforms.parallax;
+        self.ground_materials = materials;
+        self.views.get_mut().unwrap().clear();
+        self.write_materials(queue);
+    }
+
+    /// Enable or disable triplanar projection of the ground materials on steep slopes.
+    pub(crate) fn set_triplanar_mapping(&mut self, queue: &wgpu::Queue, enabled: bool) {
+        self.ground_materials.uniforms.settings[2] = enabled as u8 as f32;
+        self.write_materials(queue);
+    }
+
+    /// Enable parallax occlusion mapping of the ground with at most `max_steps` steps per ray, or
+    /// disable it if None.
+    pub(crate) fn set_parallax_occlusion(&mut self, queue: &wgpu::Queue, max_steps: Option<u32>) {
+        self.ground_materials.uniforms.parallax = match max_steps {
+            Some(steps) => [steps.max(1) as f32, PARALLAX_DEPTH, PARALLAX_DISTANCE, 0.0],
+            None => [0.0; 4],
+        };
+        self.write_materials(queue);
+    }
+
+    fn write_materials(&self, queue: &wgpu::Queue) {
+        let uniforms = &self.ground_materials.uniforms;
+        queue.write_buffer(&self.materials, 0, bytemuck::bytes_of(uniforms));
+    }
+
+    /// Replace the shadow map with one of the given resolution, or a placeholder if None. Bind
+    /// groups referencing the old shadow map must be recreated.
+    pub(crate) fn set_shadow_map_resolution(
+        &mut self,
+        device: &wgpu::Device,
+        resolution: Option<u32>,
+    ) {
+        self.shadow_map = create_shadow_map(device, resolution.unwrap_or(1));
+        self.views.get_mut().unwrap().clear();
+    }
+
+    /// Replace the distance field texture with a full sized one if `enabled`, or a placeholder
+    /// otherwise. Bind groups referencing the old texture must be recreated.
+    /// Load the atmosphere lookup tables again after they have been generated. Bind groups that
+    /// use them must be recreated afterwards.
+    pub(crate) fn reload_atmosphere(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mapfile: &MapFile,
+    ) -> Result<(), anyhow::Error> {
+        self.transmittance = load_atmosphere_table(
+            device,
+            queue,
+            mapfile,
+            "transmittance",
+            wgpu::TextureDimension::D2,
+        )?;
+        self.inscattering = load_atmosphere_table(
+            device,
+            queue,
+            mapfile,
+            "inscattering",
+            wgpu::TextureDimension::D3,
+        )?;
+        self.views.get_mut().unwrap().clear();
+        Ok(())
+    }
+
+    pub(crate) fn set_distance_field(&mut self, device: &wgpu::Device, enabled: bool) {
+        self.distance_field = create_distance_field(device, enabled);
+        self.views.get_mut().unwrap().clear();
+    }
+
+    /// Replace the planet imposter textures with ones of the given resolution, or placeholders if
+    /// None. Bind groups referencing the old textures must be recreated.
+    pub(crate) fn set_imposter_resolution(
+        &mut self,
+        device: &wgpu::Device,
+        resolution: Option<u32>,
+    ) {
+        let resolution = resolution.unwrap_or(1);
+        self.imposter_albedo = create_imposter_texture(device, resolution, "imposter_albedo");
+        self.imposter_normals = create_imposter_texture(device, resolution, "imposter_normals");
+        self.views.get_mut().unwrap().clear();
+    }
+
+    /// Create a view of the texture bound to shader images called `name`.
+    fn create_view(&self, name: &str) -> wgpu::TextureView {
+        match name {
+            "noise" => &self.noise,
+            "sky" => &self.sky,
+            "transmittance" => &self.transmittance,
+            "inscattering" => &self.inscattering,
+            "aerial_perspective" => &self.aerial_perspective,
+            name if self.tile_layers.contains_key(name) => &self.tile_cache[self.tile_layers[name]],
+            "grass_canopy" => &self.texture_cache[SingularLayerType::GrassCanopy],
+            "bc4_staging" => &self.bc4_staging,
+            "bc5_staging" => &self.bc5_staging,
+            "shadow_map" => &self.shadow_map,
+            "distance_field" => &self.distance_field,
+            "skylines" => &self.skylines,
+            "ground_albedo" => &self.ground_materials.albedo,
+            "ground_normals" => &self.ground_materials.normals,
+            "imposter_albedo" => &self.imposter_albedo,
+            "imposter_normals" => &self.imposter_normals,
+            _ => unreachable!("unrecognized image: {}", name),
+        }
+        .create_view(&wgpu::TextureViewDescriptor {
+            label: Some(&format!("view.{}", name)),
+            // Material arrays may have a single layer.
+            dimension: match name {
+                "ground_albedo" | "ground_normals" => Some(wgpu::TextureViewDimension::D2Array),
+                _ => None,
+            },
+            ..Default::default()
         })
     }
 
@@ -158,44 +559,21 @@ impl GpuState {
         buffers: HashMap<Cow<str>, (bool, wgpu::BindingResource)>,
         image_views: HashMap<Cow<str>, wgpu::TextureView>,
         group_name: &str,
-    ) -> (wgpu::BindGroup, wgpu::BindGroupLayout) {
+    ) -> (wgpu::BindGroup, Arc<wgpu::BindGroupLayout>) {
         let mut layout_descriptor_entries = shader.layout_descriptor().entries.to_vec();
 
         let mut buffers = buffers;
-        let mut image_views = image_views;
-        //let mut samplers = HashMap::new();
+        let mut views = self.views.lock().unwrap();
+        let mut global_views = HashMap::new();
         for (name, layout) in shader.desc_names().iter().zip(layout_descriptor_entries.iter()) {
             let name = &**name.as_ref().unwrap();
             match layout.ty {
                 wgpu::BindingType::StorageTexture { .. } | wgpu::BindingType::Texture { .. } => {
                     if !image_views.contains_key(name) {
-                        image_views.insert(
-                            name.into(),
-                            match name {
-                                "noise" => &self.noise,
-                                "sky" => &self.sky,
-                                "transmittance" => &self.transmittance,
-                                "inscattering" => &self.inscattering,
-                                "aerial_perspective" => &self.aerial_perspective,
-                                "displacements" => &self.tile_cache[LayerType::Displacements],
-                                "albedo" => &self.tile_cache[LayerType::Albedo],
-                                "roughness" => &self.tile_cache[LayerType::Roughness],
-                                "normals" => &self.tile_cache[LayerType::Normals],
-                                "heightmaps" => &self.tile_cache[LayerType::Heightmaps],
-                                "grass_canopy" => {
-                                    &self.texture_cache[SingularLayerType::GrassCanopy]
-                                }
-                                "bc4_staging" => &self.bc4_staging,
-                                "bc5_staging" => &self.bc5_staging,
-                                _ => unreachable!("unrecognized image: {}", name),
-                            }
-                            .create_view(
-                                &wgpu::TextureViewDescriptor {
-                                    label: Some(&format!("view.{}", name)),
-                                    ..Default::default()
-                                },
-                            ),
-                        );
+                        let view = views
+                            .entry(name.to_owned())
+                            .or_insert_with(|| Arc::new(self.create_view(name)));
+                        global_views.insert(name, Arc::clone(view));
                     }
                 }
                 wgpu::BindingType::Buffer { .. } => {
@@ -205,6 +583,7 @@ impl GpuState {
                             "grass_storage" => &self.mesh_cache[MeshType::Grass].storage,
                             "nodes" => &self.node_buffer,
                             "globals" => &self.globals,
+                            "materials" => &self.materials,
                             _ => unreachable!("unrecognized storage buffer: {}", name),
                         };
                         let resource = wgpu::BindingResource::Buffer(wgpu::BufferBinding {
@@ -218,14 +597,19 @@ impl GpuState {
                 wgpu::BindingType::Sampler { .. } => {}
             }
         }
+        drop(views);
 
+        let view = |name: &str| match image_views.get(name) {
+            Some(view) => view,
+            None => &*global_views[name],
+        };
         let mut bindings = Vec::new();
         for (name, layout) in shader.desc_names().iter().zip(layout_descriptor_entries.iter_mut()) {
             let name = &**name.as_ref().unwrap();
             bindings.push(wgpu::BindGroupEntry {
                 binding: layout.binding,
                 resource: match layout.ty {
-                    wgpu::BindingType::Sampler { ref mut filtering, .. } => {
+                    wgpu::BindingType::Sampler { ref mut filtering, ref mut comparison } => {
                         wgpu::BindingResource::Sampler(match name {
                             "nearest" => {
                                 *filtering = false;
@@ -233,20 +617,28 @@ impl GpuState {
                             }
                             "linear" => &self.linear,
                             "linear_wrap" => &self.linear_wrap,
+                            "filtered" => &self.filtered,
+                            "ground" => &self.ground,
+                            "shadow" => {
+                                *comparison = true;
+                                &self.shadow
+                            }
                             _ => unreachable!("unrecognized sampler: {}", name),
                         })
                     }
                     wgpu::BindingType::StorageTexture { .. } => {
-                        wgpu::BindingResource::TextureView(&image_views[name])
+                        wgpu::BindingResource::TextureView(view(name))
                     }
                     wgpu::BindingType::Texture { ref mut sample_type, .. } => {
                         match name {
-                            "transmittance" | "inscattering" | "heightmaps" | "displacements" => {
+                            "transmittance" | "inscattering" | "heightmaps" | "displacements"
+                            | "hiz" | "hiz_in" | "skylines" => {
                                 *sample_type = wgpu::TextureSampleType::Float { filterable: false }
                             }
+                            "shadow_map" => *sample_type = wgpu::TextureSampleType::Depth,
                             _ => {}
                         }
-                        wgpu::BindingResource::TextureView(&image_views[name])
+                        wgpu::BindingResource::TextureView(view(name))
                     }
                     wgpu::BindingType::Buffer { ref mut has_dynamic_offset, .. } => {
                         let (d, ref buf) = buffers[name];
@@ -257,10 +649,16 @@ impl GpuState {
             });
         }
 
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &layout_descriptor_entries,
-            label: Some(&format!("layout.{}", group_name)),
-        });
+        let bind_group_layout = Arc::clone(
+            self.layouts.lock().unwrap().entry(layout_descriptor_entries).or_insert_with_key(
+                |entries| {
+                    Arc::new(device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                        entries,
+                        label: Some(&format!("layout.{}", group_name)),
+                    }))
+                },
+            ),
+        );
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &bind_group_layout,
             entries: &*bindings,
@@ -0,0 +1,157 @@
+use crate::coordinates;
+use anyhow::{ensure, Error};
+use cgmath::{InnerSpace, Vector3};
+use fnv::FnvHashMap;
+use maplit::hashmap;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Maximum number of holes that can be registered at once. Must match terrain.frag.
+const MAX_HOLES: usize = 64;
+/// Maximum number of vertices across all registered holes. Must match terrain.frag.
+const MAX_HOLE_VERTICES: usize = 1024;
+
+/// Identifies a polygon registered with `Terrain::add_hole`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TerrainHoleId(u64);
+
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct GpuHole {
+    center: [f32; 3],
+    radius: f32,
+    east: [f32; 3],
+    first_vertex: u32,
+    north: [f32; 3],
+    num_vertices: u32,
+}
+unsafe impl bytemuck::Pod for GpuHole {}
+unsafe impl bytemuck::Zeroable for GpuHole {}
+
+struct Hole {
+    /// Point on the planet surface at the middle of the polygon.
+    center: Vector3<f64>,
+    /// Unit vectors spanning the plane tangent to the surface at `center`.
+    east: Vector3<f64>,
+    north: Vector3<f64>,
+    /// Distance from the center to the furthest vertex.
+    radius: f64,
+    /// Vertices projected onto the tangent plane, in meters.
+    vertices: Vec<[f32; 2]>,
+}
+
+/// Polygons in which the terrain is not drawn. Each one is projected onto the plane tangent to the
+/// planet at its center and extruded vertically, so it removes terrain at any elevation.
+pub(crate) struct TerrainHoles {
+    holes: FnvHashMap<TerrainHoleId, Hole>,
+    next_id: u64,
+    buffer: wgpu::Buffer,
+}
+impl TerrainHoles {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self { holes: FnvHashMap::default(), next_id: 0, buffer: Self::create_buffer(device) }
+    }
+
+    fn create_buffer(device: &wgpu::Device) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            size: (16
+                + std::mem::size_of::<GpuHole>() * MAX_HOLES
+                + std::mem::size_of::<[f32; 2]>() * MAX_HOLE_VERTICES) as u64,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::STORAGE,
+            label: Some("buffer.holes"),
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Move the GPU copy of the holes to `device`. The holes themselves are kept, and are
+    /// uploaded again before the next frame is drawn.
+    pub fn recreate_buffer(&mut self, device: &wgpu::Device) {
+        self.buffer = Self::create_buffer(device);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.holes.is_empty()
+    }
+
+    pub fn add(&mut self, polygon: &[(f64, f64)]) -> Result<TerrainHoleId, Error> {
+        ensure!(polygon.len() >= 3, "hole polygons need at least three vertices");
+        ensure!(self.holes.len() < MAX_HOLES, "at most {} holes are supported", MAX_HOLES);
+        let num_vertices = self.holes.values().map(|h| h.vertices.len()).sum::<usize>();
+        ensure!(
+            num_vertices + polygon.len() <= MAX_HOLE_VERTICES,
+            "at most {} hole vertices are supported",
+            MAX_HOLE_VERTICES
+        );
+
+        let points: Vec<_> = polygon
+            .iter()
+            .map(|&(lat, long)| coordinates::polar_to_ecef(Vector3::new(lat, long, 0.0)))
+            .collect();
+        let up = points.iter().fold(Vector3::new(0.0, 0.0, 0.0), |a, &p| a + p).normalize();
+        let center = up * coordinates::PLANET_RADIUS;
+        let east = if up.z.abs() < 0.999 {
+            Vector3::unit_z().cross(up).normalize()
+        } else {
+            Vector3::unit_x()
+        };
+        let north = up.cross(east);
+
+        let vertices: Vec<_> = points
+            .iter()
+            .map(|&p| [(p - center).dot(east) as f32, (p - center).dot(north) as f32])
+            .collect();
+        let radius =
+            vertices.iter().map(|v| f64::hypot(v[0] as f64, v[1] as f64)).fold(0.0, f64::max);
+
+        let id = TerrainHoleId(self.next_id);
+        self.next_id += 1;
+        self.holes.insert(id, Hole { center, east, north, radius, vertices });
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: TerrainHoleId) {
+        self.holes.remove(&id);
+    }
+
+    /// Write the holes to the GPU, with positions relative to `camera` to match the positions
+    /// used by the terrain shaders.
+    pub fn upload(&self, queue: &wgpu::Queue, camera: mint::Point3<f64>) {
+        let camera = Vector3::new(camera.x, camera.y, camera.z);
+        let to_f32 = |v: Vector3<f64>| [v.x as f32, v.y as f32, v.z as f32];
+
+        let mut holes = Vec::new();
+        let mut vertices = Vec::new();
+        for hole in self.holes.values() {
+            holes.push(GpuHole {
+                center: to_f32(hole.center - camera),
+                radius: hole.radius as f32,
+                east: to_f32(hole.east),
+                first_vertex: vertices.len() as u32,
+                north: to_f32(hole.north),
+                num_vertices: hole.vertices.len() as u32,
+            });
+            vertices.extend_from_slice(&hole.vertices);
+        }
+
+        let header = [holes.len() as u32, 0, 0, 0];
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&header));
+        queue.write_buffer(&self.buffer, 16, bytemuck::cast_slice(&holes));
+        queue.write_buffer(
+            &self.buffer,
+            16 + (std::mem::size_of::<GpuHole>() * MAX_HOLES) as u64,
+            bytemuck::cast_slice(&vertices),
+        );
+    }
+
+    /// Buffers to pass when creating bind groups for shaders that test against the holes.
+    pub fn bindings(&self) -> HashMap<Cow<str>, (bool, wgpu::BindingResource)> {
+        hashmap![
+            "holes".into() => (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer: &self.buffer,
+                offset: 0,
+                size: None,
+            })),
+        ]
+    }
+}
@@ -0,0 +1,203 @@
+use crate::cache::{LayerType, UnifiedPriorityCache};
+use crate::coordinates::PLANET_RADIUS;
+use crate::generate::ComputeShader;
+use crate::gpu_state::GpuState;
+use crate::terrain::quadtree::node::VNode;
+use cgmath::{EuclideanSpace, InnerSpace, Point3};
+use std::collections::HashMap;
+
+/// Resolution of each face of the imposter, matching the interior of the root tiles.
+const RESOLUTION: u32 = 512;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct GenImposterUniforms {
+    /// Slot of the root tile of each face.
+    slots: [[i32; 4]; 2],
+    texture_origin: f32,
+    texture_ratio: f32,
+    resolution: u32,
+    padding: u32,
+}
+unsafe impl bytemuck::Zeroable for GenImposterUniforms {}
+unsafe impl bytemuck::Pod for GenImposterUniforms {}
+
+/// A coarse cube map of albedo, roughness and normals for the whole planet, drawn instead of the
+/// terrain when the camera is far enough away that individual tiles can no longer be made out.
+///
+/// The imposter is generated once from the root tiles, after which no tiles need to be streamed
+/// or drawn while it is in use.
+pub(crate) struct PlanetImposter {
+    /// Altitude above sea level in meters above which the imposter is drawn.
+    altitude: f64,
+    shader: rshader::ShaderSet,
+    bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    generate: ComputeShader<GenImposterUniforms>,
+    generated: bool,
+}
+impl PlanetImposter {
+    pub fn new(device: &wgpu::Device, gpu_state: &mut GpuState, altitude: f64) -> Self {
+        gpu_state.set_imposter_resolution(device, Some(RESOLUTION));
+        Self {
+            altitude,
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "sky.vert", "declarations.glsl"),
+                rshader::shader_source!(
+                    "shaders",
+                    "imposter.frag",
+                    "declarations.glsl",
+                    "pbr.glsl",
+                    "atmosphere.glsl"
+                ),
+            )
+            .unwrap(),
+            bindgroup_pipeline: None,
+            generate: Self::create_generate_shader(),
+            generated: false,
+        }
+    }
+
+    fn create_generate_shader() -> ComputeShader<GenImposterUniforms> {
+        ComputeShader::new(
+            rshader::shader_source!("shaders", "gen-imposter.comp", "declarations.glsl"),
+            "gen-imposter".to_string(),
+        )
+    }
+
+    pub fn set_altitude(&mut self, altitude: f64) {
+        self.altitude = altitude;
+    }
+
+    /// Must be called after `gpu_state` is replaced, since the imposter textures and every bind
+    /// group referencing them are lost with it.
+    pub fn reset(&mut self, device: &wgpu::Device, gpu_state: &mut GpuState) {
+        gpu_state.set_imposter_resolution(device, Some(RESOLUTION));
+        self.bindgroup_pipeline = None;
+        self.generate = Self::create_generate_shader();
+        self.generated = false;
+    }
+
+    /// Whether the imposter should be drawn in place of the terrain for a camera at `camera`.
+    pub fn active(&self, camera: mint::Point3<f64>) -> bool {
+        self.generated && Point3::from(camera).to_vec().magnitude() - PLANET_RADIUS > self.altitude
+    }
+
+    /// Generate the imposter if that hasn't happened yet and all of the root tiles are loaded.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        gpu_state: &GpuState,
+        cache: &UnifiedPriorityCache,
+    ) {
+        if self.generate.refresh() {
+            self.generated = false;
+        }
+        if self.generated {
+            return;
+        }
+
+        let mask = LayerType::Albedo.bit_mask()
+            | LayerType::Roughness.bit_mask()
+            | LayerType::Normals.bit_mask();
+        let mut slots = [[0; 4]; 2];
+        for (i, root) in VNode::roots().iter().enumerate() {
+            if !cache.tiles.contains_all(*root, mask) {
+                return;
+            }
+            slots[i / 4][i % 4] = cache.tiles.get_slot(*root).unwrap() as i32;
+        }
+
+        let texture_resolution = cache.tile_desc(LayerType::Albedo).texture_resolution;
+        let texture_border = cache.tile_desc(LayerType::Albedo).texture_border_size;
+        self.generate.run(
+            device,
+            encoder,
+            gpu_state,
+            ((RESOLUTION + 7) / 8, (RESOLUTION + 7) / 8, 6),
+            &GenImposterUniforms {
+                slots,
+                texture_origin: texture_border as f32 / texture_resolution as f32,
+                texture_ratio: (texture_resolution - 2 * texture_border) as f32
+                    / texture_resolution as f32,
+                resolution: RESOLUTION,
+                padding: 0,
+            },
+        );
+        self.generated = true;
+    }
+
+    /// Create the render pipeline if needed. Must be called before `render`.
+    pub fn prepare(&mut self, device: &wgpu::Device, gpu_state: &GpuState) {
+        if self.shader.refresh() {
+            self.bindgroup_pipeline = None;
+        }
+        if self.bindgroup_pipeline.is_some() {
+            return;
+        }
+
+        let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+            device,
+            &self.shader,
+            HashMap::new(),
+            HashMap::new(),
+            "imposter",
+        );
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: [&*bind_group_layout][..].into(),
+                push_constant_ranges: &[],
+                label: Some("pipeline.imposter.layout"),
+            });
+        self.bindgroup_pipeline = Some((
+            bind_group,
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                layout: Some(&render_pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.imposter.vertex"),
+                        source: wgpu::ShaderSource::SpirV(self.shader.vertex().into()),
+                        flags: wgpu::ShaderFlags::VALIDATION,
+                    }),
+                    entry_point: "main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.imposter.fragment"),
+                        source: wgpu::ShaderSource::SpirV(self.shader.fragment().into()),
+                        flags: wgpu::ShaderFlags::VALIDATION,
+                    }),
+                    entry_point: "main",
+                    targets: &[wgpu::ColorTargetState {
+                        format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                        blend: Some(wgpu::BlendState {
+                            color: wgpu::BlendComponent::REPLACE,
+                            alpha: wgpu::BlendComponent::REPLACE,
+                        }),
+                        write_mask: wgpu::ColorWrite::ALL,
+                    }],
+                }),
+                primitive: Default::default(),
+                // The fragment shader writes the depth of the planet surface, so that the sky is
+                // only drawn around it.
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: wgpu::TextureFormat::Depth32Float,
+                    depth_compare: wgpu::CompareFunction::Greater,
+                    depth_write_enabled: true,
+                    bias: Default::default(),
+                    stencil: Default::default(),
+                }),
+                multisample: Default::default(),
+                label: Some("pipeline.imposter"),
+            }),
+        ));
+    }
+
+    pub fn render<'a>(&'a self, rpass: &mut wgpu::RenderPass<'a>) {
+        let (bind_group, pipeline) = self.bindgroup_pipeline.as_ref().unwrap();
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+}
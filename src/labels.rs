@@ -0,0 +1,325 @@
+use crate::coordinates::{self, Horizon};
+use crate::places::{Place, PlaceKind};
+use cgmath::{InnerSpace, Matrix4, Vector3};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::mem;
+
+/// Maximum number of places tested for occlusion each frame. The closest places are kept.
+const MAX_LABELS: usize = 4096;
+/// Height in meters above the terrain that labels are anchored at, so that they aren't hidden
+/// by the ground they sit on.
+const LABEL_HEIGHT: f64 = 20.0;
+
+/// Where to draw the label of a place, returned by `Terrain::label_anchors`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelAnchor {
+    pub place: Place,
+    /// Position in pixels from the top left corner of the frame.
+    pub position: mint::Point2<f32>,
+    /// Distance in meters from the camera.
+    pub distance: f32,
+    /// Whether terrain closer to the camera covers the anchor.
+    pub occluded: bool,
+}
+
+/// Layout must match `Anchor` in label-occlusion.comp.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct GpuAnchor {
+    pixel: [i32; 2],
+    depth: f32,
+    padding: u32,
+}
+unsafe impl bytemuck::Zeroable for GpuAnchor {}
+unsafe impl bytemuck::Pod for GpuAnchor {}
+
+fn buffer_binding(buffer: &wgpu::Buffer) -> wgpu::BindingResource {
+    wgpu::BindingResource::Buffer(wgpu::BufferBinding { buffer, offset: 0, size: None })
+}
+
+/// Project `places` onto a frame of `frame_size` pixels drawn with `view_proj` from `camera`,
+/// returning those in front of the camera and inside the frame along with their depth, closest
+/// first. `elevation` gives the height of the rendered terrain at a latitude and longitude.
+///
+/// Places hidden by the curvature of the planet are dropped here, while those behind terrain are
+/// left for the GPU occlusion test.
+pub(crate) fn project<'a>(
+    places: impl Iterator<Item = &'a Place>,
+    elevation: impl Fn(f64, f64) -> f32,
+    view_proj: Matrix4<f32>,
+    camera: Vector3<f64>,
+    frame_size: (u32, u32),
+) -> Vec<(LabelAnchor, f32)> {
+    let horizon = Horizon::new(mint::Point3 { x: camera.x, y: camera.y, z: camera.z });
+    let mut anchors: Vec<_> = places
+        .filter_map(|place| {
+            let mut ground = elevation(place.latitude, place.longitude);
+            if let (PlaceKind::Peak, Some(summit)) = (place.kind, place.elevation) {
+                ground = ground.max(summit);
+            }
+            let position = coordinates::polar_to_ecef(Vector3::new(
+                place.latitude,
+                place.longitude,
+                ground as f64 + LABEL_HEIGHT,
+            ));
+            if horizon.is_occluded(mint::Point3 { x: position.x, y: position.y, z: position.z }) {
+                return None;
+            }
+
+            let relative = position - camera;
+            let clip = view_proj * relative.cast::<f32>().unwrap().extend(1.0);
+            if clip.w <= 0.0 {
+                return None;
+            }
+            let ndc = clip.truncate() / clip.w;
+            if ndc.x.abs() > 1.0 || ndc.y.abs() > 1.0 {
+                return None;
+            }
+
+            let anchor = LabelAnchor {
+                place: place.clone(),
+                position: mint::Point2 {
+                    x: (ndc.x * 0.5 + 0.5) * frame_size.0 as f32,
+                    y: (0.5 - ndc.y * 0.5) * frame_size.1 as f32,
+                },
+                distance: relative.magnitude() as f32,
+                occluded: false,
+            };
+            Some((anchor, ndc.z))
+        })
+        .collect();
+    anchors.sort_by(|a, b| a.0.distance.partial_cmp(&b.0.distance).unwrap());
+    anchors.truncate(MAX_LABELS);
+    anchors
+}
+
+/// Tests label anchors against the depth buffer on the GPU. Results are read back
+/// asynchronously, so the anchors returned lag behind the frame being drawn by at least one
+/// frame.
+pub(crate) struct PlaceLabels {
+    shader: rshader::ShaderSet,
+    pipeline: Option<(wgpu::BindGroupLayout, wgpu::ComputePipeline)>,
+    pending: Option<BoxFuture<'static, Result<(Vec<LabelAnchor>, wgpu::Buffer), ()>>>,
+    anchors: Vec<LabelAnchor>,
+}
+impl PlaceLabels {
+    pub fn new() -> Self {
+        Self {
+            shader: rshader::ShaderSet::compute_only(rshader::shader_source!(
+                "shaders",
+                "label-occlusion.comp",
+                "declarations.glsl"
+            ))
+            .unwrap(),
+            pipeline: None,
+            pending: None,
+            anchors: Vec::new(),
+        }
+    }
+
+    /// Anchors from the most recent occlusion test to complete.
+    pub fn anchors(&self) -> &[LabelAnchor] {
+        &self.anchors
+    }
+
+    /// Pick up the results of the previous occlusion test if it has completed, and if so start a
+    /// new one for `anchors` against `depth_buffer`. The depth texture must have been created
+    /// with `TextureUsage::SAMPLED`.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        depth_buffer: &wgpu::TextureView,
+        anchors: Vec<(LabelAnchor, f32)>,
+    ) {
+        if let Some(result) = self.pending.as_mut().and_then(|p| p.now_or_never()) {
+            self.pending = None;
+            if let Ok((mut anchors, buffer)) = result {
+                {
+                    let mapped = buffer.slice(..).get_mapped_range();
+                    let occluded: &[u32] = bytemuck::cast_slice(&mapped);
+                    for (anchor, &o) in anchors.iter_mut().zip(occluded) {
+                        anchor.occluded = o != 0;
+                    }
+                }
+                buffer.unmap();
+                self.anchors = anchors;
+            }
+        }
+        if self.pending.is_some() {
+            return;
+        }
+        if anchors.is_empty() {
+            self.anchors.clear();
+            return;
+        }
+        if self.shader.refresh() {
+            self.pipeline = None;
+        }
+
+        let gpu_anchors: Vec<GpuAnchor> = anchors
+            .iter()
+            .map(|(a, depth)| GpuAnchor {
+                pixel: [a.position.x as i32, a.position.y as i32],
+                depth: *depth,
+                padding: 0,
+            })
+            .collect();
+        let anchors: Vec<LabelAnchor> = anchors.into_iter().map(|(a, _)| a).collect();
+
+        let buffer = |name: &str, contents: &[u8], usage: wgpu::BufferUsage| {
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                size: contents.len().max(16) as u64,
+                usage: usage | wgpu::BufferUsage::COPY_DST,
+                label: Some(&format!("buffer.labels.{}", name)),
+                mapped_at_creation: false,
+            });
+            queue.write_buffer(&buffer, 0, contents);
+            buffer
+        };
+        let uniforms = [gpu_anchors.len() as u32, 0, 0, 0];
+        let uniform_buffer =
+            buffer("uniforms", bytemuck::bytes_of(&uniforms), wgpu::BufferUsage::UNIFORM);
+        let anchor_buffer =
+            buffer("anchors", bytemuck::cast_slice(&gpu_anchors), wgpu::BufferUsage::STORAGE);
+        let occluded_size = (anchors.len().max(4) * mem::size_of::<u32>()) as u64;
+        let occluded_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: occluded_size,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_SRC,
+            label: Some("buffer.labels.occluded"),
+            mapped_at_creation: false,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            size: occluded_size,
+            usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+            label: Some("buffer.labels.readback"),
+            mapped_at_creation: false,
+        });
+
+        // The depth buffer is owned by the application so the bind group is built directly
+        // rather than through `bind_group_for_shader`.
+        if self.pipeline.is_none() {
+            let mut entries = self.shader.layout_descriptor().entries.to_vec();
+            for (name, entry) in self.shader.desc_names().iter().zip(&mut entries) {
+                if let wgpu::BindingType::Texture { ref mut sample_type, .. } = entry.ty {
+                    assert_eq!(name.as_deref(), Some("depth"));
+                    *sample_type = wgpu::TextureSampleType::Depth;
+                }
+            }
+            let bind_group_layout =
+                device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: &entries,
+                    label: Some("layout.labels"),
+                });
+            let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.labels.layout"),
+                })),
+                module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some("shader.labels"),
+                    source: wgpu::ShaderSource::SpirV(self.shader.compute().into()),
+                    flags: wgpu::ShaderFlags::empty(),
+                }),
+                entry_point: "main",
+                label: Some("pipeline.labels"),
+            });
+            self.pipeline = Some((bind_group_layout, pipeline));
+        }
+        let (bind_group_layout, pipeline) = self.pipeline.as_ref().unwrap();
+
+        let bindings: Vec<_> = self
+            .shader
+            .desc_names()
+            .iter()
+            .zip(self.shader.layout_descriptor().entries.iter())
+            .map(|(name, entry)| wgpu::BindGroupEntry {
+                binding: entry.binding,
+                resource: match name.as_deref().unwrap() {
+                    "ubo" => buffer_binding(&uniform_buffer),
+                    "anchors" => buffer_binding(&anchor_buffer),
+                    "occluded" => buffer_binding(&occluded_buffer),
+                    "depth" => wgpu::BindingResource::TextureView(depth_buffer),
+                    name => unreachable!("unrecognized binding: {}", name),
+                },
+            })
+            .collect();
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: bind_group_layout,
+            entries: &*bindings,
+            label: Some("bindgroup.labels"),
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.labels"),
+        });
+        {
+            let mut cpass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            cpass.set_pipeline(pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch((anchors.len() as u32 + 63) / 64, 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&occluded_buffer, 0, &readback, 0, occluded_size);
+        queue.submit(Some(encoder.finish()));
+
+        self.pending = Some(
+            readback
+                .slice(..)
+                .map_async(wgpu::MapMode::Read)
+                .then(move |result| {
+                    futures::future::ready(match result {
+                        Ok(()) => Ok((anchors, readback)),
+                        Err(_) => Err(()),
+                    })
+                })
+                .boxed(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Point3};
+
+    #[test]
+    fn project_label_anchors() {
+        let place = |name: &str, latitude: f64, longitude: f64| Place {
+            name: name.to_owned(),
+            kind: PlaceKind::City,
+            latitude: latitude.to_radians(),
+            longitude: longitude.to_radians(),
+            elevation: None,
+            population: 0,
+        };
+        let places = vec![
+            place("center", 0.0, 0.0),
+            place("east", 0.0, 0.05),
+            place("behind", 0.0, 180.0),
+            place("outside", 10.0, 0.0),
+        ];
+
+        // Look straight down at latitude and longitude zero from 100 km up.
+        let camera = Vector3::new(coordinates::PLANET_RADIUS + 100000.0, 0.0, 0.0);
+        let view = Matrix4::look_at_rh(
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(-1.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, 1.0),
+        );
+        let proj = cgmath::perspective(Deg(60.0f32), 1.0, 1.0, 1.0e7);
+        let view_proj = proj * view.cast::<f32>().unwrap();
+
+        let anchors = project(places.iter(), |_, _| 0.0, view_proj, camera, (800, 800));
+        let names: Vec<_> = anchors.iter().map(|(a, _)| a.place.name.as_str()).collect();
+        assert_eq!(names, vec!["center", "east"]);
+
+        let center = &anchors[0].0;
+        assert!((center.position.x - 400.0).abs() < 0.5 && (center.position.y - 400.0).abs() < 0.5);
+        assert!((center.distance as f64 - (100000.0 - LABEL_HEIGHT)).abs() < 1.0);
+        // Longitude increases towards +Y, which is to the right when looking down with +Z up.
+        assert!(anchors[1].0.position.x > 400.0);
+    }
+}
@@ -1,4 +1,16 @@
 //! Terra is a large scale terrain generation and rendering library built on top of wgpu.
+//!
+//! Rendering lives behind the `render` feature, which is enabled by default. Without it only the
+//! core of the crate is built: map files, tile generation, and CPU terrain queries through
+//! `HeadlessTerrain`. This lets dedicated servers answer terrain queries without linking wgpu or
+//! shaderc.
+//!
+//! Diagnostics are emitted through the `log` crate under a few stable targets, so applications can
+//! tune their verbosity separately (for instance `RUST_LOG=terra::store=debug` with env_logger):
+//!
+//! - `terra::stream`: tiles requested from and returned by the background streaming thread.
+//! - `terra::gen`: tiles generated on the GPU and offline generation from raw datasets.
+//! - `terra::store`: reads, writes and downloads of tiles, and recovery of the tile store.
 #![cfg_attr(test, feature(test))]
 
 #[cfg(test)]
@@ -6,65 +18,563 @@ extern crate test;
 
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "render")]
 extern crate rshader;
 
+#[cfg(feature = "render")]
+mod adaptive;
+mod airports;
 mod asset;
+#[cfg(feature = "render")]
+mod authority;
+#[cfg(feature = "render")]
+mod biome;
 mod cache;
+mod camera;
+pub mod controller;
 mod coordinates;
+mod date;
+mod deformation;
+#[cfg(feature = "render")]
+mod distance_field;
+mod download;
+#[cfg(feature = "render")]
+mod ephemeris;
+#[cfg(feature = "render")]
+mod exposure;
+mod flight;
 mod generate;
+pub mod geodesy;
+#[cfg(feature = "render")]
 mod gpu_state;
+#[cfg(feature = "render")]
+mod holes;
+#[cfg(feature = "render")]
+mod imposter;
+#[cfg(feature = "render")]
+mod labels;
 mod mapfile;
+#[cfg(feature = "render")]
+mod memory;
+#[cfg(feature = "render")]
+mod options;
+mod places;
+mod planet;
+mod query;
+#[cfg(feature = "render")]
+mod raycast;
+#[cfg(feature = "render")]
+mod reflections;
+#[cfg(feature = "render")]
+mod regions;
+#[cfg(feature = "render")]
+mod season;
+#[cfg(feature = "render")]
+mod sensor;
+#[cfg(feature = "render")]
+mod shafts;
+#[cfg(feature = "render")]
 mod sky;
+#[cfg(feature = "render")]
+mod sky_lod;
+mod skyline;
+mod spatial;
 mod srgb;
+#[cfg(feature = "render")]
 mod stream;
+#[cfg(test)]
+mod synthetic;
 pub(crate) mod terrain;
 mod utils;
+mod vector;
+#[cfg(feature = "render")]
+mod viewpoints;
+#[cfg(feature = "render")]
+mod watch;
+#[cfg(feature = "render")]
+mod weather;
+
+#[cfg(feature = "render")]
+use {
+    crate::adaptive::AdaptiveQualityController,
+    crate::airports::{self, Runways},
+    crate::authority::{SharedQuadTree, TerrainDelta},
+    crate::cache::{LayerMask, LayerParams, LayerType, MeshCacheDesc, MeshType},
+    crate::generate::{HeightmapPass, MapFileBuilder, TileGenerator},
+    crate::mapfile::{AlbedoBlend, MapFile},
+    crate::terrain::quadtree::node::VNode,
+    anyhow::Error,
+    cache::{SingularLayerDesc, SingularLayerType, TileCache, UnifiedPriorityCache},
+    cgmath::SquareMatrix,
+    distance_field::DistanceField,
+    ephemeris::Ephemeris,
+    fnv::FnvHashMap,
+    generate::ComputeShader,
+    gpu_state::{GlobalUniformBlock, GpuState},
+    holes::TerrainHoles,
+    imposter::PlanetImposter,
+    labels::PlaceLabels,
+    memory::GpuMemory,
+    raycast::BatchRaycaster,
+    reflections::WaterReflections,
+    regions::{DetailRegions, PriorityBoosts},
+    season::Season,
+    sensor::{SensorKind, SensorRenderer},
+    shafts::LightShafts,
+    sky_lod::SkyLod,
+    std::collections::HashMap,
+    std::num::NonZeroU32,
+    std::path::{Path, PathBuf},
+    std::sync::Arc,
+    std::time::Duration,
+    terrain::material::{GpuMaterials, GROUND_PERIOD},
+    terrain::quadtree::{occlusion::OcclusionCuller, QuadTree},
+    vec_map::VecMap,
+    viewpoints::StreamingViewpoints,
+    watch::ElevationWatches,
+    wgpu::util::DeviceExt,
+};
+
+#[cfg(feature = "render")]
+pub use crate::adaptive::{AdaptiveQuality, AdaptiveQualityState};
+pub use crate::airports::Runway;
+#[cfg(feature = "render")]
+pub use crate::authority::StreamingAuthority;
+#[cfg(feature = "render")]
+pub use crate::biome::Biome;
+#[cfg(feature = "render")]
+pub use crate::cache::NodeStatistics;
+pub use crate::cache::{LayerDesc, LayerGenerator, TextureFormat};
+pub use crate::camera::Camera;
+pub use crate::coordinates::{
+    normalize_lat_long, normalize_longitude, Horizon, LatLongBounds, MapProjection, UtmCoordinate,
+    WebMercator,
+};
+pub use crate::date::Date;
+pub use crate::deformation::HeightDelta;
+pub use crate::flight::{FlightPath, TerrainFollower, Waypoint};
+pub use crate::generate::{
+    FractalDetail, FractalDetailTable, TileData, TileNode, BLUE_MARBLE_URLS,
+};
+#[cfg(feature = "render")]
+pub use crate::gpu_state::TextureFiltering;
+#[cfg(feature = "render")]
+pub use crate::holes::TerrainHoleId;
+#[cfg(feature = "render")]
+pub use crate::labels::LabelAnchor;
+pub use crate::mapfile::{SyncPolicy, TileProvenance, TileStore};
+#[cfg(feature = "render")]
+pub use crate::memory::TerrainEvent;
+#[cfg(feature = "render")]
+pub use crate::options::{QualityPreset, TerrainOptions};
+pub use crate::places::{Place, PlaceKind};
+pub use crate::planet::PlanetConfig;
+pub use crate::query::{HeadlessTerrain, PathSample, TerrainMesh, TileCacheStats};
+#[cfg(feature = "render")]
+pub use crate::raycast::{Ray, RayHit};
+#[cfg(feature = "render")]
+pub use crate::reflections::ReflectionSources;
+#[cfg(feature = "render")]
+pub use crate::regions::{DetailRegionId, PriorityBoostId};
+#[cfg(feature = "render")]
+pub use crate::sensor::SensorImage;
+pub use crate::spatial::{QuadSpatialIndex, SpatialObjectId};
+#[cfg(feature = "render")]
+pub use crate::terrain::material::{GroundMaterial, LandCoverWeights, MaterialSet};
+pub use crate::terrain::raster::RasterCacheStats;
+pub use crate::vector::{VectorTileCallback, VectorTileId};
+#[cfg(feature = "render")]
+pub use crate::viewpoints::StreamingViewpointId;
+#[cfg(feature = "render")]
+pub use crate::watch::{ElevationCallback, ElevationWatchId};
+#[cfg(feature = "render")]
+pub use crate::weather::Weather;
+#[cfg(feature = "render")]
+pub use rshader;
+
+/// Additional per-pixel outputs written by `Terrain::render_with_gbuffer`. Both textures must be
+/// the size of the frame. Pixels not covered by terrain are cleared to zero.
+#[cfg(feature = "render")]
+pub struct GBuffer<'a> {
+    /// `R32Float` texture set to the distance in meters along the view direction.
+    pub linear_depth: &'a wgpu::TextureView,
+    /// `Rgba32Float` texture set to latitude and longitude in radians, height above sea level in
+    /// meters, and one in the alpha channel.
+    pub geo_position: &'a wgpu::TextureView,
+}
+
+/// What a call to `Terrain::render_internal` draws into.
+#[cfg(feature = "render")]
+#[derive(Copy, Clone)]
+enum RenderTarget<'a> {
+    /// Shaded terrain, along with the sky.
+    Color(&'a wgpu::TextureView),
+    /// Range and intensity of the terrain only. See `Terrain::render_sensor`.
+    Sensor(&'a SensorImage<'a>),
+    /// Surface temperature of the terrain only. See `Terrain::render_thermal`.
+    Thermal(&'a wgpu::TextureView),
+}
 
-use crate::cache::{LayerType, MeshCacheDesc, MeshType};
-use crate::generate::MapFileBuilder;
-use crate::mapfile::MapFile;
-use crate::terrain::quadtree::node::VNode;
-use anyhow::Error;
-use cache::{SingularLayerDesc, SingularLayerType, TextureFormat, UnifiedPriorityCache};
-use cgmath::SquareMatrix;
-use generate::ComputeShader;
-use gpu_state::{GlobalUniformBlock, GpuState};
-use std::collections::HashMap;
-use std::sync::Arc;
-use terrain::quadtree::QuadTree;
-use wgpu::util::DeviceExt;
-
-pub use crate::generate::BLUE_MARBLE_URLS;
+/// Which part of the view a call to `Terrain::render_internal` draws. See
+/// `Terrain::set_depth_partition`.
+#[cfg(feature = "render")]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum DepthPass {
+    /// Everything in a single pass.
+    Full,
+    /// Terrain beyond the partition distance, along with the sky.
+    Far,
+    /// Terrain closer than the partition distance, drawn over the results of the far pass.
+    Near,
+}
 
+#[cfg(feature = "render")]
 pub struct Terrain {
     shader: rshader::ShaderSet,
+    /// Variant of `shader` that discards fragments inside holes.
+    holes_shader: rshader::ShaderSet,
     bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
     index_buffer: wgpu::Buffer,
 
+    /// Whether to render terrain depth in a separate pass before shading.
+    depth_prepass: bool,
+    depth_prepass_pipeline: Option<wgpu::RenderPipeline>,
+
+    occlusion: Option<OcclusionCuller>,
+
+    /// Resolution of the shadow map for external shadow casters, if enabled.
+    shadow_map_resolution: Option<u32>,
+    /// Transform from world space into the clip space of the shadow map.
+    shadow_caster_view_proj: Option<mint::ColumnMatrix4<f64>>,
+
+    /// Coarse distance field of the terrain around the camera for soft shadows, if enabled.
+    distance_field: Option<DistanceField>,
+    /// Region covered by the skylines of distant mountains in the map file, if any.
+    skyline_bounds: Option<LatLongBounds>,
+
+    /// Distance at which `render_camera` splits the view into a far and a near pass, if enabled.
+    depth_partition: Option<f64>,
+
+    imposter: Option<PlanetImposter>,
+    raycaster: BatchRaycaster,
+    sensor: SensorRenderer,
+    /// Occlusion tests for place name labels, if enabled.
+    place_labels: Option<PlaceLabels>,
+    /// Screen space reflections on water, if enabled.
+    water_reflections: Option<WaterReflections>,
+    /// Created by the first call to `render_light_shafts`.
+    light_shafts: Option<LightShafts>,
+    map_projection: Option<MapProjection>,
+
+    motion_vector_shader: rshader::ShaderSet,
+    motion_vector_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+
+    gbuffer_shader: rshader::ShaderSet,
+    gbuffer_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+
     sky_shader: rshader::ShaderSet,
     sky_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    /// Sky pipeline without a depth test, for drawing into the reduced resolution sky. Shares the
+    /// bind group of `sky_bindgroup_pipeline`.
+    sky_low_resolution_pipeline: Option<wgpu::RenderPipeline>,
+    /// Reduced resolution sky near the ground, if enabled.
+    sky_lod: Option<SkyLod>,
+    /// Receives the outcome of precomputing the atmosphere lookup tables in the background, while
+    /// the sky is drawn without them.
+    atmosphere_tables: Option<crossbeam::channel::Receiver<Result<(), Error>>>,
     aerial_perspective: ComputeShader<u32>,
 
+    /// Sub-pixel offset applied to the projection of the next frame.
+    jitter: mint::Vector2<f32>,
+    /// View projection matrix and camera position from the previous frame.
+    previous_frame: Option<(mint::ColumnMatrix4<f32>, mint::Point3<f64>)>,
+    /// Valid layers of every resident tile as of the last frame.
+    resident_tiles: Vec<(VNode, LayerMask)>,
+    /// Whether `resident_tiles` changed during the last frame.
+    tiles_changed: bool,
+
+    /// Maximum allowed screen-space geometric error in pixels, or None for distance-only LOD.
+    max_screen_space_error: Option<f32>,
+    /// Projected size in pixels of one meter at a distance of one meter, from the last frame.
+    lod_pixel_scale: Option<f64>,
+    /// Used in place of the scale derived from the view-projection matrix while rendering the
+    /// tiles of a larger image, so every tile selects the same level of detail.
+    lod_pixel_scale_override: Option<f64>,
+    /// Overrides the screen-space error and grass settings to hold a target frame time.
+    adaptive_quality: Option<AdaptiveQualityController>,
+
+    tile_cache_size: usize,
+    grass_cache_size: usize,
+    /// Budget for the caches, and allocation failures caught while updating them.
+    gpu_memory: GpuMemory,
+    /// Whether albedo, roughness and normal tiles are held at half resolution.
+    compact: bool,
+    /// Whether albedo finer than the imagery is upsampled with `TerrainOptions::guided_upsampling`.
+    guided_upsampling: bool,
+    texture_filtering: TextureFiltering,
+    /// Whether ground materials use triplanar projection on steep slopes.
+    triplanar_mapping: bool,
+    /// Maximum steps of parallax occlusion mapping, or None if disabled.
+    parallax_occlusion: Option<u32>,
+    /// Ground materials last passed to `set_materials`, kept so `recreate` can load them again.
+    materials: Option<MaterialSet>,
+    /// Whether grass meshes are drawn.
+    grass: bool,
+    /// Range of EV100 that automatic exposure picks from.
+    exposure_range: (f32, f32),
+    /// Whether terrain is tinted by where its heightmap tiles came from.
+    provenance_overlay: bool,
+    /// Whether `render` draws whatever has loaded instead of waiting for the root tiles.
+    progressive_loading: bool,
+
+    elevation_watches: ElevationWatches,
+    holes: TerrainHoles,
+    detail_regions: DetailRegions,
+    priority_boosts: PriorityBoosts,
+    streaming_viewpoints: StreamingViewpoints,
+    /// Quadtree that collision heights are read from, if streaming is server-authoritative.
+    shared_quadtree: Option<SharedQuadTree>,
+    heightmap_passes: Vec<HeightmapPass>,
+    albedo_supersampling: u32,
+    runways: Arc<Runways>,
+
+    /// Date and time being rendered, if one has been set.
+    time: Option<Date>,
+    weather: Weather,
+
     gpu_state: GpuState,
     quadtree: QuadTree,
+    /// Elevation ranges recorded in the map file for nodes whose heightmaps aren't resident, so
+    /// each is only read once. None if the node has no recorded range.
+    recorded_elevation_ranges: FnvHashMap<VNode, Option<(f32, f32)>>,
     mapfile: Arc<MapFile>,
 
     cache: UnifiedPriorityCache,
 }
+#[cfg(feature = "render")]
 impl Terrain {
-    /// Create a new Terrain object.
+    /// Deepest level that the quadtree is refined to while blending towards a map projection.
+    const MAP_PROJECTION_MAX_LEVEL: u8 = VNode::LEVEL_CELL_625M;
+
+    /// Create a new Terrain object with the default options.
     pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Result<Self, Error> {
-        let mapfile = Arc::new(futures::executor::block_on(MapFileBuilder::new().build())?);
-        let cache = UnifiedPriorityCache::new(
+        Self::with_options(device, queue, TerrainOptions::default())
+    }
+
+    /// Create a new Terrain object with the given quality options.
+    pub fn with_options(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        options: TerrainOptions,
+    ) -> Result<Self, Error> {
+        Self::with_layers(device, queue, options, Vec::new())
+    }
+
+    /// Create a new Terrain object with custom tile layers in addition to the built in ones. The
+    /// layers are generated and cached alongside the others, and each may be sampled by later
+    /// generators using its name. The name "biomes" is reserved for the built in biome layer.
+    pub fn with_layers(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        options: TerrainOptions,
+        layers: Vec<LayerDesc>,
+    ) -> Result<Self, Error> {
+        let mapfile = MapFileBuilder::new(options.tile_store)
+            .with_archive(options.tile_archive.as_deref())?
+            .with_planet(options.planet)?
+            .with_layers(std::iter::once(biome::layer_desc()).chain(layers).collect())?;
+        let mapfile = Arc::new(futures::executor::block_on(mapfile.build())?);
+        let cache = Self::create_cache(
+            device,
+            &mapfile,
+            options.tile_cache_size,
+            options.grass_cache_size,
+            options.compact,
+            options.guided_upsampling,
+        )?;
+        let gpu_state = GpuState::new(device, queue, &mapfile, &cache, options.texture_filtering)?;
+
+        // Precomputing the atmosphere takes a while, so it happens in the background and the sky
+        // is drawn without the lookup tables until it finishes.
+        let atmosphere_tables = if generate::atmosphere_outdated(&mapfile)? {
+            let (sender, receiver) = crossbeam::channel::bounded(1);
+            let mapfile = Arc::clone(&mapfile);
+            std::thread::spawn(move || {
+                let _ = sender.send(generate::generate_atmosphere(&mapfile));
+            });
+            Some(receiver)
+        } else {
+            None
+        };
+
+        let quadtree = QuadTree::new(
+            cache.tile_desc(LayerType::Displacements).texture_resolution - 1,
+            mapfile.planet().drawn_range(),
+        );
+
+        let index_buffer = quadtree.create_index_buffers(device);
+
+        let shader = rshader::ShaderSet::simple(
+            rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
+            rshader::shader_source!(
+                "shaders",
+                "terrain.frag",
+                "declarations.glsl",
+                "pbr.glsl",
+                "hash.glsl",
+                "clouds.glsl";
+                "HOLES" = "0"
+            ),
+        )
+        .unwrap();
+        let holes_shader = rshader::ShaderSet::simple(
+            rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
+            rshader::shader_source!(
+                "shaders",
+                "terrain.frag",
+                "declarations.glsl",
+                "pbr.glsl",
+                "hash.glsl",
+                "clouds.glsl";
+                "HOLES" = "1"
+            ),
+        )
+        .unwrap();
+        let motion_vector_shader = rshader::ShaderSet::simple(
+            rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
+            rshader::shader_source!("shaders", "terrain-motion.frag", "declarations.glsl"),
+        )
+        .unwrap();
+        let gbuffer_shader = rshader::ShaderSet::simple(
+            rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
+            rshader::shader_source!("shaders", "terrain-gbuffer.frag", "declarations.glsl"),
+        )
+        .unwrap();
+        let sky_shader = rshader::ShaderSet::simple(
+            rshader::shader_source!("shaders", "sky.vert", "declarations.glsl"),
+            rshader::shader_source!(
+                "shaders",
+                "sky.frag",
+                "declarations.glsl",
+                "pbr.glsl",
+                "atmosphere.glsl"
+            ),
+        )
+        .unwrap();
+        let mut terrain = Self {
+            bindgroup_pipeline: None,
+            shader,
+            holes_shader,
+
+            index_buffer,
+
+            depth_prepass: false,
+            depth_prepass_pipeline: None,
+
+            occlusion: None,
+
+            shadow_map_resolution: None,
+            shadow_caster_view_proj: None,
+
+            distance_field: None,
+            skyline_bounds: mapfile.skyline_bounds()?,
+
+            depth_partition: None,
+
+            imposter: None,
+            raycaster: BatchRaycaster::new(),
+            sensor: SensorRenderer::new(device),
+            place_labels: None,
+            water_reflections: None,
+            light_shafts: None,
+            map_projection: None,
+
+            motion_vector_shader,
+            motion_vector_bindgroup_pipeline: None,
+
+            gbuffer_shader,
+            gbuffer_bindgroup_pipeline: None,
+
+            sky_shader,
+            sky_bindgroup_pipeline: None,
+            sky_low_resolution_pipeline: None,
+            sky_lod: None,
+            atmosphere_tables,
+            aerial_perspective: Self::create_aerial_perspective_shader(),
+
+            jitter: mint::Vector2 { x: 0.0, y: 0.0 },
+            previous_frame: None,
+
+            max_screen_space_error: None,
+            lod_pixel_scale: None,
+            lod_pixel_scale_override: None,
+            adaptive_quality: None,
+            resident_tiles: Vec::new(),
+            tiles_changed: true,
+
+            tile_cache_size: options.tile_cache_size,
+            grass_cache_size: options.grass_cache_size,
+            gpu_memory: GpuMemory::new(),
+            compact: options.compact,
+            guided_upsampling: options.guided_upsampling,
+            texture_filtering: options.texture_filtering,
+            triplanar_mapping: false,
+            parallax_occlusion: None,
+            materials: None,
+            grass: options.grass,
+            exposure_range: options.exposure_range,
+            provenance_overlay: false,
+            progressive_loading: false,
+
+            elevation_watches: ElevationWatches::default(),
+            holes: TerrainHoles::new(device),
+            detail_regions: DetailRegions::new(mapfile.planet().drawn_range()),
+            priority_boosts: PriorityBoosts::default(),
+            streaming_viewpoints: StreamingViewpoints::default(),
+            shared_quadtree: None,
+            heightmap_passes: Vec::new(),
+            albedo_supersampling: 1,
+            runways: Runways::load(&mapfile)?,
+            time: None,
+            weather: Weather::default(),
+
+            gpu_state,
+            quadtree,
+            recorded_elevation_ranges: FnvHashMap::default(),
+            mapfile,
+            cache,
+        };
+        terrain.set_options(device, queue, options)?;
+        Ok(terrain)
+    }
+
+    fn create_cache(
+        device: &wgpu::Device,
+        mapfile: &Arc<MapFile>,
+        tile_cache_size: usize,
+        grass_cache_size: usize,
+        compact: bool,
+        guided_upsampling: bool,
+    ) -> Result<UnifiedPriorityCache, Error> {
+        let layers = TileCache::cache_layers(
+            mapfile,
+            device.features().contains(wgpu::Features::TEXTURE_COMPRESSION_BC),
+            compact,
+        );
+        Ok(UnifiedPriorityCache::new(
             device,
-            Arc::clone(&mapfile),
-            512,
+            Arc::clone(mapfile),
+            layers.clone(),
+            tile_cache_size,
             crate::generate::generators(
-                mapfile.layers(),
+                &layers,
                 !device.features().contains(wgpu::Features::SHADER_FLOAT64),
+                mapfile.fractal_detail()?,
+                *mapfile.planet(),
+                guided_upsampling,
             ),
             vec![MeshCacheDesc {
-                size: 32,
+                size: grass_cache_size,
                 ty: MeshType::Grass,
                 max_bytes_per_entry: 128 * 128 * 32,
                 dimensions: 128 / 8,
@@ -111,37 +621,70 @@ impl Terrain {
                     ),
                     "grass-canopy".to_string(),
                 ),
-                cache_size: 32,
+                cache_size: grass_cache_size,
                 dependency_mask: LayerType::Normals.bit_mask(),
                 level: VNode::LEVEL_CELL_1M,
                 ty: SingularLayerType::GrassCanopy,
                 texture_resolution: 516,
                 texture_format: TextureFormat::RGBA8,
             }],
-        );
-        let gpu_state = GpuState::new(device, queue, &mapfile, &cache)?;
-        let quadtree =
-            QuadTree::new(cache.tile_desc(LayerType::Displacements).texture_resolution - 1);
+        ))
+    }
 
-        let index_buffer = quadtree.create_index_buffers(device);
+    /// Pipeline drawing the sky wherever nothing else was drawn, or over the whole target when
+    /// `depth_tested` is false.
+    fn create_sky_pipeline(
+        device: &wgpu::Device,
+        shader: &rshader::ShaderSet,
+        layout: &wgpu::PipelineLayout,
+        depth_tested: bool,
+    ) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some("shader.sky.vertex"),
+                    source: wgpu::ShaderSource::SpirV(shader.vertex().into()),
+                    flags: wgpu::ShaderFlags::VALIDATION,
+                }),
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                    label: Some("shader.sky.fragment"),
+                    source: wgpu::ShaderSource::SpirV(shader.fragment().into()),
+                    flags: wgpu::ShaderFlags::VALIDATION,
+                }),
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                    blend: Some(wgpu::BlendState {
+                        color: wgpu::BlendComponent::REPLACE,
+                        alpha: wgpu::BlendComponent::REPLACE,
+                    }),
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: Default::default(),
+            depth_stencil: if depth_tested {
+                Some(wgpu::DepthStencilState {
+                    format: wgpu::TextureFormat::Depth32Float,
+                    depth_compare: wgpu::CompareFunction::GreaterEqual,
+                    depth_write_enabled: false,
+                    bias: Default::default(),
+                    stencil: Default::default(),
+                })
+            } else {
+                None
+            },
+            multisample: Default::default(),
+            label: Some(if depth_tested { "pipeline.sky" } else { "pipeline.sky.low_resolution" }),
+        })
+    }
 
-        let shader = rshader::ShaderSet::simple(
-            rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
-            rshader::shader_source!("shaders", "terrain.frag", "declarations.glsl", "pbr.glsl"),
-        )
-        .unwrap();
-        let sky_shader = rshader::ShaderSet::simple(
-            rshader::shader_source!("shaders", "sky.vert", "declarations.glsl"),
-            rshader::shader_source!(
-                "shaders",
-                "sky.frag",
-                "declarations.glsl",
-                "pbr.glsl",
-                "atmosphere.glsl"
-            ),
-        )
-        .unwrap();
-        let aerial_perspective = ComputeShader::new(
+    fn create_aerial_perspective_shader() -> ComputeShader<u32> {
+        ComputeShader::new(
             rshader::shader_source!(
                 "shaders",
                 "gen-aerial-perspective.comp",
@@ -149,168 +692,1208 @@ impl Terrain {
                 "atmosphere.glsl"
             ),
             "gen-aerial-perspective".to_string(),
+        )
+    }
+
+    /// Switch to different quality options. Render pipelines affected by the change are rebuilt
+    /// when the next frame is drawn. Changing a cache size, compact mode or guided upsampling
+    /// reallocates the caches before this returns and discards all streamed tiles, so detail will
+    /// briefly drop while they are reloaded. The tile cache is kept within the budget set with
+    /// `set_gpu_memory_budget`.
+    pub fn set_options(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        options: TerrainOptions,
+    ) -> Result<(), Error> {
+        let tile_cache_size = self.fit_tile_cache(
+            device,
+            options.tile_cache_size,
+            options.grass_cache_size,
+            options.compact,
         );
+        if tile_cache_size != self.tile_cache_size
+            || options.grass_cache_size != self.grass_cache_size
+            || options.compact != self.compact
+            || options.guided_upsampling != self.guided_upsampling
+        {
+            self.tile_cache_size = tile_cache_size;
+            self.grass_cache_size = options.grass_cache_size;
+            self.compact = options.compact;
+            self.guided_upsampling = options.guided_upsampling;
+            self.recreate_cache(device, queue)?;
+        }
 
-        Ok(Self {
-            bindgroup_pipeline: None,
-            shader,
+        if options.texture_filtering != self.texture_filtering {
+            self.set_texture_filtering(device, options.texture_filtering);
+        }
+        self.set_depth_prepass(options.depth_prepass);
+        self.set_occlusion_culling(device, options.occlusion_culling);
+        self.set_shadow_receiver(device, options.shadow_map_resolution);
+        self.set_distance_field_shadows(device, options.distance_field_shadows);
+        self.set_max_screen_space_error(options.max_screen_space_error);
+        self.set_triplanar_mapping(queue, options.triplanar_mapping);
+        self.set_parallax_occlusion(queue, options.parallax_occlusion);
+        self.set_water_reflections(device, options.water_reflections);
+        self.set_sky_downsampling(device, options.sky_downsampling);
+        self.grass = options.grass;
+        self.exposure_range = options.exposure_range;
+        Ok(())
+    }
 
-            index_buffer,
+    /// Limit the GPU memory taken by the tile and grass caches to `budget` bytes, or lift the
+    /// limit with None. If the caches don't fit, the tile cache is shrunk and all tiles on the GPU
+    /// are discarded, keeping only the heightmaps held on the CPU. Materials, shadow maps and
+    /// other resources aren't counted.
+    ///
+    /// wgpu can't report how much memory an adapter has, so the budget has to come from the
+    /// application, for instance from `VK_EXT_memory_budget` or `QueryVideoMemoryInfo`. Raising
+    /// the budget doesn't grow the cache again until the next call to `set_options`.
+    pub fn set_gpu_memory_budget(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        budget: Option<u64>,
+    ) -> Result<(), Error> {
+        self.gpu_memory.set_budget(budget);
+        let tile_cache_size =
+            self.fit_tile_cache(device, self.tile_cache_size, self.grass_cache_size, self.compact);
+        if tile_cache_size != self.tile_cache_size {
+            self.resize_tile_cache(device, queue, tile_cache_size)?;
+        }
+        Ok(())
+    }
 
-            sky_shader,
-            sky_bindgroup_pipeline: None,
-            aerial_perspective,
+    /// Events that happened since the last call, in the order they happened.
+    pub fn poll_events(&mut self) -> Vec<TerrainEvent> {
+        self.gpu_memory.take_events()
+    }
 
-            gpu_state,
-            quadtree,
-            mapfile,
-            cache,
-        })
+    /// Largest tile cache no bigger than `tile_cache_size` that fits within the GPU memory budget.
+    fn fit_tile_cache(
+        &self,
+        device: &wgpu::Device,
+        tile_cache_size: usize,
+        grass_cache_size: usize,
+        compact: bool,
+    ) -> usize {
+        let layers = self.cache_layers(device, compact);
+        self.gpu_memory.fit_tile_cache(&layers, tile_cache_size, grass_cache_size)
     }
 
-    fn loading_complete(&self) -> bool {
-        VNode::roots().iter().copied().all(|root| {
-            self.cache.tiles.contains(root, LayerType::Heightmaps)
-                && self.cache.tiles.contains(root, LayerType::Albedo)
-                && self.cache.tiles.contains(root, LayerType::Roughness)
-        })
+    fn cache_layers(&self, device: &wgpu::Device, compact: bool) -> VecMap<LayerParams> {
+        TileCache::cache_layers(
+            &self.mapfile,
+            device.features().contains(wgpu::Features::TEXTURE_COMPRESSION_BC),
+            compact,
+        )
     }
 
-    /// Returns whether initial map file streaming has completed for tiles in the vicinity of
-    /// `camera`.
-    ///
-    /// Terra cannot render any terrain until all root tiles have been downloaded and streamed to
-    /// the GPU. This function returns whether tohse tiles have been streamed, and also initiates
-    /// streaming of more detailed tiles for the indicated tile position.
-    pub fn poll_loading_status(
+    /// Recreate the tile cache with `tile_cache_size` slots, keeping the tiles held on the CPU.
+    fn resize_tile_cache(
         &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        camera: mint::Point3<f64>,
-    ) -> bool {
-        self.quadtree.update_visibility(camera);
-        if !self.loading_complete() {
-            self.cache.update(device, queue, &self.gpu_state, &self.mapfile, &self.quadtree);
-            self.loading_complete()
-        } else {
-            true
+        tile_cache_size: usize,
+    ) -> Result<(), Error> {
+        let previous_size = std::mem::replace(&mut self.tile_cache_size, tile_cache_size);
+        let previous = match self.recreate_cache(device, queue) {
+            Ok(previous) => previous,
+            Err(e) => {
+                self.tile_cache_size = previous_size;
+                return Err(e);
+            }
+        };
+        self.cache.tiles.restore_from(previous.tiles);
+        self.tiles_changed = true;
+        Ok(())
+    }
+
+    /// Update the caches, catching any allocation failures so that `relieve_memory_pressure` can
+    /// shrink the tile cache once the device reports them.
+    fn update_cache(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.gpu_memory.push_scope(device);
+        self.cache.update(device, queue, &self.gpu_state, &self.mapfile, &self.quadtree);
+        self.gpu_memory.pop_scope(device);
+    }
+
+    /// Shrink the tile cache if the GPU ran out of memory during an earlier cache update, and
+    /// report it with `TerrainEvent::OutOfGpuMemory`. If the cache can't be rebuilt, the old one
+    /// is kept and the failure is reported with `TerrainEvent::TileCacheResizeFailed` instead.
+    /// Nothing is done once the cache is as small as it gets, since rebuilding it at the same size
+    /// would only discard every tile again. Must not be called between building the render
+    /// pipelines of a frame and drawing it.
+    fn relieve_memory_pressure(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if !self.gpu_memory.out_of_memory() {
+            return;
+        }
+        let tile_cache_size = memory::shrink_tile_cache(self.tile_cache_size);
+        if tile_cache_size == self.tile_cache_size {
+            return;
         }
+        if let Err(e) = self.resize_tile_cache(device, queue, tile_cache_size) {
+            self.gpu_memory.shrink_failed(tile_cache_size, &e);
+            return;
+        }
+        let layers = self.cache_layers(device, self.compact);
+        let bytes = memory::cache_bytes(&layers, tile_cache_size, self.grass_cache_size);
+        self.gpu_memory.shrunk(tile_cache_size, bytes);
     }
 
-    /// Render the terrain.
-    ///
-    /// This function will block if the root tiles haven't been downloaded/loaded from disk. If
-    /// you want to avoid this, call `poll_loading_status` first to see whether this function will
-    /// block.
-    pub fn render(
+    /// Discard all streamed and generated tiles, along with everything that refers to the old
+    /// cache. Returns the old cache. On error, the old cache is left in place.
+    fn recreate_cache(
         &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        color_buffer: &wgpu::TextureView,
-        depth_buffer: &wgpu::TextureView,
-        _frame_size: (u32, u32),
-        view_proj: mint::ColumnMatrix4<f32>,
-        camera: mint::Point3<f64>,
-    ) {
-        if self.shader.refresh() {
-            self.bindgroup_pipeline = None;
+    ) -> Result<UnifiedPriorityCache, Error> {
+        let albedo_blend = self.cache.tiles.albedo_blend().cloned();
+        let persist_generated = self.cache.tiles.persist_generated();
+        let height_deltas = self.cache.tiles.height_deltas().clone();
+        let cache = Self::create_cache(
+            device,
+            &self.mapfile,
+            self.tile_cache_size,
+            self.grass_cache_size,
+            self.compact,
+            self.guided_upsampling,
+        )?;
+        let gpu_state =
+            GpuState::new(device, queue, &self.mapfile, &cache, self.texture_filtering)?;
+        let previous_cache = std::mem::replace(&mut self.cache, cache);
+        self.cache.tiles.set_albedo_blend(albedo_blend);
+        self.cache.tiles.set_persist_generated(persist_generated);
+        self.cache.tiles.set_height_deltas(height_deltas);
+        let previous = std::mem::replace(&mut self.gpu_state, gpu_state);
+        self.gpu_state.set_shadow_map_resolution(device, self.shadow_map_resolution);
+        self.gpu_state.set_distance_field(device, self.distance_field.is_some());
+        if let Some(ref mut distance_field) = self.distance_field {
+            distance_field.reset();
+        }
+        self.gpu_state.set_materials(queue, previous.ground_materials);
+        self.gpu_state.set_triplanar_mapping(queue, self.triplanar_mapping);
+        self.gpu_state.set_parallax_occlusion(queue, self.parallax_occlusion);
+        if let Some(ref mut imposter) = self.imposter {
+            imposter.reset(device, &mut self.gpu_state);
         }
 
-        if self.bindgroup_pipeline.is_none() {
-            let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
-                device,
-                &self.shader,
-                HashMap::new(),
-                HashMap::new(),
-                "terrain",
-            );
-            let render_pipeline_layout =
-                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    bind_group_layouts: &[&bind_group_layout],
-                    push_constant_ranges: &[],
-                    label: Some("pipeline.terrain.layout"),
-                });
-            self.bindgroup_pipeline = Some((
-                bind_group,
-                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                    layout: Some(&render_pipeline_layout),
-                    vertex: wgpu::VertexState {
-                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("shader.terrain.vertex"),
-                            source: wgpu::ShaderSource::SpirV(self.shader.vertex().into()),
-                            flags: wgpu::ShaderFlags::empty(),
-                        }),
-                        entry_point: "main",
-                        buffers: &[],
-                    },
-                    fragment: Some(wgpu::FragmentState {
-                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("shader.terrain.fragment"),
-                            source: wgpu::ShaderSource::SpirV(self.shader.fragment().into()),
-                            flags: wgpu::ShaderFlags::empty(),
-                        }),
-                        entry_point: "main",
-                        targets: &[wgpu::ColorTargetState {
-                            format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                            blend: Some(wgpu::BlendState {
-                                color: wgpu::BlendComponent::REPLACE,
-                                alpha: wgpu::BlendComponent::REPLACE,
-                            }),
-                            write_mask: wgpu::ColorWrite::ALL,
-                        }],
-                    }),
-                    primitive: wgpu::PrimitiveState {
-                        cull_mode: Some(wgpu::Face::Front),
-                        ..Default::default()
-                    },
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth32Float,
-                        depth_write_enabled: true,
-                        depth_compare: wgpu::CompareFunction::Greater,
-                        bias: Default::default(),
-                        stencil: Default::default(),
-                    }),
-                    multisample: Default::default(),
-                    label: Some("pipeline.terrain"),
-                }),
-            ));
+        self.aerial_perspective = Self::create_aerial_perspective_shader();
+        if self.occlusion.is_some() {
+            self.occlusion = Some(OcclusionCuller::new(device));
         }
+        self.bindgroup_pipeline = None;
+        self.depth_prepass_pipeline = None;
+        self.motion_vector_bindgroup_pipeline = None;
+        self.gbuffer_bindgroup_pipeline = None;
+        self.sky_bindgroup_pipeline = None;
+        self.sensor.reset();
+        Ok(previous_cache)
+    }
 
-        if self.sky_shader.refresh() {
-            self.sky_bindgroup_pipeline = None;
+    /// Rebuild every GPU resource on `device`, for instance after the previous device was lost or
+    /// the application moved to a different adapter. Must be called before anything else is done
+    /// with the new device. Heightmap, vector and place tiles held on the CPU are kept and
+    /// heightmaps are uploaded again, so the terrain reappears without waiting for them to be
+    /// streamed. All other tiles are regenerated, and ground materials are read from disk again.
+    pub fn recreate(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), Error> {
+        let previous = self.recreate_cache(device, queue)?;
+        self.cache.tiles.restore_from(previous.tiles);
+
+        let materials = match self.materials {
+            Some(ref materials) => materials.load(device, queue)?,
+            None => GpuMaterials::empty(device),
+        };
+        self.gpu_state.set_materials(queue, materials);
+
+        self.index_buffer = self.quadtree.create_index_buffers(device);
+        self.holes.recreate_buffer(device);
+        self.raycaster = BatchRaycaster::new();
+        self.sensor.recreate(device);
+        if self.place_labels.is_some() {
+            self.place_labels = Some(PlaceLabels::new());
         }
-        if self.sky_bindgroup_pipeline.is_none() {
-            let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
-                device,
-                &self.sky_shader,
-                HashMap::new(),
+        if let Some(max_steps) = self.water_reflections.as_ref().map(|r| r.max_steps()) {
+            self.water_reflections = Some(WaterReflections::new(device, max_steps));
+        }
+        if let Some(downsampling) = self.sky_lod.as_ref().map(|l| l.downsampling()) {
+            self.sky_lod = Some(SkyLod::new(device, downsampling));
+        }
+        self.light_shafts = None;
+        self.tiles_changed = true;
+        Ok(())
+    }
+
+    /// Change how terrain detail is synthesized below the resolution of the elevation data, for
+    /// each class of land cover. The table is saved in the map file. All tiles are discarded and
+    /// regenerated, so detail will briefly drop while they are reloaded.
+    pub fn set_fractal_detail(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        table: FractalDetailTable,
+    ) -> Result<(), Error> {
+        self.mapfile.set_fractal_detail(&table)?;
+        for &layer in TileCache::PERSISTED_LAYERS.iter() {
+            self.mapfile.clear_generated(layer)?;
+        }
+        self.recorded_elevation_ranges.clear();
+        self.recreate_cache(device, queue).map(drop)
+    }
+
+    /// Save normal tiles to the map file after they are generated on the GPU, so that later runs
+    /// can stream them instead of generating them again. Other layers are always regenerated.
+    /// Saved tiles are discarded whenever the shaders that generate them change.
+    pub fn persist_generated_tiles(&mut self, enabled: bool) {
+        self.cache.tiles.set_persist_generated(enabled);
+    }
+
+    /// Choose whether files written to the map file are flushed to disk before they are recorded.
+    /// Defaults to `SyncPolicy::Always`.
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.mapfile.set_sync_policy(policy);
+    }
+
+    /// Map tile files into memory instead of reading them, which speeds up cold starts where
+    /// thousands of tiles are streamed at once and keeps them out of the process's private memory.
+    /// Normal tiles are copied to the GPU straight from the mapping, and albedo and roughness
+    /// tiles are decoded from it. Tiles in an archive are only mapped if stored uncompressed.
+    /// Heightmap tiles are always read, since they are decoded relative to their parents and
+    /// cached. Disabled by default.
+    pub fn set_memory_mapped_tiles(&mut self, enabled: bool) {
+        self.mapfile.set_memory_mapped(enabled);
+    }
+
+    /// Ask the tile servers whether any downloaded tiles within `radius` meters of the given
+    /// latitude and longitude, in radians, have changed upstream, and download the ones that have.
+    /// Returns how many tiles were replaced. If there were any, all streamed and generated tiles
+    /// are discarded so that terrain derived from the old tiles is regenerated.
+    pub async fn check_for_updates(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        latitude: f64,
+        longitude: f64,
+        radius: f64,
+    ) -> Result<usize, Error> {
+        let center = coordinates::polar_to_ecef(cgmath::Vector3::new(latitude, longitude, 0.0));
+        let updated = self.mapfile.check_for_updates(center, radius).await?;
+        if !updated.is_empty() {
+            for &layer in TileCache::PERSISTED_LAYERS.iter() {
+                self.mapfile.clear_generated(layer)?;
+            }
+            self.recorded_elevation_ranges.clear();
+            self.recreate_cache(device, queue)?;
+        }
+        Ok(updated.len())
+    }
+
+    /// Limit tile downloads to an average of `bytes_per_second`, or remove the limit with None.
+    /// There is no limit by default.
+    pub fn set_download_rate_limit(&mut self, bytes_per_second: Option<u32>) {
+        self.mapfile.set_download_rate_limit(bytes_per_second);
+    }
+
+    /// Only download coarse tiles, for users on capped connections. Detail beyond them is
+    /// synthesized instead, unless finer levels are allowed with `permit_downloads`. Disabled by
+    /// default.
+    pub fn set_metered_connection(&mut self, metered: bool) {
+        self.mapfile.set_metered_connection(metered);
+    }
+
+    /// Download base tiles from servers holding copies of the default tiles, given by their base
+    /// URLs. Downloads are spread across them and the default server, and move to another server
+    /// when one fails. Replaces any mirrors set before.
+    pub fn set_tile_mirrors(&mut self, mirrors: &[String]) {
+        self.mapfile.set_tile_mirrors(mirrors);
+    }
+
+    /// Allow base tiles down to `level` to be downloaded when `set_metered_connection` is
+    /// enabled, for instance after the user agrees to fetch full detail around their location.
+    /// Tiles that were already generated in place of missing ones are only replaced once they
+    /// are evicted from the cache.
+    pub fn permit_downloads(&mut self, level: u8) {
+        self.mapfile.permit_downloads(level);
+    }
+
+    /// Change how albedo and normal textures are filtered. Higher quality settings reduce
+    /// shimmering at grazing angles at some cost in texture bandwidth.
+    pub fn set_texture_filtering(&mut self, device: &wgpu::Device, filtering: TextureFiltering) {
+        self.texture_filtering = filtering;
+        self.gpu_state.set_texture_filtering(device, filtering);
+        self.bindgroup_pipeline = None;
+    }
+
+    /// Project ground materials along all three axes on steep slopes, rather than only along the
+    /// surface of the cube face, so that they aren't stretched on cliffs. This roughly triples the
+    /// texture samples for materials.
+    pub fn set_triplanar_mapping(&mut self, queue: &wgpu::Queue, enabled: bool) {
+        self.triplanar_mapping = enabled;
+        self.gpu_state.set_triplanar_mapping(queue, enabled);
+    }
+
+    /// Add apparent depth to the ground within a few tens of meters of the camera by ray marching
+    /// through fine scale relief with at most `max_steps` steps per pixel. Passing None disables
+    /// it. Only affects the ground materials set with `Terrain::set_materials`.
+    pub fn set_parallax_occlusion(&mut self, queue: &wgpu::Queue, max_steps: Option<u32>) {
+        self.parallax_occlusion = max_steps;
+        self.gpu_state.set_parallax_occlusion(queue, max_steps);
+    }
+
+    /// Reflect terrain and sky on water by ray marching the depth buffer with at most `max_steps`
+    /// steps per pixel. Passing None disables it, after which `render_water_reflections` does
+    /// nothing.
+    pub fn set_water_reflections(&mut self, device: &wgpu::Device, max_steps: Option<u32>) {
+        match (max_steps, self.water_reflections.as_mut()) {
+            (None, _) => self.water_reflections = None,
+            (Some(max_steps), Some(reflections)) => reflections.set_max_steps(max_steps),
+            (Some(max_steps), None) => {
+                self.water_reflections = Some(WaterReflections::new(device, max_steps))
+            }
+        }
+    }
+
+    /// Draw the sky at `1 / downsampling` of the frame resolution while the camera is near the
+    /// ground, where it changes slowly across the screen. Passing None always draws it at full
+    /// resolution.
+    pub fn set_sky_downsampling(&mut self, device: &wgpu::Device, downsampling: Option<u32>) {
+        match (downsampling, self.sky_lod.as_mut()) {
+            (None, _) => self.sky_lod = None,
+            (Some(downsampling), Some(lod)) => lod.set_downsampling(downsampling),
+            (Some(downsampling), None) => self.sky_lod = Some(SkyLod::new(device, downsampling)),
+        }
+    }
+
+    /// Tint the terrain by where the heightmap of each tile came from, for tracking down bad data:
+    /// blue for tiles downloaded from a tile server, green for tiles generated locally from
+    /// elevation datasets and orange for tiles synthesized with fractal detail. Tints are darker
+    /// the longer ago the tile was produced, and the edges of tiles are outlined. See
+    /// `tile_provenance` to look up a single point.
+    pub fn set_provenance_overlay(&mut self, enabled: bool) {
+        self.provenance_overlay = enabled;
+    }
+
+    /// Replace the ground materials splatted onto the terrain near the camera. Passing an empty
+    /// `MaterialSet` disables them. Textures are read from disk before this returns.
+    pub fn set_materials(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        materials: &MaterialSet,
+    ) -> Result<(), Error> {
+        self.gpu_state.set_materials(queue, materials.load(device, queue)?);
+        self.materials = Some(materials.clone());
+        self.bindgroup_pipeline = None;
+        Ok(())
+    }
+
+    /// Show albedo for `date` by crossfading between the two nearest albedo epochs in the map file.
+    /// Has no effect if the map file has no epochs. See `Terrain::generate_albedo_epoch`.
+    pub fn set_albedo_epoch(&mut self, date: Date) -> Result<(), Error> {
+        let epochs = self.mapfile.albedo_epochs()?;
+        self.cache.tiles.set_albedo_blend(AlbedoBlend::between(&epochs, date.julian_day()));
+        Ok(())
+    }
+
+    /// Set the date and time being rendered. The sun and moon are placed according to their
+    /// ephemeris, with the moon showing the matching phase. The snowline, vegetation color, and
+    /// deciduous foliage all follow the season at each point on the planet. Until this is called,
+    /// the sun has a fixed direction, there is no moon, and the terrain is shown exactly as in
+    /// the albedo tiles.
+    pub fn set_time(&mut self, date: Date) {
+        self.time = Some(date);
+    }
+
+    /// Change the conditions of the atmosphere: auroras and airglow, cloud shadows on the terrain
+    /// and the strength of light shafts. Auroras and clouds are animated using the time of day
+    /// given to `Terrain::set_time`.
+    pub fn set_weather(&mut self, weather: Weather) {
+        self.weather = weather;
+    }
+
+    /// Offset the projection of subsequent frames by `jitter` pixels, for use with temporal
+    /// anti-aliasing. Motion vectors are computed without the jitter applied.
+    pub fn set_projection_jitter(&mut self, jitter: mint::Vector2<f32>) {
+        self.jitter = jitter;
+    }
+
+    /// Enable or disable a depth-only pre-pass for terrain. When enabled, the shaded terrain pass
+    /// only runs the fragment shader for visible pixels, which can substantially reduce fragment
+    /// cost in scenes with lots of overdraw like mountainous regions.
+    pub fn set_depth_prepass(&mut self, enabled: bool) {
+        if self.depth_prepass != enabled {
+            self.depth_prepass = enabled;
+            self.bindgroup_pipeline = None;
+            self.depth_prepass_pipeline = None;
+        }
+    }
+
+    /// Enable or disable occlusion culling of terrain tiles hidden behind closer terrain, using the
+    /// depth buffer of the previous frame. This mostly helps ground-level views in mountainous
+    /// areas.
+    ///
+    /// When enabled, the depth buffer passed to `render` must have been created with
+    /// `TextureUsage::SAMPLED`. Culled tiles are drawn with a single indirect multi-draw if the
+    /// device was created with `wgpu::Features::MULTI_DRAW_INDIRECT`.
+    pub fn set_occlusion_culling(&mut self, device: &wgpu::Device, enabled: bool) {
+        if enabled != self.occlusion.is_some() {
+            self.occlusion = if enabled { Some(OcclusionCuller::new(device)) } else { None };
+        }
+    }
+
+    /// Enable or disable soft sun shadows and sky occlusion from a coarse distance field of the
+    /// terrain within about 16 km of the camera. They take over from shadow maps beyond a few
+    /// hundred meters, where shadow maps lose resolution, and fade out towards the edge of the
+    /// field. The field is rebuilt on the CPU from resident heightmaps as the camera moves and
+    /// tiles stream in, and is not used above about 16 km altitude.
+    pub fn set_distance_field_shadows(&mut self, device: &wgpu::Device, enabled: bool) {
+        if enabled != self.distance_field.is_some() {
+            self.distance_field = if enabled { Some(DistanceField::new()) } else { None };
+            self.gpu_state.set_distance_field(device, enabled);
+            self.bindgroup_pipeline = None;
+        }
+    }
+
+    /// Allocate a `resolution` x `resolution` shadow map that the application can render its own
+    /// objects into, so that they cast shadows onto the terrain. Passing None frees it.
+    ///
+    /// The shadow map uses the `Depth32Float` format with depth increasing away from the light,
+    /// and should be cleared to 1.0 before shadow casters are drawn.
+    pub fn set_shadow_receiver(&mut self, device: &wgpu::Device, resolution: Option<u32>) {
+        if self.shadow_map_resolution != resolution {
+            self.shadow_map_resolution = resolution;
+            self.gpu_state.set_shadow_map_resolution(device, resolution);
+            self.bindgroup_pipeline = None;
+        }
+    }
+
+    /// Returns a view of the shadow map allocated by `set_shadow_receiver` for use as a depth
+    /// attachment when drawing shadow casters.
+    pub fn shadow_map_view(&self) -> Option<wgpu::TextureView> {
+        self.shadow_map_resolution?;
+        Some(self.gpu_state.shadow_map.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("view.shadow_map"),
+            ..Default::default()
+        }))
+    }
+
+    /// Set the view projection matrix that shadow casters were drawn into the shadow map with. It
+    /// should map from world space (earth-centered, earth-fixed coordinates in meters) to clip
+    /// space. Shadows are ignored until this has been set.
+    pub fn set_shadow_caster_transform(&mut self, view_proj: Option<mint::ColumnMatrix4<f64>>) {
+        self.shadow_caster_view_proj = view_proj;
+    }
+
+    /// Set the maximum screen-space error, in pixels, that terrain geometry may have before more
+    /// detailed tiles are used. Passing None falls back to selecting detail purely by distance.
+    pub fn set_max_screen_space_error(&mut self, pixels: Option<f32>) {
+        self.max_screen_space_error = pixels;
+        self.quadtree.invalidate_visibility();
+    }
+
+    /// Set the deepest quadtree level that terrain is streamed and drawn at outside of any region
+    /// added with `add_detail_region`. Each level halves the spacing between samples, so capping
+    /// it bounds the disk space and GPU memory spent on areas the application doesn't care about.
+    pub fn set_max_level(&mut self, level: u8) {
+        self.detail_regions.set_max_level(level);
+        self.quadtree.invalidate_visibility();
+    }
+
+    /// Allow terrain within `radius` meters of the given latitude and longitude, in radians, to be
+    /// refined down to `max_level` even if that is deeper than the level set with
+    /// `set_max_level`. For instance an application could stream level 18 around an airport and
+    /// level 13 everywhere else. Where regions overlap the most detailed one applies.
+    pub fn add_detail_region(
+        &mut self,
+        latitude: f64,
+        longitude: f64,
+        radius: f64,
+        max_level: u8,
+    ) -> DetailRegionId {
+        self.quadtree.invalidate_visibility();
+        self.detail_regions.add(latitude, longitude, radius, max_level)
+    }
+
+    /// Same as `add_detail_region`, but covering the circle that encloses `bounds`, which may
+    /// cross the antimeridian or contain a pole.
+    pub fn add_detail_bounds(&mut self, bounds: LatLongBounds, max_level: u8) -> DetailRegionId {
+        let (latitude, longitude, radius) = bounds.bounding_circle();
+        self.add_detail_region(latitude, longitude, radius, max_level)
+    }
+
+    /// Remove a region previously added with `add_detail_region`.
+    pub fn remove_detail_region(&mut self, id: DetailRegionId) {
+        self.detail_regions.remove(id);
+        self.quadtree.invalidate_visibility();
+    }
+
+    /// Keep the terrain within `bounds` streamed in as though the camera were close by, even
+    /// while it is elsewhere, for instance around mission objectives or other players. With a
+    /// `weight` of 1.0 tiles are prioritized as if seen from 1 km away, and higher weights request
+    /// finer detail. Boosted tiles share the tile cache with those around the camera, so large or
+    /// heavily weighted regions can push out detail where the camera is. Nothing is drawn
+    /// differently, and detail is still limited by `set_max_level` and `add_detail_region`.
+    pub fn boost_priority(&mut self, bounds: LatLongBounds, weight: f32) -> PriorityBoostId {
+        let (latitude, longitude, radius) = bounds.bounding_circle();
+        self.quadtree.invalidate_visibility();
+        self.priority_boosts.add(latitude, longitude, radius, weight as f64)
+    }
+
+    /// Stop boosting a region previously passed to `boost_priority`.
+    pub fn remove_priority_boost(&mut self, id: PriorityBoostId) {
+        self.priority_boosts.remove(id);
+        self.quadtree.invalidate_visibility();
+    }
+
+    /// Stream terrain around `position`, in the same coordinates as the camera, just as it is
+    /// around the camera passed to `render`. Useful when several cameras share one `Terrain`,
+    /// such as split screen players each rendered in turn, or for points of interest on a server.
+    /// Tiles are prioritized by whichever viewpoint is closest, but only the camera being
+    /// rendered decides what is drawn.
+    pub fn add_streaming_viewpoint(&mut self, position: mint::Point3<f64>) -> StreamingViewpointId {
+        self.quadtree.invalidate_visibility();
+        self.streaming_viewpoints.add(position)
+    }
+
+    /// Move a viewpoint added with `add_streaming_viewpoint`. Does nothing if it was removed.
+    pub fn set_streaming_viewpoint(
+        &mut self,
+        id: StreamingViewpointId,
+        position: mint::Point3<f64>,
+    ) {
+        if self.streaming_viewpoints.set(id, position) {
+            self.quadtree.invalidate_visibility();
+        }
+    }
+
+    /// Stop streaming around a viewpoint added with `add_streaming_viewpoint`.
+    pub fn remove_streaming_viewpoint(&mut self, id: StreamingViewpointId) {
+        self.streaming_viewpoints.remove(id);
+        self.quadtree.invalidate_visibility();
+    }
+
+    /// Make streaming server-authoritative, so that every player in a multiplayer game collides
+    /// against the same terrain, or pass None to go back to each instance streaming on its own.
+    ///
+    /// A server splits a quadtree shared with its clients around its streaming viewpoints, which
+    /// should be placed at each player. Holes it adds or removes are shared too. Every call to
+    /// `authoritative_update` returns the changes since the previous one, which must reach each
+    /// client's `apply_authoritative_update` in order. Clients may still add their own holes, which
+    /// stay local.
+    ///
+    /// On both the server and clients the tiles of the shared quadtree are kept loaded, and
+    /// `get_height`, `is_underground` and watched elevations read only from the tile of the leaf
+    /// containing each point, so they agree everywhere once `authoritative_tiles_loaded` returns
+    /// true. Rendering is unaffected.
+    pub fn set_streaming_authority(&mut self, authority: Option<StreamingAuthority>) {
+        self.shared_quadtree = authority.map(SharedQuadTree::new);
+        self.quadtree.invalidate_visibility();
+    }
+
+    /// Split the shared quadtree around the current streaming viewpoints and return the
+    /// compressed update to send to clients. Fails unless this instance is a server.
+    pub fn authoritative_update(&mut self) -> Result<Vec<u8>, Error> {
+        let shared = self
+            .shared_quadtree
+            .as_mut()
+            .ok_or_else(|| anyhow::format_err!("Streaming is not server-authoritative"))?;
+        let update = shared.update(&self.streaming_viewpoints.positions())?;
+        self.quadtree.invalidate_visibility();
+        Ok(update)
+    }
+
+    /// Apply an update produced by `authoritative_update` on the server. Fails if this instance
+    /// is not a client, if the update is for a different map version, or if updates were lost or
+    /// reordered, in which case the client has to be reset with `set_streaming_authority`.
+    pub fn apply_authoritative_update(&mut self, update: &[u8]) -> Result<(), Error> {
+        let shared = self
+            .shared_quadtree
+            .as_mut()
+            .ok_or_else(|| anyhow::format_err!("Streaming is not server-authoritative"))?;
+        let holes = &mut self.holes;
+        let was_empty = holes.is_empty();
+        let result = shared.apply(update, |polygon| holes.add(polygon), |id| holes.remove(id));
+        if was_empty != self.holes.is_empty() {
+            self.bindgroup_pipeline = None;
+            self.depth_prepass_pipeline = None;
+        }
+        self.quadtree.invalidate_visibility();
+        result
+    }
+
+    /// Whether the heightmap tiles of every leaf of the shared quadtree are loaded, so that
+    /// heights match those seen by the server and other clients.
+    pub fn authoritative_tiles_loaded(&self) -> bool {
+        match self.shared_quadtree {
+            Some(ref shared) => shared
+                .leaves()
+                .into_iter()
+                .all(|node| self.cache.tiles.contains(node, LayerType::Heightmaps)),
+            None => false,
+        }
+    }
+
+    /// Continuously adjust terrain detail to hold `settings.target_frame_time`, or pass None to go
+    /// back to the screen-space error and grass setting from `TerrainOptions`.
+    ///
+    /// The controller needs to be told how long each frame took on the GPU through
+    /// `report_gpu_frame_time`. When frames run over budget it coarsens terrain geometry by raising
+    /// the screen-space error, and past `settings.disable_grass_above` it stops drawing grass.
+    /// Detail is restored gradually once there is headroom again.
+    pub fn set_adaptive_quality(&mut self, settings: Option<AdaptiveQuality>) {
+        if self.adaptive_quality.as_ref().map(|a| a.settings()) != settings.as_ref() {
+            self.adaptive_quality = settings.map(AdaptiveQualityController::new);
+            self.quadtree.invalidate_visibility();
+        }
+    }
+
+    /// Report how long the GPU spent on the most recent frame, typically measured by the
+    /// application with timestamp queries. Has no effect unless adaptive quality is enabled.
+    pub fn report_gpu_frame_time(&mut self, frame_time: Duration) {
+        if let Some(ref mut adaptive_quality) = self.adaptive_quality {
+            let decisions = |state: AdaptiveQualityState| (state.screen_space_error, state.grass);
+            let before = decisions(adaptive_quality.state());
+            adaptive_quality.report_frame_time(frame_time);
+            if decisions(adaptive_quality.state()) != before {
+                self.quadtree.invalidate_visibility();
+            }
+        }
+    }
+
+    /// Returns the current decisions of the adaptive quality controller, or None if it isn't
+    /// enabled.
+    pub fn adaptive_quality_state(&self) -> Option<AdaptiveQualityState> {
+        self.adaptive_quality.as_ref().map(|a| a.state())
+    }
+
+    /// Whether roughness and normal tiles are kept in BC compressed textures. They are only
+    /// compressed if the device was created with `wgpu::Features::TEXTURE_COMPRESSION_BC`, and
+    /// otherwise use up to eight times as much GPU memory.
+    pub fn compressed_textures(&self) -> bool {
+        self.cache.tiles.compressed_textures()
+    }
+
+    fn update_visibility(&mut self, camera: mint::Point3<f64>) {
+        // Newly streamed tiles have tighter geometric errors and elevation ranges than the parents
+        // they were estimated from, which refines LOD selection and culling.
+        if self.cache.tiles.take_bounds_changed() {
+            self.quadtree.invalidate_visibility();
+        }
+
+        let tiles = &self.cache.tiles;
+        let mapfile = &self.mapfile;
+        let max_screen_space_error = match self.adaptive_quality {
+            Some(ref adaptive_quality) => Some(adaptive_quality.state().screen_space_error),
+            None => self.max_screen_space_error,
+        };
+        let scale = match (max_screen_space_error, self.lod_pixel_scale) {
+            (Some(tolerance), Some(pixel_scale)) => Some(pixel_scale / tolerance as f64),
+            _ => None,
+        };
+        // A node replaces its parent once the parent's geometric error would project to more than
+        // the tolerance, so scale each node by the error recorded for its parent.
+        let lod_scale = |node: VNode| {
+            let error = tiles.geometric_error(node.parent()?.0)? as f64;
+            Some((error * scale? / node.min_distance()).max(0.5).min(2.0))
+        };
+        // Recorded ranges are of the raw heights, which are raised and clamped before being drawn.
+        let planet = mapfile.planet();
+        let recorded_elevation_ranges = &mut self.recorded_elevation_ranges;
+        let elevation_range = |node: VNode| {
+            let range = tiles.elevation_range(node).or_else(|| {
+                *recorded_elevation_ranges
+                    .entry(node)
+                    .or_insert_with(|| mapfile.elevation_range(node))
+            })?;
+            Some(planet.clamp_range(range))
+        };
+        let detail_regions = &self.detail_regions;
+        let max_level = match self.map_projection {
+            Some(_) => Self::MAP_PROJECTION_MAX_LEVEL,
+            None => VNode::LEVEL_CELL_2CM,
+        };
+        let priority_boosts = &self.priority_boosts;
+        let shared_quadtree = &self.shared_quadtree;
+        self.quadtree.update_visibility(
+            camera,
+            lod_scale,
+            elevation_range,
+            |node| detail_regions.max_level(node).min(max_level),
+            &self.streaming_viewpoints.positions(),
+            |node| {
+                let priority = priority_boosts.priority(node);
+                match shared_quadtree {
+                    Some(shared) => priority.max(shared.priority(node)),
+                    None => priority,
+                }
+            },
+        );
+    }
+
+    /// Returns the minimum and maximum distance from the camera to any terrain that may be
+    /// rendered this frame. Useful for choosing near and far clipping planes.
+    pub fn depth_bounds(&self) -> Option<(f64, f64)> {
+        self.quadtree.depth_bounds()
+    }
+
+    /// Start recording which tiles are streamed in and evicted on each frame. The resulting log
+    /// can be replayed to reproduce streaming behavior across machines.
+    pub fn start_streaming_recording(&mut self) {
+        self.cache.tiles.start_recording();
+    }
+
+    /// Stop recording streaming decisions and return the compressed log, or None if no recording
+    /// was in progress.
+    pub fn finish_streaming_recording(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        self.cache.tiles.finish_recording().map(|log| log.to_bytes()).transpose()
+    }
+
+    fn loading_complete(&self) -> bool {
+        VNode::roots().iter().copied().all(|root| {
+            self.cache.tiles.contains(root, LayerType::Heightmaps)
+                && self.cache.tiles.contains(root, LayerType::Albedo)
+                && self.cache.tiles.contains(root, LayerType::Roughness)
+        })
+    }
+
+    /// Returns whether initial map file streaming has completed for tiles in the vicinity of
+    /// `camera`.
+    ///
+    /// Terra cannot render any terrain until all root tiles have been downloaded and streamed to
+    /// the GPU. This function returns whether tohse tiles have been streamed, and also initiates
+    /// streaming of more detailed tiles for the indicated tile position.
+    pub fn poll_loading_status(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: mint::Point3<f64>,
+    ) -> bool {
+        self.update_visibility(camera);
+        if !self.loading_complete() {
+            self.update_cache(device, queue);
+            self.loading_complete()
+        } else {
+            true
+        }
+    }
+
+    /// Let `render` be called before the root tiles have loaded. Parts of the planet whose root
+    /// tiles are missing are left out, and the terrain is refined as tiles stream in over the
+    /// following frames. See `readiness` for deciding when to hide a loading screen.
+    pub fn set_progressive_loading(&mut self, enabled: bool) {
+        self.progressive_loading = enabled;
+    }
+
+    /// How much of the terrain chosen for the last camera is drawn with its own tiles, from 0
+    /// before anything has loaded to 1 once every tile it needs is resident. Root tiles are
+    /// always counted, so this stays low until the whole planet can be drawn.
+    pub fn readiness(&self) -> f32 {
+        let mask = LayerType::Displacements.bit_mask()
+            | LayerType::Albedo.bit_mask()
+            | LayerType::Roughness.bit_mask()
+            | LayerType::Normals.bit_mask();
+        let (mut loaded, mut total) = (0, 0);
+        for node in VNode::roots().iter().copied().chain(self.quadtree.rendered_nodes()) {
+            if self.cache.tiles.contains_all(node, mask) {
+                loaded += 1;
+            }
+            total += 1;
+        }
+        loaded as f32 / total as f32
+    }
+
+    /// Whether rendering another frame from the same camera could look different from the last
+    /// one, because tiles are still being streamed or generated, or finished loading during the
+    /// last frame. Applications that render on demand can skip frames while this returns false
+    /// and the camera isn't moving.
+    pub fn needs_redraw(&self) -> bool {
+        self.tiles_changed || !self.cache.tiles.is_idle() || !self.loading_complete()
+    }
+
+    /// Render the terrain.
+    ///
+    /// This function will block if the root tiles haven't been downloaded/loaded from disk. If
+    /// you want to avoid this, call `poll_loading_status` first to see whether this function will
+    /// block, or enable `set_progressive_loading`.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color_buffer: &wgpu::TextureView,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        view_proj: mint::ColumnMatrix4<f32>,
+        camera: mint::Point3<f64>,
+    ) {
+        self.render_internal(
+            device,
+            queue,
+            RenderTarget::Color(color_buffer),
+            depth_buffer,
+            None,
+            None,
+            frame_size,
+            view_proj,
+            camera,
+            DepthPass::Full,
+        );
+        self.update_place_labels(device, queue, depth_buffer, frame_size, view_proj, camera);
+    }
+
+    /// Same as `render`, but takes a double precision camera instead of a view projection matrix.
+    ///
+    /// If a depth partition is set, the frame is drawn in two passes. See
+    /// `Terrain::set_depth_partition`.
+    pub fn render_camera(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color_buffer: &wgpu::TextureView,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        camera: &Camera,
+    ) {
+        let aspect = frame_size.0 as f64 / frame_size.1 as f64;
+        // The planet imposter is drawn in a single pass regardless.
+        let imposter = self.imposter_active(camera.position);
+        let split = match self.depth_partition {
+            Some(split) if split > camera.near && !imposter => split,
+            _ => {
+                return self.render(
+                    device,
+                    queue,
+                    color_buffer,
+                    depth_buffer,
+                    frame_size,
+                    camera.view_proj(aspect),
+                    camera.position,
+                )
+            }
+        };
+
+        // The depth buffer is cleared between the passes, so it can't be used to cull occluded
+        // tiles.
+        let occlusion = self.occlusion.take();
+        let far_camera = Camera { near: split, ..*camera };
+        self.render_internal(
+            device,
+            queue,
+            RenderTarget::Color(color_buffer),
+            depth_buffer,
+            None,
+            None,
+            frame_size,
+            far_camera.view_proj(aspect),
+            camera.position,
+            DepthPass::Far,
+        );
+        self.render_internal(
+            device,
+            queue,
+            RenderTarget::Color(color_buffer),
+            depth_buffer,
+            None,
+            None,
+            frame_size,
+            camera.view_proj_with_far(aspect, split),
+            camera.position,
+            DepthPass::Near,
+        );
+        self.occlusion = occlusion;
+        self.update_place_labels(
+            device,
+            queue,
+            depth_buffer,
+            frame_size,
+            camera.view_proj_with_far(aspect, split),
+            camera.position,
+        );
+    }
+
+    /// Split views drawn with `render_camera` into two passes with separate depth ranges: one for
+    /// terrain beyond `distance` meters from the camera, and then one for closer terrain. Each
+    /// pass gets the full precision of the depth buffer, which avoids z-fighting when a single
+    /// view spans from a few meters to thousands of kilometers, such as when looking at the
+    /// horizon from low orbit. The depth buffer only holds the near pass afterwards. Passing None
+    /// draws everything in a single pass.
+    pub fn set_depth_partition(&mut self, distance: Option<f64>) {
+        self.depth_partition = distance;
+    }
+
+    /// When the camera is more than `altitude` meters above sea level, draw the whole planet from
+    /// a coarse precomputed imposter instead of from terrain tiles. No tiles are streamed or drawn
+    /// while the imposter is in use, which makes zoomed-out views of the globe much cheaper.
+    /// Passing None disables the imposter and frees its textures.
+    ///
+    /// The imposter is generated from the root tiles once they are loaded, so the terrain is drawn
+    /// normally until then.
+    pub fn set_planet_imposter(&mut self, device: &wgpu::Device, altitude: Option<f64>) {
+        match (altitude, self.imposter.as_mut()) {
+            (Some(altitude), Some(imposter)) => imposter.set_altitude(altitude),
+            (Some(altitude), None) => {
+                self.imposter = Some(PlanetImposter::new(device, &mut self.gpu_state, altitude));
+            }
+            (None, Some(_)) => {
+                self.imposter = None;
+                self.gpu_state.set_imposter_resolution(device, None);
+            }
+            (None, None) => {}
+        }
+    }
+
+    fn imposter_active(&self, camera: mint::Point3<f64>) -> bool {
+        self.map_projection.is_none() && self.imposter.as_ref().map_or(false, |i| i.active(camera))
+    }
+
+    /// Blend the globe towards a flat Web Mercator map, or pass None to draw the globe normally.
+    /// Animate `projection.blend` over successive frames for a smooth transition, for instance with
+    /// `MapProjection::transition`.
+    ///
+    /// The blend happens in the vertex shader, so terrain keeps its lighting and texturing. While
+    /// it is in use only coarse levels of detail are drawn, and neither frustum nor occlusion
+    /// culling nor the planet imposter are used.
+    pub fn set_map_projection(&mut self, projection: Option<MapProjection>) {
+        let projection = projection.filter(|p| p.blend > 0.0);
+        if projection.is_some() != self.map_projection.is_some() {
+            self.quadtree.invalidate_visibility();
+        }
+        self.map_projection = projection;
+    }
+
+    /// Render the terrain, and also write per-pixel motion vectors for use by temporal
+    /// anti-aliasing or upscaling.
+    ///
+    /// `motion_vectors` must be a `Rg16Float` texture the size of the frame. Each terrain pixel is
+    /// set to the screen space motion since the previous frame in texture coordinates, and all
+    /// other pixels are cleared to zero.
+    pub fn render_with_motion_vectors(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color_buffer: &wgpu::TextureView,
+        depth_buffer: &wgpu::TextureView,
+        motion_vectors: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        view_proj: mint::ColumnMatrix4<f32>,
+        camera: mint::Point3<f64>,
+    ) {
+        self.render_internal(
+            device,
+            queue,
+            RenderTarget::Color(color_buffer),
+            depth_buffer,
+            Some(motion_vectors),
+            None,
+            frame_size,
+            view_proj,
+            camera,
+            DepthPass::Full,
+        )
+    }
+
+    /// Render the terrain, and also write per-pixel depth and geographic coordinates so that
+    /// rendered frames can be used for measurement, compositing and picking. See `GBuffer` for
+    /// the required texture formats.
+    pub fn render_with_gbuffer(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color_buffer: &wgpu::TextureView,
+        depth_buffer: &wgpu::TextureView,
+        gbuffer: &GBuffer,
+        frame_size: (u32, u32),
+        view_proj: mint::ColumnMatrix4<f32>,
+        camera: mint::Point3<f64>,
+    ) {
+        self.render_internal(
+            device,
+            queue,
+            RenderTarget::Color(color_buffer),
+            depth_buffer,
+            None,
+            Some(gbuffer),
+            frame_size,
+            view_proj,
+            camera,
+            DepthPass::Full,
+        )
+    }
+
+    /// Render range and intensity images of the terrain as seen by a radar or lidar sensor at
+    /// `camera`, without any shading, sky or atmosphere. The view is independent of the main
+    /// camera, so sensors can be placed anywhere, but tiles are only streamed in for them while
+    /// this is being called. Holes, grass and the planet imposter are not drawn.
+    ///
+    /// `depth_buffer` must be a `Depth32Float` texture the size of the frame. See `SensorImage`
+    /// for the outputs.
+    pub fn render_sensor(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &SensorImage,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        camera: &Camera,
+    ) {
+        self.render_sensor_view(
+            device,
+            queue,
+            RenderTarget::Sensor(image),
+            depth_buffer,
+            frame_size,
+            camera,
+        )
+    }
+
+    /// Render an approximation of the thermal infrared emission of the terrain as seen from
+    /// `camera`, the same way as `render_sensor`. Ground temperature follows latitude, season,
+    /// elevation and land cover, and warms with the sunlight it has received over the preceding
+    /// hours, so slopes facing the afternoon sun stay warm into the evening. The time of day comes
+    /// from `set_time`.
+    ///
+    /// `temperature` must be an `R32Float` texture the size of the frame. Each terrain pixel is
+    /// set to the brightness temperature in kelvin, and all other pixels are cleared to zero.
+    pub fn render_thermal(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        temperature: &wgpu::TextureView,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        camera: &Camera,
+    ) {
+        self.render_sensor_view(
+            device,
+            queue,
+            RenderTarget::Thermal(temperature),
+            depth_buffer,
+            frame_size,
+            camera,
+        )
+    }
+
+    fn render_sensor_view(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        target: RenderTarget,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        camera: &Camera,
+    ) {
+        // The sensor must not disturb the state carried between frames of the main view.
+        let occlusion = self.occlusion.take();
+        let previous_frame = self.previous_frame.take();
+        let jitter = std::mem::replace(&mut self.jitter, mint::Vector2 { x: 0.0, y: 0.0 });
+        self.render_internal(
+            device,
+            queue,
+            target,
+            depth_buffer,
+            None,
+            None,
+            frame_size,
+            camera.view_proj(frame_size.0 as f64 / frame_size.1 as f64),
+            camera.position,
+            DepthPass::Full,
+        );
+        self.occlusion = occlusion;
+        self.previous_frame = previous_frame;
+        self.jitter = jitter;
+    }
+
+    /// Set the reflectivity of each class of land cover at the wavelength of the sensor used by
+    /// `render_sensor`. Defaults to typical near infrared values.
+    pub fn set_sensor_reflectivity(&mut self, reflectivity: LandCoverWeights) {
+        self.sensor.set_reflectivity(reflectivity);
+    }
+
+    /// Distance in meters beyond which `render_sensor` reports no returns. Unlimited by default.
+    pub fn set_sensor_max_range(&mut self, max_range: f32) {
+        self.sensor.set_max_range(max_range);
+    }
+
+    fn render_internal(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        target: RenderTarget,
+        depth_buffer: &wgpu::TextureView,
+        motion_vectors: Option<&wgpu::TextureView>,
+        gbuffer: Option<&GBuffer>,
+        frame_size: (u32, u32),
+        view_proj: mint::ColumnMatrix4<f32>,
+        camera: mint::Point3<f64>,
+        depth_pass: DepthPass,
+    ) {
+        // The imposter has no range or temperature to measure, so sensors always see the terrain
+        // tiles.
+        let sensor = match target {
+            RenderTarget::Color(_) => None,
+            RenderTarget::Sensor(_) => Some(SensorKind::Lidar),
+            RenderTarget::Thermal(_) => Some(SensorKind::Thermal),
+        };
+        // Everything drawn with the imposter belongs to the far pass.
+        let imposter = self.imposter_active(camera) && sensor.is_none();
+        if imposter && depth_pass == DepthPass::Near {
+            return;
+        }
+        // Node bounds don't account for the map projection.
+        let culling = !imposter && self.map_projection.is_none();
+
+        // The near pass draws the tiles selected for the far pass, so the cache can't change
+        // between them.
+        if depth_pass != DepthPass::Near {
+            self.relieve_memory_pressure(device, queue);
+        }
+
+        if self.shader.refresh() {
+            self.bindgroup_pipeline = None;
+        }
+        if self.holes_shader.refresh() && !self.holes.is_empty() {
+            self.bindgroup_pipeline = None;
+        }
+
+        // The depth pre-pass does not run a fragment shader, so it can't cut holes.
+        let depth_prepass = self.depth_prepass && self.holes.is_empty();
+
+        if self.bindgroup_pipeline.is_none() {
+            let (shader, buffers) = if self.holes.is_empty() {
+                (&self.shader, HashMap::new())
+            } else {
+                (&self.holes_shader, self.holes.bindings())
+            };
+            let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
+                device,
+                shader,
+                buffers,
                 HashMap::new(),
-                "sky",
+                "terrain",
             );
             let render_pipeline_layout =
                 device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    bind_group_layouts: &[&bind_group_layout],
                     push_constant_ranges: &[],
-                    label: Some("pipeline.sky.layout"),
+                    label: Some("pipeline.terrain.layout"),
                 });
-            self.sky_bindgroup_pipeline = Some((
+            let vertex_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                label: Some("shader.terrain.vertex"),
+                source: wgpu::ShaderSource::SpirV(shader.vertex().into()),
+                flags: wgpu::ShaderFlags::empty(),
+            });
+            self.bindgroup_pipeline = Some((
                 bind_group,
                 device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
                     layout: Some(&render_pipeline_layout),
                     vertex: wgpu::VertexState {
-                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("shader.sky.vertex"),
-                            source: wgpu::ShaderSource::SpirV(self.sky_shader.vertex().into()),
-                            flags: wgpu::ShaderFlags::VALIDATION,
-                        }),
+                        module: &vertex_module,
                         entry_point: "main",
                         buffers: &[],
                     },
                     fragment: Some(wgpu::FragmentState {
                         module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-                            label: Some("shader.sky.fragment"),
-                            source: wgpu::ShaderSource::SpirV(self.sky_shader.fragment().into()),
-                            flags: wgpu::ShaderFlags::VALIDATION,
+                            label: Some("shader.terrain.fragment"),
+                            source: wgpu::ShaderSource::SpirV(shader.fragment().into()),
+                            flags: wgpu::ShaderFlags::empty(),
                         }),
                         entry_point: "main",
                         targets: &[wgpu::ColorTargetState {
@@ -322,36 +1905,310 @@ impl Terrain {
                             write_mask: wgpu::ColorWrite::ALL,
                         }],
                     }),
-                    primitive: Default::default(),
+                    primitive: QuadTree::primitive_state(),
+                    // With a depth pre-pass, depth has already been written so only fragments
+                    // that exactly match it need to be shaded.
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: wgpu::TextureFormat::Depth32Float,
+                        depth_write_enabled: !depth_prepass,
+                        depth_compare: if depth_prepass {
+                            wgpu::CompareFunction::Equal
+                        } else {
+                            wgpu::CompareFunction::Greater
+                        },
+                        bias: Default::default(),
+                        stencil: Default::default(),
+                    }),
+                    multisample: Default::default(),
+                    label: Some("pipeline.terrain"),
+                }),
+            ));
+            if depth_prepass {
+                self.depth_prepass_pipeline =
+                    Some(device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                        layout: Some(&render_pipeline_layout),
+                        vertex: wgpu::VertexState {
+                            module: &vertex_module,
+                            entry_point: "main",
+                            buffers: &[],
+                        },
+                        fragment: None,
+                        primitive: QuadTree::primitive_state(),
+                        depth_stencil: Some(wgpu::DepthStencilState {
+                            format: wgpu::TextureFormat::Depth32Float,
+                            depth_write_enabled: true,
+                            depth_compare: wgpu::CompareFunction::Greater,
+                            bias: Default::default(),
+                            stencil: Default::default(),
+                        }),
+                        multisample: Default::default(),
+                        label: Some("pipeline.terrain.depth"),
+                    }));
+            }
+        }
+
+        if self.motion_vector_shader.refresh() {
+            self.motion_vector_bindgroup_pipeline = None;
+        }
+        if motion_vectors.is_some() && self.motion_vector_bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
+                device,
+                &self.motion_vector_shader,
+                HashMap::new(),
+                HashMap::new(),
+                "terrain.motion",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: &[&bind_group_layout],
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.terrain.motion.layout"),
+                });
+            self.motion_vector_bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.terrain.motion.vertex"),
+                            source: wgpu::ShaderSource::SpirV(
+                                self.motion_vector_shader.vertex().into(),
+                            ),
+                            flags: wgpu::ShaderFlags::empty(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.terrain.motion.fragment"),
+                            source: wgpu::ShaderSource::SpirV(
+                                self.motion_vector_shader.fragment().into(),
+                            ),
+                            flags: wgpu::ShaderFlags::empty(),
+                        }),
+                        entry_point: "main",
+                        targets: &[wgpu::ColorTargetState {
+                            format: wgpu::TextureFormat::Rg16Float,
+                            blend: None,
+                            write_mask: wgpu::ColorWrite::ALL,
+                        }],
+                    }),
+                    primitive: QuadTree::primitive_state(),
+                    // Reuse the depth buffer from the main pass so that only visible terrain
+                    // fragments are shaded.
                     depth_stencil: Some(wgpu::DepthStencilState {
                         format: wgpu::TextureFormat::Depth32Float,
-                        depth_compare: wgpu::CompareFunction::GreaterEqual,
                         depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::Equal,
                         bias: Default::default(),
                         stencil: Default::default(),
                     }),
                     multisample: Default::default(),
-                    label: Some("pipeline.sky"),
+                    label: Some("pipeline.terrain.motion"),
                 }),
             ));
         }
 
-        self.quadtree.update_visibility(camera);
+        if self.gbuffer_shader.refresh() {
+            self.gbuffer_bindgroup_pipeline = None;
+        }
+        if gbuffer.is_some() && self.gbuffer_bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
+                device,
+                &self.gbuffer_shader,
+                HashMap::new(),
+                HashMap::new(),
+                "terrain.gbuffer",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: &[&bind_group_layout],
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.terrain.gbuffer.layout"),
+                });
+            self.gbuffer_bindgroup_pipeline = Some((
+                bind_group,
+                device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                    layout: Some(&render_pipeline_layout),
+                    vertex: wgpu::VertexState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.terrain.gbuffer.vertex"),
+                            source: wgpu::ShaderSource::SpirV(self.gbuffer_shader.vertex().into()),
+                            flags: wgpu::ShaderFlags::empty(),
+                        }),
+                        entry_point: "main",
+                        buffers: &[],
+                    },
+                    fragment: Some(wgpu::FragmentState {
+                        module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                            label: Some("shader.terrain.gbuffer.fragment"),
+                            source: wgpu::ShaderSource::SpirV(
+                                self.gbuffer_shader.fragment().into(),
+                            ),
+                            flags: wgpu::ShaderFlags::empty(),
+                        }),
+                        entry_point: "main",
+                        targets: &[
+                            wgpu::ColorTargetState {
+                                format: wgpu::TextureFormat::R32Float,
+                                blend: None,
+                                write_mask: wgpu::ColorWrite::ALL,
+                            },
+                            wgpu::ColorTargetState {
+                                format: wgpu::TextureFormat::Rgba32Float,
+                                blend: None,
+                                write_mask: wgpu::ColorWrite::ALL,
+                            },
+                        ],
+                    }),
+                    primitive: QuadTree::primitive_state(),
+                    depth_stencil: Some(wgpu::DepthStencilState {
+                        format: wgpu::TextureFormat::Depth32Float,
+                        depth_write_enabled: false,
+                        depth_compare: wgpu::CompareFunction::Equal,
+                        bias: Default::default(),
+                        stencil: Default::default(),
+                    }),
+                    multisample: Default::default(),
+                    label: Some("pipeline.terrain.gbuffer"),
+                }),
+            ));
+        }
+
+        // Switch to the atmosphere lookup tables once they are ready. If computing or loading
+        // them failed, the sky keeps being drawn without them.
+        if let Some(result) = self.atmosphere_tables.as_ref().and_then(|r| r.try_recv().ok()) {
+            self.atmosphere_tables = None;
+            let reloaded = result
+                .and_then(|()| self.gpu_state.reload_atmosphere(device, queue, &self.mapfile));
+            if reloaded.is_ok() {
+                self.aerial_perspective = Self::create_aerial_perspective_shader();
+                self.sky_bindgroup_pipeline = None;
+                if let Some(ref mut imposter) = self.imposter {
+                    imposter.reset(device, &mut self.gpu_state);
+                }
+            }
+        }
+
+        if self.sky_shader.refresh() {
+            self.sky_bindgroup_pipeline = None;
+        }
+        if self.sky_bindgroup_pipeline.is_none() {
+            let (bind_group, bind_group_layout) = self.gpu_state.bind_group_for_shader(
+                device,
+                &self.sky_shader,
+                HashMap::new(),
+                HashMap::new(),
+                "sky",
+            );
+            let render_pipeline_layout =
+                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&*bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.sky.layout"),
+                });
+            self.sky_bindgroup_pipeline = Some((
+                bind_group,
+                Self::create_sky_pipeline(device, &self.sky_shader, &render_pipeline_layout, true),
+            ));
+            self.sky_low_resolution_pipeline = Some(Self::create_sky_pipeline(
+                device,
+                &self.sky_shader,
+                &render_pipeline_layout,
+                false,
+            ));
+        }
+
+        // The near pass shows the same tiles as the far pass just before it, and no tiles are
+        // needed while the imposter is drawn.
+        if depth_pass != DepthPass::Near && !imposter {
+            self.lod_pixel_scale = Some(
+                self.lod_pixel_scale_override
+                    .unwrap_or_else(|| Self::pixel_scale(view_proj, frame_size.1)),
+            );
+            self.update_visibility(camera);
+
+            // Update the tile cache and then, unless drawing progressively, block until root tiles
+            // have been downloaded and streamed to the GPU.
+            self.update_cache(device, queue);
+            while !self.progressive_loading && !self.poll_loading_status(device, queue, camera) {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            let resident_tiles = self.cache.tiles.valid_layers();
+            self.tiles_changed = resident_tiles != self.resident_tiles;
+            self.resident_tiles = resident_tiles;
+            let tiles = &self.cache.tiles;
+            let shared_quadtree = self.shared_quadtree.as_ref();
+            self.elevation_watches
+                .update(|lat, long| Self::collision_height(tiles, shared_quadtree, lat, long));
+            if let Some(ref mut distance_field) = self.distance_field {
+                distance_field.update(
+                    queue,
+                    &self.gpu_state.distance_field,
+                    cgmath::Vector3::new(camera.x, camera.y, camera.z),
+                    self.tiles_changed,
+                    |lat, long| Self::height_from_tiles(tiles, lat, long),
+                );
+            }
+        }
+
+        if !imposter {
+            self.quadtree.prepare_vertex_buffer(
+                queue,
+                &mut self.gpu_state.node_buffer,
+                &self.cache,
+                camera,
+                view_proj,
+                self.map_projection.is_none(),
+            );
+        }
+
+        // Positions are relative to the camera, so the previous frame's matrix must also account
+        // for how far the camera moved.
+        let prev_view_proj = match self.previous_frame {
+            Some((prev_view_proj, prev_camera)) => {
+                let delta = cgmath::Vector3::new(
+                    (camera.x - prev_camera.x) as f32,
+                    (camera.y - prev_camera.y) as f32,
+                    (camera.z - prev_camera.z) as f32,
+                );
+                (cgmath::Matrix4::from(prev_view_proj) * cgmath::Matrix4::from_translation(delta))
+                    .into()
+            }
+            None => view_proj,
+        };
+        self.previous_frame = Some((view_proj, camera));
+
+        // Terrain positions are relative to the camera, so fold the camera position into the
+        // shadow transform while still in double precision.
+        let shadow_view_proj: Option<mint::ColumnMatrix4<f32>> = self
+            .shadow_caster_view_proj
+            .filter(|_| self.shadow_map_resolution.is_some())
+            .map(|m| {
+                let translation = cgmath::Matrix4::from_translation(cgmath::Vector3::new(
+                    camera.x, camera.y, camera.z,
+                ));
+                (cgmath::Matrix4::from(m) * translation).cast::<f32>().unwrap().into()
+            });
+
+        let (sun_direction, moon) = match self.time.map(Ephemeris::at) {
+            Some(e) => (
+                [e.sun_direction.x as f32, e.sun_direction.y as f32, e.sun_direction.z as f32, 0.0],
+                [
+                    e.moon_direction.x as f32,
+                    e.moon_direction.y as f32,
+                    e.moon_direction.z as f32,
+                    e.moon_angular_radius as f32,
+                ],
+            ),
+            None => ([0.4, 0.7, 0.2, 0.0], [0.0; 4]),
+        };
+        let seconds = self.time.map(|t| (t.day.fract() * 86400.0) as f32).unwrap_or(0.0);
 
-        // Update the tile cache and then block until root tiles have been downloaded and streamed
-        // to the GPU.
-        self.cache.update(device, queue, &self.gpu_state, &self.mapfile, &self.quadtree);
-        while !self.poll_loading_status(device, queue, camera) {
-            std::thread::sleep(std::time::Duration::from_millis(10));
+        if !self.holes.is_empty() {
+            self.holes.upload(queue, camera);
         }
-
-        self.quadtree.prepare_vertex_buffer(
-            queue,
-            &mut self.gpu_state.node_buffer,
-            &self.cache,
-            camera,
-        );
-
         queue.write_buffer(
             &self.gpu_state.globals,
             0,
@@ -359,7 +2216,58 @@ impl Terrain {
                 view_proj,
                 view_proj_inverse: cgmath::Matrix4::from(view_proj).invert().unwrap().into(),
                 camera: [camera.x as f32, camera.y as f32, camera.z as f32, 0.0],
-                sun_direction: [0.4, 0.7, 0.2, 0.0],
+                sun_direction,
+                prev_view_proj,
+                jitter: [
+                    self.jitter.x * 2.0 / frame_size.0 as f32,
+                    self.jitter.y * -2.0 / frame_size.1 as f32,
+                ],
+                ev100: exposure::auto_ev100(
+                    cgmath::Vector3::new(camera.x, camera.y, camera.z),
+                    cgmath::Vector3::new(
+                        sun_direction[0] as f64,
+                        sun_direction[1] as f64,
+                        sun_direction[2] as f64,
+                    ),
+                    self.exposure_range,
+                ),
+                debug_overlay: self.provenance_overlay as u8 as f32,
+                shadow_view_proj: shadow_view_proj.unwrap_or(view_proj),
+                shadow_params: [shadow_view_proj.is_some() as u8 as f32, 0.0005, 0.0, 0.0],
+                season: Season::uniform(self.time.map(Season::from_date).as_ref()),
+                moon,
+                weather: self.weather.uniform(seconds),
+                ground_camera: [
+                    camera.x.rem_euclid(GROUND_PERIOD) as f32,
+                    camera.y.rem_euclid(GROUND_PERIOD) as f32,
+                    camera.z.rem_euclid(GROUND_PERIOD) as f32,
+                    0.0,
+                ],
+                map_projection: match self.map_projection {
+                    Some(p) => [
+                        p.blend,
+                        p.latitude as f32,
+                        p.longitude as f32,
+                        MapProjection::mercator_y(p.latitude) as f32,
+                    ],
+                    None => [0.0; 4],
+                },
+                planet: self.mapfile.planet().uniform(),
+                clouds: self.weather.clouds_uniform(seconds),
+                distance_field: self
+                    .distance_field
+                    .as_ref()
+                    .map(|f| f.uniforms(cgmath::Vector3::new(camera.x, camera.y, camera.z)))
+                    .unwrap_or([[0.0; 4]; 3]),
+                skylines: match self.skyline_bounds {
+                    Some(b) => [
+                        b.south() as f32,
+                        b.west() as f32,
+                        (b.north() - b.south()) as f32,
+                        b.longitude_span() as f32,
+                    ],
+                    None => [0.0; 4],
+                },
             }),
         );
 
@@ -367,62 +2275,940 @@ impl Terrain {
             label: Some("encoder.render"),
         });
         {
+            if let Some(ref mut planet) = self.imposter {
+                planet.update(device, &mut encoder, &self.gpu_state, &self.cache);
+                planet.prepare(device, &self.gpu_state);
+            }
+            if let Some(kind) = sensor {
+                self.sensor.prepare(device, queue, &self.gpu_state, kind, self.time);
+            }
+
             self.aerial_perspective.refresh();
-            self.aerial_perspective.run(
-                device,
-                &mut encoder,
-                &self.gpu_state,
-                (1, 1, self.quadtree.node_buffer_length() as u32),
-                &0,
-            );
+            if !imposter && sensor.is_none() {
+                self.aerial_perspective.run(
+                    device,
+                    &mut encoder,
+                    &self.gpu_state,
+                    (1, 1, self.quadtree.node_buffer_length() as u32),
+                    &0,
+                );
+            }
 
-            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                color_attachments: &[wgpu::RenderPassColorAttachment {
+            if let Some(occlusion) = self.occlusion.as_mut().filter(|_| culling) {
+                occlusion.refresh();
+                occlusion.cull(
+                    device,
+                    queue,
+                    &mut encoder,
+                    &self.gpu_state,
+                    frame_size,
+                    self.quadtree.node_buffer_length() as u32,
+                    self.cache.tile_desc(LayerType::Displacements).texture_resolution - 1,
+                );
+            }
+            let draws = self.occlusion.as_ref().filter(|_| culling).map(|o| o.draws());
+
+            // Cleared depth values in the near pass belong to terrain drawn by the far pass.
+            let draw_sky = depth_pass != DepthPass::Near && sensor.is_none();
+
+            // Near the ground the sky is first drawn at reduced resolution, then stretched over the
+            // sky in the main render pass.
+            let altitude =
+                coordinates::ecef_to_polar(cgmath::Vector3::new(camera.x, camera.y, camera.z)).z;
+            let sky_size = self
+                .sky_lod
+                .as_ref()
+                .filter(|_| draw_sky)
+                .and_then(|lod| lod.size(frame_size, altitude));
+            if let Some(size) = sky_size {
+                let target = self.sky_lod.as_mut().unwrap().target(device, size);
+                let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    color_attachments: &[wgpu::RenderPassColorAttachment {
+                        view: target,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: None,
+                    label: Some("renderpass.sky"),
+                });
+                rpass.set_pipeline(self.sky_low_resolution_pipeline.as_ref().unwrap());
+                rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+                rpass.draw(0..3, 0..1);
+            }
+
+            let depth_prepass = depth_prepass && !imposter && sensor.is_none();
+            if depth_prepass {
+                let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    color_attachments: &[],
+                    depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                        view: depth_buffer,
+                        depth_ops: Some(wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(0.0),
+                            store: true,
+                        }),
+                        stencil_ops: None,
+                    }),
+                    label: Some("renderpass.depth"),
+                });
+                rpass.set_pipeline(self.depth_prepass_pipeline.as_ref().unwrap());
+                self.quadtree.render(
+                    &mut rpass,
+                    &self.index_buffer,
+                    &self.bindgroup_pipeline.as_ref().unwrap().0,
+                    draws,
+                );
+            }
+
+            let color_attachments = match target {
+                RenderTarget::Color(color_buffer) => vec![wgpu::RenderPassColorAttachment {
                     view: color_buffer,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color { r: 0.0, g: 0.0, b: 0.0, a: 1.0 }),
+                        load: if depth_pass == DepthPass::Near {
+                            wgpu::LoadOp::Load
+                        } else {
+                            wgpu::LoadOp::Clear(wgpu::Color { r: 0.0, g: 0.0, b: 0.0, a: 1.0 })
+                        },
+                        store: true,
+                    },
+                }],
+                RenderTarget::Sensor(image) => {
+                    let clear = wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 }),
+                        store: true,
+                    };
+                    vec![
+                        wgpu::RenderPassColorAttachment {
+                            view: image.range,
+                            resolve_target: None,
+                            ops: clear,
+                        },
+                        wgpu::RenderPassColorAttachment {
+                            view: image.intensity,
+                            resolve_target: None,
+                            ops: clear,
+                        },
+                    ]
+                }
+                RenderTarget::Thermal(temperature) => vec![wgpu::RenderPassColorAttachment {
+                    view: temperature,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 }),
                         store: true,
                     },
                 }],
+            };
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &color_attachments,
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
                     view: depth_buffer,
                     depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(0.0),
+                        load: if depth_prepass {
+                            wgpu::LoadOp::Load
+                        } else {
+                            wgpu::LoadOp::Clear(0.0)
+                        },
                         store: true,
                     }),
                     stencil_ops: None,
                 }),
                 label: Some("renderpass"),
             });
-            rpass.set_pipeline(&self.bindgroup_pipeline.as_ref().unwrap().1);
-            self.quadtree.render(
-                &mut rpass,
-                &self.index_buffer,
-                &self.bindgroup_pipeline.as_ref().unwrap().0,
+            if imposter {
+                self.imposter.as_ref().unwrap().render(&mut rpass);
+            } else if let Some(kind) = sensor {
+                self.sensor.render(&mut rpass, kind, &self.quadtree, &self.index_buffer, draws);
+            } else {
+                rpass.set_pipeline(&self.bindgroup_pipeline.as_ref().unwrap().1);
+                self.quadtree.render(
+                    &mut rpass,
+                    &self.index_buffer,
+                    &self.bindgroup_pipeline.as_ref().unwrap().0,
+                    draws,
+                );
+
+                if self.grass && self.adaptive_quality.as_ref().map_or(true, |a| a.state().grass) {
+                    self.cache.render_meshes(device, &queue, &mut rpass, &self.gpu_state, camera);
+                }
+            }
+
+            if sky_size.is_some() {
+                self.sky_lod.as_ref().unwrap().render(&mut rpass);
+            } else if draw_sky {
+                rpass.set_pipeline(&self.sky_bindgroup_pipeline.as_ref().unwrap().1);
+                rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
+                rpass.draw(0..3, 0..1);
+            }
+        }
+
+        // With the imposter, the motion vector and G-buffer targets are only cleared.
+        if let Some(motion_vectors) = motion_vectors {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: motion_vectors,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 }),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_buffer,
+                    depth_ops: Some(wgpu::Operations { load: wgpu::LoadOp::Load, store: true }),
+                    stencil_ops: None,
+                }),
+                label: Some("renderpass.motion"),
+            });
+            if !imposter {
+                rpass.set_pipeline(&self.motion_vector_bindgroup_pipeline.as_ref().unwrap().1);
+                self.quadtree.render(
+                    &mut rpass,
+                    &self.index_buffer,
+                    &self.motion_vector_bindgroup_pipeline.as_ref().unwrap().0,
+                    self.occlusion.as_ref().filter(|_| culling).map(|o| o.draws()),
+                );
+            }
+        }
+
+        if let Some(gbuffer) = gbuffer {
+            let clear = wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color { r: 0.0, g: 0.0, b: 0.0, a: 0.0 }),
+                store: true,
+            };
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[
+                    wgpu::RenderPassColorAttachment {
+                        view: gbuffer.linear_depth,
+                        resolve_target: None,
+                        ops: clear,
+                    },
+                    wgpu::RenderPassColorAttachment {
+                        view: gbuffer.geo_position,
+                        resolve_target: None,
+                        ops: clear,
+                    },
+                ],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_buffer,
+                    depth_ops: Some(wgpu::Operations { load: wgpu::LoadOp::Load, store: true }),
+                    stencil_ops: None,
+                }),
+                label: Some("renderpass.gbuffer"),
+            });
+            if !imposter {
+                rpass.set_pipeline(&self.gbuffer_bindgroup_pipeline.as_ref().unwrap().1);
+                self.quadtree.render(
+                    &mut rpass,
+                    &self.index_buffer,
+                    &self.gbuffer_bindgroup_pipeline.as_ref().unwrap().0,
+                    self.occlusion.as_ref().filter(|_| culling).map(|o| o.draws()),
+                );
+            }
+        }
+
+        if let Some(ref mut occlusion) = self.occlusion {
+            occlusion.update_hiz(device, &mut encoder, &self.gpu_state, depth_buffer);
+        }
+
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Render a cubemap of the terrain and sky as seen from `position`, for use as image based
+    /// lighting of the application's own objects.
+    ///
+    /// The returned texture has six `Bgra8UnormSrgb` array layers of `resolution` x `resolution`,
+    /// ordered +X, -X, +Y, -Y, +Z, -Z in world space, and can be viewed with
+    /// `TextureViewDimension::Cube`.
+    pub fn render_environment_map(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        position: mint::Point3<f64>,
+        resolution: u32,
+    ) -> wgpu::Texture {
+        let size =
+            wgpu::Extent3d { width: resolution, height: resolution, depth_or_array_layers: 6 };
+        let cubemap = device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT
+                | wgpu::TextureUsage::SAMPLED
+                | wgpu::TextureUsage::COPY_SRC,
+            label: Some("texture.environment_map"),
+        });
+        let depth = device.create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d { depth_or_array_layers: 1, ..size },
+            format: wgpu::TextureFormat::Depth32Float,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+            label: Some("texture.environment_map.depth"),
+        });
+        let depth_view = depth.create_view(&Default::default());
+
+        let proj = camera::infinite_reversed_perspective(std::f64::consts::FRAC_PI_2, 1.0, 0.5)
+            .cast::<f32>()
+            .unwrap();
+        let faces = [
+            (cgmath::Vector3::unit_x(), -cgmath::Vector3::unit_y()),
+            (-cgmath::Vector3::unit_x(), -cgmath::Vector3::unit_y()),
+            (cgmath::Vector3::unit_y(), cgmath::Vector3::unit_z()),
+            (-cgmath::Vector3::unit_y(), -cgmath::Vector3::unit_z()),
+            (cgmath::Vector3::unit_z(), -cgmath::Vector3::unit_y()),
+            (-cgmath::Vector3::unit_z(), -cgmath::Vector3::unit_y()),
+        ];
+
+        // The capture must not disturb the state carried between frames of the main view.
+        let occlusion = self.occlusion.take();
+        let previous_frame = self.previous_frame.take();
+        let jitter = std::mem::replace(&mut self.jitter, mint::Vector2 { x: 0.0, y: 0.0 });
+
+        for (layer, &(direction, up)) in faces.iter().enumerate() {
+            let view =
+                cgmath::Matrix4::look_to_rh(cgmath::Point3::new(0.0, 0.0, 0.0), direction, up);
+            let color_view = cubemap.create_view(&wgpu::TextureViewDescriptor {
+                label: Some(&format!("view.environment_map[{}]", layer)),
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer as u32,
+                array_layer_count: Some(NonZeroU32::new(1).unwrap()),
+                ..Default::default()
+            });
+            self.previous_frame = None;
+            self.render_internal(
+                device,
+                queue,
+                RenderTarget::Color(&color_view),
+                &depth_view,
+                None,
+                None,
+                (resolution, resolution),
+                (proj * view).into(),
+                position,
+                DepthPass::Full,
             );
+        }
+
+        self.occlusion = occlusion;
+        self.previous_frame = previous_frame;
+        self.jitter = jitter;
+
+        cubemap
+    }
+
+    /// Blend reflections of terrain and sky into water at sea level in `color_buffer`, which must
+    /// hold the frame just drawn by `render` or `render_camera`. Reflected rays are traced against
+    /// the depth buffer and pick up their color from `sources.scene`, falling back to the
+    /// environment map for rays that leave the screen or pass behind everything drawn. Reflections
+    /// grow stronger at grazing angles.
+    ///
+    /// Does nothing unless enabled with `set_water_reflections`, or on planets without a sea.
+    pub fn render_water_reflections(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sources: &ReflectionSources,
+        color_buffer: &wgpu::TextureView,
+    ) {
+        if self.mapfile.planet().sea_level.is_none() {
+            return;
+        }
+        if let Some(ref mut reflections) = self.water_reflections {
+            reflections.render(device, queue, &self.gpu_state.globals, sources, color_buffer);
+        }
+    }
+
+    /// Add shafts of sunlight streaming through gaps in the terrain to `color_buffer`, which must
+    /// hold the frame just drawn by `render` or `render_camera`. The rays are found by blurring
+    /// the sky visible in `depth_buffer` towards the sun, so it must have been created with
+    /// `TextureUsage::SAMPLED`. Their strength is set by `Weather::light_shafts`, and nothing is
+    /// drawn while it is zero or the sun is behind the camera.
+    pub fn render_light_shafts(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        depth_buffer: &wgpu::TextureView,
+        color_buffer: &wgpu::TextureView,
+    ) {
+        if self.weather.light_shafts <= 0.0 {
+            return;
+        }
+        self.light_shafts.get_or_insert_with(LightShafts::new).render(
+            device,
+            queue,
+            &self.gpu_state.globals,
+            depth_buffer,
+            color_buffer,
+        );
+    }
+
+    /// Render a still image of the terrain as seen from `camera`, independent of any window. The
+    /// image can be larger than the window, up to the maximum texture size supported by the
+    /// device. Blocks until rendering has finished and the image has been read back.
+    ///
+    /// The returned pixels are sRGB encoded, so they can be saved directly.
+    pub fn render_to_image(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: &Camera,
+        width: u32,
+        height: u32,
+    ) -> Result<image::RgbaImage, Error> {
+        let view_proj = camera.view_proj(width as f64 / height as f64);
+        self.render_view_to_image(device, queue, view_proj, camera.position, width, height)
+    }
+
+    /// Render an image of any size by splitting it into tiles of at most `tile_size` by
+    /// `tile_size` pixels, each rendered separately with a view frustum covering just its part of
+    /// the image. This allows poster-sized renders that far exceed the maximum texture size of the
+    /// device. Tiles are passed to `callback` in row-major order along with the pixel coordinates
+    /// of their top left corners, so the image never needs to be held in memory all at once.
+    ///
+    /// Before any tiles are rendered, streaming and generation are run to completion for the
+    /// camera position so that every tile draws the same terrain detail and the seams between
+    /// them match.
+    pub fn render_tiled_image<F>(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: &Camera,
+        width: u32,
+        height: u32,
+        tile_size: u32,
+        mut callback: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(u32, u32, image::RgbaImage) -> Result<(), Error>,
+    {
+        assert!(tile_size > 0);
+        let view_proj = camera.view_proj(width as f64 / height as f64);
+
+        self.lod_pixel_scale_override = Some(Self::pixel_scale(view_proj, height));
+        self.lod_pixel_scale = self.lod_pixel_scale_override;
+        self.settle_streaming(device, queue, camera.position);
+
+        let view_proj = cgmath::Matrix4::from(view_proj);
+        let result = (|| {
+            for y0 in (0..height).step_by(tile_size as usize) {
+                for x0 in (0..width).step_by(tile_size as usize) {
+                    let (x1, y1) = ((x0 + tile_size).min(width), (y0 + tile_size).min(height));
+
+                    // Map the part of clip space covered by the tile onto the whole of it.
+                    let left = 2.0 * x0 as f32 / width as f32 - 1.0;
+                    let right = 2.0 * x1 as f32 / width as f32 - 1.0;
+                    let top = 1.0 - 2.0 * y0 as f32 / height as f32;
+                    let bottom = 1.0 - 2.0 * y1 as f32 / height as f32;
+                    let (sx, sy) = (2.0 / (right - left), 2.0 / (top - bottom));
+                    let (cx, cy) = ((left + right) * 0.5, (top + bottom) * 0.5);
+                    #[rustfmt::skip]
+                    let crop = cgmath::Matrix4::new(
+                        sx, 0.0, 0.0, 0.0,
+                        0.0, sy, 0.0, 0.0,
+                        0.0, 0.0, 1.0, 0.0,
+                        -sx * cx, -sy * cy, 0.0, 1.0,
+                    );
+
+                    let tile = self.render_view_to_image(
+                        device,
+                        queue,
+                        (crop * view_proj).into(),
+                        camera.position,
+                        x1 - x0,
+                        y1 - y0,
+                    )?;
+                    callback(x0, y0, tile)?;
+                }
+            }
+            Ok(())
+        })();
 
-            self.cache.render_meshes(device, &queue, &mut rpass, &self.gpu_state, camera);
+        self.lod_pixel_scale_override = None;
+        result
+    }
+
+    /// Number of pixels per unit of distance at unit depth, used to convert screen-space error
+    /// into world-space error.
+    fn pixel_scale(view_proj: mint::ColumnMatrix4<f32>, frame_height: u32) -> f64 {
+        let m = cgmath::Matrix4::from(view_proj);
+        let focal_length = (m.x.y * m.x.y + m.y.y * m.y.y + m.z.y * m.z.y).sqrt() as f64;
+        focal_length * frame_height as f64 * 0.5
+    }
+
+    /// Update the tile cache until every tile needed to view the terrain from `camera` is either
+    /// loaded or cannot be, giving up after a fixed number of updates.
+    fn settle_streaming(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        camera: mint::Point3<f64>,
+    ) {
+        let mut previous = None;
+        for _ in 0..10000 {
+            self.update_visibility(camera);
+            self.update_cache(device, queue);
+            device.poll(wgpu::Maintain::Wait);
 
-            rpass.set_pipeline(&self.sky_bindgroup_pipeline.as_ref().unwrap().1);
-            rpass.set_bind_group(0, &self.sky_bindgroup_pipeline.as_ref().unwrap().0, &[]);
-            rpass.draw(0..3, 0..1);
+            let valid = self.cache.tiles.valid_layers();
+            let idle = self.cache.tiles.is_idle();
+            if idle && previous.as_ref() == Some(&valid) {
+                break;
+            } else if !idle {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            previous = Some(valid);
         }
+    }
+
+    fn render_view_to_image(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        view_proj: mint::ColumnMatrix4<f32>,
+        position: mint::Point3<f64>,
+        width: u32,
+        height: u32,
+    ) -> Result<image::RgbaImage, Error> {
+        let size = wgpu::Extent3d { width, height, depth_or_array_layers: 1 };
+        let color = device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            format: wgpu::TextureFormat::Bgra8UnormSrgb,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+            label: Some("texture.image"),
+        });
+        let depth = device.create_texture(&wgpu::TextureDescriptor {
+            size,
+            format: wgpu::TextureFormat::Depth32Float,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+            label: Some("texture.image.depth"),
+        });
+
+        // As with environment maps, the still must not disturb the state of the main view.
+        let occlusion = self.occlusion.take();
+        let previous_frame = self.previous_frame.take();
+        let jitter = std::mem::replace(&mut self.jitter, mint::Vector2 { x: 0.0, y: 0.0 });
+        self.render_internal(
+            device,
+            queue,
+            RenderTarget::Color(&color.create_view(&Default::default())),
+            &depth.create_view(&Default::default()),
+            None,
+            None,
+            (width, height),
+            view_proj,
+            position,
+            DepthPass::Full,
+        );
+        self.occlusion = occlusion;
+        self.previous_frame = previous_frame;
+        self.jitter = jitter;
 
+        // Rows of the readback buffer must be padded to a multiple of the copy alignment.
+        let row_bytes = width * 4;
+        let row_pitch = (row_bytes + wgpu::COPY_BYTES_PER_ROW_ALIGNMENT - 1)
+            & !(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT - 1);
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: row_pitch as u64 * height as u64,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            label: Some("buffer.image.download"),
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.image.download"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &color,
+                mip_level: 0,
+                origin: wgpu::Origin3d::default(),
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(NonZeroU32::new(row_pitch).unwrap()),
+                    rows_per_image: None,
+                },
+            },
+            size,
+        );
         queue.submit(Some(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapped = slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapped)?;
+
+        let mut pixels = Vec::with_capacity(row_bytes as usize * height as usize);
+        for row in slice.get_mapped_range().chunks_exact(row_pitch as usize) {
+            // Swap from BGRA to RGBA.
+            for bgra in row[..row_bytes as usize].chunks_exact(4) {
+                pixels.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+            }
+        }
+        buffer.unmap();
+
+        Ok(image::RgbaImage::from_raw(width, height, pixels).unwrap())
     }
 
     pub fn get_height(&self, latitude: f64, longitude: f64) -> f32 {
+        Self::collision_height(
+            &self.cache.tiles,
+            self.shared_quadtree.as_ref(),
+            latitude,
+            longitude,
+        )
+    }
+
+    /// Elevation and ruggedness statistics of a node, computed when its heightmap tile was
+    /// streamed or generated. Returns None if the heightmap tile of the node isn't loaded.
+    pub fn node_statistics(&self, node: VectorTileId) -> Option<NodeStatistics> {
+        self.cache.tiles.statistics(node.0)
+    }
+
+    /// Biome at the given point, from the most detailed biome tile generated there. Biome tiles are
+    /// generated alongside the heightmap and albedo tiles they are derived from, so like
+    /// `get_height` the answer depends on what is currently loaded. Returns None if no tile
+    /// covering the point has been generated and read back yet.
+    pub fn biome_at(&self, latitude: f64, longitude: f64) -> Option<Biome> {
+        (0..=VNode::LEVEL_CELL_1M)
+            .rev()
+            .find_map(|level| self.cache.tiles.get_biome(latitude, longitude, level))
+    }
+
+    /// Where the most detailed heightmap tile loaded at the given point came from, and when it was
+    /// produced: the time its file was written for stored tiles, or the time it was requested for
+    /// tiles generated on the fly. Useful for including in reports of bad terrain. Returns None if
+    /// no tile covering the point is loaded.
+    pub fn tile_provenance(
+        &self,
+        latitude: f64,
+        longitude: f64,
+    ) -> Option<(TileProvenance, std::time::SystemTime)> {
+        let ecef = coordinates::polar_to_ecef(cgmath::Vector3::new(latitude, longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+        (0..=VNode::LEVEL_CELL_2CM)
+            .rev()
+            .find_map(|level| self.cache.tiles.provenance(VNode::from_cspace(cspace, level).0))
+    }
+
+    /// Intersect many rays with the terrain at once using a compute shader, for uses like lidar
+    /// simulation or visibility checks that need thousands of rays per frame. Like `get_height`,
+    /// rays are tested against the heightmap tiles currently loaded.
+    ///
+    /// The returned future resolves to one entry per ray once the GPU has finished, which requires
+    /// the device to be polled, as happens during `render`.
+    pub fn raycast_batch(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        rays: &[Ray],
+    ) -> Result<impl futures::Future<Output = Result<Vec<Option<RayHit>>, Error>>, Error> {
+        let elevation_range = self.mapfile.planet().drawn_range();
+        self.raycaster.raycast(device, queue, &self.gpu_state, &self.cache, elevation_range, rays)
+    }
+
+    /// Whether a world space point lies below the terrain surface, judged from the tiles currently
+    /// loaded so that the answer matches what is rendered.
+    pub fn is_underground(&self, point: mint::Point3<f64>) -> bool {
+        let polar = coordinates::ecef_to_polar(cgmath::Vector3::new(point.x, point.y, point.z));
+        polar.z < self.get_height(polar.x, polar.y) as f64
+    }
+
+    /// Approximate signed distance in meters from a world space point to the terrain surface as
+    /// currently rendered, positive above ground and negative below. See
+    /// `HeadlessTerrain::distance_to_surface`.
+    pub fn distance_to_surface(&self, point: mint::Point3<f64>) -> f64 {
+        let point = cgmath::Vector3::new(point.x, point.y, point.z);
+        query::distance_to_surface(point, |latitude, longitude| {
+            Ok(self.get_height(latitude, longitude))
+        })
+        .unwrap()
+    }
+
+    /// Place points every `spacing` meters along the great circle arcs joining the given
+    /// (latitude, longitude) pairs, in radians. Each point is clamped to the terrain as currently
+    /// rendered and comes with the surface normal and direction of travel, which is what is needed
+    /// to lay out roads, fences or camera rails. The last vertex is always included.
+    pub fn sample_path(
+        &self,
+        polyline: &[(f64, f64)],
+        spacing: f64,
+    ) -> Result<Vec<PathSample>, Error> {
+        query::sample_path(polyline, spacing, |latitude, longitude| {
+            Ok(self.get_height(latitude, longitude))
+        })
+    }
+
+    /// Like `height_from_tiles`, but with server-authoritative streaming only reading from tiles
+    /// up to the level of the shared quadtree's leaf at the point.
+    fn collision_height(
+        tiles: &TileCache,
+        shared_quadtree: Option<&SharedQuadTree>,
+        latitude: f64,
+        longitude: f64,
+    ) -> f32 {
+        let max_level = match shared_quadtree {
+            Some(shared) => shared.leaf(latitude, longitude).level(),
+            None => VNode::LEVEL_CELL_1M,
+        };
+        (0..=max_level)
+            .rev()
+            .find_map(|level| tiles.get_height(latitude, longitude, level))
+            .unwrap_or(0.0)
+    }
+
+    fn height_from_tiles(tiles: &TileCache, latitude: f64, longitude: f64) -> f32 {
         for level in (0..=VNode::LEVEL_CELL_1M).rev() {
-            if let Some(height) = self.cache.tiles.get_height(latitude, longitude, level) {
+            if let Some(height) = tiles.get_height(latitude, longitude, level) {
                 return height;
             }
         }
         0.0
     }
+
+    /// Start tracking the ground elevation at the given point. Whenever it changes, because more
+    /// detailed tiles were streamed in or evicted, the callback passed to
+    /// `set_elevation_callback` is invoked with the new elevation and the change in meters. This
+    /// lets physics engines wake bodies resting on the terrain.
+    ///
+    /// Elevations are checked once per call to `render`.
+    pub fn watch_elevation(&mut self, latitude: f64, longitude: f64) -> ElevationWatchId {
+        self.elevation_watches.add(latitude, longitude)
+    }
+
+    /// Stop tracking a point previously passed to `watch_elevation`.
+    pub fn unwatch_elevation(&mut self, id: ElevationWatchId) {
+        self.elevation_watches.remove(id)
+    }
+
+    /// Set the callback invoked when the elevation beneath a watched point changes.
+    pub fn set_elevation_callback(&mut self, callback: Option<ElevationCallback>) {
+        self.elevation_watches.set_callback(callback)
+    }
+
+    /// Set the callback that vector tiles are passed to as they are paged in and out alongside the
+    /// terrain tiles of the same nodes. Vector tiles are written with
+    /// `HeadlessTerrain::write_vector_tile`, and are only loaded while a callback is set. The
+    /// callback is invoked from `render`.
+    pub fn set_vector_tile_callback(&mut self, callback: Option<VectorTileCallback>) {
+        self.cache.tiles.set_vector_tile_callback(callback)
+    }
+
+    /// Enable or disable place name labels. While enabled, place name tiles written with
+    /// `generate_place_names` are streamed in alongside the terrain, and each call to `render` or
+    /// `render_camera` projects the resident places into the frame and tests them against the
+    /// depth buffer, which must then have been created with `TextureUsage::SAMPLED`. Results are
+    /// available from `label_anchors`.
+    pub fn set_place_labels(&mut self, enabled: bool) {
+        self.cache.tiles.set_load_places(enabled);
+        if !enabled {
+            self.place_labels = None;
+        } else if self.place_labels.is_none() {
+            self.place_labels = Some(PlaceLabels::new());
+        }
+    }
+
+    /// Screen space positions at which the application can draw labels for the places in view,
+    /// closest first. Occlusion is read back from the GPU asynchronously, so the anchors trail the
+    /// rendered frames by one or more frames. With a depth partition, places beyond it are only
+    /// tested against the terrain of the near pass. Empty unless enabled with `set_place_labels`.
+    pub fn label_anchors(&self) -> &[LabelAnchor] {
+        self.place_labels.as_ref().map(|l| l.anchors()).unwrap_or(&[])
+    }
+
+    fn update_place_labels(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        depth_buffer: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        view_proj: mint::ColumnMatrix4<f32>,
+        camera: mint::Point3<f64>,
+    ) {
+        if let Some(ref mut place_labels) = self.place_labels {
+            let tiles = &self.cache.tiles;
+            let anchors = labels::project(
+                tiles.resident_places(),
+                |latitude, longitude| Self::height_from_tiles(tiles, latitude, longitude),
+                view_proj.into(),
+                cgmath::Vector3::new(camera.x, camera.y, camera.z),
+                frame_size,
+            );
+            place_labels.update(device, queue, depth_buffer, anchors);
+        }
+    }
+
+    /// Stop drawing terrain inside a polygon, so that applications can insert their own geometry
+    /// for features like tunnel portals and cave entrances. `polygon` gives the latitude and
+    /// longitude of each vertex in radians, and may be concave but must not intersect itself.
+    /// The polygon is extruded vertically, so terrain is cut at any elevation within it.
+    ///
+    /// Up to 64 holes with a combined 1024 vertices are supported. While any holes exist the
+    /// depth pre-pass is skipped. Grass and shadows are not affected.
+    pub fn add_hole(&mut self, polygon: &[(f64, f64)]) -> Result<TerrainHoleId, Error> {
+        let was_empty = self.holes.is_empty();
+        let id = self.holes.add(polygon)?;
+        if was_empty {
+            self.bindgroup_pipeline = None;
+            self.depth_prepass_pipeline = None;
+        }
+        if let Some(ref mut shared) = self.shared_quadtree {
+            shared.record_hole(TerrainDelta::AddHole(id, polygon.to_vec()));
+        }
+        Ok(id)
+    }
+
+    /// Remove a hole previously added with `add_hole`.
+    pub fn remove_hole(&mut self, id: TerrainHoleId) {
+        self.holes.remove(id);
+        if self.holes.is_empty() {
+            self.bindgroup_pipeline = None;
+            self.depth_prepass_pipeline = None;
+        }
+        if let Some(ref mut shared) = self.shared_quadtree {
+            shared.record_hole(TerrainDelta::RemoveHole(id));
+        }
+    }
+
+    /// Change the heights of one heightmap tile, for instance to blast a crater or dig a trench.
+    /// The delta stays in effect until `revert_height_delta` is called with it, and is added to
+    /// the tile again whenever the tile is reloaded. Tiles of the node's ancestors and descendants
+    /// keep their heights, so deltas are best made to the most detailed tiles, at level 14, which
+    /// are what `get_height` reads from up close.
+    ///
+    /// If the tile is loaded the node's normals and other layers are regenerated, so it may
+    /// briefly be drawn at a coarser level.
+    pub fn apply_height_delta(&mut self, delta: &HeightDelta) -> Result<(), Error> {
+        self.cache.tiles.apply_height_delta(delta, false)
+    }
+
+    /// Undo a delta previously passed to `apply_height_delta`. Deltas to the same tile may be
+    /// reverted in any order.
+    pub fn revert_height_delta(&mut self, delta: &HeightDelta) -> Result<(), Error> {
+        self.cache.tiles.apply_height_delta(delta, true)
+    }
+
+    /// Register a pass that modifies heightmap tiles as they are generated. See
+    /// `HeadlessTerrain::add_heightmap_pass`.
+    pub fn add_heightmap_pass<F>(&mut self, name: &str, pass: F)
+    where
+        F: Fn(&mut TileData, TileNode) + Send + Sync + 'static,
+    {
+        self.heightmap_passes.push(HeightmapPass { name: name.to_owned(), pass: Arc::new(pass) });
+    }
+
+    /// Import airport runways and flatten the ground beneath them in heightmap tiles generated
+    /// afterwards. See `HeadlessTerrain::add_runways`.
+    pub fn add_runways(
+        &mut self,
+        airports_csv: impl AsRef<Path>,
+        runways_csv: impl AsRef<Path>,
+    ) -> Result<(), Error> {
+        self.runways = airports::ingest(
+            &self.mapfile,
+            &mut self.heightmap_passes,
+            airports_csv.as_ref(),
+            runways_csv.as_ref(),
+        )?;
+        Ok(())
+    }
+
+    /// Look up a runway by airport and designator. See `HeadlessTerrain::runway`.
+    pub fn runway(&self, airport: &str, ident: &str) -> Option<Runway> {
+        self.runways.find(airport, ident)
+    }
+
+    /// Runways near a point, closest first. See `HeadlessTerrain::runways_near`.
+    pub fn runways_near(&self, latitude: f64, longitude: f64, radius: f64) -> Vec<&Runway> {
+        self.runways.near(latitude, longitude, radius)
+    }
+
+    /// Set how many samples per axis are averaged for each albedo texel. See
+    /// `HeadlessTerrain::set_albedo_supersampling`.
+    pub fn set_albedo_supersampling(&mut self, factor: u32) {
+        assert!(factor >= 1 && factor <= 4);
+        self.albedo_supersampling = factor;
+    }
+
+    fn tile_generator(&self) -> TileGenerator {
+        TileGenerator {
+            mapfile: Arc::clone(&self.mapfile),
+            heightmap_passes: self.heightmap_passes.clone(),
+            albedo_supersampling: self.albedo_supersampling,
+        }
+    }
+
+    /// Generate heightmap tiles. See `HeadlessTerrain::generate_heightmaps`.
+    pub async fn generate_heightmaps<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        etopo1_file: impl AsRef<Path>,
+        srtm3_directory: PathBuf,
+        progress_callback: F,
+    ) -> Result<RasterCacheStats, Error> {
+        let stats = self
+            .tile_generator()
+            .generate_heightmaps(etopo1_file, srtm3_directory, progress_callback)
+            .await?;
+        self.recorded_elevation_ranges.clear();
+        self.quadtree.invalidate_visibility();
+        Ok(stats)
+    }
+
+    /// Generate albedo tiles. See `HeadlessTerrain::generate_albedos`.
+    pub async fn generate_albedos<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        blue_marble_directory: impl AsRef<Path>,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        self.tile_generator().generate_albedos(blue_marble_directory, progress_callback).await
+    }
+
+    /// Generate a dated set of albedo tiles. See `HeadlessTerrain::generate_albedo_epoch`.
+    pub async fn generate_albedo_epoch<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        year: i16,
+        month: u8,
+        blue_marble_directory: impl AsRef<Path>,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        self.tile_generator()
+            .generate_albedo_epoch(year, month, blue_marble_directory, progress_callback)
+            .await
+    }
+
+    /// Generate roughness tiles. See `HeadlessTerrain::generate_roughness`.
+    pub async fn generate_roughness<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        self.tile_generator().generate_roughness(progress_callback).await
+    }
+
+    /// Generate place name tiles. See `HeadlessTerrain::generate_place_names`.
+    pub fn generate_place_names<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        geonames_file: impl AsRef<Path>,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        self.tile_generator().generate_place_names(geonames_file, progress_callback)
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "render"))]
 mod tests {
     #[test]
     fn check_send() {
@@ -1,18 +1,49 @@
+use crate::airports::Runway;
 use crate::asset::TERRA_DIRECTORY;
+#[cfg(feature = "render")]
+use crate::cache::LayerDesc;
 use crate::cache::{LayerParams, LayerType, TextureFormat};
+use crate::coordinates::LatLongBounds;
+use crate::download::{DownloadLimits, Mirrors};
+use crate::generate::FractalDetailTable;
+use crate::planet::PlanetConfig;
+#[cfg(test)]
+use crate::synthetic::SyntheticPlanet;
 use crate::terrain::quadtree::node::VNode;
-use anyhow::Error;
-use atomicwrites::{AtomicFile, OverwriteBehavior};
+use anyhow::{ensure, Error};
+#[cfg(feature = "render")]
+use cgmath::Vector3;
 use image::bmp::BmpEncoder;
+use log::{debug, info, trace, warn};
+use memmap::Mmap;
 use serde::{Deserialize, Serialize};
-use std::io::Write;
-use std::path::PathBuf;
-use std::{fs, num::NonZeroU32};
+use std::collections::HashMap;
+#[cfg(feature = "render")]
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+#[cfg(feature = "render")]
+use std::num::NonZeroU32;
+#[cfg(feature = "render")]
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(any(feature = "render", test))]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncReadExt;
 use vec_map::VecMap;
+use zip::result::ZipError;
+#[cfg(feature = "render")]
+use zip::CompressionMethod;
+use zip::ZipArchive;
 
 const TERRA_TILES_URL: &str = "https://terra.fintelia.io/file/terra-tiles/";
 
+/// Log target for the tile store. See the crate documentation.
+const LOG_TARGET: &str = "terra::store";
+
 #[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum TileState {
     Missing,
@@ -22,6 +53,33 @@ pub(crate) enum TileState {
     MissingBase,
 }
 
+/// Contents of a tile file, either read into memory or mapped from disk so that its pages are only
+/// read in as they are used. See `MapFile::read_tile_bytes`.
+#[cfg(feature = "render")]
+pub(crate) enum TileBytes {
+    Owned(Vec<u8>),
+    Mapped(Arc<Mmap>, Range<usize>),
+}
+#[cfg(feature = "render")]
+impl Deref for TileBytes {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match self {
+            TileBytes::Owned(data) => data,
+            TileBytes::Mapped(map, range) => &map[range.clone()],
+        }
+    }
+}
+#[cfg(feature = "render")]
+impl fmt::Debug for TileBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TileBytes::Owned(data) => write!(f, "Owned({} bytes)", data.len()),
+            TileBytes::Mapped(_, range) => write!(f, "Mapped({} bytes)", range.len()),
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Serialize, Deserialize)]
 pub(crate) enum TileKind {
     Base,
@@ -29,10 +87,36 @@ pub(crate) enum TileKind {
     GpuOnly,
 }
 
-#[derive(PartialEq, Eq, Serialize, Deserialize)]
+/// Where the tile of a node came from. Shown by `Terrain::set_provenance_overlay` to help track
+/// down the source of bad data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TileProvenance {
+    /// Downloaded from a tile server.
+    Downloaded,
+    /// Generated locally from real elevation or imagery datasets.
+    Dataset,
+    /// Synthesized from its parent by adding fractal detail, either on the fly or earlier and
+    /// saved to disk.
+    Fractal,
+}
+
+#[derive(PartialEq, Serialize, Deserialize)]
 struct TileMeta {
     crc32: u32,
     state: TileState,
+    /// Minimum and maximum elevation within the tile. Only recorded for heightmaps.
+    elevation_range: Option<(f32, f32)>,
+    /// Maximum vertical error in meters of drawing the tile instead of its children. Only
+    /// recorded for heightmaps.
+    geometric_error: Option<f32>,
+}
+
+/// HTTP cache validators returned by the tile server along with a downloaded tile, used to check
+/// whether it has since changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct Validator {
+    etag: Option<String>,
+    last_modified: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -49,21 +133,139 @@ pub(crate) struct ShaderDescriptor {
     hash: [u8; 32],
 }
 
+/// A set of albedo tiles showing the planet at a particular point in time.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct AlbedoEpoch {
+    /// Name of the directory holding the epoch's tiles.
+    pub name: String,
+    pub julian_day: f64,
+}
+
+/// A pair of albedo epochs to crossfade between.
+#[cfg(feature = "render")]
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct AlbedoBlend {
+    pub older: AlbedoEpoch,
+    pub newer: AlbedoEpoch,
+    /// Weight given to `newer`, from zero up to but excluding one. This is quantized so that small
+    /// changes in date don't cause every albedo tile to be reloaded.
+    pub weight: f32,
+}
+#[cfg(feature = "render")]
+impl AlbedoBlend {
+    const WEIGHT_STEPS: f32 = 32.0;
+
+    /// Blend between the two epochs nearest to `julian_day`, or just the first or last epoch if
+    /// the date is outside of the range covered. `epochs` must be sorted by date.
+    pub fn between(epochs: &[AlbedoEpoch], julian_day: f64) -> Option<Self> {
+        let (older, newer) = match epochs.iter().position(|e| e.julian_day > julian_day) {
+            Some(0) => (epochs.first()?, epochs.first()?),
+            Some(i) => (&epochs[i - 1], &epochs[i]),
+            None => (epochs.last()?, epochs.last()?),
+        };
+        let weight = if older == newer {
+            0.0
+        } else {
+            ((julian_day - older.julian_day) / (newer.julian_day - older.julian_day)) as f32
+        };
+        let weight = (weight * Self::WEIGHT_STEPS).round() / Self::WEIGHT_STEPS;
+        if weight >= 1.0 {
+            return Some(Self { older: newer.clone(), newer: newer.clone(), weight: 0.0 });
+        }
+        Some(Self { older: older.clone(), newer: newer.clone(), weight })
+    }
+
+    /// Mix two decoded RGBA8 tiles according to `weight`.
+    pub fn mix(&self, older: &[u8], newer: &[u8]) -> Vec<u8> {
+        older
+            .iter()
+            .zip(newer)
+            .map(|(&a, &b)| (a as f32 + (b as f32 - a as f32) * self.weight).round() as u8)
+            .collect()
+    }
+}
+
+/// How thoroughly writes to the map file are flushed to disk. Pass to
+/// `Terrain::set_sync_policy`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Flush every file and the directory containing it before a write is recorded, so that
+    /// written tiles survive a power failure. This is the default.
+    Always,
+    /// Leave flushing to the operating system. Files are still replaced atomically, so a crash of
+    /// the process never leaves a partial tile behind, but the most recent writes may be lost or
+    /// truncated if the whole machine goes down.
+    Never,
+}
+
+/// Where a map file keeps its tiles, textures and metadata. Set with `TerrainOptions::tile_store`
+/// or `HeadlessTerrain::with_tile_store`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TileStore {
+    /// Keep everything in the terra cache directory, so that later runs can reuse it.
+    Disk,
+    /// Keep every file in RAM and metadata in a temporary database, all of which are discarded
+    /// when the terrain is dropped. Nothing in the cache directory is read or modified, so each
+    /// run starts from scratch. Meant for tests, sandboxed environments and short-lived
+    /// procedural planets.
+    Memory,
+}
+impl Default for TileStore {
+    fn default() -> Self {
+        TileStore::Disk
+    }
+}
+
 pub(crate) struct MapFile {
     layers: VecMap<LayerParams>,
-    _db: sled::Db,
+    db: sled::Db,
     tiles: sled::Tree,
     textures: sled::Tree,
+    /// Files currently being written, along with the tile each belongs to if any. Entries left
+    /// over from a crash are cleaned up when the map file is next opened.
+    journal: sled::Tree,
+    /// Cache validators of downloaded tiles, keyed the same way as `tiles`.
+    validators: sled::Tree,
+    /// Every file in the tiles directory, keyed by its path relative to that directory, so that
+    /// checking whether a tile exists doesn't touch the filesystem. Kept up to date as files are
+    /// written and removed. None if files aren't stored on disk.
+    file_index: Option<sled::Tree>,
+    sync_writes: AtomicBool,
+    /// Contents of every file for `TileStore::Memory`, keyed by the path it would have on disk.
+    memory_files: Option<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+    /// Read-only archive consulted for files that aren't in the tile store.
+    archive: Option<Mutex<ZipArchive<fs::File>>>,
+    /// The whole archive mapped into memory, for reading entries stored uncompressed in place.
+    archive_map: Option<Arc<Mmap>>,
+    /// Whether `read_tile_bytes` maps tile files instead of reading them.
+    #[cfg(feature = "render")]
+    memory_mapped: AtomicBool,
+    download_limits: Mutex<DownloadLimits>,
+    /// Tile servers that base tiles are downloaded from.
+    mirrors: Mutex<Mirrors>,
+    planet: PlanetConfig,
+    /// Procedural planet that tiles are read from instead of the filesystem.
+    #[cfg(test)]
+    synthetic: Option<SyntheticPlanet>,
 }
 impl MapFile {
-    pub(crate) fn new(layers: VecMap<LayerParams>) -> Self {
-        let directory = TERRA_DIRECTORY.join("tiles/meta");
-        let db = sled::open(&directory).expect(&format!(
-            "Failed to open/create sled database. Deleting the '{}' directory may fix this",
-            directory.display()
-        ));
-
-        const CURRENT_VERSION: i32 = 2;
+    pub(crate) fn new(layers: VecMap<LayerParams>, store: TileStore) -> Self {
+        let (db, memory_files) = match store {
+            TileStore::Disk => {
+                let directory = TERRA_DIRECTORY.join("tiles/meta");
+                let db = sled::open(&directory).expect(&format!(
+                    "Failed to open/create sled database. Deleting the '{}' directory may fix this",
+                    directory.display()
+                ));
+                (db, None)
+            }
+            TileStore::Memory => (
+                sled::Config::new().temporary(true).open().unwrap(),
+                Some(Mutex::new(HashMap::new())),
+            ),
+        };
+
+        const CURRENT_VERSION: i32 = 4;
         let version = db.get("version").unwrap();
         let version = version
             .as_ref()
@@ -72,51 +274,435 @@ impl MapFile {
             .unwrap_or(Ok(CURRENT_VERSION))
             .unwrap();
         if version < CURRENT_VERSION {
+            info!(
+                target: LOG_TARGET,
+                "upgrading tile store from version {} to {}, forgetting tile states",
+                version,
+                CURRENT_VERSION
+            );
             db.drop_tree("tiles").unwrap();
             db.drop_tree("textures").unwrap();
         }
         db.insert("version", &*format!("{}", CURRENT_VERSION)).unwrap();
 
-        Self {
+        let mut mapfile = Self {
             layers,
             tiles: db.open_tree("tiles").unwrap(),
             textures: db.open_tree("textures").unwrap(),
-            _db: db,
+            journal: db.open_tree("journal").unwrap(),
+            validators: db.open_tree("validators").unwrap(),
+            file_index: None,
+            sync_writes: AtomicBool::new(true),
+            memory_files,
+            archive: None,
+            archive_map: None,
+            #[cfg(feature = "render")]
+            memory_mapped: AtomicBool::new(false),
+            download_limits: Mutex::new(DownloadLimits::default()),
+            mirrors: Mutex::new(Mirrors::new(&[TERRA_TILES_URL.to_owned()])),
+            planet: PlanetConfig::EARTH,
+            db,
+            #[cfg(test)]
+            synthetic: None,
+        };
+        if store == TileStore::Disk {
+            mapfile.open_file_index().unwrap();
         }
+        mapfile.recover_interrupted_writes().unwrap();
+        mapfile
+    }
+
+    /// Create a map file backed by a tiny in-memory planet of the given radius, so that code which
+    /// consumes tiles can be tested without any datasets. Only the first few levels have tiles.
+    #[cfg(test)]
+    pub(crate) fn synthetic(radius: f64, seed: u64) -> Self {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mapfile = Self {
+            layers: SyntheticPlanet::layers(),
+            tiles: db.open_tree("tiles").unwrap(),
+            textures: db.open_tree("textures").unwrap(),
+            journal: db.open_tree("journal").unwrap(),
+            validators: db.open_tree("validators").unwrap(),
+            file_index: None,
+            sync_writes: AtomicBool::new(true),
+            memory_files: None,
+            archive: None,
+            archive_map: None,
+            #[cfg(feature = "render")]
+            memory_mapped: AtomicBool::new(false),
+            download_limits: Mutex::new(DownloadLimits::default()),
+            mirrors: Mutex::new(Mirrors::new(&[TERRA_TILES_URL.to_owned()])),
+            planet: PlanetConfig::EARTH,
+            db,
+            synthetic: Some(SyntheticPlanet::new(radius, seed)),
+        };
+
+        let planet = mapfile.synthetic.as_ref().unwrap();
+        for &layer in [LayerType::Heightmaps, LayerType::Albedo, LayerType::Roughness].iter() {
+            VNode::breadth_first(|node| {
+                if !SyntheticPlanet::has_tile(layer, node) {
+                    return false;
+                }
+                let elevation_range = if layer == LayerType::Heightmaps {
+                    Some(planet.elevation_range(&mapfile.layers[layer], node))
+                } else {
+                    None
+                };
+                let meta = TileMeta {
+                    crc32: 0,
+                    state: TileState::Base,
+                    elevation_range,
+                    geometric_error: None,
+                };
+                mapfile.update_tile_meta(layer, node, meta).unwrap();
+                true
+            });
+        }
+        mapfile
     }
 
     pub(crate) fn tile_state(&self, layer: LayerType, node: VNode) -> Result<TileState, Error> {
         Ok(match self.lookup_tile_meta(layer, node)? {
+            // Base tiles that may not be downloaded are generated from their parents instead.
+            Some(TileMeta { state: TileState::MissingBase, .. })
+                if !self.download_limits.lock().unwrap().may_download(node)
+                    && !self.file_exists(&Self::tile_path(layer, node)) =>
+            {
+                TileState::GpuOnly
+            }
             Some(meta) => meta.state,
             None => TileState::GpuOnly,
         })
     }
+    /// Where the tile for `node` comes from, along with when it was written if it is stored on
+    /// disk. Tiles that haven't been downloaded yet are reported as they will be once they are.
+    #[cfg(any(feature = "render", test))]
+    pub(crate) fn tile_provenance(
+        &self,
+        layer: LayerType,
+        node: VNode,
+    ) -> (TileProvenance, Option<SystemTime>) {
+        // Only downloads record a validator, so base tiles without one were generated locally
+        // unless they came from a tile archive.
+        let filename = Self::tile_path(layer, node);
+        let key = bincode::serialize(&(layer, node)).unwrap();
+        let downloaded = self.validators.contains_key(key).unwrap_or(false)
+            || (!self.stored_file_exists(&filename) && self.archive_contains(&filename));
+        let provenance = match self.tile_state(layer, node) {
+            Ok(TileState::MissingBase) => TileProvenance::Downloaded,
+            Ok(TileState::Base) if downloaded => TileProvenance::Downloaded,
+            Ok(TileState::Base) => TileProvenance::Dataset,
+            _ => TileProvenance::Fractal,
+        };
+        let modified = match self.memory_files {
+            Some(_) => None,
+            None => fs::metadata(&filename).and_then(|m| m.modified()).ok(),
+        };
+        (provenance, modified)
+    }
+
     pub(crate) async fn read_tile(&self, layer: LayerType, node: VNode) -> Result<Vec<u8>, Error> {
+        #[cfg(test)]
+        if let Some(ref planet) = self.synthetic {
+            return Ok(planet.tile(&self.layers[layer], node));
+        }
+
         let filename = Self::tile_path(layer, node);
-        if !filename.exists() {
+        if !self.file_exists(&filename) {
             match layer {
                 LayerType::Albedo | LayerType::Heightmaps | LayerType::Roughness => {
-                    let url = Self::tile_url(layer, node);
-                    let client = hyper::Client::builder()
-                        .build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
-                    let resp = client.get(url.parse()?).await?;
-                    if resp.status().is_success() {
-                        let data = hyper::body::to_bytes(resp.into_body()).await?.to_vec();
+                    ensure!(
+                        self.download_limits.lock().unwrap().may_download(node),
+                        "Downloading '{:?}' is not permitted on a metered connection",
+                        filename
+                    );
+                    let name = Self::tile_name(layer, node);
+                    debug!(target: LOG_TARGET, "downloading {:?} tile {:?}", layer, node);
+                    if let Some((data, validator)) = self.download(&name, None).await? {
                         // TODO: Fix lifetime issues so we can do this tile write asynchronously.
-                        tokio::task::block_in_place(|| self.write_tile(layer, node, &data, true))?;
+                        tokio::task::block_in_place(|| {
+                            self.write_tile(layer, node, &data, true)?;
+                            self.update_validator(layer, node, &validator)
+                        })?;
                         return Ok(data);
-                    } else {
-                        panic!("Tile download failed with {:?} for URL '{}'", resp.status(), url);
                     }
                 }
                 _ => {}
             }
             anyhow::bail!("Tile missing: '{:?}'", filename);
         }
+        trace!(target: LOG_TARGET, "reading {:?} tile {:?}", layer, node);
+        self.read_file(&filename).await
+    }
 
-        let mut contents = Vec::new();
-        tokio::fs::File::open(filename).await?.read_to_end(&mut contents).await?;
-        Ok(contents)
+    /// Same as `read_tile`, except that with memory mapping enabled, tiles in files on disk or
+    /// stored uncompressed in the archive are mapped rather than read.
+    #[cfg(feature = "render")]
+    pub(crate) async fn read_tile_bytes(
+        &self,
+        layer: LayerType,
+        node: VNode,
+    ) -> Result<TileBytes, Error> {
+        if self.memory_mapped.load(Ordering::Relaxed) {
+            if let Some(bytes) = self.map_file(&Self::tile_path(layer, node))? {
+                return Ok(bytes);
+            }
+        }
+        Ok(TileBytes::Owned(self.read_tile(layer, node).await?))
+    }
+
+    /// Download the tile file `name` from whichever tile server is healthy and least busy, failing
+    /// over to the others in turn if that doesn't work. If `validator` is given, nothing is
+    /// downloaded and None is returned when the file hasn't changed.
+    async fn download(
+        &self,
+        name: &str,
+        validator: Option<&Validator>,
+    ) -> Result<Option<(Vec<u8>, Validator)>, Error> {
+        loop {
+            let delay = self.download_limits.lock().unwrap().delay(Instant::now());
+            if delay == Duration::from_secs(0) {
+                break;
+            }
+            tokio::time::sleep(delay).await;
+        }
+
+        let candidates = self.mirrors.lock().unwrap().candidates(Instant::now());
+        let mut errors = Vec::new();
+        for index in candidates {
+            let url = {
+                let mut mirrors = self.mirrors.lock().unwrap();
+                mirrors.start(index);
+                mirrors.url(index, name)
+            };
+            let result = Self::fetch(&url, validator).await;
+            self.mirrors.lock().unwrap().finish(index, result.is_ok(), Instant::now());
+            match result {
+                Ok(response) => {
+                    if let Some((ref data, _)) = response {
+                        self.download_limits.lock().unwrap().record(data.len(), Instant::now());
+                    }
+                    return Ok(response);
+                }
+                Err(e) => {
+                    warn!(target: LOG_TARGET, "download of '{}' failed: {}", url, e);
+                    errors.push(format!("'{}': {}", url, e))
+                }
+            }
+        }
+        anyhow::bail!("Tile download failed from every server: {}", errors.join(", "))
+    }
+
+    async fn fetch(
+        url: &str,
+        validator: Option<&Validator>,
+    ) -> Result<Option<(Vec<u8>, Validator)>, Error> {
+        use hyper::header::{HeaderName, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+
+        let mut request = hyper::Request::get(url);
+        if let Some(validator) = validator {
+            if let Some(ref etag) = validator.etag {
+                request = request.header(IF_NONE_MATCH, etag.as_str());
+            }
+            if let Some(ref last_modified) = validator.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified.as_str());
+            }
+        }
+
+        let client =
+            hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+        let resp = client.request(request.body(hyper::Body::empty())?).await?;
+        if resp.status() == hyper::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        ensure!(resp.status().is_success(), "request failed with {:?}", resp.status());
+
+        let header = |name: HeaderName| {
+            resp.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_owned)
+        };
+        let validator = Validator { etag: header(ETAG), last_modified: header(LAST_MODIFIED) };
+        Ok(Some((hyper::body::to_bytes(resp.into_body()).await?.to_vec(), validator)))
+    }
+
+    /// Ask the tile servers whether any downloaded base tiles within `radius` meters of `center`
+    /// have changed, and replace the ones that have. Only tiles downloaded along with cache
+    /// validators can be checked. Returns the tiles that were replaced.
+    #[cfg(feature = "render")]
+    pub(crate) async fn check_for_updates(
+        &self,
+        center: Vector3<f64>,
+        radius: f64,
+    ) -> Result<Vec<(LayerType, VNode)>, Error> {
+        let mut candidates = Vec::new();
+        for entry in self.validators.iter() {
+            let (key, value) = entry?;
+            let (layer, node) = bincode::deserialize::<(LayerType, VNode)>(&key)?;
+            if node.distance2(center, self.planet.drawn_range()) <= radius * radius {
+                candidates.push((layer, node, bincode::deserialize::<Validator>(&value)?));
+            }
+        }
+
+        let num_candidates = candidates.len();
+        let mut updated = Vec::new();
+        for (layer, node, validator) in candidates {
+            let name = Self::tile_name(layer, node);
+            if let Some((data, validator)) = self.download(&name, Some(&validator)).await? {
+                tokio::task::block_in_place(|| {
+                    self.write_tile(layer, node, &data, true)?;
+                    self.update_validator(layer, node, &validator)
+                })?;
+                debug!(target: LOG_TARGET, "replaced outdated {:?} tile {:?}", layer, node);
+                updated.push((layer, node));
+            }
+        }
+        info!(
+            target: LOG_TARGET,
+            "{} of {} downloaded tiles were outdated",
+            updated.len(),
+            num_candidates
+        );
+        Ok(updated)
+    }
+
+    /// Read the albedo tile for `node` from `epoch`, falling back to the default albedo tile if the
+    /// epoch doesn't have one.
+    #[cfg(feature = "render")]
+    pub(crate) async fn read_albedo_epoch_tile(
+        &self,
+        epoch: &AlbedoEpoch,
+        node: VNode,
+    ) -> Result<Vec<u8>, Error> {
+        let filename = Self::albedo_epoch_tile_path(epoch, node);
+        if !self.file_exists(&filename) {
+            return self.read_tile(LayerType::Albedo, node).await;
+        }
+        self.read_file(&filename).await
+    }
+
+    pub(crate) fn write_albedo_epoch_tile(
+        &self,
+        epoch: &AlbedoEpoch,
+        node: VNode,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.write_file(&Self::albedo_epoch_tile_path(epoch, node), data, None, || Ok(()))
+    }
+
+    /// Albedo epochs stored in this map file, sorted by date.
+    pub(crate) fn albedo_epochs(&self) -> Result<Vec<AlbedoEpoch>, Error> {
+        Ok(match self.db.get("albedo_epochs")? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => Vec::new(),
+        })
+    }
+
+    /// Record that tiles exist for `epoch`, replacing any previous epoch with the same name.
+    pub(crate) fn add_albedo_epoch(&self, epoch: AlbedoEpoch) -> Result<(), Error> {
+        let mut epochs = self.albedo_epochs()?;
+        epochs.retain(|e| e.name != epoch.name);
+        epochs.push(epoch);
+        epochs.sort_by(|a, b| a.julian_day.partial_cmp(&b.julian_day).unwrap());
+        self.db.insert("albedo_epochs", serde_json::to_vec(&epochs)?)?;
+        Ok(())
+    }
+
+    /// Fractal detail parameters used when synthesizing heightmaps below the resolution of the
+    /// source data.
+    #[cfg(feature = "render")]
+    pub(crate) fn fractal_detail(&self) -> Result<FractalDetailTable, Error> {
+        Ok(match self.db.get("fractal_detail")? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => FractalDetailTable::default(),
+        })
+    }
+
+    pub(crate) fn set_fractal_detail(&self, table: &FractalDetailTable) -> Result<(), Error> {
+        self.db.insert("fractal_detail", serde_json::to_vec(table)?)?;
+        Ok(())
+    }
+
+    /// Atmosphere height that the transmittance and inscattering textures were generated for.
+    #[cfg(feature = "render")]
+    pub(crate) fn sky_atmosphere_height(&self) -> Result<Option<f32>, Error> {
+        Ok(match self.db.get("sky_atmosphere_height")? {
+            Some(value) => Some(serde_json::from_slice(&value)?),
+            None => None,
+        })
+    }
+
+    #[cfg(feature = "render")]
+    pub(crate) fn set_sky_atmosphere_height(&self, height: f32) -> Result<(), Error> {
+        self.db.insert("sky_atmosphere_height", serde_json::to_vec(&height)?)?;
+        Ok(())
+    }
+
+    /// Region covered by the skylines generated with `HeadlessTerrain::generate_skylines`, if any.
+    #[cfg(feature = "render")]
+    pub(crate) fn skyline_bounds(&self) -> Result<Option<LatLongBounds>, Error> {
+        Ok(match self.db.get("skyline_bounds")? {
+            Some(value) => {
+                let [south, north, west, east]: [f64; 4] = serde_json::from_slice(&value)?;
+                Some(LatLongBounds::new(south, north, west, east))
+            }
+            None => None,
+        })
+    }
+
+    pub(crate) fn set_skyline_bounds(&self, bounds: Option<LatLongBounds>) -> Result<(), Error> {
+        match bounds {
+            Some(b) => {
+                let value = [b.south(), b.north(), b.west(), b.east()];
+                self.db.insert("skyline_bounds", serde_json::to_vec(&value)?)?;
+            }
+            None => drop(self.db.remove("skyline_bounds")?),
+        }
+        Ok(())
+    }
+
+    /// Runways imported from OurAirports, or an empty list if none have been.
+    pub(crate) fn runways(&self) -> Result<Vec<Runway>, Error> {
+        Ok(match self.db.get("runways")? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => Vec::new(),
+        })
+    }
+
+    pub(crate) fn set_runways(&self, runways: &[Runway]) -> Result<(), Error> {
+        self.db.insert("runways", serde_json::to_vec(runways)?)?;
+        Ok(())
+    }
+
+    /// Names of the passes that heightmap tiles were generated with, in the order they ran.
+    pub(crate) fn heightmap_passes(&self) -> Result<Vec<String>, Error> {
+        Ok(match self.db.get("heightmap_passes")? {
+            Some(value) => serde_json::from_slice(&value)?,
+            None => Vec::new(),
+        })
+    }
+
+    pub(crate) fn set_heightmap_passes(&self, names: &[String]) -> Result<(), Error> {
+        self.db.insert("heightmap_passes", serde_json::to_vec(names)?)?;
+        Ok(())
+    }
+
+    /// Return the albedo tiles missing from `epoch`, as well as the total number of base albedo
+    /// tiles.
+    pub(crate) fn missing_albedo_epoch_tiles(
+        &self,
+        epoch: &AlbedoEpoch,
+    ) -> Result<(Vec<VNode>, usize), Error> {
+        let mut total = 0;
+        let mut missing = Vec::new();
+        self.scan_tile_meta(LayerType::Albedo, |node, meta| {
+            if let TileState::Base | TileState::MissingBase = meta.state {
+                total += 1;
+                if !self.file_exists(&Self::albedo_epoch_tile_path(epoch, node)) {
+                    missing.push(node);
+                }
+            }
+            Ok(())
+        })?;
+        Ok((missing, total))
     }
 
     pub(crate) fn write_tile(
@@ -126,21 +712,353 @@ impl MapFile {
         data: &[u8],
         base: bool,
     ) -> Result<(), Error> {
-        let filename = Self::tile_path(layer, node);
+        let meta = TileMeta {
+            crc32: 0,
+            state: if base { TileState::Base } else { TileState::Generated },
+            elevation_range: None,
+            geometric_error: None,
+        };
+        trace!(target: LOG_TARGET, "writing {:?} tile {:?} ({} bytes)", layer, node, data.len());
+        self.write_file(&Self::tile_path(layer, node), data, Some((layer, node)), || {
+            self.update_tile_meta(layer, node, meta)
+        })
+    }
+
+    /// Limit tile downloads to an average of `bytes_per_second`, or remove the limit if None.
+    #[cfg(feature = "render")]
+    pub(crate) fn set_download_rate_limit(&self, bytes_per_second: Option<u32>) {
+        self.download_limits.lock().unwrap().set_rate_limit(bytes_per_second);
+    }
+
+    /// Only download base tiles down to `DownloadLimits::METERED_MAX_LEVEL`, or to the level set
+    /// with `permit_downloads`. Finer tiles that haven't been downloaded are generated instead.
+    #[cfg(feature = "render")]
+    pub(crate) fn set_metered_connection(&self, metered: bool) {
+        self.download_limits.lock().unwrap().set_metered(metered);
+    }
+
+    /// Download base tiles from the default tile server as well as from `mirrors`, which must hold
+    /// identical copies of the tiles.
+    #[cfg(feature = "render")]
+    pub(crate) fn set_tile_mirrors(&self, mirrors: &[String]) {
+        let mut base_urls = vec![TERRA_TILES_URL.to_owned()];
+        base_urls.extend_from_slice(mirrors);
+        self.mirrors.lock().unwrap().set_base_urls(&base_urls);
+    }
+
+    pub(crate) fn planet(&self) -> &PlanetConfig {
+        &self.planet
+    }
+
+    pub(crate) fn set_planet(&mut self, planet: PlanetConfig) {
+        self.planet = planet;
+    }
+
+    /// Allow base tiles down to `level` to be downloaded even on a metered connection.
+    #[cfg(feature = "render")]
+    pub(crate) fn permit_downloads(&self, level: u8) {
+        self.download_limits.lock().unwrap().set_permitted_level(level);
+    }
+
+    #[cfg(feature = "render")]
+    pub(crate) fn set_sync_policy(&self, policy: SyncPolicy) {
+        self.sync_writes.store(policy == SyncPolicy::Always, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "render")]
+    pub(crate) fn set_memory_mapped(&self, enabled: bool) {
+        self.memory_mapped.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Write `data` to a temporary file and then rename it over `filename`, so that readers never
+    /// see a partially written file. The write is recorded in the journal until `commit` has
+    /// updated whatever metadata refers to the file.
+    fn write_file<F: FnOnce() -> Result<(), Error>>(
+        &self,
+        filename: &Path,
+        data: &[u8],
+        tile: Option<(LayerType, VNode)>,
+        commit: F,
+    ) -> Result<(), Error> {
+        if let Some(ref files) = self.memory_files {
+            files.lock().unwrap().insert(filename.to_owned(), data.to_vec());
+            return commit();
+        }
+
+        let sync = self.sync_writes.load(Ordering::Relaxed);
+        let key = filename.to_string_lossy();
+        self.journal.insert(key.as_bytes(), bincode::serialize(&tile).unwrap())?;
+        if sync {
+            self.journal.flush()?;
+        }
+
         if let Some(parent) = filename.parent() {
             fs::create_dir_all(parent)?;
         }
+        let temporary = Self::temporary_path(filename);
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(data)?;
+        if sync {
+            file.sync_all()?;
+        }
+        drop(file);
+        fs::rename(&temporary, filename)?;
+        if let Some((index, key)) = self.file_index_entry(filename) {
+            index.insert(key.as_bytes(), &b""[..])?;
+        }
+        #[cfg(unix)]
+        if sync {
+            if let Some(parent) = filename.parent() {
+                fs::File::open(parent)?.sync_all()?;
+            }
+        }
+
+        commit()?;
+        self.journal.remove(key.as_bytes())?;
+        Ok(())
+    }
 
-        AtomicFile::new(filename, OverwriteBehavior::AllowOverwrite)
-            .write(|f| f.write_all(data))?;
+    /// Remove every file whose write was interrupted by a crash, along with the metadata of its
+    /// tile. Base tiles are downloaded again and generated tiles are regenerated.
+    fn recover_interrupted_writes(&self) -> Result<(), Error> {
+        for entry in self.journal.iter() {
+            let (key, value) = entry?;
+            let filename = PathBuf::from(std::str::from_utf8(&key)?);
+            warn!(target: LOG_TARGET, "discarding interrupted write of '{}'", filename.display());
+            let _ = fs::remove_file(Self::temporary_path(&filename));
+            let _ = fs::remove_file(&filename);
+            if let Some((index, key)) = self.file_index_entry(&filename) {
+                index.remove(key.as_bytes())?;
+            }
+            if let Some((layer, node)) = bincode::deserialize(&value)? {
+                self.remove_tile_meta(layer, node)?;
+            }
+            self.journal.remove(key)?;
+        }
+        Ok(())
+    }
 
-        self.update_tile_meta(
-            layer,
-            node,
-            TileMeta { crc32: 0, state: if base { TileState::Base } else { TileState::Generated } },
-        )
+    /// Open the index of files in the tiles directory, crawling the directory to build it the first
+    /// time the tile store is opened with one.
+    fn open_file_index(&mut self) -> Result<(), Error> {
+        let index = self.db.open_tree("file_index")?;
+        if self.db.get("file_index_complete")?.is_none() {
+            info!(target: LOG_TARGET, "indexing tile files");
+            let root = TERRA_DIRECTORY.join("tiles");
+            Self::index_directory(&index, &root, &root)?;
+            index.flush()?;
+            self.db.insert("file_index_complete", &b""[..])?;
+        }
+        self.file_index = Some(index);
+        Ok(())
+    }
+
+    /// Add every file below `directory` to `index`, keyed by its path relative to `root`. The
+    /// database in `root/meta` and temporary files are skipped.
+    fn index_directory(index: &sled::Tree, root: &Path, directory: &Path) -> Result<(), Error> {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                if path != root.join("meta") {
+                    Self::index_directory(index, root, &path)?;
+                }
+            } else if path.extension() != Some("tmp".as_ref()) {
+                if let Ok(relative) = path.strip_prefix(root) {
+                    index.insert(relative.to_string_lossy().as_bytes(), &b""[..])?;
+                }
+            }
+        }
+        Ok(())
     }
 
+    /// The file index along with the key of `filename` in it, or None if there is no index or the
+    /// file is outside the tiles directory.
+    fn file_index_entry(&self, filename: &Path) -> Option<(&sled::Tree, String)> {
+        let index = self.file_index.as_ref()?;
+        let relative = filename.strip_prefix(TERRA_DIRECTORY.join("tiles")).ok()?;
+        Some((index, relative.to_string_lossy().into_owned()))
+    }
+
+    /// Serve tiles and textures from a zip archive of the terra cache directory, so that they don't
+    /// have to be extracted. Entries are named by their path relative to that directory, like
+    /// `tiles/albedo/albedo_0_0E_0x0.png`. Files in the tile store take precedence, and anything
+    /// written later goes to the tile store. Entries are fastest to read if stored uncompressed.
+    pub(crate) fn open_archive(&mut self, path: &Path) -> Result<(), Error> {
+        let file = fs::File::open(path)?;
+        // Safety: the archive is only ever read, and must not be modified while it is open.
+        self.archive_map = Some(Arc::new(unsafe { Mmap::map(&file)? }));
+        self.archive = Some(Mutex::new(ZipArchive::new(file)?));
+        Ok(())
+    }
+
+    fn archive_entry_name(filename: &Path) -> Option<String> {
+        let relative = filename.strip_prefix(&*TERRA_DIRECTORY).ok()?;
+        let components: Option<Vec<&str>> = relative.iter().map(|c| c.to_str()).collect();
+        Some(components?.join("/"))
+    }
+
+    /// Contents of `filename` from the archive, or None if there is no archive or it doesn't
+    /// contain the file.
+    fn read_archive_file(&self, filename: &Path) -> Result<Option<Vec<u8>>, Error> {
+        let (archive, name) = match (&self.archive, Self::archive_entry_name(filename)) {
+            (Some(archive), Some(name)) => (archive, name),
+            _ => return Ok(None),
+        };
+        let mut archive = archive.lock().unwrap();
+        let mut entry = match archive.by_name(&name) {
+            Ok(entry) => entry,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut contents = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut contents)?;
+        Ok(Some(contents))
+    }
+
+    /// Map `filename` into memory if it is a file on disk or an uncompressed archive entry, or
+    /// return None if it has to be read some other way.
+    #[cfg(feature = "render")]
+    fn map_file(&self, filename: &Path) -> Result<Option<TileBytes>, Error> {
+        if self.stored_file_exists(filename) {
+            if self.memory_files.is_some() {
+                return Ok(None);
+            }
+            let file = fs::File::open(filename)?;
+            if file.metadata()?.len() == 0 {
+                return Ok(None);
+            }
+            // Safety: files in the tile store are replaced by renaming a new file over them and
+            // never modified in place, so the mapping stays valid even if the tile is rewritten.
+            let map = unsafe { Mmap::map(&file)? };
+            let len = map.len();
+            return Ok(Some(TileBytes::Mapped(Arc::new(map), 0..len)));
+        }
+
+        let (archive, map, name) =
+            match (&self.archive, &self.archive_map, Self::archive_entry_name(filename)) {
+                (Some(archive), Some(map), Some(name)) => (archive, map, name),
+                _ => return Ok(None),
+            };
+        let mut archive = archive.lock().unwrap();
+        let entry = match archive.by_name(&name) {
+            Ok(entry) => entry,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if entry.compression() != CompressionMethod::Stored {
+            return Ok(None);
+        }
+        let start = entry.data_start() as usize;
+        Ok(Some(TileBytes::Mapped(Arc::clone(map), start..start + entry.size() as usize)))
+    }
+
+    fn archive_contains(&self, filename: &Path) -> bool {
+        match (&self.archive, Self::archive_entry_name(filename)) {
+            (Some(archive), Some(name)) => archive.lock().unwrap().by_name(&name).is_ok(),
+            _ => false,
+        }
+    }
+
+    fn stored_file_exists(&self, filename: &Path) -> bool {
+        match self.memory_files {
+            Some(ref files) => files.lock().unwrap().contains_key(filename),
+            None => match self.file_index_entry(filename) {
+                Some((index, key)) => index.contains_key(key.as_bytes()).unwrap_or(false),
+                None => filename.exists(),
+            },
+        }
+    }
+
+    fn file_exists(&self, filename: &Path) -> bool {
+        self.stored_file_exists(filename) || self.archive_contains(filename)
+    }
+
+    /// Contents of `filename` if files are kept in memory, or None if they are on disk.
+    fn read_memory_file(&self, filename: &Path) -> Option<Result<Vec<u8>, Error>> {
+        let contents = self.memory_files.as_ref()?.lock().unwrap().get(filename).cloned();
+        Some(contents.ok_or_else(|| anyhow::format_err!("File missing: '{:?}'", filename)))
+    }
+
+    async fn read_file(&self, filename: &Path) -> Result<Vec<u8>, Error> {
+        if !self.stored_file_exists(filename) {
+            if let Some(contents) = self.read_archive_file(filename)? {
+                return Ok(contents);
+            }
+        }
+        if let Some(contents) = self.read_memory_file(filename) {
+            return contents;
+        }
+
+        let mut contents = Vec::new();
+        tokio::fs::File::open(filename).await?.read_to_end(&mut contents).await?;
+        Ok(contents)
+    }
+
+    #[cfg(feature = "render")]
+    fn read_texture_file(&self, filename: &Path) -> Result<Vec<u8>, Error> {
+        if !self.stored_file_exists(filename) {
+            if let Some(contents) = self.read_archive_file(filename)? {
+                return Ok(contents);
+            }
+        }
+        match self.read_memory_file(filename) {
+            Some(contents) => contents,
+            None => Ok(fs::read(filename)?),
+        }
+    }
+
+    fn remove_file(&self, filename: &Path) {
+        match self.memory_files {
+            Some(ref files) => drop(files.lock().unwrap().remove(filename)),
+            None => {
+                if let Some((index, key)) = self.file_index_entry(filename) {
+                    let _ = index.remove(key.as_bytes());
+                }
+                drop(fs::remove_file(filename))
+            }
+        }
+    }
+
+    fn temporary_path(filename: &Path) -> PathBuf {
+        let mut name = filename.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        filename.with_file_name(name)
+    }
+
+    /// Record the range of elevations covered by the heightmap tile for `node`, along with the
+    /// error of drawing it instead of its children.
+    pub(crate) fn set_heightmap_bounds(
+        &self,
+        node: VNode,
+        elevation_range: (f32, f32),
+        geometric_error: f32,
+    ) -> Result<(), Error> {
+        if let Some(mut meta) = self.lookup_tile_meta(LayerType::Heightmaps, node)? {
+            meta.elevation_range = Some(elevation_range);
+            meta.geometric_error = Some(geometric_error);
+            self.update_tile_meta(LayerType::Heightmaps, node, meta)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the range of elevations of `node`, if it has been recorded.
+    #[cfg(any(feature = "render", test))]
+    pub(crate) fn elevation_range(&self, node: VNode) -> Option<(f32, f32)> {
+        self.lookup_tile_meta(LayerType::Heightmaps, node).ok()??.elevation_range
+    }
+
+    /// Returns the geometric error of `node` recorded when its heightmap was generated, if any.
+    #[cfg(feature = "render")]
+    pub(crate) fn geometric_error(&self, node: VNode) -> Option<f32> {
+        self.lookup_tile_meta(LayerType::Heightmaps, node).ok()??.geometric_error
+    }
+
+    #[cfg(feature = "render")]
     pub(crate) fn read_texture(
         &self,
         device: &wgpu::Device,
@@ -178,9 +1096,12 @@ impl MapFile {
         let row_bytes = width * desc.format.bytes_per_block();
 
         let mut data = if desc.format == TextureFormat::RGBA8 {
-            image::open(TERRA_DIRECTORY.join(format!("{}.bmp", name)))?.to_rgba8().into_vec()
+            let encoded = self.read_texture_file(&TERRA_DIRECTORY.join(format!("{}.bmp", name)))?;
+            image::load_from_memory_with_format(&encoded, image::ImageFormat::Bmp)?
+                .to_rgba8()
+                .into_vec()
         } else {
-            fs::read(TERRA_DIRECTORY.join(format!("{}.raw", name)))?
+            self.read_texture_file(&TERRA_DIRECTORY.join(format!("{}.raw", name)))?
         };
 
         if cfg!(feature = "small-trace") {
@@ -235,22 +1156,21 @@ impl MapFile {
                 desc.height * desc.depth,
                 image::ColorType::Rgba8,
             )?;
-            Ok(AtomicFile::new(filename, OverwriteBehavior::AllowOverwrite)
-                .write(|f| f.write_all(&encoded))?)
+            self.write_file(&filename, &encoded, None, || Ok(()))
         } else {
             let filename = TERRA_DIRECTORY.join(format!("{}.raw", name));
-            Ok(AtomicFile::new(filename, OverwriteBehavior::AllowOverwrite)
-                .write(|f| f.write_all(data))?)
+            self.write_file(&filename, data, None, || Ok(()))
         }
     }
 
+    #[cfg(feature = "render")]
     pub(crate) fn reload_texture(&self, name: &str) -> bool {
         let desc = self.lookup_texture(name);
         if let Ok(Some(desc)) = desc {
             if desc.format == TextureFormat::RGBA8 {
-                TERRA_DIRECTORY.join(format!("{}.bmp", name)).exists()
+                self.file_exists(&TERRA_DIRECTORY.join(format!("{}.bmp", name)))
             } else {
-                TERRA_DIRECTORY.join(format!("{}.raw", name)).exists()
+                self.file_exists(&TERRA_DIRECTORY.join(format!("{}.raw", name)))
             }
         } else {
             false
@@ -261,6 +1181,43 @@ impl MapFile {
         &self.layers
     }
 
+    /// Add a custom layer after the existing ones. Its name must be unique, its format must be
+    /// writable from a compute shader, and its generator may only depend on layers that are
+    /// already registered, or on its own parent tiles.
+    #[cfg(feature = "render")]
+    pub(crate) fn register_layer(&mut self, desc: LayerDesc) -> Result<LayerType, Error> {
+        let index = self.layers.keys().map(|i| i + 1).fold(LayerType::FIRST_CUSTOM, usize::max);
+        ensure!(
+            index < LayerType::MAX_LAYERS,
+            "At most {} custom layers are supported",
+            LayerType::MAX_LAYERS - LayerType::FIRST_CUSTOM
+        );
+        ensure!(self.layer_by_name(&desc.name).is_none(), "Duplicate layer '{}'", desc.name);
+        ensure!(
+            !desc.texture_format.is_compressed(),
+            "Custom layers cannot use compressed formats, since they are written by compute shaders"
+        );
+        for input in &desc.generator.peer_inputs {
+            ensure!(self.layer_by_name(input).is_some(), "Unknown peer input '{}'", input);
+        }
+        for input in &desc.generator.parent_inputs {
+            ensure!(
+                *input == desc.name || self.layer_by_name(input).is_some(),
+                "Unknown parent input '{}'",
+                input
+            );
+        }
+
+        let layer_type = LayerType::from_index(index);
+        self.layers.insert(index, LayerParams::custom(layer_type, desc));
+        Ok(layer_type)
+    }
+
+    #[cfg(feature = "render")]
+    pub(crate) fn layer_by_name(&self, name: &str) -> Option<LayerType> {
+        self.layers.values().find(|l| l.name == name).map(|l| l.layer_type)
+    }
+
     fn tile_name(layer: LayerType, node: VNode) -> String {
         let face = match node.face() {
             0 => "0E",
@@ -277,6 +1234,9 @@ impl MapFile {
             LayerType::Roughness => ("roughness", "raw.lz4"),
             LayerType::Normals => ("normals", "raw"),
             LayerType::Heightmaps => ("heightmaps", "raw"),
+            LayerType::Vector => ("vector", "bin"),
+            LayerType::Places => ("places", "bin"),
+            _ => unreachable!("custom layers are only stored on the GPU"),
         };
         format!("{}/{}_{}_{}_{}x{}.{}", layer, layer, node.level(), face, node.x(), node.y(), ext)
     }
@@ -285,8 +1245,10 @@ impl MapFile {
         TERRA_DIRECTORY.join("tiles").join(&Self::tile_name(layer, node))
     }
 
-    fn tile_url(layer: LayerType, node: VNode) -> String {
-        format!("{}{}", TERRA_TILES_URL, Self::tile_name(layer, node))
+    fn albedo_epoch_tile_path(epoch: &AlbedoEpoch, node: VNode) -> PathBuf {
+        let name = Self::tile_name(LayerType::Albedo, node);
+        let filename = Path::new(&name).file_name().unwrap();
+        TERRA_DIRECTORY.join("tiles/albedo").join(&epoch.name).join(filename)
     }
 
     pub(crate) fn reload_tile_state(
@@ -298,7 +1260,7 @@ impl MapFile {
         let filename = Self::tile_path(layer, node);
         let meta = self.lookup_tile_meta(layer, node);
 
-        let exists = filename.exists();
+        let exists = self.file_exists(&filename);
 
         let target_state = if base && exists {
             TileState::Base
@@ -310,21 +1272,29 @@ impl MapFile {
             TileState::Missing
         };
 
-        if let Ok(Some(TileMeta { state, .. })) = meta {
-            if state == target_state {
-                return Ok(state);
+        let (mut elevation_range, mut geometric_error) = (None, None);
+        if let Ok(Some(meta)) = meta {
+            if meta.state == target_state {
+                return Ok(meta.state);
+            }
+            if exists {
+                elevation_range = meta.elevation_range;
+                geometric_error = meta.geometric_error;
             }
         }
 
-        let new_meta = TileMeta { state: target_state, crc32: 0 };
+        let new_meta = TileMeta { state: target_state, crc32: 0, elevation_range, geometric_error };
         self.update_tile_meta(layer, node, new_meta)?;
         Ok(target_state)
     }
+    /// Forget every tile of `layer` that was generated at runtime and saved, so that they are
+    /// generated again.
     #[allow(unused)]
     pub(crate) fn clear_generated(&self, layer: LayerType) -> Result<(), Error> {
         self.scan_tile_meta(layer, |node, meta| {
             if let TileState::Generated = meta.state {
                 self.remove_tile_meta(layer, node)?;
+                self.remove_file(&Self::tile_path(layer, node));
             }
             Ok(())
         })
@@ -356,6 +1326,20 @@ impl MapFile {
         self.tiles.insert(key, value)?;
         Ok(())
     }
+    fn update_validator(
+        &self,
+        layer: LayerType,
+        node: VNode,
+        validator: &Validator,
+    ) -> Result<(), Error> {
+        let key = bincode::serialize(&(layer, node)).unwrap();
+        if *validator == Validator::default() {
+            self.validators.remove(key)?;
+        } else {
+            self.validators.insert(key, bincode::serialize(validator).unwrap())?;
+        }
+        Ok(())
+    }
     fn remove_tile_meta(&self, layer: LayerType, node: VNode) -> Result<(), Error> {
         let key = bincode::serialize(&(layer, node)).unwrap();
         self.tiles.remove(key)?;
@@ -376,6 +1360,7 @@ impl MapFile {
         Ok(())
     }
 
+    #[cfg(feature = "render")]
     fn lookup_texture(&self, name: &str) -> Result<Option<TextureDescriptor>, Error> {
         Ok(self.textures.get(name)?.map(|value| serde_json::from_slice(&value).unwrap()))
     }
@@ -385,3 +1370,200 @@ impl MapFile {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::heightmap::HeightmapCache;
+
+    #[test]
+    fn synthetic_heightmaps() {
+        let mapfile = MapFile::synthetic(6371000.0, 1);
+        let planet = mapfile.synthetic.as_ref().unwrap();
+        let layer = &mapfile.layers()[LayerType::Heightmaps];
+
+        let node = VNode::roots()[2].children()[1].children()[3];
+        let state = |n| mapfile.tile_state(LayerType::Heightmaps, n).unwrap();
+        assert!(state(node) == TileState::Base);
+        assert!(state(node.children()[0]) == TileState::GpuOnly);
+
+        let mut cache = HeightmapCache::new(layer.clone(), 8);
+        let heights = futures::executor::block_on(cache.get_tile(&mapfile, node)).unwrap();
+        assert_eq!(*heights, planet.heightmap(layer, node));
+
+        let (min, max) = mapfile.elevation_range(node).unwrap();
+        assert!(heights.iter().all(|&h| h as f32 >= min && h as f32 <= max));
+    }
+
+    #[test]
+    #[cfg(feature = "render")]
+    fn albedo_blend() {
+        let epoch = |name: &str, julian_day| AlbedoEpoch { name: name.to_owned(), julian_day };
+        let epochs = vec![epoch("200401", 100.0), epoch("200402", 132.0), epoch("200403", 160.0)];
+
+        let blend = AlbedoBlend::between(&epochs, 140.0).unwrap();
+        assert_eq!((&*blend.older.name, &*blend.newer.name), ("200402", "200403"));
+        assert!((blend.weight - 8.0 / 28.0).abs() <= 0.5 / AlbedoBlend::WEIGHT_STEPS);
+        assert_eq!(blend.mix(&[0, 100], &[32, 100]), vec![9, 100]);
+
+        let before = AlbedoBlend::between(&epochs, 50.0).unwrap();
+        assert_eq!((&*before.older.name, before.weight), ("200401", 0.0));
+        let after = AlbedoBlend::between(&epochs, 500.0).unwrap();
+        assert_eq!((&*after.newer.name, after.weight), ("200403", 0.0));
+        assert!(AlbedoBlend::between(&[], 140.0).is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "render")]
+    fn register_layers() {
+        use crate::cache::LayerGenerator;
+
+        let mut mapfile = MapFile::synthetic(6371000.0, 1);
+        let desc = |name: &str, peer_inputs: &[&str], parent_inputs: &[&str]| LayerDesc {
+            name: name.to_owned(),
+            texture_format: TextureFormat::R8,
+            texture_resolution: 36,
+            texture_border_size: 2,
+            mipmaps: false,
+            generator: LayerGenerator::glsl(String::new(), 5)
+                .with_peer_inputs(peer_inputs)
+                .with_parent_inputs(parent_inputs),
+        };
+
+        let snow = mapfile.register_layer(desc("snow", &["heightmaps"], &["snow"])).unwrap();
+        assert_eq!(snow.index(), LayerType::FIRST_CUSTOM);
+        assert_eq!(mapfile.layer_by_name("snow"), Some(snow));
+        assert_eq!(mapfile.layers()[snow].texture_resolution, 36);
+
+        let wetness = mapfile.register_layer(desc("wetness", &["snow"], &[])).unwrap();
+        assert_eq!(wetness.index(), LayerType::FIRST_CUSTOM + 1);
+
+        assert!(mapfile.register_layer(desc("albedo", &[], &[])).is_err());
+        assert!(mapfile.register_layer(desc("ice", &["ice"], &[])).is_err());
+        assert!(mapfile.register_layer(desc("ice", &[], &["rivers"])).is_err());
+        assert!(mapfile.tile_state(snow, VNode::roots()[0]).unwrap() == TileState::GpuOnly);
+    }
+
+    #[test]
+    fn clear_generated_tiles() {
+        let mapfile = MapFile::synthetic(6371000.0, 1);
+        let node = VNode::roots()[3].children()[0];
+        let meta = TileMeta {
+            crc32: 0,
+            state: TileState::Generated,
+            elevation_range: None,
+            geometric_error: None,
+        };
+        let state = |layer, n| mapfile.tile_state(layer, n).unwrap();
+        mapfile.update_tile_meta(LayerType::Normals, node, meta).unwrap();
+        assert!(state(LayerType::Normals, node) == TileState::Generated);
+
+        mapfile.clear_generated(LayerType::Normals).unwrap();
+        assert!(state(LayerType::Normals, node) == TileState::GpuOnly);
+        assert!(state(LayerType::Albedo, VNode::roots()[3]) == TileState::Base);
+    }
+
+    #[test]
+    fn interrupted_writes() {
+        let mapfile = MapFile::synthetic(6371000.0, 1);
+        let directory = std::env::temp_dir().join(format!("terra-journal-{}", std::process::id()));
+        let complete = directory.join("complete.raw");
+        let interrupted = directory.join("interrupted.raw");
+        let node = VNode::roots()[3];
+
+        mapfile.write_file(&complete, &[1, 2, 3], None, || Ok(())).unwrap();
+        assert_eq!(fs::read(&complete).unwrap(), vec![1, 2, 3]);
+        assert!(!MapFile::temporary_path(&complete).exists());
+
+        let crash = || Err(anyhow::format_err!("crash"));
+        let tile = Some((LayerType::Albedo, node));
+        assert!(mapfile.write_file(&interrupted, &[4], tile, crash).is_err());
+        fs::write(MapFile::temporary_path(&interrupted), &[5]).unwrap();
+
+        mapfile.recover_interrupted_writes().unwrap();
+        assert!(complete.exists());
+        assert!(!interrupted.exists());
+        assert!(!MapFile::temporary_path(&interrupted).exists());
+        assert!(mapfile.tile_state(LayerType::Albedo, node).unwrap() == TileState::GpuOnly);
+        assert!(mapfile.journal.is_empty());
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn memory_store() {
+        let mapfile = MapFile::new(SyntheticPlanet::layers(), TileStore::Memory);
+        let node = VNode::roots()[1].children()[2];
+        let state = |n| mapfile.tile_state(LayerType::Normals, n).unwrap();
+        assert!(state(node) == TileState::GpuOnly);
+
+        mapfile.write_tile(LayerType::Normals, node, &[7; 16], false).unwrap();
+        assert!(state(node) == TileState::Generated);
+        let reloaded = mapfile.reload_tile_state(LayerType::Normals, node, false).unwrap();
+        assert!(reloaded == TileState::Generated);
+        let data = futures::executor::block_on(mapfile.read_tile(LayerType::Normals, node));
+        assert_eq!(data.unwrap(), vec![7; 16]);
+
+        mapfile.clear_generated(LayerType::Normals).unwrap();
+        assert!(state(node) == TileState::GpuOnly);
+        assert!(!mapfile.file_exists(&MapFile::tile_path(LayerType::Normals, node)));
+    }
+
+    #[test]
+    fn tile_provenance() {
+        let mapfile = MapFile::new(SyntheticPlanet::layers(), TileStore::Memory);
+        let (base, generated) = (VNode::roots()[2], VNode::roots()[2].children()[0]);
+        let provenance = |n| mapfile.tile_provenance(LayerType::Heightmaps, n);
+        assert_eq!(provenance(generated), (TileProvenance::Fractal, None));
+
+        mapfile.write_tile(LayerType::Heightmaps, base, &[1; 16], true).unwrap();
+        mapfile.write_tile(LayerType::Heightmaps, generated, &[2; 16], false).unwrap();
+        assert_eq!(provenance(base).0, TileProvenance::Dataset);
+        assert_eq!(provenance(generated).0, TileProvenance::Fractal);
+
+        let validator = Validator { etag: Some("\"1\"".to_owned()), last_modified: None };
+        mapfile.update_validator(LayerType::Heightmaps, base, &validator).unwrap();
+        assert_eq!(provenance(base).0, TileProvenance::Downloaded);
+    }
+
+    #[test]
+    fn archive_tiles() {
+        let node = VNode::roots()[4].children()[1];
+        let path = std::env::temp_dir().join(format!("terra-archive-{}.zip", std::process::id()));
+        let mut writer = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let name = format!("tiles/{}", MapFile::tile_name(LayerType::Normals, node));
+        writer.start_file(name, options).unwrap();
+        writer.write_all(&[3; 8]).unwrap();
+        writer.finish().unwrap();
+
+        let mut mapfile = MapFile::new(SyntheticPlanet::layers(), TileStore::Memory);
+        mapfile.open_archive(&path).unwrap();
+        let read = |n| futures::executor::block_on(mapfile.read_tile(LayerType::Normals, n));
+        assert_eq!(read(node).unwrap(), vec![3; 8]);
+        assert!(read(node.children()[0]).is_err());
+
+        mapfile.write_tile(LayerType::Normals, node, &[4; 8], false).unwrap();
+        assert_eq!(read(node).unwrap(), vec![4; 8]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn file_index() {
+        let root = std::env::temp_dir().join(format!("terra-index-{}", std::process::id()));
+        fs::create_dir_all(root.join("albedo/3")).unwrap();
+        fs::create_dir_all(root.join("meta")).unwrap();
+        fs::write(root.join("albedo/3/a.png"), &[1]).unwrap();
+        fs::write(root.join("albedo/3/b.png.tmp"), &[2]).unwrap();
+        fs::write(root.join("meta/db"), &[3]).unwrap();
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let index = db.open_tree("file_index").unwrap();
+        MapFile::index_directory(&index, &root, &root).unwrap();
+        MapFile::index_directory(&index, &root, &root.join("missing")).unwrap();
+        let key = Path::new("albedo/3/a.png").to_string_lossy().into_owned();
+        assert!(index.contains_key(key.as_bytes()).unwrap());
+        assert_eq!(index.len(), 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
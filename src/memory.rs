@@ -0,0 +1,174 @@
+use crate::cache::LayerParams;
+use anyhow::Error;
+use futures::future::{BoxFuture, FutureExt};
+use vec_map::VecMap;
+
+/// Fewest slots the tile cache is shrunk to. Enough for the root tiles and the few levels below
+/// them that are needed to draw anything at all.
+const MIN_TILE_CACHE_SIZE: usize = 96;
+/// Bytes taken by each entry of the grass caches created by `Terrain::create_cache`: a mesh
+/// buffer, and a canopy texture of 516 by 516 RGBA8 texels.
+const GRASS_BYTES_PER_ENTRY: u64 = 128 * 128 * 32 + 516 * 516 * 4;
+
+/// Something that happened while terrain was being drawn that the application may want to react
+/// to. Returned by `Terrain::poll_events`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TerrainEvent {
+    /// The GPU ran out of memory while tiles were being streamed, so the tile cache was shrunk to
+    /// `tile_cache_size` slots and every tile on the GPU was discarded. The GPU memory budget is
+    /// lowered to match, so later changes of options don't grow the cache back.
+    OutOfGpuMemory { tile_cache_size: usize },
+    /// The GPU ran out of memory, but the tile cache could not be shrunk to `tile_cache_size`
+    /// slots because of `error`. Terrain is still drawn with the cache it already had.
+    TileCacheResizeFailed { tile_cache_size: usize, error: String },
+}
+
+/// Bytes of GPU memory taken by a tile cache with `tile_cache_size` slots for each of `layers`,
+/// along with grass caches of `grass_cache_size` entries.
+pub(crate) fn cache_bytes(
+    layers: &VecMap<LayerParams>,
+    tile_cache_size: usize,
+    grass_cache_size: usize,
+) -> u64 {
+    let slot_bytes: u64 = layers.values().map(|layer| layer.slot_bytes()).sum();
+    slot_bytes * tile_cache_size as u64 + GRASS_BYTES_PER_ENTRY * grass_cache_size as u64
+}
+
+/// Tracks how much GPU memory the caches may take, and catches allocation failures during cache
+/// updates so that they shrink the cache instead of panicking.
+///
+/// wgpu has no way to ask an adapter how much memory it has or how much is in use, so the budget
+/// must come from the application, which can query it from the platform API if it wants to.
+pub(crate) struct GpuMemory {
+    /// Most bytes that the tile and grass caches may take, or None if unlimited.
+    budget: Option<u64>,
+    /// Error scopes around cache updates that the device hasn't reported on yet.
+    scopes: Vec<BoxFuture<'static, Option<wgpu::Error>>>,
+    events: Vec<TerrainEvent>,
+}
+impl GpuMemory {
+    pub fn new() -> Self {
+        Self { budget: None, scopes: Vec::new(), events: Vec::new() }
+    }
+
+    pub fn set_budget(&mut self, budget: Option<u64>) {
+        self.budget = budget;
+    }
+
+    /// Largest tile cache no bigger than `tile_cache_size` that fits within the budget along with
+    /// the grass caches, or `tile_cache_size` itself if there is no budget.
+    pub fn fit_tile_cache(
+        &self,
+        layers: &VecMap<LayerParams>,
+        tile_cache_size: usize,
+        grass_cache_size: usize,
+    ) -> usize {
+        let budget = match self.budget {
+            Some(budget) => budget,
+            None => return tile_cache_size,
+        };
+        let slot_bytes: u64 = layers.values().map(|layer| layer.slot_bytes()).sum();
+        let available = budget.saturating_sub(cache_bytes(layers, 0, grass_cache_size));
+        let slots = (available / slot_bytes.max(1)) as usize;
+        tile_cache_size.min(slots.max(MIN_TILE_CACHE_SIZE))
+    }
+
+    /// Start catching allocation failures. Must be followed by `pop_scope` on the same device.
+    pub fn push_scope(&self, device: &wgpu::Device) {
+        device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+    }
+
+    pub fn pop_scope(&mut self, device: &wgpu::Device) {
+        self.scopes.push(device.pop_error_scope().boxed());
+    }
+
+    /// Whether any allocation failed within the scopes the device has reported on since the last
+    /// call.
+    pub fn out_of_memory(&mut self) -> bool {
+        let mut failed = false;
+        let mut i = 0;
+        while i < self.scopes.len() {
+            match (&mut self.scopes[i]).now_or_never() {
+                Some(error) => {
+                    failed |= error.is_some();
+                    self.scopes.swap_remove(i);
+                }
+                None => i += 1,
+            }
+        }
+        failed
+    }
+
+    /// Record that the tile cache was shrunk to `tile_cache_size` slots after running out of
+    /// memory, lowering the budget to the `bytes` it now takes.
+    pub fn shrunk(&mut self, tile_cache_size: usize, bytes: u64) {
+        self.budget = Some(self.budget.map(|b| b.min(bytes)).unwrap_or(bytes));
+        self.events.push(TerrainEvent::OutOfGpuMemory { tile_cache_size });
+    }
+
+    /// Record that shrinking the tile cache to `tile_cache_size` slots failed with `error`.
+    pub fn shrink_failed(&mut self, tile_cache_size: usize, error: &Error) {
+        let error = format!("{:#}", error);
+        self.events.push(TerrainEvent::TileCacheResizeFailed { tile_cache_size, error });
+    }
+
+    pub fn take_events(&mut self) -> Vec<TerrainEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// Number of slots to shrink a tile cache of `tile_cache_size` to after running out of memory.
+pub(crate) fn shrink_tile_cache(tile_cache_size: usize) -> usize {
+    (tile_cache_size * 3 / 4).max(MIN_TILE_CACHE_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{LayerType, TextureFormat};
+
+    #[test]
+    fn fit_to_budget() {
+        let layer = LayerParams {
+            layer_type: LayerType::Heightmaps,
+            name: "heightmaps".to_string(),
+            texture_resolution: 64,
+            texture_border_size: 0,
+            texture_format: TextureFormat::R32F,
+            tiles_generated_per_frame: 1,
+            mipmaps: true,
+            generator: None,
+        };
+        // 64x64 down to 1x1 is 5461 texels of 4 bytes each.
+        assert_eq!(layer.slot_bytes(), 5461 * 4);
+        let mut layers = VecMap::new();
+        layers.insert(LayerType::Heightmaps.index(), layer);
+
+        let mut memory = GpuMemory::new();
+        assert_eq!(memory.fit_tile_cache(&layers, 500, 1), 500);
+
+        memory.set_budget(Some(cache_bytes(&layers, 200, 1) + 100));
+        assert_eq!(memory.fit_tile_cache(&layers, 500, 1), 200);
+        assert_eq!(memory.fit_tile_cache(&layers, 150, 1), 150);
+        assert_eq!(memory.fit_tile_cache(&layers, 500, 1000), MIN_TILE_CACHE_SIZE);
+
+        memory.shrunk(shrink_tile_cache(200), cache_bytes(&layers, 150, 1));
+        assert_eq!(memory.fit_tile_cache(&layers, 500, 1), 150);
+        assert_eq!(
+            memory.take_events(),
+            vec![TerrainEvent::OutOfGpuMemory { tile_cache_size: 150 }]
+        );
+        assert!(memory.take_events().is_empty());
+
+        // A failed resize leaves the budget alone.
+        memory.shrink_failed(100, &anyhow::format_err!("no memory"));
+        assert_eq!(memory.fit_tile_cache(&layers, 500, 1), 150);
+        assert_eq!(
+            memory.take_events(),
+            vec![TerrainEvent::TileCacheResizeFailed {
+                tile_cache_size: 100,
+                error: "no memory".to_owned()
+            }]
+        );
+    }
+}
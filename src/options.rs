@@ -0,0 +1,211 @@
+use crate::gpu_state::TextureFiltering;
+use crate::mapfile::TileStore;
+use crate::planet::PlanetConfig;
+use std::path::PathBuf;
+
+/// Overall quality levels for terrain rendering, for applications that expose a single terrain
+/// quality setting.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum QualityPreset {
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+
+/// Settings that trade rendering quality for performance. Pass to `Terrain::with_options` or
+/// switch at runtime with `Terrain::set_options`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TerrainOptions {
+    /// Number of tiles kept resident on the GPU. Larger caches keep more detail loaded around the
+    /// camera at the cost of GPU memory.
+    pub tile_cache_size: usize,
+    /// Number of tiles of grass kept resident on the GPU.
+    pub grass_cache_size: usize,
+    /// Hold albedo, roughness and normal tiles at half resolution, which roughly halves the GPU
+    /// memory used by the tile cache at the cost of blurrier ground textures.
+    pub compact: bool,
+    /// Where albedo is magnified beyond the resolution of the imagery, upsample it with an
+    /// edge-aware filter guided by the heightmap and the estimated land cover, instead of
+    /// stretching the coarser tile. Sharper ground at no cost in memory or downloads, but a little
+    /// more work for every generated tile.
+    pub guided_upsampling: bool,
+    /// See `Terrain::set_max_screen_space_error`. Lower values draw denser meshes.
+    pub max_screen_space_error: Option<f32>,
+    /// See `Terrain::set_texture_filtering`.
+    pub texture_filtering: TextureFiltering,
+    /// See `Terrain::set_shadow_receiver`.
+    pub shadow_map_resolution: Option<u32>,
+    /// See `Terrain::set_distance_field_shadows`.
+    pub distance_field_shadows: bool,
+    /// See `Terrain::set_depth_prepass`.
+    pub depth_prepass: bool,
+    /// See `Terrain::set_occlusion_culling`. No preset enables this, because it requires the
+    /// depth buffer to be created with `TextureUsage::SAMPLED`.
+    pub occlusion_culling: bool,
+    /// See `Terrain::set_triplanar_mapping`.
+    pub triplanar_mapping: bool,
+    /// See `Terrain::set_parallax_occlusion`.
+    pub parallax_occlusion: Option<u32>,
+    /// See `Terrain::set_water_reflections`.
+    pub water_reflections: Option<u32>,
+    /// See `Terrain::set_sky_downsampling`.
+    pub sky_downsampling: Option<u32>,
+    /// Whether to draw grass near the camera.
+    pub grass: bool,
+    /// Lowest and highest exposure, as EV100, that automatic exposure may pick. The highest is
+    /// used whenever sunlit terrain is in view, including the dayside seen from orbit, and the
+    /// lowest once the camera is on the night side with nothing lit in view.
+    pub exposure_range: (f32, f32),
+    /// Where tiles are stored. Only read when the terrain is created, so changing it with
+    /// `Terrain::set_options` has no effect.
+    pub tile_store: TileStore,
+    /// Zip archive of the terra cache directory that tiles are read from when they aren't in the
+    /// tile store, so that shipped maps don't need to be extracted. Only read when the terrain is
+    /// created.
+    pub tile_archive: Option<PathBuf>,
+    /// Sea level, elevation range and atmosphere of the body being rendered. Only read when the
+    /// terrain is created.
+    pub planet: PlanetConfig,
+}
+impl TerrainOptions {
+    /// GPUs with less memory than this are given `TerrainOptions::compact` by `for_adapter`.
+    pub const COMPACT_VIDEO_MEMORY: u64 = 2 << 30;
+
+    /// Options for GPUs with little memory, such as integrated ones: the `Low` preset with a
+    /// smaller tile cache and compact tiles.
+    pub fn compact() -> Self {
+        Self {
+            tile_cache_size: 192,
+            grass_cache_size: 4,
+            compact: true,
+            ..Self::preset(QualityPreset::Low)
+        }
+    }
+
+    /// Pick options for the GPU that `info` describes, which has `video_memory` bytes of
+    /// dedicated memory if known. wgpu doesn't report how much memory a GPU has, so applications
+    /// that can find out some other way should pass it. Otherwise integrated, virtual and software
+    /// GPUs are assumed to have too little, since they share system memory. Returns
+    /// `TerrainOptions::compact` for GPUs with less than `COMPACT_VIDEO_MEMORY`, and the default
+    /// options for all others.
+    #[cfg(feature = "render")]
+    pub fn for_adapter(info: &wgpu::AdapterInfo, video_memory: Option<u64>) -> Self {
+        let low_memory = match video_memory {
+            Some(bytes) => bytes < Self::COMPACT_VIDEO_MEMORY,
+            None => info.device_type != wgpu::DeviceType::DiscreteGpu,
+        };
+        if low_memory {
+            Self::compact()
+        } else {
+            Self::default()
+        }
+    }
+
+    pub fn preset(preset: QualityPreset) -> Self {
+        match preset {
+            QualityPreset::Low => Self {
+                tile_cache_size: 256,
+                grass_cache_size: 8,
+                compact: false,
+                guided_upsampling: false,
+                max_screen_space_error: Some(6.0),
+                texture_filtering: TextureFiltering::Trilinear,
+                shadow_map_resolution: None,
+                distance_field_shadows: false,
+                depth_prepass: false,
+                occlusion_culling: false,
+                triplanar_mapping: false,
+                parallax_occlusion: None,
+                water_reflections: None,
+                sky_downsampling: Some(4),
+                grass: false,
+                exposure_range: (4.0, 15.0),
+                tile_store: TileStore::Disk,
+                tile_archive: None,
+                planet: PlanetConfig::EARTH,
+            },
+            QualityPreset::Medium => Self {
+                tile_cache_size: 384,
+                grass_cache_size: 16,
+                compact: false,
+                guided_upsampling: false,
+                max_screen_space_error: Some(4.0),
+                texture_filtering: TextureFiltering::Anisotropic(4),
+                shadow_map_resolution: Some(1024),
+                distance_field_shadows: true,
+                depth_prepass: true,
+                occlusion_culling: false,
+                triplanar_mapping: false,
+                parallax_occlusion: None,
+                water_reflections: Some(16),
+                sky_downsampling: Some(2),
+                grass: true,
+                exposure_range: (4.0, 15.0),
+                tile_store: TileStore::Disk,
+                tile_archive: None,
+                planet: PlanetConfig::EARTH,
+            },
+            QualityPreset::High => Self {
+                tile_cache_size: 512,
+                grass_cache_size: 32,
+                compact: false,
+                guided_upsampling: true,
+                max_screen_space_error: Some(2.0),
+                texture_filtering: TextureFiltering::Anisotropic(16),
+                shadow_map_resolution: Some(2048),
+                distance_field_shadows: true,
+                depth_prepass: true,
+                occlusion_culling: false,
+                triplanar_mapping: true,
+                parallax_occlusion: Some(16),
+                water_reflections: Some(32),
+                sky_downsampling: Some(2),
+                grass: true,
+                exposure_range: (4.0, 15.0),
+                tile_store: TileStore::Disk,
+                tile_archive: None,
+                planet: PlanetConfig::EARTH,
+            },
+            QualityPreset::Ultra => Self {
+                tile_cache_size: 1024,
+                grass_cache_size: 64,
+                compact: false,
+                guided_upsampling: true,
+                max_screen_space_error: Some(1.0),
+                texture_filtering: TextureFiltering::Anisotropic(16),
+                shadow_map_resolution: Some(4096),
+                distance_field_shadows: true,
+                depth_prepass: true,
+                occlusion_culling: false,
+                triplanar_mapping: true,
+                parallax_occlusion: Some(32),
+                water_reflections: Some(64),
+                sky_downsampling: None,
+                grass: true,
+                exposure_range: (4.0, 15.0),
+                tile_store: TileStore::Disk,
+                tile_archive: None,
+                planet: PlanetConfig::EARTH,
+            },
+        }
+    }
+}
+impl Default for TerrainOptions {
+    /// Options used by `Terrain::new`. These have the cache sizes and mesh density of the `High`
+    /// preset, but leave every optional effect off and use trilinear filtering, so terrain looks
+    /// the way it did before the effects were added. Pick a preset to turn them on.
+    fn default() -> Self {
+        Self {
+            guided_upsampling: false,
+            texture_filtering: TextureFiltering::Trilinear,
+            shadow_map_resolution: None,
+            distance_field_shadows: false,
+            depth_prepass: false,
+            triplanar_mapping: false,
+            parallax_occlusion: None,
+            water_reflections: None,
+            ..Self::preset(QualityPreset::High)
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::coordinates;
+use crate::terrain::quadtree::node::VNode;
+use anyhow::{ensure, Error};
+use cgmath::Vector3;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// What a named place is, which decides how far away its label can be seen.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PlaceKind {
+    /// A city, town or village.
+    City,
+    /// A mountain, peak or volcano.
+    Peak,
+    /// A lake or reservoir.
+    Lake,
+}
+
+/// A named point from the GeoNames gazetteer.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Place {
+    pub name: String,
+    pub kind: PlaceKind,
+    /// Latitude in radians.
+    pub latitude: f64,
+    /// Longitude in radians.
+    pub longitude: f64,
+    /// Elevation in meters recorded by the gazetteer, if any. Mostly present for peaks.
+    pub elevation: Option<f32>,
+    /// Number of inhabitants, or zero if unknown or not a city.
+    pub population: u64,
+}
+impl Place {
+    /// Parse one line of a GeoNames dump, returning None for features that aren't labeled.
+    ///
+    /// Lines hold tab separated fields, of which the name, latitude, longitude, feature class,
+    /// feature code, population, elevation and digital elevation model value are used.
+    fn from_geonames_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 17 {
+            return None;
+        }
+
+        let kind = match (fields[6], fields[7]) {
+            ("P", code) if code.starts_with("PPL") => PlaceKind::City,
+            ("T", "MT") | ("T", "PK") | ("T", "VLC") => PlaceKind::Peak,
+            ("H", "LK") | ("H", "RSV") => PlaceKind::Lake,
+            _ => return None,
+        };
+        // An elevation of -9999 marks points where the elevation model has no data.
+        let elevation = fields[15]
+            .parse()
+            .ok()
+            .or_else(|| fields[16].parse().ok().filter(|&e: &f32| e > -9999.0));
+
+        Some(Self {
+            name: fields[1].to_owned(),
+            kind,
+            latitude: fields[4].parse::<f64>().ok()?.to_radians(),
+            longitude: fields[5].parse::<f64>().ok()?.to_radians(),
+            elevation,
+            population: fields[14].parse().unwrap_or(0),
+        })
+    }
+
+    /// Coarsest level of the quadtree at which the label is shown. Larger cities and higher peaks
+    /// are stored in coarser nodes, so they are visible from further away.
+    pub(crate) fn level(&self) -> u8 {
+        match self.kind {
+            PlaceKind::City if self.population >= 1_000_000 => VNode::LEVEL_CELL_5KM,
+            PlaceKind::City if self.population >= 100_000 => VNode::LEVEL_CELL_1KM,
+            PlaceKind::City if self.population >= 10_000 => VNode::LEVEL_CELL_305M,
+            PlaceKind::City => VNode::LEVEL_CELL_76M,
+            PlaceKind::Peak if self.elevation.unwrap_or(0.0) >= 6000.0 => VNode::LEVEL_CELL_1KM,
+            PlaceKind::Peak if self.elevation.unwrap_or(0.0) >= 3000.0 => VNode::LEVEL_CELL_305M,
+            PlaceKind::Peak => VNode::LEVEL_CELL_76M,
+            PlaceKind::Lake => VNode::LEVEL_CELL_305M,
+        }
+    }
+
+    /// The node whose place tile this place is stored in.
+    pub(crate) fn node(&self) -> VNode {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(self.latitude, self.longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+        VNode::from_cspace(cspace, self.level()).0
+    }
+}
+
+/// Read every labeled place from a GeoNames dump, which may be either a text file like
+/// `cities500.txt` or the zip archive it is distributed in.
+pub(crate) fn read_geonames(path: &Path) -> Result<Vec<Place>, Error> {
+    let is_zip = path.extension().map_or(false, |e| e.eq_ignore_ascii_case("zip"));
+    if !is_zip {
+        return parse_geonames(BufReader::new(File::open(path)?));
+    }
+
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let name = archive
+        .file_names()
+        .find(|name| name.ends_with(".txt") && !name.eq_ignore_ascii_case("readme.txt"))
+        .map(str::to_owned);
+    ensure!(name.is_some(), "No GeoNames dump found in '{}'", path.display());
+    let mut contents = String::new();
+    archive.by_name(&name.unwrap())?.read_to_string(&mut contents)?;
+    parse_geonames(contents.as_bytes())
+}
+
+fn parse_geonames(reader: impl BufRead) -> Result<Vec<Place>, Error> {
+    let mut places = Vec::new();
+    for line in reader.lines() {
+        places.extend(Place::from_geonames_line(&line?));
+    }
+    Ok(places)
+}
+
+/// Group places by the node they are stored in.
+pub(crate) fn page_places(places: Vec<Place>) -> HashMap<VNode, Vec<Place>> {
+    let mut pages: HashMap<VNode, Vec<Place>> = HashMap::new();
+    for place in places {
+        pages.entry(place.node()).or_default().push(place);
+    }
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geonames_places() {
+        let dump = "\
+2950159\tBerlin\tBerlin\tBerlin\t52.52437\t13.41053\tP\tPPLC\tDE\t\t16\t00\t11000\t11000000\t3426354\t74\t43\tEurope/Berlin\t2022-09-17
+2660522\tEiger\tEiger\t\t46.57757\t8.00531\tT\tPK\tCH\t\t\t\t\t\t0\t3967\t3944\tEurope/Zurich\t2012-03-02
+5593681\tLake Tahoe\tLake Tahoe\t\t39.09689\t-120.04323\tH\tLK\tUS\t\t\t\t\t\t0\t\t1897\tAmerica/Los_Angeles\t2018-02-15
+2661552\tBern District\tBern District\t\t46.95\t7.45\tA\tADM2\tCH\t\t\t\t\t\t0\t\t540\tEurope/Zurich\t2012-01-17";
+        let places = parse_geonames(dump.as_bytes()).unwrap();
+        assert_eq!(places.len(), 3);
+
+        let berlin = &places[0];
+        assert_eq!(berlin.kind, PlaceKind::City);
+        assert_eq!(berlin.population, 3426354);
+        assert!((berlin.latitude.to_degrees() - 52.52437).abs() < 1e-9);
+        assert_eq!(berlin.level(), VNode::LEVEL_CELL_5KM);
+
+        assert_eq!(places[1].kind, PlaceKind::Peak);
+        assert_eq!(places[1].elevation, Some(3967.0));
+        assert_eq!(places[2].kind, PlaceKind::Lake);
+        assert_eq!(places[2].elevation, Some(1897.0));
+
+        let pages = page_places(places);
+        assert_eq!(pages.len(), 3);
+        assert!(pages.iter().all(|(node, page)| page.iter().all(|p| p.node() == *node)));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::terrain::quadtree::node::VNode;
+use anyhow::{ensure, Error};
+
+/// Properties of the body being rendered that shading, tile selection and generation depend on.
+/// The radius isn't included, because the layout of every tile is derived from it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PlanetConfig {
+    /// Elevation in meters that terrain below it is raised to, so that the ocean floor isn't
+    /// drawn. None leaves basins and trenches at their full depth, for bodies without oceans or
+    /// to look at the sea floor from below the surface.
+    pub sea_level: Option<f32>,
+    /// Lowest and highest elevation in meters that any terrain may have. Used to bound tiles
+    /// whose heights haven't been loaded yet, and terrain outside of it is clamped into it when
+    /// displacements are generated so that it is never culled incorrectly. Must lie within the
+    /// range of heights that heightmap tiles can store.
+    pub elevation_range: (f32, f32),
+    /// Thickness of the atmosphere in meters. Changing it regenerates the precomputed
+    /// scattering tables.
+    pub atmosphere_height: f32,
+}
+impl PlanetConfig {
+    pub const EARTH: Self = Self {
+        sea_level: Some(0.0),
+        elevation_range: VNode::DEFAULT_ELEVATION_RANGE,
+        atmosphere_height: 100000.0,
+    };
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let (min, max) = self.elevation_range;
+        ensure!(min < max, "Elevation range ({}, {}) is empty", min, max);
+        ensure!(
+            min >= i16::MIN as f32 && max <= i16::MAX as f32,
+            "Elevation range ({}, {}) exceeds the heights that tiles can store",
+            min,
+            max
+        );
+        Ok(())
+    }
+
+    /// Lowest elevation that terrain is drawn at.
+    pub(crate) fn floor(&self) -> f32 {
+        self.sea_level.unwrap_or(f32::NEG_INFINITY)
+    }
+
+    /// Elevation that terrain recorded at `elevation` is drawn at, after raising it to sea level
+    /// and clamping it to `elevation_range`.
+    pub(crate) fn clamp(&self, elevation: f32) -> f32 {
+        let (min, max) = self.drawn_range();
+        elevation.max(min).min(max)
+    }
+
+    /// Bounds on the elevation of drawn terrain whose recorded heights lie within `range`.
+    #[cfg(any(feature = "render", test))]
+    pub(crate) fn clamp_range(&self, range: (f32, f32)) -> (f32, f32) {
+        (self.clamp(range.0), self.clamp(range.1))
+    }
+
+    /// Lowest and highest elevation that any drawn terrain has. Tighter than `elevation_range`
+    /// when sea level lies above its minimum.
+    pub(crate) fn drawn_range(&self) -> (f32, f32) {
+        let (min, max) = self.elevation_range;
+        (min.max(self.floor()).min(max), max)
+    }
+
+    /// Values for the `planet` field of the global uniforms.
+    #[cfg(any(feature = "render", test))]
+    pub(crate) fn uniform(&self) -> [f32; 4] {
+        [self.sea_level.unwrap_or(-1.0e9), self.atmosphere_height, 0.0, 0.0]
+    }
+}
+impl Default for PlanetConfig {
+    fn default() -> Self {
+        Self::EARTH
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn planet_floor() {
+        assert_eq!(PlanetConfig::EARTH.floor().max(-420.0), 0.0);
+        let airless = PlanetConfig { sea_level: None, ..PlanetConfig::EARTH };
+        assert_eq!(airless.floor().max(-10994.0), -10994.0);
+        assert!(airless.uniform()[0] < -10994.0);
+    }
+
+    #[test]
+    fn extreme_viewpoints() {
+        use crate::coordinates;
+        use cgmath::Vector3;
+
+        let airless = PlanetConfig { sea_level: None, ..PlanetConfig::EARTH };
+        assert!(airless.validate().is_ok());
+        let too_deep = PlanetConfig { elevation_range: (-40000.0, 9000.0), ..airless };
+        assert!(too_deep.validate().is_err());
+        assert_eq!(PlanetConfig::EARTH.clamp_range((-430.0, 120.0)), (0.0, 120.0));
+        assert_eq!(airless.clamp(12000.0), 9000.0);
+
+        // Standing on the shore of the Dead Sea and at the bottom of the Challenger Deep, the node
+        // directly below the camera must be bounded closely enough to be refined all the way down.
+        for &(latitude, longitude, elevation) in
+            [(31.5f64, 35.5f64, -430.0f64), (11.3733, 142.5917, -10994.0)].iter()
+        {
+            let camera = coordinates::polar_to_ecef(Vector3::new(
+                latitude.to_radians(),
+                longitude.to_radians(),
+                elevation + 2.0,
+            ));
+            let cspace = camera / camera.x.abs().max(camera.y.abs()).max(camera.z.abs());
+            let (node, _, _) = VNode::from_cspace(cspace, VNode::LEVEL_CELL_76M);
+            let range = airless.clamp_range((elevation as f32, elevation as f32 + 100.0));
+            assert_eq!(range.0, elevation as f32);
+            assert_eq!(node.distance2(camera, airless.drawn_range()), 0.0);
+            assert_eq!(node.distance2(camera, range), 0.0);
+        }
+    }
+}
@@ -0,0 +1,865 @@
+use crate::airports::{self, Runway, Runways};
+use crate::cache::{LayerParams, LayerType};
+use crate::coordinates::{self, LatLongBounds, PLANET_RADIUS};
+use crate::deformation::HeightDelta;
+use crate::generate::{
+    heightmap::HeightmapCache, FractalDetailTable, HeightmapPass, MapFileBuilder, TileData,
+    TileGenerator, TileNode,
+};
+use crate::mapfile::{MapFile, TileState, TileStore};
+use crate::planet::PlanetConfig;
+use crate::skyline;
+use crate::terrain::quadtree::node::VNode;
+use crate::terrain::raster::RasterCacheStats;
+use crate::vector::VectorTileId;
+use anyhow::{ensure, format_err, Error};
+use cgmath::{EuclideanSpace, InnerSpace, Point3, Vector3};
+use fnv::FnvHashMap;
+use linked_hash_map::LinkedHashMap;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+/// Maximum number of steps taken when marching a ray against the terrain.
+const MAX_RAY_STEPS: usize = 4096;
+
+/// Number of recently sampled heightmap tiles that queries check before searching the map file.
+const HEIGHT_PATCHES: usize = 16;
+
+/// Most detailed level of heightmap tiles sampled for skylines, which only show distant terrain.
+const SKYLINE_LEVEL: u8 = 8;
+
+/// A triangle mesh of the terrain covered by a single quadtree node.
+#[derive(Clone, Debug)]
+pub struct TerrainMesh {
+    /// World space position that all vertex positions are relative to.
+    pub origin: mint::Point3<f64>,
+    /// Vertex positions relative to `origin`.
+    pub positions: Vec<[f32; 3]>,
+    /// Triangle list indices, wound counter-clockwise when viewed from above.
+    pub indices: Vec<u32>,
+}
+
+/// Counters for the compressed heightmap cache of a `HeadlessTerrain`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TileCacheStats {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+    /// Lookups that had to read and decode tiles from the map file.
+    pub misses: u64,
+    /// Number of tiles currently held.
+    pub tiles: usize,
+    /// Compressed size of the tiles currently held.
+    pub bytes: usize,
+}
+
+/// Decoded heightmap tiles kept LZ4 compressed, evicting the least recently used ones once their
+/// total size exceeds a budget. Decoding a tile from the map file requires decoding its ancestors
+/// as well, so this is much faster than reading it again.
+struct CompressedHeightmaps {
+    tiles: LinkedHashMap<VNode, Vec<u8>>,
+    budget: usize,
+    stats: TileCacheStats,
+}
+impl CompressedHeightmaps {
+    fn new(budget: usize) -> Self {
+        Self { tiles: LinkedHashMap::new(), budget, stats: TileCacheStats::default() }
+    }
+
+    fn get(&mut self, node: VNode) -> Option<Vec<i16>> {
+        let heights = self.tiles.get_refresh(&node).and_then(|data| {
+            let bytes = lz4::block::decompress(data, None).ok()?;
+            Some(bytes.chunks_exact(2).map(|b| i16::from_ne_bytes([b[0], b[1]])).collect())
+        });
+        match heights {
+            Some(_) => self.stats.hits += 1,
+            None => self.stats.misses += 1,
+        }
+        heights
+    }
+
+    fn insert(&mut self, node: VNode, heights: &[i16]) {
+        if let Ok(data) = lz4::block::compress(bytemuck::cast_slice(heights), None, true) {
+            self.stats.bytes += data.len();
+            if let Some(previous) = self.tiles.insert(node, data) {
+                self.stats.bytes -= previous.len();
+            }
+            self.evict();
+        }
+    }
+
+    fn set_budget(&mut self, bytes: usize) {
+        self.budget = bytes;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.stats.bytes > self.budget {
+            match self.tiles.pop_front() {
+                Some((_, data)) => self.stats.bytes -= data.len(),
+                None => break,
+            }
+        }
+        self.stats.tiles = self.tiles.len();
+    }
+}
+
+/// A heightmap tile that queries can sample without consulting the map file, because no more
+/// detailed tile exists anywhere within it.
+#[derive(Clone)]
+struct HeightPatch {
+    node: VNode,
+    /// Level limit of the queries that the patch answers.
+    max_level: u8,
+    heights: Arc<Vec<i16>>,
+    /// Lowest and highest elevation anywhere in the tile.
+    min: f32,
+    max: f32,
+}
+
+/// A point along a path returned by `Terrain::sample_path`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PathSample {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Distance in meters along the path from its first vertex, measured on the planet surface.
+    pub distance: f64,
+    /// World space position on the terrain surface.
+    pub position: mint::Point3<f64>,
+    /// World space surface normal of the terrain.
+    pub normal: mint::Vector3<f64>,
+    /// Direction of travel in radians clockwise from north.
+    pub heading: f64,
+}
+
+/// Answers terrain queries and generates base tiles using only the CPU.
+///
+/// Heightmap tiles are decoded on demand from the same map file used for rendering, so results
+/// match `Terrain::get_height` once the relevant tiles have streamed in. No wgpu device is needed,
+/// which makes this suitable for headless simulation servers. All queries block until the tiles
+/// they need have been loaded.
+pub struct HeadlessTerrain {
+    mapfile: Arc<MapFile>,
+    heightmaps: HeightmapCache,
+    compressed_heightmaps: CompressedHeightmaps,
+    /// Most recently used first.
+    patches: VecDeque<HeightPatch>,
+    heightmap_passes: Vec<HeightmapPass>,
+    albedo_supersampling: u32,
+    runways: Arc<Runways>,
+    /// Height deltas applied at runtime, by the node they change.
+    height_deltas: FnvHashMap<VNode, Vec<HeightDelta>>,
+    runtime: Runtime,
+}
+impl HeadlessTerrain {
+    pub fn new() -> Result<Self, Error> {
+        Self::with_tile_store(TileStore::Disk)
+    }
+
+    /// Create a headless terrain whose tiles are kept in `store`. With `TileStore::Memory`,
+    /// generated and downloaded tiles only last as long as the returned object.
+    pub fn with_tile_store(store: TileStore) -> Result<Self, Error> {
+        Self::from_mapfile(Arc::new(MapFileBuilder::new(store).build_headless()))
+    }
+
+    /// Create a headless terrain that reads tiles out of a zip archive of the terra cache
+    /// directory whenever they aren't in `store`, without extracting it.
+    pub fn with_tile_archive(store: TileStore, archive: &Path) -> Result<Self, Error> {
+        let mapfile = MapFileBuilder::new(store).with_archive(Some(archive))?;
+        Self::from_mapfile(Arc::new(mapfile.build_headless()))
+    }
+
+    /// Create a headless terrain with a different sea level, elevation range or atmosphere. The
+    /// planet always has Earth's radius. See `PlanetConfig`.
+    pub fn with_planet(store: TileStore, planet: PlanetConfig) -> Result<Self, Error> {
+        let mapfile = MapFileBuilder::new(store).with_planet(planet)?;
+        Self::from_mapfile(Arc::new(mapfile.build_headless()))
+    }
+
+    pub(crate) fn from_mapfile(mapfile: Arc<MapFile>) -> Result<Self, Error> {
+        Ok(Self {
+            heightmaps: HeightmapCache::new(layer(&mapfile).clone(), 4),
+            compressed_heightmaps: CompressedHeightmaps::new(64 << 20),
+            patches: VecDeque::new(),
+            runways: Runways::load(&mapfile)?,
+            mapfile,
+            heightmap_passes: Vec::new(),
+            albedo_supersampling: 1,
+            height_deltas: FnvHashMap::default(),
+            runtime: Runtime::new()?,
+        })
+    }
+
+    /// Limit the memory used to keep heightmap tiles around between queries, in bytes. Tiles are
+    /// held LZ4 compressed, so a budget covers several times as many tiles as it would
+    /// uncompressed. The default is 64 MiB.
+    pub fn set_tile_cache_budget(&mut self, bytes: usize) {
+        self.compressed_heightmaps.set_budget(bytes);
+    }
+
+    pub fn tile_cache_stats(&self) -> TileCacheStats {
+        self.compressed_heightmaps.stats
+    }
+
+    /// Register a pass that modifies each heightmap tile after its elevations have been sampled
+    /// from the source datasets, and before it is written to the map file. This is the place for
+    /// effects like thermal or hydraulic erosion and terracing. Passes run in the order they were
+    /// added, and their output is what every other layer is later derived from.
+    ///
+    /// All heightmap tiles must be generated with the same passes: `generate_heightmaps` returns
+    /// an error if some tiles already exist and were generated with different ones. `name`
+    /// identifies the pass for that check.
+    pub fn add_heightmap_pass<F>(&mut self, name: &str, pass: F)
+    where
+        F: Fn(&mut TileData, TileNode) + Send + Sync + 'static,
+    {
+        self.heightmap_passes.push(HeightmapPass { name: name.to_owned(), pass: Arc::new(pass) });
+    }
+
+    /// Change the heights of one heightmap tile for all later queries. See
+    /// `Terrain::apply_height_delta`. Nothing is written to the map file.
+    pub fn apply_height_delta(&mut self, delta: &HeightDelta) -> Result<(), Error> {
+        ensure!(
+            delta.fits(layer(&self.mapfile).texture_resolution as usize),
+            "Height delta extends past the edge of its tile"
+        );
+        self.height_deltas.entry(delta.vnode()).or_default().push(delta.clone());
+        self.patches.clear();
+        Ok(())
+    }
+
+    /// Undo a delta previously passed to `apply_height_delta`.
+    pub fn revert_height_delta(&mut self, delta: &HeightDelta) -> Result<(), Error> {
+        let deltas = self.height_deltas.entry(delta.vnode()).or_default();
+        let index = deltas
+            .iter()
+            .position(|d| d == delta)
+            .ok_or_else(|| format_err!("Height delta to revert was never applied"))?;
+        deltas.remove(index);
+        if deltas.is_empty() {
+            self.height_deltas.remove(&delta.vnode());
+        }
+        self.patches.clear();
+        Ok(())
+    }
+
+    /// Change the fractal detail parameters saved in the map file. They only affect rendering,
+    /// where terrain detail is synthesized below the resolution of the elevation data.
+    pub fn set_fractal_detail(&mut self, table: FractalDetailTable) -> Result<(), Error> {
+        self.mapfile.set_fractal_detail(&table)
+    }
+
+    /// Generate albedo tiles by averaging `factor` x `factor` samples of the source imagery within
+    /// each texel, rather than a single sample at its center. Factors of 2 to 4 noticeably reduce
+    /// aliasing and moiré from high frequency detail in the imagery, at the cost of proportionally
+    /// longer generation. The default is 1.
+    pub fn set_albedo_supersampling(&mut self, factor: u32) {
+        assert!(factor >= 1 && factor <= 4);
+        self.albedo_supersampling = factor;
+    }
+
+    fn tile_generator(&self) -> TileGenerator {
+        TileGenerator {
+            mapfile: Arc::clone(&self.mapfile),
+            heightmap_passes: self.heightmap_passes.clone(),
+            albedo_supersampling: self.albedo_supersampling,
+        }
+    }
+
+    /// Generate heightmap tiles.
+    ///
+    /// `etopo1_file` is the location of [ETOPO1_Ice_c_geotiff.zip](https://www.ngdc.noaa.gov/mgg/global/relief/ETOPO1/data/ice_surface/cell_registered/georeferenced_tiff/ETOPO1_Ice_c_geotiff.zip).
+    ///
+    /// Returns how often the SRTM rasters and the windows of their cells were found in memory
+    /// rather than loaded, which is useful for tuning generation on machines with little RAM.
+    pub async fn generate_heightmaps<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        etopo1_file: impl AsRef<Path>,
+        srtm3_directory: PathBuf,
+        progress_callback: F,
+    ) -> Result<RasterCacheStats, Error> {
+        self.patches.clear();
+        self.tile_generator()
+            .generate_heightmaps(etopo1_file, srtm3_directory, progress_callback)
+            .await
+    }
+
+    /// Generate albedo tiles.
+    ///
+    /// `blue_marble_directory` must contain the 8 files from NASA's Blue Marble: Next Generation
+    /// indicated in [`BLUE_MARBLE_URLS`](constant.BLUE_MARBLE_URLS.html).
+    pub async fn generate_albedos<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        blue_marble_directory: impl AsRef<Path>,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        self.tile_generator().generate_albedos(blue_marble_directory, progress_callback).await
+    }
+
+    /// Generate albedo tiles for a single month, stored as an epoch alongside the default albedo
+    /// tiles. `Terrain::set_albedo_epoch` crossfades between the epochs nearest to a given date.
+    ///
+    /// `blue_marble_directory` must contain the 8 files from the Blue Marble: Next Generation
+    /// release for that month, named like those in
+    /// [`BLUE_MARBLE_URLS`](constant.BLUE_MARBLE_URLS.html) but with `200406` replaced by the year
+    /// and month.
+    pub async fn generate_albedo_epoch<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        year: i16,
+        month: u8,
+        blue_marble_directory: impl AsRef<Path>,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        self.tile_generator()
+            .generate_albedo_epoch(year, month, blue_marble_directory, progress_callback)
+            .await
+    }
+
+    /// Generate roughness tiles.
+    pub async fn generate_roughness<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        self.tile_generator().generate_roughness(progress_callback).await
+    }
+
+    /// Generate place name tiles, which `Terrain::set_place_labels` projects into label anchors.
+    ///
+    /// `geonames_file` is a dump from the [GeoNames](https://download.geonames.org/export/dump/)
+    /// gazetteer such as `cities500.zip` or `allCountries.zip`, either zipped or extracted. Cities,
+    /// peaks and lakes are kept and everything else is skipped.
+    pub fn generate_place_names<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        geonames_file: impl AsRef<Path>,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        self.tile_generator().generate_place_names(geonames_file, progress_callback)
+    }
+
+    /// Precompute the skylines of distant mountains for viewpoints about `spacing` meters apart
+    /// covering `bounds`, replacing any generated before. Terrain created from the map file
+    /// afterwards draws them along the horizon while the camera is within `bounds`, so that
+    /// ranges hundreds of kilometers away show up without their tiles being streamed.
+    ///
+    /// Each viewpoint traces the heightmap tiles in 128 directions, so large regions with a small
+    /// spacing take a long time.
+    pub fn generate_skylines<F: FnMut(&str, usize, usize) + Send>(
+        &mut self,
+        bounds: LatLongBounds,
+        spacing: f64,
+        progress_callback: F,
+    ) -> Result<(), Error> {
+        let mapfile = Arc::clone(&self.mapfile);
+        skyline::generate(
+            &mapfile,
+            bounds,
+            spacing,
+            |latitude, longitude| {
+                let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+                self.height_cspace(ecef, SKYLINE_LEVEL)
+            },
+            progress_callback,
+        )
+    }
+
+    /// Find the most detailed heightmap tile containing `cspace`, returning it along with the
+    /// position of the point within the tile.
+    fn tile(&mut self, cspace: Vector3<f64>, max_level: u8) -> Result<(VNode, f32, f32), Error> {
+        let mut found = None;
+        for level in 0..=max_level.min(VNode::LEVEL_CELL_1M) {
+            let (node, x, y) = VNode::from_cspace(cspace, level);
+            match self.mapfile.tile_state(LayerType::Heightmaps, node)? {
+                TileState::Base | TileState::MissingBase | TileState::Generated => {
+                    found = Some((node, x, y))
+                }
+                TileState::Missing | TileState::GpuOnly => break,
+            }
+        }
+        found.ok_or_else(|| anyhow::format_err!("no heightmap tiles in map file"))
+    }
+
+    /// Heights of the tile of `node` with any height deltas added. Tiles are cached without them,
+    /// because more detailed tiles are decoded relative to the originals.
+    fn heights(&mut self, node: VNode) -> Result<Arc<Vec<i16>>, Error> {
+        let heights = self.stored_heights(node)?;
+        Ok(match self.height_deltas.get(&node) {
+            Some(deltas) => {
+                let resolution = layer(&self.mapfile).texture_resolution as usize;
+                let mut heights = heights.to_vec();
+                for delta in deltas {
+                    delta.add_to(&mut heights, resolution, 1);
+                }
+                Arc::new(heights)
+            }
+            None => heights,
+        })
+    }
+
+    fn stored_heights(&mut self, node: VNode) -> Result<Arc<Vec<i16>>, Error> {
+        if let Some(heights) = self.heightmaps.cached(node) {
+            return Ok(heights);
+        }
+        if let Some(heights) = self.compressed_heightmaps.get(node) {
+            let heights = Arc::new(heights);
+            self.heightmaps.insert(node, Arc::clone(&heights));
+            return Ok(heights);
+        }
+
+        let future = self.heightmaps.get_tile(&*self.mapfile, node);
+        let heights = self.runtime.block_on(future)?;
+        self.compressed_heightmaps.insert(node, &heights);
+        Ok(heights)
+    }
+
+    /// The heightmap tile to sample for the point at `cspace`, using tiles no more detailed than
+    /// `max_level`, along with the position of the point within the tile.
+    ///
+    /// Queries tend to cluster, so recently used tiles are checked first. This skips the search
+    /// through every level of the map file, which otherwise dominates the cost of a query.
+    fn patch(
+        &mut self,
+        cspace: Vector3<f64>,
+        max_level: u8,
+    ) -> Result<(HeightPatch, f32, f32), Error> {
+        let cspace = cspace / cspace.x.abs().max(cspace.y.abs()).max(cspace.z.abs());
+        for i in 0..self.patches.len() {
+            if self.patches[i].max_level != max_level {
+                continue;
+            }
+            let (node, x, y) = VNode::from_cspace(cspace, self.patches[i].node.level());
+            if node == self.patches[i].node {
+                let patch = self.patches.remove(i).unwrap();
+                self.patches.push_front(patch.clone());
+                return Ok((patch, x, y));
+            }
+        }
+
+        let (node, x, y) = self.tile(cspace, max_level)?;
+        let heights = self.heights(node)?;
+        let (min, max) =
+            heights.iter().fold((i16::MAX, i16::MIN), |(min, max), &h| (min.min(h), max.max(h)));
+        let patch = HeightPatch { node, max_level, heights, min: min as f32, max: max as f32 };
+
+        // Only tiles without more detailed descendants give the same answer for every point.
+        let leaf = node.level() >= max_level.min(VNode::LEVEL_CELL_1M)
+            || node.children().iter().all(|&child| {
+                matches!(
+                    self.mapfile.tile_state(LayerType::Heightmaps, child),
+                    Ok(TileState::Missing) | Ok(TileState::GpuOnly)
+                )
+            });
+        if leaf {
+            self.patches.push_front(patch.clone());
+            self.patches.truncate(HEIGHT_PATCHES);
+        }
+        Ok((patch, x, y))
+    }
+
+    /// Elevation of the point at `cspace`, using tiles no more detailed than `max_level`.
+    fn height_cspace(&mut self, cspace: Vector3<f64>, max_level: u8) -> Result<f32, Error> {
+        let (patch, x, y) = self.patch(cspace, max_level)?;
+        Ok(sample(&self.mapfile, &patch.heights, x, y))
+    }
+
+    /// Returns the elevation in meters of the terrain at the given latitude and longitude, given
+    /// in radians.
+    pub fn get_height(&mut self, latitude: f64, longitude: f64) -> Result<f32, Error> {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        self.height_cspace(ecef, VNode::LEVEL_CELL_1M)
+    }
+
+    /// Returns the world space surface normal of the terrain at the given latitude and longitude.
+    pub fn get_normal(
+        &mut self,
+        latitude: f64,
+        longitude: f64,
+    ) -> Result<mint::Vector3<f64>, Error> {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        let up = ecef.normalize();
+        let east = Vector3::new(0.0, 0.0, 1.0).cross(up).normalize();
+        let north = up.cross(east);
+
+        // Use central differences with a spacing of roughly one heightmap sample.
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+        let (node, _, _) = self.tile(cspace, VNode::LEVEL_CELL_1M)?;
+        let l = layer(&self.mapfile);
+        let spacing = node.aprox_side_length() as f64
+            / (l.texture_resolution - 2 * l.texture_border_size) as f64;
+        let mut surface = |offset: Vector3<f64>| -> Result<Vector3<f64>, Error> {
+            let p = ecef + offset;
+            let height = self.height_cspace(p, node.level())? as f64;
+            Ok(p.normalize() * (PLANET_RADIUS + height))
+        };
+        let dx = surface(east * spacing)? - surface(-east * spacing)?;
+        let dy = surface(north * spacing)? - surface(-north * spacing)?;
+        Ok(dx.cross(dy).normalize().into())
+    }
+
+    /// Intersect a ray with the terrain, returning the first world space point where it hits the
+    /// surface within `max_distance` meters of `origin`.
+    pub fn raycast(
+        &mut self,
+        origin: mint::Point3<f64>,
+        direction: mint::Vector3<f64>,
+        max_distance: f64,
+    ) -> Result<Option<mint::Point3<f64>>, Error> {
+        let origin = Point3::from(origin).to_vec();
+        let direction = Vector3::from(direction).normalize();
+
+        // Signed height of a point above the terrain.
+        let mut clearance = |t: f64| -> Result<f64, Error> {
+            let p = origin + direction * t;
+            Ok(p.magnitude() - PLANET_RADIUS - self.height_cspace(p, VNode::LEVEL_CELL_1M)? as f64)
+        };
+
+        let mut t = 0.0;
+        let mut c = clearance(t)?;
+        if c <= 0.0 {
+            return Ok(Some(Point3::from_vec(origin).into()));
+        }
+        for _ in 0..MAX_RAY_STEPS {
+            // Terrain slopes are rarely steeper than 45 degrees, so half the clearance is a step
+            // that almost never skips over the surface.
+            let next = (t + (c * 0.5).max(1.0)).min(max_distance);
+            let next_c = clearance(next)?;
+            if next_c <= 0.0 {
+                // Refine the hit by bisection.
+                let (mut lo, mut hi) = (t, next);
+                for _ in 0..32 {
+                    let mid = 0.5 * (lo + hi);
+                    if clearance(mid)? > 0.0 {
+                        lo = mid;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                return Ok(Some(Point3::from_vec(origin + direction * hi).into()));
+            }
+            if next >= max_distance {
+                break;
+            }
+            t = next;
+            c = next_c;
+        }
+        Ok(None)
+    }
+
+    /// Import airport runways from the `airports.csv` and `runways.csv` files published by
+    /// [OurAirports](https://ourairports.com/data/), replacing any imported before. The runways are
+    /// saved in the map file for `runway` and `runways_near`, and a heightmap pass named "runways"
+    /// is registered that flattens the ground beneath them, so this must be called before
+    /// `generate_heightmaps` for the runways to be leveled.
+    pub fn add_runways(
+        &mut self,
+        airports_csv: impl AsRef<Path>,
+        runways_csv: impl AsRef<Path>,
+    ) -> Result<(), Error> {
+        self.runways = airports::ingest(
+            &self.mapfile,
+            &mut self.heightmap_passes,
+            airports_csv.as_ref(),
+            runways_csv.as_ref(),
+        )?;
+        Ok(())
+    }
+
+    /// Look up a runway by the identifier of its airport and either its full designator, like
+    /// "09L/27R", or that of one end, like "27R". When matched by one end, the runway is described
+    /// as seen from that end, so its heading is the direction of takeoffs and landings there.
+    pub fn runway(&self, airport: &str, ident: &str) -> Option<Runway> {
+        self.runways.find(airport, ident)
+    }
+
+    /// Runways whose midpoints lie within `radius` meters of a latitude and longitude given in
+    /// radians, closest first.
+    pub fn runways_near(&self, latitude: f64, longitude: f64, radius: f64) -> Vec<&Runway> {
+        self.runways.near(latitude, longitude, radius)
+    }
+
+    /// Attach arbitrary application data, such as points of interest or navigation graphs, to a
+    /// quadtree node. The data is stored in the map file and streamed to the callback set with
+    /// `Terrain::set_vector_tile_callback` whenever the node's terrain tiles are resident.
+    pub fn write_vector_tile(&mut self, tile: VectorTileId, data: &[u8]) -> Result<(), Error> {
+        self.mapfile.write_tile(LayerType::Vector, tile.0, data, true)
+    }
+
+    /// Whether a world space point lies below the terrain surface.
+    pub fn is_underground(&mut self, point: mint::Point3<f64>) -> Result<bool, Error> {
+        let p = Point3::from(point).to_vec();
+        let altitude = p.magnitude() - PLANET_RADIUS;
+        let (patch, x, y) = self.patch(p, VNode::LEVEL_CELL_1M)?;
+        if altitude > patch.max as f64 || altitude < patch.min as f64 {
+            return Ok(altitude < patch.min as f64);
+        }
+        Ok(altitude < sample(&self.mapfile, &patch.heights, x, y) as f64)
+    }
+
+    /// Approximate signed distance in meters from a world space point to the terrain surface,
+    /// positive above ground and negative below. This lets cave and voxel systems decide where
+    /// their own geometry should take over from the terrain surface.
+    pub fn distance_to_surface(&mut self, point: mint::Point3<f64>) -> Result<f64, Error> {
+        distance_to_surface(Point3::from(point).to_vec(), |latitude, longitude| {
+            self.get_height(latitude, longitude)
+        })
+    }
+
+    /// Place points every `spacing` meters along the great circle arcs joining the given latitude
+    /// and longitude pairs, clamped to the ground. See `Terrain::sample_path`.
+    pub fn sample_path(
+        &mut self,
+        polyline: &[(f64, f64)],
+        spacing: f64,
+    ) -> Result<Vec<PathSample>, Error> {
+        sample_path(polyline, spacing, |latitude, longitude| self.get_height(latitude, longitude))
+    }
+
+    /// Build a triangle mesh for the quadtree node at `level` that contains the given latitude and
+    /// longitude. If the map file doesn't have heightmaps that detailed, the mesh is built from
+    /// the most detailed ancestor available instead.
+    pub fn extract_mesh(
+        &mut self,
+        latitude: f64,
+        longitude: f64,
+        level: u8,
+    ) -> Result<TerrainMesh, Error> {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+        let (node, _, _) = self.tile(cspace, level)?;
+        let heights = self.heights(node)?;
+
+        let l = layer(&self.mapfile);
+        let resolution = l.texture_resolution;
+        let border = l.texture_border_size;
+        let origin = node.center_wspace();
+
+        let mut positions = Vec::new();
+        for y in border..(resolution - border) {
+            for x in border..(resolution - border) {
+                let cspace =
+                    node.grid_position_cspace(x as i32, y as i32, border as u16, resolution as u16);
+                let height = heights[(x + y * resolution) as usize] as f64;
+                let p = cspace.normalize() * (PLANET_RADIUS + height) - origin;
+                positions.push([p.x as f32, p.y as f32, p.z as f32]);
+            }
+        }
+
+        // Pick the winding that faces away from the planet center.
+        let width = resolution - 2 * border;
+        let p = |i: u32| Vector3::from(positions[i as usize]).cast::<f64>().unwrap() + origin;
+        let flip = (p(1) - p(0)).cross(p(width) - p(0)).dot(p(0)) < 0.0;
+
+        let mut indices = Vec::new();
+        for y in 0..(width - 1) {
+            for x in 0..(width - 1) {
+                let i = x + y * width;
+                let quad = [i, i + 1, i + width, i + width, i + 1, i + width + 1];
+                if flip {
+                    indices
+                        .extend_from_slice(&[quad[0], quad[2], quad[1], quad[3], quad[5], quad[4]]);
+                } else {
+                    indices.extend_from_slice(&quad);
+                }
+            }
+        }
+
+        Ok(TerrainMesh { origin: Point3::from_vec(origin).into(), positions, indices })
+    }
+}
+
+/// Signed distance from `point` to the terrain surface whose elevation at each latitude and
+/// longitude is given by `height`. The surface is treated as the plane tangent to it directly
+/// above or below the point, which is accurate close to the surface where it matters most.
+pub(crate) fn distance_to_surface(
+    point: Vector3<f64>,
+    height: impl FnMut(f64, f64) -> Result<f32, Error>,
+) -> Result<f64, Error> {
+    let (origin, normal) = surface_frame(point, height)?;
+    Ok((point - origin).dot(normal))
+}
+
+/// Samples along the great circle arcs joining each pair of consecutive vertices in `polyline`,
+/// with elevations given by `height`. The final vertex is always included so that the path ends
+/// exactly where requested.
+pub(crate) fn sample_path(
+    polyline: &[(f64, f64)],
+    spacing: f64,
+    mut height: impl FnMut(f64, f64) -> Result<f32, Error>,
+) -> Result<Vec<PathSample>, Error> {
+    ensure!(polyline.len() >= 2, "paths need at least two vertices");
+    ensure!(spacing > 0.0, "path sample spacing must be positive");
+
+    let mut sample = |dir: Vector3<f64>, tangent: Vector3<f64>, distance: f64| {
+        let polar = coordinates::ecef_to_polar(dir);
+        let (east, north) = tangent_frame(dir);
+        let ground = dir * (PLANET_RADIUS + height(polar.x, polar.y)? as f64);
+        let (position, normal) = surface_frame(ground, &mut height)?;
+        Ok::<_, Error>(PathSample {
+            latitude: polar.x,
+            longitude: polar.y,
+            distance,
+            position: Point3::from_vec(position).into(),
+            normal: normal.into(),
+            heading: tangent.dot(east).atan2(tangent.dot(north)),
+        })
+    };
+
+    let points: Vec<_> = polyline
+        .iter()
+        .map(|&(lat, long)| coordinates::polar_to_ecef(Vector3::new(lat, long, 0.0)).normalize())
+        .collect();
+
+    let mut samples = Vec::new();
+    let mut start = 0.0;
+    let mut next = 0.0;
+    let mut end_tangent = None;
+    for segment in points.windows(2) {
+        let (a, b) = (segment[0], segment[1]);
+        let axis = a.cross(b);
+        let angle = axis.magnitude().atan2(a.dot(b));
+        if axis.magnitude2() == 0.0 {
+            continue;
+        }
+        let axis = axis.normalize();
+        let length = angle * PLANET_RADIUS;
+        while next <= start + length {
+            let t = (next - start) / PLANET_RADIUS;
+            let dir = a * t.cos() + axis.cross(a) * t.sin();
+            samples.push(sample(dir, axis.cross(dir), next)?);
+            next += spacing;
+        }
+        start += length;
+        end_tangent = Some(axis.cross(b));
+    }
+
+    ensure!(end_tangent.is_some(), "path has zero length");
+    if samples.last().unwrap().distance < start {
+        samples.push(sample(*points.last().unwrap(), end_tangent.unwrap(), start)?);
+    }
+    Ok(samples)
+}
+
+/// Unit vectors pointing east and north on the plane tangent to the sphere at `point`.
+fn tangent_frame(point: Vector3<f64>) -> (Vector3<f64>, Vector3<f64>) {
+    let up = point.normalize();
+    let east = if up.z.abs() < 0.999 {
+        Vector3::unit_z().cross(up).normalize()
+    } else {
+        Vector3::unit_x()
+    };
+    (east, up.cross(east))
+}
+
+/// The point on the terrain surface directly above or below `point`, and the surface normal
+/// there estimated from elevations one meter away on either side.
+fn surface_frame(
+    point: Vector3<f64>,
+    mut height: impl FnMut(f64, f64) -> Result<f32, Error>,
+) -> Result<(Vector3<f64>, Vector3<f64>), Error> {
+    let (east, north) = tangent_frame(point);
+    let mut surface = |offset: Vector3<f64>| -> Result<Vector3<f64>, Error> {
+        let p = point + offset;
+        let polar = coordinates::ecef_to_polar(p);
+        Ok(p.normalize() * (PLANET_RADIUS + height(polar.x, polar.y)? as f64))
+    };
+    let origin = surface(Vector3::new(0.0, 0.0, 0.0))?;
+    let dx = surface(east)? - surface(-east)?;
+    let dy = surface(north)? - surface(-north)?;
+    Ok((origin, dx.cross(dy).normalize()))
+}
+
+fn layer(mapfile: &MapFile) -> &LayerParams {
+    &mapfile.layers()[LayerType::Heightmaps]
+}
+
+/// Bilinearly interpolate a heightmap tile at position (`x`, `y`), given as fractions of the
+/// node's width.
+fn sample(mapfile: &MapFile, heights: &[i16], x: f32, y: f32) -> f32 {
+    let layer = layer(mapfile);
+    let border = layer.texture_border_size as usize;
+    let resolution = layer.texture_resolution as usize;
+    let x = (x * (resolution - 2 * border - 1) as f32) + border as f32;
+    let y = (y * (resolution - 2 * border - 1) as f32) + border as f32;
+
+    let h = |x: f32, y: f32| heights[x as usize + y as usize * resolution] as f32;
+    let (fx, fy) = (x.fract(), y.fract());
+    let height = h(x.floor(), y.floor()) * (1.0 - fx) * (1.0 - fy)
+        + h(x.ceil(), y.floor()) * fx * (1.0 - fy)
+        + h(x.floor(), y.ceil()) * (1.0 - fx) * fy
+        + h(x.ceil(), y.ceil()) * fx * fy;
+    mapfile.planet().clamp(height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synthetic::SyntheticPlanet;
+
+    #[test]
+    fn headless_queries() {
+        let mapfile = Arc::new(MapFile::synthetic(PLANET_RADIUS + 3000.0, 7));
+        let mut terrain = HeadlessTerrain::from_mapfile(mapfile).unwrap();
+        let planet = SyntheticPlanet::new(PLANET_RADIUS + 3000.0, 7);
+
+        let (latitude, longitude) = (0.3f64, 1.1f64);
+        let height = terrain.get_height(latitude, longitude).unwrap();
+        let expected = planet.elevation(Vector3::new(
+            latitude.cos() * longitude.cos(),
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+        ));
+        assert!((height - expected.max(0.0)).abs() < 50.0, "{} vs {}", height, expected);
+        assert_eq!(terrain.patches.len(), 1);
+        assert_eq!(terrain.get_height(latitude, longitude).unwrap(), height);
+
+        let normal = Vector3::from(terrain.get_normal(latitude, longitude).unwrap());
+        let surface = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        assert!(normal.dot(surface.normalize()) > 0.5);
+
+        let above = surface.normalize() * (PLANET_RADIUS + 20_000.0);
+        let hit = terrain.raycast(Point3::from_vec(above).into(), (-surface).into(), 40_000.0);
+        let hit_height = Point3::from(hit.unwrap().unwrap()).to_vec().magnitude() - PLANET_RADIUS;
+        assert!((hit_height - height as f64).abs() < 50.0);
+
+        let below = surface.normalize() * (PLANET_RADIUS + height as f64 - 10.0);
+        assert!(terrain.is_underground(Point3::from_vec(below).into()).unwrap());
+        assert!(!terrain.is_underground(Point3::from_vec(above).into()).unwrap());
+        let distance = terrain.distance_to_surface(Point3::from_vec(below).into()).unwrap();
+        assert!(distance < 0.0 && distance > -10.01, "{}", distance);
+
+        let path = [(latitude, longitude), (latitude, longitude + 0.001)];
+        let samples = terrain.sample_path(&path, 1000.0).unwrap();
+        assert_eq!(samples.len(), 8);
+        let start = Point3::from(samples[0].position).to_vec();
+        assert!((start.magnitude() - PLANET_RADIUS - height as f64).abs() < 0.01);
+        assert!((samples[7].distance - 0.001 * latitude.cos() * PLANET_RADIUS).abs() < 1.0);
+        assert!(samples.iter().all(|s| (s.heading - std::f64::consts::FRAC_PI_2).abs() < 0.01));
+
+        let mesh =
+            terrain.extract_mesh(latitude, longitude, SyntheticPlanet::HEIGHTMAP_LEVELS).unwrap();
+        assert_eq!(mesh.positions.len(), 33 * 33);
+        assert_eq!(mesh.indices.len(), 32 * 32 * 6);
+    }
+
+    #[test]
+    fn compressed_heightmaps() {
+        let node = |i| VNode::roots()[i];
+        let tile = |i: usize| (0..1000).map(|j| (j % 37) as i16 * i as i16).collect::<Vec<_>>();
+
+        let mut cache = CompressedHeightmaps::new(usize::MAX);
+        assert_eq!(cache.get(node(0)), None);
+        cache.insert(node(0), &tile(0));
+        cache.insert(node(1), &tile(1));
+        assert_eq!(cache.get(node(1)), Some(tile(1)));
+        assert_eq!(cache.get(node(0)), Some(tile(0)));
+        let stats = cache.stats;
+        assert_eq!((stats.hits, stats.misses, stats.tiles), (2, 1, 2));
+        assert!(stats.bytes < 2 * 2000);
+
+        // Shrinking the budget evicts the least recently used tile first.
+        cache.set_budget(stats.bytes - 1);
+        assert_eq!(cache.stats.tiles, 1);
+        assert_eq!(cache.get(node(1)), None);
+        assert_eq!(cache.get(node(0)), Some(tile(0)));
+    }
+}
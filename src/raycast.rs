@@ -0,0 +1,292 @@
+use crate::cache::{LayerType, UnifiedPriorityCache};
+use crate::coordinates::PLANET_RADIUS;
+use crate::gpu_state::GpuState;
+use crate::terrain::quadtree::node::VNode;
+use anyhow::{ensure, Error};
+use cgmath::{EuclideanSpace, InnerSpace, Point3, Vector3};
+use futures::{Future, FutureExt};
+use maplit::hashmap;
+use std::mem;
+
+/// A ray passed to `Terrain::raycast_batch`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Ray {
+    /// World space origin of the ray.
+    pub origin: mint::Point3<f64>,
+    pub direction: mint::Vector3<f32>,
+    /// Distance in meters beyond which the ray is considered to have missed.
+    pub max_distance: f32,
+}
+
+/// Where a ray passed to `Terrain::raycast_batch` first hit the terrain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RayHit {
+    /// Distance in meters from the origin of the ray.
+    pub distance: f32,
+    /// World space position of the hit.
+    pub position: mint::Point3<f64>,
+}
+
+/// Layout must match `Ray` in raycast-batch.comp.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct GpuRay {
+    /// Origin of the ray relative to `RaycastUniforms::reference`.
+    origin: [f32; 3],
+    /// Altitude of the origin above sea level, computed in double precision.
+    altitude: f32,
+    direction: [f32; 3],
+    max_distance: f32,
+}
+unsafe impl bytemuck::Zeroable for GpuRay {}
+unsafe impl bytemuck::Pod for GpuRay {}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct RaycastUniforms {
+    /// Point that ray origins are relative to. It only needs to be near the rays, so rounding it
+    /// to f32 doesn't move them relative to each other.
+    reference: [f32; 3],
+    num_rays: u32,
+    table_mask: u32,
+    max_level: u32,
+    resolution: u32,
+    border: u32,
+    min_elevation: f32,
+    max_elevation: f32,
+    padding: u32,
+}
+unsafe impl bytemuck::Zeroable for RaycastUniforms {}
+unsafe impl bytemuck::Pod for RaycastUniforms {}
+
+/// Hash of a node used to look up its slot. Must match `node_hash` in raycast-batch.comp.
+fn node_hash(node: VNode) -> u32 {
+    node.x().wrapping_mul(73856093)
+        ^ node.y().wrapping_mul(19349663)
+        ^ (node.level() as u32 * 8 + node.face() as u32).wrapping_mul(83492791)
+}
+
+/// Open addressing hash table from resident nodes to their heightmap slots, so that the shader can
+/// find the most detailed tile covering any point. Each entry holds `(level + 1) | face << 8`,
+/// `x`, `y` and the slot, with zeros marking empty entries.
+fn slot_table(nodes: &[(VNode, usize)]) -> Vec<[u32; 4]> {
+    let size = (nodes.len() * 2).next_power_of_two().max(16);
+    let mut table = vec![[0; 4]; size];
+    for &(node, slot) in nodes {
+        let mut i = node_hash(node) as usize & (size - 1);
+        while table[i][0] != 0 {
+            i = (i + 1) & (size - 1);
+        }
+        let key = (node.level() as u32 + 1) | ((node.face() as u32) << 8);
+        table[i] = [key, node.x(), node.y(), slot as u32];
+    }
+    table
+}
+
+fn binding(buffer: &wgpu::Buffer) -> (bool, wgpu::BindingResource) {
+    (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding { buffer, offset: 0, size: None }))
+}
+
+/// Intersects batches of rays with the resident heightmap tiles using a compute shader.
+pub(crate) struct BatchRaycaster {
+    shader: rshader::ShaderSet,
+    pipeline: Option<wgpu::ComputePipeline>,
+}
+impl BatchRaycaster {
+    pub fn new() -> Self {
+        Self {
+            shader: rshader::ShaderSet::compute_only(rshader::shader_source!(
+                "shaders",
+                "raycast-batch.comp",
+                "declarations.glsl"
+            ))
+            .unwrap(),
+            pipeline: None,
+        }
+    }
+
+    /// Record the raycasts and return a future that resolves once the GPU has finished them. The
+    /// device must keep being polled, as `Terrain::render` does, for the future to make progress.
+    pub fn raycast(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        cache: &UnifiedPriorityCache,
+        elevation_range: (f32, f32),
+        rays: &[Ray],
+    ) -> Result<impl Future<Output = Result<Vec<Option<RayHit>>, Error>>, Error> {
+        let resident = cache.tiles.resident_slots(LayerType::Heightmaps);
+        ensure!(!resident.is_empty(), "no heightmap tiles are resident");
+        if self.shader.refresh() {
+            self.pipeline = None;
+        }
+
+        // Absolute positions on the planet are only accurate to about half a meter as f32, so the
+        // origins are uploaded relative to one of them. The reference is rounded to f32 before the
+        // offsets are taken, so that the shader adds back exactly the same point.
+        let reference = rays
+            .first()
+            .map(|ray| Point3::from(ray.origin).to_vec().cast::<f32>().unwrap())
+            .unwrap_or_else(|| Vector3::new(0.0, 0.0, 0.0));
+        let reference_f64 = reference.cast::<f64>().unwrap();
+        let gpu_rays: Vec<GpuRay> = rays
+            .iter()
+            .map(|ray| {
+                let origin = Point3::from(ray.origin).to_vec();
+                let direction = Vector3::from(ray.direction).normalize();
+                GpuRay {
+                    origin: (origin - reference_f64).cast::<f32>().unwrap().into(),
+                    altitude: (origin.magnitude() - PLANET_RADIUS) as f32,
+                    direction: direction.into(),
+                    max_distance: ray.max_distance,
+                }
+            })
+            .collect();
+        let table = slot_table(&resident);
+        let layer = cache.tile_desc(LayerType::Heightmaps);
+        let uniforms = RaycastUniforms {
+            reference: reference.into(),
+            num_rays: rays.len() as u32,
+            table_mask: table.len() as u32 - 1,
+            max_level: VNode::LEVEL_CELL_1M as u32,
+            resolution: layer.texture_resolution,
+            border: layer.texture_border_size,
+            min_elevation: elevation_range.0,
+            max_elevation: elevation_range.1,
+            padding: 0,
+        };
+
+        let buffer = |name: &str, contents: &[u8], usage: wgpu::BufferUsage| {
+            let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                size: contents.len().max(16) as u64,
+                usage: usage | wgpu::BufferUsage::COPY_DST,
+                label: Some(&format!("buffer.raycast.{}", name)),
+                mapped_at_creation: false,
+            });
+            queue.write_buffer(&buffer, 0, contents);
+            buffer
+        };
+        let uniform_buffer =
+            buffer("uniforms", bytemuck::bytes_of(&uniforms), wgpu::BufferUsage::UNIFORM);
+        let ray_buffer =
+            buffer("rays", bytemuck::cast_slice(&gpu_rays), wgpu::BufferUsage::STORAGE);
+        let table_buffer =
+            buffer("table", bytemuck::cast_slice(&table), wgpu::BufferUsage::STORAGE);
+        let hits_size = (rays.len().max(4) * mem::size_of::<f32>()) as u64;
+        let hits_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            size: hits_size,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_SRC,
+            label: Some("buffer.raycast.hits"),
+            mapped_at_creation: false,
+        });
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            size: hits_size,
+            usage: wgpu::BufferUsage::MAP_READ | wgpu::BufferUsage::COPY_DST,
+            label: Some("buffer.raycast.readback"),
+            mapped_at_creation: false,
+        });
+
+        let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+            device,
+            &self.shader,
+            hashmap![
+                "ubo".into() => binding(&uniform_buffer),
+                "rays".into() => binding(&ray_buffer),
+                "table".into() => binding(&table_buffer),
+                "hits".into() => binding(&hits_buffer),
+            ],
+            Default::default(),
+            "raycast",
+        );
+        if self.pipeline.is_none() {
+            self.pipeline =
+                Some(device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                        bind_group_layouts: [&*bind_group_layout][..].into(),
+                        push_constant_ranges: &[],
+                        label: Some("pipeline.raycast.layout"),
+                    })),
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.raycast"),
+                        source: wgpu::ShaderSource::SpirV(self.shader.compute().into()),
+                        flags: wgpu::ShaderFlags::empty(),
+                    }),
+                    entry_point: "main",
+                    label: Some("pipeline.raycast"),
+                }));
+        }
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.raycast"),
+        });
+        {
+            let mut cpass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            cpass.set_pipeline(self.pipeline.as_ref().unwrap());
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch((rays.len() as u32 + 63) / 64, 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&hits_buffer, 0, &readback, 0, hits_size);
+        queue.submit(Some(encoder.finish()));
+
+        let origins: Vec<_> = rays.iter().map(|ray| (ray.origin, ray.direction)).collect();
+        Ok(readback.slice(..).map_async(wgpu::MapMode::Read).map(move |result| {
+            result?;
+            let hits = {
+                let mapped = readback.slice(..).get_mapped_range();
+                let distances: &[f32] = bytemuck::cast_slice(&mapped);
+                origins
+                    .iter()
+                    .zip(distances)
+                    .map(|(&(origin, direction), &distance)| {
+                        if distance < 0.0 {
+                            return None;
+                        }
+                        let direction = Vector3::from(direction).cast::<f64>().unwrap().normalize();
+                        let position = Point3::from(origin) + direction * distance as f64;
+                        Some(RayHit { distance, position: position.into() })
+                    })
+                    .collect()
+            };
+            readback.unmap();
+            Ok(hits)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raycast_slot_table() {
+        let mut nodes = Vec::new();
+        for (i, root) in VNode::roots().iter().enumerate() {
+            nodes.push((*root, i));
+            for (j, child) in root.children().iter().enumerate() {
+                nodes.push((*child, 6 + i * 4 + j));
+            }
+        }
+        let table = slot_table(&nodes);
+        assert!(table.len().is_power_of_two() && table.len() >= nodes.len() * 2);
+
+        // Probe the same way the shader does.
+        let lookup = |node: VNode| {
+            let mask = table.len() - 1;
+            let key = (node.level() as u32 + 1) | ((node.face() as u32) << 8);
+            let mut i = node_hash(node) as usize & mask;
+            while table[i][0] != 0 {
+                if table[i][..3] == [key, node.x(), node.y()] {
+                    return Some(table[i][3] as usize);
+                }
+                i = (i + 1) & mask;
+            }
+            None
+        };
+        for &(node, slot) in &nodes {
+            assert_eq!(lookup(node), Some(slot));
+        }
+        assert_eq!(lookup(VNode::roots()[0].children()[0].children()[0]), None);
+    }
+}
@@ -0,0 +1,189 @@
+/// Farthest distance in meters that reflected rays are marched before falling back to the
+/// environment map.
+const MAX_DISTANCE: f32 = 20000.0;
+
+/// Images that water reflections are drawn from, all owned by the application.
+pub struct ReflectionSources<'a> {
+    /// Copy of the color buffer after the terrain and sky were drawn. Must be sampled rather than
+    /// written to, so it can't be the color buffer itself.
+    pub scene: &'a wgpu::TextureView,
+    /// Depth buffer of the same frame, created with `TextureUsage::SAMPLED`.
+    pub depth: &'a wgpu::TextureView,
+    /// Cube view of a map from `Terrain::render_environment_map`, used wherever a reflected ray
+    /// leaves the screen or passes behind everything drawn.
+    pub environment_map: &'a wgpu::TextureView,
+}
+
+fn buffer_binding(buffer: &wgpu::Buffer) -> wgpu::BindingResource {
+    wgpu::BindingResource::Buffer(wgpu::BufferBinding { buffer, offset: 0, size: None })
+}
+
+/// Screen space reflections of terrain and sky on water at sea level, blended over the color
+/// buffer as a full screen pass.
+pub(crate) struct WaterReflections {
+    max_steps: u32,
+    shader: rshader::ShaderSet,
+    pipeline: Option<(wgpu::BindGroupLayout, wgpu::RenderPipeline)>,
+    uniforms: wgpu::Buffer,
+    sampler: wgpu::Sampler,
+}
+impl WaterReflections {
+    pub fn new(device: &wgpu::Device, max_steps: u32) -> Self {
+        Self {
+            max_steps,
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "sky.vert", "declarations.glsl"),
+                rshader::shader_source!("shaders", "water-reflections.frag", "declarations.glsl"),
+            )
+            .unwrap(),
+            pipeline: None,
+            uniforms: device.create_buffer(&wgpu::BufferDescriptor {
+                size: 16,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                label: Some("buffer.reflections.uniforms"),
+                mapped_at_creation: false,
+            }),
+            sampler: device.create_sampler(&wgpu::SamplerDescriptor {
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                label: Some("sampler.reflections"),
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn max_steps(&self) -> u32 {
+        self.max_steps
+    }
+
+    pub fn set_max_steps(&mut self, max_steps: u32) {
+        self.max_steps = max_steps;
+    }
+
+    /// Blend reflections into `color_buffer`, using the camera of the frame most recently written
+    /// to `globals`.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        globals: &wgpu::Buffer,
+        sources: &ReflectionSources,
+        color_buffer: &wgpu::TextureView,
+    ) {
+        if self.shader.refresh() {
+            self.pipeline = None;
+        }
+
+        let uniforms = [self.max_steps.max(1) as f32, MAX_DISTANCE, 0.0, 0.0];
+        queue.write_buffer(&self.uniforms, 0, bytemuck::bytes_of(&uniforms));
+
+        // The images are owned by the application so the bind group is built directly rather
+        // than through `bind_group_for_shader`.
+        if self.pipeline.is_none() {
+            let mut entries = self.shader.layout_descriptor().entries.to_vec();
+            for (name, entry) in self.shader.desc_names().iter().zip(&mut entries) {
+                if let wgpu::BindingType::Texture { ref mut sample_type, .. } = entry.ty {
+                    if name.as_deref() == Some("depth") {
+                        *sample_type = wgpu::TextureSampleType::Depth;
+                    }
+                }
+            }
+            let bind_group_layout =
+                device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: &entries,
+                    label: Some("layout.reflections"),
+                });
+            let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.reflections.layout"),
+                })),
+                vertex: wgpu::VertexState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.reflections.vertex"),
+                        source: wgpu::ShaderSource::SpirV(self.shader.vertex().into()),
+                        flags: wgpu::ShaderFlags::VALIDATION,
+                    }),
+                    entry_point: "main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.reflections.fragment"),
+                        source: wgpu::ShaderSource::SpirV(self.shader.fragment().into()),
+                        flags: wgpu::ShaderFlags::VALIDATION,
+                    }),
+                    entry_point: "main",
+                    targets: &[wgpu::ColorTargetState {
+                        format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                        blend: Some(wgpu::BlendState {
+                            color: wgpu::BlendComponent {
+                                src_factor: wgpu::BlendFactor::SrcAlpha,
+                                dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                                operation: wgpu::BlendOperation::Add,
+                            },
+                            alpha: wgpu::BlendComponent {
+                                src_factor: wgpu::BlendFactor::Zero,
+                                dst_factor: wgpu::BlendFactor::One,
+                                operation: wgpu::BlendOperation::Add,
+                            },
+                        }),
+                        write_mask: wgpu::ColorWrite::ALL,
+                    }],
+                }),
+                primitive: Default::default(),
+                depth_stencil: None,
+                multisample: Default::default(),
+                label: Some("pipeline.reflections"),
+            });
+            self.pipeline = Some((bind_group_layout, pipeline));
+        }
+        let (bind_group_layout, pipeline) = self.pipeline.as_ref().unwrap();
+
+        let bindings: Vec<_> = self
+            .shader
+            .desc_names()
+            .iter()
+            .zip(self.shader.layout_descriptor().entries.iter())
+            .map(|(name, entry)| wgpu::BindGroupEntry {
+                binding: entry.binding,
+                resource: match name.as_deref().unwrap() {
+                    "globals" => buffer_binding(globals),
+                    "reflections" => buffer_binding(&self.uniforms),
+                    "linear" => wgpu::BindingResource::Sampler(&self.sampler),
+                    "scene" => wgpu::BindingResource::TextureView(sources.scene),
+                    "depth" => wgpu::BindingResource::TextureView(sources.depth),
+                    "environment_map" => {
+                        wgpu::BindingResource::TextureView(sources.environment_map)
+                    }
+                    name => unreachable!("unrecognized binding: {}", name),
+                },
+            })
+            .collect();
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: bind_group_layout,
+            entries: &*bindings,
+            label: Some("bindgroup.reflections"),
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.reflections"),
+        });
+        {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: color_buffer,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load: wgpu::LoadOp::Load, store: true },
+                }],
+                depth_stencil_attachment: None,
+                label: Some("renderpass.reflections"),
+            });
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(0, &bind_group, &[]);
+            rpass.draw(0..3, 0..1);
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::cache::Priority;
+use crate::coordinates;
+use crate::terrain::quadtree::node::VNode;
+use cgmath::Vector3;
+use fnv::FnvHashMap;
+
+/// Distance in meters that tiles in a boosted region are prioritized as if seen from, before
+/// scaling by the boost's weight.
+const BOOST_VIEWING_DISTANCE: f64 = 1000.0;
+
+/// Identifies a region registered with `Terrain::add_detail_region`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DetailRegionId(u64);
+
+struct DetailRegion {
+    /// Point at sea level in the middle of the region.
+    center: Vector3<f64>,
+    radius: f64,
+    max_level: u8,
+}
+
+/// Limits on how far the quadtree may be refined. Outside of any region nodes stop at
+/// `max_level`, while nodes touching a region may go as deep as the most detailed region allows.
+pub(crate) struct DetailRegions {
+    regions: FnvHashMap<DetailRegionId, DetailRegion>,
+    next_id: u64,
+    max_level: u8,
+    /// Elevations that terrain within a region may have.
+    elevation_range: (f32, f32),
+}
+impl Default for DetailRegions {
+    fn default() -> Self {
+        Self::new(VNode::DEFAULT_ELEVATION_RANGE)
+    }
+}
+impl DetailRegions {
+    pub fn new(elevation_range: (f32, f32)) -> Self {
+        Self {
+            regions: FnvHashMap::default(),
+            next_id: 0,
+            max_level: VNode::LEVEL_CELL_2CM,
+            elevation_range,
+        }
+    }
+
+    pub fn set_max_level(&mut self, level: u8) {
+        self.max_level = level.min(VNode::LEVEL_CELL_2CM);
+    }
+
+    pub fn add(
+        &mut self,
+        latitude: f64,
+        longitude: f64,
+        radius: f64,
+        max_level: u8,
+    ) -> DetailRegionId {
+        let id = DetailRegionId(self.next_id);
+        self.next_id += 1;
+        self.regions.insert(
+            id,
+            DetailRegion {
+                center: coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0)),
+                radius,
+                max_level: max_level.min(VNode::LEVEL_CELL_2CM),
+            },
+        );
+        id
+    }
+
+    pub fn remove(&mut self, id: DetailRegionId) {
+        self.regions.remove(&id);
+    }
+
+    /// The deepest level that `node` may be refined to, so it may only be drawn if its own level
+    /// is no greater.
+    pub fn max_level(&self, node: VNode) -> u8 {
+        self.regions
+            .values()
+            .filter(|r| r.max_level > self.max_level)
+            .filter(|r| node.distance2(r.center, self.elevation_range) <= r.radius * r.radius)
+            .map(|r| r.max_level)
+            .fold(self.max_level, u8::max)
+    }
+}
+
+/// Identifies a region registered with `Terrain::boost_priority`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PriorityBoostId(u64);
+
+struct PriorityBoost {
+    /// Point at sea level in the middle of the region.
+    center: Vector3<f64>,
+    radius: f64,
+    weight: f64,
+}
+
+/// Regions whose tiles are streamed as though the camera were close by, wherever it actually is.
+#[derive(Default)]
+pub(crate) struct PriorityBoosts {
+    boosts: FnvHashMap<PriorityBoostId, PriorityBoost>,
+    next_id: u64,
+}
+impl PriorityBoosts {
+    pub fn add(
+        &mut self,
+        latitude: f64,
+        longitude: f64,
+        radius: f64,
+        weight: f64,
+    ) -> PriorityBoostId {
+        let id = PriorityBoostId(self.next_id);
+        self.next_id += 1;
+        self.boosts.insert(
+            id,
+            PriorityBoost {
+                center: coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0)),
+                radius,
+                weight,
+            },
+        );
+        id
+    }
+
+    pub fn remove(&mut self, id: PriorityBoostId) {
+        self.boosts.remove(&id);
+    }
+
+    /// Highest priority that any region gives `node`. Within a region nodes are prioritized as if
+    /// the camera were `BOOST_VIEWING_DISTANCE / weight` meters away, and further away the
+    /// distance to the edge of the region is added.
+    pub fn priority(&self, node: VNode) -> Priority {
+        self.boosts
+            .values()
+            .map(|b| {
+                let d = node.distance2(b.center, VNode::DEFAULT_ELEVATION_RANGE).sqrt();
+                let d = (d - b.radius).max(0.0) + BOOST_VIEWING_DISTANCE;
+                let min_distance = node.min_distance() * b.weight;
+                Priority::from_f32((min_distance * min_distance / (d * d)) as f32)
+            })
+            .fold(Priority::none(), Priority::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region_max_level() {
+        let mut regions = DetailRegions::default();
+        regions.set_max_level(VNode::LEVEL_CELL_2M);
+        let id = regions.add(0.7, 0.2, 5000.0, VNode::LEVEL_CELL_7CM);
+
+        let node_at = |latitude: f64, longitude: f64| {
+            let p = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+            VNode::from_cspace(p / p.x.abs().max(p.y.abs()).max(p.z.abs()), 16).0
+        };
+        let inside = node_at(0.7, 0.2);
+        assert_eq!(regions.max_level(inside), VNode::LEVEL_CELL_7CM);
+        assert_eq!(regions.max_level(inside.parent().unwrap().0), VNode::LEVEL_CELL_7CM);
+        assert_eq!(regions.max_level(node_at(-0.7, 0.2)), VNode::LEVEL_CELL_2M);
+
+        regions.remove(id);
+        assert_eq!(regions.max_level(inside), VNode::LEVEL_CELL_2M);
+    }
+
+    #[test]
+    fn boosted_priority() {
+        let mut boosts = PriorityBoosts::default();
+        let node_at = |latitude: f64, longitude: f64, level: u8| {
+            let p = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+            VNode::from_cspace(p / p.x.abs().max(p.y.abs()).max(p.z.abs()), level).0
+        };
+        assert_eq!(boosts.priority(node_at(0.7, 0.2, 10)), Priority::none());
+
+        let id = boosts.add(0.7, 0.2, 5000.0, 1.0);
+        assert!(boosts.priority(node_at(0.7, 0.2, 10)) > Priority::cutoff());
+        assert!(boosts.priority(node_at(0.7, 0.2, 18)) < Priority::cutoff());
+        assert!(boosts.priority(node_at(-0.7, 0.2, 10)) < Priority::cutoff());
+
+        boosts.remove(id);
+        assert_eq!(boosts.priority(node_at(0.7, 0.2, 10)), Priority::none());
+    }
+}
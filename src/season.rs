@@ -0,0 +1,48 @@
+use crate::date::Date;
+use std::f64::consts::PI;
+
+/// Day of the year on which the northern hemisphere is warmest on average. This lags the
+/// solstice by several weeks.
+const NORTHERN_MIDSUMMER_DAY: f64 = 196.0;
+
+/// Seasonal parameters for the terrain shaders.
+///
+/// The shaders lower the snowline and fade deciduous vegetation in winter, with the strength of
+/// the effect depending on latitude. Everything here is for the northern hemisphere; the shaders
+/// flip the sign of the phase south of the equator.
+pub(crate) struct Season {
+    /// One at the height of northern summer and negative one in the depths of northern winter.
+    pub summer: f32,
+    /// Positive while the northern hemisphere is warming, negative while it is cooling.
+    pub spring: f32,
+}
+impl Season {
+    pub fn from_date(date: Date) -> Self {
+        let phase = 2.0 * PI * (date.day_of_year() - NORTHERN_MIDSUMMER_DAY) / 365.25;
+        Self { summer: phase.cos() as f32, spring: -phase.sin() as f32 }
+    }
+
+    /// Packed form passed to shaders. The third component is zero when seasons are disabled.
+    pub fn uniform(season: Option<&Self>) -> [f32; 4] {
+        match season {
+            Some(s) => [s.summer, s.spring, 1.0, 0.0],
+            None => [0.0; 4],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn january_is_winter() {
+        let january = Season::from_date(Date::new(2021, 1, 15.0));
+        let april = Season::from_date(Date::new(2021, 4, 15.0));
+        let july = Season::from_date(Date::new(2021, 7, 15.0));
+        let october = Season::from_date(Date::new(2021, 10, 15.0));
+
+        assert!(january.summer < -0.9 && july.summer > 0.9);
+        assert!(april.spring > 0.9 && october.spring < -0.9);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::date::Date;
+use crate::ephemeris::Ephemeris;
+use crate::gpu_state::GpuState;
+use crate::terrain::material::LandCoverWeights;
+use crate::terrain::quadtree::occlusion::IndirectDraws;
+use crate::terrain::quadtree::QuadTree;
+use maplit::hashmap;
+use std::collections::HashMap;
+
+/// Per-pixel outputs written by `Terrain::render_sensor`. Both textures must be the size of the
+/// frame. Pixels without a return are cleared to zero.
+pub struct SensorImage<'a> {
+    /// `R32Float` texture set to the distance in meters from the sensor.
+    pub range: &'a wgpu::TextureView,
+    /// `R32Float` texture set to the fraction of the emitted signal reflected back towards the
+    /// sensor, from the reflectivity of the land cover and the angle of incidence.
+    pub intensity: &'a wgpu::TextureView,
+}
+
+/// Number of hourly sun positions that thermal rendering uses to model how the ground heats up
+/// and cools down.
+const SUN_HISTORY: usize = 8;
+/// Time in hours over which the ground forgets past sunlight.
+const THERMAL_TIME_CONSTANT: f64 = 3.0;
+
+/// Layout must match `SensorBlock` in terrain-sensor.frag and terrain-thermal.frag.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SensorUniforms {
+    /// Reflectivity of plains, forest, desert and rock.
+    reflectivity: [f32; 4],
+    ice_reflectivity: f32,
+    max_range: f32,
+    padding: [f32; 2],
+    /// Direction of the sun at the current time and each of the preceding hours, with weights
+    /// summing to one.
+    sun_history: [[f32; 4]; SUN_HISTORY],
+}
+unsafe impl bytemuck::Zeroable for SensorUniforms {}
+unsafe impl bytemuck::Pod for SensorUniforms {}
+
+/// Direction of the sun over the hours leading up to `time`, weighted by how much each still
+/// contributes to the temperature of the ground. Without a time, the sun is fixed in the same
+/// default direction used for shading.
+fn sun_history(time: Option<Date>) -> [[f32; 4]; SUN_HISTORY] {
+    let total: f64 = (0..SUN_HISTORY).map(|h| (-(h as f64) / THERMAL_TIME_CONSTANT).exp()).sum();
+    let mut history = [[0.0; 4]; SUN_HISTORY];
+    for (h, entry) in history.iter_mut().enumerate() {
+        let weight = (-(h as f64) / THERMAL_TIME_CONSTANT).exp() / total;
+        let sun = match time {
+            Some(t) => Ephemeris::at(Date { day: t.day - h as f64 / 24.0, ..t })
+                .sun_direction
+                .cast::<f32>()
+                .unwrap(),
+            None => cgmath::Vector3::new(0.4, 0.7, 0.2),
+        };
+        *entry = [sun.x, sun.y, sun.z, weight as f32];
+    }
+    history
+}
+
+/// Which image `SensorRenderer` draws.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SensorKind {
+    /// Range and intensity written to a `SensorImage`.
+    Lidar,
+    /// Surface temperature in kelvin.
+    Thermal,
+}
+
+/// Draws the terrain into a `SensorImage` or thermal image instead of shading it.
+pub(crate) struct SensorRenderer {
+    /// Reflectivity of each class of land cover at the wavelength of the sensor.
+    reflectivity: LandCoverWeights,
+    /// Distance in meters beyond which there are no returns.
+    max_range: f32,
+    shader: rshader::ShaderSet,
+    bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    thermal_shader: rshader::ShaderSet,
+    thermal_bindgroup_pipeline: Option<(wgpu::BindGroup, wgpu::RenderPipeline)>,
+    uniforms: wgpu::Buffer,
+}
+impl SensorRenderer {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            // Typical values in the near infrared used by lidar.
+            reflectivity: LandCoverWeights {
+                plains: 0.5,
+                forest: 0.4,
+                desert: 0.45,
+                rock: 0.3,
+                ice: 0.8,
+            },
+            max_range: f32::INFINITY,
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
+                rshader::shader_source!("shaders", "terrain-sensor.frag", "declarations.glsl"),
+            )
+            .unwrap(),
+            bindgroup_pipeline: None,
+            thermal_shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "terrain.vert", "declarations.glsl"),
+                rshader::shader_source!("shaders", "terrain-thermal.frag", "declarations.glsl"),
+            )
+            .unwrap(),
+            thermal_bindgroup_pipeline: None,
+            uniforms: device.create_buffer(&wgpu::BufferDescriptor {
+                size: std::mem::size_of::<SensorUniforms>() as u64,
+                usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+                label: Some("buffer.sensor.uniforms"),
+                mapped_at_creation: false,
+            }),
+        }
+    }
+
+    pub fn set_reflectivity(&mut self, reflectivity: LandCoverWeights) {
+        self.reflectivity = reflectivity;
+    }
+
+    pub fn set_max_range(&mut self, max_range: f32) {
+        self.max_range = max_range;
+    }
+
+    /// Recreate all GPU resources on `device`, keeping the reflectivity and maximum range.
+    pub fn recreate(&mut self, device: &wgpu::Device) {
+        *self = Self {
+            reflectivity: self.reflectivity,
+            max_range: self.max_range,
+            ..Self::new(device)
+        };
+    }
+
+    /// Must be called after `gpu_state` is replaced.
+    pub fn reset(&mut self) {
+        self.bindgroup_pipeline = None;
+        self.thermal_bindgroup_pipeline = None;
+    }
+
+    /// Upload the uniforms and create the render pipeline for `kind` if needed. Must be called
+    /// before `render`.
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        gpu_state: &GpuState,
+        kind: SensorKind,
+        time: Option<Date>,
+    ) {
+        let r = self.reflectivity;
+        queue.write_buffer(
+            &self.uniforms,
+            0,
+            bytemuck::bytes_of(&SensorUniforms {
+                reflectivity: [r.plains, r.forest, r.desert, r.rock],
+                ice_reflectivity: r.ice,
+                max_range: self.max_range,
+                padding: [0.0; 2],
+                sun_history: sun_history(time),
+            }),
+        );
+
+        if self.shader.refresh() {
+            self.bindgroup_pipeline = None;
+        }
+        if self.thermal_shader.refresh() {
+            self.thermal_bindgroup_pipeline = None;
+        }
+        let (shader, bindgroup_pipeline, targets, name) = match kind {
+            SensorKind::Lidar => (&self.shader, &mut self.bindgroup_pipeline, 2, "terrain.sensor"),
+            SensorKind::Thermal => {
+                (&self.thermal_shader, &mut self.thermal_bindgroup_pipeline, 1, "terrain.thermal")
+            }
+        };
+        if bindgroup_pipeline.is_some() {
+            return;
+        }
+
+        let (bind_group, bind_group_layout) = gpu_state.bind_group_for_shader(
+            device,
+            shader,
+            hashmap!["sensor".into() => (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer: &self.uniforms,
+                offset: 0,
+                size: None,
+            }))],
+            HashMap::new(),
+            name,
+        );
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: &[&bind_group_layout],
+                push_constant_ranges: &[],
+                label: Some(&format!("pipeline.{}.layout", name)),
+            });
+        let target = wgpu::ColorTargetState {
+            format: wgpu::TextureFormat::R32Float,
+            blend: None,
+            write_mask: wgpu::ColorWrite::ALL,
+        };
+        *bindgroup_pipeline = Some((
+            bind_group,
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                layout: Some(&render_pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some(&format!("shader.{}.vertex", name)),
+                        source: wgpu::ShaderSource::SpirV(shader.vertex().into()),
+                        flags: wgpu::ShaderFlags::empty(),
+                    }),
+                    entry_point: "main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some(&format!("shader.{}.fragment", name)),
+                        source: wgpu::ShaderSource::SpirV(shader.fragment().into()),
+                        flags: wgpu::ShaderFlags::empty(),
+                    }),
+                    entry_point: "main",
+                    targets: &[target.clone(), target][..targets],
+                }),
+                primitive: QuadTree::primitive_state(),
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: wgpu::TextureFormat::Depth32Float,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::Greater,
+                    bias: Default::default(),
+                    stencil: Default::default(),
+                }),
+                multisample: Default::default(),
+                label: Some(&format!("pipeline.{}", name)),
+            }),
+        ));
+    }
+
+    pub fn render<'a>(
+        &'a self,
+        rpass: &mut wgpu::RenderPass<'a>,
+        kind: SensorKind,
+        quadtree: &QuadTree,
+        index_buffer: &'a wgpu::Buffer,
+        draws: Option<IndirectDraws<'a>>,
+    ) {
+        let (bind_group, pipeline) = match kind {
+            SensorKind::Lidar => self.bindgroup_pipeline.as_ref().unwrap(),
+            SensorKind::Thermal => self.thermal_bindgroup_pipeline.as_ref().unwrap(),
+        };
+        rpass.set_pipeline(pipeline);
+        quadtree.render(rpass, index_buffer, bind_group, draws);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sun_history_weights() {
+        let time = Date::new(2021, 6, 21.5);
+        let history = sun_history(Some(time));
+        let total: f32 = history.iter().map(|h| h[3]).sum();
+        assert!((total - 1.0).abs() < 1e-5);
+        assert!(history.windows(2).all(|w| w[0][3] > w[1][3]));
+
+        // The first entry is the sun right now, and the planet turns by 15 degrees an hour.
+        let now = Ephemeris::at(time).sun_direction;
+        assert!((history[0][0] as f64 - now.x).abs() < 1e-5);
+        let angle = |h: [f32; 4]| (h[1] as f64).atan2(h[0] as f64).to_degrees();
+        assert!((angle(history[1]) - angle(history[0]) - 15.0).abs() < 0.5);
+    }
+}
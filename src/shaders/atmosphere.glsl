@@ -28,6 +28,21 @@ vec2 rsi(vec3 r0, vec3 rd, float sr) {
     );
 }
 
+// Transmittance along the segment from `x` to `y`, found by integrating the density of the
+// atmosphere along it. Used in place of the transmittance table until it has been generated.
+vec3 analytic_transmittance(vec3 x, vec3 y) {
+	const int steps = 8;
+	float step_size = distance(x, y) / steps;
+	float rayleigh = 0.0;
+	float mie = 0.0;
+	for (int i = 0; i < steps; i++) {
+		float height = max(length(mix(x, y, (i + 0.5) / steps)) - planetRadius, 0.0);
+		rayleigh += exp(-height / 8000.0) * step_size;
+		mie += exp(-height / 1200.0) * step_size;
+	}
+	return exp(-rayleigh_Bs * rayleigh - 2.0e-6 * 1.1 * mie);
+}
+
 vec3 atmosphere(vec3 r0, vec3 r1, vec3 pSun) {
 	float iSun = 100000.0;
 	vec3 kRlh = vec3(5.8e-6, 13.5e-6, 33.1e-6);
@@ -116,6 +131,17 @@ vec3 atmosphere(vec3 r0, vec3 r1, vec3 pSun) {
 // 
 vec3 precomputed_transmittance(float r, float mu) {
 	vec2 size = textureSize(transmittance, 0);
+	if (size.x <= 1) {
+		vec3 x = vec3(0, 0, r);
+		vec3 d = vec3(sqrt(max(1.0 - mu * mu, 0.0)), 0, mu);
+		vec2 ground = rsi(x, d, planetRadius);
+		if (ground.x < ground.y && ground.x > 0.0)
+			return vec3(0);
+		return analytic_transmittance(x, x + d * max(rsi(x, d, atmosphereRadius).y, 0.0));
+	}
+
+	// The table starts at the surface, so points below it use the values at the surface.
+	r = max(r, planetRadius);
 
 	float H = sqrt(atmosphereRadius * atmosphereRadius - planetRadius * planetRadius);
 	float rho = sqrt(r * r - planetRadius * planetRadius);
@@ -137,8 +163,11 @@ vec3 precomputed_transmittance(float r, float mu) {
 }
 
 vec3 precomputed_transmittance2(vec3 x, vec3 y) {
-	float r1 = length(x);
-	float r2 = length(y);
+	if (textureSize(transmittance, 0).x <= 1)
+		return analytic_transmittance(x, y);
+
+	float r1 = max(length(x), planetRadius);
+	float r2 = max(length(y), planetRadius);
 	float mu1 = dot(normalize(x), normalize(x - y));
 	float mu2 = dot(normalize(y), normalize(x - y));
 
@@ -0,0 +1,48 @@
+// Requires hash.glsl.
+
+const float cloudAltitude = 2000.0;
+const float cloudScale = 4000.0;
+
+float cloud_value_noise(vec3 p) {
+	vec3 i = floor(p);
+	vec3 f = smoothstep(0, 1, fract(p));
+	uvec3 c = uvec3(ivec3(i));
+	return mix(mix(mix(random(c), random(c + uvec3(1, 0, 0)), f.x),
+				   mix(random(c + uvec3(0, 1, 0)), random(c + uvec3(1, 1, 0)), f.x), f.y),
+			   mix(mix(random(c + uvec3(0, 0, 1)), random(c + uvec3(1, 0, 1)), f.x),
+				   mix(random(c + uvec3(0, 1, 1)), random(c + uvec3(1, 1, 1)), f.x), f.y), f.z);
+}
+
+// Fraction of sunlight reaching `world_position` through the cloud layer, given the cloud
+// uniforms from `Weather::clouds_uniform`. Clouds are a thin layer of fractal noise carried by
+// the wind, and are sampled where the ray towards the sun crosses that layer.
+float cloud_shadow(vec3 world_position, vec3 sun_direction, vec4 clouds) {
+	float coverage = clouds.x;
+	if (coverage <= 0)
+		return 1.0;
+
+	vec3 up = normalize(world_position);
+	float height = cloudAltitude - (length(world_position) - 6371000.0);
+	if (height <= 0)
+		return 1.0;
+	vec3 p = world_position + sun_direction * height / max(dot(up, sun_direction), 0.05);
+
+	// The wind offset is in meters east and north, which is close enough to a fixed direction
+	// over the extent of the layer that can cast shadows into view.
+	vec3 east = normalize(cross(vec3(0, 0, 1), up) + vec3(1e-6, 0, 0));
+	vec3 north = cross(up, east);
+	p -= east * clouds.y + north * clouds.z;
+
+	float density = 0;
+	float amplitude = 0.5;
+	p /= cloudScale;
+	for (int i = 0; i < 4; i++) {
+		density += amplitude * cloud_value_noise(p);
+		amplitude *= 0.5;
+		p *= 2.03;
+	}
+	density /= 0.9375;
+
+	float cover = smoothstep(1 - coverage - 0.1, 1 - coverage + 0.1, density);
+	return 1 - 0.8 * cover;
+}
@@ -6,6 +6,21 @@ struct Globals {
 	mat4 view_proj_inverse;
 	vec3 camera;
 	vec3 sun_direction;
+	mat4 prev_view_proj;
+	vec2 jitter;
+	float ev100;
+	float debug_overlay;
+	mat4 shadow_view_proj;
+	vec4 shadow_params;
+	vec4 season;
+	vec4 moon;
+	vec4 weather;
+	vec4 ground_camera;
+	vec4 map_projection;
+	vec4 planet;
+	vec4 clouds;
+	vec4 distance_field[3];
+	vec4 skylines;
 };
 
 struct LayerDesc {
@@ -29,5 +44,113 @@ struct NodeState {
 	float min_distance;
 	vec3 parent_relative_position;
 	float padding1;
-	vec4 padding2[4];
+	vec3 bounds_min;
+	float padding2;
+	vec3 bounds_max;
+	float padding3;
+	vec2 ground_origin;
+	float ground_step;
+	uint provenance;
+	float provenance_age;
+	float padding5;
+	vec2 padding4;
 };
+
+// Point on the unit cube for a position in [-1, 1] on `face`, matching `VNode::fspace_to_cspace`.
+vec3 fspace_to_cspace(vec2 p, uint face) {
+	p = sign(p) * (1.4511 - sqrt(1.4511 * 1.4511 - 1.8044 * abs(p))) / 0.9022;
+	switch (face) {
+		case 0u: return vec3(1, p.x, -p.y);
+		case 1u: return vec3(-1, -p.x, -p.y);
+		case 2u: return vec3(p.x, 1, p.y);
+		case 3u: return vec3(-p.x, -1, p.y);
+		case 4u: return vec3(p.x, -p.y, 1);
+		default: return vec3(-p.x, -p.y, -1);
+	}
+}
+
+// Inverse of `fspace_to_cspace` for any direction `d`. Returns the position on the face in xy and
+// the face in z.
+vec3 direction_to_fspace(vec3 d) {
+	vec3 a = abs(d);
+	vec3 f;
+	if (a.x >= a.y && a.x >= a.z) {
+		d /= a.x;
+		f = d.x > 0 ? vec3(d.y, -d.z, 0) : vec3(-d.y, -d.z, 1);
+	} else if (a.y >= a.z) {
+		d /= a.y;
+		f = d.y > 0 ? vec3(d.x, d.z, 2) : vec3(-d.x, d.z, 3);
+	} else {
+		d /= a.z;
+		f = d.z > 0 ? vec3(d.x, -d.y, 4) : vec3(-d.x, -d.y, 5);
+	}
+	f.xy *= 1.4511 + (1.0 - 1.4511) * abs(f.xy);
+	return f;
+}
+
+struct LandCover {
+	float plains;
+	float forest;
+	float desert;
+	float rock;
+	float ice;
+};
+
+// Estimate the land cover from linear albedo. The weights sum to one.
+LandCover estimate_land_cover(vec3 albedo) {
+	float luminance = dot(albedo, vec3(0.2126, 0.7152, 0.0722));
+	float saturation = (max(albedo.r, max(albedo.g, albedo.b)) - min(albedo.r, min(albedo.g, albedo.b)))
+		/ max(max(albedo.r, max(albedo.g, albedo.b)), 1e-4);
+
+	LandCover c;
+	c.ice = smoothstep(0.35, 0.6, luminance) * (1 - smoothstep(0.1, 0.25, saturation));
+	float vegetation = (1 - c.ice) * smoothstep(0.0, 0.15, (albedo.g - max(albedo.r, albedo.b)) / max(luminance, 1e-4));
+	c.forest = vegetation * (1 - smoothstep(0.03, 0.08, luminance));
+	c.plains = vegetation - c.forest;
+	c.desert = (1 - c.ice - vegetation) * smoothstep(0.1, 0.3, (albedo.r - albedo.b) / max(albedo.r, 1e-4))
+		* smoothstep(0.08, 0.2, luminance);
+	c.rock = max(1 - c.ice - vegetation - c.desert, 0);
+	return c;
+}
+
+// Must match `Biome` in biome.rs.
+#define BIOME_OCEAN 0u
+#define BIOME_ICE_SHEET 1u
+#define BIOME_TUNDRA 2u
+#define BIOME_BOREAL_FOREST 3u
+#define BIOME_TEMPERATE_FOREST 4u
+#define BIOME_GRASSLAND 5u
+#define BIOME_SHRUBLAND 6u
+#define BIOME_DESERT 7u
+#define BIOME_SAVANNA 8u
+#define BIOME_TROPICAL_FOREST 9u
+#define BIOME_ALPINE 10u
+
+// Classify a point from its land cover, elevation in meters and the sine of its latitude. Mean
+// temperature falls off towards the poles and with altitude at the standard lapse rate, while the
+// amount of vegetation stands in for precipitation.
+uint classify_biome(LandCover c, float elevation, float latitude_sine, float sea_level) {
+	if (elevation <= sea_level)
+		return BIOME_OCEAN;
+	if (c.ice > 0.5)
+		return BIOME_ICE_SHEET;
+
+	float temperature = 27 - 35 * pow(abs(latitude_sine), 1.5) - 0.0065 * max(elevation, 0);
+	float moisture = clamp(c.forest + 0.6 * c.plains, 0, 1);
+	bool forested = c.forest > c.plains;
+
+	if (c.rock > 0.5 && temperature < 5)
+		return BIOME_ALPINE;
+	if (temperature < -2)
+		return BIOME_TUNDRA;
+	if (moisture < 0.2)
+		return BIOME_DESERT;
+	if (temperature < 6)
+		return forested ? BIOME_BOREAL_FOREST : BIOME_TUNDRA;
+	if (temperature < 20) {
+		if (forested)
+			return BIOME_TEMPERATE_FOREST;
+		return moisture < 0.45 ? BIOME_SHRUBLAND : BIOME_GRASSLAND;
+	}
+	return forested ? BIOME_TROPICAL_FOREST : BIOME_SAVANNA;
+}
@@ -0,0 +1,54 @@
+// Procedural erosion for synthesized heightmap detail. Rather than running a simulation (which
+// would need neighboring tiles to agree on far more than the shared border), each sample is
+// offset based only on its own position, the local gradient, and the curvature of the parent
+// heightmap. That makes the result identical on both sides of every tile border within a face.
+// Because every level interpolates from an already eroded parent, gullies accumulate across
+// scales as more detailed tiles are generated.
+
+// Width of gullies, in samples.
+const float EROSION_CELL_SIZE = 6.0;
+// Depth of gullies on steep slopes, relative to the sample spacing.
+const float GULLY_DEPTH = 0.6;
+// Fraction of the local curvature filled in by deposited sediment.
+const float DEPOSITION_RATE = 0.15;
+
+vec2 erosion_jitter(ivec2 cell, uint face) {
+	uvec2 c = uvec2(cell);
+	return vec2(random(uvec3(c, face * 2)), random(uvec3(c, face * 2 + 1))) - 0.5;
+}
+
+// Returns a value in [-1, 1] forming stripes that run down the slope given by `gradient`. The
+// stripes are broken up by blending between randomly placed points so they do not look regular.
+float gullies(vec2 p, vec2 gradient, uint face) {
+	vec2 dir = normalize(vec2(gradient.y, -gradient.x));
+	ivec2 ip = ivec2(floor(p));
+	vec2 fp = p - vec2(ip);
+
+	float value = 0;
+	float weight_sum = 0;
+	for (int i = -1; i <= 2; i++) {
+		for (int j = -1; j <= 2; j++) {
+			vec2 d = fp - vec2(i, j) - 0.5 - erosion_jitter(ip + ivec2(i, j), face);
+			float w = exp(-2.0 * dot(d, d));
+			value += cos(2.0 * 3.141592 * dot(d, dir)) * w;
+			weight_sum += w;
+		}
+	}
+	return value / weight_sum;
+}
+
+// Height offset in meters to apply to a sample at `position` on the given face. `gradient` is
+// the slope of the terrain, and `laplacian` is the curvature of the parent heightmap (positive
+// in valleys). `edge_distance` is the number of samples to the nearest face edge, where erosion
+// fades out since positions on adjacent faces are not continuous.
+float erosion(ivec2 position, vec2 gradient, float laplacian, float spacing, uint face, int edge_distance) {
+	float fade = clamp(float(edge_distance) / (2.0 * EROSION_CELL_SIZE), 0.0, 1.0);
+	float slope = length(gradient);
+
+	float offset = DEPOSITION_RATE * max(laplacian, 0.0);
+	if (slope > 0.0) {
+		float carve = smoothstep(0.15, 0.6, slope) * GULLY_DEPTH * spacing;
+		offset += carve * gullies(vec2(position) / EROSION_CELL_SIZE, gradient, face);
+	}
+	return offset * fade;
+}
@@ -52,7 +52,7 @@ void main() {
 						normalize(vec3(0.4, .7, 0.2)),
 						vec3(100000.0)) * .8;
 
-   	float ev100 = 15.0;
+   	float ev100 = globals.ev100;
 	float exposure = 1.0 / (pow(2.0, ev100) * 1.2);
 	out_color = tonemap(out_color, exposure, 2.2);
 }
\ No newline at end of file
@@ -67,4 +67,5 @@ void main() {
     texcoord = vec2(entry.position_u.w, entry.albedo_v.w);
 
     gl_Position = globals.view_proj * vec4(position, 1.0);
+    gl_Position.xy += globals.jitter * gl_Position.w;
 }
\ No newline at end of file
@@ -0,0 +1,68 @@
+#version 450 core
+#include "declarations.glsl"
+#include "pbr.glsl"
+
+layout(set = 0, binding = 0) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1) uniform sampler linear;
+layout(set = 0, binding = 2) uniform sampler nearest;
+layout(set = 0, binding = 3) uniform texture2D transmittance;
+layout(set = 0, binding = 4) uniform texture2DArray imposter_albedo;
+layout(set = 0, binding = 5) uniform texture2DArray imposter_normals;
+
+layout(location = 0) in vec4 position;
+
+layout(location = 0) out vec4 OutColor;
+
+const float planetRadius = 6371000.0;
+#define atmosphereRadius (planetRadius + globals.planet.y)
+
+vec2 rsi(vec3 r0, vec3 rd, float sr);
+vec3 precomputed_transmittance2(vec3 x, vec3 y);
+vec3 atmosphere(vec3 r0, vec3 r1, vec3 pSun);
+
+// Draw the whole planet as a sphere textured with the imposter generated from the root tiles.
+// Pixels that miss the planet are discarded so the sky can be drawn behind it.
+void main() {
+	vec4 r0 = globals.view_proj_inverse * vec4(position.xy, 1, 1);
+	vec4 r1 = globals.view_proj_inverse * vec4(position.xy, 1e-9, 1);
+	vec3 r = normalize(r1.xyz / r1.w - r0.xyz / r0.w);
+
+	vec3 x0 = r0.xyz / r0.w + globals.camera;
+	vec2 ground = rsi(x0, r, planetRadius);
+	if (ground.x > ground.y || ground.x < 0.0)
+		discard;
+
+	vec3 hit = x0 + r * ground.x;
+	vec3 f = direction_to_fspace(normalize(hit));
+	vec3 texcoord = vec3(f.xy * 0.5 + 0.5, f.z);
+	vec4 albedo_roughness = texture(sampler2DArray(imposter_albedo, linear), texcoord);
+	vec3 normal = texture(sampler2DArray(imposter_normals, linear), texcoord).xyz * 2 - 1;
+
+	vec3 sun_direction = normalize(globals.sun_direction);
+	float daylight = smoothstep(-0.02, 0.02, dot(normalize(hit), sun_direction));
+
+	OutColor = vec4(1);
+	OutColor.rgb = pbr(albedo_roughness.rgb,
+					   albedo_roughness.a,
+					   hit,
+					   normal,
+					   x0,
+					   sun_direction,
+					   vec3(100000.0) * daylight);
+
+	vec2 p = rsi(x0, r, atmosphereRadius);
+	vec3 entry = x0 + r * max(p.x, 0.0);
+	OutColor.rgb = atmosphere(entry, hit, globals.sun_direction)
+		+ OutColor.rgb * precomputed_transmittance2(entry, hit);
+
+	float ev100 = globals.ev100;
+	float exposure = 1.0 / (pow(2.0, ev100) * 1.2);
+	OutColor = tonemap(OutColor, exposure, 2.2);
+
+	vec4 clip = globals.view_proj * vec4(hit - globals.camera, 1);
+	gl_FragDepth = clip.z / clip.w;
+}
+
+#include "atmosphere.glsl"
@@ -0,0 +1,56 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1) uniform texture2D depth;
+
+layout(location = 0) in vec4 position;
+
+layout(location = 0) out vec4 out_color;
+
+const int numSamples = 48;
+
+float sky_visible(vec2 uv) {
+	ivec2 size = textureSize(depth, 0);
+	return texelFetch(depth, clamp(ivec2(uv * size), ivec2(0), size - ivec2(1)), 0).r == 0 ? 1 : 0;
+}
+
+void main() {
+	float strength = globals.clouds.w;
+	vec3 sun_direction = normalize(globals.sun_direction);
+	vec4 sun_clip = globals.view_proj * vec4(sun_direction, 0);
+	if (strength <= 0 || sun_clip.w <= 0)
+		discard;
+
+	// Rays only show while the sun is above the horizon as seen from the camera.
+	vec3 up = normalize(globals.camera);
+	float daylight = smoothstep(-0.02, 0.05, dot(up, sun_direction));
+
+	vec2 uv = gl_FragCoord.xy / vec2(textureSize(depth, 0));
+	vec2 sun_uv = sun_clip.xy / sun_clip.w * vec2(0.5, -0.5) + vec2(0.5);
+
+	// Step from the pixel towards the sun, collecting the sky that is visible along the way.
+	// Gaps between terrain that let the sky through leave bright streaks radiating from the sun.
+	vec2 delta = (sun_uv - uv) / float(numSamples);
+	float illumination = 0;
+	float decay = 1;
+	vec2 s = uv;
+	for (int i = 0; i < numSamples; i++) {
+		s += delta;
+		if (any(lessThan(s, vec2(0))) || any(greaterThan(s, vec2(1))))
+			break;
+		illumination += sky_visible(s) * decay;
+		decay *= 0.96;
+	}
+	illumination /= float(numSamples);
+
+	// Fade out as the sun leaves the frame, and for pixels facing away from it.
+	vec4 p = globals.view_proj_inverse * vec4(uv * vec2(2, -2) + vec2(-1, 1), 1, 1);
+	float facing = pow(max(dot(normalize(p.xyz / p.w), sun_direction), 0), 4);
+	vec2 outside = max(max(-sun_uv, sun_uv - vec2(1)), vec2(0));
+	float on_screen = 1 - smoothstep(0, 0.5, max(outside.x, outside.y));
+
+	out_color = vec4(vec3(1.0, 0.9, 0.75) * strength * illumination * facing * on_screen * daylight, 1);
+}
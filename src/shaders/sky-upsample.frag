@@ -0,0 +1,13 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0) uniform sampler linear;
+layout(set = 0, binding = 1) uniform texture2D low_resolution_sky;
+
+layout(location = 0) in vec4 position;
+
+layout(location = 0) out vec4 OutColor;
+
+void main() {
+	OutColor = texture(sampler2D(low_resolution_sky, linear), position.xy * vec2(0.5, -0.5) + 0.5);
+}
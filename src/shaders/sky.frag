@@ -9,19 +9,156 @@ layout(set = 0, binding = 1) uniform sampler linear;
 layout(set = 0, binding = 2) uniform sampler nearest;
 layout(set = 0, binding = 3) uniform texture2D sky;
 layout(set = 0, binding = 4) uniform texture2D transmittance;
+layout(set = 0, binding = 5) uniform texture3D skylines;
 
 layout(location = 0) in vec4 position;
 
 layout(location = 0) out vec4 OutColor;
 
 const float planetRadius = 6371000.0;
-const float atmosphereRadius = 6371000.0 + 100000.0;
+#define atmosphereRadius (planetRadius + globals.planet.y)
 
 vec2 rsi(vec3 r0, vec3 rd, float sr);
 vec3 precomputed_transmittance(float r, float mu);
+vec3 precomputed_transmittance2(vec3 x, vec3 y);
 vec3 precomputed_atmosphere(vec3 x, vec3 x0, vec3 sun_normalized);
 vec3 atmosphere(vec3 r0, vec3 r1, vec3 pSun);
 
+// Radiance of the moon's disk in direction `r`. The disk is shaded as a sphere lit by the sun, so
+// the phase of the moon follows from the directions of the two bodies.
+vec3 moon(vec3 r) {
+	float angular_radius = globals.moon.w;
+	float cos_angle = dot(r, globals.moon.xyz);
+	if (angular_radius == 0 || cos_angle < cos(angular_radius))
+		return vec3(0);
+
+	// Point on the unit sphere facing the viewer that is seen along `r`.
+	vec3 offset = (r - globals.moon.xyz * cos_angle) / sin(angular_radius);
+	vec3 normal = offset - globals.moon.xyz * sqrt(max(1.0 - dot(offset, offset), 0.0));
+
+	// Lambertian surface with the moon's average albedo of 0.12, lit by the sun.
+	float irradiance = max(dot(normalize(normal), normalize(globals.sun_direction)), 0.0);
+	return vec3(100000.0 * 0.12 / 3.141592 * irradiance);
+}
+
+// Geomagnetic north pole, at roughly 80.7°N 72.7°W.
+const vec3 magneticNorth = vec3(0.0481, -0.1543, 0.9869);
+
+// Emission from auroras along the ray from `x0` in direction `r`, up to a distance of `max_t`.
+// Auroras are curtains of light between 100 and 300 km altitude, centered on a band of magnetic
+// latitude that moves towards the equator as their strength increases.
+vec3 aurora(vec3 x0, vec3 r, float max_t) {
+	float strength = globals.weather.x;
+	float seconds = globals.weather.z;
+
+	vec2 shell = rsi(x0, r, planetRadius + 300000.0);
+	float t0 = max(shell.x, 0.0);
+	float t1 = min(shell.y, max_t);
+	if (t0 >= t1)
+		return vec3(0);
+
+	vec3 east = normalize(cross(magneticNorth, vec3(0, 0, 1)));
+	vec3 north = cross(east, magneticNorth);
+	float oval_latitude = radians(67.0 - 15.0 * strength);
+	float oval_width = radians(2.0 + 4.0 * strength);
+
+	const int steps = 48;
+	float dt = (t1 - t0) / steps;
+	vec3 emission = vec3(0);
+	for (int i = 0; i < steps; i++) {
+		vec3 p = x0 + r * (t0 + (i + 0.5) * dt);
+		float altitude = length(p) - planetRadius;
+		if (altitude < 100000.0)
+			continue;
+
+		vec3 up = normalize(p);
+		float magnetic_latitude = asin(abs(dot(up, magneticNorth)));
+		float magnetic_longitude = atan(dot(up, north), dot(up, east));
+
+		// Slowly drifting folds in the curtain, with fine rays along it.
+		float fold = sin(magnetic_longitude * 7.0 + seconds * 0.03)
+			+ 0.5 * sin(magnetic_longitude * 17.0 - seconds * 0.05)
+			+ 0.25 * sin(magnetic_longitude * 41.0 + seconds * 0.11);
+		float offset = (magnetic_latitude - oval_latitude - fold * oval_width) / oval_width;
+		float curtain = exp(-offset * offset)
+			* (0.6 + 0.4 * sin(magnetic_longitude * 900.0 + fold * 20.0));
+
+		// Oxygen emits green light low in the curtain and red light near its top.
+		vec3 green = vec3(0.1, 1.0, 0.35) * exp(-max(altitude - 110000.0, 0.0) / 40000.0);
+		vec3 red = vec3(0.9, 0.1, 0.25) * smoothstep(180000.0, 260000.0, altitude) * 0.4;
+		emission += (green + red) * curtain * dt;
+	}
+	return emission * 0.03 * strength;
+}
+
+// Airglow from the thin layer of the upper atmosphere between 85 and 95 km altitude. Looking
+// through the layer at a grazing angle produces a faint band above the horizon.
+vec3 airglow(vec3 x0, vec3 r, float max_t) {
+	vec2 outer = rsi(x0, r, planetRadius + 95000.0);
+	vec2 inner = rsi(x0, r, planetRadius + 85000.0);
+	float path = max(min(outer.y, max_t) - max(outer.x, 0.0), 0.0);
+	if (inner.x < inner.y)
+		path -= max(min(inner.y, max_t) - max(inner.x, 0.0), 0.0);
+	return vec3(0.35, 1.0, 0.45) * 4e-4 * max(path, 0.0);
+}
+
+// Distant mountains seen from `x0` in direction `r`, from the skylines precomputed for a grid of
+// viewpoints on the ground. Returns the tangent of the angle that the mountains rise above the
+// horizon and their distance, or a distance of zero if there are none or `x0` is outside the
+// region covered by skylines.
+vec2 distant_terrain(vec3 x0, vec3 r) {
+	if (globals.skylines.z == 0 || length(x0) - planetRadius > 20000.0)
+		return vec2(0);
+
+	vec3 up = normalize(x0);
+	float latitude = asin(up.z);
+	float longitude = atan(up.y, up.x);
+	vec2 grid = vec2(mod(longitude - globals.skylines.y, 2 * M_PI), latitude - globals.skylines.x)
+		/ globals.skylines.wz;
+	if (any(lessThan(grid, vec2(0))) || any(greaterThan(grid, vec2(1))))
+		return vec2(0);
+
+	vec3 east = normalize(cross(vec3(0, 0, 1), up));
+	vec3 north = cross(up, east);
+	float azimuth = atan(dot(r, east), dot(r, north));
+
+	ivec3 size = textureSize(skylines, 0);
+	vec3 texel = vec3(mod(azimuth / (2 * M_PI), 1) * size.x, grid * vec2(size.yz - 1));
+	ivec3 i0 = ivec3(floor(texel));
+	vec3 f = texel - vec3(i0);
+
+	// Interpolate the angle of the mountains rather than their elevation and distance, leaving
+	// out directions where the skyline has nothing.
+	float altitude = length(x0) - planetRadius;
+	vec3 sum = vec3(0);
+	for (int i = 0; i < 8; i++) {
+		ivec3 o = ivec3(i & 1, (i >> 1) & 1, (i >> 2) & 1);
+		ivec3 t = ivec3((i0.x + o.x) % size.x, min(i0.yz + o.yz, size.yz - 1));
+		vec2 peak = texelFetch(skylines, t, 0).xy;
+		if (peak.y == 0)
+			continue;
+		vec3 w3 = mix(vec3(1) - f, f, vec3(o));
+		float w = w3.x * w3.y * w3.z;
+		float rise = (peak.x - altitude) / peak.y - 0.5 * peak.y / planetRadius;
+		sum += w * vec3(rise, peak.y, 1);
+	}
+	if (sum.z < 0.5)
+		return vec2(0);
+	return sum.xy / sum.z;
+}
+
+// Radiance of distant mountains at `x`, seen along `r`. They are too far away for any detail, so
+// are shaded as slopes of uniform albedo facing the viewer.
+vec3 distant_terrain_radiance(vec3 x, vec3 r) {
+	vec3 up = normalize(x);
+	vec3 normal = normalize(up - 0.5 * r);
+	vec3 sun = normalize(globals.sun_direction);
+	vec3 sun_light = 100000.0 * precomputed_transmittance(length(x), dot(up, sun))
+		* max(dot(normal, sun), 0.0);
+	float sky_light = 15000.0 * smoothstep(-0.1, 0.1, dot(up, sun));
+	return 0.1 / M_PI * (sun_light + sky_light);
+}
+
 void main() {
 	vec4 r0 = globals.view_proj_inverse * vec4(position.xy, 1, 1);
 	vec4 r1 = globals.view_proj_inverse * vec4(position.xy, 1e-9, 1);
@@ -30,19 +167,40 @@ void main() {
 	float lat = acos(r.z)/3.141592 * 0.5 + 0.5;
 	float lon = atan(r.y, r.x) / 3.141592 * 0.5 + 0.5;
 	OutColor.rgb = pow(texture(sampler2D(sky, linear), vec2(lon, lat)).rgb, vec3(5)) * 10000;
+	OutColor.rgb += moon(r);
 
 	vec3 x0 = r0.xyz / r0.w + globals.camera;
 	vec2 p = rsi(x0, r, atmosphereRadius);
 
-	if (p.x < p.y && p.y > 0.0) {
+	// Mountains beyond the streamed terrain hide everything behind them.
+	vec2 mountains = distant_terrain(x0, r);
+	float mu = dot(r, normalize(x0));
+	bool hidden = mountains.y > 0 && mu / sqrt(max(1 - mu * mu, 1e-6)) < mountains.x;
+
+	// Auroras and airglow are only visible when the camera is on the night side of the planet.
+	if (!hidden && (globals.weather.x > 0 || globals.weather.y > 0)) {
+		vec2 ground = rsi(x0, r, planetRadius);
+		float max_t = ground.x < ground.y && ground.x > 0.0 ? ground.x : 1e9;
+		float night = smoothstep(0.1, -0.1, dot(normalize(x0), normalize(globals.sun_direction)));
+		if (globals.weather.x > 0)
+			OutColor.rgb += aurora(x0, r, max_t) * night;
+		if (globals.weather.y > 0)
+			OutColor.rgb += airglow(x0, r, max_t) * night;
+	}
+
+	if (hidden) {
+		vec3 x1 = x0 + r * mountains.y;
+		OutColor.rgb = atmosphere(x0, x1, globals.sun_direction)
+			+ distant_terrain_radiance(x1, r) * precomputed_transmittance2(x0, x1);
+	} else if (p.x < p.y && p.y > 0.0) {
 		vec3 x1 = x0 + r * p.y;
 		x0 = x0 + r * max(p.x, 0.0);
 
-		OutColor.rgb = atmosphere(x0, x1, vec3(0.4, 0.7, 0.2))
+		OutColor.rgb = atmosphere(x0, x1, globals.sun_direction)
 			+ OutColor.rgb * precomputed_transmittance(length(x0), dot(normalize(x0), r));
 	}
 
-	float ev100 = 15.0;
+	float ev100 = globals.ev100;
 	float exposure = 1.0 / (pow(2.0, ev100) * 1.2);
 	OutColor = tonemap(OutColor, exposure, 2.2);
 	// if (dot(x0 + r * max(p.x, 0.0), vec3(0.4, 0.7, 0.2)) < 0)
@@ -0,0 +1,24 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+    Globals globals;
+};
+
+layout(location = 0) in vec3 position;
+layout(location = 8) in vec4 clip_position;
+
+layout(location = 0) out float out_linear_depth;
+layout(location = 1) out vec4 out_geo_position;
+
+void main() {
+	// For a perspective projection, w is the distance along the view direction.
+	out_linear_depth = clip_position.w;
+
+	vec3 world_position = position + globals.camera;
+	float r = length(world_position);
+	out_geo_position = vec4(asin(world_position.z / r),
+							atan(world_position.y, world_position.x),
+							r - 6371000.0,
+							1.0);
+}
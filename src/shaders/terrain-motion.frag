@@ -0,0 +1,14 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(location = 8) in vec4 clip_position;
+layout(location = 9) in vec4 prev_clip_position;
+
+layout(location = 0) out vec2 out_motion;
+
+void main() {
+	// Motion in texture coordinates from the previous frame to this one, ignoring jitter.
+	vec2 current = clip_position.xy / clip_position.w;
+	vec2 previous = prev_clip_position.xy / prev_clip_position.w;
+	out_motion = (current - previous) * vec2(0.5, -0.5);
+}
@@ -0,0 +1,74 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(early_fragment_tests) in;
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+    Globals globals;
+};
+layout(set = 0, binding = 1, std140) readonly buffer NodeBlock {
+	NodeState nodes[];
+};
+layout(set = 0, binding = 4) uniform texture2DArray normals;
+layout(set = 0, binding = 5) uniform texture2DArray albedo;
+layout(set = 0, binding = 11) uniform sampler filtered;
+layout(set = 0, binding = 20, std140) uniform SensorBlock {
+	vec4 reflectivity;
+	float ice_reflectivity;
+	float max_range;
+	vec2 padding;
+} sensor;
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 texcoord;
+layout(location = 2) in float morph;
+layout(location = 3) in vec3 normal;
+layout(location = 4) in vec3 tangent;
+layout(location = 5) in vec3 bitangent;
+layout(location = 6) in vec2 i_position;
+layout(location = 7) flat in uint instance;
+
+layout(location = 0) out float out_range;
+layout(location = 1) out float out_intensity;
+
+vec3 extract_normal(vec2 n) {
+	n = n * 2.0 - vec2(1.0);
+	float y = sqrt(max(1.0 - dot(n, n),0));
+	return normalize(vec3(n.x, y, n.y));
+}
+
+void main() {
+	// Positions are relative to the camera, which is placed at the sensor.
+	float range = length(position);
+	if (range > sensor.max_range)
+		discard;
+
+	NodeState node = nodes[instance];
+
+	vec3 normals_texcoord = node.normals.origin + vec3(texcoord * node.normals._step, 0);
+	vec3 tex_normal = extract_normal(texture(sampler2DArray(normals, filtered), normals_texcoord).xy);
+	if (node.normals.parent_origin.z >= 0) {
+		vec3 normals_parent_texcoord = node.normals.parent_origin + vec3(texcoord * node.normals.parent_step, 0);
+		vec3 pn = extract_normal(texture(sampler2DArray(normals, filtered), normals_parent_texcoord).xy);
+		tex_normal = mix(pn, tex_normal, morph);
+	}
+
+	vec3 albedo_texcoord = node.albedo.origin + vec3(texcoord * node.albedo._step, 0);
+	vec3 albedo_value = texture(sampler2DArray(albedo, filtered), albedo_texcoord).rgb;
+	if (node.albedo.parent_origin.z >= 0) {
+		vec3 albedo_parent_texcoord = node.albedo.parent_origin + vec3(texcoord * node.albedo.parent_step, 0);
+		vec3 parent_albedo = texture(sampler2DArray(albedo, filtered), albedo_parent_texcoord).rgb;
+		albedo_value = mix(parent_albedo, albedo_value, morph);
+	}
+
+	LandCover c = estimate_land_cover(albedo_value);
+	float reflectivity = dot(sensor.reflectivity, vec4(c.plains, c.forest, c.desert, c.rock))
+		+ sensor.ice_reflectivity * c.ice;
+
+	// Lambertian return from a co-located emitter and receiver.
+	vec3 bent_normal = normalize(mat3(tangent, normal, bitangent) * tex_normal);
+	float incidence = max(dot(bent_normal, -position / range), 0);
+
+	out_range = range;
+	out_intensity = reflectivity * incidence;
+}
@@ -0,0 +1,104 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(early_fragment_tests) in;
+
+layout(set = 0, binding = 0, std140) uniform UniformBlock {
+    Globals globals;
+};
+layout(set = 0, binding = 1, std140) readonly buffer NodeBlock {
+	NodeState nodes[];
+};
+layout(set = 0, binding = 4) uniform texture2DArray normals;
+layout(set = 0, binding = 5) uniform texture2DArray albedo;
+layout(set = 0, binding = 11) uniform sampler filtered;
+layout(set = 0, binding = 20, std140) uniform SensorBlock {
+	vec4 reflectivity;
+	float ice_reflectivity;
+	float max_range;
+	vec2 padding;
+	vec4 sun_history[8];
+} sensor;
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec2 texcoord;
+layout(location = 2) in float morph;
+layout(location = 3) in vec3 normal;
+layout(location = 4) in vec3 tangent;
+layout(location = 5) in vec3 bitangent;
+layout(location = 6) in vec2 i_position;
+layout(location = 7) flat in uint instance;
+
+layout(location = 0) out float out_temperature;
+
+// Thermal properties of plains, forest, desert, rock and ice, in that order.
+const float EMISSIVITY[5] = float[](0.97, 0.98, 0.92, 0.93, 0.98);
+// Warming in kelvin of ground facing the sun under full sunlight.
+const float HEATING[5] = float[](15, 8, 30, 25, 4);
+// How much temperature follows recent sunlight rather than the current sun, from thermal inertia.
+const float INERTIA[5] = float[](0.5, 0.7, 0.3, 0.6, 0.8);
+
+vec3 extract_normal(vec2 n) {
+	n = n * 2.0 - vec2(1.0);
+	float y = sqrt(max(1.0 - dot(n, n),0));
+	return normalize(vec3(n.x, y, n.y));
+}
+
+// Fraction of full sunlight falling on a surface with normal `n` at `up` from the sun in direction
+// `sun`.
+float insolation(vec3 n, vec3 up, vec3 sun) {
+	sun = normalize(sun);
+	return max(dot(n, sun), 0) * smoothstep(-0.02, 0.02, dot(up, sun));
+}
+
+void main() {
+	NodeState node = nodes[instance];
+
+	vec3 normals_texcoord = node.normals.origin + vec3(texcoord * node.normals._step, 0);
+	vec3 tex_normal = extract_normal(texture(sampler2DArray(normals, filtered), normals_texcoord).xy);
+	if (node.normals.parent_origin.z >= 0) {
+		vec3 normals_parent_texcoord = node.normals.parent_origin + vec3(texcoord * node.normals.parent_step, 0);
+		vec3 pn = extract_normal(texture(sampler2DArray(normals, filtered), normals_parent_texcoord).xy);
+		tex_normal = mix(pn, tex_normal, morph);
+	}
+
+	vec3 albedo_texcoord = node.albedo.origin + vec3(texcoord * node.albedo._step, 0);
+	vec3 albedo_value = texture(sampler2DArray(albedo, filtered), albedo_texcoord).rgb;
+	if (node.albedo.parent_origin.z >= 0) {
+		vec3 albedo_parent_texcoord = node.albedo.parent_origin + vec3(texcoord * node.albedo.parent_step, 0);
+		vec3 parent_albedo = texture(sampler2DArray(albedo, filtered), albedo_parent_texcoord).rgb;
+		albedo_value = mix(parent_albedo, albedo_value, morph);
+	}
+
+	LandCover c = estimate_land_cover(albedo_value);
+	float weights[5] = float[](c.plains, c.forest, c.desert, c.rock, c.ice);
+	float emissivity = 0;
+	float heating = 0;
+	float inertia = 0;
+	for (int i = 0; i < 5; i++) {
+		emissivity += weights[i] * EMISSIVITY[i];
+		heating += weights[i] * HEATING[i];
+		inertia += weights[i] * INERTIA[i];
+	}
+
+	vec3 world_position = position + globals.camera;
+	vec3 up = normalize(world_position);
+	float elevation = length(world_position) - 6371000.0;
+
+	// Air temperature from latitude, season and the standard lapse rate.
+	float summer = globals.season.z > 0 ? globals.season.x * (up.z >= 0 ? 1.0 : -1.0) : 0;
+	float air = 300.0 - 50.0 * up.z * up.z + 10.0 * summer * abs(up.z) - 0.0065 * max(elevation, 0);
+
+	vec3 bent_normal = normalize(mat3(tangent, normal, bitangent) * tex_normal);
+	float current = insolation(bent_normal, up, sensor.sun_history[0].xyz);
+	float history = 0;
+	for (int i = 0; i < 8; i++)
+		history += sensor.sun_history[i].w * insolation(bent_normal, up, sensor.sun_history[i].xyz);
+
+	// Ground that has been in the dark cools below the air temperature.
+	float exposure = mix(current, history, inertia);
+	float temperature = air + heating * exposure - 5.0 * (1 - exposure);
+
+	// Report the brightness temperature that a sensor assuming a black body would measure.
+	out_temperature = temperature * pow(emissivity, 0.25);
+}
@@ -1,8 +1,14 @@
 #version 450 core
 #include "declarations.glsl"
 #include "pbr.glsl"
+#include "hash.glsl"
+#include "clouds.glsl"
 
+// Discarding fragments inside holes is incompatible with early depth tests, so they are only
+// forced when there are no holes.
+#if !HOLES
 layout(early_fragment_tests) in;
+#endif
 
 layout(set = 0, binding = 0, std140) uniform UniformBlock {
     Globals globals;
@@ -19,6 +25,65 @@ layout(set = 0, binding = 7) uniform texture2DArray grass_canopy;
 layout(set = 0, binding = 8) uniform texture2DArray aerial_perspective;
 //layout(set = 0, binding = 9) uniform texture2DArray displacements;
 layout(set = 0, binding = 10) uniform sampler nearest;
+layout(set = 0, binding = 11) uniform sampler filtered;
+layout(set = 0, binding = 12) uniform texture2D shadow_map;
+layout(set = 0, binding = 13) uniform samplerShadow shadow;
+layout(set = 0, binding = 15) uniform sampler ground;
+layout(set = 0, binding = 16) uniform texture2DArray ground_albedo;
+layout(set = 0, binding = 17) uniform texture2DArray ground_normals;
+layout(set = 0, binding = 19) uniform texture2D noise;
+layout(set = 0, binding = 20) uniform texture3D distance_field;
+
+#define MAX_MATERIALS 16
+layout(set = 0, binding = 18, std140) uniform MaterialBlock {
+	vec4 settings;
+	vec4 parallax;
+	vec4 land_cover[MAX_MATERIALS];
+	vec4 params[MAX_MATERIALS];
+	vec4 average_albedo[MAX_MATERIALS];
+	vec4 surface[MAX_MATERIALS];
+} materials;
+
+#if HOLES
+struct Hole {
+	vec3 center;
+	float radius;
+	vec3 east;
+	uint first_vertex;
+	vec3 north;
+	uint num_vertices;
+};
+layout(set = 0, binding = 14, std430) readonly buffer HoleBlock {
+	uint num_holes;
+	uint padding[3];
+	Hole entries[64];
+	vec2 vertices[1024];
+} holes;
+
+// Whether `p`, relative to the camera, lies within any of the hole polygons. Each polygon is in
+// the plane tangent to the planet at its center, so `p` is projected onto that plane first.
+bool inside_hole(vec3 p) {
+	for (uint i = 0; i < holes.num_holes; i++) {
+		Hole hole = holes.entries[i];
+		vec3 d = p - hole.center;
+		vec2 q = vec2(dot(d, hole.east), dot(d, hole.north));
+		if (dot(q, q) > hole.radius * hole.radius)
+			continue;
+
+		// Count edge crossings of a ray from q in the east direction.
+		bool inside = false;
+		for (uint j = 0, k = hole.num_vertices - 1; j < hole.num_vertices; k = j++) {
+			vec2 a = holes.vertices[hole.first_vertex + j];
+			vec2 b = holes.vertices[hole.first_vertex + k];
+			if ((a.y > q.y) != (b.y > q.y) && q.x < a.x + (q.y - a.y) * (b.x - a.x) / (b.y - a.y))
+				inside = !inside;
+		}
+		if (inside)
+			return true;
+	}
+	return false;
+}
+#endif
 
 layout(location = 0) in vec3 position;
 layout(location = 1) in vec2 texcoord;
@@ -40,6 +105,25 @@ float mipmap_level(in vec2 texture_coordinate)
 }
 
 vec3 debug_overlay(vec3 color) {
+	// Tint each tile by where its heightmap came from: blue if downloaded, green if generated from
+	// datasets and orange if synthesized with fractal detail. Tints darken with the age of the tile,
+	// from produced within the last minute to over a month ago, and tile edges are outlined.
+	if (globals.debug_overlay > 0) {
+		NodeState node = nodes[instance];
+		vec3 tint = vec3(0.5);
+		if (node.provenance == 1u)
+			tint = vec3(0.1, 0.3, 1);
+		else if (node.provenance == 2u)
+			tint = vec3(0.1, 0.9, 0.2);
+		else if (node.provenance == 3u)
+			tint = vec3(1, 0.5, 0.05);
+		float age = log(1 + node.provenance_age / 60) / log(1 + 60 * 24 * 30);
+		tint *= mix(1.0, 0.3, clamp(age, 0, 1));
+
+		vec2 edge = min(texcoord, 1 - texcoord) / fwidth(texcoord);
+		color = mix(color, tint, min(edge.x, edge.y) < 1 ? 0.9 : 0.6);
+	}
+
 	// if((fract(0.5*position.x/32) < 0.5) != (fract(0.5*position.z/32) < 0.5))
 	// 	color = mix(color, vec3(0,0,1), 0.3);
 
@@ -202,6 +286,247 @@ vec3 debug_overlay(vec3 color) {
  	return color;
 }
 
+// Fraction of sunlight that isn't blocked by shadow casters drawn into the application's shadow map.
+float external_shadow() {
+	if (globals.shadow_params.x == 0)
+		return 1.0;
+
+	vec4 p = globals.shadow_view_proj * vec4(position, 1.0);
+	vec3 s = p.xyz / p.w;
+	vec2 uv = s.xy * vec2(0.5, -0.5) + 0.5;
+	if (any(lessThan(uv, vec2(0))) || any(greaterThan(uv, vec2(1))) || s.z < 0 || s.z > 1)
+		return 1.0;
+
+	return texture(sampler2DShadow(shadow_map, shadow), vec3(uv, s.z - globals.shadow_params.y));
+}
+
+// Signed distance in meters from `p`, relative to the camera, to the coarse terrain surface held in
+// the distance field around the camera. See `DistanceField::uniforms` for the layout.
+float terrain_distance(vec3 p) {
+	vec3 offset = p - globals.distance_field[0].xyz;
+	float extent = globals.distance_field[0].w;
+	vec3 uvw = vec3(dot(offset, globals.distance_field[1].xyz) / extent + 0.5,
+					dot(offset, globals.distance_field[2].xyz) / extent + 0.5,
+					(length(p + globals.camera) - 6371000.0 - globals.distance_field[1].w) / globals.distance_field[2].w);
+	return (texture(sampler3D(distance_field, linear), uvw).r * 2 - 1) * 4000.0;
+}
+
+// How much the distance field should be trusted at `p`: not at all close to the camera, where it is
+// too coarse and shadow maps take over, nor near the edges of the region it covers.
+float distance_field_weight(vec3 p) {
+	float extent = globals.distance_field[0].w;
+	if (extent == 0)
+		return 0;
+	vec3 offset = p - globals.distance_field[0].xyz;
+	float edge = max(abs(dot(offset, globals.distance_field[1].xyz)), abs(dot(offset, globals.distance_field[2].xyz)));
+	return smoothstep(250, 1000, length(p)) * (1 - smoothstep(0.4 * extent, 0.5 * extent, edge));
+}
+
+// Soft shadow cast by the coarse terrain onto `p` from the sun in direction `l`, found by sphere
+// tracing the distance field. The penumbra widens with how closely the ray passes by terrain.
+float distance_field_shadow(vec3 p, vec3 n, vec3 l) {
+	float voxel = globals.distance_field[0].w / 64;
+	vec3 start = p + n * voxel * 0.5;
+	float shadow = 1;
+	float t = voxel;
+	for (int i = 0; i < 24 && t < 12000; i++) {
+		float d = terrain_distance(start + l * t);
+		shadow = min(shadow, 8 * d / t);
+		if (shadow <= 0)
+			return 0;
+		t += max(d, 0.5 * voxel);
+	}
+	return clamp(shadow, 0, 1);
+}
+
+// Fraction of the sky visible from `p`, estimated from how close the terrain comes at a few points
+// along the normal.
+float distance_field_occlusion(vec3 p, vec3 n) {
+	float voxel = globals.distance_field[0].w / 64;
+	float occlusion = 0;
+	float weight = 0.5;
+	for (int i = 1; i <= 4; i++) {
+		float h = voxel * 0.5 * i;
+		occlusion += weight * max(h - terrain_distance(p + n * h), 0) / h;
+		weight *= 0.5;
+	}
+	return clamp(1 - occlusion, 0, 1);
+}
+
+// Adjust albedo for the time of year by moving the snowline and fading deciduous vegetation
+// outside of summer. Does nothing unless the application has set the date.
+vec3 seasonal_albedo(vec3 albedo, vec3 surface_normal) {
+	if (globals.season.z == 0)
+		return albedo;
+
+	vec3 world_position = position + globals.camera;
+	vec3 up = normalize(world_position);
+	float elevation = length(world_position) - 6371000.0;
+	float hemisphere = up.z >= 0 ? 1.0 : -1.0;
+
+	// Both are in [-1, 1] and reversed in the southern hemisphere.
+	float summer = globals.season.x * hemisphere;
+	float autumn = -globals.season.y * hemisphere;
+
+	// Seasonal swings are small near the equator and grow towards the poles.
+	float seasonality = abs(up.z);
+
+	float snowline = 5500.0 * (1.0 - up.z * up.z) - 1000.0 + 1500.0 * summer * seasonality;
+	float snow = smoothstep(snowline - 300.0, snowline + 300.0, elevation)
+		* smoothstep(0.6, 0.9, dot(surface_normal, up));
+
+	// Deciduous forests are concentrated at mid latitudes.
+	float vegetation = clamp((albedo.g - max(albedo.r, albedo.b)) * 20.0, 0, 1);
+	float deciduous = vegetation * smoothstep(0.35, 0.6, seasonality) * smoothstep(0.95, 0.8, seasonality);
+
+	vec3 fall_foliage = albedo.ggb * vec3(1.3, 0.8, 0.5);
+	vec3 bare_branches = vec3(dot(albedo, vec3(0.5, 0.35, 0.15))) * vec3(1.0, 0.85, 0.6);
+	albedo = mix(albedo, fall_foliage, deciduous * max(autumn, 0) * (1 - abs(summer)));
+	albedo = mix(albedo, bare_branches, deciduous * max(-summer, 0));
+
+	return mix(albedo, vec3(0.8), snow);
+}
+
+// Albedo of material `i` relative to its average, and its tangent space normal with +Z pointing
+// away from the surface.
+void sample_material(uint i, vec2 uv, out vec3 albedo, out vec3 n) {
+	vec3 coord = vec3(uv / materials.params[i].w, i);
+	albedo = texture(sampler2DArray(ground_albedo, ground), coord).rgb
+		/ max(materials.average_albedo[i].rgb, vec3(1e-3));
+	n = vec3(0, 0, 1);
+	if (materials.surface[i].y > 0)
+		n = texture(sampler2DArray(ground_normals, ground), coord).xyz * 2 - 1;
+}
+
+// Project material `i` along each world axis and blend by the surface normal `n`, so that steep
+// slopes aren't stretched. Normals are combined with `n` using a whiteout blend.
+void sample_material_triplanar(uint i, vec3 p, vec3 n, out vec3 albedo, out vec3 blended_normal) {
+	vec3 w = pow(abs(n), vec3(4));
+	w /= w.x + w.y + w.z;
+
+	vec3 ax, ay, az, nx, ny, nz;
+	sample_material(i, p.zy, ax, nx);
+	sample_material(i, p.xz, ay, ny);
+	sample_material(i, p.xy, az, nz);
+
+	albedo = ax * w.x + ay * w.y + az * w.z;
+	nx = vec3(nx.xy + n.zy, abs(nx.z) * n.x);
+	ny = vec3(ny.xy + n.xz, abs(ny.z) * n.y);
+	nz = vec3(nz.xy + n.xy, abs(nz.z) * n.z);
+	blended_normal = normalize(nx.zyx * w.x + ny.xzy * w.y + nz.xyz * w.z);
+}
+
+// Height of the fine scale ground relief in [0, 1], from the noise texture repeating every 8 meters.
+float ground_height(vec2 p, vec2 dpdx, vec2 dpdy) {
+	vec4 n = textureGrad(sampler2D(noise, ground), p / 8.0, dpdx / 8.0, dpdy / 8.0);
+	return dot(n, vec4(0.4, 0.3, 0.2, 0.1));
+}
+
+// Parallax occlusion mapping: march the view ray through the ground relief and return the ground
+// position, in meters, where it first hits. `view` points from the surface towards the camera in
+// the tangent space of the ground position, and `dpdx` and `dpdy` are the screen space derivatives of
+// `p`.
+vec2 parallax_occlusion(vec2 p, vec3 view, vec2 dpdx, vec2 dpdy) {
+	// Rays at grazing angles cross more of the relief, so they need more steps.
+	float steps = ceil(mix(materials.parallax.x, 1, abs(view.z)));
+	float layer = 1.0 / steps;
+	vec2 delta = view.xy / max(view.z, 0.2) * materials.parallax.y * layer;
+
+	float depth = 0;
+	float surface = 1 - ground_height(p, dpdx, dpdy);
+	for (int i = 0; i < int(steps) && depth < surface; i++) {
+		p -= delta;
+		depth += layer;
+		surface = 1 - ground_height(p, dpdx, dpdy);
+	}
+
+	// Interpolate between the last two samples to find the intersection.
+	float after = surface - depth;
+	float before = 1 - ground_height(p + delta, dpdx, dpdy) - depth + layer;
+	return mix(p, p + delta, clamp(after / (after - before), 0, 1));
+}
+
+// Splat the ground materials near the camera, weighted by land cover and slope. Materials only add
+// detail, so the albedo is scaled by the ratio of the material to its average color.
+void splat_materials(NodeState node, inout vec3 albedo_value, inout float roughness_value, inout vec3 tex_normal) {
+	uint count = uint(materials.settings.x);
+	float fade = 1 - smoothstep(0.5 * materials.settings.y, materials.settings.y, length(position));
+	if (count == 0 || fade <= 0)
+		return;
+
+	LandCover c = estimate_land_cover(albedo_value);
+	float slope = acos(clamp(tex_normal.y, 0, 1));
+	vec2 ground_position = node.ground_origin + texcoord * node.ground_step;
+
+	if (materials.parallax.x > 0) {
+		// Frame with the ground position along the tangent axes and the geometric normal.
+		vec3 dp1 = dFdx(position);
+		vec3 dp2 = dFdy(position);
+		vec2 duv1 = dFdx(ground_position);
+		vec2 duv2 = dFdy(ground_position);
+		vec3 dp2perp = cross(dp2, normal);
+		vec3 dp1perp = cross(normal, dp1);
+		vec3 t = dp2perp * duv1.x + dp1perp * duv2.x;
+		vec3 b = dp2perp * duv1.y + dp1perp * duv2.y;
+		float scale = inversesqrt(max(dot(t, t), dot(b, b)));
+
+		vec3 v = normalize(-position);
+		vec3 view = vec3(dot(v, t * scale), dot(v, b * scale), dot(v, normal));
+		float distance_fade = 1 - smoothstep(0.5 * materials.parallax.z, materials.parallax.z, length(position));
+		if (distance_fade > 0 && view.z > 0)
+			ground_position = mix(ground_position, parallax_occlusion(ground_position, view, duv1, duv2), distance_fade);
+	}
+
+	// Triplanar projection takes over on steep slopes, where the cube face projection stretches.
+	bool triplanar = materials.settings.z > 0;
+	float steep = triplanar ? smoothstep(0.5, 0.8, slope) : 0;
+	mat3 tbn = mat3(tangent, normal, bitangent);
+	vec3 world_normal = normalize(tbn * tex_normal);
+	vec3 world_position = position + globals.ground_camera.xyz;
+
+	vec3 planet_position = position + globals.camera;
+	uint biome = classify_biome(c, length(planet_position) - 6371000.0, normalize(planet_position).z, globals.planet.x);
+
+	vec3 albedo_sum = vec3(0);
+	vec3 normal_sum = vec3(0);
+	float roughness_sum = 0;
+	float roughness_weight = 0;
+	float total = 0;
+	for (uint i = 0; i < count; i++) {
+		vec4 p = materials.params[i];
+		float w = dot(materials.land_cover[i], vec4(c.plains, c.forest, c.desert, c.rock)) + p.x * c.ice;
+		w *= smoothstep(p.y - 0.1, p.y, slope) * (1 - smoothstep(p.z, p.z + 0.1, slope));
+		if (materials.surface[i].z >= 0 && (uint(materials.surface[i].z) & (1u << biome)) == 0)
+			w = 0;
+
+		vec3 a, n;
+		sample_material(i, ground_position, a, n);
+		n = normalize(vec3(tex_normal.xz + n.xy, tex_normal.y * n.z).xzy);
+		if (triplanar) {
+			vec3 ta, tn;
+			sample_material_triplanar(i, world_position, world_normal, ta, tn);
+			a = mix(a, ta, steep);
+			n = normalize(mix(n, transpose(tbn) * tn, steep));
+		}
+		albedo_sum += w * a;
+		normal_sum += w * n;
+
+		if (materials.surface[i].x >= 0) {
+			roughness_sum += w * materials.surface[i].x;
+			roughness_weight += w;
+		}
+		total += w;
+	}
+	if (total <= 0)
+		return;
+
+	float strength = fade * min(total, 1);
+	albedo_value *= mix(vec3(1), albedo_sum / total, strength);
+	if (roughness_weight > 0)
+		roughness_value = mix(roughness_value, roughness_sum / roughness_weight, fade * min(roughness_weight, 1));
+	tex_normal = normalize(mix(tex_normal, normalize(normal_sum), strength));
+}
+
 vec3 extract_normal(vec2 n) {
 	n = n * 2.0 - vec2(1.0);
 	float y = sqrt(max(1.0 - dot(n, n),0));
@@ -209,6 +534,11 @@ vec3 extract_normal(vec2 n) {
 }
 
 void main() {
+#if HOLES
+	if (inside_hole(position))
+		discard;
+#endif
+
 	NodeState node = nodes[instance];
 
 	vec3 albedo_texcoord = node.albedo.origin + vec3(texcoord * node.albedo._step, 0);
@@ -219,16 +549,15 @@ void main() {
 	vec3 normals_parent_texcoord = node.normals.parent_origin + vec3(texcoord * node.normals.parent_step, 0);
 
 	vec3 light_direction = normalize(vec3(0.4, 0.7,0.2));
-	vec3 tex_normal = extract_normal(texture(sampler2DArray(normals, linear), normals_texcoord).xy);
+	vec3 tex_normal = extract_normal(texture(sampler2DArray(normals, filtered), normals_texcoord).xy);
 	if (node.normals.parent_origin.z >= 0) {
-		vec3 pn = extract_normal(texture(sampler2DArray(normals, linear), normals_parent_texcoord).xy);
+		vec3 pn = extract_normal(texture(sampler2DArray(normals, filtered), normals_parent_texcoord).xy);
 		tex_normal = mix(pn, tex_normal, morph);
 	}
-	vec3 bent_normal = mat3(tangent, normal, bitangent) * tex_normal;
 
-	vec3 albedo_value = texture(sampler2DArray(albedo, linear), albedo_texcoord).rgb;
+	vec3 albedo_value = texture(sampler2DArray(albedo, filtered), albedo_texcoord).rgb;
 	if (node.albedo.parent_origin.z >= 0) {
-		vec3 parent_albedo = texture(sampler2DArray(albedo, linear), albedo_parent_texcoord).rgb;
+		vec3 parent_albedo = texture(sampler2DArray(albedo, filtered), albedo_parent_texcoord).rgb;
 		albedo_value = mix(parent_albedo, albedo_value, morph);
 	}
 
@@ -246,6 +575,27 @@ void main() {
 		}
 	}
 
+	splat_materials(node, albedo_value, roughness_value, tex_normal);
+	vec3 bent_normal = mat3(tangent, normal, bitangent) * tex_normal;
+
+	albedo_value = seasonal_albedo(albedo_value, bent_normal);
+
+	// Points on the night side of the planet receive no direct sunlight.
+	vec3 up = normalize(position + globals.camera);
+	vec3 sun_direction = normalize(globals.sun_direction);
+	float daylight = smoothstep(-0.02, 0.02, dot(up, sun_direction));
+	float clouds = cloud_shadow(position + globals.camera, sun_direction, globals.clouds);
+
+	// At medium distances the terrain shadows itself through the distance field, with occluded
+	// light from the sky filling in the shadows.
+	float terrain_shadow = 1;
+	float sky_visibility = 1;
+	float field_weight = distance_field_weight(position);
+	if (field_weight > 0) {
+		terrain_shadow = mix(1, distance_field_shadow(position, normal, sun_direction), field_weight);
+		sky_visibility = mix(1, distance_field_occlusion(position, normal), field_weight);
+	}
+
 	out_color = vec4(1);
 	out_color.rgb = pbr(albedo_value,
 						roughness_value,
@@ -253,14 +603,28 @@ void main() {
 						bent_normal,
 						globals.camera,
 						globals.sun_direction,
-						vec3(100000.0));
+						vec3(100000.0) * external_shadow() * clouds * terrain_shadow * daylight);
+	if (globals.distance_field[0].w > 0)
+		out_color.rgb += albedo_value * 15000.0 / M_PI * sky_visibility * daylight;
+
+	// Moonlight, scaled by the lit fraction of the moon. A full moon is about 0.25 lux.
+	if (globals.moon.w > 0 && dot(up, globals.moon.xyz) > 0) {
+		float moon_phase = 0.5 - 0.5 * dot(sun_direction, globals.moon.xyz);
+		out_color.rgb += pbr(albedo_value,
+							 roughness_value,
+							 position,
+							 bent_normal,
+							 globals.camera,
+							 globals.moon.xyz,
+							 vec3(0.25 * moon_phase));
+	}
 
 	vec4 ap = texture(sampler2DArray(aerial_perspective, linear),
 					  vec3((texcoord / 64.0 * 16 + 0.5) / 17, node.node_index));
 	out_color.rgb *= ap.a * 16.0;
 	out_color.rgb += ap.rgb * 16.0;
 
-	float ev100 = 15.0;
+	float ev100 = globals.ev100;
 	float exposure = 1.0 / (pow(2.0, ev100) * 1.2);
 	out_color = tonemap(out_color, exposure, 2.2);
 
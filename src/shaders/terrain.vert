@@ -18,6 +18,8 @@ layout(location = 4) out vec3 out_tangent;
 layout(location = 5) out vec3 out_bitangent;
 layout(location = 6) out vec2 out_i_position;
 layout(location = 7) flat out uint out_instance;
+layout(location = 8) out vec4 out_clip_position;
+layout(location = 9) out vec4 out_prev_clip_position;
 
 const vec3 tangents[6] = vec3[6](
 	vec3(0,1,0),
@@ -28,6 +30,38 @@ const vec3 tangents[6] = vec3[6](
 	vec3(-1,0,0)
 );
 
+const float planetRadius = 6371000.0;
+const float maxLatitude = 1.4844222;
+
+float wrap_angle(float a) {
+	return mod(a + 3.141592653, 2 * 3.141592653) - 3.141592653;
+}
+
+// Move a position relative to the camera towards where it lies on the flat Web Mercator map
+// described by `MapProjection`. Longitudes are measured from the center of the node so that tiles
+// aren't stretched across the map where it wraps around.
+vec3 map_projection(vec3 position, vec3 node_center) {
+	float center_latitude = globals.map_projection.y;
+	float center_longitude = globals.map_projection.z;
+
+	vec3 p = position + globals.camera;
+	float r = length(p);
+	float latitude = clamp(asin(p.z / r), -maxLatitude, maxLatitude);
+	float node_longitude = atan(node_center.y, node_center.x);
+	float x = wrap_angle(node_longitude - center_longitude)
+		+ wrap_angle(atan(p.y, p.x) - node_longitude);
+	float y = log(tan(0.25 * 3.141592653 + 0.5 * latitude)) - globals.map_projection.w;
+
+	vec3 up = vec3(cos(center_latitude) * cos(center_longitude),
+				   cos(center_latitude) * sin(center_longitude),
+				   sin(center_latitude));
+	vec3 east = vec3(-sin(center_longitude), cos(center_longitude), 0);
+	vec3 north = cross(up, east);
+	vec3 flat_position = up * r + (east * x + north * y) * planetRadius;
+
+	return mix(position, flat_position - globals.camera, globals.map_projection.x);
+}
+
 void main() {
 	NodeState node = nodes[gl_InstanceIndex];
 
@@ -53,6 +87,9 @@ void main() {
 	}
 
 	vec3 normal = normalize(position + globals.camera);
+	if (globals.map_projection.x > 0)
+		position = map_projection(position, globals.camera - node.relative_position);
+
 	vec3 bitangent = normalize(cross(normal, tangents[node.face]));
 	vec3 tangent = normalize(cross(normal, bitangent));
 
@@ -65,5 +102,9 @@ void main() {
 	out_i_position = vec2(iPosition);
 	out_instance = gl_InstanceIndex;
 
-	gl_Position = globals.view_proj * vec4(position, 1.0);
+	out_clip_position = globals.view_proj * vec4(position, 1.0);
+	out_prev_clip_position = globals.prev_view_proj * vec4(position, 1.0);
+
+	gl_Position = out_clip_position;
+	gl_Position.xy += globals.jitter * gl_Position.w;
 }
@@ -0,0 +1,90 @@
+#version 450 core
+#include "declarations.glsl"
+
+layout(set = 0, binding = 0) uniform UniformBlock {
+	Globals globals;
+};
+layout(set = 0, binding = 1) uniform ReflectionBlock {
+	float max_steps;
+	float max_distance;
+	vec2 padding;
+} reflections;
+layout(set = 0, binding = 2) uniform sampler linear;
+layout(set = 0, binding = 3) uniform texture2D scene;
+layout(set = 0, binding = 4) uniform texture2D depth;
+layout(set = 0, binding = 5) uniform textureCube environment_map;
+
+layout(location = 0) in vec4 position;
+
+layout(location = 0) out vec4 out_color;
+
+const float planetRadius = 6371000.0;
+
+float scene_depth(vec2 uv) {
+	ivec2 size = textureSize(depth, 0);
+	return texelFetch(depth, clamp(ivec2(uv * size), ivec2(0), size - ivec2(1)), 0).r;
+}
+
+// Position relative to the camera of the point drawn at `uv` with depth `d`.
+vec3 unproject(vec2 uv, float d) {
+	vec4 p = globals.view_proj_inverse * vec4(uv * vec2(2, -2) + vec2(-1, 1), d, 1);
+	return p.xyz / p.w;
+}
+
+// Screen coordinates of a point relative to the camera, or negative coordinates if the point is
+// behind the camera.
+vec2 project(vec3 p) {
+	vec4 clip = globals.view_proj * vec4(p, 1);
+	if (clip.w <= 0)
+		return vec2(-1);
+	return clip.xy / clip.w * vec2(0.5, -0.5) + vec2(0.5);
+}
+
+void main() {
+	vec2 uv = gl_FragCoord.xy / vec2(textureSize(depth, 0));
+	float d = scene_depth(uv);
+	if (d <= 0)
+		discard;
+
+	// The ocean floor is raised to sea level, so anything drawn that low is open water. Depth
+	// precision falls off with distance, hence the growing tolerance.
+	vec3 p = unproject(uv, d);
+	float distance = length(p);
+	vec3 up = normalize(p + globals.camera);
+	float elevation = length(p + globals.camera) - planetRadius;
+	if (elevation > globals.planet.x + 0.5 + 1e-4 * distance)
+		discard;
+
+	// Calm water is a flat mirror, reflecting more at grazing angles.
+	vec3 v = p / distance;
+	vec3 r = reflect(v, up);
+	float fresnel = 0.02 + 0.98 * pow(1 - max(dot(-v, up), 0), 5);
+
+	// March along the reflected ray with steps that grow geometrically, so that nearby shores are
+	// resolved while still reaching distant mountains. Rays that leave the screen or pass behind
+	// everything drawn fall back to the environment map.
+	vec3 color = texture(samplerCube(environment_map, linear), r).rgb;
+	float growth = pow(reflections.max_distance, 1.0 / reflections.max_steps);
+	float t = 1;
+	float previous_t = 0;
+	for (int i = 0; i < int(reflections.max_steps); i++) {
+		vec3 q = p + r * t;
+		vec2 s = project(q);
+		if (any(lessThan(s, vec2(0))) || any(greaterThan(s, vec2(1))))
+			break;
+
+		// The ray hits whatever was drawn at `s` if it has only just passed behind it.
+		float ray = length(q);
+		float surface = length(unproject(s, scene_depth(s)));
+		if (surface < ray && ray - surface < 2 * (t - previous_t)) {
+			vec2 edge = min(s, vec2(1) - s);
+			float fade = smoothstep(0, 0.05, min(edge.x, edge.y));
+			color = mix(color, texture(sampler2D(scene, linear), s).rgb, fade);
+			break;
+		}
+		previous_t = t;
+		t *= growth;
+	}
+
+	out_color = vec4(color, fresnel);
+}
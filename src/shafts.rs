@@ -0,0 +1,140 @@
+fn buffer_binding(buffer: &wgpu::Buffer) -> wgpu::BindingResource {
+    wgpu::BindingResource::Buffer(wgpu::BufferBinding { buffer, offset: 0, size: None })
+}
+
+/// Crepuscular rays: streaks of sunlight through gaps in the terrain, added onto the color buffer
+/// as a full screen pass by blurring the visible sky in the depth buffer towards the sun.
+pub(crate) struct LightShafts {
+    shader: rshader::ShaderSet,
+    pipeline: Option<(wgpu::BindGroupLayout, wgpu::RenderPipeline)>,
+}
+impl LightShafts {
+    pub fn new() -> Self {
+        Self {
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "sky.vert", "declarations.glsl"),
+                rshader::shader_source!("shaders", "light-shafts.frag", "declarations.glsl"),
+            )
+            .unwrap(),
+            pipeline: None,
+        }
+    }
+
+    /// Add light shafts to `color_buffer`, using the camera and weather of the frame most recently
+    /// written to `globals`.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        globals: &wgpu::Buffer,
+        depth_buffer: &wgpu::TextureView,
+        color_buffer: &wgpu::TextureView,
+    ) {
+        if self.shader.refresh() {
+            self.pipeline = None;
+        }
+
+        // The depth buffer is owned by the application so the bind group is built directly
+        // rather than through `bind_group_for_shader`.
+        if self.pipeline.is_none() {
+            let mut entries = self.shader.layout_descriptor().entries.to_vec();
+            for (name, entry) in self.shader.desc_names().iter().zip(&mut entries) {
+                if let wgpu::BindingType::Texture { ref mut sample_type, .. } = entry.ty {
+                    assert_eq!(name.as_deref(), Some("depth"));
+                    *sample_type = wgpu::TextureSampleType::Depth;
+                }
+            }
+            let bind_group_layout =
+                device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: &entries,
+                    label: Some("layout.shafts"),
+                });
+            let additive = wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            };
+            let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.shafts.layout"),
+                })),
+                vertex: wgpu::VertexState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.shafts.vertex"),
+                        source: wgpu::ShaderSource::SpirV(self.shader.vertex().into()),
+                        flags: wgpu::ShaderFlags::VALIDATION,
+                    }),
+                    entry_point: "main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.shafts.fragment"),
+                        source: wgpu::ShaderSource::SpirV(self.shader.fragment().into()),
+                        flags: wgpu::ShaderFlags::VALIDATION,
+                    }),
+                    entry_point: "main",
+                    targets: &[wgpu::ColorTargetState {
+                        format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                        blend: Some(wgpu::BlendState {
+                            color: additive,
+                            alpha: wgpu::BlendComponent {
+                                src_factor: wgpu::BlendFactor::Zero,
+                                dst_factor: wgpu::BlendFactor::One,
+                                operation: wgpu::BlendOperation::Add,
+                            },
+                        }),
+                        write_mask: wgpu::ColorWrite::ALL,
+                    }],
+                }),
+                primitive: Default::default(),
+                depth_stencil: None,
+                multisample: Default::default(),
+                label: Some("pipeline.shafts"),
+            });
+            self.pipeline = Some((bind_group_layout, pipeline));
+        }
+        let (bind_group_layout, pipeline) = self.pipeline.as_ref().unwrap();
+
+        let bindings: Vec<_> = self
+            .shader
+            .desc_names()
+            .iter()
+            .zip(self.shader.layout_descriptor().entries.iter())
+            .map(|(name, entry)| wgpu::BindGroupEntry {
+                binding: entry.binding,
+                resource: match name.as_deref().unwrap() {
+                    "globals" => buffer_binding(globals),
+                    "depth" => wgpu::BindingResource::TextureView(depth_buffer),
+                    name => unreachable!("unrecognized binding: {}", name),
+                },
+            })
+            .collect();
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: bind_group_layout,
+            entries: &*bindings,
+            label: Some("bindgroup.shafts"),
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("encoder.shafts"),
+        });
+        {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: color_buffer,
+                    resolve_target: None,
+                    ops: wgpu::Operations { load: wgpu::LoadOp::Load, store: true },
+                }],
+                depth_stencil_attachment: None,
+                label: Some("renderpass.shafts"),
+            });
+            rpass.set_pipeline(pipeline);
+            rpass.set_bind_group(0, &bind_group, &[]);
+            rpass.draw(0..3, 0..1);
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+}
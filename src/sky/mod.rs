@@ -1,4 +1,5 @@
 use crate::asset::AssetLoadContext;
+use crate::coordinates::PLANET_RADIUS;
 use crate::sky::lut::{LookupTable, LookupTableDefinition};
 use crate::sky::precompute::{InscatteringTable, TransmittanceTable};
 use anyhow::Error;
@@ -11,10 +12,13 @@ pub(crate) struct Atmosphere {
     pub inscattering: LookupTable,
 }
 impl Atmosphere {
-    pub fn new(context: &mut AssetLoadContext) -> Result<Self, Error> {
-        let transmittance = TransmittanceTable { steps: 1000 }.generate(context)?;
+    /// Precompute scattering for an atmosphere `atmosphere_height` meters thick.
+    pub fn new(context: &mut AssetLoadContext, atmosphere_height: f32) -> Result<Self, Error> {
+        let radius = PLANET_RADIUS + atmosphere_height as f64;
+        let transmittance = TransmittanceTable { steps: 1000, Rt: radius }.generate(context)?;
         let inscattering =
-            InscatteringTable { steps: 30, transmittance: &transmittance }.generate(context)?;
+            InscatteringTable { steps: 30, Rt: radius, transmittance: &transmittance }
+                .generate(context)?;
 
         Ok(Self { transmittance, inscattering })
     }
@@ -10,7 +10,6 @@ use cgmath::{ElementWise, InnerSpace, Vector2, Vector3, Vector4, VectorSpace, Ze
 // http://publications.lib.chalmers.se/records/fulltext/203057/203057.pdf
 // https://sebh.github.io/publications/egsr2020.pdf
 const Rg: f64 = 6371000.0;
-const Rt: f64 = 6471000.0;
 
 mod rayleigh {
     use super::*;
@@ -39,7 +38,14 @@ mod mie {
     // }
 }
 
-fn integral<V, F>(r: f64, theta: f64, steps: u32, force_hit_planet_surface: bool, f: F) -> V
+fn integral<V, F>(
+    Rt: f64,
+    r: f64,
+    theta: f64,
+    steps: u32,
+    force_hit_planet_surface: bool,
+    f: F,
+) -> V
 where
     V: VectorSpace<Scalar = f64>,
     F: Fn(Vector2<f64>) -> V,
@@ -82,9 +88,11 @@ where
 
 pub(super) struct TransmittanceTable {
     pub steps: u32,
+    /// Radius of the top of the atmosphere.
+    pub Rt: f64,
 }
 impl TransmittanceTable {
-    fn compute_parameters(size: [u16; 3], u_r: f64, u_mu: f64) -> (f64, f64) {
+    fn compute_parameters(Rt: f64, size: [u16; 3], u_r: f64, u_mu: f64) -> (f64, f64) {
         assert!(u_r >= 0.0 && u_r <= 1.0);
         assert!(u_mu >= 0.0 && u_mu <= 1.0);
 
@@ -108,7 +116,7 @@ impl TransmittanceTable {
 
         (r, mu)
     }
-    fn reverse_parameters(size: [u16; 3], r: f64, mu: f64) -> (f64, f64) {
+    fn reverse_parameters(Rt: f64, size: [u16; 3], r: f64, mu: f64) -> (f64, f64) {
         assert!(r >= Rg && r <= Rt);
         assert!(mu >= -1.0 && mu <= 1.0);
 
@@ -141,6 +149,7 @@ impl LookupTableDefinition for TransmittanceTable {
     }
     fn compute(&self, [x, y, _]: [u16; 3]) -> [f32; 4] {
         let (r, v) = Self::compute_parameters(
+            self.Rt,
             self.size(),
             f64::from(x) / f64::from(self.size()[0] - 1),
             f64::from(y) / f64::from(self.size()[1] - 1),
@@ -149,7 +158,7 @@ impl LookupTableDefinition for TransmittanceTable {
         assert!(v >= -1.0 && v <= 1.0, "AA {}", v);
 
         let intersects_ground = y < self.size()[1] / 2;
-        let t = integral(r, f64::acos(v), self.steps, intersects_ground, |y| {
+        let t = integral(self.Rt, r, f64::acos(v), self.steps, intersects_ground, |y| {
             let height = y.magnitude() - Rg;
             let Beta_e_R = rayleigh::Beta_e * f64::exp(-height / rayleigh::H);
             let Beta_e_M = mie::Beta_e * f64::exp(-height / mie::H);
@@ -176,10 +185,17 @@ impl LookupTableDefinition for TransmittanceTable {
 
 pub(super) struct InscatteringTable<'a> {
     pub steps: u32,
+    pub Rt: f64,
     pub transmittance: &'a LookupTable,
 }
 impl<'a> InscatteringTable<'a> {
-    fn compute_parameters(size: [u16; 3], u_r: f64, u_mu: f64, u_mu_s: f64) -> (f64, f64, f64) {
+    fn compute_parameters(
+        Rt: f64,
+        size: [u16; 3],
+        u_r: f64,
+        u_mu: f64,
+        u_mu_s: f64,
+    ) -> (f64, f64, f64) {
         assert!(u_r >= 0.0 && u_r <= 1.0);
         assert!(u_mu >= 0.0 && u_mu <= 1.0);
         assert!(u_mu_s >= 0.0 && u_mu_s <= 1.0);
@@ -205,7 +221,7 @@ impl<'a> InscatteringTable<'a> {
         (r, mu, mu_s)
     }
     #[cfg(test)]
-    fn reverse_parameters(size: [u16; 3], r: f64, mu: f64, mu_s: f64) -> (f64, f64, f64) {
+    fn reverse_parameters(Rt: f64, size: [u16; 3], r: f64, mu: f64, mu_s: f64) -> (f64, f64, f64) {
         assert!(r >= Rg && r <= Rt);
         assert!(mu >= -1.0 && mu <= 1.0);
         assert!(mu_s >= -1.0 && mu_s <= 1.0);
@@ -239,6 +255,7 @@ impl<'a> LookupTableDefinition for InscatteringTable<'a> {
     }
     fn compute(&self, [x, y, z]: [u16; 3]) -> [f32; 4] {
         let (r, mu, mu_s) = Self::compute_parameters(
+            self.Rt,
             self.size(),
             f64::from(x) / f64::from(self.size()[0] - 1),
             f64::from(y) / f64::from(self.size()[1] - 1),
@@ -248,7 +265,7 @@ impl<'a> LookupTableDefinition for InscatteringTable<'a> {
         let intersects_ground = y < self.size()[1] / 2;
 
         let (xx0, yy0) =
-            TransmittanceTable::reverse_parameters(self.transmittance.size.clone(), r, mu);
+            TransmittanceTable::reverse_parameters(self.Rt, self.transmittance.size.clone(), r, mu);
         let [Tr0, Tg0, Tb0, _] = { self.transmittance.get2(xx0, yy0) };
 
         // let vv = if mu > 0.0 {
@@ -260,7 +277,7 @@ impl<'a> LookupTableDefinition for InscatteringTable<'a> {
         // let ss = Vector2::new(f64::sqrt(1.0 - mu_s * mu_s), mu_s);
 
         let L_sun = 100000.0;
-        let s = integral(r, f64::acos(mu), self.steps, intersects_ground, |y| {
+        let s = integral(self.Rt, r, f64::acos(mu), self.steps, intersects_ground, |y| {
             // // Check if the sun is below the horizon
             // if y.dot(ss) < 0.0 {
             //     return Vector4::new(0.0, 0.0, 0.0, 0.0);
@@ -275,11 +292,16 @@ impl<'a> LookupTableDefinition for InscatteringTable<'a> {
             let r = (y_magnitude).max(Rg);
             let h = r - Rg;
 
-            let (xx, yy) =
-                TransmittanceTable::reverse_parameters(self.transmittance.size.clone(), r, mu_s);
+            let (xx, yy) = TransmittanceTable::reverse_parameters(
+                self.Rt,
+                self.transmittance.size.clone(),
+                r,
+                mu_s,
+            );
             let [Tr, Tg, Tb, _] = self.transmittance.get2(xx, yy);
 
             let (xx, yy) = TransmittanceTable::reverse_parameters(
+                self.Rt,
                 self.transmittance.size.clone(),
                 r,
                 y.dot(vv) / y_magnitude,
@@ -324,6 +346,8 @@ mod tests {
     use approx::assert_relative_eq;
     use rand::{self, Rng};
 
+    const Rt: f64 = Rg + 100000.0;
+
     #[test]
     fn invert_transmittance_parameters() {
         let mut rng = rand::thread_rng();
@@ -331,8 +355,8 @@ mod tests {
         for _ in 0..10000 {
             let (r, mu) = (rng.gen_range(Rg..Rt), rng.gen_range(-1.0..1.0));
 
-            let (x, y) = TransmittanceTable::reverse_parameters(size.clone(), r, mu);
-            let (r2, mu2) = TransmittanceTable::compute_parameters(size.clone(), x, y);
+            let (x, y) = TransmittanceTable::reverse_parameters(Rt, size.clone(), r, mu);
+            let (r2, mu2) = TransmittanceTable::compute_parameters(Rt, size.clone(), x, y);
 
             assert_relative_eq!(r, r2, max_relative = 0.0001);
             assert_relative_eq!(mu, mu2, max_relative = 0.0001);
@@ -348,8 +372,8 @@ mod tests {
             let (x, y, z) =
                 (rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0), rng.gen_range(0.0..1.0));
 
-            let (r, mu, mu_s) = InscatteringTable::compute_parameters(size.clone(), x, y, z);
-            let (x2, y2, z2) = InscatteringTable::reverse_parameters(size.clone(), r, mu, mu_s);
+            let (r, mu, mu_s) = InscatteringTable::compute_parameters(Rt, size.clone(), x, y, z);
+            let (x2, y2, z2) = InscatteringTable::reverse_parameters(Rt, size.clone(), r, mu, mu_s);
 
             assert_relative_eq!(x, x2, max_relative = 0.0001);
             assert_relative_eq!(y, y2, max_relative = 0.0001);
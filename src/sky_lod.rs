@@ -0,0 +1,152 @@
+/// Above this altitude in meters the limb of the planet makes a sharp edge across the sky, so it
+/// is always drawn at full resolution.
+const MAX_ALTITUDE: f64 = 10000.0;
+
+/// Reduced resolution rendering of the sky near the ground, where it holds no sharp detail. The
+/// sky is drawn into a small offscreen texture, which is then stretched over the parts of the
+/// main render pass that nothing else covers.
+pub(crate) struct SkyLod {
+    downsampling: u32,
+    shader: rshader::ShaderSet,
+    pipeline: Option<(wgpu::BindGroupLayout, wgpu::RenderPipeline)>,
+    sampler: wgpu::Sampler,
+    /// Size of the reduced resolution sky, its view and the bind group that samples it.
+    target: Option<((u32, u32), wgpu::TextureView, wgpu::BindGroup)>,
+}
+impl SkyLod {
+    pub fn new(device: &wgpu::Device, downsampling: u32) -> Self {
+        Self {
+            downsampling,
+            shader: rshader::ShaderSet::simple(
+                rshader::shader_source!("shaders", "sky.vert", "declarations.glsl"),
+                rshader::shader_source!("shaders", "sky-upsample.frag", "declarations.glsl"),
+            )
+            .unwrap(),
+            pipeline: None,
+            sampler: device.create_sampler(&wgpu::SamplerDescriptor {
+                mag_filter: wgpu::FilterMode::Linear,
+                min_filter: wgpu::FilterMode::Linear,
+                label: Some("sampler.sky_lod"),
+                ..Default::default()
+            }),
+            target: None,
+        }
+    }
+
+    pub fn downsampling(&self) -> u32 {
+        self.downsampling
+    }
+
+    pub fn set_downsampling(&mut self, downsampling: u32) {
+        self.downsampling = downsampling;
+    }
+
+    /// Size that the sky should be drawn at for a frame of `frame_size` with the camera at
+    /// `altitude`, or None if it should be drawn at full resolution.
+    pub fn size(&self, frame_size: (u32, u32), altitude: f64) -> Option<(u32, u32)> {
+        if self.downsampling <= 1 || altitude > MAX_ALTITUDE {
+            return None;
+        }
+        let d = self.downsampling;
+        Some(((frame_size.0 + d - 1) / d, (frame_size.1 + d - 1) / d))
+    }
+
+    /// Texture to draw the reduced resolution sky into, recreated whenever `size` changes.
+    pub fn target(&mut self, device: &wgpu::Device, size: (u32, u32)) -> &wgpu::TextureView {
+        if self.shader.refresh() {
+            self.pipeline = None;
+            self.target = None;
+        }
+        if self.pipeline.is_none() {
+            let bind_group_layout =
+                device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: self.shader.layout_descriptor().entries,
+                    label: Some("layout.sky_lod"),
+                });
+            let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    bind_group_layouts: [&bind_group_layout][..].into(),
+                    push_constant_ranges: &[],
+                    label: Some("pipeline.sky_lod.layout"),
+                })),
+                vertex: wgpu::VertexState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.sky_lod.vertex"),
+                        source: wgpu::ShaderSource::SpirV(self.shader.vertex().into()),
+                        flags: wgpu::ShaderFlags::VALIDATION,
+                    }),
+                    entry_point: "main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                        label: Some("shader.sky_lod.fragment"),
+                        source: wgpu::ShaderSource::SpirV(self.shader.fragment().into()),
+                        flags: wgpu::ShaderFlags::VALIDATION,
+                    }),
+                    entry_point: "main",
+                    targets: &[wgpu::ColorTargetState {
+                        format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                        blend: Some(wgpu::BlendState {
+                            color: wgpu::BlendComponent::REPLACE,
+                            alpha: wgpu::BlendComponent::REPLACE,
+                        }),
+                        write_mask: wgpu::ColorWrite::ALL,
+                    }],
+                }),
+                primitive: Default::default(),
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: wgpu::TextureFormat::Depth32Float,
+                    depth_compare: wgpu::CompareFunction::GreaterEqual,
+                    depth_write_enabled: false,
+                    bias: Default::default(),
+                    stencil: Default::default(),
+                }),
+                multisample: Default::default(),
+                label: Some("pipeline.sky_lod"),
+            });
+            self.pipeline = Some((bind_group_layout, pipeline));
+        }
+
+        if self.target.as_ref().map(|t| t.0) != Some(size) {
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                size: wgpu::Extent3d { width: size.0, height: size.1, depth_or_array_layers: 1 },
+                format: wgpu::TextureFormat::Bgra8UnormSrgb,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
+                label: Some("texture.sky_lod"),
+            });
+            let view = texture.create_view(&Default::default());
+            let bindings: Vec<_> = self
+                .shader
+                .desc_names()
+                .iter()
+                .zip(self.shader.layout_descriptor().entries.iter())
+                .map(|(name, entry)| wgpu::BindGroupEntry {
+                    binding: entry.binding,
+                    resource: match name.as_deref().unwrap() {
+                        "linear" => wgpu::BindingResource::Sampler(&self.sampler),
+                        "low_resolution_sky" => wgpu::BindingResource::TextureView(&view),
+                        name => unreachable!("unrecognized binding: {}", name),
+                    },
+                })
+                .collect();
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.pipeline.as_ref().unwrap().0,
+                entries: &*bindings,
+                label: Some("bindgroup.sky_lod"),
+            });
+            self.target = Some((size, view, bind_group));
+        }
+        &self.target.as_ref().unwrap().1
+    }
+
+    /// Stretch the sky last drawn into `target` over the sky in the main render pass.
+    pub fn render<'a>(&'a self, rpass: &mut wgpu::RenderPass<'a>) {
+        rpass.set_pipeline(&self.pipeline.as_ref().unwrap().1);
+        rpass.set_bind_group(0, &self.target.as_ref().unwrap().2, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+}
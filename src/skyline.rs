@@ -0,0 +1,145 @@
+use crate::cache::TextureFormat;
+use crate::coordinates::{self, LatLongBounds, PLANET_RADIUS};
+use crate::mapfile::{MapFile, TextureDescriptor};
+use anyhow::Error;
+use cgmath::{InnerSpace, Vector3};
+use std::f64::consts::PI;
+
+/// Number of directions around each viewpoint that a skyline is recorded for.
+pub(crate) const DIRECTIONS: usize = 128;
+/// Closest and farthest terrain in meters that skylines are made of. Terrain closer than `NEAR`
+/// is always streamed in detail, and terrain beyond `FAR` is hidden by the curvature of the planet
+/// from any but the highest mountains.
+const NEAR: f64 = 20000.0;
+const FAR: f64 = 400000.0;
+/// Number of points sampled along each direction, spaced geometrically between `NEAR` and `FAR`.
+const SAMPLES: usize = 96;
+/// Height in meters of the viewer above the ground at each viewpoint.
+const EYE_HEIGHT: f32 = 2.0;
+
+/// Name of the texture holding the skylines in the map file.
+pub(crate) const TEXTURE_NAME: &str = "skylines";
+
+/// Latitude and longitude of the point `distance` meters from `up` along the surface of the
+/// planet, heading `azimuth` radians clockwise from north.
+fn travel(up: Vector3<f64>, azimuth: f64, distance: f64) -> (f64, f64) {
+    let east = Vector3::new(0.0, 0.0, 1.0).cross(up);
+    let east = if east.magnitude2() > 1e-12 { east.normalize() } else { Vector3::unit_x() };
+    let north = up.cross(east);
+    let heading = east * azimuth.sin() + north * azimuth.cos();
+    let angle = distance / PLANET_RADIUS;
+    let p = coordinates::ecef_to_polar((up * angle.cos() + heading * angle.sin()) * PLANET_RADIUS);
+    (p.x, p.y)
+}
+
+/// The skyline seen from the ground at the given latitude and longitude: for each direction,
+/// clockwise from north, the elevation of the distant terrain that rises highest above the
+/// horizon along with its distance in meters. Directions where nothing rises above `sea_level`
+/// have a distance of zero.
+pub(crate) fn compute<F>(
+    latitude: f64,
+    longitude: f64,
+    sea_level: Option<f32>,
+    mut height: F,
+) -> Result<Vec<[f32; 2]>, Error>
+where
+    F: FnMut(f64, f64) -> Result<f32, Error>,
+{
+    let up = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0)).normalize();
+    let eye = height(latitude, longitude)? + EYE_HEIGHT;
+    let growth = (FAR / NEAR).powf(1.0 / (SAMPLES - 1) as f64);
+
+    let mut skyline = Vec::with_capacity(DIRECTIONS);
+    for i in 0..DIRECTIONS {
+        let azimuth = 2.0 * PI * i as f64 / DIRECTIONS as f64;
+        let mut highest = [0.0, 0.0];
+        let mut best = f64::NEG_INFINITY;
+        let mut distance = NEAR;
+        for _ in 0..SAMPLES {
+            let (lat, long) = travel(up, azimuth, distance);
+            let h = height(lat, long)?;
+
+            // Tangent of the angle above the horizon, less the drop due to the curvature of
+            // the planet.
+            let rise = (h - eye) as f64 / distance - 0.5 * distance / PLANET_RADIUS;
+            if rise > best && sea_level.map(|s| h > s + 1.0).unwrap_or(true) {
+                best = rise;
+                highest = [h, distance as f32];
+            }
+            distance *= growth;
+        }
+        skyline.push(highest);
+    }
+    Ok(skyline)
+}
+
+/// Compute skylines for a grid of viewpoints about `spacing` meters apart covering `bounds`, and
+/// save them to the map file as a 3D texture with a texel for each direction, grid column and grid
+/// row in that order.
+pub(crate) fn generate<F, P>(
+    mapfile: &MapFile,
+    bounds: LatLongBounds,
+    spacing: f64,
+    mut height: F,
+    mut progress_callback: P,
+) -> Result<(), Error>
+where
+    F: FnMut(f64, f64) -> Result<f32, Error>,
+    P: FnMut(&str, usize, usize),
+{
+    let (center_latitude, _) = bounds.center();
+    let width = bounds.longitude_span() * PLANET_RADIUS * center_latitude.cos();
+    let height_span = (bounds.north() - bounds.south()) * PLANET_RADIUS;
+    let columns = ((width / spacing).ceil() as usize + 1).max(2);
+    let rows = ((height_span / spacing).ceil() as usize + 1).max(2);
+
+    let sea_level = mapfile.planet().sea_level;
+    let mut data: Vec<[f32; 2]> = Vec::with_capacity(DIRECTIONS * columns * rows);
+    for row in 0..rows {
+        progress_callback("Computing skylines... ", row, rows);
+        let latitude =
+            bounds.south() + (bounds.north() - bounds.south()) * row as f64 / (rows - 1) as f64;
+        for column in 0..columns {
+            let longitude =
+                bounds.west() + bounds.longitude_span() * column as f64 / (columns - 1) as f64;
+            let (latitude, longitude) = coordinates::normalize_lat_long(latitude, longitude);
+            data.extend(compute(latitude, longitude, sea_level, &mut height)?);
+        }
+    }
+
+    mapfile.write_texture(
+        TEXTURE_NAME,
+        TextureDescriptor {
+            width: DIRECTIONS as u32,
+            height: columns as u32,
+            depth: rows as u32,
+            format: TextureFormat::RG32F,
+            bytes: data.len() * 8,
+        },
+        bytemuck::cast_slice(&data),
+    )?;
+    mapfile.set_skyline_bounds(Some(bounds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skyline_towards_ridge() {
+        // A 3000 meter ridge running north-south about 50 km east of a viewpoint at sea level.
+        let ridge = |_: f64, longitude: f64| {
+            let east = longitude * PLANET_RADIUS;
+            Ok(if (east - 50000.0).abs() < 2000.0 { 3000.0 } else { 0.0 })
+        };
+        let skyline = compute(0.0, 0.0, Some(0.0), ridge).unwrap();
+        assert_eq!(skyline.len(), DIRECTIONS);
+
+        let east = skyline[DIRECTIONS / 4];
+        assert_eq!(east[0], 3000.0);
+        assert!((east[1] - 50000.0).abs() < 3000.0);
+
+        // Looking west there is only sea.
+        assert_eq!(skyline[3 * DIRECTIONS / 4][1], 0.0);
+    }
+}
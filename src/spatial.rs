@@ -0,0 +1,261 @@
+use crate::camera::Camera;
+use crate::coordinates::PLANET_RADIUS;
+use crate::terrain::quadtree::node::VNode;
+use crate::vector::VectorTileId;
+use cgmath::{InnerSpace, Matrix4, Vector3};
+use fnv::FnvHashMap;
+
+/// Identifies an object stored in a `QuadSpatialIndex`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SpatialObjectId(u64);
+
+struct Object<T> {
+    position: Vector3<f64>,
+    node: VNode,
+    value: T,
+}
+
+/// Buckets application objects by the quadtree node they lie within, using the same cube-sphere
+/// subdivision as the terrain.
+///
+/// Each object is recorded in the node containing it at `max_level` and in every ancestor of that
+/// node. This lets range and frustum queries skip empty parts of the planet, and makes it cheap
+/// to find every object inside a node passed to the vector tile callback, so objects can be
+/// activated and deactivated exactly as terrain tiles stream in and out.
+pub struct QuadSpatialIndex<T> {
+    max_level: u8,
+    objects: FnvHashMap<SpatialObjectId, Object<T>>,
+    /// For each level, the objects inside every node that has any.
+    levels: Vec<FnvHashMap<VNode, Vec<SpatialObjectId>>>,
+    /// Range of altitudes of all objects inserted so far, used to bound the volume of each node.
+    altitude_range: (f32, f32),
+    next_id: u64,
+}
+impl<T> QuadSpatialIndex<T> {
+    /// Create an empty index that buckets objects down to nodes at `max_level`. Finer levels mean
+    /// tighter queries but more memory per object.
+    pub fn new(max_level: u8) -> Self {
+        assert!(max_level <= VNode::LEVEL_CELL_2CM);
+        Self {
+            max_level,
+            objects: FnvHashMap::default(),
+            levels: (0..=max_level).map(|_| FnvHashMap::default()).collect(),
+            altitude_range: (0.0, 0.0),
+            next_id: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// Add an object at the given world space position.
+    pub fn insert(&mut self, position: mint::Point3<f64>, value: T) -> SpatialObjectId {
+        let id = SpatialObjectId(self.next_id);
+        self.next_id += 1;
+
+        let position = Vector3::new(position.x, position.y, position.z);
+        let node = self.node_containing(position);
+        self.add_to_nodes(id, node);
+        self.include_altitude(position);
+        self.objects.insert(id, Object { position, node, value });
+        id
+    }
+
+    /// Remove an object, returning its value.
+    pub fn remove(&mut self, id: SpatialObjectId) -> Option<T> {
+        let object = self.objects.remove(&id)?;
+        self.remove_from_nodes(id, object.node);
+        Some(object.value)
+    }
+
+    /// Move an object to a new world space position.
+    pub fn set_position(&mut self, id: SpatialObjectId, position: mint::Point3<f64>) {
+        let position = Vector3::new(position.x, position.y, position.z);
+        let node = self.node_containing(position);
+        let old_node = match self.objects.get_mut(&id) {
+            Some(object) => {
+                object.position = position;
+                std::mem::replace(&mut object.node, node)
+            }
+            None => return,
+        };
+        if old_node != node {
+            self.remove_from_nodes(id, old_node);
+            self.add_to_nodes(id, node);
+        }
+        self.include_altitude(position);
+    }
+
+    pub fn get(&self, id: SpatialObjectId) -> Option<&T> {
+        self.objects.get(&id).map(|o| &o.value)
+    }
+
+    pub fn get_mut(&mut self, id: SpatialObjectId) -> Option<&mut T> {
+        self.objects.get_mut(&id).map(|o| &mut o.value)
+    }
+
+    pub fn position(&self, id: SpatialObjectId) -> Option<mint::Point3<f64>> {
+        self.objects.get(&id).map(|o| mint::Point3 {
+            x: o.position.x,
+            y: o.position.y,
+            z: o.position.z,
+        })
+    }
+
+    /// The node at `max_level` that an object is bucketed in.
+    pub fn node(&self, id: SpatialObjectId) -> Option<VectorTileId> {
+        self.objects.get(&id).map(|o| VectorTileId(o.node))
+    }
+
+    /// All objects inside the given node, which may be at any level.
+    pub fn in_node(&self, node: VectorTileId) -> Vec<(SpatialObjectId, &T)> {
+        let node = node.0;
+        let level = node.level().min(self.max_level);
+        let mut bucket = node;
+        while bucket.level() > level {
+            bucket = bucket.parent().unwrap().0;
+        }
+
+        self.levels[level as usize]
+            .get(&bucket)
+            .into_iter()
+            .flatten()
+            .map(|id| (*id, &self.objects[id]))
+            .filter(|(_, o)| {
+                node.level() <= self.max_level || Self::node_at(o.position, node.level()) == node
+            })
+            .map(|(id, o)| (id, &o.value))
+            .collect()
+    }
+
+    /// All objects within `radius` meters of a world space point.
+    pub fn within_radius(
+        &self,
+        center: mint::Point3<f64>,
+        radius: f64,
+    ) -> Vec<(SpatialObjectId, &T)> {
+        let center = Vector3::new(center.x, center.y, center.z);
+        self.query(
+            |node, range| node.distance2(center, range) <= radius * radius,
+            |p| (p - center).magnitude2() <= radius * radius,
+        )
+    }
+
+    /// All objects inside the view frustum of `camera`, for a frame with the given aspect ratio.
+    pub fn in_frustum(&self, camera: &Camera, aspect: f64) -> Vec<(SpatialObjectId, &T)> {
+        let view_proj = Matrix4::from(camera.view_proj(aspect)).cast::<f64>().unwrap();
+        let camera = Vector3::new(camera.position.x, camera.position.y, camera.position.z);
+        self.query(
+            |node, range| node.in_frustum(range, camera, &view_proj),
+            |p| {
+                let c = view_proj * (p - camera).extend(1.0);
+                c.w > 0.0 && c.x.abs() <= c.w && c.y.abs() <= c.w
+            },
+        )
+    }
+
+    /// Walk down from the roots through nodes that contain objects and pass `visit_node`, then
+    /// return the objects in the remaining nodes at `max_level` that pass `visit_object`.
+    fn query<N, O>(&self, visit_node: N, visit_object: O) -> Vec<(SpatialObjectId, &T)>
+    where
+        N: Fn(VNode, (f32, f32)) -> bool,
+        O: Fn(Vector3<f64>) -> bool,
+    {
+        let mut results = Vec::new();
+        let mut pending: Vec<VNode> = VNode::roots().to_vec();
+        while let Some(node) = pending.pop() {
+            let ids = match self.levels[node.level() as usize].get(&node) {
+                Some(ids) if visit_node(node, self.altitude_range) => ids,
+                _ => continue,
+            };
+            if node.level() < self.max_level {
+                pending.extend_from_slice(&node.children());
+            } else {
+                for id in ids {
+                    let object = &self.objects[id];
+                    if visit_object(object.position) {
+                        results.push((*id, &object.value));
+                    }
+                }
+            }
+        }
+        results
+    }
+
+    fn node_at(position: Vector3<f64>, level: u8) -> VNode {
+        let cspace = position / position.x.abs().max(position.y.abs()).max(position.z.abs());
+        VNode::from_cspace(cspace, level).0
+    }
+
+    fn node_containing(&self, position: Vector3<f64>) -> VNode {
+        Self::node_at(position, self.max_level)
+    }
+
+    fn include_altitude(&mut self, position: Vector3<f64>) {
+        let altitude = (position.magnitude() - PLANET_RADIUS) as f32;
+        self.altitude_range =
+            (self.altitude_range.0.min(altitude), self.altitude_range.1.max(altitude));
+    }
+
+    /// Record an object in `node` and all of its ancestors.
+    fn add_to_nodes(&mut self, id: SpatialObjectId, node: VNode) {
+        let mut node = Some(node);
+        while let Some(n) = node {
+            self.levels[n.level() as usize].entry(n).or_default().push(id);
+            node = n.parent().map(|p| p.0);
+        }
+    }
+
+    fn remove_from_nodes(&mut self, id: SpatialObjectId, node: VNode) {
+        let mut node = Some(node);
+        while let Some(n) = node {
+            let level = &mut self.levels[n.level() as usize];
+            if let Some(ids) = level.get_mut(&n) {
+                ids.retain(|i| *i != id);
+                if ids.is_empty() {
+                    level.remove(&n);
+                }
+            }
+            node = n.parent().map(|p| p.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coordinates;
+
+    #[test]
+    fn radius_and_node_queries() {
+        let point = |lat: f64, long: f64| {
+            let p = coordinates::polar_to_ecef(Vector3::new(lat, long, 100.0));
+            mint::Point3 { x: p.x, y: p.y, z: p.z }
+        };
+
+        let mut index = QuadSpatialIndex::new(VNode::LEVEL_CELL_1KM);
+        let a = index.insert(point(0.5, 0.5), "a");
+        let b = index.insert(point(0.5, 0.5001), "b");
+        let c = index.insert(point(-0.5, 2.0), "c");
+
+        let mut near: Vec<_> = index.within_radius(point(0.5, 0.5), 1000.0);
+        near.sort_by_key(|(id, _)| id.0);
+        assert_eq!(near, vec![(a, &"a"), (b, &"b")]);
+
+        let node = VectorTileId::containing(-0.5, 2.0, 3);
+        assert_eq!(index.in_node(node), vec![(c, &"c")]);
+
+        index.set_position(c, point(0.5, 0.50005));
+        assert_eq!(index.in_node(node), vec![]);
+        assert_eq!(index.within_radius(point(0.5, 0.5), 1000.0).len(), 3);
+
+        assert_eq!(index.remove(a), Some("a"));
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.within_radius(point(0.5, 0.5), 1.0).len(), 0);
+    }
+}
@@ -1,26 +1,37 @@
 use crate::cache::LayerType;
 use crate::generate::heightmap::HeightmapCache;
-use crate::mapfile::MapFile;
+use crate::mapfile::{AlbedoBlend, MapFile, TileBytes};
+use crate::places::Place;
 use crate::terrain::quadtree::node::VNode;
 use anyhow::Error;
 use futures::{FutureExt, StreamExt};
+use log::{debug, error, trace, warn};
 use std::io::{Cursor, Read};
 use std::sync::Arc;
 use std::thread;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
-#[derive(Copy, Clone, Debug)]
+/// Log target for tile streaming. See the crate documentation.
+const LOG_TARGET: &str = "terra::stream";
+
+#[derive(Clone, Debug)]
 struct TileRequest {
     node: VNode,
     layer: LayerType,
+    /// Albedo epochs to crossfade between, if any.
+    albedo_blend: Option<AlbedoBlend>,
 }
 
 #[derive(Debug)]
 pub(crate) enum TileResult {
-    Heightmaps(VNode, Arc<Vec<i16>>),
+    /// Heights of a tile, along with the geometric error recorded when it was generated.
+    Heightmaps(VNode, Arc<Vec<i16>>, Option<f32>),
     Albedo(VNode, Vec<u8>),
     Roughness(VNode, Vec<u8>),
+    Normals(VNode, TileBytes),
+    Vector(VNode, Vec<u8>),
+    Places(VNode, Vec<Place>),
 }
 impl TileResult {
     pub fn layer(&self) -> LayerType {
@@ -28,26 +39,35 @@ impl TileResult {
             TileResult::Heightmaps(..) => LayerType::Heightmaps,
             TileResult::Albedo(..) => LayerType::Albedo,
             TileResult::Roughness(..) => LayerType::Roughness,
+            TileResult::Normals(..) => LayerType::Normals,
+            TileResult::Vector(..) => LayerType::Vector,
+            TileResult::Places(..) => LayerType::Places,
         }
     }
     pub fn node(&self) -> VNode {
         match self {
             TileResult::Heightmaps(node, ..)
             | TileResult::Albedo(node, ..)
-            | TileResult::Roughness(node, ..) => *node,
+            | TileResult::Roughness(node, ..)
+            | TileResult::Normals(node, ..)
+            | TileResult::Vector(node, ..)
+            | TileResult::Places(node, ..) => *node,
         }
     }
 }
 
 pub(crate) struct TileStreamerEndpoint {
     sender: UnboundedSender<TileRequest>,
+    write_backs: UnboundedSender<TileWriteBack>,
     receiver: crossbeam::channel::Receiver<TileResult>,
     join_handle: Option<thread::JoinHandle<Result<(), Error>>>,
     num_inflight: usize,
+    albedo_blend: Option<AlbedoBlend>,
 }
 impl TileStreamerEndpoint {
     pub(crate) fn new(mapfile: Arc<MapFile>) -> Result<Self, Error> {
         let (sender, requests) = unbounded_channel();
+        let (write_backs, writes) = unbounded_channel();
         let (results, receiver) = crossbeam::channel::unbounded();
 
         let rt = Runtime::new()?;
@@ -55,6 +75,7 @@ impl TileStreamerEndpoint {
             rt.block_on(
                 TileStreamer {
                     requests,
+                    writes,
                     results,
                     heightmap_tiles: HeightmapCache::new(
                         mapfile.layers()[LayerType::Heightmaps].clone(),
@@ -66,11 +87,22 @@ impl TileStreamerEndpoint {
             )
         }));
 
-        Ok(Self { sender, receiver, join_handle, num_inflight: 0 })
+        Ok(Self { sender, write_backs, receiver, join_handle, num_inflight: 0, albedo_blend: None })
+    }
+
+    pub(crate) fn albedo_blend(&self) -> Option<&AlbedoBlend> {
+        self.albedo_blend.as_ref()
+    }
+
+    /// Set the albedo epochs that subsequently requested albedo tiles are blended from.
+    pub(crate) fn set_albedo_blend(&mut self, blend: Option<AlbedoBlend>) {
+        self.albedo_blend = blend;
     }
 
     pub(crate) fn request_tile(&mut self, node: VNode, layer: LayerType) {
-        if let Err(_) = self.sender.send(TileRequest { node, layer }) {
+        let albedo_blend =
+            if layer == LayerType::Albedo { self.albedo_blend.clone() } else { None };
+        if let Err(_) = self.sender.send(TileRequest { node, layer, albedo_blend }) {
             // The worker thread has panicked (we still have the sender open, so that cannot be why
             // it exited). Join it to see what the panic message was.
             self.join_handle.take().unwrap().join().unwrap().expect("TileStreamer panicked");
@@ -79,6 +111,13 @@ impl TileStreamerEndpoint {
         self.num_inflight += 1;
     }
 
+    /// Save a tile that was generated on the GPU, so that it is streamed from disk instead of
+    /// being generated again in the future. Failures are ignored, since the tile can always be
+    /// regenerated.
+    pub(crate) fn write_back_tile(&mut self, node: VNode, layer: LayerType, data: Vec<u8>) {
+        let _ = self.write_backs.send(TileWriteBack { node, layer, data });
+    }
+
     pub(crate) fn try_complete(&mut self) -> Option<TileResult> {
         if let Ok(result) = self.receiver.try_recv() {
             self.num_inflight -= 1;
@@ -93,8 +132,15 @@ impl TileStreamerEndpoint {
     }
 }
 
+struct TileWriteBack {
+    node: VNode,
+    layer: LayerType,
+    data: Vec<u8>,
+}
+
 struct TileStreamer {
     requests: UnboundedReceiver<TileRequest>,
+    writes: UnboundedReceiver<TileWriteBack>,
     results: crossbeam::channel::Sender<TileResult>,
     mapfile: Arc<MapFile>,
     heightmap_tiles: HeightmapCache,
@@ -102,43 +148,88 @@ struct TileStreamer {
 
 impl TileStreamer {
     async fn run(self) -> Result<(), Error> {
-        let TileStreamer { mut requests, results, mapfile, mut heightmap_tiles } = self;
+        let TileStreamer { mut requests, mut writes, results, mapfile, mut heightmap_tiles } = self;
         let mapfile = &*mapfile;
 
+        debug!(target: LOG_TARGET, "tile streamer started");
         let mut pending = futures::stream::futures_unordered::FuturesUnordered::new();
         loop {
             futures::select! {
                 request = requests.recv().fuse() => if let Some(request) = request {
+                    trace!(target: LOG_TARGET, "requested {:?} tile {:?}", request.layer, request.node);
                     match request.layer {
                         LayerType::Heightmaps => {
                             let fut = heightmap_tiles.get_tile(mapfile, request.node);
 
                             pending.push(async move {
-                                Ok(TileResult::Heightmaps(request.node, fut.await?))
+                                let heights = fut.await?;
+                                let geometric_error = mapfile.geometric_error(request.node);
+                                Ok(TileResult::Heightmaps(request.node, heights, geometric_error))
                             }.boxed());
                         }
                         LayerType::Albedo => pending.push(async move {
-                            let raw_data = mapfile.read_tile(request.layer, request.node).await?;
-                            let data = tokio::task::spawn_blocking(move || {
-                                Ok::<Vec<u8>, Error>(image::load_from_memory(&raw_data)?.to_rgba8().to_vec())
-                            }).await??;
+                            let data = match request.albedo_blend {
+                                None => decode_albedo(mapfile.read_tile_bytes(request.layer, request.node).await?).await?,
+                                Some(blend) => {
+                                    let older = TileBytes::Owned(mapfile.read_albedo_epoch_tile(&blend.older, request.node).await?);
+                                    if blend.weight == 0.0 {
+                                        decode_albedo(older).await?
+                                    } else {
+                                        let newer = TileBytes::Owned(mapfile.read_albedo_epoch_tile(&blend.newer, request.node).await?);
+                                        blend.mix(&decode_albedo(older).await?, &decode_albedo(newer).await?)
+                                    }
+                                }
+                            };
                             Ok::<TileResult, Error>(TileResult::Albedo(request.node, data))
                         }.boxed()),
                         LayerType::Roughness => pending.push(async move {
                             let mut data = Vec::new();
-                            let raw_data = mapfile.read_tile(request.layer, request.node).await?;
-                            lz4::Decoder::new(Cursor::new(&raw_data))?.read_to_end(&mut data)?;
+                            let raw_data = mapfile.read_tile_bytes(request.layer, request.node).await?;
+                            lz4::Decoder::new(Cursor::new(&*raw_data))?.read_to_end(&mut data)?;
                             Ok::<TileResult, Error>(TileResult::Roughness(request.node, data))
                         }.boxed()),
-                        LayerType::Normals | LayerType::Displacements => unreachable!(),
+                        LayerType::Normals => pending.push(async move {
+                            let data = mapfile.read_tile_bytes(request.layer, request.node).await?;
+                            Ok::<TileResult, Error>(TileResult::Normals(request.node, data))
+                        }.boxed()),
+                        LayerType::Vector => pending.push(async move {
+                            let data = mapfile.read_tile(request.layer, request.node).await?;
+                            Ok::<TileResult, Error>(TileResult::Vector(request.node, data))
+                        }.boxed()),
+                        LayerType::Places => pending.push(async move {
+                            let data = mapfile.read_tile(request.layer, request.node).await?;
+                            Ok::<TileResult, Error>(TileResult::Places(request.node, bincode::deserialize(&data)?))
+                        }.boxed()),
+                        _ => unreachable!(),
+                    }
+                },
+                write = writes.recv().fuse() => if let Some(TileWriteBack { node, layer, data }) = write {
+                    match tokio::task::block_in_place(|| mapfile.write_tile(layer, node, &data, false)) {
+                        Ok(()) => trace!(target: LOG_TARGET, "saved generated {:?} tile {:?}", layer, node),
+                        Err(e) => warn!(target: LOG_TARGET, "failed to save generated {:?} tile {:?}: {:#}", layer, node, e),
                     }
                 },
-                tile_result = pending.select_next_some() => {
-                    results.send(tile_result?)?;
+                tile_result = pending.select_next_some() => match tile_result {
+                    Ok(result) => {
+                        trace!(target: LOG_TARGET, "streamed {:?} tile {:?}", result.layer(), result.node());
+                        results.send(result)?;
+                    }
+                    Err(e) => {
+                        error!(target: LOG_TARGET, "tile streaming failed: {:#}", e);
+                        return Err(e);
+                    }
                 },
                 complete => break,
             }
         }
+        debug!(target: LOG_TARGET, "tile streamer stopped");
         Ok(())
     }
 }
+
+async fn decode_albedo(raw_data: TileBytes) -> Result<Vec<u8>, Error> {
+    tokio::task::spawn_blocking(move || {
+        Ok::<_, Error>(image::load_from_memory(&raw_data)?.to_rgba8().to_vec())
+    })
+    .await?
+}
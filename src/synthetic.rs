@@ -0,0 +1,176 @@
+//! A small procedurally generated planet, used by tests in place of downloaded datasets.
+
+use crate::cache::{LayerParams, LayerType, TextureFormat};
+use crate::generate::{heightmap::compress_heightmap_tile, EARTH_RADIUS};
+use crate::terrain::quadtree::node::VNode;
+use cgmath::{InnerSpace, Vector3};
+use image::png::PngEncoder;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::io::Write;
+use vec_map::VecMap;
+
+/// Number of sinusoidal bumps summed to produce the terrain.
+const NUM_WAVES: usize = 8;
+
+pub(crate) struct SyntheticPlanet {
+    /// Offset from terra's reference sphere to the synthetic planet's sea level.
+    base_elevation: f32,
+    /// Direction, spatial frequency, phase and amplitude of each wave.
+    waves: Vec<(Vector3<f64>, f64, f64, f32)>,
+}
+impl SyntheticPlanet {
+    /// Highest level for which heightmap tiles exist.
+    pub const HEIGHTMAP_LEVELS: u8 = 2;
+    /// Highest level for which albedo tiles exist.
+    pub const ALBEDO_LEVELS: u8 = 1;
+
+    /// Create a planet with the given mean radius. The quadtree geometry is tied to the radius of
+    /// the earth so `radius` must be close to it; the difference is applied as an elevation offset.
+    pub fn new(radius: f64, seed: u64) -> Self {
+        let base_elevation = radius - EARTH_RADIUS;
+        assert!(base_elevation.abs() < 10_000.0, "synthetic planet radius too far from earth's");
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let waves = (0..NUM_WAVES)
+            .map(|i| {
+                let direction = Vector3::new(
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                    rng.gen_range(-1.0..1.0),
+                )
+                .normalize();
+                let frequency = 2.0 * (i + 1) as f64;
+                let phase = rng.gen_range(0.0..std::f64::consts::TAU);
+                let amplitude = 2000.0 / (i + 1) as f32;
+                (direction, frequency, phase, amplitude)
+            })
+            .collect();
+
+        Self { base_elevation: base_elevation as f32, waves }
+    }
+
+    /// Layer parameters with much smaller tiles than the real map file, to keep tests fast.
+    pub fn layers() -> VecMap<LayerParams> {
+        let layer = |layer_type: LayerType,
+                     name: &str,
+                     texture_resolution,
+                     texture_border_size,
+                     format,
+                     mipmaps| {
+            (
+                layer_type.index(),
+                LayerParams {
+                    layer_type,
+                    name: name.to_owned(),
+                    texture_resolution,
+                    texture_border_size,
+                    texture_format: format,
+                    tiles_generated_per_frame: 16,
+                    mipmaps,
+                    #[cfg(feature = "render")]
+                    generator: None,
+                },
+            )
+        };
+        vec![
+            layer(LayerType::Heightmaps, "heightmaps", 41, 4, TextureFormat::R32F, false),
+            layer(LayerType::Displacements, "displacements", 17, 0, TextureFormat::RGBA32F, false),
+            layer(LayerType::Albedo, "albedo", 36, 2, TextureFormat::RGBA8, true),
+            layer(LayerType::Roughness, "roughness", 36, 2, TextureFormat::BC4, false),
+            layer(LayerType::Normals, "normals", 36, 2, TextureFormat::BC5, true),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    /// Whether a base tile exists for `node` in `layer`.
+    pub fn has_tile(layer: LayerType, node: VNode) -> bool {
+        match layer {
+            LayerType::Heightmaps => node.level() <= Self::HEIGHTMAP_LEVELS,
+            LayerType::Albedo => node.level() <= Self::ALBEDO_LEVELS,
+            LayerType::Roughness => node.level() == 0,
+            _ => false,
+        }
+    }
+
+    /// Elevation at a point given in cspace.
+    pub fn elevation(&self, cspace: Vector3<f64>) -> f32 {
+        let p = cspace.normalize();
+        self.base_elevation
+            + self
+                .waves
+                .iter()
+                .map(|&(d, f, phase, a)| a * (p.dot(d) * f + phase).sin() as f32)
+                .sum::<f32>()
+    }
+
+    pub fn heightmap(&self, layer: &LayerParams, node: VNode) -> Vec<i16> {
+        let resolution = layer.texture_resolution;
+        (0..resolution * resolution)
+            .map(|i| {
+                let cspace = node.grid_position_cspace(
+                    (i % resolution) as i32,
+                    (i / resolution) as i32,
+                    layer.texture_border_size as u16,
+                    resolution as u16,
+                );
+                self.elevation(cspace).round() as i16
+            })
+            .collect()
+    }
+
+    /// Minimum and maximum elevation of the heightmap tile for `node`.
+    pub fn elevation_range(&self, layer: &LayerParams, node: VNode) -> (f32, f32) {
+        self.heightmap(layer, node)
+            .iter()
+            .fold((f32::MAX, f32::MIN), |(min, max), &h| (min.min(h as f32), max.max(h as f32)))
+    }
+
+    /// Produce the encoded contents of a tile, exactly as they would be stored on disk.
+    pub fn tile(&self, layer: &LayerParams, node: VNode) -> Vec<u8> {
+        let resolution = layer.texture_resolution as usize;
+        match layer.layer_type {
+            LayerType::Heightmaps => {
+                let heights = self.heightmap(layer, node);
+                let parent = node.parent().map(|(p, i)| (i, self.heightmap(layer, p)));
+                compress_heightmap_tile(
+                    resolution,
+                    layer.texture_border_size as usize,
+                    0,
+                    &heights,
+                    parent.as_ref().map(|(i, p)| (*i, &**p)),
+                )
+            }
+            LayerType::Albedo => {
+                let mut data = Vec::with_capacity(resolution * resolution * 4);
+                for y in 0..resolution {
+                    for x in 0..resolution {
+                        let cspace = node.cell_position_cspace(
+                            x as i32,
+                            y as i32,
+                            layer.texture_border_size as u16,
+                            resolution as u16,
+                        );
+                        let shade = (self.elevation(cspace) / 40.0).max(0.0).min(255.0) as u8;
+                        data.extend_from_slice(&[shade / 2, 64 + shade / 2, shade / 4, 255]);
+                    }
+                }
+                let mut encoded = Vec::new();
+                PngEncoder::new(&mut encoded)
+                    .encode(&data, resolution as u32, resolution as u32, image::ColorType::Rgba8)
+                    .unwrap();
+                encoded
+            }
+            LayerType::Roughness => {
+                // Every BC4 block is a constant mid-range roughness.
+                let blocks = (resolution / 4) * (resolution / 4);
+                let data: Vec<u8> =
+                    (0..blocks).flat_map(|_| vec![128, 128, 0, 0, 0, 0, 0, 0]).collect();
+                let mut e = lz4::EncoderBuilder::new().build(Vec::new()).unwrap();
+                e.write_all(&data).unwrap();
+                e.finish().0
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -1,5 +1,7 @@
+#[cfg(feature = "render")]
 use rand::distributions::Distribution;
 use rand::{self, Rng};
+#[cfg(feature = "render")]
 use rand_distr::Normal;
 
 use std::f32::consts::PI;
@@ -144,6 +146,7 @@ pub fn perlin_noise(grid_resolution: usize, grid_spacing: usize) -> Heightmap<f3
 /// Evaluate wavelet noise on a grid with the given resolution and grid spacing. ///
 /// The output heightmap will have a width and height of `grid_resolution` * `grid_spacing`. Values
 /// will have a mean of approximately zero, and a variance of 1.
+#[cfg(feature = "render")]
 pub fn wavelet_noise(grid_resolution: usize, grid_spacing: usize) -> Heightmap<f32> {
     // See: https://graphics.pixar.com/library/WaveletNoise/paper.pdf
 
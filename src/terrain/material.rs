@@ -1,270 +1,267 @@
-use std::io::{Cursor, Read};
-
-use anyhow::Error;
-use image::{self, GenericImage, GenericImageView};
-use zip::ZipArchive;
-
-use cache::{AssetLoadContext, GeneratedAsset, WebAsset};
-use srgb::{LINEAR_TO_SRGB, SRGB_TO_LINEAR};
-
-#[derive(Clone, Copy)]
-pub enum MaterialType {
-    Dirt = 0,
-    Grass = 1,
-    GrassRocky = 2,
-    Rock = 3,
-    RockSteep = 4,
+use crate::biome::Biome;
+use crate::srgb::SRGB_TO_LINEAR;
+use anyhow::{ensure, Error};
+use image::imageops::FilterType;
+use image::RgbaImage;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+
+/// Maximum number of materials in a `MaterialSet`. Must match `MAX_MATERIALS` in terrain.frag.
+pub(crate) const MAX_MATERIALS: usize = 16;
+
+/// Materials tile seamlessly when their scale evenly divides this distance in meters.
+pub(crate) const GROUND_PERIOD: f64 = 1024.0;
+
+/// Depth in meters of the relief added by parallax occlusion mapping.
+pub(crate) const PARALLAX_DEPTH: f32 = 0.05;
+/// Distance from the camera in meters beyond which parallax occlusion mapping is skipped.
+pub(crate) const PARALLAX_DISTANCE: f32 = 30.0;
+
+/// How strongly a material appears on each class of land cover. Land cover is estimated from
+/// the albedo of the terrain the same way as for `FractalDetailTable`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LandCoverWeights {
+    pub plains: f32,
+    pub forest: f32,
+    pub desert: f32,
+    pub rock: f32,
+    pub ice: f32,
+}
+impl Default for LandCoverWeights {
+    /// Appear equally on all land cover.
+    fn default() -> Self {
+        Self { plains: 1.0, forest: 1.0, desert: 1.0, rock: 1.0, ice: 1.0 }
+    }
 }
 
-struct MaterialTypeRaw(MaterialType);
-impl WebAsset for MaterialTypeRaw {
-    type Type = MaterialRaw;
+/// A tiling PBR material drawn on the ground close to the camera.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroundMaterial {
+    /// Image file holding the sRGB albedo of the material. Only variation around its average
+    /// color is drawn, so the large scale color still comes from the albedo tiles.
+    pub albedo: PathBuf,
+    /// Optional tangent space normal map, with +Z pointing away from the surface.
+    pub normals: Option<PathBuf>,
+    /// Roughness of the material, or `None` to keep the roughness of the terrain.
+    pub roughness: Option<f32>,
+    /// Size in meters covered by one repetition of the textures. Should evenly divide 1024 so
+    /// that the textures line up across tile boundaries.
+    pub scale: f32,
+    pub land_cover: LandCoverWeights,
+    /// Range of slopes in radians that the material appears on. Weights fade out over a tenth of
+    /// a radian at either end.
+    pub slope: (f32, f32),
+    /// Biomes that the material is restricted to, or empty to appear in all of them. Biomes are
+    /// classified per pixel the same way as the biome layer.
+    pub biomes: Vec<Biome>,
+}
 
-    fn url(&self) -> String {
-        match self.0 {
-            _ => "https://opengameart.org/sites/default/files/terrain_0.zip",
-        }.to_owned()
+/// Ground materials splatted onto the terrain according to land cover and slope. Pass to
+/// `Terrain::set_materials`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialSet {
+    materials: Vec<GroundMaterial>,
+    resolution: u32,
+    fade_distance: f32,
+}
+impl MaterialSet {
+    /// Create an empty set, whose textures are resampled to `resolution` pixels on a side.
+    pub fn new(resolution: u32) -> Self {
+        Self { materials: Vec::new(), resolution, fade_distance: 2000.0 }
     }
 
-    fn filename(&self) -> String {
-        let name = match self.0 {
-            _ => "terrain.zip",
-        };
-        format!("materials/raw/{}", name)
+    /// Add a material. At most 16 materials may be used.
+    pub fn with_material(mut self, material: GroundMaterial) -> Self {
+        self.materials.push(material);
+        self
     }
 
-    fn parse(&self, _context: &mut AssetLoadContext, data: Vec<u8>) -> Result<Self::Type, Error> {
-        let name = match self.0 {
-            MaterialType::Dirt => "ground_mud2_d.jpg",
-            MaterialType::Grass => "grass_green_d.jpg",
-            MaterialType::GrassRocky => "grass_rocky_d.jpg",
-            MaterialType::Rock => "mntn_gray_d.jpg",
-            MaterialType::RockSteep => "mntn_gray_d.jpg",
-        };
-
-        let mut raw = MaterialRaw::default();
-        let mut zip = ZipArchive::new(Cursor::new(data))?;
-        for i in 0..zip.len() {
-            let mut file = zip.by_index(i)?;
-            if file.name().contains(name) {
-                raw.albedo.clear();
-                file.read_to_end(&mut raw.albedo)?;
-                return Ok(raw);
-            }
-        }
-        Err(format_err!("Material file not found {}", name))
+    /// Distance from the camera in meters at which the materials have faded out completely.
+    /// Defaults to 2000.
+    pub fn with_fade_distance(mut self, meters: f32) -> Self {
+        self.fade_distance = meters;
+        self
     }
-}
-
-struct MaterialTypeFiltered(MaterialType);
-impl GeneratedAsset for MaterialTypeFiltered {
-    type Type = Material;
 
-    fn filename(&self) -> String {
-        let name = match self.0 {
-            MaterialType::Dirt => "dirt.bin",
-            MaterialType::Grass => "grass.bin",
-            MaterialType::GrassRocky => "grassrocky.bin",
-            MaterialType::Rock => "rock.bin",
-            MaterialType::RockSteep => "rocksteep.bin",
-        };
-        format!("materials/filtered/{}", name)
+    pub fn materials(&self) -> &[GroundMaterial] {
+        &self.materials
     }
 
-    fn generate(&self, context: &mut AssetLoadContext) -> Result<Self::Type, Error> {
-        context.set_progress_and_total(0, 7);
-
-        let resolution = 1024;
-        let mipmaps = 11;
-
-        let raw = MaterialTypeRaw(self.0).load(context)?;
-        let mut albedo_image =
-            image::DynamicImage::ImageRgba8(image::load_from_memory(&raw.albedo[..])?.to_rgba());
-        if albedo_image.width() != resolution || albedo_image.height() != resolution {
-            albedo_image =
-                albedo_image.resize_exact(resolution, resolution, image::FilterType::Triangle);
-        }
-
-        let albedo_image_blurred = {
-            let sigma = 8;
-            context.set_progress(1);
-            let tiled = image::RgbaImage::from_fn(
-                resolution + 4 * sigma,
-                resolution + 4 * sigma,
-                |x, y| {
-                    albedo_image.get_pixel(
-                        (x + resolution - 2 * sigma) % resolution,
-                        (y + resolution - 2 * sigma) % resolution,
-                    )
-                },
-            );
-            context.set_progress(2);
-            let mut tiled = image::DynamicImage::ImageRgba8(tiled).blur(sigma as f32);
-            context.set_progress(3);
-            tiled.crop(2 * sigma, 2 * sigma, resolution, resolution)
-        };
-
-        context.set_progress(4);
-        let mut albedo_sum = [0u64; 4];
-        for (_, _, color) in albedo_image.pixels() {
-            for i in 0..4 {
-                albedo_sum[i] += color[i] as u64;
+    /// Read every material from disk and upload them as texture arrays.
+    pub(crate) fn load(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<GpuMaterials, Error> {
+        ensure!(self.materials.len() <= MAX_MATERIALS, "At most {} materials", MAX_MATERIALS);
+        ensure!(self.resolution.is_power_of_two(), "Material resolution must be a power of two");
+
+        let count = self.materials.len().max(1) as u32;
+        let albedo = create_array_texture(device, self.resolution, count, "albedo", true);
+        let normals = create_array_texture(device, self.resolution, count, "normals", false);
+
+        let mut uniforms = MaterialUniforms::empty();
+        uniforms.settings = [self.materials.len() as f32, self.fade_distance, 0.0, 0.0];
+        for (i, material) in self.materials.iter().enumerate() {
+            let image = image::open(&material.albedo)?.to_rgba8();
+            let levels = mip_chain(image, self.resolution);
+            upload_layer(queue, &albedo, i as u32, &levels);
+            uniforms.average_albedo[i] = average_linear(levels.last().unwrap());
+
+            if let Some(ref path) = material.normals {
+                let image = image::open(path)?.to_rgba8();
+                upload_layer(queue, &normals, i as u32, &mip_chain(image, self.resolution));
+                uniforms.surface[i][1] = 1.0;
             }
-        }
-        let num_pixels = (albedo_image.width() * albedo_image.height()) as u64;
-        let average_albedo: [u8; 4] = [
-            (albedo_sum[0] / num_pixels) as u8,
-            (albedo_sum[1] / num_pixels) as u8,
-            (albedo_sum[2] / num_pixels) as u8,
-            (albedo_sum[3] / num_pixels) as u8,
-        ];
 
-        context.set_progress(5);
-        for (x, y, blurred_color) in albedo_image_blurred.pixels() {
-            let mut color = albedo_image.get_pixel(x, y);
-            for i in 0..4 {
-                use image::math::utils::clamp;
-                color[i] = clamp(
-                    (color[i] as i16) - (blurred_color[i] as i16) + (average_albedo[i] as i16),
-                    0,
-                    255,
-                ) as u8;
-            }
-            albedo_image.put_pixel(x, y, color);
+            let w = &material.land_cover;
+            uniforms.land_cover[i] = [w.plains, w.forest, w.desert, w.rock];
+            uniforms.params[i] = [w.ice, material.slope.0, material.slope.1, material.scale];
+            uniforms.surface[i][0] = material.roughness.unwrap_or(-1.0);
+            uniforms.surface[i][2] = match material.biomes.len() {
+                0 => -1.0,
+                _ => material.biomes.iter().fold(0, |mask, b| mask | b.bit_mask()) as f32,
+            };
         }
 
-        context.set_progress(6);
-        let mut albedo = Vec::new();
-        for level in 0..mipmaps {
-            let level_resolution = (resolution >> level) as u32;
-            if albedo_image.width() != level_resolution || albedo_image.height() != level_resolution
-            {
-                albedo_image = albedo_image.resize_exact(
-                    level_resolution,
-                    level_resolution,
-                    image::FilterType::Triangle,
-                );
-            }
+        Ok(GpuMaterials { albedo, normals, uniforms })
+    }
+}
 
-            albedo.push(
-                albedo_image.to_rgba().to_vec()[..]
-                    .chunks(4)
-                    .map(|c| [c[0], c[1], c[2], c[3]])
-                    .collect(),
-            );
+/// Textures and parameters of a `MaterialSet` after it has been loaded.
+pub(crate) struct GpuMaterials {
+    pub albedo: wgpu::Texture,
+    pub normals: wgpu::Texture,
+    pub uniforms: MaterialUniforms,
+}
+impl GpuMaterials {
+    /// Placeholder bound when no materials are in use.
+    pub fn empty(device: &wgpu::Device) -> Self {
+        Self {
+            albedo: create_array_texture(device, 1, 1, "albedo", true),
+            normals: create_array_texture(device, 1, 1, "normals", false),
+            uniforms: MaterialUniforms::empty(),
         }
-        context.set_progress(7);
-        Ok(Material {
-            resolution: resolution as u16,
-            mipmaps,
-            albedo,
-        })
     }
 }
 
-/// Holds the raw bytes of the image files for each map of a material.
-#[derive(Serialize, Deserialize, Default)]
-struct MaterialRaw {
-    albedo: Vec<u8>,
+/// Layout must match `MaterialBlock` in terrain.frag.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(crate) struct MaterialUniforms {
+    /// x: number of materials, y: fade distance, z: whether triplanar mapping is enabled.
+    pub settings: [f32; 4],
+    /// x: maximum parallax occlusion steps or zero if disabled, y: depth, z: distance.
+    pub parallax: [f32; 4],
+    /// Weights for plains, forest, desert and rock.
+    pub land_cover: [[f32; 4]; MAX_MATERIALS],
+    /// x: weight for ice, y: minimum slope, z: maximum slope, w: scale.
+    pub params: [[f32; 4]; MAX_MATERIALS],
+    /// Linear average of the albedo texture.
+    pub average_albedo: [[f32; 4]; MAX_MATERIALS],
+    /// x: roughness or -1, y: whether there is a normal map, z: bitmask of allowed biomes or -1.
+    pub surface: [[f32; 4]; MAX_MATERIALS],
 }
-
-#[derive(Serialize, Deserialize)]
-struct Material {
-    resolution: u16,
-    mipmaps: u8,
-
-    albedo: Vec<Vec<[u8; 4]>>,
+unsafe impl bytemuck::Pod for MaterialUniforms {}
+unsafe impl bytemuck::Zeroable for MaterialUniforms {}
+impl MaterialUniforms {
+    fn empty() -> Self {
+        bytemuck::Zeroable::zeroed()
+    }
 }
 
-pub struct MaterialSet<R: gfx::Resources> {
-    pub(crate) texture_view: gfx_core::handle::ShaderResourceView<R, [f32; 4]>,
-    pub(crate) _texture: gfx_core::handle::Texture<R, gfx_core::format::R8_G8_B8_A8>,
-    average_albedos: Vec<[u8; 4]>,
+fn create_array_texture(
+    device: &wgpu::Device,
+    resolution: u32,
+    layers: u32,
+    name: &str,
+    srgb: bool,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        size: wgpu::Extent3d {
+            width: resolution,
+            height: resolution,
+            depth_or_array_layers: layers,
+        },
+        format: if srgb {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        } else {
+            wgpu::TextureFormat::Rgba8Unorm
+        },
+        mip_level_count: resolution.trailing_zeros() + 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        usage: wgpu::TextureUsage::COPY_DST | wgpu::TextureUsage::SAMPLED,
+        label: Some(&format!("texture.materials.{}", name)),
+    })
 }
 
-impl<R: gfx::Resources> MaterialSet<R> {
-    pub(crate) fn load<F: gfx::Factory<R>, C: gfx_core::command::Buffer<R>>(
-        factory: &mut F,
-        encoder: &mut gfx::Encoder<R, C>,
-        context: &mut AssetLoadContext,
-    ) -> Result<Self, Error> {
-        let resolution = 1024;
-        let mipmaps = 11;
-
-        let materials = vec![
-            MaterialTypeFiltered(MaterialType::Dirt).load(context)?,
-            MaterialTypeFiltered(MaterialType::Grass).load(context)?,
-            MaterialTypeFiltered(MaterialType::GrassRocky).load(context)?,
-            MaterialTypeFiltered(MaterialType::Rock).load(context)?,
-            MaterialTypeFiltered(MaterialType::RockSteep).load(context)?,
-        ];
-
-        let mut average_albedos = Vec::new();
-
-        let texture = factory
-            .create_texture::<R8_G8_B8_A8>(
-                gfx::texture::Kind::D2Array(
-                    resolution,
-                    resolution,
-                    materials.len() as u16,
-                    gfx::texture::AaMode::Single,
-                ),
-                mipmaps,
-                gfx::memory::Bind::SHADER_RESOURCE,
-                gfx::memory::Usage::Dynamic,
-                Some(ChannelType::Srgb),
-            ).unwrap();
-
-        for (layer, material) in materials.iter().enumerate() {
-            assert_eq!(mipmaps, material.mipmaps);
-            assert_eq!(mipmaps as usize, material.albedo.len());
+fn upload_layer(queue: &wgpu::Queue, texture: &wgpu::Texture, layer: u32, levels: &[RgbaImage]) {
+    for (level, image) in levels.iter().enumerate() {
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: level as u32,
+                origin: wgpu::Origin3d { x: 0, y: 0, z: layer },
+            },
+            image.as_raw(),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(NonZeroU32::new(image.width() * 4).unwrap()),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: image.width(),
+                height: image.height(),
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}
 
-            for (level, albedo) in material.albedo.iter().enumerate() {
-                encoder
-                    .update_texture::<R8_G8_B8_A8, gfx::format::Srgba8>(
-                        &texture,
-                        None,
-                        gfx_core::texture::NewImageInfo {
-                            xoffset: 0,
-                            yoffset: 0,
-                            zoffset: layer as u16,
-                            width: resolution >> level,
-                            height: resolution >> level,
-                            depth: 1,
-                            format: (),
-                            mipmap: level as u8,
-                        },
-                        &albedo[..],
-                    ).unwrap();
-            }
-            average_albedos.push(material.albedo.last().unwrap()[0]);
-        }
+/// Resample `image` to `resolution` and compute every mip level below it, down to 1x1.
+fn mip_chain(image: RgbaImage, resolution: u32) -> Vec<RgbaImage> {
+    let mut levels =
+        vec![image::imageops::resize(&image, resolution, resolution, FilterType::Triangle)];
+    while levels.last().unwrap().width() > 1 {
+        let previous = levels.last().unwrap();
+        let size = previous.width() / 2;
+        levels.push(image::imageops::resize(previous, size, size, FilterType::Triangle));
+    }
+    levels
+}
 
-        // TODO: get rid of this hack.
-        let s = |v: &mut u8, s: f32| *v = LINEAR_TO_SRGB[(SRGB_TO_LINEAR[*v] as f32 * s) as u8];
-        for i in 0..4 {
-            s(&mut average_albedos[MaterialType::Rock as usize][i], 0.5);
-            s(
-                &mut average_albedos[MaterialType::RockSteep as usize][i],
-                0.4,
-            );
+fn average_linear(image: &RgbaImage) -> [f32; 4] {
+    let mut sum = [0.0; 4];
+    for pixel in image.pixels() {
+        for i in 0..3 {
+            sum[i] += SRGB_TO_LINEAR[pixel[i]] as f32 / 255.0;
         }
-
-        let texture_view = factory
-            .view_texture_as_shader_resource::<gfx::format::Srgba8>(
-                &texture,
-                (0, mipmaps),
-                Swizzle::new(),
-            ).unwrap();
-
-        Ok(Self {
-            texture_view,
-            _texture: texture,
-            average_albedos,
-        })
+        sum[3] += pixel[3] as f32 / 255.0;
     }
+    let n = (image.width() * image.height()) as f32;
+    [sum[0] / n, sum[1] / n, sum[2] / n, sum[3] / n]
+}
 
-    pub(crate) fn get_average_albedo(&self, material: MaterialType) -> [u8; 4] {
-        self.average_albedos[material as usize].clone()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn material_mip_chain() {
+        let image = RgbaImage::from_fn(48, 48, |x, _| {
+            if x < 24 {
+                image::Rgba([255, 255, 255, 255])
+            } else {
+                image::Rgba([0, 0, 0, 255])
+            }
+        });
+        let levels = mip_chain(image, 16);
+        let sizes: Vec<_> = levels.iter().map(|l| l.width()).collect();
+        assert_eq!(sizes, vec![16, 8, 4, 2, 1]);
+
+        let average = average_linear(&levels[0]);
+        assert!((average[0] - 0.5).abs() < 0.05);
+        assert_eq!(average[3], 1.0);
     }
 }
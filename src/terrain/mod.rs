@@ -1,5 +1,6 @@
 pub mod dem;
-// pub mod material;
+#[cfg(feature = "render")]
+pub mod material;
 pub mod quadtree;
 
 pub(crate) mod heightmap;
@@ -1,14 +1,24 @@
 use crate::cache::Priority;
 use cgmath::*;
 use fnv::FnvHashMap;
+use rayon::prelude::*;
+#[cfg(feature = "render")]
 use std::convert::TryInto;
 
 pub(crate) mod node;
+#[cfg(feature = "render")]
+pub(crate) mod occlusion;
+#[cfg(feature = "render")]
 pub(crate) mod render;
 
 pub(crate) use crate::terrain::quadtree::node::*;
+#[cfg(feature = "render")]
 pub(crate) use crate::terrain::quadtree::render::*;
 
+/// Fewest nodes whose priorities are computed on a single thread by `update_visibility`. Smaller
+/// batches cost more to hand out than to evaluate.
+const PRIORITY_BATCH_SIZE: usize = 64;
+
 /// The central object in terra. It holds all relevant state and provides functions to update and
 /// render the terrain.
 pub(crate) struct QuadTree {
@@ -17,11 +27,25 @@ pub(crate) struct QuadTree {
     visible_nodes: Vec<VNode>,
     partially_visible_nodes: Vec<(VNode, u8)>,
 
+    #[cfg(feature = "render")]
     heights_resolution: u32,
 
+    #[cfg(feature = "render")]
     node_states: Vec<NodeState>,
 
     node_priorities: FnvHashMap<VNode, Priority>,
+    /// Factor applied to the `min_distance` of each node, based on its geometric error.
+    lod_scales: FnvHashMap<VNode, f64>,
+    /// Bounds on the elevation of each node, either known directly or inherited from its parent.
+    elevation_ranges: FnvHashMap<VNode, (f32, f32)>,
+    /// Elevation range assumed for nodes that have no recorded range of their own or of any
+    /// ancestor. See `PlanetConfig::elevation_range`.
+    default_elevation_range: (f32, f32),
+    /// Minimum and maximum distance from the camera to any visible node.
+    depth_bounds: Option<(f64, f64)>,
+    /// Number of entries at the start of `node_states` that are full (not partial) nodes.
+    #[cfg(feature = "render")]
+    full_node_states: u32,
     last_camera_position: Option<mint::Point3<f64>>,
 }
 
@@ -33,32 +57,68 @@ impl std::fmt::Debug for QuadTree {
 
 #[allow(unused)]
 impl QuadTree {
-    pub(crate) fn new(heights_resolution: u32) -> Self {
+    pub(crate) fn new(heights_resolution: u32, default_elevation_range: (f32, f32)) -> Self {
         Self {
             visible_nodes: Vec::new(),
             partially_visible_nodes: Vec::new(),
+            #[cfg(feature = "render")]
             node_states: Vec::new(),
+            #[cfg(feature = "render")]
             heights_resolution,
             node_priorities: FnvHashMap::default(),
+            lod_scales: FnvHashMap::default(),
+            elevation_ranges: FnvHashMap::default(),
+            default_elevation_range,
+            depth_bounds: None,
+            #[cfg(feature = "render")]
+            full_node_states: 0,
             last_camera_position: None,
         }
     }
 
+    /// Number of indices needed to draw a grid with `resolution` quads on a side.
+    #[cfg(feature = "render")]
+    fn index_count(resolution: u32) -> u32 {
+        // One triangle strip per column plus a primitive restart after each.
+        resolution * (2 * (resolution + 1) + 1)
+    }
+
+    /// Primitive state that all pipelines drawing terrain tiles must use.
+    #[cfg(feature = "render")]
+    pub(crate) fn primitive_state() -> wgpu::PrimitiveState {
+        wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleStrip,
+            strip_index_format: Some(wgpu::IndexFormat::Uint16),
+            cull_mode: Some(wgpu::Face::Front),
+            ..Default::default()
+        }
+    }
+
+    /// Build the shared index buffer used to draw every tile. Vertex positions and displacements
+    /// are fetched in the vertex shader based on the vertex index and per-node instance data, so
+    /// no vertex buffer is needed.
+    ///
+    /// Each column of quads is emitted as a single triangle strip. This needs roughly a third as
+    /// many indices as a triangle list and keeps adjacent vertices close together for the
+    /// post-transform vertex cache.
+    #[cfg(feature = "render")]
     pub(crate) fn create_index_buffers(&self, device: &wgpu::Device) -> wgpu::Buffer {
-        let mut make_index_buffer = |resolution: u16| -> Vec<u16> {
+        let make_index_buffer = |resolution: u16| -> Vec<u16> {
             let mut data = Vec::new();
 
             let width = resolution + 1;
-            for y in 0..resolution {
-                for x in 0..resolution {
-                    for offset in [0, 1, width, 1, width + 1, width].iter() {
-                        data.push(offset + (x + y * width));
-                    }
+            for x in 0..resolution {
+                for y in 0..width {
+                    data.push(x + y * width);
+                    data.push(x + 1 + y * width);
                 }
+                data.push(0xffff);
             }
+            assert_eq!(data.len() as u32, Self::index_count(resolution as u32));
             data
         };
         let resolution = self.heights_resolution as u16;
+        assert!((resolution as u32 + 1) * (resolution as u32 + 1) < 0xffff);
         let full = make_index_buffer(resolution);
         let half = make_index_buffer(resolution / 2);
 
@@ -76,28 +136,100 @@ impl QuadTree {
         buffer
     }
 
-    pub fn update_visibility(&mut self, camera: mint::Point3<f64>) {
+    /// Select which nodes to render from `camera`.
+    ///
+    /// `lod_scale` returns how much to scale the distance at which a node is refined, or None if
+    /// unknown in which case the scale of its parent is used. Scales above 1.0 cause nodes to be
+    /// refined from further away.
+    ///
+    /// `elevation_range` similarly returns the minimum and maximum elevation of a node if known,
+    /// and `max_level` returns the deepest level that a node may be refined to. Nodes below that
+    /// level are never drawn or streamed.
+    ///
+    /// Nodes are streamed with the highest of their priority from `camera`, from any of
+    /// `viewpoints`, and from `boost`. These only affect which tiles are loaded, not which nodes
+    /// are drawn.
+    pub fn update_visibility(
+        &mut self,
+        camera: mint::Point3<f64>,
+        lod_scale: impl Fn(VNode) -> Option<f64>,
+        mut elevation_range: impl FnMut(VNode) -> Option<(f32, f32)>,
+        max_level: impl Fn(VNode) -> u8,
+        viewpoints: &[mint::Point3<f64>],
+        boost: impl Fn(VNode) -> Priority,
+    ) {
         if self.last_camera_position == Some(camera) {
             return;
         }
         self.last_camera_position = Some(camera);
 
         let camera = Vector3::new(camera.x, camera.y, camera.z);
+        let viewpoints: Vec<_> = viewpoints.iter().map(|v| Vector3::new(v.x, v.y, v.z)).collect();
 
         self.visible_nodes.clear();
         self.partially_visible_nodes.clear();
         self.node_priorities.clear();
+        self.lod_scales.clear();
+        self.elevation_ranges.clear();
 
         let mut node_visibilities: FnvHashMap<VNode, bool> = FnvHashMap::default();
 
         // Any node with all needed layers in cache is visible...
-        VNode::breadth_first(|node| {
-            let priority = node.priority(camera);
-            self.node_priorities.insert(node, priority);
-            let visible = node.level() == 0 || priority >= Priority::cutoff();
-            node_visibilities.insert(node, visible);
-            visible && node.level() < VNode::LEVEL_CELL_2CM
-        });
+        //
+        // Nodes are visited a level at a time, in the same order as `VNode::breadth_first`, so
+        // that the priorities of each level can be computed in parallel. Deep trees have thousands
+        // of candidates on their finest levels.
+        let mut candidates = VNode::roots().to_vec();
+        while !candidates.is_empty() {
+            let nodes: Vec<_> = candidates
+                .drain(..)
+                .map(|node| {
+                    let scale = lod_scale(node)
+                        .or_else(|| node.parent().map(|(p, _)| self.lod_scales[&p]))
+                        .unwrap_or(1.0);
+                    self.lod_scales.insert(node, scale);
+
+                    // Finer levels add detail that wasn't captured in the recorded range, so leave
+                    // a margin of roughly one mesh cell.
+                    let margin = node.aprox_side_length() / 64.0;
+                    let range = elevation_range(node)
+                        .map(|(min, max)| (min - margin, max + margin))
+                        .or_else(|| node.parent().map(|(p, _)| self.elevation_ranges[&p]))
+                        .unwrap_or(self.default_elevation_range);
+                    self.elevation_ranges.insert(node, range);
+
+                    (node, scale, range, node.level() <= max_level(node))
+                })
+                .collect();
+
+            let priorities: Vec<_> = nodes
+                .par_iter()
+                .with_min_len(PRIORITY_BATCH_SIZE)
+                .map(|&(node, scale, range, in_range)| {
+                    if !in_range {
+                        return (Priority::none(), Priority::none());
+                    }
+                    let priority = node.scaled_priority(camera, scale, range);
+                    let streamed = viewpoints
+                        .iter()
+                        .map(|&v| node.scaled_priority(v, scale, range))
+                        .fold(priority, Priority::max);
+                    (priority, streamed)
+                })
+                .collect();
+
+            for (&(node, _, _, in_range), &(priority, streamed)) in nodes.iter().zip(&priorities) {
+                let streamed = if in_range { streamed.max(boost(node)) } else { streamed };
+                self.node_priorities.insert(node, streamed);
+                let visible = node.level() == 0 || priority >= Priority::cutoff();
+                node_visibilities.insert(node, visible);
+                if (visible || streamed >= Priority::cutoff())
+                    && node.level() < VNode::LEVEL_CELL_2CM
+                {
+                    candidates.extend_from_slice(&node.children());
+                }
+            }
+        }
         // let min_missing_level = node_visibilities
         //     .iter()
         //     .filter(|(&n, &v)| v && !tile_cache.contains(n, LayerType::Displacements))
@@ -135,8 +267,49 @@ impl QuadTree {
                 false
             }
         });
+
+        let mut depth_bounds: Option<(f64, f64)> = None;
+        let rendered =
+            self.visible_nodes.iter().chain(self.partially_visible_nodes.iter().map(|(n, _)| n));
+        for &node in rendered {
+            let range = self.elevation_ranges[&node];
+            let near = node.distance2(camera, range).sqrt();
+            let far =
+                node.bounding_points(range).iter().map(|p| p.distance(camera)).fold(0.0, f64::max);
+            depth_bounds = Some(match depth_bounds {
+                Some((n, f)) => (n.min(near), f.max(far)),
+                None => (near, far),
+            });
+        }
+        self.depth_bounds = depth_bounds;
+    }
+
+    /// Force the next call to `update_visibility` to reselect nodes even if the camera hasn't
+    /// moved.
+    pub fn invalidate_visibility(&mut self) {
+        self.last_camera_position = None;
+    }
+
+    /// Minimum and maximum distance from the camera to any terrain that could be rendered, for
+    /// fitting near and far planes.
+    pub fn depth_bounds(&self) -> Option<(f64, f64)> {
+        self.depth_bounds
+    }
+
+    fn elevation_range(&self, node: VNode) -> (f32, f32) {
+        self.elevation_ranges.get(&node).cloned().unwrap_or(self.default_elevation_range)
     }
 
+    /// Nodes chosen by the last call to `update_visibility`, including those only partially drawn.
+    #[cfg(feature = "render")]
+    pub fn rendered_nodes(&self) -> impl Iterator<Item = VNode> + '_ {
+        self.visible_nodes
+            .iter()
+            .chain(self.partially_visible_nodes.iter().map(|(n, _)| n))
+            .copied()
+    }
+
+    #[cfg(feature = "render")]
     pub fn node_buffer_length(&self) -> usize {
         self.node_states.len()
     }
@@ -145,6 +318,10 @@ impl QuadTree {
         self.node_priorities.get(&node).cloned().unwrap_or(Priority::none())
     }
 
+    fn lod_scale(&self, node: VNode) -> f64 {
+        self.lod_scales.get(&node).cloned().unwrap_or(1.0)
+    }
+
     // pub fn get_height(
     //     &self,
     //     mapfile: &MapFile,
@@ -190,7 +190,68 @@ impl VNode {
         self.cell_position_cspace(0, 0, 0, 1).normalize() * crate::coordinates::PLANET_RADIUS
     }
 
-    fn distance2(&self, point: Vector3<f64>) -> f64 {
+    /// Range of elevations, in meters, that any node could possibly have. Spans from the bottom of
+    /// the Challenger Deep to above the summit of Mount Everest.
+    pub const DEFAULT_ELEVATION_RANGE: (f32, f32) = (-11000.0, 9000.0);
+
+    /// Points whose convex hull contains all terrain within this node, given that its elevation
+    /// lies within `elevation_range`.
+    pub fn bounding_points(&self, elevation_range: (f32, f32)) -> [Vector3<f64>; 10] {
+        let min_radius = EARTH_RADIUS + elevation_range.0 as f64;
+        let max_radius = EARTH_RADIUS + elevation_range.1 as f64;
+        let corners = [
+            self.grid_position_cspace(0, 0, 0, 2).normalize(),
+            self.grid_position_cspace(1, 0, 0, 2).normalize(),
+            self.grid_position_cspace(1, 1, 0, 2).normalize(),
+            self.grid_position_cspace(0, 1, 0, 2).normalize(),
+        ];
+
+        // The surface bulges outward between the corners, so the top face is pushed out far enough
+        // to cover the center of the node.
+        let center = self.grid_position_cspace(1, 1, 0, 3).normalize();
+        let bulge = max_radius / corners.iter().map(|c| c.dot(center)).fold(1.0, f64::min);
+
+        [
+            corners[0] * min_radius,
+            corners[1] * min_radius,
+            corners[2] * min_radius,
+            corners[3] * min_radius,
+            corners[0] * bulge,
+            corners[1] * bulge,
+            corners[2] * bulge,
+            corners[3] * bulge,
+            center * min_radius,
+            center * bulge,
+        ]
+    }
+
+    /// Returns whether any part of this node could be inside the view frustum. Positions are
+    /// relative to `camera`, matching the convention for `view_proj`.
+    pub fn in_frustum(
+        &self,
+        elevation_range: (f32, f32),
+        camera: Vector3<f64>,
+        view_proj: &Matrix4<f64>,
+    ) -> bool {
+        // Bounding points of large nodes span so much of the planet that testing them against the
+        // frustum is meaningless.
+        if self.level() < 2 {
+            return true;
+        }
+
+        let points = self.bounding_points(elevation_range);
+        let clip: Vec<Vector4<f64>> =
+            points.iter().map(|p| view_proj * (p - camera).extend(1.0)).collect();
+        !(clip.iter().all(|c| c.x < -c.w)
+            || clip.iter().all(|c| c.x > c.w)
+            || clip.iter().all(|c| c.y < -c.w)
+            || clip.iter().all(|c| c.y > c.w)
+            || clip.iter().all(|c| c.w <= 0.0)
+            || clip.iter().all(|c| c.z > c.w)
+            || clip.iter().all(|c| c.z < 0.0))
+    }
+
+    pub(crate) fn distance2(&self, point: Vector3<f64>, elevation_range: (f32, f32)) -> f64 {
         let corners = [
             self.grid_position_cspace(0, 0, 0, 2),
             self.grid_position_cspace(1, 0, 0, 2),
@@ -205,17 +266,17 @@ impl VNode {
             corners[3].cross(-corners[0]),
         ];
 
-        const MIN_RADIUS: f64 = EARTH_RADIUS - 1000.0;
-        const MAX_RADIUS: f64 = EARTH_RADIUS + 9000.0;
+        let min_radius = EARTH_RADIUS + elevation_range.0 as f64;
+        let max_radius = EARTH_RADIUS + elevation_range.1 as f64;
 
         // Top and bottom
         if normals.iter().all(|n| n.dot(point) >= 0.0) {
             let length2 = point.dot(point);
-            if length2 > MIN_RADIUS * MIN_RADIUS && length2 < MAX_RADIUS * MAX_RADIUS {
+            if length2 > min_radius * min_radius && length2 < max_radius * max_radius {
                 return 0.0;
             }
             let length = length2.sqrt();
-            let d = (length - MAX_RADIUS).max(MIN_RADIUS - length);
+            let d = (length - max_radius).max(min_radius - length);
             return d * d;
         }
 
@@ -223,7 +284,7 @@ impl VNode {
         let mut d2 = f64::INFINITY;
         for i in 0..4 {
             let corner = corners[i].normalize();
-            let segment_point = point.dot(corner).min(MAX_RADIUS).max(MIN_RADIUS) * corner;
+            let segment_point = point.dot(corner).min(max_radius).max(min_radius) * corner;
             d2 = d2.min(segment_point.distance2(point));
         }
 
@@ -236,11 +297,11 @@ impl VNode {
                 let mut surface_point =
                     point - normals[i] * normals[i].dot(point) / normals[i].dot(normals[i]);
                 let length2 = surface_point.dot(surface_point);
-                if length2 > MAX_RADIUS * MAX_RADIUS {
-                    surface_point = surface_point.normalize() * MAX_RADIUS;
+                if length2 > max_radius * max_radius {
+                    surface_point = surface_point.normalize() * max_radius;
                     d2 = d2.min(surface_point.distance2(point));
-                } else if length2 < MIN_RADIUS * MIN_RADIUS {
-                    surface_point = surface_point.normalize() * MIN_RADIUS;
+                } else if length2 < min_radius * min_radius {
+                    surface_point = surface_point.normalize() * min_radius;
                     d2 = d2.min(surface_point.distance2(point));
                 } else {
                     let dot = normals[i].dot(point);
@@ -255,9 +316,21 @@ impl VNode {
 
     /// How much this node is needed for the current frame. Nodes with priority less than 1.0 will
     /// not be rendered (they are too detailed).
+    #[cfg(any(feature = "render", test))]
     pub(super) fn priority(&self, camera: Vector3<f64>) -> Priority {
-        let min_distance = self.min_distance();
-        let distance2 = self.distance2(camera);
+        self.scaled_priority(camera, 1.0, Self::DEFAULT_ELEVATION_RANGE)
+    }
+
+    /// Same as `priority` but with `min_distance` multiplied by `lod_scale` and the node's
+    /// elevation known to be within `elevation_range`.
+    pub(super) fn scaled_priority(
+        &self,
+        camera: Vector3<f64>,
+        lod_scale: f64,
+        elevation_range: (f32, f32),
+    ) -> Priority {
+        let min_distance = self.min_distance() * lod_scale;
+        let distance2 = self.distance2(camera, elevation_range);
 
         Priority::from_f32(((min_distance * min_distance) / distance2.max(1e-12)) as f32)
     }
@@ -280,6 +353,7 @@ impl VNode {
         ]
     }
 
+    #[cfg(feature = "render")]
     pub fn find_ancestor<Visit>(&self, mut visit: Visit) -> Option<(VNode, usize, Vector2<u32>)>
     where
         Visit: FnMut(VNode) -> bool,
@@ -0,0 +1,329 @@
+use super::render::MAX_RENDERED_NODES;
+use crate::gpu_state::GpuState;
+use maplit::hashmap;
+use std::{borrow::Cow, collections::HashMap, mem, num::NonZeroU32};
+
+/// Size in bytes of a single `DrawIndexedIndirect` command.
+const DRAW_INDIRECT_SIZE: u64 = 20;
+
+/// Indirect draw commands written by `OcclusionCuller::cull`, one per node.
+#[derive(Copy, Clone)]
+pub(crate) struct IndirectDraws<'a> {
+    pub buffer: &'a wgpu::Buffer,
+    /// Whether the device supports issuing all of the commands with a single
+    /// `multi_draw_indexed_indirect`.
+    pub multi_draw: bool,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct CullUniforms {
+    num_nodes: u32,
+    full_resolution: u32,
+    hiz_valid: u32,
+    hiz_levels: u32,
+}
+unsafe impl bytemuck::Zeroable for CullUniforms {}
+unsafe impl bytemuck::Pod for CullUniforms {}
+
+/// Culls terrain nodes hidden behind closer terrain by testing their bounding boxes against a
+/// hierarchical depth buffer (Hi-Z) built from the previous frame.
+///
+/// Culling happens entirely on the GPU: the output is one indirect draw per node, with an instance
+/// count of zero for nodes that are occluded.
+pub(crate) struct OcclusionCuller {
+    downsample_depth: rshader::ShaderSet,
+    downsample: rshader::ShaderSet,
+    cull: rshader::ShaderSet,
+
+    downsample_depth_pipeline: Option<wgpu::ComputePipeline>,
+    downsample_pipeline: Option<wgpu::ComputePipeline>,
+    cull_pipeline: Option<wgpu::ComputePipeline>,
+
+    uniforms: wgpu::Buffer,
+    draws: wgpu::Buffer,
+
+    hiz: Option<(wgpu::Texture, (u32, u32))>,
+    /// Whether `hiz` holds the depth of the last frame rendered at the current size.
+    hiz_valid: bool,
+    multi_draw: bool,
+}
+impl OcclusionCuller {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            downsample_depth: rshader::ShaderSet::compute_only(rshader::shader_source!(
+                "../../shaders",
+                "hiz-downsample.comp",
+                "declarations.glsl";
+                "FROM_DEPTH" = "1"
+            ))
+            .unwrap(),
+            downsample: rshader::ShaderSet::compute_only(rshader::shader_source!(
+                "../../shaders",
+                "hiz-downsample.comp",
+                "declarations.glsl";
+                "FROM_DEPTH" = "0"
+            ))
+            .unwrap(),
+            cull: rshader::ShaderSet::compute_only(rshader::shader_source!(
+                "../../shaders",
+                "cull-nodes.comp",
+                "declarations.glsl"
+            ))
+            .unwrap(),
+            downsample_depth_pipeline: None,
+            downsample_pipeline: None,
+            cull_pipeline: None,
+            uniforms: device.create_buffer(&wgpu::BufferDescriptor {
+                size: mem::size_of::<CullUniforms>() as u64,
+                usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::UNIFORM,
+                label: Some("buffer.occlusion.uniforms"),
+                mapped_at_creation: false,
+            }),
+            draws: device.create_buffer(&wgpu::BufferDescriptor {
+                size: DRAW_INDIRECT_SIZE * MAX_RENDERED_NODES as u64,
+                usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::INDIRECT,
+                label: Some("buffer.occlusion.draws"),
+                mapped_at_creation: false,
+            }),
+            hiz: None,
+            hiz_valid: false,
+            multi_draw: device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT),
+        }
+    }
+
+    pub fn refresh(&mut self) {
+        if self.downsample_depth.refresh() {
+            self.downsample_depth_pipeline = None;
+        }
+        if self.downsample.refresh() {
+            self.downsample_pipeline = None;
+        }
+        if self.cull.refresh() {
+            self.cull_pipeline = None;
+        }
+    }
+
+    /// Indirect draw commands written by the last call to `cull`, one per node.
+    pub fn draws(&self) -> IndirectDraws<'_> {
+        IndirectDraws { buffer: &self.draws, multi_draw: self.multi_draw }
+    }
+
+    fn hiz_levels(size: (u32, u32)) -> u32 {
+        32 - size.0.max(size.1).leading_zeros()
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        shader: &rshader::ShaderSet,
+        bind_group_layout: &wgpu::BindGroupLayout,
+        name: &str,
+    ) -> wgpu::ComputePipeline {
+        device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            layout: Some(&device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                bind_group_layouts: [bind_group_layout][..].into(),
+                push_constant_ranges: &[],
+                label: Some(&format!("pipeline.{}.layout", name)),
+            })),
+            module: &device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                label: Some(&format!("shader.{}", name)),
+                source: wgpu::ShaderSource::SpirV(shader.compute().into()),
+                flags: wgpu::ShaderFlags::empty(),
+            }),
+            entry_point: "main",
+            label: Some(&format!("pipeline.{}", name)),
+        })
+    }
+
+    fn hiz_view(&self, level: u32) -> wgpu::TextureView {
+        self.hiz.as_ref().unwrap().0.create_view(&wgpu::TextureViewDescriptor {
+            label: Some(&format!("view.hiz.mip{}", level)),
+            base_mip_level: level,
+            mip_level_count: Some(NonZeroU32::new(1).unwrap()),
+            ..Default::default()
+        })
+    }
+
+    /// Record a compute pass that writes an indirect draw for each of the first `num_nodes` nodes
+    /// in the node buffer, skipping the ones hidden behind last frame's depth.
+    pub fn cull(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        state: &GpuState,
+        frame_size: (u32, u32),
+        num_nodes: u32,
+        full_resolution: u32,
+    ) {
+        let size = ((frame_size.0 + 1) / 2, (frame_size.1 + 1) / 2);
+        if self.hiz.as_ref().map(|h| h.1) != Some(size) {
+            self.hiz = Some((
+                device.create_texture(&wgpu::TextureDescriptor {
+                    size: wgpu::Extent3d {
+                        width: size.0,
+                        height: size.1,
+                        depth_or_array_layers: 1,
+                    },
+                    format: wgpu::TextureFormat::R32Float,
+                    mip_level_count: Self::hiz_levels(size),
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    usage: wgpu::TextureUsage::STORAGE | wgpu::TextureUsage::SAMPLED,
+                    label: Some("texture.hiz"),
+                }),
+                size,
+            ));
+            self.hiz_valid = false;
+        }
+
+        queue.write_buffer(
+            &self.uniforms,
+            0,
+            bytemuck::bytes_of(&CullUniforms {
+                num_nodes,
+                full_resolution,
+                hiz_valid: self.hiz_valid as u32,
+                hiz_levels: Self::hiz_levels(size),
+            }),
+        );
+
+        let mut image_views: HashMap<Cow<str>, _> = HashMap::new();
+        image_views.insert(
+            "hiz".into(),
+            self.hiz.as_ref().unwrap().0.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("view.hiz"),
+                ..Default::default()
+            }),
+        );
+        let (bind_group, bind_group_layout) = state.bind_group_for_shader(
+            device,
+            &self.cull,
+            hashmap![
+                "ubo".into() => (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &self.uniforms,
+                    offset: 0,
+                    size: None,
+                })),
+                "draws".into() => (false, wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &self.draws,
+                    offset: 0,
+                    size: None,
+                })),
+            ],
+            image_views,
+            "occlusion.cull",
+        );
+        if self.cull_pipeline.is_none() {
+            self.cull_pipeline = Some(Self::create_pipeline(
+                device,
+                &self.cull,
+                &bind_group_layout,
+                "occlusion.cull",
+            ));
+        }
+
+        let mut cpass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+        cpass.set_pipeline(self.cull_pipeline.as_ref().unwrap());
+        cpass.set_bind_group(0, &bind_group, &[]);
+        cpass.dispatch((num_nodes + 63) / 64, 1, 1);
+    }
+
+    /// Build the Hi-Z pyramid from `depth_buffer` for use by the next frame. The depth texture
+    /// must have been created with `TextureUsage::SAMPLED`.
+    pub fn update_hiz(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        state: &GpuState,
+        depth_buffer: &wgpu::TextureView,
+    ) {
+        let size = match self.hiz {
+            Some((_, size)) => size,
+            None => return,
+        };
+
+        for level in 0..Self::hiz_levels(size) {
+            let output = self.hiz_view(level);
+            let bind_group = if level == 0 {
+                // The depth buffer is owned by the application so it is bound directly rather
+                // than through `bind_group_for_shader`.
+                let mut entries = self.downsample_depth.layout_descriptor().entries.to_vec();
+                for (name, entry) in self.downsample_depth.desc_names().iter().zip(&mut entries) {
+                    if let wgpu::BindingType::Texture { ref mut sample_type, .. } = entry.ty {
+                        assert_eq!(name.as_deref(), Some("depth"));
+                        *sample_type = wgpu::TextureSampleType::Depth;
+                    }
+                }
+                let bind_group_layout =
+                    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                        entries: &entries,
+                        label: Some("layout.occlusion.downsample_depth"),
+                    });
+                if self.downsample_depth_pipeline.is_none() {
+                    self.downsample_depth_pipeline = Some(Self::create_pipeline(
+                        device,
+                        &self.downsample_depth,
+                        &bind_group_layout,
+                        "occlusion.downsample_depth",
+                    ));
+                }
+                let bindings: Vec<_> = self
+                    .downsample_depth
+                    .desc_names()
+                    .iter()
+                    .zip(entries.iter())
+                    .map(|(name, entry)| wgpu::BindGroupEntry {
+                        binding: entry.binding,
+                        resource: wgpu::BindingResource::TextureView(
+                            match name.as_deref().unwrap() {
+                                "depth" => depth_buffer,
+                                "hiz_out" => &output,
+                                name => unreachable!("unrecognized image: {}", name),
+                            },
+                        ),
+                    })
+                    .collect();
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &bind_group_layout,
+                    entries: &*bindings,
+                    label: Some("bindgroup.occlusion.downsample_depth"),
+                })
+            } else {
+                let mut image_views: HashMap<Cow<str>, _> = HashMap::new();
+                image_views.insert("hiz_in".into(), self.hiz_view(level - 1));
+                image_views.insert("hiz_out".into(), output);
+                let (bind_group, bind_group_layout) = state.bind_group_for_shader(
+                    device,
+                    &self.downsample,
+                    HashMap::new(),
+                    image_views,
+                    "occlusion.downsample",
+                );
+                if self.downsample_pipeline.is_none() {
+                    self.downsample_pipeline = Some(Self::create_pipeline(
+                        device,
+                        &self.downsample,
+                        &bind_group_layout,
+                        "occlusion.downsample",
+                    ));
+                }
+                bind_group
+            };
+
+            let pipeline = if level == 0 {
+                self.downsample_depth_pipeline.as_ref().unwrap()
+            } else {
+                self.downsample_pipeline.as_ref().unwrap()
+            };
+            let level_size = ((size.0 >> level).max(1), (size.1 >> level).max(1));
+            let mut cpass =
+                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
+            cpass.set_pipeline(pipeline);
+            cpass.set_bind_group(0, &bind_group, &[]);
+            cpass.dispatch((level_size.0 + 7) / 8, (level_size.1 + 7) / 8, 1);
+        }
+
+        self.hiz_valid = true;
+    }
+}
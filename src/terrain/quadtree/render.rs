@@ -1,6 +1,10 @@
 use super::*;
 use crate::cache::{CacheLookup, LayerType, SingularLayerType, UnifiedPriorityCache};
+use crate::mapfile::TileProvenance;
+use crate::terrain::material::GROUND_PERIOD;
+use crate::terrain::quadtree::occlusion::IndirectDraws;
 use std::mem;
+use std::time::SystemTime;
 
 #[derive(Copy, Clone)]
 #[repr(C, align(4))]
@@ -17,7 +21,20 @@ pub(crate) struct NodeState {
     relative_position: [f32; 3],
     min_distance: f32,
     parent_relative_position: [f32; 3],
-    _padding1: [u32; 17],
+    _padding1: u32,
+    /// Bounding box of the node, relative to the camera.
+    bounds_min: [f32; 3],
+    _padding2: u32,
+    bounds_max: [f32; 3],
+    _padding3: u32,
+    /// Position of the node's origin within the repeating ground material pattern, and the
+    /// distance between vertices, both in meters.
+    ground_origin: [f32; 2],
+    ground_step: f32,
+    /// Where the heightmap tile came from and its age in seconds. See `QuadTree::provenance`.
+    provenance: u32,
+    provenance_age: f32,
+    _padding4: [u32; 3],
     // side_length: f32,
     // padding0: f32,
     // padding1: u32,
@@ -25,9 +42,47 @@ pub(crate) struct NodeState {
 unsafe impl bytemuck::Pod for NodeState {}
 unsafe impl bytemuck::Zeroable for NodeState {}
 
-const MAX_RENDERED_NODES: usize = 1024;
+pub(super) const MAX_RENDERED_NODES: usize = 1024;
 
 impl QuadTree {
+    /// Whether the root tile on the face of `node` holds every layer that nodes are drawn with,
+    /// so that `find_descs` can fall back to it. Until then the face is left out.
+    pub(crate) fn face_loaded(cache: &UnifiedPriorityCache, node: VNode) -> bool {
+        cache.tiles.contains_all(
+            VNode::roots()[node.face() as usize],
+            LayerType::Displacements.bit_mask()
+                | LayerType::Albedo.bit_mask()
+                | LayerType::Roughness.bit_mask()
+                | LayerType::Normals.bit_mask(),
+        )
+    }
+
+    /// Code for where the heightmap tile of `node` came from, matching the provenance overlay in
+    /// terrain.frag, along with how many seconds ago it was produced. Zero if not yet known.
+    fn provenance(cache: &UnifiedPriorityCache, node: VNode, now: SystemTime) -> (u32, f32) {
+        match cache.tiles.provenance(node) {
+            Some((provenance, produced)) => {
+                let code = match provenance {
+                    TileProvenance::Downloaded => 1,
+                    TileProvenance::Dataset => 2,
+                    TileProvenance::Fractal => 3,
+                };
+                (code, now.duration_since(produced).unwrap_or_default().as_secs_f32())
+            }
+            None => (0, 0.0),
+        }
+    }
+
+    /// Offset of `node` within the ground material pattern, which repeats every `GROUND_PERIOD`
+    /// meters along each axis of the cube face.
+    fn ground_origin(node: VNode) -> [f32; 2] {
+        let side = node.aprox_side_length() as f64;
+        [
+            (node.x() as f64 * side % GROUND_PERIOD) as f32,
+            (node.y() as f64 * side % GROUND_PERIOD) as f32,
+        ]
+    }
+
     pub fn find_descs(
         node: VNode,
         cache: &UnifiedPriorityCache,
@@ -89,12 +144,30 @@ impl QuadTree {
         [offset.x, offset.y, lookup.slot as f32, scale * texture_step]
     }
 
+    /// Axis aligned bounding box of `node` relative to `camera`.
+    fn bounding_box(
+        node: VNode,
+        elevation_range: (f32, f32),
+        camera: Vector3<f64>,
+    ) -> ([f32; 3], [f32; 3]) {
+        let mut min = Vector3::new(f64::MAX, f64::MAX, f64::MAX);
+        let mut max = Vector3::new(f64::MIN, f64::MIN, f64::MIN);
+        for p in node.bounding_points(elevation_range).iter() {
+            let p = p - camera;
+            min = Vector3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+            max = Vector3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+        }
+        (min.cast::<f32>().unwrap().into(), max.cast::<f32>().unwrap().into())
+    }
+
     pub fn prepare_vertex_buffer(
         &mut self,
         queue: &wgpu::Queue,
         vertex_buffer: &wgpu::Buffer,
         cache: &UnifiedPriorityCache,
         camera: mint::Point3<f64>,
+        view_proj: mint::ColumnMatrix4<f32>,
+        frustum_culling: bool,
     ) {
         assert_eq!(
             cache.tile_desc(LayerType::Albedo).texture_resolution,
@@ -113,9 +186,22 @@ impl QuadTree {
         let texture_step = texture_ratio / resolution as f32;
         let texture_origin = texture_border as f32 / texture_resolution as f32;
 
+        let camera_position = Vector3::new(camera.x, camera.y, camera.z);
+        let view_proj = Matrix4::from(view_proj).cast::<f64>().unwrap();
+
+        let now = SystemTime::now();
         self.node_states.clear();
         for &node in self.visible_nodes.iter() {
             assert!(node.min_distance() as f32 != 0.0);
+            if !Self::face_loaded(cache, node) {
+                continue;
+            }
+            if frustum_culling
+                && !node.in_frustum(self.elevation_range(node), camera_position, &view_proj)
+            {
+                continue;
+            }
+            let min_distance = node.min_distance() * self.lod_scale(node);
             let (displacements_desc, displacements_node) = Self::find_descs(
                 node,
                 &cache,
@@ -167,10 +253,22 @@ impl QuadTree {
                     )
                 })
                 .unwrap_or([0.0, 0.0, -1.0, 0.0]);
+            let (bounds_min, bounds_max) =
+                Self::bounding_box(node, self.elevation_range(node), camera_position);
+            let (provenance, provenance_age) = Self::provenance(cache, displacements_node, now);
             let node_index = self.node_states.len() as u32;
             self.node_states.push(NodeState {
-                _padding1: [0; 17],
-                min_distance: node.min_distance() as f32,
+                _padding1: 0,
+                bounds_min,
+                _padding2: 0,
+                bounds_max,
+                _padding3: 0,
+                ground_origin: Self::ground_origin(node),
+                ground_step: node.aprox_side_length() / resolution as f32,
+                provenance,
+                provenance_age,
+                _padding4: [0; 3],
+                min_distance: min_distance as f32,
                 displacements_desc,
                 albedo_desc,
                 roughness_desc,
@@ -192,11 +290,24 @@ impl QuadTree {
                 .into(),
             });
         }
+        self.full_node_states = self.node_states.len() as u32;
         for &(node, mask) in self.partially_visible_nodes.iter() {
             assert!(mask < 15);
             assert!(node.min_distance() as f32 != 0.0);
+            if !Self::face_loaded(cache, node) {
+                continue;
+            }
+            let min_distance = node.min_distance() * self.lod_scale(node);
             for i in 0..4u8 {
-                if mask & (1 << i) != 0 {
+                let child = node.children()[i as usize];
+                if mask & (1 << i) != 0
+                    && (!frustum_culling
+                        || child.in_frustum(
+                            self.elevation_range(node),
+                            camera_position,
+                            &view_proj,
+                        ))
+                {
                     let offset = ((i % 2) as f32, (i / 2) as f32);
                     let base_origin = Vector2::new(offset.0 * (0.5), offset.1 * (0.5));
                     let (displacements_desc, displacements_node) = Self::find_descs(
@@ -250,11 +361,24 @@ impl QuadTree {
                             )
                         })
                         .unwrap_or([0.0, 0.0, -1.0, 0.0]);
+                    let (bounds_min, bounds_max) =
+                        Self::bounding_box(child, self.elevation_range(node), camera_position);
+                    let (provenance, provenance_age) =
+                        Self::provenance(cache, displacements_node, now);
                     let node_index = self.node_states.len() as u32;
                     self.node_states.push(NodeState {
-                        _padding1: [0; 17],
+                        _padding1: 0,
+                        bounds_min,
+                        _padding2: 0,
+                        bounds_max,
+                        _padding3: 0,
+                        ground_origin: Self::ground_origin(child),
+                        ground_step: node.aprox_side_length() / resolution as f32,
+                        provenance,
+                        provenance_age,
+                        _padding4: [0; 3],
                         // side_length: node.side_length() * 0.5,
-                        min_distance: node.min_distance() as f32,
+                        min_distance: min_distance as f32,
                         displacements_desc,
                         albedo_desc,
                         roughness_desc,
@@ -288,21 +412,37 @@ impl QuadTree {
         queue.write_buffer(vertex_buffer, 0, bytemuck::cast_slice(&self.node_states));
     }
 
+    /// Draw all nodes. If `draws` is provided, it must hold an indirect draw command for each node
+    /// as written by `OcclusionCuller::cull`. Those are issued with a single multi-draw where the
+    /// device supports it, and one at a time otherwise.
     pub(crate) fn render<'b, 'c>(
         &self,
         rpass: &'b mut wgpu::RenderPass<'c>,
         index_buffer: &'c wgpu::Buffer,
         bind_group: &'c wgpu::BindGroup,
+        draws: Option<IndirectDraws<'c>>,
     ) {
         let resolution = self.heights_resolution;
-        let visible_nodes = self.visible_nodes.len() as u32;
+        let visible_nodes = self.full_node_states;
         let total_nodes = self.node_states.len() as u32;
 
-        let num_indices_full = resolution * resolution * 6;
-        let num_indices_partial = (resolution / 2) * (resolution / 2) * 6;
+        let num_indices_full = Self::index_count(resolution);
+        let num_indices_partial = Self::index_count(resolution / 2);
 
         rpass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint16);
         rpass.set_bind_group(0, bind_group, &[]);
+
+        if let Some(draws) = draws {
+            if draws.multi_draw {
+                rpass.multi_draw_indexed_indirect(draws.buffer, 0, total_nodes);
+            } else {
+                for i in 0..total_nodes as u64 {
+                    rpass.draw_indexed_indirect(draws.buffer, i * 20);
+                }
+            }
+            return;
+        }
+
         rpass.draw_indexed(0..num_indices_full, 0, 0..visible_nodes);
         rpass.draw_indexed(
             num_indices_full..(num_indices_full + num_indices_partial),
@@ -1,18 +1,27 @@
+use crate::asset::TERRA_DIRECTORY;
 use crate::coordinates;
-use anyhow::Error;
+use anyhow::{ensure, Error};
 use bit_vec::BitVec;
 use crossbeam::channel::{self, Receiver, Sender};
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use lru_cache::LruCache;
+use memmap::Mmap;
 use serde::{Deserialize, Serialize};
 use std::f64::consts::PI;
+use std::fs::File;
+use std::marker::PhantomData;
 use std::ops::{Deref, Index};
+use std::path::{Path, PathBuf};
 use std::{
     collections::{HashMap, HashSet},
-    sync::{Arc, Weak},
+    sync::{Arc, Mutex, Weak},
 };
 
+/// Cells along each side of the windows that `RasterCache` copies out of its rasters.
+const WINDOW_SIZE: usize = 128;
+
 pub trait Scalar: Copy + 'static {
     fn from_f64(_: f64) -> Self;
     fn to_f64(self) -> f64;
@@ -32,7 +41,7 @@ impl Index<usize> for BitContainer {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub(crate) struct MMappedRasterHeader {
     pub width: usize,
     pub height: usize,
@@ -42,6 +51,42 @@ pub(crate) struct MMappedRasterHeader {
     pub latitude_llcorner: f64,
     pub longitude_llcorner: f64,
 }
+impl MMappedRasterHeader {
+    /// The cell to interpolate from at the given position, the next cell over in each direction
+    /// clamped to the raster, and the fractional position between them.
+    fn cells(
+        &self,
+        latitude: f64,
+        longitude: f64,
+    ) -> Option<((usize, usize), (usize, usize), (f64, f64))> {
+        let x = (longitude - self.longitude_llcorner) / self.cell_size;
+        let y = (self.height - 1) as f64 - (latitude - self.latitude_llcorner) / self.cell_size;
+
+        let fx = x.floor() as usize;
+        let fy = y.floor() as usize;
+
+        if x < 0.0 || fx >= self.width || y < 0.0 || fy >= self.height {
+            return None;
+        }
+
+        // TODO: These should be interpolating across tiles...
+        let fx_1 = (fx + 1).min(self.width - 1);
+        let fy_1 = (fy + 1).min(self.height - 1);
+        Some(((fx, fy), (fx_1, fy_1), (x - fx as f64, y - fy as f64)))
+    }
+}
+
+/// Read-only memory mapping of a decoded raster's values.
+pub(crate) struct MMappedValues<T> {
+    mmap: Mmap,
+    _phantom: PhantomData<T>,
+}
+impl<T: bytemuck::Pod> Deref for MMappedValues<T> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        bytemuck::cast_slice(&self.mmap[..])
+    }
+}
 
 /// Currently assumes that values are taken at the lower left corner of each cell.
 #[derive(Clone, Serialize, Deserialize)]
@@ -71,30 +116,43 @@ impl<T: Into<f64> + Copy, C: Deref<Target = [T]>> Raster<T, C> {
             * (self.latitude_llcorner + self.cell_size * y as f64).to_radians().cos()
     }
 
-    pub fn interpolate(&self, latitude: f64, longitude: f64, band: usize) -> Option<f64> {
-        assert!(band < self.bands);
-
-        let x = (longitude - self.longitude_llcorner) / self.cell_size;
-        let y = (self.height - 1) as f64 - (latitude - self.latitude_llcorner) / self.cell_size;
-
-        let fx = x.floor() as usize;
-        let fy = y.floor() as usize;
-
-        if x < 0.0 || fx >= self.width || y < 0.0 || fy >= self.height {
-            return None;
+    fn header(&self) -> MMappedRasterHeader {
+        MMappedRasterHeader {
+            width: self.width,
+            height: self.height,
+            bands: self.bands,
+            cell_size: self.cell_size,
+            latitude_llcorner: self.latitude_llcorner,
+            longitude_llcorner: self.longitude_llcorner,
         }
+    }
 
-        // TODO: These should be interpolating across tiles...
-        let fx_1 = (fx + 1).min(self.width - 1);
-        let fy_1 = (fy + 1).min(self.height - 1);
+    pub fn interpolate(&self, latitude: f64, longitude: f64, band: usize) -> Option<f64> {
+        assert!(band < self.bands);
 
+        let ((fx, fy), (fx_1, fy_1), (tx, ty)) = self.header().cells(latitude, longitude)?;
         let h00 = self.values[(fx + fy * self.width) * self.bands + band].into();
         let h10 = self.values[(fx_1 + fy * self.width) * self.bands + band].into();
         let h01 = self.values[(fx + fy_1 * self.width) * self.bands + band].into();
         let h11 = self.values[(fx_1 + fy_1 * self.width) * self.bands + band].into();
-        let h0 = h00 + (h01 - h00) * (y - fy as f64);
-        let h1 = h10 + (h11 - h10) * (y - fy as f64);
-        Some(h0 + (h1 - h0) * (x - fx as f64))
+        let h0 = h00 + (h01 - h00) * ty;
+        let h1 = h10 + (h11 - h10) * ty;
+        Some(h0 + (h1 - h0) * tx)
+    }
+
+    /// Copy the cells of window `(wx, wy)`, in row-major order. Windows along the right and bottom
+    /// edges are cut short by the edge of the raster.
+    fn copy_window(&self, (wx, wy): (usize, usize)) -> Vec<T> {
+        let (x0, y0) = (wx * WINDOW_SIZE, wy * WINDOW_SIZE);
+        let width = WINDOW_SIZE.min(self.width - x0);
+        let height = WINDOW_SIZE.min(self.height - y0);
+
+        let mut values = Vec::with_capacity(width * height * self.bands);
+        for y in y0..(y0 + height) {
+            let row = &self.values[(x0 + y * self.width) * self.bands..];
+            values.extend_from_slice(&row[..width * self.bands]);
+        }
+        values
     }
 
     pub fn nearest3(&self, latitude: f64, longitude: f64) -> Option<[f64; 3]> {
@@ -132,6 +190,196 @@ pub(crate) trait RasterSource: Send + Sync {
     }
 }
 
+/// Wraps another raster source so that decoded rasters are written to disk the first time they are
+/// loaded and memory mapped from then on. This avoids repeating expensive decompression and lets
+/// the OS page out rasters that aren't actively being sampled.
+pub(crate) struct MMappedRasterSource<T: Into<f64> + bytemuck::Pod + Send + Sync> {
+    name: String,
+    inner: Arc<dyn RasterSource<Type = T, Container = Vec<T>>>,
+}
+impl<T: Into<f64> + bytemuck::Pod + Send + Sync> MMappedRasterSource<T> {
+    pub fn new(name: &str, inner: Arc<dyn RasterSource<Type = T, Container = Vec<T>>>) -> Self {
+        Self { name: name.to_owned(), inner }
+    }
+
+    fn filename(&self, latitude: i16, longitude: i16) -> PathBuf {
+        TERRA_DIRECTORY.join("rasters").join(&self.name).join(format!(
+            "{}{:02}_{}{:03}",
+            if latitude >= 0 { "N" } else { "S" },
+            latitude.abs(),
+            if longitude >= 0 { "E" } else { "W" },
+            longitude.abs(),
+        ))
+    }
+
+    fn open(
+        header_filename: &Path,
+        data_filename: &Path,
+    ) -> Result<Raster<T, MMappedValues<T>>, Error> {
+        let header: MMappedRasterHeader = bincode::deserialize(&std::fs::read(header_filename)?)?;
+        let mmap = unsafe { Mmap::map(&File::open(data_filename)?)? };
+        let expected = header.width * header.height * header.bands * std::mem::size_of::<T>();
+        ensure!(
+            mmap.len() == expected,
+            "{} is {} bytes but should be {}",
+            data_filename.display(),
+            mmap.len(),
+            expected
+        );
+
+        Ok(Raster {
+            width: header.width,
+            height: header.height,
+            bands: header.bands,
+            cell_size: header.cell_size,
+            latitude_llcorner: header.latitude_llcorner,
+            longitude_llcorner: header.longitude_llcorner,
+            values: MMappedValues { mmap, _phantom: PhantomData },
+        })
+    }
+}
+#[async_trait::async_trait]
+impl<T: Into<f64> + bytemuck::Pod + Send + Sync> RasterSource for MMappedRasterSource<T> {
+    type Type = T;
+    type Container = MMappedValues<T>;
+    async fn load(
+        &self,
+        latitude: i16,
+        longitude: i16,
+    ) -> Result<Option<Raster<T, MMappedValues<T>>>, Error> {
+        let filename = self.filename(latitude, longitude);
+        let header_filename = filename.with_extension("hdr");
+        let data_filename = filename.with_extension("data");
+
+        // The header is written last, so its presence indicates that the data file is complete. A
+        // data file that doesn't match its header was truncated or corrupted some other way, so it
+        // is deleted and decoded again.
+        if header_filename.exists() {
+            match Self::open(&header_filename, &data_filename) {
+                Ok(raster) => return Ok(Some(raster)),
+                Err(e) => {
+                    log::warn!(target: crate::generate::LOG_TARGET, "discarding raster: {}", e);
+                    let _ = std::fs::remove_file(&header_filename);
+                    let _ = std::fs::remove_file(&data_filename);
+                }
+            }
+        }
+
+        let raster = match self.inner.load(latitude, longitude).await? {
+            Some(raster) => raster,
+            None => return Ok(None),
+        };
+        tokio::fs::create_dir_all(filename.parent().unwrap()).await?;
+        tokio::fs::write(&data_filename, bytemuck::cast_slice(&raster.values[..])).await?;
+        tokio::fs::write(&header_filename, bincode::serialize(&raster.header())?).await?;
+
+        Ok(Some(Self::open(&header_filename, &data_filename)?))
+    }
+    fn bands(&self) -> usize {
+        self.inner.bands()
+    }
+    fn raster_size(&self) -> i16 {
+        self.inner.raster_size()
+    }
+}
+
+/// Counters describing how effective the cache of elevation data used while generating heightmap
+/// tiles has been. Returned by `HeadlessTerrain::generate_heightmaps`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RasterCacheStats {
+    /// Lookups satisfied by a raster held in the LRU cache.
+    pub hits: u64,
+    /// Lookups satisfied by a raster that had been evicted from the LRU cache but was still alive.
+    pub weak_hits: u64,
+    /// Lookups for a location known to have no raster.
+    pub hole_hits: u64,
+    /// Lookups that required loading the raster from its source.
+    pub misses: u64,
+    /// Windows of cells found in the in-memory LRU.
+    pub window_hits: u64,
+    /// Windows of cells that had to be copied out of a memory mapped raster.
+    pub window_misses: u64,
+}
+
+/// Most recently used windows of cells, shared by the heightmap tiles being generated at once.
+struct Windows<T> {
+    lru: LruCache<((i16, i16), (usize, usize)), Arc<Vec<T>>>,
+    hits: u64,
+    misses: u64,
+}
+
+/// Handle to the windows of a `RasterCache`, which can be used from the futures returned by `get`.
+#[derive(Clone)]
+pub(crate) struct RasterWindows<T>(Arc<Mutex<Windows<T>>>);
+impl<T: Into<f64> + Copy> RasterWindows<T> {
+    /// Gather the windows of `raster`, which was returned by `RasterCache::get` for `key`, that
+    /// are needed to interpolate at each of `points`. Windows not already in the LRU are copied
+    /// out of the raster.
+    pub fn view<C: Deref<Target = [T]>>(
+        &self,
+        key: (i16, i16),
+        raster: &Raster<T, C>,
+        points: impl Iterator<Item = (f64, f64)>,
+    ) -> WindowedRaster<T> {
+        let header = raster.header();
+        let mut needed = FnvHashSet::default();
+        for (latitude, longitude) in points {
+            if let Some(((fx, fy), (fx_1, fy_1), _)) = header.cells(latitude, longitude) {
+                for &(x, y) in &[(fx, fy), (fx_1, fy), (fx, fy_1), (fx_1, fy_1)] {
+                    needed.insert((x / WINDOW_SIZE, y / WINDOW_SIZE));
+                }
+            }
+        }
+
+        let mut windows = self.0.lock().unwrap();
+        let mut view = WindowedRaster { header, windows: FnvHashMap::default() };
+        for window in needed {
+            let values = match windows.lru.get_mut(&(key, window)) {
+                Some(values) => {
+                    let values = Arc::clone(values);
+                    windows.hits += 1;
+                    values
+                }
+                None => {
+                    let values = Arc::new(raster.copy_window(window));
+                    windows.lru.insert((key, window), Arc::clone(&values));
+                    windows.misses += 1;
+                    values
+                }
+            };
+            view.windows.insert(window, values);
+        }
+        view
+    }
+}
+
+/// The cells of a raster around some set of points, copied into memory a window at a time.
+pub(crate) struct WindowedRaster<T> {
+    header: MMappedRasterHeader,
+    windows: FnvHashMap<(usize, usize), Arc<Vec<T>>>,
+}
+impl<T: Into<f64> + Copy> WindowedRaster<T> {
+    fn value(&self, x: usize, y: usize, band: usize) -> f64 {
+        let window = &self.windows[&(x / WINDOW_SIZE, y / WINDOW_SIZE)];
+        let width = WINDOW_SIZE.min(self.header.width - x / WINDOW_SIZE * WINDOW_SIZE);
+        window[(x % WINDOW_SIZE + y % WINDOW_SIZE * width) * self.header.bands + band].into()
+    }
+
+    /// Same as `Raster::interpolate`, for points that were passed to `RasterWindows::view`.
+    pub fn interpolate(&self, latitude: f64, longitude: f64, band: usize) -> Option<f64> {
+        assert!(band < self.header.bands);
+
+        let ((fx, fy), (fx_1, fy_1), (tx, ty)) = self.header.cells(latitude, longitude)?;
+        let h00 = self.value(fx, fy, band);
+        let h10 = self.value(fx_1, fy, band);
+        let h01 = self.value(fx, fy_1, band);
+        let h11 = self.value(fx_1, fy_1, band);
+        let h0 = h00 + (h01 - h00) * ty;
+        let h1 = h10 + (h11 - h10) * ty;
+        Some(h0 + (h1 - h0) * tx)
+    }
+}
+
 pub(crate) struct RasterCache<
     T: Into<f64> + Copy + 'static,
     C: Deref<Target = [T]> + Send + Sync + 'static,
@@ -143,11 +391,20 @@ pub(crate) struct RasterCache<
     strong: LruCache<(i16, i16), Arc<Raster<T, C>>>,
     sender: Sender<((i16, i16), Option<Arc<Raster<T, C>>>)>,
     receiver: Receiver<((i16, i16), Option<Arc<Raster<T, C>>>)>,
+    windows: RasterWindows<T>,
+
+    stats: RasterCacheStats,
 }
 impl<T: Into<f64> + Copy + 'static, C: Deref<Target = [T]> + Send + Sync + 'static>
     RasterCache<T, C>
 {
-    pub fn new(source: Arc<dyn RasterSource<Type = T, Container = C>>, capacity: usize) -> Self {
+    /// Create a cache that keeps up to `capacity` rasters open, and up to `window_capacity`
+    /// windows of their cells in memory.
+    pub fn new(
+        source: Arc<dyn RasterSource<Type = T, Container = C>>,
+        capacity: usize,
+        window_capacity: usize,
+    ) -> Self {
         let (sender, receiver) = channel::unbounded();
 
         Self {
@@ -157,8 +414,24 @@ impl<T: Into<f64> + Copy + 'static, C: Deref<Target = [T]> + Send + Sync + 'stat
             strong: LruCache::new(capacity),
             sender,
             receiver,
+            windows: RasterWindows(Arc::new(Mutex::new(Windows {
+                lru: LruCache::new(window_capacity),
+                hits: 0,
+                misses: 0,
+            }))),
+            stats: RasterCacheStats::default(),
         }
     }
+
+    pub fn stats(&self) -> RasterCacheStats {
+        let windows = self.windows.0.lock().unwrap();
+        RasterCacheStats { window_hits: windows.hits, window_misses: windows.misses, ..self.stats }
+    }
+
+    pub fn windows(&self) -> RasterWindows<T> {
+        self.windows.clone()
+    }
+
     fn insert(&mut self, key: (i16, i16), raster: Option<Arc<Raster<T, C>>>) {
         match raster {
             Some(a) => {
@@ -172,6 +445,7 @@ impl<T: Into<f64> + Copy + 'static, C: Deref<Target = [T]> + Send + Sync + 'stat
     }
     fn try_get(&mut self, key: (i16, i16)) -> Option<Option<Arc<Raster<T, C>>>> {
         if self.holes.contains(&key) {
+            self.stats.hole_hits += 1;
             return Some(None);
         }
 
@@ -183,17 +457,23 @@ impl<T: Into<f64> + Copy + 'static, C: Deref<Target = [T]> + Send + Sync + 'stat
             self.insert(t.0, t.1);
         }
         if found.is_some() {
+            self.stats.hits += 1;
             return Some(found);
         }
 
         match self.strong.get_mut(&key) {
-            Some(e) => Some(Some(Arc::clone(e))),
+            Some(e) => {
+                self.stats.hits += 1;
+                Some(Some(Arc::clone(e)))
+            }
             None => match self.weak.get(&key).and_then(|w| w.upgrade()) {
                 Some(t) => {
+                    self.stats.weak_hits += 1;
                     self.strong.insert(key, t.clone());
                     Some(Some(Arc::clone(&t)))
                 }
                 None => {
+                    self.stats.misses += 1;
                     self.weak.remove(&key);
                     None
                 }
@@ -230,6 +510,7 @@ impl<T: Into<f64> + Copy + 'static, C: Deref<Target = [T]> + Send + Sync + 'stat
         longitude: f64,
         band: usize,
     ) -> Result<Option<f64>, Error> {
+        let longitude = (longitude + 180.0).rem_euclid(360.0) - 180.0;
         Ok(self
             .get(latitude.floor() as i16, longitude.floor() as i16)
             .await?
@@ -242,6 +523,7 @@ impl<T: Into<f64> + Copy + 'static, C: Deref<Target = [T]> + Send + Sync + 'stat
         latitude: f64,
         longitude: f64,
     ) -> Result<Option<[f64; 3]>, Error> {
+        let longitude = (longitude + 180.0).rem_euclid(360.0) - 180.0;
         Ok(self
             .get(latitude.floor() as i16, longitude.floor() as i16)
             .await?
@@ -249,6 +531,35 @@ impl<T: Into<f64> + Copy + 'static, C: Deref<Target = [T]> + Send + Sync + 'stat
     }
 }
 
+/// How the values in a raster band relate to the quantity they represent.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum BandEncoding {
+    /// Values are directly proportional to the quantity, as for elevations.
+    Linear,
+    /// Values are sRGB encoded, with `max` representing full intensity.
+    Srgb { max: f64 },
+    /// Values are encoded with a power law, with `max` representing full intensity.
+    Gamma { gamma: f64, max: f64 },
+}
+impl BandEncoding {
+    /// Convert an encoded value to linear space. Values of sRGB and gamma encoded bands are
+    /// normalized to the range [0, 1].
+    pub fn decode(&self, value: f64) -> f64 {
+        match *self {
+            BandEncoding::Linear => value,
+            BandEncoding::Srgb { max } => {
+                let v = (value / max).max(0.0).min(1.0);
+                if v <= 0.04045 {
+                    v / 12.92
+                } else {
+                    ((v + 0.055) / 1.055).powf(2.4)
+                }
+            }
+            BandEncoding::Gamma { gamma, max } => (value / max).max(0.0).min(1.0).powf(gamma),
+        }
+    }
+}
+
 /// Currently assumes that values are taken at the *center* of cells.
 pub(crate) struct GlobalRaster<T: Into<f64> + Copy, C: Index<usize, Output = T> = Vec<T>> {
     pub width: usize,
@@ -271,22 +582,73 @@ impl<T: Into<f64> + Copy, C: Index<usize, Output = T>> GlobalRaster<T, C> {
         self.values[(x + y * self.width) * self.bands + band].into()
     }
 
-    pub fn interpolate(&self, latitude: f64, longitude: f64, band: usize) -> f64 {
+    /// Returns the cell to the upper left of the given position, along with the fractional
+    /// position within it.
+    fn cell(&self, latitude: f64, longitude: f64) -> (i64, i64, f64, f64) {
         assert!(latitude >= -90.0 && latitude <= 90.0);
-        assert!(longitude >= -180.0 && latitude <= 180.0);
 
-        let x = (longitude + 180.0) / 360.0 * self.width as f64 - 0.5;
+        // Longitudes wrap around, so columns past either edge come from the other side.
+        let x = (longitude + 180.0).rem_euclid(360.0) / 360.0 * self.width as f64 - 0.5;
         let y = (90.0 - latitude) / 180.0 * self.height as f64 - 0.5;
 
         let fx = x.floor() as i64;
         let fy = y.floor() as i64;
+        (fx, fy, x - fx as f64, y - fy as f64)
+    }
+
+    fn bilinear(
+        &self,
+        (fx, fy, tx, ty): (i64, i64, f64, f64),
+        band: usize,
+        decode: impl Fn(f64) -> f64,
+    ) -> f64 {
+        let h00 = decode(self.get(fx, fy, band));
+        let h10 = decode(self.get(fx + 1, fy, band));
+        let h01 = decode(self.get(fx, fy + 1, band));
+        let h11 = decode(self.get(fx + 1, fy + 1, band));
+        let h0 = h00 + (h01 - h00) * ty;
+        let h1 = h10 + (h11 - h10) * ty;
+        h0 + (h1 - h0) * tx
+    }
+
+    pub fn interpolate(&self, latitude: f64, longitude: f64, band: usize) -> f64 {
+        self.bilinear(self.cell(latitude, longitude), band, |v| v)
+    }
+
+    /// Interpolate the first `encodings.len()` bands at once, writing the results to `output`.
+    /// Each band is decoded to linear space before interpolating, so that gamma encoded color
+    /// channels blend correctly. The results are left in linear space.
+    pub fn interpolate_bands(
+        &self,
+        latitude: f64,
+        longitude: f64,
+        encodings: &[BandEncoding],
+        output: &mut [f64],
+    ) {
+        assert!(encodings.len() <= self.bands);
+        let cell = self.cell(latitude, longitude);
+        for (band, (encoding, output)) in encodings.iter().zip(output).enumerate() {
+            *output = self.bilinear(cell, band, |v| encoding.decode(v));
+        }
+    }
+}
 
-        let h00 = self.get(fx, fy, band);
-        let h10 = self.get(fx + 1, fy, band);
-        let h01 = self.get(fx, fy + 1, band);
-        let h11 = self.get(fx + 1, fy + 1, band);
-        let h0 = h00 + (h01 - h00) * (y - fy as f64);
-        let h1 = h10 + (h11 - h10) * (y - fy as f64);
-        h0 + (h1 - h0) * (x - fx as f64)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolate_srgb_bands() {
+        // Two columns, black and white, with a second band holding linear values.
+        let raster = GlobalRaster { width: 2, height: 1, bands: 2, values: vec![0u8, 0, 255, 100] };
+        let encodings = [BandEncoding::Srgb { max: 255.0 }, BandEncoding::Linear];
+        let mut output = [0.0; 2];
+
+        // Halfway between the cell centers, the color should be half the linear intensity rather
+        // than half the encoded value.
+        raster.interpolate_bands(0.0, 0.0, &encodings, &mut output);
+        assert!((output[0] - 0.5).abs() < 1e-6);
+        assert!((output[1] - 50.0).abs() < 1e-6);
+        assert!((raster.interpolate(0.0, 0.0, 0) - 127.5).abs() < 1e-6);
     }
 }
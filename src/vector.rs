@@ -0,0 +1,41 @@
+use crate::coordinates;
+use crate::terrain::quadtree::node::VNode;
+use cgmath::Vector3;
+
+/// Identifies the quadtree node that a vector tile is attached to. Vector tiles are paged in and
+/// out along with the terrain tiles of the same node, so data attached to coarse nodes is
+/// available from far away while data attached to fine nodes only loads up close.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VectorTileId(pub(crate) VNode);
+impl VectorTileId {
+    /// The node at `level` that contains the given latitude and longitude, in radians.
+    pub fn containing(latitude: f64, longitude: f64, level: u8) -> Self {
+        let ecef = coordinates::polar_to_ecef(Vector3::new(latitude, longitude, 0.0));
+        let cspace = ecef / ecef.x.abs().max(ecef.y.abs()).max(ecef.z.abs());
+        Self(VNode::from_cspace(cspace, level).0)
+    }
+
+    /// Level of the node in the quadtree. Each level has twice the resolution of the one above.
+    pub fn level(&self) -> u8 {
+        self.0.level()
+    }
+
+    /// Which of the six faces of the cube map the node lies on.
+    pub fn face(&self) -> u8 {
+        self.0.face()
+    }
+
+    /// Column of the node within its face and level.
+    pub fn x(&self) -> u32 {
+        self.0.x()
+    }
+
+    /// Row of the node within its face and level.
+    pub fn y(&self) -> u32 {
+        self.0.y()
+    }
+}
+
+/// Callback invoked with the contents of a vector tile when it is loaded, and with `None` when it
+/// is unloaded again.
+pub type VectorTileCallback = Box<dyn FnMut(VectorTileId, Option<&[u8]>) + Send>;
@@ -0,0 +1,60 @@
+/// Identifies a viewpoint registered with `Terrain::add_streaming_viewpoint`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StreamingViewpointId(u64);
+
+/// Positions besides the camera being rendered that terrain is streamed around, such as the
+/// cameras of other players in split screen or points of interest on a server.
+#[derive(Default)]
+pub(crate) struct StreamingViewpoints {
+    viewpoints: Vec<(StreamingViewpointId, mint::Point3<f64>)>,
+    next_id: u64,
+}
+impl StreamingViewpoints {
+    pub fn add(&mut self, position: mint::Point3<f64>) -> StreamingViewpointId {
+        let id = StreamingViewpointId(self.next_id);
+        self.next_id += 1;
+        self.viewpoints.push((id, position));
+        id
+    }
+
+    /// Move a viewpoint. Returns false if there is no viewpoint with the given id.
+    pub fn set(&mut self, id: StreamingViewpointId, position: mint::Point3<f64>) -> bool {
+        match self.viewpoints.iter_mut().find(|v| v.0 == id) {
+            Some(v) => {
+                v.1 = position;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: StreamingViewpointId) {
+        self.viewpoints.retain(|v| v.0 != id);
+    }
+
+    /// Positions of every viewpoint, in the order they were added.
+    pub fn positions(&self) -> Vec<mint::Point3<f64>> {
+        self.viewpoints.iter().map(|v| v.1).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn move_and_remove_viewpoints() {
+        let point = |x: f64| mint::Point3 { x, y: 0.0, z: 0.0 };
+        let mut viewpoints = StreamingViewpoints::default();
+        let a = viewpoints.add(point(1.0));
+        let b = viewpoints.add(point(2.0));
+        assert_ne!(a, b);
+
+        assert!(viewpoints.set(a, point(3.0)));
+        assert_eq!(viewpoints.positions(), vec![point(3.0), point(2.0)]);
+
+        viewpoints.remove(a);
+        assert!(!viewpoints.set(a, point(4.0)));
+        assert_eq!(viewpoints.positions(), vec![point(2.0)]);
+    }
+}
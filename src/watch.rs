@@ -0,0 +1,63 @@
+use fnv::FnvHashMap;
+
+/// Smallest change in elevation, in meters, that is reported.
+const ELEVATION_EPSILON: f32 = 0.001;
+
+/// Identifies a point registered with `Terrain::watch_elevation`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ElevationWatchId(u64);
+
+/// Callback invoked with the watched point, its new elevation, and the change from the previously
+/// reported elevation.
+pub type ElevationCallback = Box<dyn FnMut(ElevationWatchId, f32, f32) + Send>;
+
+struct WatchedPoint {
+    latitude: f64,
+    longitude: f64,
+    elevation: Option<f32>,
+}
+
+/// Tracks the ground elevation beneath a set of points, so applications can be told when it
+/// changes. This happens whenever more detailed tiles are streamed in or evicted.
+#[derive(Default)]
+pub(crate) struct ElevationWatches {
+    points: FnvHashMap<ElevationWatchId, WatchedPoint>,
+    next_id: u64,
+    callback: Option<ElevationCallback>,
+}
+impl ElevationWatches {
+    pub fn add(&mut self, latitude: f64, longitude: f64) -> ElevationWatchId {
+        let id = ElevationWatchId(self.next_id);
+        self.next_id += 1;
+        self.points.insert(id, WatchedPoint { latitude, longitude, elevation: None });
+        id
+    }
+
+    pub fn remove(&mut self, id: ElevationWatchId) {
+        self.points.remove(&id);
+    }
+
+    pub fn set_callback(&mut self, callback: Option<ElevationCallback>) {
+        self.callback = callback;
+    }
+
+    /// Re-evaluate the elevation of every watched point and report any that changed. The first
+    /// evaluation of a point is reported with a delta of zero.
+    pub fn update(&mut self, elevation: impl Fn(f64, f64) -> f32) {
+        let callback = match self.callback {
+            Some(ref mut callback) => callback,
+            None => return,
+        };
+
+        for (&id, point) in self.points.iter_mut() {
+            let new = elevation(point.latitude, point.longitude);
+            match point.elevation {
+                Some(old) if (new - old).abs() < ELEVATION_EPSILON => {}
+                old => {
+                    point.elevation = Some(new);
+                    callback(id, new, old.map(|old| new - old).unwrap_or(0.0));
+                }
+            }
+        }
+    }
+}
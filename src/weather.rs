@@ -0,0 +1,42 @@
+/// Conditions in the atmosphere that change how the sky and the light reaching the ground look.
+/// The default has no auroras, no airglow, clear skies and no light shafts.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Weather {
+    /// Strength of auroras, from zero for none up to one for a strong geomagnetic storm. Stronger
+    /// auroras are brighter and reach further from the magnetic poles. Auroras are only drawn
+    /// when the camera is on the night side of the planet.
+    pub aurora: f32,
+    /// Whether to draw airglow: the faint light emitted by the upper atmosphere at night, which is
+    /// most visible as a band just above the horizon.
+    pub airglow: bool,
+    /// Fraction of the sky covered by clouds, from zero for clear skies to one for overcast. The
+    /// clouds themselves aren't drawn, but cast shadows onto the terrain.
+    pub cloud_coverage: f32,
+    /// Speed in meters per second that cloud shadows move across the ground. Like auroras, they
+    /// are animated using the time of day given to `Terrain::set_time`.
+    pub wind_speed: f32,
+    /// Direction in radians, clockwise from north, that cloud shadows move towards.
+    pub wind_heading: f32,
+    /// Strength of the light shafts drawn by `Terrain::render_light_shafts`, from zero for none
+    /// up to one.
+    pub light_shafts: f32,
+}
+impl Weather {
+    /// Packed form passed to shaders. `seconds` is used to animate auroras.
+    pub(crate) fn uniform(&self, seconds: f32) -> [f32; 4] {
+        [self.aurora.max(0.0).min(1.0), self.airglow as u8 as f32, seconds, 0.0]
+    }
+
+    /// Packed cloud and light shaft settings passed to shaders: coverage, how far the wind has
+    /// carried the clouds east and north in meters after `seconds`, and light shaft strength.
+    pub(crate) fn clouds_uniform(&self, seconds: f32) -> [f32; 4] {
+        let (east, north) = self.wind_heading.sin_cos();
+        let distance = self.wind_speed * seconds;
+        [
+            self.cloud_coverage.max(0.0).min(1.0),
+            east * distance,
+            north * distance,
+            self.light_shafts.max(0.0).min(1.0),
+        ]
+    }
+}